@@ -0,0 +1,197 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Benchmarks of the bus lookup structures and of the dispatch through `IoManager`.
+//!
+//! Run with `cargo bench`; each benchmark is parameterized by the number of registered
+//! devices, and the bus benchmarks by the `Storage` backing the bus.
+
+use std::sync::Arc;
+
+use criterion::measurement::WallTime;
+use criterion::{
+    black_box, criterion_group, criterion_main, BatchSize, BenchmarkGroup, BenchmarkId, Criterion,
+};
+
+use vm_device::bus::{
+    Bus, IntervalTree, MmioAddress, MmioAddressOffset, MmioRange, PageIndex, SortedVec, Storage,
+};
+use vm_device::device_manager::{IoManager, MmioManager, SharedIoManager};
+use vm_device::trusted::TrustedLayout;
+use vm_device::DeviceMmio;
+
+const DEVICE_COUNTS: [u64; 5] = [1, 16, 64, 256, 1024];
+const DEVICE_SIZE: u64 = 0x1000;
+// Leave a gap between devices, so lookups also have to reject the preceding range.
+const DEVICE_STRIDE: u64 = 2 * DEVICE_SIZE;
+
+type Group<'a> = BenchmarkGroup<'a, WallTime>;
+
+struct NoopDevice;
+
+impl DeviceMmio for NoopDevice {
+    fn mmio_read(&self, _base: MmioAddress, _offset: MmioAddressOffset, _data: &mut [u8]) {}
+    fn mmio_write(&self, _base: MmioAddress, _offset: MmioAddressOffset, _data: &[u8]) {}
+}
+
+fn range(idx: u64) -> MmioRange {
+    MmioRange::new(MmioAddress(idx * DEVICE_STRIDE), DEVICE_SIZE).unwrap()
+}
+
+// Addresses hitting every device, in an order that doesn't follow the address space.
+fn addresses(count: u64) -> Vec<MmioAddress> {
+    (0..count)
+        .map(|idx| MmioAddress((idx * 7919 % count) * DEVICE_STRIDE + 0x10))
+        .collect()
+}
+
+fn bus<S: Storage<MmioAddress, u64>>(count: u64) -> Bus<MmioAddress, u64, S> {
+    let mut bus = Bus::default();
+    for idx in 0..count {
+        bus.register(range(idx), idx).unwrap();
+    }
+    bus
+}
+
+fn bench_lookup(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bus_lookup");
+    for &count in DEVICE_COUNTS.iter() {
+        lookup::<SortedVec<_, _>>(&mut group, "sorted_vec", count);
+        lookup::<IntervalTree<_, _>>(&mut group, "interval_tree", count);
+        lookup::<PageIndex<_>>(&mut group, "page_index", count);
+    }
+    group.finish();
+}
+
+fn lookup<S: Storage<MmioAddress, u64>>(group: &mut Group, storage: &str, count: u64) {
+    let bus = bus::<S>(count);
+    let addresses = addresses(count);
+    group.bench_function(BenchmarkId::new(storage, count), |b| {
+        b.iter(|| {
+            for &addr in addresses.iter() {
+                black_box(bus.check_access(black_box(addr), 4).unwrap());
+            }
+        })
+    });
+}
+
+fn bench_register(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bus_register");
+    for &count in DEVICE_COUNTS.iter() {
+        register::<SortedVec<_, _>>(&mut group, "sorted_vec", count);
+        register::<IntervalTree<_, _>>(&mut group, "interval_tree", count);
+        register::<PageIndex<_>>(&mut group, "page_index", count);
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("bus_deregister");
+    for &count in DEVICE_COUNTS.iter() {
+        deregister::<SortedVec<_, _>>(&mut group, "sorted_vec", count);
+        deregister::<IntervalTree<_, _>>(&mut group, "interval_tree", count);
+        deregister::<PageIndex<_>>(&mut group, "page_index", count);
+    }
+    group.finish();
+}
+
+fn register<S: Storage<MmioAddress, u64>>(group: &mut Group, storage: &str, count: u64) {
+    group.bench_function(BenchmarkId::new(storage, count), |b| {
+        b.iter(|| bus::<S>(black_box(count)))
+    });
+}
+
+fn deregister<S: Storage<MmioAddress, u64>>(group: &mut Group, storage: &str, count: u64) {
+    let addresses = addresses(count);
+    group.bench_function(BenchmarkId::new(storage, count), |b| {
+        b.iter_batched(
+            || bus::<S>(count),
+            |mut bus| {
+                for &addr in addresses.iter() {
+                    black_box(bus.deregister(addr).unwrap());
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+fn bench_dispatch(c: &mut Criterion) {
+    let mut group = c.benchmark_group("manager_dispatch");
+    for &count in DEVICE_COUNTS.iter() {
+        let mut manager = IoManager::new();
+        let device = Arc::new(NoopDevice);
+        for idx in 0..count {
+            manager.register_mmio(range(idx), device.clone()).unwrap();
+        }
+        let addresses = addresses(count);
+        group.bench_with_input(BenchmarkId::from_parameter(count), &count, |b, _| {
+            b.iter(|| {
+                let mut data = [0; 4];
+                for &addr in addresses.iter() {
+                    manager.mmio_read(black_box(addr), &mut data).unwrap();
+                    manager.mmio_write(black_box(addr), &data).unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_trusted_dispatch(c: &mut Criterion) {
+    let mut group = c.benchmark_group("trusted_dispatch");
+    trusted_dispatch::<1>(&mut group);
+    trusted_dispatch::<16>(&mut group);
+    trusted_dispatch::<64>(&mut group);
+    trusted_dispatch::<256>(&mut group);
+    trusted_dispatch::<1024>(&mut group);
+    group.finish();
+}
+
+fn trusted_dispatch<const N: usize>(group: &mut Group) {
+    static DEVICE: NoopDevice = NoopDevice;
+    let layout = TrustedLayout::<N>::new(std::array::from_fn(|idx| {
+        let range = range(idx as u64);
+        (range.base(), range.size(), &DEVICE as _)
+    }));
+    let addresses = addresses(N as u64);
+    group.bench_with_input(BenchmarkId::from_parameter(N), &N, |b, _| {
+        b.iter(|| {
+            let mut data = [0; 4];
+            for &addr in addresses.iter() {
+                layout.mmio_read(black_box(addr), &mut data).unwrap();
+                layout.mmio_write(black_box(addr), &data).unwrap();
+            }
+        })
+    });
+}
+
+// Queue notifications hit the same device over and over, which dispatch handles optimize for.
+fn bench_repeated_dispatch(c: &mut Criterion) {
+    let mut group = c.benchmark_group("repeated_dispatch");
+    for &count in DEVICE_COUNTS.iter() {
+        let mut manager = IoManager::new();
+        let device = Arc::new(NoopDevice);
+        for idx in 0..count {
+            manager.register_mmio(range(idx), device.clone()).unwrap();
+        }
+        let addr = MmioAddress(range(count / 2).base().0 + 0x50);
+        let shared = SharedIoManager::new(manager);
+        group.bench_function(BenchmarkId::new("shared_manager", count), |b| {
+            b.iter(|| shared.mmio_write(black_box(addr), &[1, 0, 0, 0]).unwrap())
+        });
+        let mut handle = shared.handle();
+        group.bench_function(BenchmarkId::new("dispatch_handle", count), |b| {
+            b.iter(|| handle.mmio_write(black_box(addr), &[1, 0, 0, 0]).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_lookup,
+    bench_register,
+    bench_dispatch,
+    bench_trusted_dispatch,
+    bench_repeated_dispatch
+);
+criterion_main!(benches);
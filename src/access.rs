@@ -0,0 +1,270 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Single entry point alternative to the device traits.
+//!
+//! [`MmioAccessHandler`] and [`PioAccessHandler`] handle reads and writes through a single
+//! `access` method, taking an [`MmioRequest`] or [`PioRequest`] which carries the direction
+//! and data of the access. Decorators, e.g. tracing the accesses, injecting faults or
+//! throttling a device, then only need to wrap one method:
+//!
+//! ```
+//! # use std::sync::Arc;
+//! # use std::sync::atomic::{AtomicUsize, Ordering};
+//! use vm_device::access::{AccessAdapter, MmioAccessHandler, MmioRequest};
+//! use vm_device::bus::{MmioAddress, MmioRange};
+//! use vm_device::device_manager::{IoManager, MmioManager};
+//! use vm_device::exit::ExitAccess;
+//! # use vm_device::bus::MmioAddressOffset;
+//! # use vm_device::DeviceMmio;
+//! # struct Rtc;
+//! # impl DeviceMmio for Rtc {
+//! #     fn mmio_read(&self, _: MmioAddress, _: MmioAddressOffset, _: &mut [u8]) {}
+//! #     fn mmio_write(&self, _: MmioAddress, _: MmioAddressOffset, _: &[u8]) {}
+//! # }
+//!
+//! // Counts the writes to a device.
+//! struct CountWrites<H>(H, AtomicUsize);
+//!
+//! impl<H: MmioAccessHandler> MmioAccessHandler for CountWrites<H> {
+//!     fn access(&self, request: MmioRequest<'_>) {
+//!         if let ExitAccess::Write(_) = request.access {
+//!             self.1.fetch_add(1, Ordering::Relaxed);
+//!         }
+//!         self.0.access(request)
+//!     }
+//! }
+//!
+//! // Every device is a handler, and adapters turn handlers back into devices.
+//! let rtc = Arc::new(AccessAdapter::new(CountWrites(Rtc, AtomicUsize::new(0))));
+//! let mut manager = IoManager::new();
+//! let range = MmioRange::new(MmioAddress(0x1000), 0x10).unwrap();
+//! manager.register_mmio(range, rtc.clone()).unwrap();
+//! manager.mmio_write(MmioAddress(0x1000), &[1]).unwrap();
+//! assert_eq!(rtc.inner().1.load(Ordering::Relaxed), 1);
+//! ```
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::bus::{MmioAddress, MmioAddressOffset, PioAddress, PioAddressOffset};
+use crate::exit::ExitAccess;
+use crate::{DeviceMmio, DevicePio};
+
+/// An access to a device on the MMIO bus.
+#[derive(Debug, Eq, PartialEq)]
+pub struct MmioRequest<'a> {
+    /// Base address of the range of the device.
+    pub base: MmioAddress,
+    /// Offset of the access in the range.
+    pub offset: MmioAddressOffset,
+    /// Direction and data of the access.
+    pub access: ExitAccess<'a>,
+}
+
+/// An access to a device on the PIO bus.
+#[derive(Debug, Eq, PartialEq)]
+pub struct PioRequest<'a> {
+    /// Base port of the range of the device.
+    pub base: PioAddress,
+    /// Offset of the access in the range.
+    pub offset: PioAddressOffset,
+    /// Direction and data of the access.
+    pub access: ExitAccess<'a>,
+}
+
+/// Handles the accesses to a device on the MMIO bus through a single method.
+///
+/// Implemented by every [`DeviceMmio`]; [`AccessAdapter`] turns handlers back into devices.
+pub trait MmioAccessHandler {
+    /// Handle `request`, filling in the buffer of a read.
+    fn access(&self, request: MmioRequest<'_>);
+
+    /// Describe the current state of the device, like [`DeviceMmio::introspect`].
+    ///
+    /// The default implementation returns no properties.
+    fn introspect(&self) -> Vec<(String, String)> {
+        Vec::new()
+    }
+}
+
+/// Handles the accesses to a device on the PIO bus through a single method.
+///
+/// Implemented by every [`DevicePio`]; [`AccessAdapter`] turns handlers back into devices.
+pub trait PioAccessHandler {
+    /// Handle `request`, filling in the buffer of a read.
+    fn access(&self, request: PioRequest<'_>);
+
+    /// Describe the current state of the device, like [`DevicePio::introspect`].
+    ///
+    /// The default implementation returns no properties.
+    fn introspect(&self) -> Vec<(String, String)> {
+        Vec::new()
+    }
+}
+
+impl<T: DeviceMmio + ?Sized> MmioAccessHandler for T {
+    fn access(&self, request: MmioRequest<'_>) {
+        match request.access {
+            ExitAccess::Read(data) => self.mmio_read(request.base, request.offset, data),
+            ExitAccess::Write(data) => self.mmio_write(request.base, request.offset, data),
+        }
+    }
+
+    fn introspect(&self) -> Vec<(String, String)> {
+        DeviceMmio::introspect(self)
+    }
+}
+
+impl<T: DevicePio + ?Sized> PioAccessHandler for T {
+    fn access(&self, request: PioRequest<'_>) {
+        match request.access {
+            ExitAccess::Read(data) => self.pio_read(request.base, request.offset, data),
+            ExitAccess::Write(data) => self.pio_write(request.base, request.offset, data),
+        }
+    }
+
+    fn introspect(&self) -> Vec<(String, String)> {
+        DevicePio::introspect(self)
+    }
+}
+
+/// Exposes an [`MmioAccessHandler`] or a [`PioAccessHandler`] as a device, so it can be
+/// registered with a manager.
+pub struct AccessAdapter<H> {
+    handler: H,
+}
+
+impl<H> AccessAdapter<H> {
+    /// Expose `handler` as a device.
+    pub fn new(handler: H) -> Self {
+        AccessAdapter { handler }
+    }
+
+    /// Return the handler.
+    pub fn inner(&self) -> &H {
+        &self.handler
+    }
+}
+
+impl<H: MmioAccessHandler> DeviceMmio for AccessAdapter<H> {
+    fn mmio_read(&self, base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+        self.handler.access(MmioRequest {
+            base,
+            offset,
+            access: ExitAccess::Read(data),
+        });
+    }
+
+    fn mmio_write(&self, base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+        self.handler.access(MmioRequest {
+            base,
+            offset,
+            access: ExitAccess::Write(data),
+        });
+    }
+
+    fn introspect(&self) -> Vec<(String, String)> {
+        MmioAccessHandler::introspect(&self.handler)
+    }
+}
+
+impl<H: PioAccessHandler> DevicePio for AccessAdapter<H> {
+    fn pio_read(&self, base: PioAddress, offset: PioAddressOffset, data: &mut [u8]) {
+        self.handler.access(PioRequest {
+            base,
+            offset,
+            access: ExitAccess::Read(data),
+        });
+    }
+
+    fn pio_write(&self, base: PioAddress, offset: PioAddressOffset, data: &[u8]) {
+        self.handler.access(PioRequest {
+            base,
+            offset,
+            access: ExitAccess::Write(data),
+        });
+    }
+
+    fn introspect(&self) -> Vec<(String, String)> {
+        PioAccessHandler::introspect(&self.handler)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::bus::{MmioRange, PioRange};
+    use crate::device_manager::{IoManager, MmioManager, PioManager};
+    use crate::testing::{MockDevice, Scratchpad};
+
+    // Fails the reads at `offset`, which return all ones.
+    struct FaultInjector<H> {
+        handler: H,
+        offset: u64,
+    }
+
+    impl<H: MmioAccessHandler> MmioAccessHandler for FaultInjector<H> {
+        fn access(&self, request: MmioRequest<'_>) {
+            match request.access {
+                ExitAccess::Read(data) if request.offset == self.offset => data.fill(0xff),
+                _ => self.handler.access(request),
+            }
+        }
+
+        fn introspect(&self) -> Vec<(String, String)> {
+            self.handler.introspect()
+        }
+    }
+
+    impl<H: PioAccessHandler> PioAccessHandler for FaultInjector<H> {
+        fn access(&self, request: PioRequest<'_>) {
+            match request.access {
+                ExitAccess::Read(data) if request.offset == self.offset as u16 => data.fill(0xff),
+                _ => self.handler.access(request),
+            }
+        }
+    }
+
+    #[test]
+    fn test_access_adapter() {
+        let mock = Arc::new(MockDevice::new());
+        let device = Arc::new(AccessAdapter::new(FaultInjector {
+            handler: mock.clone(),
+            offset: 4,
+        }));
+        let mut manager = IoManager::new();
+        let range = MmioRange::new(MmioAddress(0x1000), 0x10).unwrap();
+        manager.register_mmio(range, device.clone()).unwrap();
+        let range = PioRange::new(PioAddress(0x60), 0x10).unwrap();
+        manager.register_pio(range, device.clone()).unwrap();
+
+        mock.push_read_response(0, &[1, 2]);
+        let mut data = [0; 2];
+        manager.mmio_read(MmioAddress(0x1000), &mut data).unwrap();
+        assert_eq!(data, [1, 2]);
+        manager.mmio_read(MmioAddress(0x1004), &mut data).unwrap();
+        assert_eq!(data, [0xff; 2]);
+        manager.pio_read(PioAddress(0x64), &mut data).unwrap();
+        assert_eq!(data, [0xff; 2]);
+        manager.pio_write(PioAddress(0x64), &[3]).unwrap();
+        manager.mmio_write(MmioAddress(0x1008), &[4]).unwrap();
+        assert_eq!(mock.accesses().len(), 3);
+        assert_eq!(mock.accesses()[2].offset, 8);
+
+        // Devices are handlers on their own.
+        let scratchpad = Scratchpad::new(4);
+        MmioAccessHandler::access(
+            &scratchpad,
+            MmioRequest {
+                base: MmioAddress(0),
+                offset: 1,
+                access: ExitAccess::Write(&[7]),
+            },
+        );
+        assert_eq!(scratchpad.contents(), [0, 7, 0, 0]);
+    }
+}
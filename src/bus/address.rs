@@ -1,9 +1,9 @@
 // Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
-use std::cmp::Ordering;
-use std::convert::TryFrom;
-use std::ops::{Add, Sub};
+use core::cmp::Ordering;
+use core::convert::TryFrom;
+use core::ops::{Add, Sub};
 
 /// This trait defines the operations we expect to apply to bus address values.
 pub trait BusAddress:
@@ -17,6 +17,7 @@ pub trait BusAddress:
     type V: Add<Output = Self::V>
         + Copy
         + From<u8>
+        + Into<u64>
         + PartialEq
         + Ord
         + Sub<Output = Self::V>
@@ -28,6 +29,13 @@ pub trait BusAddress:
     /// Return the bus address computed by offsetting `self` by the specified value, if no
     /// overflow occurs.
     fn checked_add(&self, value: Self::V) -> Option<Self>;
+
+    /// Return whether the address is a multiple of `alignment`.
+    fn is_aligned(&self, alignment: Self::V) -> bool;
+
+    /// Return the smallest multiple of `alignment` greater than or equal to the address, if
+    /// `alignment` isn't zero and no overflow occurs.
+    fn checked_align_up(&self, alignment: Self::V) -> Option<Self>;
 }
 
 /// Represents a MMIO address offset.
@@ -35,18 +43,66 @@ pub type MmioAddressOffset = u64;
 
 /// Represents a MMIO address.
 #[derive(Clone, Copy, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(transparent)
+)]
 pub struct MmioAddress(pub MmioAddressOffset);
 
+/// Represents an offset in a 32 bit MMIO address space.
+pub type Mmio32AddressOffset = u32;
+
+/// Represents an address in a 32 bit MMIO address space.
+///
+/// Buses and ranges of `Mmio32Address`es take half the memory of their [`MmioAddress`]
+/// counterparts, for platforms whose guests only have 32 bit physical addresses. The
+/// addresses convert to `MmioAddress`es to access the devices.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(transparent)
+)]
+pub struct Mmio32Address(pub Mmio32AddressOffset);
+
 /// Represents a PIO address offset.
 pub type PioAddressOffset = u16;
 
 /// Represents a PIO address.
 #[derive(Clone, Copy, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(transparent)
+)]
 pub struct PioAddress(pub PioAddressOffset);
 
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for MmioAddress {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        u.arbitrary().map(MmioAddress)
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for Mmio32Address {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        u.arbitrary().map(Mmio32Address)
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for PioAddress {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        u.arbitrary().map(PioAddress)
+    }
+}
+
 // Implementing `BusAddress` and its prerequisites for `MmioAddress`.
 
 impl PartialEq for MmioAddress {
+    #[inline]
     fn eq(&self, other: &Self) -> bool {
         self.0 == other.0
     }
@@ -55,12 +111,14 @@ impl PartialEq for MmioAddress {
 impl Eq for MmioAddress {}
 
 impl PartialOrd for MmioAddress {
+    #[inline]
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        self.0.partial_cmp(&other.0)
+        Some(self.cmp(other))
     }
 }
 
 impl Ord for MmioAddress {
+    #[inline]
     fn cmp(&self, other: &Self) -> Ordering {
         self.0.cmp(&other.0)
     }
@@ -69,6 +127,7 @@ impl Ord for MmioAddress {
 impl Add<MmioAddressOffset> for MmioAddress {
     type Output = Self;
 
+    #[inline]
     fn add(self, rhs: MmioAddressOffset) -> Self::Output {
         MmioAddress(self.0 + rhs)
     }
@@ -77,6 +136,7 @@ impl Add<MmioAddressOffset> for MmioAddress {
 impl Sub for MmioAddress {
     type Output = MmioAddressOffset;
 
+    #[inline]
     fn sub(self, rhs: Self) -> Self::Output {
         self.0 - rhs.0
     }
@@ -85,18 +145,115 @@ impl Sub for MmioAddress {
 impl BusAddress for MmioAddress {
     type V = MmioAddressOffset;
 
+    #[inline]
     fn value(&self) -> Self::V {
         self.0
     }
 
+    #[inline]
     fn checked_add(&self, value: Self::V) -> Option<Self> {
         self.0.checked_add(value).map(MmioAddress)
     }
+
+    #[inline]
+    fn is_aligned(&self, alignment: Self::V) -> bool {
+        self.0.is_multiple_of(alignment)
+    }
+
+    fn checked_align_up(&self, alignment: Self::V) -> Option<Self> {
+        self.0.checked_next_multiple_of(alignment).map(MmioAddress)
+    }
+}
+
+// Implementing `BusAddress` and its prerequisites for `Mmio32Address`.
+
+impl PartialEq for Mmio32Address {
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl Eq for Mmio32Address {}
+
+impl PartialOrd for Mmio32Address {
+    #[inline]
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Mmio32Address {
+    #[inline]
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.cmp(&other.0)
+    }
+}
+
+impl Add<Mmio32AddressOffset> for Mmio32Address {
+    type Output = Self;
+
+    #[inline]
+    fn add(self, rhs: Mmio32AddressOffset) -> Self::Output {
+        Mmio32Address(self.0 + rhs)
+    }
+}
+
+impl Sub for Mmio32Address {
+    type Output = Mmio32AddressOffset;
+
+    #[inline]
+    fn sub(self, rhs: Self) -> Self::Output {
+        self.0 - rhs.0
+    }
+}
+
+impl BusAddress for Mmio32Address {
+    type V = Mmio32AddressOffset;
+
+    #[inline]
+    fn value(&self) -> Self::V {
+        self.0
+    }
+
+    #[inline]
+    fn checked_add(&self, value: Self::V) -> Option<Self> {
+        self.0.checked_add(value).map(Mmio32Address)
+    }
+
+    #[inline]
+    fn is_aligned(&self, alignment: Self::V) -> bool {
+        self.0.is_multiple_of(alignment)
+    }
+
+    fn checked_align_up(&self, alignment: Self::V) -> Option<Self> {
+        self.0
+            .checked_next_multiple_of(alignment)
+            .map(Mmio32Address)
+    }
+}
+
+impl From<Mmio32Address> for MmioAddress {
+    #[inline]
+    fn from(addr: Mmio32Address) -> Self {
+        MmioAddress(addr.0.into())
+    }
+}
+
+impl TryFrom<MmioAddress> for Mmio32Address {
+    type Error = core::num::TryFromIntError;
+
+    /// Fails if the address is above 4 GiB.
+    #[inline]
+    fn try_from(addr: MmioAddress) -> Result<Self, Self::Error> {
+        u32::try_from(addr.0).map(Mmio32Address)
+    }
 }
 
 // Implementing `BusAddress` and its prerequisites for `PioAddress`.
 
 impl PartialEq for PioAddress {
+    #[inline]
     fn eq(&self, other: &Self) -> bool {
         self.0 == other.0
     }
@@ -105,12 +262,14 @@ impl PartialEq for PioAddress {
 impl Eq for PioAddress {}
 
 impl PartialOrd for PioAddress {
+    #[inline]
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        self.0.partial_cmp(&other.0)
+        Some(self.cmp(other))
     }
 }
 
 impl Ord for PioAddress {
+    #[inline]
     fn cmp(&self, other: &Self) -> Ordering {
         self.0.cmp(&other.0)
     }
@@ -119,6 +278,7 @@ impl Ord for PioAddress {
 impl Add<PioAddressOffset> for PioAddress {
     type Output = Self;
 
+    #[inline]
     fn add(self, rhs: PioAddressOffset) -> Self::Output {
         PioAddress(self.0 + rhs)
     }
@@ -127,6 +287,7 @@ impl Add<PioAddressOffset> for PioAddress {
 impl Sub for PioAddress {
     type Output = PioAddressOffset;
 
+    #[inline]
     fn sub(self, rhs: Self) -> Self::Output {
         self.0 - rhs.0
     }
@@ -135,13 +296,24 @@ impl Sub for PioAddress {
 impl BusAddress for PioAddress {
     type V = PioAddressOffset;
 
+    #[inline]
     fn value(&self) -> Self::V {
         self.0
     }
 
+    #[inline]
     fn checked_add(&self, value: Self::V) -> Option<Self> {
         self.0.checked_add(value).map(PioAddress)
     }
+
+    #[inline]
+    fn is_aligned(&self, alignment: Self::V) -> bool {
+        self.0.is_multiple_of(alignment)
+    }
+
+    fn checked_align_up(&self, alignment: Self::V) -> Option<Self> {
+        self.0.checked_next_multiple_of(alignment).map(PioAddress)
+    }
 }
 
 #[cfg(test)]
@@ -168,11 +340,28 @@ mod tests {
 
         let addr_max = addr_zero.checked_add(max_value).unwrap();
         assert!(addr_max.checked_add(A::V::from(1)).is_none());
+
+        assert!(addr_zero.is_aligned(A::V::from(4)));
+        assert!(!addr.is_aligned(A::V::from(4)));
+        assert!(addr.is_aligned(A::V::from(5)));
+        assert!(!addr_max.is_aligned(A::V::from(2)));
     }
 
     #[test]
     fn test_address_ops() {
-        check_bus_address_ops(MmioAddress(0), std::u64::MAX);
-        check_bus_address_ops(PioAddress(0), std::u16::MAX);
+        check_bus_address_ops(MmioAddress(0), u64::MAX);
+        check_bus_address_ops(Mmio32Address(0), u32::MAX);
+        check_bus_address_ops(PioAddress(0), u16::MAX);
+    }
+
+    #[test]
+    fn test_mmio32_address() {
+        let addr = Mmio32Address(0xfeed_0000);
+        assert_eq!(MmioAddress::from(addr), MmioAddress(0xfeed_0000));
+        assert_eq!(
+            Mmio32Address::try_from(MmioAddress(0xffff_ffff)).unwrap().0,
+            u32::MAX
+        );
+        assert!(Mmio32Address::try_from(MmioAddress(1 << 32)).is_err());
     }
 }
@@ -7,56 +7,239 @@
 //! disjoint intervals (ranges) from an address space and objects (devices) associated with them.
 //! A single device can be registered with multiple ranges, but no two ranges can overlap,
 //! regardless with their device associations.
+//!
+//! The MMIO addresses are 64 bit wide. Platforms whose guests only have 32 bit physical
+//! addresses can use a [`Mmio32Bus`] instead, whose ranges take half the memory, and widen
+//! the addresses to [`MmioAddress`]es to access the devices:
+//!
+//! ```
+//! # use std::convert::TryFrom;
+//! # use std::sync::Arc;
+//! # use vm_device::bus::MmioAddressOffset;
+//! use vm_device::bus::{Mmio32Address, Mmio32Bus, Mmio32Range, MmioAddress};
+//! use vm_device::DeviceMmio;
+//!
+//! struct Dummy;
+//!
+//! impl DeviceMmio for Dummy {
+//!     # fn mmio_read(&self, _: MmioAddress, _: MmioAddressOffset, _: &mut [u8]) {}
+//!     # fn mmio_write(&self, _: MmioAddress, _: MmioAddressOffset, _: &[u8]) {}
+//!     // ...
+//! }
+//!
+//! let mut bus: Mmio32Bus<Arc<dyn DeviceMmio>> = Mmio32Bus::new();
+//! let range = Mmio32Range::new(Mmio32Address(0x900_0000), 0x1000).unwrap();
+//! bus.register(range, Arc::new(Dummy)).unwrap();
+//!
+//! // An exit reported with a 64 bit address.
+//! let addr = Mmio32Address::try_from(MmioAddress(0x900_0010)).unwrap();
+//! let mut data = [0; 4];
+//! let (range, device) = bus.check_access(addr, data.len()).unwrap();
+//! let offset = addr - range.base();
+//! device.mmio_read(range.base().into(), offset.into(), &mut data);
+//! ```
 
 mod address;
 mod range;
-
-use std::collections::BTreeMap;
-use std::convert::TryFrom;
-use std::fmt::{Display, Formatter};
-use std::result::Result;
-
-use address::BusAddress;
-
-pub use address::{MmioAddress, MmioAddressOffset, PioAddress, PioAddressOffset};
-pub use range::{BusRange, MmioRange, PioRange};
+#[cfg(feature = "std")]
+mod sharded;
+mod storage;
+
+use core::convert::TryFrom;
+use core::fmt::{Debug, Display, Formatter};
+use core::marker::PhantomData;
+use core::result::Result;
+
+use crate::sync::{self, Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+pub(crate) use address::BusAddress;
+
+pub use address::{
+    Mmio32Address, Mmio32AddressOffset, MmioAddress, MmioAddressOffset, PioAddress,
+    PioAddressOffset,
+};
+pub use range::{BusRange, BusRangeBuilder, Mmio32Range, MmioRange, PioRange};
+#[cfg(feature = "std")]
+pub use sharded::{ShardStats, ShardedBus};
+#[cfg(feature = "std")]
+pub use storage::PageIndex;
+pub use storage::{IntervalTree, SortedVec, Storage};
 
 /// Errors encountered during bus operations.
-#[derive(Debug, Eq, PartialEq)]
+///
+/// Addresses are widened to `u64`, so the errors of the PIO and MMIO buses share a type.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum Error {
-    /// No device is associated with the specified address or range.
-    DeviceNotFound,
+    /// No device is associated with the specified address, or the access doesn't fit within
+    /// the range of the device.
+    DeviceNotFound {
+        /// Address of the access.
+        addr: u64,
+        /// Length of the access.
+        len: usize,
+    },
     /// Specified range overlaps an already registered range.
-    DeviceOverlap,
+    DeviceOverlap {
+        /// Base address of the rejected range.
+        base: u64,
+        /// Size of the rejected range.
+        size: u64,
+    },
     /// Access with invalid length attempted.
-    InvalidAccessLength(usize),
+    InvalidAccessLength {
+        /// Address of the access.
+        addr: u64,
+        /// Length of the access.
+        len: usize,
+    },
     /// Invalid range provided (either zero-sized, or last address overflows).
-    InvalidRange,
+    InvalidRange {
+        /// Base address of the range.
+        base: u64,
+        /// Size of the range.
+        size: u64,
+    },
+    /// The base address of a range isn't a multiple of the required alignment, or the
+    /// alignment is zero.
+    MisalignedRange {
+        /// Base address of the range.
+        base: u64,
+        /// Required alignment.
+        align: u64,
+    },
+}
+
+impl Error {
+    pub(crate) fn not_found<A: BusAddress>(addr: A, len: usize) -> Self {
+        Error::DeviceNotFound {
+            addr: addr.value().into(),
+            len,
+        }
+    }
+
+    pub(crate) fn overlap<A: BusAddress>(range: &BusRange<A>) -> Self {
+        Error::DeviceOverlap {
+            base: range.base().value().into(),
+            size: range.size().into(),
+        }
+    }
+
+    pub(crate) fn invalid_range<A: BusAddress>(base: A, size: A::V) -> Self {
+        Error::InvalidRange {
+            base: base.value().into(),
+            size: size.into(),
+        }
+    }
 }
 
+// Written by hand rather than derived, like the other error types of the crate: the bus is
+// part of the `no_std` build, where `std::error::Error` isn't implemented, and a derive
+// would pull a proc-macro dependency into it for the sake of one enum.
 impl Display for Error {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
         match self {
-            Error::DeviceNotFound => write!(f, "device not found"),
-            Error::DeviceOverlap => write!(f, "range overlaps with existing device"),
-            Error::InvalidAccessLength(len) => write!(f, "invalid access length ({})", len),
-            Error::InvalidRange => write!(f, "invalid range provided"),
+            Error::DeviceNotFound { addr, len } => {
+                write!(f, "device not found at {:#x} (access length {})", addr, len)
+            }
+            Error::DeviceOverlap { base, size } => write!(
+                f,
+                "range at {:#x} of size {:#x} overlaps with existing device",
+                base, size
+            ),
+            Error::InvalidAccessLength { addr, len } => {
+                write!(f, "invalid access length ({}) at {:#x}", len, addr)
+            }
+            Error::InvalidRange { base, size } => write!(
+                f,
+                "invalid range provided (base {:#x}, size {:#x})",
+                base, size
+            ),
+            Error::MisalignedRange { base, align } => {
+                write!(f, "range at {:#x} is not aligned to {:#x}", base, align)
+            }
         }
     }
 }
 
+#[cfg(feature = "std")]
 impl std::error::Error for Error {}
 
+/// Marks an access in flight on a [`Bus`].
+///
+/// Obtained with [`Bus::begin_access`]; the access is considered complete when the guard
+/// is dropped.
+pub struct AccessGuard<'a> {
+    _gate: RwLockReadGuard<'a, ()>,
+}
+
+/// Keeps a [`Bus`] quiesced.
+///
+/// Obtained with [`Bus::quiesce`]; accesses are allowed to start again when the guard is
+/// dropped.
+pub struct QuiesceGuard<'a> {
+    _gate: RwLockWriteGuard<'a, ()>,
+}
+
 /// A bus that's agnostic to the range address type and device type.
-pub struct Bus<A: BusAddress, D> {
-    devices: BTreeMap<BusRange<A>, D>,
+///
+/// The ranges are kept in a [`SortedVec`] by default, which suits the few dozen devices
+/// buses usually hold. Buses holding hundreds of ranges can use an [`IntervalTree`] instead:
+///
+/// ```
+/// use vm_device::bus::{Bus, IntervalTree, MmioAddress, MmioRange};
+///
+/// let mut bus = Bus::<MmioAddress, u32, IntervalTree<_, _>>::default();
+/// for idx in 0..512 {
+///     let range = MmioRange::new(MmioAddress(idx * 0x1000), 0x100).unwrap();
+///     bus.register(range, idx as u32).unwrap();
+/// }
+/// assert_eq!(bus.device(MmioAddress(0x3_0010)).unwrap().1, &0x30);
+/// ```
+///
+/// MMIO buses whose devices each sit alone in a page, such as virtio-mmio transports, can use
+/// a [`PageIndex`], which finds them in constant time.
+pub struct Bus<A: BusAddress, D, S: Storage<A, D> = SortedVec<A, D>> {
+    devices: S,
+    // Held for reading while an access is in flight, and for writing while the bus is
+    // quiesced. The lock protects no data, so poisoning is ignored. Clones of the bus share
+    // the lock.
+    gate: Arc<RwLock<()>>,
+    _marker: PhantomData<fn() -> (A, D)>,
 }
 
-impl<A: BusAddress, D> Default for Bus<A, D> {
+impl<A: BusAddress, D, S: Storage<A, D>> Default for Bus<A, D, S> {
     fn default() -> Self {
         Bus {
-            devices: BTreeMap::new(),
+            devices: S::default(),
+            gate: Arc::new(RwLock::new(())),
+            _marker: PhantomData,
+        }
+    }
+}
+
+/// Clones share the quiesce state of the original bus: quiescing one of them waits for the
+/// accesses in flight on all of them, and stalls new ones on all of them. This lets a copy
+/// of the bus be updated and swapped in while accesses are still dispatched through the
+/// original.
+impl<A: BusAddress, D, S: Storage<A, D> + Clone> Clone for Bus<A, D, S> {
+    fn clone(&self) -> Self {
+        Bus {
+            devices: self.devices.clone(),
+            gate: self.gate.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+/// Summarizes the registered ranges, without requiring the devices to implement `Debug`.
+impl<A: BusAddress, D, S: Storage<A, D>> Debug for Bus<A, D, S> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.write_str("Bus ")?;
+        let mut ranges = f.debug_list();
+        for (range, _) in self.devices.iter() {
+            ranges.entry(&format_args!("{}", range));
         }
+        ranges.finish()
     }
 }
 
@@ -65,62 +248,100 @@ impl<A: BusAddress, D> Bus<A, D> {
     pub fn new() -> Self {
         Self::default()
     }
+}
 
+impl<A: BusAddress, D, S: Storage<A, D>> Bus<A, D, S> {
     /// Return the registered range and device associated with `addr`.
+    #[inline]
     pub fn device(&self, addr: A) -> Option<(&BusRange<A>, &D)> {
         // The range is returned as an optimization because the caller
         // might need both the device and its associated bus range.
         // The same goes for the device_mut() method.
-        self.devices
-            .range(..=BusRange::unit(addr))
-            .nth_back(0)
-            .filter(|pair| pair.0.last() >= addr)
+        self.devices.get(addr)
     }
 
     /// Return the registered range and a mutable reference to the device
     /// associated with `addr`.
     pub fn device_mut(&mut self, addr: A) -> Option<(&BusRange<A>, &mut D)> {
-        self.devices
-            .range_mut(..=BusRange::unit(addr))
-            .nth_back(0)
-            .filter(|pair| pair.0.last() >= addr)
+        self.devices.get_mut(addr)
     }
 
     /// Register a device with the provided range.
     pub fn register(&mut self, range: BusRange<A>, device: D) -> Result<(), Error> {
-        for r in self.devices.keys() {
-            if range.overlaps(r) {
-                return Err(Error::DeviceOverlap);
-            }
-        }
-
-        self.devices.insert(range, device);
-
-        Ok(())
+        self.devices.insert(range, device)
     }
 
     /// Deregister the device associated with `addr`.
     pub fn deregister(&mut self, addr: A) -> Option<(BusRange<A>, D)> {
-        let range = self.device(addr).map(|(range, _)| *range)?;
-        self.devices.remove(&range).map(|device| (range, device))
+        self.devices.remove(addr)
+    }
+
+    /// Reserve room for registering at least `additional` more ranges, so that registering
+    /// them doesn't allocate (see [`Storage::reserve`]).
+    pub fn reserve(&mut self, additional: usize) {
+        self.devices.reserve(additional);
+    }
+
+    /// Return an iterator over the registered ranges and their associated devices, in
+    /// ascending address order.
+    pub fn iter(&self) -> impl Iterator<Item = (&BusRange<A>, &D)> {
+        self.devices.iter()
+    }
+
+    /// Mark the beginning of an access to one of the devices on the bus.
+    ///
+    /// Blocks while the bus is quiesced. The returned guard must be held until the device
+    /// has finished handling the access.
+    #[inline]
+    pub fn begin_access(&self) -> AccessGuard<'_> {
+        AccessGuard {
+            _gate: sync::read(&self.gate),
+        }
+    }
+
+    /// Quiesce the bus.
+    ///
+    /// Waits for all the accesses in flight (i.e. started with [`Bus::begin_access`]) to
+    /// complete, and keeps new ones from starting until the returned guard is dropped.
+    /// Calling this while holding an [`AccessGuard`] of the same bus deadlocks.
+    pub fn quiesce(&self) -> QuiesceGuard<'_> {
+        QuiesceGuard {
+            _gate: sync::write(&self.gate),
+        }
     }
 
     /// Verify whether an access starting at `addr` with length `len` fits within any of
     /// the registered ranges. Return the range and a handle to the device when present.
+    #[inline]
     pub fn check_access(&self, addr: A, len: usize) -> Result<(&BusRange<A>, &D), Error> {
+        // Fast path for aligned 32-bit accesses, which make up most of the accesses to device
+        // registers. An aligned access can't overflow the address space, so it ends exactly
+        // 3 bytes after `addr`.
+        if len == 4 && addr.is_aligned(4.into()) {
+            let last = addr + 3.into();
+            return self
+                .device(addr)
+                .filter(|(range, _)| range.last() >= last)
+                .ok_or_else(|| Error::not_found(addr, len));
+        }
+
         let access_range = BusRange::new(
             addr,
-            A::V::try_from(len).map_err(|_| Error::InvalidAccessLength(len))?,
-        )
-        .map_err(|_| Error::InvalidRange)?;
+            A::V::try_from(len).map_err(|_| Error::InvalidAccessLength {
+                addr: addr.value().into(),
+                len,
+            })?,
+        )?;
         self.device(addr)
             .filter(|(range, _)| range.last() >= access_range.last())
-            .ok_or(Error::DeviceNotFound)
+            .ok_or_else(|| Error::not_found(addr, len))
     }
 }
 
 /// Represents an MMIO bus.
 pub type MmioBus<D> = Bus<MmioAddress, D>;
+/// Represents a 32 bit MMIO bus.
+pub type Mmio32Bus<D> = Bus<Mmio32Address, D>;
 /// Represents a PIO bus.
 pub type PioBus<D> = Bus<PioAddress, D>;
 
@@ -134,6 +355,14 @@ pub trait BusManager<A: BusAddress> {
 
     /// Return a mutable reference to the bus.
     fn bus_mut(&mut self) -> &mut Bus<A, Self::D>;
+
+    /// Called after `range` is registered through the `PioManager` or `MmioManager`
+    /// implementation provided for the type. Does nothing by default.
+    fn registered(&self, _range: BusRange<A>) {}
+
+    /// Called after `range` is deregistered through the `PioManager` or `MmioManager`
+    /// implementation provided for the type. Does nothing by default.
+    fn deregistered(&self, _range: BusRange<A>) {}
 }
 
 #[cfg(test)]
@@ -156,6 +385,8 @@ mod test {
 
         bus.register(range, device).unwrap();
         assert_eq!(bus.devices.len(), 1);
+        assert_eq!(bus.iter().collect::<Vec<_>>(), vec![(&range, &device)]);
+        assert_eq!(format!("{:?}", bus), "Bus [0xa-0x13]");
 
         assert!(bus.device(base_prev).is_none());
         assert!(bus.device_mut(base_prev).is_none());
@@ -190,7 +421,7 @@ mod test {
         }
 
         // Detect double registration with the same range.
-        assert_eq!(bus.register(range, device), Err(Error::DeviceOverlap));
+        assert_eq!(bus.register(range, device), Err(Error::overlap(&range)));
 
         // We detect overlaps even if it's another range associated with the same device (we don't
         // implicitly merge ranges). `check_access` fails if the specified range does not fully
@@ -198,28 +429,31 @@ mod test {
 
         {
             let range2 = MmioRange::new(MmioAddress(1), 10).unwrap();
-            assert_eq!(bus.register(range2, device), Err(Error::DeviceOverlap));
+            let len = usize::try_from(range2.size()).unwrap();
+            assert_eq!(bus.register(range2, device), Err(Error::overlap(&range2)));
             assert_eq!(
-                bus.check_access(range2.base(), usize::try_from(range2.size()).unwrap()),
-                Err(Error::DeviceNotFound)
+                bus.check_access(range2.base(), len),
+                Err(Error::not_found(range2.base(), len))
             );
         }
 
         {
             let range2 = MmioRange::new(range.last(), 10).unwrap();
-            assert_eq!(bus.register(range2, device), Err(Error::DeviceOverlap));
+            let len = usize::try_from(range2.size()).unwrap();
+            assert_eq!(bus.register(range2, device), Err(Error::overlap(&range2)));
             assert_eq!(
-                bus.check_access(range2.base(), usize::try_from(range2.size()).unwrap()),
-                Err(Error::DeviceNotFound)
+                bus.check_access(range2.base(), len),
+                Err(Error::not_found(range2.base(), len))
             );
         }
 
         {
             let range2 = MmioRange::new(MmioAddress(1), range.last().value() + 100).unwrap();
-            assert_eq!(bus.register(range2, device), Err(Error::DeviceOverlap));
+            let len = usize::try_from(range2.size()).unwrap();
+            assert_eq!(bus.register(range2, device), Err(Error::overlap(&range2)));
             assert_eq!(
-                bus.check_access(range2.base(), usize::try_from(range2.size()).unwrap()),
-                Err(Error::DeviceNotFound)
+                bus.check_access(range2.base(), len),
+                Err(Error::not_found(range2.base(), len))
             );
         }
 
@@ -229,9 +463,10 @@ mod test {
 
             let range2 = MmioRange::new(range.last().checked_add(1).unwrap(), 5).unwrap();
 
+            let len = usize::try_from(range2.size()).unwrap();
             assert_eq!(
-                bus.check_access(range2.base(), usize::try_from(range2.size()).unwrap()),
-                Err(Error::DeviceNotFound)
+                bus.check_access(range2.base(), len),
+                Err(Error::not_found(range2.base(), len))
             );
 
             // Validate registration, and that `deregister` works for all addresses within a range.
@@ -258,12 +493,19 @@ mod test {
             // Even though the new range is associated with the same device, and right after the
             // previous one, accesses across multiple ranges are not allowed for now.
             // TODO: Do we want to support this in the future?
+            let len = usize::try_from(range.size() + 1).unwrap();
             assert_eq!(
-                bus.check_access(range.base(), usize::try_from(range.size() + 1).unwrap()),
-                Err(Error::DeviceNotFound)
+                bus.check_access(range.base(), len),
+                Err(Error::not_found(range.base(), len))
             );
         }
 
+        // The bus can be quiesced again once the previous guard is dropped, and accesses
+        // can start once it's no longer quiesced.
+        drop(bus.quiesce());
+        drop(bus.quiesce());
+        drop(bus.begin_access());
+
         // Ensure that bus::check_access() fails when the len argument
         // cannot be safely converted to PioAddressOffset which is u16.
         let pio_base = PioAddress(10);
@@ -274,7 +516,132 @@ mod test {
         pio_bus.register(pio_range, pio_device).unwrap();
         assert_eq!(
             pio_bus.check_access(pio_base, usize::MAX),
-            Err(Error::InvalidAccessLength(usize::MAX))
+            Err(Error::InvalidAccessLength {
+                addr: 10,
+                len: usize::MAX
+            })
         );
     }
+
+    #[test]
+    fn test_bus_aligned_access() {
+        let mut bus = Bus::new();
+        bus.register(MmioRange::new(MmioAddress(0x1000), 6).unwrap(), 1u8)
+            .unwrap();
+        let end = MmioRange::new(MmioAddress(u64::MAX - 7), 8).unwrap();
+        bus.register(end, 2).unwrap();
+
+        assert_eq!(bus.check_access(MmioAddress(0x1000), 4).unwrap().1, &1);
+        // The access goes past the end of the range.
+        assert_eq!(
+            bus.check_access(MmioAddress(0x1004), 4),
+            Err(Error::DeviceNotFound {
+                addr: 0x1004,
+                len: 4
+            })
+        );
+        // Unaligned accesses take the general path.
+        assert_eq!(bus.check_access(MmioAddress(0x1002), 4).unwrap().1, &1);
+        assert_eq!(
+            bus.check_access(MmioAddress(0x1003), 4),
+            Err(Error::DeviceNotFound {
+                addr: 0x1003,
+                len: 4
+            })
+        );
+
+        // Aligned accesses at the end of the address space don't overflow.
+        assert_eq!(
+            bus.check_access(MmioAddress(u64::MAX - 3), 4).unwrap().1,
+            &2
+        );
+        assert_eq!(
+            bus.check_access(MmioAddress(u64::MAX - 1), 4),
+            Err(Error::InvalidRange {
+                base: u64::MAX - 1,
+                size: 4
+            })
+        );
+    }
+    #[test]
+    fn test_bus_order() {
+        check_bus_order::<SortedVec<_, _>>();
+        check_bus_order::<IntervalTree<_, _>>();
+        check_bus_order::<PageIndex<_>>();
+    }
+
+    fn check_bus_order<S: Storage<MmioAddress, u8>>() {
+        let mut bus = Bus::<_, _, S>::default();
+        for &(base, device) in [(0x300u64, 3u8), (0x100, 1), (0x200, 2)].iter() {
+            bus.register(MmioRange::new(MmioAddress(base), 0x80).unwrap(), device)
+                .unwrap();
+        }
+        let devices: Vec<_> = bus.iter().map(|(_, device)| *device).collect();
+        assert_eq!(devices, [1, 2, 3]);
+
+        // Overlaps with the following range and the preceding one, respectively.
+        let range = MmioRange::new(MmioAddress(0xc0), 0x80).unwrap();
+        assert_eq!(bus.register(range, 0), Err(Error::overlap(&range)));
+        let range = MmioRange::new(MmioAddress(0x27f), 0x10).unwrap();
+        assert_eq!(bus.register(range, 0), Err(Error::overlap(&range)));
+
+        // Fits in the gap between two ranges.
+        let range = MmioRange::new(MmioAddress(0x180), 0x80).unwrap();
+        bus.register(range, 4).unwrap();
+        assert_eq!(bus.device(MmioAddress(0x1ff)).unwrap().1, &4);
+        assert_eq!(bus.device(MmioAddress(0x200)).unwrap().1, &2);
+        assert!(bus.device(MmioAddress(0x2ff)).is_none());
+        assert!(bus.device(MmioAddress(0xff)).is_none());
+
+        *bus.device_mut(MmioAddress(0x310)).unwrap().1 = 5;
+        assert_eq!(bus.deregister(MmioAddress(0x37f)).unwrap().1, 5);
+        let devices: Vec<_> = bus.iter().map(|(_, device)| *device).collect();
+        assert_eq!(devices, [1, 4, 2]);
+    }
+
+    #[test]
+    fn test_mmio32_bus() {
+        assert_eq!(core::mem::size_of::<Mmio32Range>(), 8);
+
+        let mut bus = Mmio32Bus::new();
+        let range = Mmio32Range::new(Mmio32Address(0xffff_f000), 0x1000).unwrap();
+        bus.register(range, 1u8).unwrap();
+        let (found, device) = bus.check_access(Mmio32Address(0xffff_fffc), 4).unwrap();
+        assert_eq!((*found, *device), (range, 1));
+        assert!(bus.check_access(Mmio32Address(0xffff_fffc), 8).is_err());
+        // The address space ends at 4 GiB.
+        assert!(Mmio32Range::new(Mmio32Address(0xffff_f000), 0x1001).is_err());
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::*;
+
+    use loom::sync::atomic::{AtomicBool, Ordering};
+    use loom::sync::Arc;
+    use loom::thread;
+
+    #[test]
+    fn loom_quiesce_waits_for_accesses() {
+        loom::model(|| {
+            let bus = Arc::new(Bus::<MmioAddress, ()>::new());
+            let in_flight = Arc::new(AtomicBool::new(false));
+
+            let accessor = {
+                let (bus, in_flight) = (bus.clone(), in_flight.clone());
+                thread::spawn(move || {
+                    let _access = bus.begin_access();
+                    in_flight.store(true, Ordering::Relaxed);
+                    in_flight.store(false, Ordering::Relaxed);
+                })
+            };
+
+            {
+                let _quiesced = bus.quiesce();
+                assert!(!in_flight.load(Ordering::Relaxed));
+            }
+            accessor.join().unwrap();
+        });
+    }
 }
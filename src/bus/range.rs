@@ -1,9 +1,11 @@
 // Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
-use std::cmp::Ordering;
+use core::cmp::Ordering;
+#[cfg(feature = "std")]
+use core::convert::TryFrom;
 
-use crate::bus::{BusAddress, Error, MmioAddress, PioAddress};
+use crate::bus::{BusAddress, Error, Mmio32Address, MmioAddress, PioAddress};
 
 /// An interval in the address space of a bus.
 #[derive(Copy, Clone, Debug)]
@@ -14,20 +16,40 @@ pub struct BusRange<A: BusAddress> {
 
 impl<A: BusAddress> BusRange<A> {
     /// Create a new range while checking for overflow.
+    #[inline]
     pub fn new(base: A, size: A::V) -> Result<Self, Error> {
         // A zero-length range is not valid.
         if size == 0.into() {
-            return Err(Error::InvalidRange);
+            return Err(Error::invalid_range(base, size));
         }
 
         // Subtracting one, because a range that ends at the very edge of the address space
         // is still valid.
         base.checked_add(size - 1.into())
-            .ok_or(Error::InvalidRange)?;
+            .ok_or_else(|| Error::invalid_range(base, size))?;
 
         Ok(BusRange { base, size })
     }
 
+    /// Return a builder for a range whose base address is aligned, e.g. to the page size for
+    /// a virtio-mmio window.
+    ///
+    /// ```
+    /// use vm_device::bus::{MmioAddress, MmioRange};
+    ///
+    /// let range = MmioRange::builder()
+    ///     .base(MmioAddress(0xd000_0010))
+    ///     .size(0x1000)
+    ///     .align(0x1000)
+    ///     .align_base_up()
+    ///     .build()
+    ///     .unwrap();
+    /// assert_eq!(range.base(), MmioAddress(0xd000_1000));
+    /// ```
+    pub fn builder() -> BusRangeBuilder<A> {
+        BusRangeBuilder::default()
+    }
+
     /// Create a new unit range (its size equals `1`).
     pub fn unit(base: A) -> Self {
         BusRange {
@@ -37,6 +59,7 @@ impl<A: BusAddress> BusRange<A> {
     }
 
     /// Return the base address of this range.
+    #[inline]
     pub fn base(&self) -> A {
         self.base
     }
@@ -47,6 +70,7 @@ impl<A: BusAddress> BusRange<A> {
     }
 
     /// Return the last bus address that's still part of the range.
+    #[inline]
     pub fn last(&self) -> A {
         self.base + (self.size - 1.into())
     }
@@ -55,9 +79,112 @@ impl<A: BusAddress> BusRange<A> {
     pub fn overlaps(&self, other: &BusRange<A>) -> bool {
         !(self.base > other.last() || self.last() < other.base)
     }
+
+    // Check whether an access of `len` bytes starting at `addr` falls within the range.
+    #[cfg(feature = "std")]
+    #[inline]
+    pub(crate) fn covers(&self, addr: A, len: usize) -> bool {
+        A::V::try_from(len)
+            .ok()
+            .and_then(|len| BusRange::new(addr, len).ok())
+            .is_some_and(|access| addr >= self.base && access.last() <= self.last())
+    }
+}
+
+/// Builder of [`BusRange`] values checking the alignment of the base address.
+///
+/// Obtained with [`BusRange::builder`]. The range is validated by [`BusRangeBuilder::build`]:
+/// building it without a base address or a size fails with [`Error::InvalidRange`], like a
+/// zero-sized range.
+#[derive(Clone, Copy, Debug)]
+pub struct BusRangeBuilder<A: BusAddress> {
+    base: Option<A>,
+    size: Option<A::V>,
+    align: Option<A::V>,
+    align_base_up: bool,
+}
+
+impl<A: BusAddress> Default for BusRangeBuilder<A> {
+    fn default() -> Self {
+        BusRangeBuilder {
+            base: None,
+            size: None,
+            align: None,
+            align_base_up: false,
+        }
+    }
+}
+
+impl<A: BusAddress> BusRangeBuilder<A> {
+    /// Set the base address of the range.
+    pub fn base(mut self, base: A) -> Self {
+        self.base = Some(base);
+        self
+    }
+
+    /// Set the size of the range.
+    pub fn size(mut self, size: A::V) -> Self {
+        self.size = Some(size);
+        self
+    }
+
+    /// Require the base address to be a multiple of `align`.
+    pub fn align(mut self, align: A::V) -> Self {
+        self.align = Some(align);
+        self
+    }
+
+    /// Move a misaligned base address up to the next multiple of the alignment, instead of
+    /// rejecting it.
+    pub fn align_base_up(mut self) -> Self {
+        self.align_base_up = true;
+        self
+    }
+
+    /// Build the range.
+    ///
+    /// Fails with [`Error::MisalignedRange`] when the alignment is zero or the base address
+    /// isn't aligned, and with [`Error::InvalidRange`] when the range is empty or overflows
+    /// the address space, including after moving its base address up.
+    pub fn build(self) -> Result<BusRange<A>, Error> {
+        let size = self.size.unwrap_or_else(|| 0.into());
+        let mut base = self.base.ok_or(Error::InvalidRange {
+            base: 0,
+            size: size.into(),
+        })?;
+        if let Some(align) = self.align {
+            let misaligned = Error::MisalignedRange {
+                base: base.value().into(),
+                align: align.into(),
+            };
+            if align == 0.into() {
+                return Err(misaligned);
+            }
+            if self.align_base_up {
+                base = base
+                    .checked_align_up(align)
+                    .ok_or_else(|| Error::invalid_range(base, size))?;
+            } else if !base.is_aligned(align) {
+                return Err(misaligned);
+            }
+        }
+        BusRange::new(base, size)
+    }
 }
 
-// We need to implement the following traits so we can use `BusRange` values with `BTreeMap`s.
+/// Formats the range as its first and last addresses, e.g. `0x3f8-0x3ff`.
+impl<A: BusAddress> core::fmt::Display for BusRange<A> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:#x}-{:#x}",
+            self.base.value().into(),
+            self.last().value().into()
+        )
+    }
+}
+
+// We need to implement the following traits so we can keep `BusRange` values sorted.
 // This usage scenario requires treating ranges as if they supported a total order, but that's
 // not really possible with intervals, so we write the implementations as if `BusRange`s were
 // solely determined by their base addresses, and apply extra checks in the `Bus` logic.
@@ -72,7 +199,7 @@ impl<A: BusAddress> Eq for BusRange<A> {}
 
 impl<A: BusAddress> PartialOrd for BusRange<A> {
     fn partial_cmp(&self, other: &BusRange<A>) -> Option<Ordering> {
-        self.base.partial_cmp(&other.base)
+        Some(self.cmp(other))
     }
 }
 
@@ -84,25 +211,181 @@ impl<A: BusAddress> Ord for BusRange<A> {
 
 /// Represents an MMIO bus range.
 pub type MmioRange = BusRange<MmioAddress>;
+/// Represents a range of a 32 bit MMIO bus.
+pub type Mmio32Range = BusRange<Mmio32Address>;
 /// Represents a PIO bus range.
 pub type PioRange = BusRange<PioAddress>;
 
+// Generated ranges always uphold the invariants checked by `BusRange::new`: the size is
+// never zero, and the range never extends past the end of the address space.
+macro_rules! impl_arbitrary_range {
+    ($address:ident, $value:ty) => {
+        #[cfg(feature = "arbitrary")]
+        impl<'a> arbitrary::Arbitrary<'a> for BusRange<$address> {
+            fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+                let size: $value = u.int_in_range(1..=<$value>::MAX)?;
+                let base = u.int_in_range(0..=<$value>::MAX - (size - 1))?;
+                Ok(BusRange {
+                    base: $address(base),
+                    size,
+                })
+            }
+        }
+    };
+}
+
+impl_arbitrary_range!(MmioAddress, u64);
+impl_arbitrary_range!(Mmio32Address, u32);
+impl_arbitrary_range!(PioAddress, u16);
+
+#[cfg(feature = "serde")]
+impl<A> serde::Serialize for BusRange<A>
+where
+    A: BusAddress + serde::Serialize,
+    A::V: serde::Serialize,
+{
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        let mut state = serializer.serialize_struct("BusRange", 2)?;
+        state.serialize_field("base", &self.base)?;
+        state.serialize_field("size", &self.size)?;
+        state.end()
+    }
+}
+
+// Deserialized ranges go through `BusRange::new`, so they uphold the same invariants as the
+// ones created by the crate.
+#[cfg(feature = "serde")]
+impl<'de, A> serde::Deserialize<'de> for BusRange<A>
+where
+    A: BusAddress + serde::Deserialize<'de>,
+    A::V: serde::Deserialize<'de>,
+{
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(serde::Deserialize)]
+        #[serde(rename = "BusRange")]
+        struct Fields<A, V> {
+            base: A,
+            size: V,
+        }
+
+        let fields = Fields::<A, A::V>::deserialize(deserializer)?;
+        BusRange::new(fields.base, fields.size).map_err(serde::de::Error::custom)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_bus_range_builder() {
+        let range = MmioRange::builder()
+            .base(MmioAddress(0x1000))
+            .size(0x200)
+            .align(0x1000)
+            .build()
+            .unwrap();
+        assert_eq!(range, MmioRange::new(MmioAddress(0x1000), 0x200).unwrap());
+        assert_eq!(range.size(), 0x200);
+
+        // Misaligned bases are rejected, unless they may be moved up.
+        let builder = MmioRange::builder()
+            .base(MmioAddress(0x1200))
+            .size(0x200)
+            .align(0x1000);
+        assert_eq!(
+            builder.build(),
+            Err(Error::MisalignedRange {
+                base: 0x1200,
+                align: 0x1000
+            })
+        );
+        let range = builder.align_base_up().build().unwrap();
+        assert_eq!(range.base(), MmioAddress(0x2000));
+        assert_eq!(range.size(), 0x200);
+
+        // Alignments don't need to be powers of two.
+        let range = PioRange::builder()
+            .base(PioAddress(0x3f9))
+            .size(8)
+            .align(3)
+            .align_base_up()
+            .build()
+            .unwrap();
+        assert_eq!(range.base(), PioAddress(0x3f9));
+        assert_eq!(
+            PioRange::builder()
+                .base(PioAddress(0x3f8))
+                .size(8)
+                .align(0)
+                .build(),
+            Err(Error::MisalignedRange {
+                base: 0x3f8,
+                align: 0
+            })
+        );
+
+        // Overflows are caught, including the ones caused by moving the base up.
+        assert_eq!(
+            PioRange::builder()
+                .base(PioAddress(0xfff1))
+                .size(8)
+                .align(0x10)
+                .align_base_up()
+                .build(),
+            Err(Error::InvalidRange {
+                base: 0xfff1,
+                size: 8
+            })
+        );
+        assert_eq!(
+            PioRange::builder()
+                .base(PioAddress(0xfff0))
+                .size(0x20)
+                .align(0x10)
+                .build(),
+            Err(Error::InvalidRange {
+                base: 0xfff0,
+                size: 0x20
+            })
+        );
+        assert_eq!(
+            PioRange::builder().base(PioAddress(0x60)).build(),
+            Err(Error::InvalidRange {
+                base: 0x60,
+                size: 0
+            })
+        );
+        assert_eq!(
+            PioRange::builder().size(8).build(),
+            Err(Error::InvalidRange { base: 0, size: 8 })
+        );
+    }
+
     #[test]
     fn test_bus_range() {
         let base_zero = MmioAddress(0);
         let value = 5;
 
-        assert_eq!(BusRange::new(base_zero, 0), Err(Error::InvalidRange));
+        assert_eq!(
+            BusRange::new(base_zero, 0),
+            Err(Error::InvalidRange { base: 0, size: 0 })
+        );
 
-        assert!(BusRange::new(base_zero, std::u64::MAX).is_ok());
-        assert!(BusRange::new(MmioAddress(1), std::u64::MAX).is_ok());
+        assert!(BusRange::new(base_zero, u64::MAX).is_ok());
+        assert_eq!(
+            BusRange::new(PioAddress(0x3f8), 8).unwrap().to_string(),
+            "0x3f8-0x3ff"
+        );
+        assert!(BusRange::new(MmioAddress(1), u64::MAX).is_ok());
         assert_eq!(
-            BusRange::new(MmioAddress(2), std::u64::MAX),
-            Err(Error::InvalidRange)
+            BusRange::new(MmioAddress(2), u64::MAX),
+            Err(Error::InvalidRange {
+                base: 2,
+                size: u64::MAX
+            })
         );
 
         {
@@ -175,4 +458,33 @@ mod tests {
             }
         }
     }
+
+    #[cfg(feature = "arbitrary")]
+    #[test]
+    fn test_arbitrary_range() {
+        use arbitrary::{Arbitrary, Unstructured};
+
+        for raw in [[0u8; 16], [0xff; 16], [0x5a; 16]].iter() {
+            let mut u = Unstructured::new(raw);
+            let range = MmioRange::arbitrary(&mut u).unwrap();
+            assert!(BusRange::new(range.base(), range.size()).is_ok());
+            let range = PioRange::arbitrary(&mut u).unwrap();
+            assert!(BusRange::new(range.base(), range.size()).is_ok());
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_range() {
+        let range = MmioRange::new(MmioAddress(0x1000), 0x10).unwrap();
+        let json = serde_json::to_value(range).unwrap();
+        assert_eq!(json, serde_json::json!({"base": 0x1000, "size": 0x10}));
+        let other: MmioRange = serde_json::from_value(json).unwrap();
+        assert_eq!(other.base(), range.base());
+        assert_eq!(other.size(), range.size());
+
+        // Invalid ranges are rejected.
+        assert!(serde_json::from_str::<MmioRange>(r#"{"base": 0, "size": 0}"#).is_err());
+        assert!(serde_json::from_str::<PioRange>(r#"{"base": 65535, "size": 2}"#).is_err());
+    }
 }
@@ -0,0 +1,288 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+use std::sync::atomic::{AtomicU64, Ordering};
+// The shards are handed out through `ShardedBus::shard`, so they use the `std` locks even
+// when building with `--cfg loom`.
+use std::sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError};
+
+use crate::bus::{Bus, BusAddress, BusRange, Error, SortedVec, Storage};
+
+/// Counters describing the use of a shard of a [`ShardedBus`].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ShardStats {
+    /// Number of ranges registered in the shard.
+    pub devices: usize,
+    /// Number of lookups served by the shard.
+    pub lookups: u64,
+    /// Number of registrations and deregistrations in the shard.
+    pub updates: u64,
+    /// Number of lookups and updates which had to wait for the shard's lock.
+    pub contended: u64,
+}
+
+struct Shard<A: BusAddress, D, S: Storage<A, D>> {
+    bus: RwLock<Bus<A, D, S>>,
+    lookups: AtomicU64,
+    updates: AtomicU64,
+    contended: AtomicU64,
+}
+
+/// A bus split into independently locked shards, selected by the high bits of addresses.
+///
+/// The address space is cut into granules of `1 << shift` bytes, which are assigned to the
+/// shards in a round-robin fashion. Lookups and registrations in different shards don't
+/// contend, which helps when thousands of ranges (e.g. virtio-mmio slots of a large guest)
+/// are accessed and hotplugged concurrently. Ranges can't cross a granule boundary.
+///
+/// ```
+/// use vm_device::bus::{MmioAddress, MmioRange, ShardedBus};
+///
+/// // Four shards of 64 KiB granules.
+/// let bus = ShardedBus::<MmioAddress, u32>::new(4, 16);
+/// for idx in 0..64 {
+///     let range = MmioRange::new(MmioAddress(idx * 0x1000), 0x200).unwrap();
+///     bus.register(range, idx as u32).unwrap();
+/// }
+/// assert_eq!(bus.device(MmioAddress(0x1_1010)).unwrap().1, 0x11);
+/// assert_eq!(bus.shard_of(MmioAddress(0x1_1010)), 1);
+/// assert_eq!(bus.stats()[1].devices, 16);
+/// ```
+pub struct ShardedBus<A: BusAddress, D, S: Storage<A, D> = SortedVec<A, D>> {
+    shards: Vec<Shard<A, D, S>>,
+    shift: u32,
+}
+
+impl<A: BusAddress, D, S: Storage<A, D>> ShardedBus<A, D, S> {
+    /// Create a bus with `shards` shards, cutting the address space into granules of
+    /// `1 << shift` bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `shards` is zero.
+    pub fn new(shards: usize, shift: u32) -> Self {
+        assert!(shards > 0, "a sharded bus needs at least one shard");
+        ShardedBus {
+            shards: (0..shards)
+                .map(|_| Shard {
+                    bus: RwLock::new(Bus::default()),
+                    lookups: AtomicU64::new(0),
+                    updates: AtomicU64::new(0),
+                    contended: AtomicU64::new(0),
+                })
+                .collect(),
+            shift,
+        }
+    }
+
+    fn granule(&self, addr: A) -> u64 {
+        addr.value().into().checked_shr(self.shift).unwrap_or(0)
+    }
+
+    /// Return the index of the shard holding the ranges containing `addr`.
+    #[inline]
+    pub fn shard_of(&self, addr: A) -> usize {
+        (self.granule(addr) % self.shards.len() as u64) as usize
+    }
+
+    /// Return the number of shards.
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    fn read(&self, idx: usize) -> RwLockReadGuard<'_, Bus<A, D, S>> {
+        let shard = &self.shards[idx];
+        shard.lookups.fetch_add(1, Ordering::Relaxed);
+        match shard.bus.try_read() {
+            Ok(bus) => bus,
+            Err(TryLockError::Poisoned(err)) => err.into_inner(),
+            Err(TryLockError::WouldBlock) => {
+                shard.contended.fetch_add(1, Ordering::Relaxed);
+                shard.bus.read().unwrap_or_else(PoisonError::into_inner)
+            }
+        }
+    }
+
+    fn write(&self, idx: usize) -> RwLockWriteGuard<'_, Bus<A, D, S>> {
+        let shard = &self.shards[idx];
+        shard.updates.fetch_add(1, Ordering::Relaxed);
+        match shard.bus.try_write() {
+            Ok(bus) => bus,
+            Err(TryLockError::Poisoned(err)) => err.into_inner(),
+            Err(TryLockError::WouldBlock) => {
+                shard.contended.fetch_add(1, Ordering::Relaxed);
+                shard.bus.write().unwrap_or_else(PoisonError::into_inner)
+            }
+        }
+    }
+
+    /// Return a handle to the shard with index `idx`, which blocks updates to that shard
+    /// while held.
+    ///
+    /// The shards cover interleaved parts of the address space, so walking them in order
+    /// doesn't visit the ranges in address order.
+    pub fn shard(&self, idx: usize) -> Option<RwLockReadGuard<'_, Bus<A, D, S>>> {
+        (idx < self.shards.len()).then(|| {
+            self.shards[idx]
+                .bus
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+        })
+    }
+
+    /// Register a device with the provided range, in the shard covering it.
+    ///
+    /// Fails with [`Error::InvalidRange`] if the range crosses a granule boundary.
+    pub fn register(&self, range: BusRange<A>, device: D) -> Result<(), Error> {
+        if self.granule(range.base()) != self.granule(range.last()) {
+            return Err(Error::invalid_range(range.base(), range.size()));
+        }
+        self.write(self.shard_of(range.base()))
+            .register(range, device)
+    }
+
+    /// Deregister the device associated with `addr`.
+    pub fn deregister(&self, addr: A) -> Option<(BusRange<A>, D)> {
+        self.write(self.shard_of(addr)).deregister(addr)
+    }
+
+    /// Return the per shard counters, indexed by shard.
+    pub fn stats(&self) -> Vec<ShardStats> {
+        self.shards
+            .iter()
+            .map(|shard| ShardStats {
+                devices: shard
+                    .bus
+                    .read()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .iter()
+                    .count(),
+                lookups: shard.lookups.load(Ordering::Relaxed),
+                updates: shard.updates.load(Ordering::Relaxed),
+                contended: shard.contended.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+}
+
+impl<A: BusAddress, D: Clone, S: Storage<A, D>> ShardedBus<A, D, S> {
+    /// Return the registered range and device associated with `addr`.
+    ///
+    /// The device is cloned so the shard's lock isn't held after returning, which is cheap
+    /// for the `Arc` wrapped devices buses usually hold.
+    #[inline]
+    pub fn device(&self, addr: A) -> Option<(BusRange<A>, D)> {
+        self.read(self.shard_of(addr))
+            .device(addr)
+            .map(|(range, device)| (*range, device.clone()))
+    }
+
+    /// Verify whether an access starting at `addr` with length `len` fits within any of
+    /// the registered ranges. Return the range and a handle to the device when present.
+    #[inline]
+    pub fn check_access(&self, addr: A, len: usize) -> Result<(BusRange<A>, D), Error> {
+        self.read(self.shard_of(addr))
+            .check_access(addr, len)
+            .map(|(range, device)| (*range, device.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+
+    use crate::bus::{MmioAddress, MmioRange, PioAddress, PioRange};
+
+    #[test]
+    fn test_sharded_bus() {
+        let bus = ShardedBus::<MmioAddress, u32>::new(3, 12);
+        for idx in 0..9 {
+            let range = MmioRange::new(MmioAddress(idx * 0x1000 + 0x800), 0x800).unwrap();
+            bus.register(range, idx as u32).unwrap();
+        }
+        assert_eq!(
+            bus.register(MmioRange::new(MmioAddress(0x9800), 0x801).unwrap(), 9),
+            Err(Error::InvalidRange {
+                base: 0x9800,
+                size: 0x801
+            })
+        );
+        assert_eq!(
+            bus.register(MmioRange::new(MmioAddress(0x1fff), 1).unwrap(), 9),
+            Err(Error::DeviceOverlap {
+                base: 0x1fff,
+                size: 1
+            })
+        );
+
+        assert_eq!(bus.shard_of(MmioAddress(0x4800)), 1);
+        assert_eq!(bus.device(MmioAddress(0x4fff)).unwrap().1, 4);
+        assert!(bus.device(MmioAddress(0x4000)).is_none());
+        assert_eq!(
+            bus.check_access(MmioAddress(0x4ffe), 4).unwrap_err(),
+            Error::DeviceNotFound {
+                addr: 0x4ffe,
+                len: 4
+            }
+        );
+        assert_eq!(bus.check_access(MmioAddress(0x4ffc), 4).unwrap().1, 4);
+
+        let devices: Vec<_> = (0..bus.shard_count())
+            .map(|idx| {
+                let shard = bus.shard(idx).unwrap();
+                shard.iter().map(|(_, device)| *device).collect::<Vec<_>>()
+            })
+            .collect();
+        assert_eq!(devices, vec![vec![0, 3, 6], vec![1, 4, 7], vec![2, 5, 8]]);
+        assert!(bus.shard(3).is_none());
+
+        assert_eq!(bus.deregister(MmioAddress(0x4900)).unwrap().1, 4);
+        let stats = bus.stats();
+        assert_eq!(
+            stats.iter().map(|s| s.devices).collect::<Vec<_>>(),
+            [3, 2, 3]
+        );
+        assert_eq!(stats[1].lookups, 4);
+        assert_eq!(stats[1].updates, 5);
+        assert_eq!(stats[1].contended, 0);
+
+        // Granules larger than the address space put everything in the first shard.
+        let bus = ShardedBus::<PioAddress, u32>::new(2, 16);
+        bus.register(PioRange::new(PioAddress(0xfff0), 0x10).unwrap(), 0)
+            .unwrap();
+        assert_eq!(bus.shard_of(PioAddress(0xffff)), 0);
+    }
+
+    #[test]
+    fn test_sharded_bus_concurrent() {
+        const THREADS: u64 = 4;
+        let bus = Arc::new(ShardedBus::<MmioAddress, u64>::new(THREADS as usize, 12));
+        let barrier = Arc::new(Barrier::new(THREADS as usize));
+        let threads: Vec<_> = (0..THREADS)
+            .map(|shard| {
+                let (bus, barrier) = (bus.clone(), barrier.clone());
+                thread::spawn(move || {
+                    barrier.wait();
+                    for idx in 0..256 {
+                        let base = (idx * THREADS + shard) << 12;
+                        let range = MmioRange::new(MmioAddress(base), 0x200).unwrap();
+                        bus.register(range, base).unwrap();
+                        assert_eq!(bus.device(MmioAddress(base + 0x10)).unwrap().1, base);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        // Each thread only touched its own shard.
+        for stats in bus.stats() {
+            assert_eq!(stats.devices, 256);
+            assert_eq!(stats.contended, 0);
+        }
+    }
+}
@@ -0,0 +1,412 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::ops::Bound::{Excluded, Unbounded};
+#[cfg(feature = "std")]
+use std::collections::HashMap;
+#[cfg(feature = "std")]
+use std::hash::{BuildHasherDefault, Hasher};
+
+use crate::bus::{BusAddress, BusRange, Error};
+#[cfg(feature = "std")]
+use crate::bus::{MmioAddress, MmioRange};
+
+/// Lookup structure holding the ranges registered with a [`Bus`](crate::bus::Bus) and their
+/// associated devices.
+///
+/// Implementations can rely on the registered ranges never overlapping.
+pub trait Storage<A: BusAddress, D>: Default {
+    /// Return the range containing `addr` and its associated device.
+    fn get(&self, addr: A) -> Option<(&BusRange<A>, &D)>;
+
+    /// Return the range containing `addr` and a mutable reference to its associated device.
+    fn get_mut(&mut self, addr: A) -> Option<(&BusRange<A>, &mut D)>;
+
+    /// Insert `range` and its associated device, unless it overlaps a range that's
+    /// already registered.
+    fn insert(&mut self, range: BusRange<A>, device: D) -> Result<(), Error>;
+
+    /// Remove the range containing `addr`, and return it with its associated device.
+    fn remove(&mut self, addr: A) -> Option<(BusRange<A>, D)>;
+
+    /// Return an iterator over the ranges and their associated devices, in ascending
+    /// address order.
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a BusRange<A>, &'a D)> + 'a>;
+
+    /// Return the number of registered ranges.
+    fn len(&self) -> usize;
+
+    /// Return whether no range is registered.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Reserve room for registering at least `additional` more ranges without allocating,
+    /// when the storage supports it.
+    fn reserve(&mut self, _additional: usize) {}
+}
+
+/// Storage keeping the ranges in a vector sorted by base address.
+///
+/// Buses usually hold a few dozen devices which are rarely registered or deregistered, so
+/// binary searching a vector is cheaper than walking a tree, and keeps lookups cache
+/// friendly. Registering and deregistering devices is linear in the number of ranges.
+#[derive(Clone)]
+pub struct SortedVec<A: BusAddress, D> {
+    devices: Vec<(BusRange<A>, D)>,
+}
+
+impl<A: BusAddress, D> Default for SortedVec<A, D> {
+    fn default() -> Self {
+        SortedVec {
+            devices: Vec::new(),
+        }
+    }
+}
+
+impl<A: BusAddress, D> SortedVec<A, D> {
+    // Return whether any of the ranges overlaps `range`.
+    fn overlaps(&self, range: &BusRange<A>) -> bool {
+        let idx = self
+            .devices
+            .partition_point(|(r, _)| r.base() < range.base());
+        let prev = idx.checked_sub(1).map(|idx| &self.devices[idx].0);
+        let next = self.devices.get(idx).map(|(r, _)| r);
+        prev.into_iter().chain(next).any(|r| range.overlaps(r))
+    }
+
+    // Return the index of the range containing `addr`.
+    #[inline]
+    fn position(&self, addr: A) -> Option<usize> {
+        // Index of the first range starting after `addr`.
+        let idx = self
+            .devices
+            .partition_point(|(range, _)| range.base() <= addr);
+        idx.checked_sub(1)
+            .filter(|&idx| self.devices[idx].0.last() >= addr)
+    }
+}
+
+impl<A: BusAddress, D> Storage<A, D> for SortedVec<A, D> {
+    #[inline]
+    fn get(&self, addr: A) -> Option<(&BusRange<A>, &D)> {
+        self.position(addr)
+            .map(|idx| (&self.devices[idx].0, &self.devices[idx].1))
+    }
+
+    fn get_mut(&mut self, addr: A) -> Option<(&BusRange<A>, &mut D)> {
+        let idx = self.position(addr)?;
+        let (range, device) = &mut self.devices[idx];
+        Some((&*range, device))
+    }
+
+    fn insert(&mut self, range: BusRange<A>, device: D) -> Result<(), Error> {
+        // Registered ranges don't overlap, so only the ones right before and right after
+        // the new range can overlap it.
+        if self.overlaps(&range) {
+            return Err(Error::overlap(&range));
+        }
+
+        let idx = self
+            .devices
+            .partition_point(|(r, _)| r.base() < range.base());
+        self.devices.insert(idx, (range, device));
+        Ok(())
+    }
+
+    fn remove(&mut self, addr: A) -> Option<(BusRange<A>, D)> {
+        self.position(addr).map(|idx| self.devices.remove(idx))
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a BusRange<A>, &'a D)> + 'a> {
+        Box::new(self.devices.iter().map(|(range, device)| (range, device)))
+    }
+
+    fn len(&self) -> usize {
+        self.devices.len()
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        self.devices.reserve(additional);
+    }
+}
+
+/// Storage keeping the ranges in a balanced search tree.
+///
+/// Since registered ranges never overlap, a tree ordered by base address is enough to answer
+/// stabbing queries: the only range which can contain an address is the one with the
+/// greatest base not above it. Likewise, a new range can only overlap its neighbours. Lookups,
+/// registration and deregistration are all logarithmic in the number of ranges, which pays
+/// off for buses with hundreds of ranges (e.g. per-queue doorbells or many shared memory
+/// windows). Unlike [`SortedVec`], registering a range always allocates.
+#[derive(Clone)]
+pub struct IntervalTree<A: BusAddress, D> {
+    devices: BTreeMap<BusRange<A>, D>,
+}
+
+impl<A: BusAddress, D> Default for IntervalTree<A, D> {
+    fn default() -> Self {
+        IntervalTree {
+            devices: BTreeMap::new(),
+        }
+    }
+}
+
+impl<A: BusAddress, D> Storage<A, D> for IntervalTree<A, D> {
+    #[inline]
+    fn get(&self, addr: A) -> Option<(&BusRange<A>, &D)> {
+        self.devices
+            .range(..=BusRange::unit(addr))
+            .next_back()
+            .filter(|(range, _)| range.last() >= addr)
+    }
+
+    fn get_mut(&mut self, addr: A) -> Option<(&BusRange<A>, &mut D)> {
+        self.devices
+            .range_mut(..=BusRange::unit(addr))
+            .next_back()
+            .filter(|(range, _)| range.last() >= addr)
+    }
+
+    fn insert(&mut self, range: BusRange<A>, device: D) -> Result<(), Error> {
+        // The range before also covers the one starting at the same address.
+        let prev = self.devices.range(..=range).next_back();
+        let next = self.devices.range((Excluded(range), Unbounded)).next();
+        if prev.into_iter().chain(next).any(|(r, _)| range.overlaps(r)) {
+            return Err(Error::overlap(&range));
+        }
+
+        self.devices.insert(range, device);
+        Ok(())
+    }
+
+    fn remove(&mut self, addr: A) -> Option<(BusRange<A>, D)> {
+        let range = *self.get(addr)?.0;
+        self.devices.remove(&range).map(|device| (range, device))
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a BusRange<A>, &'a D)> + 'a> {
+        Box::new(self.devices.iter())
+    }
+
+    fn len(&self) -> usize {
+        self.devices.len()
+    }
+}
+
+// Size of the pages indexed by `PageIndex`.
+#[cfg(feature = "std")]
+const PAGE_SHIFT: u64 = 12;
+
+// Hashes page numbers with a single multiplication, which is enough to spread the
+// consecutive page numbers devices typically use.
+#[cfg(feature = "std")]
+#[derive(Default)]
+struct PageHasher(u64);
+
+#[cfg(feature = "std")]
+impl Hasher for PageHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.write_u64(u64::from(byte));
+        }
+    }
+
+    #[inline]
+    fn write_u64(&mut self, value: u64) {
+        self.0 = (self.0 ^ value).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    }
+}
+
+/// MMIO storage indexing ranges by the 4 KiB page they're in.
+///
+/// Devices are usually page aligned, and their ranges (e.g. 0x200 bytes for virtio-mmio
+/// transports) don't cross into another page. A range which is alone in its page is found
+/// with a single hash lookup, regardless of the number of registered ranges. Ranges sharing
+/// a page, or spanning several pages, are kept in a [`SortedVec`] instead. A range which got
+/// moved there because another range was registered in its page stays there after that
+/// range is deregistered.
+///
+/// Only available with the `std` feature.
+#[cfg(feature = "std")]
+#[derive(Clone)]
+pub struct PageIndex<D> {
+    // Ranges which are the only ones in their page, by page number.
+    pages: HashMap<u64, (MmioRange, D), BuildHasherDefault<PageHasher>>,
+    // All other ranges.
+    fallback: SortedVec<MmioAddress, D>,
+}
+
+#[cfg(feature = "std")]
+impl<D> Default for PageIndex<D> {
+    fn default() -> Self {
+        PageIndex {
+            pages: HashMap::default(),
+            fallback: SortedVec::default(),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<D> PageIndex<D> {
+    // Return the numbers of the indexed pages between `first` and `last`.
+    fn indexed(&self, first: u64, last: u64) -> Vec<u64> {
+        if last - first < self.pages.len() as u64 {
+            (first..=last)
+                .filter(|page| self.pages.contains_key(page))
+                .collect()
+        } else {
+            self.pages
+                .keys()
+                .filter(|&&page| page >= first && page <= last)
+                .copied()
+                .collect()
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+#[inline]
+fn page(addr: MmioAddress) -> u64 {
+    addr.0 >> PAGE_SHIFT
+}
+
+#[cfg(feature = "std")]
+#[inline]
+fn contains(range: &MmioRange, addr: MmioAddress) -> bool {
+    range.base() <= addr && range.last() >= addr
+}
+
+#[cfg(feature = "std")]
+impl<D> Storage<MmioAddress, D> for PageIndex<D> {
+    #[inline]
+    fn get(&self, addr: MmioAddress) -> Option<(&MmioRange, &D)> {
+        match self.pages.get(&page(addr)) {
+            // Nothing else is registered in an indexed page.
+            Some((range, device)) => Some((range, device)).filter(|_| contains(range, addr)),
+            None => self.fallback.get(addr),
+        }
+    }
+
+    fn get_mut(&mut self, addr: MmioAddress) -> Option<(&MmioRange, &mut D)> {
+        match self.pages.get_mut(&page(addr)) {
+            Some((range, device)) => Some((&*range, device)).filter(|_| contains(range, addr)),
+            None => self.fallback.get_mut(addr),
+        }
+    }
+
+    fn insert(&mut self, range: MmioRange, device: D) -> Result<(), Error> {
+        let (first, last) = (page(range.base()), page(range.last()));
+        let indexed = self.indexed(first, last);
+        if self.fallback.overlaps(&range)
+            || indexed
+                .iter()
+                .any(|page| range.overlaps(&self.pages[page].0))
+        {
+            return Err(Error::overlap(&range));
+        }
+
+        if first == last && indexed.is_empty() {
+            let page_range = MmioRange::new(MmioAddress(first << PAGE_SHIFT), 1 << PAGE_SHIFT)?;
+            if !self.fallback.overlaps(&page_range) {
+                self.pages.insert(first, (range, device));
+                return Ok(());
+            }
+        }
+
+        // The ranges in the same pages aren't alone in them anymore.
+        for page in indexed {
+            let (range, device) = self.pages.remove(&page).unwrap();
+            self.fallback.insert(range, device)?;
+        }
+        self.fallback.insert(range, device)
+    }
+
+    fn remove(&mut self, addr: MmioAddress) -> Option<(MmioRange, D)> {
+        let page = page(addr);
+        match self.pages.get(&page) {
+            Some((range, _)) if contains(range, addr) => self.pages.remove(&page),
+            Some(_) => None,
+            None => self.fallback.remove(addr),
+        }
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a MmioRange, &'a D)> + 'a> {
+        let mut devices: Vec<_> = self
+            .pages
+            .values()
+            .map(|(range, device)| (range, device))
+            .chain(self.fallback.iter())
+            .collect();
+        devices.sort_unstable_by_key(|(range, _)| range.base());
+        Box::new(devices.into_iter())
+    }
+
+    fn len(&self) -> usize {
+        self.pages.len() + self.fallback.len()
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        self.pages.reserve(additional);
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    fn range(base: u64, size: u64) -> MmioRange {
+        MmioRange::new(MmioAddress(base), size).unwrap()
+    }
+
+    #[test]
+    fn test_page_index() {
+        let mut storage = PageIndex::default();
+        storage.insert(range(0x1000, 0x200), 1).unwrap();
+        storage.insert(range(0x2000, 0x1000), 2).unwrap();
+        assert_eq!(storage.pages.len(), 2);
+        assert_eq!(storage.get(MmioAddress(0x11ff)).map(|(_, d)| *d), Some(1));
+        assert!(storage.get(MmioAddress(0x1200)).is_none());
+        assert_eq!(storage.get(MmioAddress(0x2fff)).map(|(_, d)| *d), Some(2));
+
+        // Ranges sharing a page or spanning several pages go to the fallback storage.
+        assert_eq!(
+            storage.insert(range(0x1100, 0x200), 3).unwrap_err(),
+            Error::overlap(&range(0x1100, 0x200))
+        );
+        storage.insert(range(0x1800, 0x100), 3).unwrap();
+        storage.insert(range(0x4000, 0x2000), 4).unwrap();
+        assert_eq!(
+            storage.insert(range(0x5000, 0x10), 5).unwrap_err(),
+            Error::overlap(&range(0x5000, 0x10))
+        );
+        storage.insert(range(0x6ff0, 0x20), 5).unwrap();
+        assert_eq!(storage.pages.len(), 1);
+        assert_eq!(storage.fallback.len(), 4);
+
+        let devices: Vec<_> = storage.iter().map(|(_, d)| *d).collect();
+        assert_eq!(devices, vec![1, 3, 2, 4, 5]);
+        assert_eq!(storage.get(MmioAddress(0x5fff)).map(|(_, d)| *d), Some(4));
+        assert_eq!(storage.get(MmioAddress(0x7000)).map(|(_, d)| *d), Some(5));
+        assert!(storage.get(MmioAddress(0x1900)).is_none());
+
+        *storage.get_mut(MmioAddress(0x2000)).unwrap().1 = 6;
+        assert_eq!(
+            storage.remove(MmioAddress(0x2800)),
+            Some((range(0x2000, 0x1000), 6))
+        );
+        assert!(storage.remove(MmioAddress(0x2800)).is_none());
+        assert_eq!(
+            storage.remove(MmioAddress(0x1000)),
+            Some((range(0x1000, 0x200), 1))
+        );
+        assert_eq!(storage.len(), 3);
+    }
+}
@@ -0,0 +1,293 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Caching of read-mostly MMIO registers.
+//!
+//! Guests re-read constant registers (e.g. the magic value, version and IDs of virtio-mmio
+//! transports, or the feature banks once negotiated) surprisingly often while probing and
+//! across suspend/resume cycles. [`Cached`] wraps a device and serves reads of the registers
+//! marked as cacheable from a cache, only calling into the device on the first read after
+//! the cache was invalidated.
+//!
+//! # Example
+//!
+//! ```
+//! # use vm_device::bus::{MmioAddress, MmioAddressOffset};
+//! # use vm_device::DeviceMmio;
+//! use vm_device::cache::Cached;
+//!
+//! struct Transport;
+//!
+//! impl DeviceMmio for Transport {
+//!     fn mmio_read(&self, _base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+//!         if offset == 0 {
+//!             data.copy_from_slice(&0x7472_6976u32.to_le_bytes());
+//!         }
+//!     }
+//!     fn mmio_write(&self, _base: MmioAddress, _offset: MmioAddressOffset, _data: &[u8]) {}
+//! }
+//!
+//! // Cache the magic value, version, device and vendor IDs, and drop the cache when the
+//! // guest writes the status register to reset the device.
+//! let device = Cached::new(Transport).cache(0x00..0x10).invalidate_on(0x70);
+//! let mut data = [0; 4];
+//! device.mmio_read(MmioAddress(0), 0, &mut data);
+//! device.mmio_read(MmioAddress(0), 0, &mut data);
+//! assert_eq!(device.hits(), 1);
+//! ```
+
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::bus::{MmioAddress, MmioAddressOffset};
+use crate::completion::Completion;
+use crate::exit::ExitAccess;
+use crate::health::Health;
+use crate::info::DeviceDetails;
+use crate::resources::Resource;
+use crate::stats::Statistics;
+use crate::DeviceMmio;
+
+/// A device wrapper serving reads of cacheable registers from a cache.
+///
+/// A read is served from the cache when it falls entirely within one of the ranges passed
+/// to [`cache`](Cached::cache), and the same offset was read with the same length since the
+/// cache was last invalidated. The cache is invalidated by writes to the offsets passed to
+/// [`invalidate_on`](Cached::invalidate_on), such as a reset or a bank selector register,
+/// and by explicit calls to [`invalidate`](Cached::invalidate). Writes to cacheable
+/// registers are forwarded to the device and drop the cached values they overlap. All
+/// other accesses are forwarded to the device untouched.
+pub struct Cached<D> {
+    device: D,
+    cacheable: Vec<Range<MmioAddressOffset>>,
+    triggers: Vec<MmioAddressOffset>,
+    values: Mutex<Values>,
+    hits: AtomicU64,
+}
+
+#[derive(Default)]
+struct Values {
+    // Cached values, by offset and length.
+    values: HashMap<(MmioAddressOffset, usize), Vec<u8>>,
+    // Incremented whenever values are dropped, so reads which raced with a write don't
+    // cache what they got.
+    generation: u64,
+}
+
+impl<D> Cached<D> {
+    /// Wrap `device`, with no register marked as cacheable yet.
+    pub fn new(device: D) -> Self {
+        Cached {
+            device,
+            cacheable: Vec::new(),
+            triggers: Vec::new(),
+            values: Mutex::new(Values::default()),
+            hits: AtomicU64::new(0),
+        }
+    }
+
+    /// Mark the registers in `offsets` as cacheable.
+    pub fn cache(mut self, offsets: Range<MmioAddressOffset>) -> Self {
+        self.cacheable.push(offsets);
+        self
+    }
+
+    /// Invalidate the cache whenever the register at `offset` is written.
+    pub fn invalidate_on(mut self, offset: MmioAddressOffset) -> Self {
+        self.triggers.push(offset);
+        self
+    }
+
+    /// Drop all the cached values, e.g. when the device is reset by the VMM.
+    pub fn invalidate(&self) {
+        let mut values = self.values.lock().unwrap();
+        values.values.clear();
+        values.generation += 1;
+    }
+
+    /// Return the number of reads served from the cache.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Return the wrapped device.
+    pub fn inner(&self) -> &D {
+        &self.device
+    }
+
+    fn is_cacheable(&self, offset: MmioAddressOffset, len: usize) -> bool {
+        let end = offset.checked_add(len as MmioAddressOffset);
+        self.cacheable
+            .iter()
+            .any(|range| offset >= range.start && end.is_some_and(|end| end <= range.end))
+    }
+
+    // Drop the cached values a write of `len` bytes at `offset` may have changed.
+    fn written(&self, offset: MmioAddressOffset, len: usize) {
+        if self.triggers.contains(&offset) {
+            self.invalidate();
+            return;
+        }
+
+        let end = offset.saturating_add(len as MmioAddressOffset);
+        let mut values = self.values.lock().unwrap();
+        values.values.retain(|&(cached, len), _| {
+            cached >= end || cached + len as MmioAddressOffset <= offset
+        });
+        values.generation += 1;
+    }
+}
+
+impl<D: DeviceMmio> DeviceMmio for Cached<D> {
+    fn mmio_read(&self, base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+        if !self.is_cacheable(offset, data.len()) {
+            return self.device.mmio_read(base, offset, data);
+        }
+
+        let key = (offset, data.len());
+        let generation = {
+            let values = self.values.lock().unwrap();
+            if let Some(value) = values.values.get(&key) {
+                data.copy_from_slice(value);
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            values.generation
+        };
+        // Don't hold the lock while the device handles the read.
+        self.device.mmio_read(base, offset, data);
+        let mut values = self.values.lock().unwrap();
+        if values.generation == generation {
+            values.values.insert(key, data.to_vec());
+        }
+    }
+
+    fn mmio_write(&self, base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+        self.device.mmio_write(base, offset, data);
+        self.written(offset, data.len());
+    }
+
+    fn introspect(&self) -> Vec<(String, String)> {
+        self.device.introspect()
+    }
+
+    fn info(&self) -> Option<DeviceDetails> {
+        self.device.info()
+    }
+
+    fn health(&self) -> Health {
+        self.device.health()
+    }
+
+    fn stats(&self) -> Option<Statistics> {
+        self.device.stats()
+    }
+
+    fn update_resources(&self, resources: &[Resource]) {
+        self.device.update_resources(resources)
+    }
+
+    fn debug_read(&self, base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) -> bool {
+        self.device.debug_read(base, offset, data)
+    }
+
+    fn mmio_update(
+        &self,
+        base: MmioAddress,
+        offset: MmioAddressOffset,
+        data: &mut [u8],
+        update: &mut dyn FnMut(&mut [u8]),
+    ) {
+        // The read bypasses the cache, like the write.
+        self.device.mmio_update(base, offset, data, update);
+        self.written(offset, data.len());
+    }
+
+    fn mmio_deferred(
+        &self,
+        base: MmioAddress,
+        offset: MmioAddressOffset,
+        access: ExitAccess<'_>,
+    ) -> Completion {
+        // Deferred reads bypass the cache, since their value may only be known later.
+        match access {
+            ExitAccess::Read(data) => {
+                self.device
+                    .mmio_deferred(base, offset, ExitAccess::Read(data))
+            }
+            ExitAccess::Write(data) => {
+                let completion = self
+                    .device
+                    .mmio_deferred(base, offset, ExitAccess::Write(data));
+                self.written(offset, data.len());
+                completion
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Device whose registers hold their offset plus the number of resets, and counting the
+    // reads reaching it.
+    #[derive(Default)]
+    struct Registers {
+        resets: Mutex<u8>,
+        reads: AtomicU64,
+    }
+
+    impl DeviceMmio for Registers {
+        fn mmio_read(&self, _base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+            self.reads.fetch_add(1, Ordering::Relaxed);
+            data.fill(offset as u8 + *self.resets.lock().unwrap());
+        }
+
+        fn mmio_write(&self, _base: MmioAddress, offset: MmioAddressOffset, _data: &[u8]) {
+            if offset == 0x70 {
+                *self.resets.lock().unwrap() += 1;
+            }
+        }
+    }
+
+    #[test]
+    fn test_cached() {
+        let device = Cached::new(Registers::default())
+            .cache(0x00..0x10)
+            .invalidate_on(0x70);
+        let read = |offset, len| {
+            let mut data = vec![0; len];
+            device.mmio_read(MmioAddress(0), offset, &mut data);
+            data
+        };
+
+        assert_eq!(read(0x4, 4), vec![4; 4]);
+        assert_eq!(read(0x4, 4), vec![4; 4]);
+        assert_eq!(read(0x4, 2), vec![4; 2]);
+        // Reads crossing the end of the cacheable range always reach the device.
+        assert_eq!(read(0xe, 4), vec![0xe; 4]);
+        assert_eq!(read(0xe, 4), vec![0xe; 4]);
+        assert_eq!(read(0x20, 4), vec![0x20; 4]);
+        assert_eq!(device.hits(), 1);
+        assert_eq!(device.inner().reads.load(Ordering::Relaxed), 5);
+
+        // Writes to a cacheable register drop the values they overlap.
+        read(0x8, 4);
+        device.mmio_write(MmioAddress(0), 0x6, &[0; 2]);
+        read(0x4, 4);
+        read(0x8, 4);
+        assert_eq!(device.hits(), 2);
+
+        device.mmio_write(MmioAddress(0), 0x70, &[0; 4]);
+        assert_eq!(read(0x4, 4), vec![5; 4]);
+        assert_eq!(device.hits(), 2);
+        assert_eq!(read(0x4, 4), vec![5; 4]);
+        device.invalidate();
+        read(0x4, 4);
+        assert_eq!(device.hits(), 3);
+        assert_eq!(device.inner().reads.load(Ordering::Relaxed), 9);
+    }
+}
@@ -0,0 +1,311 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Kernel command line parameters describing the virtio-mmio devices of the guest.
+//!
+//! Guests without ACPI or a device tree discover virtio-mmio devices through
+//! `virtio_mmio.device=<size>@<base>:<irq>` parameters. [`virtio_mmio_param`] formats the
+//! parameter of a single device, and [`IoManager::append_virtio_mmio_params`] appends the
+//! parameters of all the virtio devices registered with a manager to a [`KernelCmdline`].
+//!
+//! The virtio devices are found in the [`Manifest`](crate::manifest::Manifest): they are
+//! the MMIO devices whose `type` starts with `virtio`, e.g. a
+//! [`MmioTransport`](crate::virtio::MmioTransport) built
+//! [`with_irq`](crate::virtio::MmioTransport::with_irq). `linux_loader::cmdline::Cmdline`
+//! plugs in through a wrapper:
+//!
+//! ```ignore
+//! struct LoaderCmdline<'a>(&'a mut Cmdline);
+//!
+//! impl KernelCmdline for LoaderCmdline<'_> {
+//!     type Error = linux_loader::cmdline::Error;
+//!
+//!     fn remaining(&self) -> Option<usize> {
+//!         Some(CMDLINE_MAX_SIZE - self.0.as_cstring().map_or(0, |s| s.as_bytes().len()))
+//!     }
+//!
+//!     fn contains(&self, param: &str) -> bool {
+//!         let cmdline = self.0.as_cstring().unwrap_or_default();
+//!         cmdline.to_string_lossy().split_whitespace().any(|p| p == param)
+//!     }
+//!
+//!     fn insert_str(&mut self, param: &str) -> Result<(), Self::Error> {
+//!         self.0.insert_str(param)
+//!     }
+//! }
+//!
+//! manager.append_virtio_mmio_params(&mut LoaderCmdline(&mut cmdline))?;
+//! ```
+
+use std::convert::Infallible;
+use std::fmt::{Display, Formatter};
+
+use crate::device_manager::IoManager;
+
+/// Errors encountered while building the kernel command line.
+#[derive(Debug)]
+pub enum Error {
+    /// The virtio device at the address doesn't have exactly one MMIO range and one
+    /// interrupt.
+    InvalidDevice(u64),
+    /// The parameters don't fit in the command line.
+    TooLong {
+        /// Number of bytes needed by the parameters.
+        needed: usize,
+        /// Number of bytes left in the command line.
+        remaining: usize,
+    },
+    /// The command line rejected a parameter.
+    Cmdline(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::InvalidDevice(base) => write!(
+                f,
+                "cmdline: device at {:#x} needs one range and one interrupt",
+                base
+            ),
+            Error::TooLong { needed, remaining } => write!(
+                f,
+                "cmdline: parameters need {} bytes, only {} left",
+                needed, remaining
+            ),
+            Error::Cmdline(_) => write!(f, "cmdline: cannot append parameter"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Cmdline(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// A kernel command line, e.g. `linux_loader::cmdline::Cmdline`.
+pub trait KernelCmdline {
+    /// Error returned when a parameter can't be appended.
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    /// Return the number of bytes which can still be appended, if the command line is
+    /// bounded.
+    ///
+    /// The default implementation returns `None`.
+    fn remaining(&self) -> Option<usize> {
+        None
+    }
+
+    /// Return whether `param` is already on the command line, so it isn't appended twice.
+    ///
+    /// The default implementation returns `false`.
+    fn contains(&self, _param: &str) -> bool {
+        false
+    }
+
+    /// Append `param`, separated from the previous parameters by a space.
+    fn insert_str(&mut self, param: &str) -> Result<(), Self::Error>;
+}
+
+impl KernelCmdline for String {
+    type Error = Infallible;
+
+    fn contains(&self, param: &str) -> bool {
+        self.split_whitespace().any(|existing| existing == param)
+    }
+
+    fn insert_str(&mut self, param: &str) -> Result<(), Self::Error> {
+        if !self.is_empty() {
+            self.push(' ');
+        }
+        self.push_str(param);
+        Ok(())
+    }
+}
+
+/// Format the `virtio_mmio.device` parameter of a device registered at `base` with `size`
+/// bytes, raising `irq`.
+///
+/// ```
+/// # use vm_device::cmdline::virtio_mmio_param;
+/// assert_eq!(
+///     virtio_mmio_param(0xd000_0000, 0x1000, 5),
+///     "virtio_mmio.device=4K@0xd0000000:5"
+/// );
+/// ```
+pub fn virtio_mmio_param(base: u64, size: u64, irq: u32) -> String {
+    let size = match size {
+        size if size != 0 && size % (1 << 30) == 0 => format!("{}G", size >> 30),
+        size if size != 0 && size % (1 << 20) == 0 => format!("{}M", size >> 20),
+        size if size != 0 && size % (1 << 10) == 0 => format!("{}K", size >> 10),
+        size => size.to_string(),
+    };
+    format!("virtio_mmio.device={}@{:#x}:{}", size, base, irq)
+}
+
+impl IoManager {
+    /// Return the `virtio_mmio.device` parameters of the registered virtio devices, in bus
+    /// order.
+    pub fn virtio_mmio_params(&self) -> Result<Vec<String>, Error> {
+        let mut params = Vec::new();
+        for device in self.export_manifest().mmio {
+            let virtio = device
+                .kind
+                .as_deref()
+                .is_some_and(|kind| kind.starts_with("virtio"));
+            if !virtio {
+                continue;
+            }
+            let (range, irq) = match (device.ranges.as_slice(), device.irqs.as_slice()) {
+                ([range], [irq]) => (*range, *irq),
+                _ => return Err(Error::InvalidDevice(device.ranges[0].base)),
+            };
+            params.push(virtio_mmio_param(range.base, range.size, irq));
+        }
+        Ok(params)
+    }
+
+    /// Append the `virtio_mmio.device` parameters of the registered virtio devices to
+    /// `cmdline`, except the ones it already contains, and return how many were appended.
+    ///
+    /// The parameters are checked to fit before any is appended, so the command line is
+    /// left untouched on failure, unless it rejects a parameter on its own.
+    pub fn append_virtio_mmio_params<C: KernelCmdline>(
+        &self,
+        cmdline: &mut C,
+    ) -> Result<usize, Error> {
+        let mut params = self.virtio_mmio_params()?;
+        params.retain(|param| !cmdline.contains(param));
+        // Each parameter may need a separating space.
+        let needed = params.iter().map(|param| param.len() + 1).sum();
+        match cmdline.remaining() {
+            Some(remaining) if remaining < needed => {
+                return Err(Error::TooLong { needed, remaining })
+            }
+            _ => {}
+        }
+        for param in &params {
+            cmdline
+                .insert_str(param)
+                .map_err(|e| Error::Cmdline(Box::new(e)))?;
+        }
+        Ok(params.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::bus::{MmioAddress, MmioAddressOffset, MmioRange};
+    use crate::device_manager::MmioManager;
+    use crate::testing::MockDevice;
+    use crate::DeviceMmio;
+
+    struct Virtio(Vec<(&'static str, &'static str)>);
+
+    impl DeviceMmio for Virtio {
+        fn mmio_read(&self, _base: MmioAddress, _offset: MmioAddressOffset, _data: &mut [u8]) {}
+        fn mmio_write(&self, _base: MmioAddress, _offset: MmioAddressOffset, _data: &[u8]) {}
+
+        fn introspect(&self) -> Vec<(String, String)> {
+            self.0
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect()
+        }
+    }
+
+    // Bounded command line.
+    struct Bounded(String, usize);
+
+    impl KernelCmdline for Bounded {
+        type Error = Infallible;
+
+        fn contains(&self, param: &str) -> bool {
+            KernelCmdline::contains(&self.0, param)
+        }
+
+        fn remaining(&self) -> Option<usize> {
+            Some(self.1 - self.0.len())
+        }
+
+        fn insert_str(&mut self, param: &str) -> Result<(), Self::Error> {
+            KernelCmdline::insert_str(&mut self.0, param)
+        }
+    }
+
+    fn register(
+        manager: &mut IoManager,
+        base: u64,
+        size: u64,
+        device: Arc<dyn DeviceMmio + Send + Sync>,
+    ) {
+        let range = MmioRange::new(MmioAddress(base), size).unwrap();
+        manager.register_mmio(range, device).unwrap();
+    }
+
+    #[test]
+    fn test_virtio_mmio_param() {
+        assert_eq!(
+            virtio_mmio_param(0xd000_0000, 0x200, 5),
+            "virtio_mmio.device=512@0xd0000000:5"
+        );
+        assert_eq!(
+            virtio_mmio_param(0x1_0000_0000, 2 << 20, 32),
+            "virtio_mmio.device=2M@0x100000000:32"
+        );
+    }
+
+    #[test]
+    fn test_append_virtio_mmio_params() {
+        let mut manager = IoManager::new();
+        let blk = Arc::new(Virtio(vec![("type", "virtio-block"), ("irqs", "6")]));
+        let net = Arc::new(Virtio(vec![("type", "virtio-net"), ("irqs", "5")]));
+        register(&mut manager, 0xd000_1000, 0x1000, blk);
+        register(&mut manager, 0xd000_0000, 0x1000, net);
+        register(&mut manager, 0x1000, 0x10, Arc::new(MockDevice::new()));
+
+        let mut cmdline = String::from("console=ttyS0");
+        assert_eq!(manager.append_virtio_mmio_params(&mut cmdline).unwrap(), 2);
+        assert_eq!(
+            cmdline,
+            "console=ttyS0 virtio_mmio.device=4K@0xd0000000:5 \
+             virtio_mmio.device=4K@0xd0001000:6"
+        );
+        // The parameters already there aren't appended again.
+        let appended = cmdline.clone();
+        assert_eq!(manager.append_virtio_mmio_params(&mut cmdline).unwrap(), 0);
+        assert_eq!(cmdline, appended);
+
+        // Nothing is appended when the parameters don't fit.
+        let mut cmdline = Bounded(String::from("console=ttyS0"), 64);
+        assert!(matches!(
+            manager.append_virtio_mmio_params(&mut cmdline),
+            Err(Error::TooLong {
+                needed: 70,
+                remaining: 51
+            })
+        ));
+        assert_eq!(cmdline.0, "console=ttyS0");
+        let mut cmdline = Bounded(String::new(), 70);
+        assert_eq!(manager.append_virtio_mmio_params(&mut cmdline).unwrap(), 2);
+
+        // Every virtio device needs an interrupt.
+        let console = Arc::new(Virtio(vec![
+            ("name", "console0"),
+            ("type", "virtio-console"),
+        ]));
+        register(&mut manager, 0xd000_2000, 0x1000, console);
+        let err = manager.virtio_mmio_params().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "cmdline: device at 0xd0002000 needs one range and one interrupt"
+        );
+    }
+}
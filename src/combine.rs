@@ -0,0 +1,402 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Write combining for device configuration spaces.
+//!
+//! Guests often update configuration fields one byte at a time, which is costly for devices
+//! committing each write to their backend. [`Combined`] wraps a device and buffers the
+//! consecutive writes to adjacent offsets of its configuration space, handing them to the
+//! device as a single write once the guest reads from the device, writes elsewhere or to a
+//! barrier register, or stops writing for longer than a window.
+//!
+//! ```
+//! # use std::sync::{Arc, Mutex};
+//! # use vm_device::bus::{MmioAddress, MmioAddressOffset, MmioRange};
+//! use vm_device::combine::Combined;
+//! use vm_device::device_manager::{IoManager, MmioManager};
+//! use vm_device::MutDeviceMmio;
+//!
+//! // Commits every write to its backend.
+//! #[derive(Default)]
+//! struct Device {
+//!     commits: usize,
+//! }
+//!
+//! impl MutDeviceMmio for Device {
+//!     fn mmio_read(&mut self, _: MmioAddress, _: MmioAddressOffset, _: &mut [u8]) {}
+//!
+//!     fn mmio_write(&mut self, _: MmioAddress, _: MmioAddressOffset, _: &[u8]) {
+//!         self.commits += 1;
+//!     }
+//! }
+//!
+//! let device = Arc::new(Mutex::new(Device::default()));
+//! let combined = Arc::new(Combined::new(device.clone(), 0x100..0x200).with_barrier(0x50));
+//! let mut manager = IoManager::new();
+//! let range = MmioRange::new(MmioAddress(0x1000), 0x200).unwrap();
+//! manager.register_mmio(range, combined).unwrap();
+//! for (i, byte) in [1, 2, 3, 4].iter().enumerate() {
+//!     manager.mmio_write(MmioAddress(0x1100 + i as u64), &[*byte]).unwrap();
+//! }
+//! // Writing the barrier register flushes the configuration write first.
+//! manager.mmio_write(MmioAddress(0x1050), &[1]).unwrap();
+//! assert_eq!(device.lock().unwrap().commits, 2);
+//! ```
+//!
+//! The window is only checked on the next access: the VMM may call
+//! [`Combined::flush_mmio`] or [`Combined::flush_pio`] from a timer to bound how long a
+//! trailing write stays buffered.
+
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::bus::{MmioAddress, MmioAddressOffset, PioAddress, PioAddressOffset};
+use crate::completion::Completion;
+use crate::exit::ExitAccess;
+use crate::health::Health;
+use crate::info::DeviceDetails;
+use crate::resources::Resource;
+use crate::stats::Statistics;
+use crate::{DeviceMmio, DevicePio};
+
+// A write buffered until it's flushed to the device.
+struct Pending {
+    base: u64,
+    offset: u64,
+    data: Vec<u8>,
+    since: Instant,
+}
+
+impl Pending {
+    fn end(&self) -> u64 {
+        self.offset + self.data.len() as u64
+    }
+}
+
+/// A device wrapper combining the consecutive writes to adjacent configuration offsets.
+///
+/// A write to the configuration offsets is buffered, and the following writes are appended
+/// to it as long as they start where the buffered data ends, within the window of the first
+/// one. Any other access flushes the buffered write to the device first, so the device sees
+/// the accesses in the guest's order. Debug reads don't flush, and don't see the buffered
+/// data.
+///
+/// The MMIO and PIO writes are buffered separately. Writes still buffered when the wrapper
+/// is dropped are lost.
+pub struct Combined<D> {
+    device: D,
+    offsets: Range<u64>,
+    barriers: Vec<u64>,
+    window: Duration,
+    mmio: Mutex<Option<Pending>>,
+    pio: Mutex<Option<Pending>>,
+    combined: AtomicU64,
+}
+
+impl<D> Combined<D> {
+    /// Wrap `device`, combining the writes to its configuration space, at `offsets`.
+    ///
+    /// Writes are combined within a window of 100 microseconds by default.
+    pub fn new(device: D, offsets: Range<u64>) -> Self {
+        Combined {
+            device,
+            offsets,
+            barriers: Vec::new(),
+            window: Duration::from_micros(100),
+            mmio: Mutex::new(None),
+            pio: Mutex::new(None),
+            combined: AtomicU64::new(0),
+        }
+    }
+
+    /// Flush the buffered write before each write to the register at `offset`.
+    ///
+    /// Writes covering a barrier are never buffered themselves.
+    pub fn with_barrier(mut self, offset: u64) -> Self {
+        self.barriers.push(offset);
+        self
+    }
+
+    /// Only combine the writes issued within `window` of the first buffered one.
+    pub fn with_window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Return the wrapped device.
+    pub fn inner(&self) -> &D {
+        &self.device
+    }
+
+    /// Return how many writes were appended to a buffered one, saving a device write.
+    pub fn combined(&self) -> u64 {
+        self.combined.load(Ordering::Relaxed)
+    }
+
+    // Buffer the write of `data` at `offset` if possible, or hand it to `write`, after the
+    // buffered write if it can't be appended to it.
+    fn write<F>(&self, slot: &Mutex<Option<Pending>>, base: u64, offset: u64, data: &[u8], write: F)
+    where
+        F: Fn(u64, u64, &[u8]),
+    {
+        let mut pending = slot.lock().unwrap();
+        let combinable = offset.checked_add(data.len() as u64).is_some_and(|end| {
+            self.offsets.start <= offset
+                && end <= self.offsets.end
+                && !self.barriers.iter().any(|b| (offset..end).contains(b))
+        });
+        if let Some(buffered) = pending.as_mut() {
+            if combinable
+                && buffered.base == base
+                && buffered.end() == offset
+                && buffered.since.elapsed() < self.window
+            {
+                buffered.data.extend_from_slice(data);
+                self.combined.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        }
+        if let Some(buffered) = pending.take() {
+            write(buffered.base, buffered.offset, &buffered.data);
+        }
+        if combinable {
+            *pending = Some(Pending {
+                base,
+                offset,
+                data: data.to_vec(),
+                since: Instant::now(),
+            });
+        } else {
+            write(base, offset, data);
+        }
+    }
+
+    // Hand the buffered write, if any, to `write`.
+    fn flush<F: Fn(u64, u64, &[u8])>(slot: &Mutex<Option<Pending>>, write: F) {
+        let mut pending = slot.lock().unwrap();
+        if let Some(buffered) = pending.take() {
+            write(buffered.base, buffered.offset, &buffered.data);
+        }
+    }
+
+    fn properties(&self, mut properties: Vec<(String, String)>) -> Vec<(String, String)> {
+        properties.push(("combined".to_string(), self.combined().to_string()));
+        properties
+    }
+}
+
+impl<D: DeviceMmio> Combined<D> {
+    /// Write the buffered MMIO write, if any, to the device.
+    pub fn flush_mmio(&self) {
+        Self::flush(&self.mmio, |base, offset, data| {
+            self.device.mmio_write(MmioAddress(base), offset, data)
+        });
+    }
+}
+
+impl<D: DevicePio> Combined<D> {
+    /// Write the buffered PIO write, if any, to the device.
+    pub fn flush_pio(&self) {
+        // The buffered addresses come from PIO accesses, so they fit in 16 bits.
+        Self::flush(&self.pio, |base, offset, data| {
+            self.device
+                .pio_write(PioAddress(base as u16), offset as u16, data)
+        });
+    }
+}
+
+impl<D: DeviceMmio> DeviceMmio for Combined<D> {
+    fn mmio_read(&self, base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+        self.flush_mmio();
+        self.device.mmio_read(base, offset, data);
+    }
+
+    fn mmio_write(&self, base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+        self.write(&self.mmio, base.0, offset, data, |base, offset, data| {
+            self.device.mmio_write(MmioAddress(base), offset, data)
+        });
+    }
+
+    fn introspect(&self) -> Vec<(String, String)> {
+        self.properties(self.device.introspect())
+    }
+
+    fn info(&self) -> Option<DeviceDetails> {
+        self.device.info()
+    }
+
+    fn health(&self) -> Health {
+        self.device.health()
+    }
+
+    fn stats(&self) -> Option<Statistics> {
+        self.device.stats()
+    }
+
+    fn update_resources(&self, resources: &[Resource]) {
+        self.device.update_resources(resources)
+    }
+
+    fn debug_read(&self, base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) -> bool {
+        self.device.debug_read(base, offset, data)
+    }
+
+    fn mmio_update(
+        &self,
+        base: MmioAddress,
+        offset: MmioAddressOffset,
+        data: &mut [u8],
+        update: &mut dyn FnMut(&mut [u8]),
+    ) {
+        self.flush_mmio();
+        self.device.mmio_update(base, offset, data, update);
+    }
+
+    fn mmio_deferred(
+        &self,
+        base: MmioAddress,
+        offset: MmioAddressOffset,
+        access: ExitAccess<'_>,
+    ) -> Completion {
+        // The device may only complete the write later, so it isn't buffered.
+        self.flush_mmio();
+        self.device.mmio_deferred(base, offset, access)
+    }
+}
+
+impl<D: DevicePio> DevicePio for Combined<D> {
+    fn pio_read(&self, base: PioAddress, offset: PioAddressOffset, data: &mut [u8]) {
+        self.flush_pio();
+        self.device.pio_read(base, offset, data);
+    }
+
+    fn pio_write(&self, base: PioAddress, offset: PioAddressOffset, data: &[u8]) {
+        let (base, offset) = (base.0.into(), offset.into());
+        self.write(&self.pio, base, offset, data, |base, offset, data| {
+            self.device
+                .pio_write(PioAddress(base as u16), offset as u16, data)
+        });
+    }
+
+    fn introspect(&self) -> Vec<(String, String)> {
+        self.properties(self.device.introspect())
+    }
+
+    fn info(&self) -> Option<DeviceDetails> {
+        self.device.info()
+    }
+
+    fn health(&self) -> Health {
+        self.device.health()
+    }
+
+    fn stats(&self) -> Option<Statistics> {
+        self.device.stats()
+    }
+
+    fn debug_read(&self, base: PioAddress, offset: PioAddressOffset, data: &mut [u8]) -> bool {
+        self.device.debug_read(base, offset, data)
+    }
+
+    fn pio_deferred(
+        &self,
+        base: PioAddress,
+        offset: PioAddressOffset,
+        access: ExitAccess<'_>,
+    ) -> Completion {
+        self.flush_pio();
+        self.device.pio_deferred(base, offset, access)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::bus::{MmioRange, PioRange};
+    use crate::device_manager::{IoManager, MmioManager, PioManager};
+    use crate::testing::{Direction, MockDevice};
+
+    fn writes(device: &MockDevice) -> Vec<(u64, Vec<u8>)> {
+        device
+            .accesses()
+            .into_iter()
+            .filter(|access| access.direction == Direction::Write)
+            .map(|access| (access.offset, access.data))
+            .collect()
+    }
+
+    #[test]
+    fn test_combined() {
+        let mock = Arc::new(MockDevice::new());
+        let device = Arc::new(
+            Combined::new(mock.clone(), 0x100..0x200)
+                .with_barrier(0x1fc)
+                .with_window(Duration::from_secs(60)),
+        );
+        let mut manager = IoManager::new();
+        let range = MmioRange::new(MmioAddress(0x1000), 0x200).unwrap();
+        manager.register_mmio(range, device.clone()).unwrap();
+        let range = PioRange::new(PioAddress(0x60), 0x200).unwrap();
+        manager.register_pio(range, device.clone()).unwrap();
+
+        for offset in 0x100..0x104 {
+            manager
+                .mmio_write(MmioAddress(0x1000 + offset), &[offset as u8])
+                .unwrap();
+        }
+        manager.mmio_write(MmioAddress(0x1104), &[4, 5]).unwrap();
+        assert!(writes(&mock).is_empty());
+        // Reads flush the buffered write first.
+        manager.mmio_read(MmioAddress(0x1000), &mut [0; 4]).unwrap();
+        assert_eq!(writes(&mock), [(0x100, vec![0, 1, 2, 3, 4, 5])]);
+        assert_eq!(mock.accesses()[1].direction, Direction::Read);
+        assert_eq!(device.combined(), 4);
+
+        // Writes which aren't adjacent, outside of the configuration space or to a barrier
+        // aren't combined.
+        mock.clear();
+        manager.mmio_write(MmioAddress(0x1110), &[1]).unwrap();
+        manager.mmio_write(MmioAddress(0x1120), &[2]).unwrap();
+        manager.mmio_write(MmioAddress(0x1121), &[3]).unwrap();
+        manager.mmio_write(MmioAddress(0x1010), &[4]).unwrap();
+        manager.mmio_write(MmioAddress(0x1130), &[5]).unwrap();
+        manager.mmio_write(MmioAddress(0x11fc), &[6; 4]).unwrap();
+        assert_eq!(
+            writes(&mock),
+            [
+                (0x110, vec![1]),
+                (0x120, vec![2, 3]),
+                (0x10, vec![4]),
+                (0x130, vec![5]),
+                (0x1fc, vec![6; 4])
+            ]
+        );
+
+        // The PIO writes are buffered separately.
+        mock.clear();
+        manager.pio_write(PioAddress(0x160), &[1]).unwrap();
+        manager.mmio_write(MmioAddress(0x1110), &[2]).unwrap();
+        manager.pio_write(PioAddress(0x161), &[3]).unwrap();
+        device.flush_pio();
+        device.flush_mmio();
+        assert_eq!(writes(&mock), [(0x100, vec![1, 3]), (0x110, vec![2])]);
+        assert_eq!(mock.accesses()[0].base, 0x60);
+    }
+
+    #[test]
+    fn test_combined_window() {
+        let mock = Arc::new(MockDevice::new());
+        let device = Combined::new(mock.clone(), 0..0x10).with_window(Duration::ZERO);
+        device.mmio_write(MmioAddress(0x1000), 0, &[1]);
+        device.mmio_write(MmioAddress(0x1000), 1, &[2]);
+        assert_eq!(writes(&mock), [(0, vec![1])]);
+        device.flush_mmio();
+        assert_eq!(writes(&mock), [(0, vec![1]), (1, vec![2])]);
+        assert_eq!(device.combined(), 0);
+    }
+}
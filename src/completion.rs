@@ -0,0 +1,491 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Accesses completed asynchronously by the devices.
+//!
+//! Some accesses can't be handled before the vCPU resumes, e.g. a write to the status
+//! register of a device which connects to a backend over the network. Instead of blocking
+//! in the handler, or pretending the access succeeded, such a device implements
+//! [`DeviceMmio::mmio_deferred`] (or the PIO and mutable counterparts): it creates a
+//! [`channel`], starts the backend operation with the [`Completer`], and returns the
+//! [`Pending`] side as [`Completion::Pending`].
+//!
+//! [`IoManager::dispatch_deferred`] parks such accesses and returns them to the exit loop,
+//! which either awaits them, as [`Pending`] is a future usable with any executor, or waits
+//! for them from the vCPU thread. [`IoManager::dispatch_stalling`] does the latter, calling
+//! back the VMM before stalling the vCPU:
+//!
+//! ```ignore
+//! let exit = vcpu.run()?.into_io_exit()?;
+//! manager.dispatch_stalling(exit, |_| metrics.stalled_vcpus.inc())?;
+//! ```
+//!
+//! Backend failures reach the exit loop as [`Error::Backend`], and a device which drops a
+//! [`Completer`] without completing the access as [`Error::Abandoned`].
+
+use std::fmt::{Debug, Display, Formatter};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use crate::bus::{self, BusManager, MmioAddress, PioAddress};
+use crate::device_manager::IoManager;
+use crate::exit::{ExitAccess, IoExit, MmioExit, PioExit};
+use crate::trace;
+use crate::{DeviceMmio, DevicePio};
+
+/// Errors encountered while completing an access.
+#[derive(Debug)]
+pub enum Error {
+    /// The access couldn't be dispatched.
+    Bus(bus::Error),
+    /// The backend of the device failed to complete the access.
+    Backend(Box<dyn std::error::Error + Send + Sync>),
+    /// The device dropped the access without completing it.
+    Abandoned,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Bus(e) => write!(f, "completion: {}", e),
+            Error::Backend(_) => write!(f, "completion: backend failed"),
+            Error::Abandoned => write!(f, "completion: access abandoned by the device"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Bus(e) => Some(e),
+            Error::Backend(e) => Some(e.as_ref()),
+            Error::Abandoned => None,
+        }
+    }
+}
+
+/// Outcome of an access started with [`DeviceMmio::mmio_deferred`] or
+/// [`DevicePio::pio_deferred`].
+#[derive(Debug)]
+pub enum Completion {
+    /// The device handled the access.
+    Done,
+    /// The device completes the access later, through the matching [`Completer`].
+    Pending(Pending),
+}
+
+#[derive(Default)]
+struct State {
+    result: Option<Result<Vec<u8>, Error>>,
+    waker: Option<Waker>,
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    completed: Condvar,
+}
+
+impl Debug for Shared {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Shared").finish_non_exhaustive()
+    }
+}
+
+/// Create a [`Completer`] and the [`Pending`] access it completes.
+pub fn channel() -> (Completer, Pending) {
+    let shared = Arc::new(Shared::default());
+    (
+        Completer {
+            shared: shared.clone(),
+        },
+        Pending { shared },
+    )
+}
+
+/// Completes an access, from any thread or executor.
+///
+/// Dropping the completer without completing the access fails it with
+/// [`Error::Abandoned`].
+pub struct Completer {
+    shared: Arc<Shared>,
+}
+
+impl Completer {
+    /// Complete the access. `data` holds the value of a read, and is ignored for writes.
+    pub fn complete(self, data: &[u8]) {
+        self.finish(Ok(data.to_vec()));
+    }
+
+    /// Fail the access with the error of the backend.
+    pub fn fail<E>(self, error: E)
+    where
+        E: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        self.finish(Err(Error::Backend(error.into())));
+    }
+
+    fn finish(&self, result: Result<Vec<u8>, Error>) {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.result.is_some() {
+            return;
+        }
+        state.result = Some(result);
+        let waker = state.waker.take();
+        drop(state);
+        self.shared.completed.notify_all();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl Drop for Completer {
+    fn drop(&mut self) {
+        self.finish(Err(Error::Abandoned));
+    }
+}
+
+/// An access the device hasn't completed yet.
+///
+/// As a future, it resolves to the value of a read, or an empty buffer for writes.
+#[derive(Debug)]
+pub struct Pending {
+    shared: Arc<Shared>,
+}
+
+impl Pending {
+    /// Return whether the access is complete, so that waiting for it doesn't block.
+    pub fn is_complete(&self) -> bool {
+        self.shared.state.lock().unwrap().result.is_some()
+    }
+
+    /// Block until the access is complete, and return the value of a read.
+    pub fn wait(self) -> Result<Vec<u8>, Error> {
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if let Some(result) = state.result.take() {
+                return result;
+            }
+            state = self.shared.completed.wait(state).unwrap();
+        }
+    }
+}
+
+impl Future for Pending {
+    type Output = Result<Vec<u8>, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.shared.state.lock().unwrap();
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+// Reborrow `exit`, so it can still be used once the access is dispatched.
+fn reborrow<'b>(exit: &'b mut IoExit<'_>) -> IoExit<'b> {
+    let access = |access: &'b mut ExitAccess<'_>| match access {
+        ExitAccess::Read(data) => ExitAccess::Read(data),
+        ExitAccess::Write(data) => ExitAccess::Write(data),
+    };
+    match exit {
+        IoExit::Mmio(exit) => IoExit::Mmio(MmioExit {
+            addr: exit.addr,
+            access: access(&mut exit.access),
+        }),
+        IoExit::Pio(exit) => IoExit::Pio(PioExit {
+            addr: exit.addr,
+            access: access(&mut exit.access),
+        }),
+    }
+}
+
+impl IoManager {
+    /// Dispatch the access which caused `exit` to the device registered at its address,
+    /// letting the device complete it asynchronously.
+    ///
+    /// Returns the parked access if the device didn't complete it, in which case the vCPU
+    /// must not resume before it is, and the buffer of a read has to be filled in with the
+    /// value the access resolves to.
+    pub fn dispatch_deferred(&self, exit: IoExit<'_>) -> Result<Option<Pending>, bus::Error> {
+        let completion = match exit {
+            IoExit::Mmio(MmioExit { addr, access }) => {
+                let bus = BusManager::<MmioAddress>::bus(self);
+                let _access = bus.begin_access();
+                let (kind, len) = describe(&access);
+                let result = bus.check_access(addr, len);
+                let (range, device) = trace::dispatch("mmio", kind, addr.0, len, result)?;
+                device.mmio_deferred(range.base(), addr - range.base(), access)
+            }
+            IoExit::Pio(PioExit { addr, access }) => {
+                let bus = BusManager::<PioAddress>::bus(self);
+                let _access = bus.begin_access();
+                let (kind, len) = describe(&access);
+                let result = bus.check_access(addr, len);
+                let (range, device) = trace::dispatch("pio", kind, u64::from(addr.0), len, result)?;
+                device.pio_deferred(range.base(), addr - range.base(), access)
+            }
+        };
+        Ok(match completion {
+            Completion::Done => None,
+            Completion::Pending(pending) => Some(pending),
+        })
+    }
+
+    /// Dispatch the access which caused `exit` like [`IoManager::dispatch_deferred`], and
+    /// stall the calling vCPU thread until it is complete.
+    ///
+    /// `stall` is called with the parked access before the thread blocks, e.g. to account
+    /// for the stalled vCPU, or to kick the event loop running the backend.
+    pub fn dispatch_stalling<S>(&self, mut exit: IoExit<'_>, stall: S) -> Result<(), Error>
+    where
+        S: FnOnce(&Pending),
+    {
+        let pending = match self.dispatch_deferred(reborrow(&mut exit)) {
+            Ok(Some(pending)) => pending,
+            Ok(None) => return Ok(()),
+            Err(e) => return Err(Error::Bus(e)),
+        };
+        stall(&pending);
+        let value = pending.wait()?;
+        let access = match &mut exit {
+            IoExit::Mmio(exit) => &mut exit.access,
+            IoExit::Pio(exit) => &mut exit.access,
+        };
+        if let ExitAccess::Read(data) = access {
+            let len = value.len().min(data.len());
+            data[..len].copy_from_slice(&value[..len]);
+            data[len..].fill(0);
+        }
+        Ok(())
+    }
+}
+
+// Kind and length of `access`, as traced.
+fn describe(access: &ExitAccess<'_>) -> (&'static str, usize) {
+    match access {
+        ExitAccess::Read(data) => ("read", data.len()),
+        ExitAccess::Write(data) => ("write", data.len()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::Wake;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::bus::{MmioAddressOffset, MmioRange, PioAddressOffset, PioRange};
+    use crate::device_manager::{MmioManager, PioManager};
+    use crate::latency::{Delayed, Latency};
+    use crate::testing::Scratchpad;
+    use crate::{MutDeviceMmio, MutDevicePio};
+
+    const STATUS: u64 = 0x70;
+
+    // Device connecting to a backend when the driver writes its status register, and
+    // reading the status back from the backend.
+    #[derive(Default)]
+    struct Connector {
+        completers: Vec<Completer>,
+    }
+
+    impl Connector {
+        fn start(&mut self) -> Completion {
+            let (completer, pending) = channel();
+            self.completers.push(completer);
+            Completion::Pending(pending)
+        }
+    }
+
+    impl MutDeviceMmio for Connector {
+        fn mmio_read(&mut self, _base: MmioAddress, _offset: MmioAddressOffset, _data: &mut [u8]) {}
+        fn mmio_write(&mut self, _base: MmioAddress, _offset: MmioAddressOffset, _data: &[u8]) {}
+
+        fn mmio_deferred(
+            &mut self,
+            _base: MmioAddress,
+            offset: MmioAddressOffset,
+            access: ExitAccess<'_>,
+        ) -> Completion {
+            match offset {
+                STATUS => self.start(),
+                _ => {
+                    if let ExitAccess::Read(data) = access {
+                        data.fill(0xff);
+                    }
+                    Completion::Done
+                }
+            }
+        }
+    }
+
+    impl MutDevicePio for Connector {
+        fn pio_read(&mut self, _base: PioAddress, _offset: PioAddressOffset, _data: &mut [u8]) {}
+        fn pio_write(&mut self, _base: PioAddress, _offset: PioAddressOffset, _data: &[u8]) {}
+
+        fn pio_deferred(
+            &mut self,
+            _base: PioAddress,
+            _offset: PioAddressOffset,
+            _access: ExitAccess<'_>,
+        ) -> Completion {
+            self.start()
+        }
+    }
+
+    struct Counter(AtomicUsize);
+
+    impl Wake for Counter {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn mmio(addr: u64, access: ExitAccess<'_>) -> IoExit<'_> {
+        IoExit::Mmio(MmioExit {
+            addr: MmioAddress(addr),
+            access,
+        })
+    }
+
+    fn setup() -> (IoManager, Arc<Mutex<Connector>>) {
+        let mut manager = IoManager::new();
+        let device = Arc::new(Mutex::new(Connector::default()));
+        let range = MmioRange::new(MmioAddress(0x1000), 0x100).unwrap();
+        manager.register_mmio(range, device.clone()).unwrap();
+        let range = PioRange::new(PioAddress(0x60), 4).unwrap();
+        manager.register_pio(range, device.clone()).unwrap();
+        let range = MmioRange::new(MmioAddress(0x2000), 0x10).unwrap();
+        manager
+            .register_mmio(range, Arc::new(Scratchpad::new(0x10)))
+            .unwrap();
+        (manager, device)
+    }
+
+    #[test]
+    fn test_dispatch_deferred() {
+        let (manager, device) = setup();
+        let mut data = [0; 4];
+
+        // Devices completing their accesses synchronously, including the ones relying on
+        // the default implementation.
+        let write = mmio(0x2000, ExitAccess::Write(&[1, 2, 3, 4]));
+        assert!(manager.dispatch_deferred(write).unwrap().is_none());
+        let read = mmio(0x2000, ExitAccess::Read(&mut data));
+        assert!(manager.dispatch_deferred(read).unwrap().is_none());
+        assert_eq!(data, [1, 2, 3, 4]);
+        let read = mmio(0x1000, ExitAccess::Read(&mut data));
+        assert!(manager.dispatch_deferred(read).unwrap().is_none());
+        assert_eq!(data, [0xff; 4]);
+        let read = mmio(0x3000, ExitAccess::Read(&mut data));
+        assert!(manager.dispatch_deferred(read).is_err());
+
+        // The parked access completes through an executor agnostic waker.
+        let write = mmio(0x1000 + STATUS, ExitAccess::Write(&[1, 0, 0, 0]));
+        let mut pending = manager.dispatch_deferred(write).unwrap().unwrap();
+        let counter = Arc::new(Counter(AtomicUsize::new(0)));
+        let waker = Waker::from(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+        assert!(Pin::new(&mut pending).poll(&mut cx).is_pending());
+        assert!(!pending.is_complete());
+        device
+            .lock()
+            .unwrap()
+            .completers
+            .pop()
+            .unwrap()
+            .complete(&[]);
+        assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+        assert!(pending.is_complete());
+        match Pin::new(&mut pending).poll(&mut cx) {
+            Poll::Ready(Ok(value)) => assert!(value.is_empty()),
+            _ => panic!("access not complete"),
+        }
+
+        // Backend failures and abandoned accesses are reported.
+        let write = mmio(0x1000 + STATUS, ExitAccess::Write(&[1, 0, 0, 0]));
+        let pending = manager.dispatch_deferred(write).unwrap().unwrap();
+        let completer = device.lock().unwrap().completers.pop().unwrap();
+        completer.fail("connection refused");
+        let err = pending.wait().unwrap_err();
+        assert_eq!(err.to_string(), "completion: backend failed");
+        let write = mmio(0x1000 + STATUS, ExitAccess::Write(&[0; 4]));
+        let pending = manager.dispatch_deferred(write).unwrap().unwrap();
+        device.lock().unwrap().completers.clear();
+        assert!(matches!(pending.wait(), Err(Error::Abandoned)));
+    }
+
+    #[test]
+    fn test_dispatch_stalling() {
+        let (manager, device) = setup();
+        let mut data = [0xaa; 4];
+
+        // The backend completes the read from another thread while the vCPU is stalled.
+        let stalled = AtomicUsize::new(0);
+        let read = IoExit::Pio(PioExit {
+            addr: PioAddress(0x60),
+            access: ExitAccess::Read(&mut data),
+        });
+        manager
+            .dispatch_stalling(read, |pending| {
+                assert!(!pending.is_complete());
+                stalled.fetch_add(1, Ordering::SeqCst);
+                let completer = device.lock().unwrap().completers.pop().unwrap();
+                thread::spawn(move || completer.complete(&[7, 8]));
+            })
+            .unwrap();
+        assert_eq!(stalled.load(Ordering::SeqCst), 1);
+        assert_eq!(data, [7, 8, 0, 0]);
+
+        // Accesses completed right away don't stall the vCPU.
+        let read = mmio(0x1000, ExitAccess::Read(&mut data));
+        manager
+            .dispatch_stalling(read, |_| {
+                stalled.fetch_add(1, Ordering::SeqCst);
+            })
+            .unwrap();
+        assert_eq!(stalled.load(Ordering::SeqCst), 1);
+        let write = mmio(0x3000, ExitAccess::Write(&[0]));
+        let err = manager.dispatch_stalling(write, |_| {}).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "completion: device not found at 0x3000 (access length 1)"
+        );
+    }
+
+    #[test]
+    fn test_wrapped_deferred() {
+        let mut manager = IoManager::new();
+        let device = Arc::new(Mutex::new(Connector::default()));
+        let delayed = Arc::new(Delayed::new(device.clone(), Latency::Fixed(Duration::ZERO)));
+        let range = MmioRange::new(MmioAddress(0x1000), 0x100).unwrap();
+        manager.register_mmio(range, delayed.clone()).unwrap();
+        let range = PioRange::new(PioAddress(0x60), 4).unwrap();
+        manager.register_pio(range, delayed).unwrap();
+
+        // The wrapper hands the accesses to the device, which completes them later.
+        let write = mmio(0x1000 + STATUS, ExitAccess::Write(&[1, 0, 0, 0]));
+        let pending = manager.dispatch_deferred(write).unwrap().unwrap();
+        let mut data = [0; 2];
+        let read = IoExit::Pio(PioExit {
+            addr: PioAddress(0x60),
+            access: ExitAccess::Read(&mut data),
+        });
+        assert!(manager.dispatch_deferred(read).unwrap().is_some());
+        let mut device = device.lock().unwrap();
+        assert_eq!(device.completers.len(), 2);
+        device.completers.remove(0).complete(&[]);
+        assert!(pending.is_complete());
+    }
+}
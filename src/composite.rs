@@ -0,0 +1,254 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Devices owning several MMIO ranges with different roles.
+//!
+//! Some devices expose more than one range, e.g. control registers, a doorbell page and a
+//! shared memory window. Registering the same object for each range leaves it to the device
+//! to tell the ranges apart from their base address, which breaks as soon as the ranges are
+//! moved. A [`CompositeDevice`] is registered once with
+//! [`IoManager::register_composite`], along with the role of each of its ranges, and every
+//! access is dispatched with the role of the range it hit:
+//!
+//! ```
+//! # use std::sync::Arc;
+//! # use std::sync::atomic::{AtomicU64, Ordering};
+//! use vm_device::bus::{MmioAddress, MmioAddressOffset, MmioRange};
+//! use vm_device::composite::CompositeDevice;
+//! use vm_device::device_manager::{IoManager, MmioManager};
+//!
+//! #[derive(Clone, Copy, Debug)]
+//! enum Role {
+//!     Control,
+//!     Doorbell,
+//! }
+//!
+//! #[derive(Default)]
+//! struct Device {
+//!     notified: AtomicU64,
+//! }
+//!
+//! impl CompositeDevice for Device {
+//!     type Role = Role;
+//!
+//!     fn composite_read(&self, _: Role, _: MmioAddress, _: MmioAddressOffset, data: &mut [u8]) {
+//!         data.fill(0);
+//!     }
+//!
+//!     fn composite_write(&self, role: Role, _: MmioAddress, offset: MmioAddressOffset, _: &[u8]) {
+//!         if let Role::Doorbell = role {
+//!             self.notified.store(offset, Ordering::Relaxed);
+//!         }
+//!     }
+//! }
+//!
+//! let device = Arc::new(Device::default());
+//! let mut manager = IoManager::new();
+//! let ranges = [
+//!     (Role::Control, MmioRange::new(MmioAddress(0x1000), 0x100).unwrap()),
+//!     (Role::Doorbell, MmioRange::new(MmioAddress(0x8000), 0x1000).unwrap()),
+//! ];
+//! manager.register_composite(device.clone(), &ranges).unwrap();
+//! manager.mmio_write(MmioAddress(0x8004), &[1]).unwrap();
+//! assert_eq!(device.notified.load(Ordering::Relaxed), 4);
+//! ```
+//!
+//! [`IoManager::register_composite`]: crate::device_manager::IoManager::register_composite
+
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use crate::bus::{MmioAddress, MmioAddressOffset};
+use crate::DeviceMmio;
+
+/// A device owning several MMIO ranges, each of them with a role.
+pub trait CompositeDevice {
+    /// Identifies the ranges of the device, e.g. an enum with a variant per range.
+    type Role: Copy + Debug + Send + Sync + 'static;
+
+    /// Handle a read operation on the range with `role`.
+    ///
+    /// # Arguments
+    ///
+    /// * `role`:   role of the accessed range
+    /// * `base`:   base address of the range on the MMIO bus
+    /// * `offset`: offset of the access in the range
+    /// * `data`:   a buffer provided by the caller to store the read data
+    fn composite_read(
+        &self,
+        role: Self::Role,
+        base: MmioAddress,
+        offset: MmioAddressOffset,
+        data: &mut [u8],
+    );
+
+    /// Handle a write operation to the range with `role`.
+    ///
+    /// # Arguments
+    ///
+    /// * `role`:   role of the accessed range
+    /// * `base`:   base address of the range on the MMIO bus
+    /// * `offset`: offset of the access in the range
+    /// * `data`:   a buffer provided by the caller holding the data to write
+    fn composite_write(
+        &self,
+        role: Self::Role,
+        base: MmioAddress,
+        offset: MmioAddressOffset,
+        data: &[u8],
+    );
+
+    /// Describe the current state of the device, like [`DeviceMmio::introspect`].
+    ///
+    /// The default implementation returns no properties.
+    fn introspect(&self) -> Vec<(String, String)> {
+        Vec::new()
+    }
+}
+
+// One of the ranges of a composite device, as registered on the bus.
+pub(crate) struct CompositePart<T: CompositeDevice + ?Sized> {
+    device: Arc<T>,
+    role: T::Role,
+}
+
+impl<T: CompositeDevice + ?Sized> CompositePart<T> {
+    pub(crate) fn new(device: Arc<T>, role: T::Role) -> Self {
+        CompositePart { device, role }
+    }
+}
+
+impl<T: CompositeDevice + ?Sized> DeviceMmio for CompositePart<T> {
+    fn mmio_read(&self, base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+        self.device.composite_read(self.role, base, offset, data)
+    }
+
+    fn mmio_write(&self, base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+        self.device.composite_write(self.role, base, offset, data)
+    }
+
+    fn introspect(&self) -> Vec<(String, String)> {
+        let mut properties = vec![("role".to_string(), format!("{:?}", self.role))];
+        properties.extend(self.device.introspect());
+        properties
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use crate::bus::{self, MmioRange};
+    use crate::device_manager::{Error, IoManager, MmioManager};
+
+    #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+    enum Role {
+        Control,
+        Doorbell,
+        Shm,
+    }
+
+    #[derive(Default)]
+    struct Device {
+        accesses: Mutex<Vec<(Role, u64, MmioAddressOffset)>>,
+    }
+
+    impl CompositeDevice for Device {
+        type Role = Role;
+
+        fn composite_read(
+            &self,
+            role: Role,
+            base: MmioAddress,
+            offset: MmioAddressOffset,
+            data: &mut [u8],
+        ) {
+            data.fill(role as u8);
+            self.accesses.lock().unwrap().push((role, base.0, offset));
+        }
+
+        fn composite_write(
+            &self,
+            role: Role,
+            base: MmioAddress,
+            offset: MmioAddressOffset,
+            _data: &[u8],
+        ) {
+            self.accesses.lock().unwrap().push((role, base.0, offset));
+        }
+
+        fn introspect(&self) -> Vec<(String, String)> {
+            let accesses = self.accesses.lock().unwrap().len();
+            vec![("accesses".to_string(), accesses.to_string())]
+        }
+    }
+
+    fn range(base: u64, size: u64) -> MmioRange {
+        MmioRange::new(MmioAddress(base), size).unwrap()
+    }
+
+    #[test]
+    fn test_composite_device() {
+        let device = Arc::new(Device::default());
+        let mut manager = IoManager::new();
+        let ranges = [
+            (Role::Control, range(0x1000, 0x100)),
+            (Role::Doorbell, range(0x2000, 0x1000)),
+            (Role::Shm, range(0x10_0000, 0x1_0000)),
+        ];
+        manager.register_composite(device.clone(), &ranges).unwrap();
+
+        let mut data = [0xff; 4];
+        manager.mmio_read(MmioAddress(0x1010), &mut data).unwrap();
+        assert_eq!(data, [0; 4]);
+        manager.mmio_write(MmioAddress(0x2004), &[1]).unwrap();
+        manager
+            .mmio_read(MmioAddress(0x10_0008), &mut data)
+            .unwrap();
+        assert_eq!(data, [2; 4]);
+        assert_eq!(
+            *device.accesses.lock().unwrap(),
+            [
+                (Role::Control, 0x1000, 0x10),
+                (Role::Doorbell, 0x2000, 0x4),
+                (Role::Shm, 0x10_0000, 0x8)
+            ]
+        );
+        let (_, part) = manager.mmio_device(MmioAddress(0x2000)).unwrap();
+        assert_eq!(
+            part.introspect(),
+            [
+                ("role".to_string(), "Doorbell".to_string()),
+                ("accesses".to_string(), "3".to_string())
+            ]
+        );
+
+        // A range which can't be registered undoes the registration of the others.
+        let other = Arc::new(Device::default());
+        let ranges = [
+            (Role::Control, range(0x3000, 0x100)),
+            (Role::Doorbell, range(0x2800, 0x100)),
+        ];
+        assert_eq!(
+            manager.register_composite(other, &ranges),
+            Err(Error::Resource {
+                index: 1,
+                error: bus::Error::DeviceOverlap {
+                    base: 0x2800,
+                    size: 0x100
+                }
+            })
+        );
+        assert!(manager.mmio_device(MmioAddress(0x3000)).is_none());
+
+        let ranges = [
+            (Role::Control, range(0x1000, 0x100)),
+            (Role::Doorbell, range(0x2000, 0x1000)),
+            (Role::Shm, range(0x10_0000, 0x1_0000)),
+        ];
+        assert_eq!(manager.deregister_composite(&ranges), 3);
+        assert!(manager.mmio_device(MmioAddress(0x1000)).is_none());
+    }
+}
@@ -0,0 +1,529 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Device layouts loaded from configuration files (`config` feature).
+//!
+//! A [`LayoutConfig`] describes the MMIO devices of a VM: their type, their MMIO range and
+//! interrupt, and type specific options. Its fields left unspecified, e.g. the base address
+//! of a device or an `"auto"` interrupt, are allocated with a [`ResourceAllocator`], while
+//! the specified ones are reserved with it. [`LayoutConfig::load`] then creates the devices
+//! with a [`DeviceRegistry`], handing them their options as a JSON configuration blob, and
+//! registers them with an [`IoManager`]. It returns a [`Report`] of what was placed where.
+//!
+//! [`LayoutConfig::from_json`] parses JSON descriptions. Descriptions in other formats, e.g.
+//! TOML, are deserialized into a [`LayoutConfig`] with the matching serde crate.
+//!
+//! ```
+//! # use std::sync::Arc;
+//! # use vm_device::bus::{MmioAddress, MmioAddressOffset};
+//! use vm_device::config::LayoutConfig;
+//! use vm_device::device_manager::IoManager;
+//! use vm_device::registry::DeviceRegistry;
+//! # use vm_device::resources::{Resource, ResourceAllocator, ResourceConstraint};
+//! # use vm_device::resources::ResourceReservation;
+//! # use vm_device::DeviceMmio;
+//! # struct Uart;
+//! # impl DeviceMmio for Uart {
+//! #     fn mmio_read(&self, _: MmioAddress, _: MmioAddressOffset, _: &mut [u8]) {}
+//! #     fn mmio_write(&self, _: MmioAddress, _: MmioAddressOffset, _: &[u8]) {}
+//! # }
+//! # struct Allocator;
+//! # impl ResourceReservation for Allocator {
+//! #     fn reserve(&mut self, _: &Resource) -> bool { true }
+//! #     fn release(&mut self, _: &Resource) {}
+//! # }
+//! # impl ResourceAllocator for Allocator {
+//! #     fn allocate(&mut self, constraint: &ResourceConstraint) -> Option<Resource> {
+//! #         match *constraint {
+//! #             ResourceConstraint::MmioAddress { size, .. } => {
+//! #                 Some(Resource::MmioAddressRange { base: 0xd000_0000, size })
+//! #             }
+//! #             ResourceConstraint::LegacyIrq { .. } => Some(Resource::LegacyIrq(5)),
+//! #             _ => None,
+//! #         }
+//! #     }
+//! # }
+//! # let mut allocator = Allocator;
+//!
+//! let mut registry = DeviceRegistry::new();
+//! registry
+//!     .register("pl011", |_resources, _options| Ok(Arc::new(Uart)))
+//!     .unwrap();
+//!
+//! let config = LayoutConfig::from_json(
+//!     r#"{ "devices": [
+//!         { "type": "pl011", "id": "uart0", "base": 150994944, "size": 4096, "irq": 1 },
+//!         { "type": "pl011", "id": "uart1", "size": 4096, "irq": "auto" }
+//!     ] }"#,
+//! )
+//! .unwrap();
+//! let mut manager = IoManager::new();
+//! let report = config
+//!     .load(&mut manager, &registry, &mut allocator)
+//!     .unwrap();
+//! assert_eq!(report.devices[1].base, 0xd000_0000);
+//! assert_eq!(report.devices[1].irq, Some(5));
+//! ```
+
+use std::convert::TryFrom;
+use std::fmt::{Display, Formatter};
+
+use serde::{Deserialize, Serialize};
+
+use crate::device_manager::IoManager;
+use crate::registry::{self, DeviceRegistry};
+use crate::resources::{
+    DeviceResources, Resource, ResourceAllocator, ResourceConstraint, ResourceReservation,
+};
+
+/// Errors encountered while loading a device layout.
+#[derive(Debug)]
+pub enum Error {
+    /// The description couldn't be parsed.
+    Parse(serde_json::Error),
+    /// A resource specified by the device at the index couldn't be reserved.
+    ResourceUnavailable {
+        /// Position of the device in the layout.
+        index: usize,
+        /// The resource.
+        resource: Resource,
+    },
+    /// No resource could be allocated for the device at the index.
+    Exhausted {
+        /// Position of the device in the layout.
+        index: usize,
+        /// Constraint of the missing resource.
+        constraint: ResourceConstraint,
+    },
+    /// The device at the index couldn't be created or registered.
+    Registry {
+        /// Position of the device in the layout.
+        index: usize,
+        /// Cause of the failure.
+        error: registry::Error,
+    },
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Parse(e) => write!(f, "config: {}", e),
+            Error::ResourceUnavailable { index, resource } => {
+                write!(f, "config: device {}: {} is not available", index, resource)
+            }
+            Error::Exhausted { index, constraint } => {
+                write!(
+                    f,
+                    "config: device {}: cannot allocate {}",
+                    index, constraint
+                )
+            }
+            Error::Registry { index, error } => write!(f, "config: device {}: {}", index, error),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Parse(e) => Some(e),
+            Error::Registry { error, .. } => Some(error),
+            Error::ResourceUnavailable { .. } | Error::Exhausted { .. } => None,
+        }
+    }
+}
+
+/// Interrupt of a device in a [`LayoutConfig`].
+///
+/// Written as the number of the interrupt line, or `"auto"` for an allocated one.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(try_from = "IrqValue", into = "IrqValue")]
+pub enum IrqConfig {
+    /// The given legacy interrupt line.
+    Line(u32),
+    /// A legacy interrupt line allocated for the device.
+    Auto,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum IrqValue {
+    Line(u32),
+    Keyword(String),
+}
+
+impl TryFrom<IrqValue> for IrqConfig {
+    type Error = String;
+
+    fn try_from(value: IrqValue) -> Result<Self, String> {
+        match value {
+            IrqValue::Line(irq) => Ok(IrqConfig::Line(irq)),
+            IrqValue::Keyword(keyword) if keyword == "auto" => Ok(IrqConfig::Auto),
+            IrqValue::Keyword(keyword) => Err(format!("invalid irq {:?}", keyword)),
+        }
+    }
+}
+
+impl From<IrqConfig> for IrqValue {
+    fn from(irq: IrqConfig) -> Self {
+        match irq {
+            IrqConfig::Line(irq) => IrqValue::Line(irq),
+            IrqConfig::Auto => IrqValue::Keyword("auto".to_string()),
+        }
+    }
+}
+
+/// Description of a device in a [`LayoutConfig`].
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceConfig {
+    /// Type of the device, the name of its factory in the [`DeviceRegistry`].
+    #[serde(rename = "type")]
+    pub device_type: String,
+    /// Name of the device, for the report.
+    #[serde(default)]
+    pub id: Option<String>,
+    /// Base address of the MMIO range of the device, allocated if unspecified.
+    #[serde(default)]
+    pub base: Option<u64>,
+    /// Size of the MMIO range of the device.
+    pub size: u64,
+    /// Interrupt of the device, if any.
+    #[serde(default)]
+    pub irq: Option<IrqConfig>,
+    /// Type specific options, handed to the factory of the device as a JSON configuration
+    /// blob, or an empty one if unspecified.
+    #[serde(default)]
+    pub options: serde_json::Value,
+}
+
+/// Description of the MMIO devices of a VM.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct LayoutConfig {
+    /// The devices, created in order.
+    pub devices: Vec<DeviceConfig>,
+}
+
+/// Where a device of a [`LayoutConfig`] was placed.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct Placement {
+    /// Name of the device, if any.
+    pub id: Option<String>,
+    /// Type of the device.
+    pub device_type: String,
+    /// Base address of the MMIO range of the device.
+    pub base: u64,
+    /// Size of the MMIO range of the device.
+    pub size: u64,
+    /// Interrupt line of the device, if any.
+    pub irq: Option<u32>,
+    /// Whether the base address was allocated.
+    pub allocated_base: bool,
+    /// Whether the interrupt line was allocated.
+    pub allocated_irq: bool,
+}
+
+/// Report of a [`LayoutConfig`] loaded with [`LayoutConfig::load`].
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct Report {
+    /// Placement of the devices, in the order of the layout.
+    pub devices: Vec<Placement>,
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for device in self.devices.iter() {
+            write!(
+                f,
+                "{} {} at {:#x}+{:#x}",
+                device.device_type,
+                device.id.as_deref().unwrap_or("-"),
+                device.base,
+                device.size
+            )?;
+            if let Some(irq) = device.irq {
+                write!(f, " irq {}", irq)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl LayoutConfig {
+    /// Parse a JSON description of the devices.
+    pub fn from_json(json: &str) -> Result<Self, Error> {
+        serde_json::from_str(json).map_err(Error::Parse)
+    }
+
+    /// Create the devices of the layout with `registry`, and register them with `manager`.
+    ///
+    /// The MMIO ranges and interrupts given by the layout are reserved with `allocator`,
+    /// and the missing ones allocated with it. Upon failure, the devices registered so far
+    /// are deregistered and their resources released.
+    pub fn load<A>(
+        &self,
+        manager: &mut IoManager,
+        registry: &DeviceRegistry,
+        allocator: &mut A,
+    ) -> Result<Report, Error>
+    where
+        A: ResourceAllocator + ?Sized,
+    {
+        let mut report = Report::default();
+        let mut placed: Vec<DeviceResources> = Vec::new();
+        for (index, device) in self.devices.iter().enumerate() {
+            match place(index, device, manager, registry, allocator) {
+                Ok((resources, placement)) => {
+                    placed.push(resources);
+                    report.devices.push(placement);
+                }
+                Err(e) => {
+                    for resources in placed.iter().rev() {
+                        manager.deregister_resources(resources.get_all_resources());
+                        release(allocator, resources);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(report)
+    }
+}
+
+// Acquire the resources of `device`, and create and register it.
+fn place<A>(
+    index: usize,
+    device: &DeviceConfig,
+    manager: &mut IoManager,
+    registry: &DeviceRegistry,
+    allocator: &mut A,
+) -> Result<(DeviceResources, Placement), Error>
+where
+    A: ResourceAllocator + ?Sized,
+{
+    let mut resources = DeviceResources::new();
+    let result = acquire(index, device, allocator, &mut resources).and_then(|()| {
+        let options = match &device.options {
+            serde_json::Value::Null => Vec::new(),
+            options => options.to_string().into_bytes(),
+        };
+        registry
+            .add(manager, &device.device_type, &resources, &options)
+            .map_err(|error| Error::Registry { index, error })
+    });
+    if let Err(e) = result {
+        release(allocator, &resources);
+        return Err(e);
+    }
+
+    let (base, size) = resources.get_mmio_address_ranges()[0];
+    let placement = Placement {
+        id: device.id.clone(),
+        device_type: device.device_type.clone(),
+        base,
+        size,
+        irq: resources.get_legacy_irq(),
+        allocated_base: device.base.is_none(),
+        allocated_irq: device.irq == Some(IrqConfig::Auto),
+    };
+    Ok((resources, placement))
+}
+
+// Reserve or allocate the resources of `device`, appending them to `resources`.
+fn acquire<A>(
+    index: usize,
+    device: &DeviceConfig,
+    allocator: &mut A,
+    resources: &mut DeviceResources,
+) -> Result<(), Error>
+where
+    A: ResourceAllocator + ?Sized,
+{
+    let range = device.base.map(|base| Resource::MmioAddressRange {
+        base,
+        size: device.size,
+    });
+    let constraint = ResourceConstraint::new_mmio(device.size);
+    resources.append(reserve(index, allocator, range, constraint)?);
+    let irq = match device.irq {
+        None => return Ok(()),
+        Some(IrqConfig::Line(irq)) => Some(Resource::LegacyIrq(irq)),
+        Some(IrqConfig::Auto) => None,
+    };
+    let constraint = ResourceConstraint::new_legacy_irq(None);
+    resources.append(reserve(index, allocator, irq, constraint)?);
+    Ok(())
+}
+
+// Reserve `resource`, or allocate one satisfying `constraint` if it's not specified.
+fn reserve<A>(
+    index: usize,
+    allocator: &mut A,
+    resource: Option<Resource>,
+    constraint: ResourceConstraint,
+) -> Result<Resource, Error>
+where
+    A: ResourceAllocator + ?Sized,
+{
+    match resource {
+        Some(resource) if allocator.reserve(&resource) => Ok(resource),
+        Some(resource) => Err(Error::ResourceUnavailable { index, resource }),
+        None => allocator
+            .allocate(&constraint)
+            .ok_or(Error::Exhausted { index, constraint }),
+    }
+}
+
+fn release<A: ResourceReservation + ?Sized>(allocator: &mut A, resources: &DeviceResources) {
+    resources
+        .get_all_resources()
+        .iter()
+        .for_each(|res| allocator.release(res));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::BTreeSet;
+    use std::sync::Arc;
+
+    use crate::bus::MmioAddress;
+    use crate::device_manager::MmioManager;
+    use crate::testing::Scratchpad;
+    use crate::DeviceMmio;
+
+    // Hands out 4 KiB MMIO pages from 0x1000_0000, and interrupts from 5.
+    #[derive(Default)]
+    struct Allocator {
+        used: BTreeSet<(bool, u64)>,
+    }
+
+    impl Allocator {
+        fn key(resource: &Resource) -> (bool, u64) {
+            match *resource {
+                Resource::MmioAddressRange { base, .. } => (true, base),
+                Resource::LegacyIrq(irq) => (false, irq.into()),
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    impl ResourceReservation for Allocator {
+        fn reserve(&mut self, resource: &Resource) -> bool {
+            self.used.insert(Allocator::key(resource))
+        }
+
+        fn release(&mut self, resource: &Resource) {
+            self.used.remove(&Allocator::key(resource));
+        }
+    }
+
+    impl ResourceAllocator for Allocator {
+        fn allocate(&mut self, constraint: &ResourceConstraint) -> Option<Resource> {
+            let resource = match *constraint {
+                ResourceConstraint::MmioAddress { size, .. } => (0..16)
+                    .map(|page| Resource::MmioAddressRange {
+                        base: 0x1000_0000 + page * 0x1000,
+                        size,
+                    })
+                    .find(|res| !self.used.contains(&Allocator::key(res)))?,
+                ResourceConstraint::LegacyIrq { .. } => (5..8)
+                    .map(Resource::LegacyIrq)
+                    .find(|res| !self.used.contains(&Allocator::key(res)))?,
+                _ => return None,
+            };
+            self.reserve(&resource);
+            Some(resource)
+        }
+    }
+
+    fn registry() -> DeviceRegistry {
+        let mut registry = DeviceRegistry::new();
+        registry
+            .register("scratchpad", |resources, options| {
+                let (_, size) = resources.get_mmio_address_ranges()[0];
+                let scratchpad = Scratchpad::new(size as usize);
+                // The first byte of the options reads back at offset 0.
+                scratchpad.mmio_write(MmioAddress(0), 0, &options[..options.len().min(1)]);
+                Ok(Arc::new(scratchpad))
+            })
+            .unwrap();
+        registry
+    }
+
+    #[test]
+    fn test_load() {
+        let config = LayoutConfig::from_json(
+            r#"{ "devices": [
+                { "type": "scratchpad", "id": "fixed", "base": 4096, "size": 16, "irq": 7 },
+                { "type": "scratchpad", "size": 16, "irq": "auto", "options": { "x": 1 } },
+                { "type": "scratchpad", "size": 16 }
+            ] }"#,
+        )
+        .unwrap();
+        let registry = registry();
+        let mut allocator = Allocator::default();
+        let mut manager = IoManager::new();
+        let report = config
+            .load(&mut manager, &registry, &mut allocator)
+            .unwrap();
+        let placed: Vec<_> = report
+            .devices
+            .iter()
+            .map(|d| (d.base, d.irq, d.allocated_base, d.allocated_irq))
+            .collect();
+        assert_eq!(
+            placed,
+            [
+                (0x1000, Some(7), false, false),
+                (0x1000_0000, Some(5), true, true),
+                (0x1000_1000, None, true, false),
+            ]
+        );
+        assert_eq!(
+            report.to_string().lines().next(),
+            Some("scratchpad fixed at 0x1000+0x10 irq 7")
+        );
+        let mut data = [0; 1];
+        manager
+            .mmio_read(MmioAddress(0x1000_0000), &mut data)
+            .unwrap();
+        assert_eq!(data, [b'{']);
+
+        // The devices placed before a failure are removed, and their resources released.
+        let conflict = LayoutConfig::from_json(
+            r#"{ "devices": [
+                { "type": "scratchpad", "size": 16, "irq": "auto" },
+                { "type": "scratchpad", "base": 4096, "size": 16 }
+            ] }"#,
+        )
+        .unwrap();
+        let used = allocator.used.clone();
+        assert!(matches!(
+            conflict.load(&mut manager, &registry, &mut allocator),
+            Err(Error::ResourceUnavailable { index: 1, .. })
+        ));
+        assert_eq!(allocator.used, used);
+        assert!(manager
+            .mmio_read(MmioAddress(0x1000_2000), &mut data)
+            .is_err());
+
+        let unknown = LayoutConfig::from_json(
+            r#"{ "devices": [{ "type": "virtio-blk", "size": 16, "irq": "auto" }] }"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            unknown.load(&mut manager, &registry, &mut allocator),
+            Err(Error::Registry { index: 0, .. })
+        ));
+        assert_eq!(allocator.used, used);
+
+        assert!(matches!(
+            LayoutConfig::from_json(r#"{ "devices": [{ "type": "x", "size": 1, "irq": "any" }] }"#),
+            Err(Error::Parse(_))
+        ));
+    }
+}
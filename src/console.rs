@@ -0,0 +1,345 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Reference virtio-console device (`device-console` feature).
+//!
+//! [`ConsoleDevice`] is a single port console backed by a pair of pipes: what the guest
+//! writes to its transmit queue goes to the `output` pipe, and what the VMM reads from the
+//! `input` pipe fills the buffers of the receive queue. Unlike the
+//! [`RngDevice`](crate::rng::RngDevice), it uses several queues, and a configuration space
+//! holding the size of the console, whose changes are notified to the guest:
+//!
+//! ```ignore
+//! let console = ConsoleDevice::new(input, output).with_size(80, 25);
+//! let transport = Arc::new(Mutex::new(MmioTransport::new(memory, irq, console)));
+//!
+//! // When the input pipe is readable.
+//! transport.lock().unwrap().process_queue(RECEIVE_QUEUE);
+//! // When the terminal is resized.
+//! transport.lock().unwrap().update_config(|console| console.set_size(cols, rows));
+//! ```
+//!
+//! The `input` pipe must be non-blocking, so the device can fill the receive queue with
+//! whatever input is pending when the guest provides new buffers.
+
+use std::convert::TryFrom;
+use std::io::{ErrorKind, Read, Write};
+
+use crate::dma::DmaMemory;
+use crate::virtio::{Endian, Error, Queue, VirtioDevice};
+
+/// Index of the queue holding the buffers filled with the input.
+pub const RECEIVE_QUEUE: usize = 0;
+
+/// Index of the queue holding the buffers written to the output.
+pub const TRANSMIT_QUEUE: usize = 1;
+
+/// Maximum size of the queues.
+pub const QUEUE_MAX_SIZE: u16 = 256;
+
+/// Feature bit of consoles reporting their size in the configuration space.
+pub const VIRTIO_CONSOLE_F_SIZE: u64 = 1 << 0;
+
+/// Feature bit of consoles supporting emergency writes through the configuration space.
+pub const VIRTIO_CONSOLE_F_EMERG_WRITE: u64 = 1 << 2;
+
+// Virtio device ID of consoles.
+const VIRTIO_ID_CONSOLE: u32 = 3;
+
+// Offset of the emergency write register in the configuration space.
+const EMERG_WR_OFFSET: u64 = 8;
+
+// Size of the configuration space: columns, rows, maximum number of ports, emergency write.
+const CONFIG_SIZE: usize = 12;
+
+// Bytes moved between the pipes and guest memory at once.
+const CHUNK: usize = 256;
+
+/// A virtio-console device with a single port, reading its input from `R` and writing its
+/// output to `W`.
+pub struct ConsoleDevice<R, W> {
+    input: R,
+    output: W,
+    // Input read from the pipe, but not handed to the guest yet.
+    pending: Vec<u8>,
+    cols: u16,
+    rows: u16,
+    endian: Endian,
+}
+
+impl<R: Read + Send, W: Write + Send> ConsoleDevice<R, W> {
+    /// Create a console reading its input from the non-blocking `input` pipe, and writing
+    /// its output to `output`.
+    pub fn new(input: R, output: W) -> Self {
+        ConsoleDevice {
+            input,
+            output,
+            pending: Vec::new(),
+            cols: 0,
+            rows: 0,
+            endian: Endian::Little,
+        }
+    }
+
+    /// Report the size of the console to the guest.
+    pub fn with_size(mut self, cols: u16, rows: u16) -> Self {
+        self.set_size(cols, rows);
+        self
+    }
+
+    /// Change the size of the console, which is notified to the guest when done through
+    /// [`MmioTransport::update_config`](crate::virtio::MmioTransport::update_config).
+    pub fn set_size(&mut self, cols: u16, rows: u16) {
+        self.cols = cols;
+        self.rows = rows;
+    }
+
+    /// Return the output pipe.
+    pub fn output(&self) -> &W {
+        &self.output
+    }
+
+    fn config(&self) -> [u8; CONFIG_SIZE] {
+        let mut config = [0; CONFIG_SIZE];
+        config[0..2].copy_from_slice(&self.endian.encode_u16(self.cols));
+        config[2..4].copy_from_slice(&self.endian.encode_u16(self.rows));
+        config[4..8].copy_from_slice(&self.endian.encode_u32(1));
+        config
+    }
+
+    // Read the pending input, returning `false` if there is none.
+    fn read_input(&mut self) -> bool {
+        if self.pending.is_empty() {
+            let mut data = [0; CHUNK];
+            match self.input.read(&mut data) {
+                Ok(len) => self.pending.extend_from_slice(&data[..len]),
+                Err(e) if e.kind() == ErrorKind::Interrupted => return self.read_input(),
+                // `WouldBlock` once the pipe is drained, or a failed pipe.
+                Err(_) => {}
+            }
+        }
+        !self.pending.is_empty()
+    }
+
+    // Write the output, losing it if the pipe is gone, like a disconnected serial port.
+    fn write_output(&mut self, data: &[u8]) {
+        let _ = self.output.write_all(data);
+    }
+
+    fn receive(&mut self, queue: &mut Queue, memory: &DmaMemory) -> Result<bool, Error> {
+        let mut used = false;
+        while self.read_input() {
+            let chain = match queue.pop(memory)? {
+                Some(chain) => chain,
+                None => break,
+            };
+            let mut written = 0;
+            for desc in chain.descriptors.iter().filter(|desc| desc.writable) {
+                let len = self.pending.len().min(desc.len as usize);
+                memory.write(desc.addr, &self.pending[..len])?;
+                self.pending.drain(..len);
+                written += len as u32;
+            }
+            queue.add_used(memory, chain.head, written)?;
+            used = true;
+        }
+        Ok(used)
+    }
+
+    fn transmit(&mut self, queue: &mut Queue, memory: &DmaMemory) -> Result<bool, Error> {
+        let mut data = [0; CHUNK];
+        let mut used = false;
+        while let Some(chain) = queue.pop(memory)? {
+            for desc in chain.descriptors.iter().filter(|desc| !desc.writable) {
+                let mut done = 0;
+                while done < desc.len {
+                    let chunk = &mut data[..CHUNK.min((desc.len - done) as usize)];
+                    memory.read(desc.addr + u64::from(done), chunk)?;
+                    self.write_output(chunk);
+                    done += chunk.len() as u32;
+                }
+            }
+            queue.add_used(memory, chain.head, 0)?;
+            used = true;
+        }
+        let _ = self.output.flush();
+        Ok(used)
+    }
+}
+
+impl<R: Read + Send, W: Write + Send> VirtioDevice for ConsoleDevice<R, W> {
+    fn device_type(&self) -> u32 {
+        VIRTIO_ID_CONSOLE
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_CONSOLE_F_SIZE | VIRTIO_CONSOLE_F_EMERG_WRITE
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &[QUEUE_MAX_SIZE, QUEUE_MAX_SIZE]
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let config = self.config();
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = config.get(offset as usize + i).copied().unwrap_or(0);
+        }
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        if let (EMERG_WR_OFFSET, Ok(bytes)) = (offset, <[u8; 4]>::try_from(data)) {
+            // The character is in the low byte of the register.
+            self.write_output(&[self.endian.decode_u32(bytes) as u8]);
+            let _ = self.output.flush();
+        }
+    }
+
+    fn set_endian(&mut self, endian: Endian) {
+        self.endian = endian;
+    }
+
+    fn process_queue(
+        &mut self,
+        index: usize,
+        queues: &mut [Queue],
+        memory: &DmaMemory,
+    ) -> Result<bool, Error> {
+        match index {
+            RECEIVE_QUEUE => self.receive(&mut queues[RECEIVE_QUEUE], memory),
+            TRANSMIT_QUEUE => self.transmit(&mut queues[TRANSMIT_QUEUE], memory),
+            _ => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::VecDeque;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    use crate::bus::MmioAddress;
+    use crate::device_manager::MmioManager;
+    use crate::virtio::tests::Driver;
+    use crate::virtio::*;
+
+    // Non-blocking pipe, with the data written to it by the test.
+    #[derive(Clone, Default)]
+    struct Pipe(Arc<Mutex<VecDeque<u8>>>);
+
+    impl Read for Pipe {
+        fn read(&mut self, data: &mut [u8]) -> io::Result<usize> {
+            let mut pipe = self.0.lock().unwrap();
+            if pipe.is_empty() {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            let len = pipe.len().min(data.len());
+            for (byte, value) in data.iter_mut().zip(pipe.drain(..len)) {
+                *byte = value;
+            }
+            Ok(len)
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend(data);
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Pipe {
+        fn drain(&self) -> Vec<u8> {
+            self.0.lock().unwrap().drain(..).collect()
+        }
+    }
+
+    #[test]
+    fn test_console() {
+        let mut input = Pipe::default();
+        let output = Pipe::default();
+        let pipes = (input.clone(), output.clone());
+        let (driver, transport) = Driver::new(move |memory, irq| {
+            let console = ConsoleDevice::new(pipes.0, pipes.1).with_size(80, 25);
+            Mutex::new(MmioTransport::new(memory, irq, console))
+        });
+        assert_eq!(driver.read(DEVICE_ID), VIRTIO_ID_CONSOLE);
+        assert_eq!(
+            u64::from(driver.read(DEVICE_FEATURES)),
+            VIRTIO_CONSOLE_F_SIZE | VIRTIO_CONSOLE_F_EMERG_WRITE
+        );
+        driver.init(VIRTIO_F_VERSION_1 | VIRTIO_CONSOLE_F_SIZE, 2);
+        assert_eq!(driver.read(CONFIG), 80 | 25 << 16);
+        assert_eq!(driver.read(CONFIG + 4), 1);
+
+        // Output, split over a chain.
+        let receive = RECEIVE_QUEUE as u16;
+        let transmit = TRANSMIT_QUEUE as u16;
+        driver.memory.write(0x40000, b"hello, world").unwrap();
+        driver.descriptor(transmit, 0, (0x40000, 7), DESC_F_NEXT);
+        driver.descriptor(transmit, 1, (0x40007, 5), 0);
+        driver.make_available(transmit, &[0]);
+        driver.write(QUEUE_NOTIFY, TRANSMIT_QUEUE as u32);
+        assert_eq!(output.drain(), b"hello, world");
+        assert_eq!(driver.used(transmit), (1, vec![(0, 0)]));
+        driver.irq.expect_triggered(1);
+
+        // Input arriving before the guest provides buffers is kept until it does.
+        input.write_all(b"ls -l\n").unwrap();
+        transport.lock().unwrap().process_queue(RECEIVE_QUEUE);
+        assert_eq!(driver.used(receive).0, 0);
+        driver.descriptor(receive, 0, (0x50000, 4), DESC_F_WRITE);
+        driver.descriptor(receive, 1, (0x50010, 4), DESC_F_WRITE);
+        driver.make_available(receive, &[0, 1]);
+        driver.write(QUEUE_NOTIFY, RECEIVE_QUEUE as u32);
+        assert_eq!(driver.used(receive), (2, vec![(0, 4), (1, 2)]));
+        let mut data = [0; 4];
+        driver.memory.read(0x50000, &mut data).unwrap();
+        assert_eq!(&data, b"ls -");
+        driver.memory.read(0x50010, &mut data).unwrap();
+        assert_eq!(&data[..2], b"l\n");
+        driver.irq.expect_triggered(2);
+
+        // Resizing the console is notified.
+        driver.write(INTERRUPT_ACK, INT_USED_RING);
+        transport
+            .lock()
+            .unwrap()
+            .update_config(|console| console.set_size(132, 43));
+        driver.irq.expect_triggered(3);
+        assert_eq!(driver.read(INTERRUPT_STATUS), INT_CONFIG);
+        assert_eq!(driver.read(CONFIG_GENERATION), 1);
+        let mut rows = [0; 2];
+        driver
+            .manager
+            .mmio_read(MmioAddress(0xd000_0000 + CONFIG + 2), &mut rows)
+            .unwrap();
+        assert_eq!(u16::from_le_bytes(rows), 43);
+
+        // Emergency writes go straight to the output.
+        driver.write(CONFIG + EMERG_WR_OFFSET, u32::from(b'!'));
+        assert_eq!(output.drain(), b"!");
+    }
+
+    #[test]
+    fn test_big_endian() {
+        let output = Pipe::default();
+        let pipes = (Pipe::default(), output.clone());
+        let (_, transport) = Driver::new(move |memory, irq| {
+            let console = ConsoleDevice::new(pipes.0, pipes.1).with_size(80, 25);
+            Mutex::new(MmioTransport::new(memory, irq, console).with_endian(Endian::Big))
+        });
+        let base = MmioAddress(0xd000_0000);
+        assert_eq!(transport.read_u32_be(base, CONFIG), 80 << 16 | 25);
+        assert_eq!(transport.read_u32_be(base, CONFIG + 4), 1);
+        transport.write_u32_be(base, CONFIG + EMERG_WR_OFFSET, u32::from(b'!'));
+        assert_eq!(output.drain(), b"!");
+    }
+}
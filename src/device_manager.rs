@@ -114,33 +114,129 @@
 //! manager.mmio_write(MmioAddress(0), &vec![b'o', b'k']).unwrap();
 //! ```
 
+use core::result::Result;
+#[cfg(feature = "std")]
+use std::collections::BTreeMap;
+#[cfg(feature = "std")]
 use std::fmt::{Display, Formatter};
-use std::result::Result;
-use std::sync::Arc;
-
-use crate::bus::{self, BusManager, MmioAddress, MmioBus, MmioRange, PioAddress, PioBus, PioRange};
-use crate::resources::Resource;
+#[cfg(feature = "std")]
+use std::ops::{Deref, DerefMut};
+#[cfg(feature = "std")]
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+#[cfg(feature = "std")]
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+#[cfg(feature = "std")]
+use std::thread;
+
+#[cfg(feature = "std")]
+use arc_swap::ArcSwap;
+
+use crate::bus::{self, BusManager, MmioAddress, MmioRange, PioAddress, PioRange};
+#[cfg(feature = "std")]
+use crate::bus::{Bus, BusAddress, BusRange, MmioBus, PioBus, QuiesceGuard};
+#[cfg(feature = "std")]
+use crate::composite::{CompositeDevice, CompositePart};
+#[cfg(feature = "std")]
+use crate::dma::{Direction, DmaMemory};
+#[cfg(feature = "std")]
+use crate::events::{EventLoop, Subscribe};
+use crate::info::DeviceDetails;
+#[cfg(feature = "std")]
+use crate::resources::{DeviceResources, Resource, ResourceReservation};
+#[cfg(feature = "std")]
+use crate::throttle::{Throttle, Throttled};
+#[cfg(feature = "std")]
+use crate::topology::{Subscription, Topology, TopologyEvent};
+use crate::trace;
+#[cfg(all(feature = "std", debug_assertions))]
+use crate::watch::Watched;
 use crate::{DeviceMmio, DevicePio};
 
 /// Error type for [IoManager] usage.
-#[derive(Debug)]
+#[cfg(feature = "std")]
+#[derive(Debug, Eq, PartialEq)]
 pub enum Error {
     /// Error during bus operation.
     Bus(bus::Error),
+    /// A resource or range of the device couldn't be registered on its bus.
+    Resource {
+        /// Position of the resource or range in the resources or ranges of the device.
+        index: usize,
+        /// Cause of the failure.
+        error: bus::Error,
+    },
+    /// A resource could not be reserved with the resource allocators.
+    ResourceUnavailable {
+        /// Position of the resource in the resources of the device.
+        index: usize,
+        /// The resource.
+        resource: Resource,
+    },
+    /// The event loop rejected the device.
+    EventLoop,
+    /// No guest memory was set for the devices doing DMA.
+    NoDmaMemory,
+    /// No device is registered with the MMIO ranges of the given resources.
+    NoDevice,
+    /// The device couldn't be moved to its new ranges, nor registered with its previous
+    /// ones again, so it's left without MMIO ranges.
+    Rollback {
+        /// Cause of the failure to move the device.
+        error: Box<Error>,
+        /// Cause of the failure to register the previous ranges again.
+        rollback: Box<Error>,
+    },
+    /// The resource isn't handled by the manager, which is in strict mode.
+    UnsupportedResource {
+        /// Position of the resource in the resources of the device.
+        index: usize,
+        /// The resource.
+        resource: Resource,
+    },
 }
 
+#[cfg(feature = "std")]
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
-            Error::Bus(_) => write!(f, "device_manager: bus error"),
+            Error::Bus(e) => write!(f, "device_manager: {}", e),
+            Error::Resource { index, error } => {
+                write!(f, "device_manager: resource {}: {}", index, error)
+            }
+            Error::ResourceUnavailable { index, resource } => write!(
+                f,
+                "device_manager: resource {} not available: {:?}",
+                index, resource
+            ),
+            Error::EventLoop => write!(f, "device_manager: event loop rejected the device"),
+            Error::NoDmaMemory => write!(f, "device_manager: no DMA memory"),
+            Error::NoDevice => write!(f, "device_manager: no device with these resources"),
+            Error::Rollback { .. } => {
+                write!(
+                    f,
+                    "device_manager: device left unregistered by a failed update"
+                )
+            }
+            Error::UnsupportedResource { index, resource } => write!(
+                f,
+                "device_manager: unsupported resource {}: {:?}",
+                index, resource
+            ),
         }
     }
 }
 
+#[cfg(feature = "std")]
 impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
-            Error::Bus(e) => Some(e),
+            Error::Bus(e) | Error::Resource { error: e, .. } => Some(e),
+            Error::Rollback { error, .. } => Some(error.as_ref()),
+            Error::ResourceUnavailable { .. }
+            | Error::EventLoop
+            | Error::NoDmaMemory
+            | Error::NoDevice
+            | Error::UnsupportedResource { .. } => None,
         }
     }
 }
@@ -160,6 +256,26 @@ pub trait PioManager {
     /// Dispatch a write operation to the device registered at `addr`.
     fn pio_write(&self, addr: PioAddress, data: &[u8]) -> Result<(), bus::Error>;
 
+    /// Dispatch a side effect free read to the device registered at `addr` (see
+    /// [`DevicePio::debug_read`]). Returns `false` if the device doesn't support debug reads.
+    ///
+    /// The default implementation looks the device up with [`PioManager::pio_device`].
+    fn pio_debug_read(&self, addr: PioAddress, data: &mut [u8]) -> Result<bool, bus::Error> {
+        let (range, device) = self
+            .pio_device(addr)
+            .filter(|(range, _)| {
+                u64::from(addr - range.base()) + data.len() as u64 <= u64::from(range.size())
+            })
+            .ok_or_else(|| bus::Error::not_found(addr, data.len()))?;
+        Ok(device.debug_read(range.base(), addr - range.base(), data))
+    }
+
+    /// Return the details of the device registered at `addr` (see [`DevicePio::info`]), if
+    /// any.
+    fn pio_info(&self, addr: PioAddress) -> Option<DeviceDetails> {
+        self.pio_device(addr)?.1.info()
+    }
+
     /// Register the provided device with the specified range.
     fn register_pio(&mut self, range: PioRange, device: Self::D) -> Result<(), bus::Error>;
 
@@ -181,24 +297,56 @@ where
         self.bus().device(addr)
     }
 
+    #[inline]
     fn pio_read(&self, addr: PioAddress, data: &mut [u8]) -> Result<(), bus::Error> {
-        self.bus()
-            .check_access(addr, data.len())
-            .map(|(range, device)| device.pio_read(range.base(), addr - range.base(), data))
+        let bus = self.bus();
+        let _access = bus.begin_access();
+        let result = bus.check_access(addr, data.len());
+        let (range, device) =
+            trace::dispatch("pio", "read", u64::from(addr.0), data.len(), result)?;
+        device.pio_read(range.base(), addr - range.base(), data);
+        Ok(())
     }
 
+    #[inline]
     fn pio_write(&self, addr: PioAddress, data: &[u8]) -> Result<(), bus::Error> {
-        self.bus()
-            .check_access(addr, data.len())
-            .map(|(range, device)| device.pio_write(range.base(), addr - range.base(), data))
+        let bus = self.bus();
+        let _access = bus.begin_access();
+        let result = bus.check_access(addr, data.len());
+        let (range, device) =
+            trace::dispatch("pio", "write", u64::from(addr.0), data.len(), result)?;
+        device.pio_write(range.base(), addr - range.base(), data);
+        Ok(())
+    }
+
+    fn pio_debug_read(&self, addr: PioAddress, data: &mut [u8]) -> Result<bool, bus::Error> {
+        let bus = self.bus();
+        let _access = bus.begin_access();
+        let (range, device) = bus.check_access(addr, data.len())?;
+        Ok(device.debug_read(range.base(), addr - range.base(), data))
     }
 
     fn register_pio(&mut self, range: PioRange, device: Self::D) -> Result<(), bus::Error> {
-        self.bus_mut().register(range, device)
+        let result = self.bus_mut().register(range, device);
+        trace::register(
+            "pio",
+            u64::from(range.base().0),
+            u64::from(range.size()),
+            &result,
+        );
+        if result.is_ok() {
+            self.registered(range);
+        }
+        result
     }
 
     fn deregister_pio(&mut self, addr: PioAddress) -> Option<(PioRange, Self::D)> {
-        self.bus_mut().deregister(addr)
+        let result = self.bus_mut().deregister(addr);
+        trace::deregister("pio", u64::from(addr.0), result.is_some());
+        if let Some((range, _)) = &result {
+            self.deregistered(*range);
+        }
+        result
     }
 }
 
@@ -217,6 +365,45 @@ pub trait MmioManager {
     /// Dispatch a write operation to the device registered at `addr`.
     fn mmio_write(&self, addr: MmioAddress, data: &[u8]) -> Result<(), bus::Error>;
 
+    /// Dispatch a side effect free read to the device registered at `addr` (see
+    /// [`DeviceMmio::debug_read`]). Returns `false` if the device doesn't support debug reads.
+    ///
+    /// The default implementation looks the device up with [`MmioManager::mmio_device`].
+    fn mmio_debug_read(&self, addr: MmioAddress, data: &mut [u8]) -> Result<bool, bus::Error> {
+        let (range, device) = self
+            .mmio_device(addr)
+            .filter(|(range, _)| {
+                (addr - range.base())
+                    .checked_add(data.len() as u64)
+                    .is_some_and(|end| end <= range.size())
+            })
+            .ok_or_else(|| bus::Error::not_found(addr, data.len()))?;
+        Ok(device.debug_read(range.base(), addr - range.base(), data))
+    }
+
+    /// Return the details of the device registered at `addr` (see [`DeviceMmio::info`]), if
+    /// any.
+    fn mmio_info(&self, addr: MmioAddress) -> Option<DeviceDetails> {
+        self.mmio_device(addr)?.1.info()
+    }
+
+    /// Read the 32 bit little endian register at `addr`, and write back the value returned
+    /// by `update` for it, without other accesses to the device in between (see
+    /// [`DeviceMmio::mmio_update`]). Returns the value read.
+    ///
+    /// The default implementation dispatches a read and a write separately, so other
+    /// accesses may reach the device in between.
+    fn mmio_rmw<F>(&self, addr: MmioAddress, update: F) -> Result<u32, bus::Error>
+    where
+        F: FnOnce(u32) -> u32,
+    {
+        let mut data = [0; 4];
+        self.mmio_read(addr, &mut data)?;
+        let old = u32::from_le_bytes(data);
+        self.mmio_write(addr, &update(old).to_le_bytes())?;
+        Ok(old)
+    }
+
     /// Register the provided device with the specified range.
     fn register_mmio(&mut self, range: MmioRange, device: Self::D) -> Result<(), bus::Error>;
 
@@ -238,68 +425,438 @@ where
         self.bus().device(addr)
     }
 
+    #[inline]
     fn mmio_read(&self, addr: MmioAddress, data: &mut [u8]) -> Result<(), bus::Error> {
-        self.bus()
-            .check_access(addr, data.len())
-            .map(|(range, device)| device.mmio_read(range.base(), addr - range.base(), data))
+        let bus = self.bus();
+        let _access = bus.begin_access();
+        let result = bus.check_access(addr, data.len());
+        let (range, device) = trace::dispatch("mmio", "read", addr.0, data.len(), result)?;
+        device.mmio_read(range.base(), addr - range.base(), data);
+        Ok(())
     }
 
+    #[inline]
     fn mmio_write(&self, addr: MmioAddress, data: &[u8]) -> Result<(), bus::Error> {
-        self.bus()
-            .check_access(addr, data.len())
-            .map(|(range, device)| device.mmio_write(range.base(), addr - range.base(), data))
+        let bus = self.bus();
+        let _access = bus.begin_access();
+        let result = bus.check_access(addr, data.len());
+        let (range, device) = trace::dispatch("mmio", "write", addr.0, data.len(), result)?;
+        device.mmio_write(range.base(), addr - range.base(), data);
+        Ok(())
+    }
+
+    fn mmio_debug_read(&self, addr: MmioAddress, data: &mut [u8]) -> Result<bool, bus::Error> {
+        let bus = self.bus();
+        let _access = bus.begin_access();
+        let (range, device) = bus.check_access(addr, data.len())?;
+        Ok(device.debug_read(range.base(), addr - range.base(), data))
+    }
+
+    fn mmio_rmw<F>(&self, addr: MmioAddress, update: F) -> Result<u32, bus::Error>
+    where
+        F: FnOnce(u32) -> u32,
+    {
+        let bus = self.bus();
+        let _access = bus.begin_access();
+        let result = bus.check_access(addr, 4);
+        let (range, device) = trace::dispatch("mmio", "rmw", addr.0, 4, result)?;
+        let mut update = Some(update);
+        let mut old = 0;
+        let mut data = [0; 4];
+        device.mmio_update(range.base(), addr - range.base(), &mut data, &mut |data| {
+            let mut value = [0; 4];
+            value.copy_from_slice(data);
+            old = u32::from_le_bytes(value);
+            if let Some(update) = update.take() {
+                data.copy_from_slice(&update(old).to_le_bytes());
+            }
+        });
+        Ok(old)
     }
 
     fn register_mmio(&mut self, range: MmioRange, device: Self::D) -> Result<(), bus::Error> {
-        self.bus_mut().register(range, device)
+        let result = self.bus_mut().register(range, device);
+        trace::register("mmio", range.base().0, range.size(), &result);
+        if result.is_ok() {
+            self.registered(range);
+        }
+        result
     }
 
     fn deregister_mmio(&mut self, addr: MmioAddress) -> Option<(MmioRange, Self::D)> {
-        self.bus_mut().deregister(addr)
+        let result = self.bus_mut().deregister(addr);
+        trace::deregister("mmio", addr.0, result.is_some());
+        if let Some((range, _)) = &result {
+            self.deregistered(*range);
+        }
+        result
+    }
+}
+
+/// Describes a device registered on the MMIO bus of an [`IoManager`] in terms of the
+/// resources it was registered with, and optionally of its type and configuration, so that
+/// it can be re-created with a [`DeviceRegistry`](crate::registry::DeviceRegistry).
+#[cfg(feature = "std")]
+#[derive(Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DeviceDescriptor {
+    resources: DeviceResources,
+    #[cfg_attr(feature = "serde", serde(default))]
+    device_type: Option<String>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    config: Vec<u8>,
+}
+
+#[cfg(feature = "std")]
+impl DeviceDescriptor {
+    /// Create a descriptor for a device owning `resources`.
+    pub fn new(resources: DeviceResources) -> Self {
+        DeviceDescriptor {
+            resources,
+            device_type: None,
+            config: Vec::new(),
+        }
+    }
+
+    /// Set the type of the described device, e.g. `virtio-blk`.
+    pub fn with_device_type(mut self, device_type: &str) -> Self {
+        self.device_type = Some(device_type.to_string());
+        self
+    }
+
+    /// Set the configuration blob the described device is created from.
+    pub fn with_config(mut self, config: Vec<u8>) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Get the resources of the described device.
+    pub fn resources(&self) -> &DeviceResources {
+        &self.resources
+    }
+
+    /// Get the type of the described device, if known.
+    pub fn device_type(&self) -> Option<&str> {
+        self.device_type.as_deref()
+    }
+
+    /// Get the configuration blob of the described device.
+    pub fn config(&self) -> &[u8] {
+        &self.config
+    }
+
+    /// Set the configuration blob of the described device, e.g. once captured with
+    /// [`IoManager::layout`].
+    pub fn set_config(&mut self, config: Vec<u8>) {
+        self.config = config;
+    }
+}
+
+/// Saved layout of the MMIO bus of an [`IoManager`].
+///
+/// A layout is obtained with [`IoManager::layout`] and re-created with [`IoManager::restore`].
+#[cfg(feature = "std")]
+#[derive(Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Layout {
+    devices: Vec<DeviceDescriptor>,
+}
+
+#[cfg(feature = "std")]
+impl Layout {
+    /// Create an empty layout.
+    pub fn new() -> Self {
+        Layout::default()
+    }
+
+    /// Append a device descriptor to the layout.
+    pub fn append(&mut self, device: DeviceDescriptor) {
+        self.devices.push(device);
+    }
+
+    /// Get the descriptors of all the devices in the layout.
+    pub fn devices(&self) -> &[DeviceDescriptor] {
+        &self.devices
+    }
+
+    /// Get the descriptors of all the devices in the layout, e.g. to set their
+    /// configuration.
+    pub fn devices_mut(&mut self) -> &mut [DeviceDescriptor] {
+        &mut self.devices
+    }
+
+    /// Return a copy of the layout where the MMIO ranges based at one of the keys of
+    /// `rebase` are moved to the associated base address.
+    pub fn rebase(&self, rebase: &BTreeMap<u64, u64>) -> Layout {
+        let mut layout = Layout::new();
+        for device in self.devices.iter() {
+            let mut resources = DeviceResources::new();
+            for res in device.resources().get_all_resources() {
+                resources.append(match *res {
+                    Resource::MmioAddressRange { base, size } => Resource::MmioAddressRange {
+                        base: rebase.get(&base).copied().unwrap_or(base),
+                        size,
+                    },
+                    _ => res.clone(),
+                });
+            }
+            layout.append(DeviceDescriptor {
+                resources,
+                ..device.clone()
+            });
+        }
+        layout
+    }
+}
+
+/// Keeps both buses of an [`IoManager`] quiesced until dropped.
+///
+/// Obtained with [`IoManager::quiesce`].
+#[cfg(feature = "std")]
+pub struct QuiescedIo<'a> {
+    _pio: QuiesceGuard<'a>,
+    _mmio: QuiesceGuard<'a>,
+}
+
+/// Deregisters the ranges of a device from an [`IoManager`] when dropped.
+///
+/// Obtained with [`IoManager::register_mmio_guarded`] and
+/// [`IoManager::register_mmio_resources_guarded`], e.g. so that tests and error paths don't
+/// leak registrations. The manager stays usable through the guard, which dereferences to it.
+#[cfg(feature = "std")]
+pub struct RegistrationGuard<'a> {
+    manager: &'a mut IoManager,
+    resources: Vec<Resource>,
+    // Ranges registered along with the guard, and deregistered when it's dropped.
+    registered: Vec<Resource>,
+    release: Option<ReleaseFn<'a>>,
+}
+
+#[cfg(feature = "std")]
+type ReleaseFn<'a> = Box<dyn FnOnce(&[Resource]) + 'a>;
+
+#[cfg(feature = "std")]
+impl<'a> RegistrationGuard<'a> {
+    /// Hand the resources of the device to `release` once its ranges are deregistered, e.g.
+    /// to give them back to the resource allocators.
+    pub fn with_release<F: FnOnce(&[Resource]) + 'a>(mut self, release: F) -> Self {
+        self.release = Some(Box::new(release));
+        self
+    }
+
+    /// Return the resources of the device.
+    pub fn resources(&self) -> &[Resource] {
+        &self.resources
+    }
+
+    /// Keep the device registered, releasing the manager.
+    pub fn keep(mut self) {
+        self.registered.clear();
+        self.release = None;
+    }
+}
+
+#[cfg(feature = "std")]
+impl Deref for RegistrationGuard<'_> {
+    type Target = IoManager;
+
+    fn deref(&self) -> &IoManager {
+        self.manager
+    }
+}
+
+#[cfg(feature = "std")]
+impl DerefMut for RegistrationGuard<'_> {
+    fn deref_mut(&mut self) -> &mut IoManager {
+        self.manager
+    }
+}
+
+#[cfg(feature = "std")]
+impl Drop for RegistrationGuard<'_> {
+    fn drop(&mut self) {
+        self.manager.deregister_resources(&self.registered);
+        if let Some(release) = self.release.take() {
+            release(&self.resources);
+        }
     }
 }
 
+/// Outcome of a batch of writes dispatched with [`IoManager::mmio_write_batch`].
+#[cfg(feature = "std")]
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct BatchOutcome {
+    /// Number of writes which reached a device.
+    pub dispatched: usize,
+    /// Number of writes which couldn't be dispatched.
+    pub failed: usize,
+    /// Position in the batch and cause of the first write which couldn't be dispatched.
+    pub first_error: Option<(usize, bus::Error)>,
+}
+
 /// System IO manager serving for all devices management and VM exit handling.
-#[derive(Default)]
+///
+/// Clones share the registered devices and the quiesce state of the original manager (see
+/// [`Bus`](crate::bus::Bus)), which [`SharedIoManager`] relies on to update a copy of the
+/// manager while accesses are dispatched through the original.
+#[cfg(feature = "std")]
+#[derive(Clone, Default)]
 pub struct IoManager {
     // Range mapping for VM exit pio operations.
     pio_bus: PioBus<Arc<dyn DevicePio + Send + Sync>>,
     // Range mapping for VM exit mmio operations.
     mmio_bus: MmioBus<Arc<dyn DeviceMmio + Send + Sync>>,
+    // Guest memory handed to the devices doing DMA.
+    dma: Option<Arc<DmaMemory>>,
+    // Whether resources that can't be registered are rejected rather than skipped.
+    strict: bool,
+    // Subscriptions to the registered and deregistered ranges.
+    topology: Topology,
 }
 
 // Enables the automatic implementation of `PioManager` for `IoManager`.
+#[cfg(feature = "std")]
 impl BusManager<PioAddress> for IoManager {
     type D = Arc<dyn DevicePio + Send + Sync>;
 
+    #[inline]
     fn bus(&self) -> &PioBus<Arc<dyn DevicePio + Send + Sync>> {
         &self.pio_bus
     }
 
+    #[inline]
     fn bus_mut(&mut self) -> &mut PioBus<Arc<dyn DevicePio + Send + Sync>> {
         &mut self.pio_bus
     }
+
+    fn registered(&self, range: PioRange) {
+        self.topology.publish(TopologyEvent::Registered {
+            bus: "pio",
+            base: u64::from(range.base().0),
+            size: u64::from(range.size()),
+        });
+    }
+
+    fn deregistered(&self, range: PioRange) {
+        self.topology.publish(TopologyEvent::Deregistered {
+            bus: "pio",
+            base: u64::from(range.base().0),
+            size: u64::from(range.size()),
+        });
+    }
 }
 
 // Enables the automatic implementation of `MmioManager` for `IoManager`.
+#[cfg(feature = "std")]
 impl BusManager<MmioAddress> for IoManager {
     type D = Arc<dyn DeviceMmio + Send + Sync>;
 
+    #[inline]
     fn bus(&self) -> &MmioBus<Arc<dyn DeviceMmio + Send + Sync>> {
         &self.mmio_bus
     }
 
+    #[inline]
     fn bus_mut(&mut self) -> &mut MmioBus<Arc<dyn DeviceMmio + Send + Sync>> {
         &mut self.mmio_bus
     }
+
+    fn registered(&self, range: MmioRange) {
+        self.topology.publish(TopologyEvent::Registered {
+            bus: "mmio",
+            base: range.base().0,
+            size: range.size(),
+        });
+    }
+
+    fn deregistered(&self, range: MmioRange) {
+        self.topology.publish(TopologyEvent::Deregistered {
+            bus: "mmio",
+            base: range.base().0,
+            size: range.size(),
+        });
+    }
 }
 
+#[cfg(feature = "std")]
 impl IoManager {
     /// Create an default IoManager with empty IO member.
     pub fn new() -> Self {
         IoManager::default()
     }
 
+    /// Make the registration methods taking device resources fail with
+    /// [`Error::UnsupportedResource`] when given resources other than PIO and MMIO ranges,
+    /// instead of skipping them (and warning about it, with the `log` feature).
+    ///
+    /// Deregistration keeps skipping such resources, since it can't fail.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Subscribe to the changes of the registered devices (see [`crate::topology`]).
+    pub fn subscribe(&self) -> Subscription {
+        self.topology.subscribe()
+    }
+
+    /// Return the topology the changes of the registered devices are published to, shared
+    /// with the clones of the manager. The VMM publishes the failures of devices to it, e.g.
+    /// from the handlers of [`Quarantined`](crate::quarantine::Quarantined) devices.
+    pub fn topology(&self) -> &Topology {
+        &self.topology
+    }
+
+    /// Reserve room for registering at least `pio` more PIO ranges and `mmio` more MMIO
+    /// ranges without allocating.
+    ///
+    /// Dispatching accesses and deregistering devices never allocate, so reserving room
+    /// upfront keeps hotplug free of allocations too.
+    pub fn reserve(&mut self, pio: usize, mmio: usize) {
+        self.pio_bus.reserve(pio);
+        self.mmio_bus.reserve(mmio);
+    }
+
+    /// Set the guest memory handed to the devices registered with
+    /// [`IoManager::register_mmio_dma`].
+    pub fn set_dma_memory(&mut self, memory: Arc<DmaMemory>) {
+        self.dma = Some(memory);
+    }
+
+    /// Make the devices registered from now on with [`IoManager::register_mmio_dma`]
+    /// translate their DMA addresses with `translation`, e.g. because they sit behind a
+    /// virtio-iommu (see [`DmaMemory::with_translation`]).
+    ///
+    /// Devices registered before keep accessing guest memory without translation.
+    pub fn set_dma_translation<F>(&mut self, translation: F) -> Result<(), Error>
+    where
+        F: Fn(u64, usize, Direction) -> Option<u64> + Send + Sync + 'static,
+    {
+        let memory = self.dma.as_deref().ok_or(Error::NoDmaMemory)?;
+        self.dma = Some(Arc::new(memory.clone().with_translation(translation)));
+        Ok(())
+    }
+
+    /// Return the guest memory handed to the devices doing DMA, if set.
+    pub fn dma_memory(&self) -> Option<&Arc<DmaMemory>> {
+        self.dma.as_ref()
+    }
+
+    /// Register the MMIO device created by `factory` with `range`, handing it the guest
+    /// memory set with [`IoManager::set_dma_memory`].
+    ///
+    /// # Arguments
+    ///
+    /// * `range`: range the device is registered with
+    /// * `factory`: closure creating the device object from the guest memory
+    pub fn register_mmio_dma<F>(&mut self, range: MmioRange, factory: F) -> Result<(), Error>
+    where
+        F: FnOnce(Arc<DmaMemory>) -> Arc<dyn DeviceMmio + Send + Sync>,
+    {
+        let memory = self.dma.clone().ok_or(Error::NoDmaMemory)?;
+        self.register_mmio(range, factory(memory))
+            .map_err(Error::Bus)
+    }
+
     /// Register a new MMIO device with its allocated resources.
     /// VMM is responsible for providing the allocated resources to virtual device.
     ///
@@ -307,22 +864,86 @@ impl IoManager {
     ///
     /// * `device`: device instance object to be registered
     /// * `resources`: resources that this device owns, might include
-    ///                port I/O and memory-mapped I/O ranges, irq number, etc.
+    ///   port I/O and memory-mapped I/O ranges, irq number, etc.
     pub fn register_mmio_resources(
         &mut self,
         device: Arc<dyn DeviceMmio + Send + Sync>,
         resources: &[Resource],
+    ) -> Result<(), Error> {
+        let _span = trace::span!("register_mmio_resources");
+        self.check_resources("register_mmio_resources", resources)?;
+        self.add_mmio_resources(device, resources)
+    }
+
+    /// Register a new MMIO device with the range `range`, and return a guard deregistering
+    /// it when dropped.
+    ///
+    /// # Arguments
+    ///
+    /// * `range`: range of the device
+    /// * `device`: device instance object to be registered
+    pub fn register_mmio_guarded(
+        &mut self,
+        range: MmioRange,
+        device: Arc<dyn DeviceMmio + Send + Sync>,
+    ) -> Result<RegistrationGuard<'_>, bus::Error> {
+        self.register_mmio(range, device)?;
+        let resources = vec![Resource::MmioAddressRange {
+            base: range.base().0,
+            size: range.size(),
+        }];
+        Ok(RegistrationGuard {
+            manager: self,
+            registered: resources.clone(),
+            resources,
+            release: None,
+        })
+    }
+
+    /// Register a new MMIO device with its allocated resources (see
+    /// [`IoManager::register_mmio_resources`]), and return a guard deregistering it when
+    /// dropped. Only the MMIO ranges of `resources` are deregistered then, since the PIO
+    /// ranges aren't registered for the device.
+    ///
+    /// Upon failure, the ranges registered so far are deregistered.
+    ///
+    /// # Arguments
+    ///
+    /// * `device`: device instance object to be registered
+    /// * `resources`: resources that this device owns, might include
+    ///   port I/O and memory-mapped I/O ranges, irq number, etc.
+    pub fn register_mmio_resources_guarded(
+        &mut self,
+        device: Arc<dyn DeviceMmio + Send + Sync>,
+        resources: &[Resource],
+    ) -> Result<RegistrationGuard<'_>, Error> {
+        let _span = trace::span!("register_mmio_resources_guarded");
+        self.check_resources("register_mmio_resources_guarded", resources)?;
+        let registered = self.register_mmio_ranges(device, resources)?;
+        Ok(RegistrationGuard {
+            manager: self,
+            resources: resources.to_vec(),
+            registered,
+            release: None,
+        })
+    }
+
+    // Register the MMIO ranges of `resources` for `device`.
+    fn add_mmio_resources(
+        &mut self,
+        device: Arc<dyn DeviceMmio + Send + Sync>,
+        resources: &[Resource],
     ) -> Result<(), Error> {
         // Register and mark device resources
         // The resources addresses being registered are sucessfully allocated before.
-        for res in resources.iter() {
+        for (index, res) in resources.iter().enumerate() {
             match *res {
                 Resource::MmioAddressRange { base, size } => {
                     self.register_mmio(
                         MmioRange::new(MmioAddress(base), size).unwrap(),
                         device.clone(),
                     )
-                    .map_err(Error::Bus)?;
+                    .map_err(|error| Error::Resource { index, error })?;
                 }
                 _ => continue,
             }
@@ -337,22 +958,33 @@ impl IoManager {
     ///
     /// * `device`: device instance object to be registered
     /// * `resources`: resources that this device owns, might include
-    ///                port I/O and memory-mapped I/O ranges, irq number, etc.
+    ///   port I/O and memory-mapped I/O ranges, irq number, etc.
     pub fn register_pio_resources(
         &mut self,
         device: Arc<dyn DevicePio + Send + Sync>,
         resources: &[Resource],
+    ) -> Result<(), Error> {
+        let _span = trace::span!("register_pio_resources");
+        self.check_resources("register_pio_resources", resources)?;
+        self.add_pio_resources(device, resources)
+    }
+
+    // Register the PIO ranges of `resources` for `device`.
+    fn add_pio_resources(
+        &mut self,
+        device: Arc<dyn DevicePio + Send + Sync>,
+        resources: &[Resource],
     ) -> Result<(), Error> {
         // Register and mark device resources
         // The resources addresses being registered are sucessfully allocated before.
-        for res in resources.iter() {
+        for (index, res) in resources.iter().enumerate() {
             match *res {
                 Resource::PioAddressRange { base, size } => {
                     self.register_pio(
                         PioRange::new(PioAddress(base), size).unwrap(),
                         device.clone(),
                     )
-                    .map_err(Error::Bus)?;
+                    .map_err(|error| Error::Resource { index, error })?;
                 }
                 _ => continue,
             }
@@ -367,14 +999,34 @@ impl IoManager {
     ///
     /// * `device`: device instance object to be registered
     /// * `resources`: resources that this device owns, might include
-    ///                port I/O and memory-mapped I/O ranges, irq number, etc.
+    ///   port I/O and memory-mapped I/O ranges, irq number, etc.
     pub fn register_resources<T: DeviceMmio + DevicePio + 'static + Send + Sync>(
         &mut self,
         device: Arc<T>,
         resources: &[Resource],
     ) -> Result<(), Error> {
-        self.register_mmio_resources(device.clone(), resources)?;
-        self.register_pio_resources(device, resources)
+        let _span = trace::span!("register_resources");
+        self.check_resources("register_resources", resources)?;
+        self.add_mmio_resources(device.clone(), resources)?;
+        self.add_pio_resources(device, resources)
+    }
+
+    // Reject the resources which aren't PIO or MMIO ranges in strict mode, or warn that they
+    // are skipped by `op` otherwise.
+    fn check_resources(&self, op: &'static str, resources: &[Resource]) -> Result<(), Error> {
+        for (index, res) in resources.iter().enumerate() {
+            match res {
+                Resource::PioAddressRange { .. } | Resource::MmioAddressRange { .. } => {}
+                _ if self.strict => {
+                    return Err(Error::UnsupportedResource {
+                        index,
+                        resource: res.clone(),
+                    })
+                }
+                _ => trace::ignored(op, res),
+            }
+        }
+        Ok(())
     }
 
     /// Deregister a device from `IoManager`, e.g. users specified removing.
@@ -385,8 +1037,9 @@ impl IoManager {
     /// # Arguments
     ///
     /// * `resources`: resources that this device owns, might include
-    ///                port I/O and memory-mapped I/O ranges, irq number, etc.
+    ///   port I/O and memory-mapped I/O ranges, irq number, etc.
     pub fn deregister_resources(&mut self, resources: &[Resource]) -> usize {
+        let _span = trace::span!("deregister_resources");
         let mut count = 0;
         for res in resources.iter() {
             match *res {
@@ -400,63 +1053,1152 @@ impl IoManager {
                         count += 1;
                     }
                 }
-                _ => continue,
+                _ => trace::ignored("deregister_resources", res),
             }
         }
         count
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Update the resources of a registered MMIO device while the VM runs, e.g. to move its
+    /// shared memory window or rewire its interrupt once the guest reconfigured it.
+    ///
+    /// The MMIO ranges of `resources` are moved to the ones of `new`, then `new` is handed
+    /// to [`DeviceMmio::update_resources`] so the device applies the other resources, e.g.
+    /// triggers another interrupt. Upon failure, the device keeps its ranges and isn't
+    /// notified, unless its ranges can't be registered again, which is reported as
+    /// [`Error::Rollback`]. Through [`SharedIoManager::update`], the vCPUs see either the old
+    /// or the new ranges, never a mix of them.
+    ///
+    /// All the MMIO ranges of `resources` must be registered with the same device, or the
+    /// update fails with [`Error::NoDevice`]. When `new` has no MMIO range, e.g. when only
+    /// the interrupt of the device is rewired, the device keeps its ranges.
+    ///
+    /// PIO ranges can't be updated, and are rejected with [`Error::UnsupportedResource`].
+    /// Interrupts are only handed to the device: rewiring them with the interrupt manager,
+    /// e.g. moving an irqfd to another GSI, is left to the device or the VMM.
+    ///
+    /// Subscribers are sent [`TopologyEvent::Relocated`] after the events deregistering the
+    /// old ranges and registering the new ones, if the ranges moved.
+    ///
+    /// # Arguments
+    ///
+    /// * `resources`: current resources of the device, identifying it
+    /// * `new`: resources the device is moved to
+    pub fn update_resources(
+        &mut self,
+        resources: &[Resource],
+        new: &[Resource],
+    ) -> Result<(), Error> {
+        let _span = trace::span!("update_resources");
+        for list in [new, resources] {
+            let pio = list
+                .iter()
+                .position(|res| matches!(res, Resource::PioAddressRange { .. }));
+            if let Some(index) = pio {
+                return Err(Error::UnsupportedResource {
+                    index,
+                    resource: list[index].clone(),
+                });
+            }
+        }
+        let (ranges, device) = self.registered_mmio(resources)?;
+        if !new
+            .iter()
+            .any(|res| matches!(res, Resource::MmioAddressRange { .. }))
+        {
+            device.update_resources(new);
+            return Ok(());
+        }
+        self.deregister_resources(&ranges);
+        if let Err(error) = self.register_mmio_ranges(device.clone(), new) {
+            // The old ranges were just freed, so registering them again should succeed.
+            return match self.register_mmio_ranges(device, &ranges) {
+                Ok(_) => Err(error),
+                Err(rollback) => Err(Error::Rollback {
+                    error: Box::new(error),
+                    rollback: Box::new(rollback),
+                }),
+            };
+        }
+        device.update_resources(new);
+        self.topology.publish(TopologyEvent::Relocated {
+            old: mmio_ranges(&ranges),
+            new: mmio_ranges(new),
+        });
+        Ok(())
+    }
 
-    use std::error::Error;
-    use std::sync::Mutex;
+    /// Replace the MMIO device registered at `addr` with `device`, on all the ranges it's
+    /// registered with, and return it. Returns `None` if no device is registered at `addr`.
+    ///
+    /// The ranges are kept registered, so accesses reach either device, never a missing
+    /// one, e.g. when upgrading the backend of a device while the VM runs. Through
+    /// [`SharedIoManager::update`], the vCPUs switch to the new device at once.
+    ///
+    /// Subscribers are sent [`TopologyEvent::Replaced`], also when the device is wrapped,
+    /// e.g. by [`IoManager::throttle_mmio`].
+    ///
+    /// # Arguments
+    ///
+    /// * `addr`: any address in the ranges of the device
+    /// * `device`: device taking over the ranges
+    pub fn replace_mmio(
+        &mut self,
+        addr: MmioAddress,
+        device: Arc<dyn DeviceMmio + Send + Sync>,
+    ) -> Option<Arc<dyn DeviceMmio + Send + Sync>> {
+        let _span = trace::span!("replace_mmio");
+        let (previous, ranges) = replace(&mut self.mmio_bus, addr, device)?;
+        self.topology.publish(TopologyEvent::Replaced {
+            bus: "mmio",
+            ranges: ranges
+                .iter()
+                .map(|range| (range.base().0, range.size()))
+                .collect(),
+        });
+        Some(previous)
+    }
 
-    use bus::{MmioAddressOffset, PioAddressOffset};
+    /// Replace the PIO device registered at `addr` with `device`, on all the ranges it's
+    /// registered with, and return it (see [`IoManager::replace_mmio`]). Returns `None` if
+    /// no device is registered at `addr`.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr`: any address in the ranges of the device
+    /// * `device`: device taking over the ranges
+    pub fn replace_pio(
+        &mut self,
+        addr: PioAddress,
+        device: Arc<dyn DevicePio + Send + Sync>,
+    ) -> Option<Arc<dyn DevicePio + Send + Sync>> {
+        let _span = trace::span!("replace_pio");
+        let (previous, ranges) = replace(&mut self.pio_bus, addr, device)?;
+        self.topology.publish(TopologyEvent::Replaced {
+            bus: "pio",
+            ranges: ranges
+                .iter()
+                .map(|range| (u64::from(range.base().0), u64::from(range.size())))
+                .collect(),
+        });
+        Some(previous)
+    }
 
-    const PIO_ADDRESS_SIZE: u16 = 4;
-    const PIO_ADDRESS_BASE: u16 = 0x40;
-    const MMIO_ADDRESS_SIZE: u64 = 0x8765_4321;
-    const MMIO_ADDRESS_BASE: u64 = 0x1234_5678;
-    const LEGACY_IRQ: u32 = 4;
-    const CONFIG_DATA: u32 = 0x1234;
+    /// Limit the rate of the accesses to a registered MMIO device with `throttle`, e.g. to
+    /// bound the emulation time a guest device can use on a multi-tenant host.
+    ///
+    /// The device is replaced on all its MMIO ranges by the returned [`Throttled`] wrapper,
+    /// so the ranges share the same budget. Throttling the device again adds another
+    /// wrapper; deregistering it removes the wrapper along with the device.
+    ///
+    /// # Arguments
+    ///
+    /// * `resources`: resources of the device, identifying it
+    /// * `throttle`: limits applied to the accesses
+    pub fn throttle_mmio(
+        &mut self,
+        resources: &[Resource],
+        throttle: Throttle,
+    ) -> Result<Arc<Throttled<Arc<dyn DeviceMmio + Send + Sync>>>, Error> {
+        let _span = trace::span!("throttle_mmio");
+        self.wrap_mmio(resources, |device| Throttled::new(device, throttle))
+    }
 
-    struct DummyDevice {
-        config: Mutex<u32>,
+    /// Wrap a registered MMIO device in a [`Watched`], to add watchpoints on its registers
+    /// (debug builds only).
+    ///
+    /// The device is replaced on all its MMIO ranges by the returned wrapper. Deregistering
+    /// the device removes the wrapper along with it.
+    ///
+    /// # Arguments
+    ///
+    /// * `resources`: resources of the device, identifying it
+    #[cfg(debug_assertions)]
+    pub fn watch_mmio(
+        &mut self,
+        resources: &[Resource],
+    ) -> Result<Arc<Watched<Arc<dyn DeviceMmio + Send + Sync>>>, Error> {
+        let _span = trace::span!("watch_mmio");
+        self.wrap_mmio(resources, Watched::new)
     }
 
-    impl DummyDevice {
-        fn new(config: u32) -> Self {
-            DummyDevice {
-                config: Mutex::new(config),
-            }
+    // Replace a registered MMIO device on all its ranges with the wrapper returned by `wrap`.
+    fn wrap_mmio<T, F>(&mut self, resources: &[Resource], wrap: F) -> Result<Arc<T>, Error>
+    where
+        T: DeviceMmio + Send + Sync + 'static,
+        F: FnOnce(Arc<dyn DeviceMmio + Send + Sync>) -> T,
+    {
+        let (ranges, device) = self.registered_mmio(resources)?;
+        let wrapper = Arc::new(wrap(device));
+        if let Some(&Resource::MmioAddressRange { base, .. }) = ranges.first() {
+            self.replace_mmio(MmioAddress(base), wrapper.clone());
         }
+        Ok(wrapper)
     }
 
-    impl DevicePio for DummyDevice {
-        fn pio_read(&self, _base: PioAddress, _offset: PioAddressOffset, data: &mut [u8]) {
-            if data.len() > 4 {
-                return;
-            }
-            for (idx, iter) in data.iter_mut().enumerate() {
-                let config = self.config.lock().expect("failed to acquire lock");
-                *iter = (*config >> (idx * 8) & 0xff) as u8;
-            }
+    // Return the MMIO ranges of `resources`, and the device registered with them. Fails
+    // unless all the ranges are registered as such, with the same device.
+    fn registered_mmio(
+        &self,
+        resources: &[Resource],
+    ) -> Result<(Vec<Resource>, Arc<dyn DeviceMmio + Send + Sync>), Error> {
+        let ranges: Vec<Resource> = resources
+            .iter()
+            .filter(|res| matches!(res, Resource::MmioAddressRange { .. }))
+            .cloned()
+            .collect();
+        let mut devices = ranges.iter().map(|res| match *res {
+            Resource::MmioAddressRange { base, size } => self
+                .mmio_device(MmioAddress(base))
+                .filter(|(range, _)| range.base() == MmioAddress(base) && range.size() == size)
+                .map(|(_, device)| device),
+            _ => None,
+        });
+        let device = devices.next().flatten().ok_or(Error::NoDevice)?;
+        if !devices.all(|other| other.is_some_and(|other| Arc::ptr_eq(other, device))) {
+            return Err(Error::NoDevice);
         }
+        Ok((ranges, device.clone()))
+    }
 
-        fn pio_write(&self, _base: PioAddress, _offset: PioAddressOffset, data: &[u8]) {
-            let mut config = self.config.lock().expect("failed to acquire lock");
-            *config = u32::from(data[0]) & 0xff;
-        }
+    /// Register a new MMIO device with its allocated resources, and add it to the event
+    /// loop its backend is driven from.
+    ///
+    /// Upon failure, the registrations performed so far are undone, and the device isn't
+    /// added to the event loop. The returned identifier must be passed to
+    /// [`IoManager::deregister_evented`] when removing the device.
+    ///
+    /// # Arguments
+    ///
+    /// * `device`: device instance object to be registered
+    /// * `resources`: resources that this device owns, might include
+    ///   port I/O and memory-mapped I/O ranges, irq number, etc.
+    /// * `events`: event loop the device is added to
+    pub fn register_mmio_evented<T, E>(
+        &mut self,
+        device: Arc<T>,
+        resources: &[Resource],
+        events: &mut E,
+    ) -> Result<E::Id, Error>
+    where
+        T: DeviceMmio + Send + Sync + 'static,
+        E: Subscribe<T> + ?Sized,
+    {
+        let registered = self.register_mmio_ranges(device.clone(), resources)?;
+        events.add(device).ok_or(Error::EventLoop).inspect_err(|_| {
+            self.deregister_resources(&registered);
+        })
     }
 
-    impl DeviceMmio for DummyDevice {
-        fn mmio_read(&self, _base: MmioAddress, _offset: MmioAddressOffset, data: &mut [u8]) {
-            if data.len() > 4 {
-                return;
-            }
+    // Register the MMIO ranges of `resources` for `device`, and return them. Upon failure,
+    // the ranges registered so far are deregistered, and only them: the other resources,
+    // e.g. PIO ranges, may belong to other devices.
+    pub(crate) fn register_mmio_ranges(
+        &mut self,
+        device: Arc<dyn DeviceMmio + Send + Sync>,
+        resources: &[Resource],
+    ) -> Result<Vec<Resource>, Error> {
+        let _span = trace::span!("register_mmio_ranges");
+        let mut registered = Vec::new();
+        for (index, res) in resources.iter().enumerate() {
+            if let Resource::MmioAddressRange { base, size } = *res {
+                let result = MmioRange::new(MmioAddress(base), size)
+                    .and_then(|range| self.register_mmio(range, device.clone()));
+                if let Err(error) = result {
+                    self.deregister_resources(&registered);
+                    return Err(Error::Resource { index, error });
+                }
+                registered.push(res.clone());
+            }
+        }
+        Ok(registered)
+    }
+
+    /// Register a device owning several MMIO ranges, which is told the role of the range
+    /// hit by each access (see [`composite`](crate::composite)).
+    ///
+    /// Upon failure, the ranges registered so far are deregistered.
+    ///
+    /// # Arguments
+    ///
+    /// * `device`: device instance object to be registered
+    /// * `ranges`: ranges of the device, along with their roles
+    pub fn register_composite<T>(
+        &mut self,
+        device: Arc<T>,
+        ranges: &[(T::Role, MmioRange)],
+    ) -> Result<(), Error>
+    where
+        T: CompositeDevice + Send + Sync + 'static,
+    {
+        let _span = trace::span!("register_composite");
+        for (index, &(role, range)) in ranges.iter().enumerate() {
+            let part = Arc::new(CompositePart::new(device.clone(), role));
+            if let Err(error) = self.register_mmio(range, part) {
+                self.deregister_composite(&ranges[..index]);
+                return Err(Error::Resource { index, error });
+            }
+        }
+        Ok(())
+    }
+
+    /// Deregister a device registered with [`IoManager::register_composite`]. Returns the
+    /// number of deregistered ranges.
+    ///
+    /// # Arguments
+    ///
+    /// * `ranges`: ranges of the device, along with their roles
+    pub fn deregister_composite<R>(&mut self, ranges: &[(R, MmioRange)]) -> usize {
+        ranges
+            .iter()
+            .filter(|(_, range)| self.deregister_mmio(range.base()).is_some())
+            .count()
+    }
+
+    /// Deregister a device registered with [`IoManager::register_mmio_evented`], and remove
+    /// it from the event loop. Returns the number of deregistered ranges.
+    ///
+    /// # Arguments
+    ///
+    /// * `resources`: resources that this device owns
+    /// * `id`: identifier returned when the device was registered
+    /// * `events`: event loop the device was added to
+    pub fn deregister_evented<E>(
+        &mut self,
+        resources: &[Resource],
+        id: E::Id,
+        events: &mut E,
+    ) -> usize
+    where
+        E: EventLoop + ?Sized,
+    {
+        // Remove the device from the event loop first, so it doesn't process events while
+        // it's gone from the bus.
+        events.remove(id);
+        self.deregister_resources(resources)
+    }
+
+    /// Re-create the MMIO registrations described by `layout`, after moving its ranges
+    /// according to `rebase`.
+    ///
+    /// This works like [`IoManager::restore`], with the factory receiving the rebased
+    /// descriptors. Once all the devices are registered, `relocate` is invoked for every
+    /// moved range with the rebased descriptor, the old and the new base address, so that
+    /// any address the device keeps internally can be updated as well.
+    ///
+    /// # Arguments
+    ///
+    /// * `layout`: layout previously captured with [`IoManager::layout`]
+    /// * `rebase`: map from old to new range base addresses
+    /// * `factory`: closure creating the device object for a descriptor
+    /// * `relocate`: closure notified about every moved range
+    pub fn restore_rebased<F, R>(
+        &mut self,
+        layout: &Layout,
+        rebase: &BTreeMap<u64, u64>,
+        factory: F,
+        mut relocate: R,
+    ) -> Result<(), Error>
+    where
+        F: FnMut(&DeviceDescriptor) -> Arc<dyn DeviceMmio + Send + Sync>,
+        R: FnMut(&DeviceDescriptor, MmioAddress, MmioAddress),
+    {
+        let rebased = layout.rebase(rebase);
+        self.restore(&rebased, factory)?;
+
+        for (old, new) in layout.devices().iter().zip(rebased.devices()) {
+            let old_ranges = old.resources().get_mmio_address_ranges();
+            let new_ranges = new.resources().get_mmio_address_ranges();
+            for ((old_base, _), (new_base, _)) in old_ranges.into_iter().zip(new_ranges) {
+                if old_base != new_base {
+                    relocate(new, MmioAddress(old_base), MmioAddress(new_base));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Register a restored MMIO + PIO device with the resources it owned when it was saved.
+    ///
+    /// All the resources are first reserved with `allocator`, so that the allocators are
+    /// kept in sync with the restored device, and the device is then registered with its
+    /// PIO and MMIO ranges. Upon failure, the reservations and registrations performed so
+    /// far are undone.
+    ///
+    /// # Arguments
+    ///
+    /// * `device`: device instance object to be registered
+    /// * `resources`: resources that this device owned when it was saved, e.g. as loaded
+    ///   with [`load_resources`](../snapshot/fn.load_resources.html)
+    /// * `allocator`: resource allocators the resources are reserved with
+    pub fn restore_resources<T, R>(
+        &mut self,
+        device: Arc<T>,
+        resources: &DeviceResources,
+        allocator: &mut R,
+    ) -> Result<(), Error>
+    where
+        T: DeviceMmio + DevicePio + 'static + Send + Sync,
+        R: ResourceReservation + ?Sized,
+    {
+        let resources = resources.get_all_resources();
+        for (idx, res) in resources.iter().enumerate() {
+            if !allocator.reserve(res) {
+                resources[..idx]
+                    .iter()
+                    .for_each(|res| allocator.release(res));
+                return Err(Error::ResourceUnavailable {
+                    index: idx,
+                    resource: res.clone(),
+                });
+            }
+        }
+
+        let mut registered = 0;
+        let result = resources.iter().try_for_each(|res| {
+            match *res {
+                Resource::PioAddressRange { base, size } => {
+                    let range = PioRange::new(PioAddress(base), size)?;
+                    self.register_pio(range, device.clone())?;
+                }
+                Resource::MmioAddressRange { base, size } => {
+                    let range = MmioRange::new(MmioAddress(base), size)?;
+                    self.register_mmio(range, device.clone())?;
+                }
+                _ => {}
+            }
+            registered += 1;
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            self.deregister_resources(&resources[..registered]);
+            resources.iter().for_each(|res| allocator.release(res));
+            return Err(Error::Resource {
+                index: registered,
+                error,
+            });
+        }
+        Ok(())
+    }
+
+    /// Dispatch a batch of MMIO writes, e.g. the entries drained from a KVM coalesced MMIO
+    /// ring.
+    ///
+    /// The writes are dispatched in order, as reordering them could change what devices
+    /// observe. The batch counts as a single access in flight, so the bus isn't quiesced
+    /// while a batch is dispatched, and consecutive writes to the same range are dispatched
+    /// without looking the device up again. Writes which can't be dispatched are skipped.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use std::sync::Arc;
+    /// # use vm_device::bus::{MmioAddress, MmioAddressOffset, MmioRange};
+    /// # use vm_device::device_manager::{IoManager, MmioManager};
+    /// # use vm_device::DeviceMmio;
+    /// # struct NoopDevice {}
+    /// #
+    /// # impl DeviceMmio for NoopDevice {
+    /// #     fn mmio_read(&self, base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {}
+    /// #     fn mmio_write(&self, base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {}
+    /// # }
+    /// let mut manager = IoManager::new();
+    /// let range = MmioRange::new(MmioAddress(0x1000), 0x100).unwrap();
+    /// manager.register_mmio(range, Arc::new(NoopDevice {})).unwrap();
+    ///
+    /// // Entries of a coalesced MMIO ring, as (address, length, data) records.
+    /// let ring = [(0x1050, 4, [1, 0, 0, 0]), (0x1050, 4, [2, 0, 0, 0]), (0x2000, 4, [0; 4])];
+    /// let outcome = manager.mmio_write_batch(
+    ///     ring.iter()
+    ///         .map(|(addr, len, data)| (MmioAddress(*addr), &data[..*len])),
+    /// );
+    /// assert_eq!(outcome.dispatched, 2);
+    /// assert_eq!(outcome.failed, 1);
+    /// ```
+    pub fn mmio_write_batch<'a, I>(&self, writes: I) -> BatchOutcome
+    where
+        I: IntoIterator<Item = (MmioAddress, &'a [u8])>,
+    {
+        let _access = self.mmio_bus.begin_access();
+        self.write_batch(writes)
+    }
+
+    // Dispatch a batch of MMIO writes, within an access to the MMIO bus begun by the caller.
+    fn write_batch<'a, I>(&self, writes: I) -> BatchOutcome
+    where
+        I: IntoIterator<Item = (MmioAddress, &'a [u8])>,
+    {
+        let mut last: Option<(&MmioRange, &Arc<dyn DeviceMmio + Send + Sync>)> = None;
+        let mut outcome = BatchOutcome::default();
+        for (idx, (addr, data)) in writes.into_iter().enumerate() {
+            let target = match last {
+                Some((range, device)) if range.covers(addr, data.len()) => Ok((range, device)),
+                _ => self.mmio_bus.check_access(addr, data.len()),
+            };
+            match target {
+                Ok((range, device)) => {
+                    device.mmio_write(range.base(), addr - range.base(), data);
+                    last = Some((range, device));
+                    outcome.dispatched += 1;
+                }
+                Err(e) => {
+                    outcome.failed += 1;
+                    outcome.first_error.get_or_insert((idx, e));
+                }
+            }
+        }
+        outcome
+    }
+
+    /// Quiesce the PIO and MMIO buses.
+    ///
+    /// Waits for the accesses dispatched by other threads to complete, and stalls new
+    /// ones until the returned guard is dropped, so that device state can be captured
+    /// without racing against guest I/O. Must not be called from within a device
+    /// handler, as that would deadlock.
+    pub fn quiesce(&self) -> QuiescedIo<'_> {
+        QuiescedIo {
+            _pio: self.pio_bus.quiesce(),
+            _mmio: self.mmio_bus.quiesce(),
+        }
+    }
+
+    /// Capture the layout of the MMIO bus.
+    ///
+    /// Ranges registered with the same device object are described by a single
+    /// [`DeviceDescriptor`], in the order in which they appear on the bus. The descriptors
+    /// hold the type reported by the [`info`](DeviceMmio::info) of the devices, if any.
+    pub fn layout(&self) -> Layout {
+        let mut layout = Layout::new();
+        for (device, ranges) in group_ranges(&self.mmio_bus) {
+            let mut resources = DeviceResources::new();
+            for range in ranges {
+                resources.append(Resource::MmioAddressRange {
+                    base: range.base().0,
+                    size: range.size(),
+                });
+            }
+            let mut descriptor = DeviceDescriptor::new(resources);
+            descriptor.device_type = device.info().map(|info| info.device_type);
+            layout.append(descriptor);
+        }
+        layout
+    }
+
+    /// Describe the registered devices as a JSON document.
+    ///
+    /// The document holds a `pio` and a `mmio` array, with one object per device object
+    /// registered on the respective bus. Each object lists the `ranges` of the device as
+    /// `base`/`size` pairs, and the `properties` reported by its `introspect` method.
+    pub fn introspect(&self) -> String {
+        let mut json = String::from("{\"pio\":");
+        write_json_devices(&mut json, &self.pio_bus, |device| device.introspect());
+        json.push_str(",\"mmio\":");
+        write_json_devices(&mut json, &self.mmio_bus, |device| device.introspect());
+        json.push('}');
+        json
+    }
+
+    /// Re-create the MMIO registrations described by `layout`.
+    ///
+    /// The `factory` is invoked once per device descriptor and must return the device
+    /// object which gets registered with all the ranges of that descriptor. The ranges
+    /// are verified to be valid and to fit on the bus before any factory gets invoked,
+    /// so the manager is left untouched when an error is returned.
+    ///
+    /// # Arguments
+    ///
+    /// * `layout`: layout previously captured with [`IoManager::layout`]
+    /// * `factory`: closure creating the device object for a descriptor
+    pub fn restore<F>(&mut self, layout: &Layout, mut factory: F) -> Result<(), Error>
+    where
+        F: FnMut(&DeviceDescriptor) -> Arc<dyn DeviceMmio + Send + Sync>,
+    {
+        let _span = trace::span!("restore");
+        let mut ranges: Vec<Vec<MmioRange>> = Vec::new();
+        for device in layout.devices() {
+            let mut device_ranges = Vec::new();
+            for (base, size) in device.resources().get_mmio_address_ranges() {
+                let range = MmioRange::new(MmioAddress(base), size).map_err(Error::Bus)?;
+                let overlaps = self.mmio_bus.iter().any(|(r, _)| r.overlaps(&range))
+                    || ranges.iter().flatten().any(|r| r.overlaps(&range))
+                    || device_ranges.iter().any(|r: &MmioRange| r.overlaps(&range));
+                if overlaps {
+                    return Err(Error::Bus(bus::Error::overlap(&range)));
+                }
+                device_ranges.push(range);
+            }
+            ranges.push(device_ranges);
+        }
+
+        for (device, device_ranges) in layout.devices().iter().zip(ranges) {
+            let object = factory(device);
+            for range in device_ranges {
+                self.register_mmio(range, object.clone())
+                    .map_err(Error::Bus)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An [`IoManager`] shared between the threads dispatching I/O and the ones registering and
+/// deregistering devices.
+///
+/// Dispatching loads the current version of the manager and begins an access to its bus
+/// (see [`Bus::begin_access`](crate::bus::Bus::begin_access)), which takes the shared side of
+/// the quiesce lock of the bus. Updates don't take that lock, so vCPU threads keep handling
+/// exits during hotplug, and only block while the buses are quiesced. Dispatching isn't
+/// lock-free though: concurrent accesses contend on the lock, and on the counter of accesses
+/// in flight waited for by [`SharedIoManager::deregister_sync`]. Updates clone the current
+/// manager, modify the copy, and swap it in. Accesses already dispatched through the
+/// previous version complete on it, and it is released once the last of them is done.
+///
+/// Updates are serialized with each other. Each one copies the registered ranges, so the
+/// manager suits devices which are registered rarely compared to the accesses they get.
+/// [`IoManager::quiesce`] on a loaded version also stalls accesses dispatched through later
+/// versions, as clones of a manager share their quiesce state.
+///
+/// Accesses in flight during an update may still reach the devices it removes.
+/// [`SharedIoManager::deregister_sync`] waits for them, so the backends of the removed
+/// devices can be torn down safely.
+///
+/// # Example
+///
+/// ```
+/// # use std::sync::Arc;
+/// # use vm_device::bus::{MmioAddress, MmioAddressOffset, MmioRange};
+/// # use vm_device::device_manager::{IoManager, MmioManager, SharedIoManager};
+/// # use vm_device::DeviceMmio;
+/// struct NoopDevice {}
+///
+/// impl DeviceMmio for NoopDevice {
+///     fn mmio_read(&self, base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {}
+///     fn mmio_write(&self, base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {}
+/// }
+///
+/// let manager = SharedIoManager::new(IoManager::new());
+/// let previous = manager.load();
+///
+/// let range = MmioRange::new(MmioAddress(0x1000), 0x10).unwrap();
+/// manager
+///     .update(|manager| manager.register_mmio(range, Arc::new(NoopDevice {})))
+///     .unwrap();
+///
+/// // Typically called from vCPU threads.
+/// manager.mmio_write(MmioAddress(0x1000), &[1, 2]).unwrap();
+/// assert!(previous.mmio_write(MmioAddress(0x1000), &[1, 2]).is_err());
+/// ```
+#[cfg(feature = "std")]
+pub struct SharedIoManager {
+    current: ArcSwap<IoManager>,
+    // Serializes updates, so that concurrent ones don't overwrite each other's changes.
+    update: Mutex<()>,
+    // Incremented after each update, so that dispatch handles notice their cached version
+    // is stale.
+    epoch: AtomicU64,
+    // Accesses in flight, counted in the slot of the parity of the epoch they began in.
+    in_flight: [AtomicUsize; 2],
+}
+
+// Counts an access in flight until dropped (see `SharedIoManager::enter`).
+#[cfg(feature = "std")]
+struct InFlight<'a>(&'a AtomicUsize);
+
+#[cfg(feature = "std")]
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Release);
+    }
+}
+
+#[cfg(feature = "std")]
+impl SharedIoManager {
+    /// Create a shared manager, starting with the devices registered with `manager`.
+    pub fn new(manager: IoManager) -> Self {
+        SharedIoManager {
+            current: ArcSwap::from_pointee(manager),
+            update: Mutex::new(()),
+            epoch: AtomicU64::new(0),
+            in_flight: [AtomicUsize::new(0), AtomicUsize::new(0)],
+        }
+    }
+
+    /// Return the current version of the manager.
+    ///
+    /// The returned version doesn't reflect later updates.
+    pub fn load(&self) -> Arc<IoManager> {
+        self.current.load_full()
+    }
+
+    /// Apply `f` to a copy of the current manager, and make the copy current.
+    ///
+    /// Accesses dispatched while `f` runs still go to the previous version. The copy is
+    /// made current even if `f` fails, so `f` should undo its partial changes when
+    /// that's not desirable.
+    pub fn update<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(&mut IoManager) -> T,
+    {
+        let _update = self.lock_updates();
+        self.apply(f)
+    }
+
+    fn lock_updates(&self) -> MutexGuard<'_, ()> {
+        // The lock protects no data, so poisoning is ignored.
+        self.update.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Apply `f` to a copy of the current manager and make the copy current, with updates
+    // locked by the caller.
+    fn apply<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(&mut IoManager) -> T,
+    {
+        let mut manager = IoManager::clone(&self.current.load());
+        let result = f(&mut manager);
+        self.current.store(Arc::new(manager));
+        self.epoch.fetch_add(1, Ordering::SeqCst);
+        result
+    }
+
+    // Count an access as in flight in the slot of `epoch`, unless the manager was updated
+    // since `epoch` was loaded, in which case the access must start over with the current
+    // version: `deregister_sync` only waits for the accesses of the versions it retires.
+    #[inline]
+    fn enter(&self, epoch: u64) -> Option<InFlight<'_>> {
+        let count = &self.in_flight[(epoch & 1) as usize];
+        count.fetch_add(1, Ordering::SeqCst);
+        let in_flight = InFlight(count);
+        match self.epoch.load(Ordering::SeqCst) == epoch {
+            true => Some(in_flight),
+            false => None,
+        }
+    }
+
+    // Run `f` on the current version of the manager, with the access counted in flight.
+    // The version is borrowed from the manager rather than cloned, so that dispatching an
+    // access doesn't touch its reference count.
+    #[inline]
+    fn dispatch<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(&IoManager) -> T,
+    {
+        loop {
+            let epoch = self.epoch.load(Ordering::Acquire);
+            let manager = self.current.load();
+            if let Some(_in_flight) = self.enter(epoch) {
+                return f(&manager);
+            }
+        }
+    }
+
+    // Wait until the accesses counted in the slot of `epoch` are done.
+    fn wait_idle(&self, epoch: u64) {
+        while self.in_flight[(epoch & 1) as usize].load(Ordering::SeqCst) != 0 {
+            thread::yield_now();
+        }
+    }
+
+    /// Return a handle for dispatching accesses from a single thread, typically a vCPU.
+    pub fn handle(&self) -> DispatchHandle<'_> {
+        let epoch = self.epoch.load(Ordering::Acquire);
+        DispatchHandle {
+            shared: self,
+            manager: self.load(),
+            epoch,
+            last_pio: None,
+            last_mmio: None,
+            hits: 0,
+        }
+    }
+
+    /// Deregister the devices owning `resources` (see [`IoManager::deregister_resources`]),
+    /// and wait until no thread is still dispatching an access to them.
+    ///
+    /// Once this returns, the devices don't get any access anymore, so their backends can
+    /// be torn down, e.g. when hot-unplugging a device under load. The devices themselves
+    /// are dropped once the idle [`DispatchHandle`]s dispatch their next access. Returns
+    /// the number of deregistered ranges.
+    ///
+    /// Only the accesses dispatched through the retired versions are waited for: the ones
+    /// starting meanwhile go through the updated version, and aren't stalled.
+    ///
+    /// Must not be called from within a device handler, as that would deadlock.
+    pub fn deregister_sync(&self, resources: &[Resource]) -> usize {
+        let _span = trace::span!("deregister_sync");
+        // With updates locked, accesses only begin in the slot of the current epoch. The
+        // other slot holds the stragglers of the versions retired by earlier updates, which
+        // may still reach the devices too, and the current one drains once retired.
+        let _update = self.lock_updates();
+        let epoch = self.epoch.load(Ordering::SeqCst);
+        self.wait_idle(epoch.wrapping_sub(1));
+        let count = self.apply(|manager| manager.deregister_resources(resources));
+        self.wait_idle(epoch);
+        count
+    }
+
+    /// Dispatch a batch of MMIO writes (see [`IoManager::mmio_write_batch`]).
+    ///
+    /// The whole batch is dispatched through the same version of the manager.
+    pub fn mmio_write_batch<'a, I>(&self, writes: I) -> BatchOutcome
+    where
+        I: IntoIterator<Item = (MmioAddress, &'a [u8])>,
+    {
+        self.dispatch(|manager| {
+            let _access = manager.mmio_bus.begin_access();
+            manager.write_batch(writes)
+        })
+    }
+
+    /// Dispatch a read operation to the PIO device registered at `addr`.
+    #[inline]
+    pub fn pio_read(&self, addr: PioAddress, data: &mut [u8]) -> Result<(), bus::Error> {
+        self.dispatch(|manager| manager.pio_read(addr, data))
+    }
+
+    /// Dispatch a write operation to the PIO device registered at `addr`.
+    #[inline]
+    pub fn pio_write(&self, addr: PioAddress, data: &[u8]) -> Result<(), bus::Error> {
+        self.dispatch(|manager| manager.pio_write(addr, data))
+    }
+
+    /// Dispatch a read operation to the MMIO device registered at `addr`.
+    #[inline]
+    pub fn mmio_read(&self, addr: MmioAddress, data: &mut [u8]) -> Result<(), bus::Error> {
+        self.dispatch(|manager| manager.mmio_read(addr, data))
+    }
+
+    /// Dispatch a write operation to the MMIO device registered at `addr`.
+    #[inline]
+    pub fn mmio_write(&self, addr: MmioAddress, data: &[u8]) -> Result<(), bus::Error> {
+        self.dispatch(|manager| manager.mmio_write(addr, data))
+    }
+}
+
+/// Dispatches accesses through a [`SharedIoManager`] on behalf of a single thread.
+///
+/// The handle remembers the range and device which handled the last PIO and MMIO accesses,
+/// so that repeated accesses to the same device (e.g. queue notifications) skip the bus
+/// lookup. The handle also keeps the version of the manager it last saw, and only checks
+/// an epoch counter to find out whether the manager was updated since, in which case both
+/// the version and the remembered devices are refreshed.
+///
+/// Since the handle keeps a version of the manager alive, devices deregistered through an
+/// update are only released once every handle dispatched an access after the update, or
+/// was dropped.
+///
+/// # Example
+///
+/// ```
+/// # use std::sync::Arc;
+/// # use vm_device::bus::{MmioAddress, MmioAddressOffset, MmioRange};
+/// # use vm_device::device_manager::{IoManager, MmioManager, SharedIoManager};
+/// # use vm_device::DeviceMmio;
+/// # struct NoopDevice {}
+/// #
+/// # impl DeviceMmio for NoopDevice {
+/// #     fn mmio_read(&self, base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {}
+/// #     fn mmio_write(&self, base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {}
+/// # }
+/// let manager = SharedIoManager::new(IoManager::new());
+/// let mut handle = manager.handle();
+///
+/// let range = MmioRange::new(MmioAddress(0x1000), 0x10).unwrap();
+/// manager
+///     .update(|manager| manager.register_mmio(range, Arc::new(NoopDevice {})))
+///     .unwrap();
+///
+/// // The handle notices the update.
+/// handle.mmio_write(MmioAddress(0x1000), &[1]).unwrap();
+/// handle.mmio_write(MmioAddress(0x1004), &[2]).unwrap();
+/// ```
+#[cfg(feature = "std")]
+pub struct DispatchHandle<'a> {
+    shared: &'a SharedIoManager,
+    manager: Arc<IoManager>,
+    epoch: u64,
+    last_pio: Option<(PioRange, Arc<dyn DevicePio + Send + Sync>)>,
+    last_mmio: Option<(MmioRange, Arc<dyn DeviceMmio + Send + Sync>)>,
+    // Accesses dispatched to a remembered device.
+    hits: u64,
+}
+
+#[cfg(feature = "std")]
+impl<'a> DispatchHandle<'a> {
+    /// Return the number of accesses dispatched to a remembered device, without looking up
+    /// the bus, e.g. to check that a device gets the repeated accesses the handle is meant
+    /// for.
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    // Begin an access through the current version of the manager, counted in flight.
+    #[inline]
+    fn begin(&mut self) -> InFlight<'a> {
+        loop {
+            self.refresh();
+            if let Some(in_flight) = self.shared.enter(self.epoch) {
+                return in_flight;
+            }
+        }
+    }
+
+    // Switch to the current version of the manager if it was updated.
+    #[inline]
+    fn refresh(&mut self) {
+        let epoch = self.shared.epoch.load(Ordering::Acquire);
+        if epoch != self.epoch {
+            self.epoch = epoch;
+            self.manager = self.shared.load();
+            self.last_pio = None;
+            self.last_mmio = None;
+        }
+    }
+
+    /// Dispatch a read operation to the PIO device registered at `addr`.
+    pub fn pio_read(&mut self, addr: PioAddress, data: &mut [u8]) -> Result<(), bus::Error> {
+        let _in_flight = self.begin();
+        let _access = self.manager.pio_bus.begin_access();
+        let (range, device) = lookup(
+            &self.manager.pio_bus,
+            &mut self.last_pio,
+            &mut self.hits,
+            addr,
+            data.len(),
+        )?;
+        device.pio_read(range.base(), addr - range.base(), data);
+        Ok(())
+    }
+
+    /// Dispatch a write operation to the PIO device registered at `addr`.
+    pub fn pio_write(&mut self, addr: PioAddress, data: &[u8]) -> Result<(), bus::Error> {
+        let _in_flight = self.begin();
+        let _access = self.manager.pio_bus.begin_access();
+        let (range, device) = lookup(
+            &self.manager.pio_bus,
+            &mut self.last_pio,
+            &mut self.hits,
+            addr,
+            data.len(),
+        )?;
+        device.pio_write(range.base(), addr - range.base(), data);
+        Ok(())
+    }
+
+    /// Dispatch a read operation to the MMIO device registered at `addr`.
+    pub fn mmio_read(&mut self, addr: MmioAddress, data: &mut [u8]) -> Result<(), bus::Error> {
+        let _in_flight = self.begin();
+        let _access = self.manager.mmio_bus.begin_access();
+        let (range, device) = lookup(
+            &self.manager.mmio_bus,
+            &mut self.last_mmio,
+            &mut self.hits,
+            addr,
+            data.len(),
+        )?;
+        device.mmio_read(range.base(), addr - range.base(), data);
+        Ok(())
+    }
+
+    /// Dispatch a write operation to the MMIO device registered at `addr`.
+    pub fn mmio_write(&mut self, addr: MmioAddress, data: &[u8]) -> Result<(), bus::Error> {
+        let _in_flight = self.begin();
+        let _access = self.manager.mmio_bus.begin_access();
+        let (range, device) = lookup(
+            &self.manager.mmio_bus,
+            &mut self.last_mmio,
+            &mut self.hits,
+            addr,
+            data.len(),
+        )?;
+        device.mmio_write(range.base(), addr - range.base(), data);
+        Ok(())
+    }
+}
+
+// Return the range and device handling an access of `len` bytes at `addr`, trying the
+// remembered `last` one before looking up `bus`, and remembering the result.
+#[cfg(feature = "std")]
+fn lookup<'a, A: BusAddress, D: ?Sized>(
+    bus: &Bus<A, Arc<D>>,
+    last: &'a mut Option<(BusRange<A>, Arc<D>)>,
+    hits: &mut u64,
+    addr: A,
+    len: usize,
+) -> Result<&'a (BusRange<A>, Arc<D>), bus::Error> {
+    if let Some((range, _)) = last {
+        if range.covers(addr, len) {
+            *hits += 1;
+        } else {
+            *last = None;
+        }
+    }
+    if last.is_none() {
+        let (range, device) = bus.check_access(addr, len)?;
+        *last = Some((*range, device.clone()));
+    }
+    Ok(last.as_ref().unwrap())
+}
+
+// Group the ranges registered on `bus` by the device object they are associated with, in
+// the order in which the devices first appear on the bus.
+#[cfg(feature = "std")]
+pub(crate) fn group_ranges<A: BusAddress, D: ?Sized>(
+    bus: &Bus<A, Arc<D>>,
+) -> Vec<(&Arc<D>, Vec<BusRange<A>>)> {
+    let mut devices: Vec<(&Arc<D>, Vec<BusRange<A>>)> = Vec::new();
+    for (range, device) in bus.iter() {
+        match devices.iter_mut().find(|(d, _)| Arc::ptr_eq(d, device)) {
+            Some((_, ranges)) => ranges.push(*range),
+            None => devices.push((device, vec![*range])),
+        }
+    }
+    devices
+}
+
+// Replace the device registered at `addr` on `bus` with `device`, on all its ranges.
+// Returns the previous device and its ranges.
+#[cfg(feature = "std")]
+fn replace<A: BusAddress, D: ?Sized>(
+    bus: &mut Bus<A, Arc<D>>,
+    addr: A,
+    device: Arc<D>,
+) -> Option<(Arc<D>, Vec<BusRange<A>>)> {
+    let previous = bus.device(addr)?.1.clone();
+    let ranges: Vec<BusRange<A>> = bus
+        .iter()
+        .filter(|(_, d)| Arc::ptr_eq(d, &previous))
+        .map(|(range, _)| *range)
+        .collect();
+    for range in ranges.iter() {
+        if let Some((_, d)) = bus.device_mut(range.base()) {
+            *d = device.clone();
+        }
+    }
+    Some((previous, ranges))
+}
+
+// Return the base addresses and sizes of the MMIO ranges of `resources`.
+#[cfg(feature = "std")]
+fn mmio_ranges(resources: &[Resource]) -> Vec<(u64, u64)> {
+    resources
+        .iter()
+        .filter_map(|res| match *res {
+            Resource::MmioAddressRange { base, size } => Some((base, size)),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(feature = "std")]
+fn write_json_devices<A, D, F>(json: &mut String, bus: &Bus<A, Arc<D>>, properties: F)
+where
+    A: BusAddress,
+    A::V: Into<u64>,
+    D: ?Sized,
+    F: Fn(&D) -> Vec<(String, String)>,
+{
+    json.push('[');
+    for (idx, (device, ranges)) in group_ranges(bus).into_iter().enumerate() {
+        if idx > 0 {
+            json.push(',');
+        }
+        json.push_str("{\"ranges\":[");
+        for (idx, range) in ranges.iter().enumerate() {
+            if idx > 0 {
+                json.push(',');
+            }
+            json.push_str(&format!(
+                "{{\"base\":{},\"size\":{}}}",
+                range.base().value().into(),
+                range.size().into()
+            ));
+        }
+        json.push_str("],\"properties\":{");
+        for (idx, (key, value)) in properties(device).iter().enumerate() {
+            if idx > 0 {
+                json.push(',');
+            }
+            write_json_string(json, key);
+            json.push(':');
+            write_json_string(json, value);
+        }
+        json.push_str("}}");
+    }
+    json.push(']');
+}
+
+#[cfg(feature = "std")]
+pub(crate) fn write_json_string(json: &mut String, value: &str) {
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    use std::error::Error;
+    use std::sync::Mutex;
+
+    use bus::{MmioAddressOffset, PioAddressOffset};
+
+    use crate::MutDeviceMmio;
+
+    const PIO_ADDRESS_SIZE: u16 = 4;
+    const PIO_ADDRESS_BASE: u16 = 0x40;
+    const MMIO_ADDRESS_SIZE: u64 = 0x8765_4321;
+    const MMIO_ADDRESS_BASE: u64 = 0x1234_5678;
+    const LEGACY_IRQ: u32 = 4;
+    const CONFIG_DATA: u32 = 0x1234;
+
+    struct DummyDevice {
+        config: Mutex<u32>,
+    }
+
+    impl DummyDevice {
+        fn new(config: u32) -> Self {
+            DummyDevice {
+                config: Mutex::new(config),
+            }
+        }
+    }
+
+    impl DevicePio for DummyDevice {
+        fn pio_read(&self, _base: PioAddress, _offset: PioAddressOffset, data: &mut [u8]) {
+            if data.len() > 4 {
+                return;
+            }
+            for (idx, iter) in data.iter_mut().enumerate() {
+                let config = self.config.lock().expect("failed to acquire lock");
+                *iter = (*config >> (idx * 8) & 0xff) as u8;
+            }
+        }
+
+        fn pio_write(&self, _base: PioAddress, _offset: PioAddressOffset, data: &[u8]) {
+            let mut config = self.config.lock().expect("failed to acquire lock");
+            *config = u32::from(data[0]) & 0xff;
+        }
+    }
+
+    impl DeviceMmio for DummyDevice {
+        fn mmio_read(&self, _base: MmioAddress, _offset: MmioAddressOffset, data: &mut [u8]) {
+            if data.len() > 4 {
+                return;
+            }
             for (idx, iter) in data.iter_mut().enumerate() {
                 let config = self.config.lock().expect("failed to acquire lock");
                 *iter = (*config >> (idx * 8) & 0xff) as u8;
@@ -470,31 +2212,78 @@ mod tests {
     }
 
     #[test]
-    fn test_register_deregister_device_io() {
+    fn test_register_deregister_device_io() {
+        let mut io_mgr = IoManager::new();
+        let dummy = DummyDevice::new(0);
+        let dum = Arc::new(dummy);
+
+        let mut resource: Vec<Resource> = Vec::new();
+        let mmio = Resource::MmioAddressRange {
+            base: MMIO_ADDRESS_BASE,
+            size: MMIO_ADDRESS_SIZE,
+        };
+        let irq = Resource::LegacyIrq(LEGACY_IRQ);
+        let pio = Resource::PioAddressRange {
+            base: PIO_ADDRESS_BASE,
+            size: PIO_ADDRESS_SIZE,
+        };
+
+        resource.push(mmio);
+        resource.push(irq);
+        resource.push(pio);
+
+        assert!(io_mgr
+            .register_mmio_resources(dum.clone(), &resource)
+            .is_ok());
+        assert!(io_mgr.register_pio_resources(dum, &resource).is_ok());
+        assert_eq!(io_mgr.deregister_resources(&resource), 2);
+    }
+
+    #[test]
+    fn test_strict_mode() {
         let mut io_mgr = IoManager::new();
-        let dummy = DummyDevice::new(0);
-        let dum = Arc::new(dummy);
+        io_mgr.set_strict(true);
+        let dum = Arc::new(DummyDevice::new(0));
 
-        let mut resource: Vec<Resource> = Vec::new();
         let mmio = Resource::MmioAddressRange {
             base: MMIO_ADDRESS_BASE,
             size: MMIO_ADDRESS_SIZE,
         };
-        let irq = Resource::LegacyIrq(LEGACY_IRQ);
         let pio = Resource::PioAddressRange {
             base: PIO_ADDRESS_BASE,
             size: PIO_ADDRESS_SIZE,
         };
+        let resources = [mmio, Resource::LegacyIrq(LEGACY_IRQ), pio];
+
+        // Nothing gets registered when a resource is rejected.
+        assert!(matches!(
+            io_mgr.register_resources(dum.clone(), &resources),
+            Err(super::Error::UnsupportedResource {
+                index: 1,
+                resource: Resource::LegacyIrq(LEGACY_IRQ)
+            })
+        ));
+        assert!(matches!(
+            io_mgr.register_mmio_resources(dum.clone(), &resources),
+            Err(super::Error::UnsupportedResource { index: 1, .. })
+        ));
+        assert!(io_mgr
+            .mmio_read(MmioAddress(MMIO_ADDRESS_BASE), &mut [0])
+            .is_err());
 
-        resource.push(mmio);
-        resource.push(irq);
-        resource.push(pio);
+        // Ranges meant for the other bus are fine.
+        assert!(io_mgr
+            .register_mmio_resources(dum.clone(), &[resources[0].clone(), resources[2].clone()])
+            .is_ok());
+        assert!(io_mgr
+            .register_pio_resources(dum, &[resources[0].clone(), resources[2].clone()])
+            .is_ok());
+        assert_eq!(io_mgr.deregister_resources(&resources), 2);
 
+        io_mgr.set_strict(false);
         assert!(io_mgr
-            .register_mmio_resources(dum.clone(), &resource)
+            .register_resources(Arc::new(DummyDevice::new(0)), &resources)
             .is_ok());
-        assert!(io_mgr.register_pio_resources(dum, &resource).is_ok());
-        assert_eq!(io_mgr.deregister_resources(&resource), 2);
     }
 
     #[test]
@@ -536,6 +2325,104 @@ mod tests {
             .is_err());
     }
 
+    #[test]
+    fn test_mmio_rmw() {
+        struct Register(u32);
+
+        impl MutDeviceMmio for Register {
+            fn mmio_read(&mut self, _: MmioAddress, _: MmioAddressOffset, data: &mut [u8]) {
+                data.copy_from_slice(&self.0.to_le_bytes());
+            }
+
+            fn mmio_write(&mut self, _: MmioAddress, _: MmioAddressOffset, data: &[u8]) {
+                let mut value = [0; 4];
+                value.copy_from_slice(data);
+                self.0 = u32::from_le_bytes(value);
+            }
+        }
+
+        let register = Arc::new(Mutex::new(Register(0x10)));
+        let mut manager = IoManager::new();
+        let range = MmioRange::new(MmioAddress(0x1000), 4).unwrap();
+        manager.register_mmio(range, register.clone()).unwrap();
+        let manager = Arc::new(manager);
+
+        assert_eq!(
+            manager.mmio_rmw(MmioAddress(0x1000), |old| old | 1),
+            Ok(0x10)
+        );
+        assert_eq!(register.lock().unwrap().0, 0x11);
+        assert!(manager.mmio_rmw(MmioAddress(0x2000), |old| old).is_err());
+
+        // The increments don't race with each other.
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let manager = manager.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..1000 {
+                        manager
+                            .mmio_rmw(MmioAddress(0x1000), |old| old + 1)
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(register.lock().unwrap().0, 0x11 + 4000);
+
+        // Managers without a bus get a read followed by a write, and debug reads looked up
+        // through `mmio_device`.
+        struct Single(MmioRange, Arc<Mutex<Register>>);
+
+        impl MmioManager for Single {
+            type D = Arc<Mutex<Register>>;
+
+            fn mmio_device(&self, addr: MmioAddress) -> Option<(&MmioRange, &Self::D)> {
+                let range = &self.0;
+                (range.base() <= addr && addr <= range.last()).then_some((range, &self.1))
+            }
+
+            fn mmio_read(&self, addr: MmioAddress, data: &mut [u8]) -> Result<(), bus::Error> {
+                DeviceMmio::mmio_read(&self.1, self.0.base(), addr - self.0.base(), data);
+                Ok(())
+            }
+
+            fn mmio_write(&self, addr: MmioAddress, data: &[u8]) -> Result<(), bus::Error> {
+                DeviceMmio::mmio_write(&self.1, self.0.base(), addr - self.0.base(), data);
+                Ok(())
+            }
+
+            fn register_mmio(&mut self, range: MmioRange, _: Self::D) -> Result<(), bus::Error> {
+                Err(bus::Error::invalid_range(range.base(), range.size()))
+            }
+
+            fn deregister_mmio(&mut self, _addr: MmioAddress) -> Option<(MmioRange, Self::D)> {
+                None
+            }
+        }
+
+        let single = Single(range, register.clone());
+        assert_eq!(
+            single.mmio_rmw(MmioAddress(0x1000), |old| old * 2),
+            Ok(0x11 + 4000)
+        );
+        assert_eq!(register.lock().unwrap().0, (0x11 + 4000) * 2);
+        let mut data = [0; 4];
+        assert_eq!(
+            single.mmio_debug_read(MmioAddress(0x1000), &mut data),
+            Ok(false)
+        );
+        assert_eq!(
+            single.mmio_debug_read(MmioAddress(0x1002), &mut data),
+            Err(bus::Error::DeviceNotFound {
+                addr: 0x1002,
+                len: 4
+            })
+        );
+    }
+
     #[test]
     fn test_pio_read_write() {
         let mut io_mgr: IoManager = Default::default();
@@ -573,10 +2460,1185 @@ mod tests {
     }
 
     #[test]
-    fn test_error_code() {
-        let err = super::Error::Bus(bus::Error::DeviceOverlap);
+    fn test_layout_restore() {
+        let mut io_mgr = IoManager::new();
+        let dum = Arc::new(DummyDevice::new(CONFIG_DATA));
+        let resources = [
+            Resource::MmioAddressRange {
+                base: 0x1000,
+                size: 0x100,
+            },
+            Resource::LegacyIrq(LEGACY_IRQ),
+            Resource::MmioAddressRange {
+                base: 0x3000,
+                size: 0x100,
+            },
+        ];
+        io_mgr.register_mmio_resources(dum, &resources).unwrap();
+        let other = Resource::MmioAddressRange {
+            base: 0x2000,
+            size: 0x100,
+        };
+        io_mgr
+            .register_mmio_resources(Arc::new(DummyDevice::new(0)), std::slice::from_ref(&other))
+            .unwrap();
+
+        let layout = io_mgr.layout();
+        assert_eq!(layout.devices().len(), 2);
+        assert_eq!(
+            layout.devices()[0].resources().get_mmio_address_ranges(),
+            vec![(0x1000, 0x100), (0x3000, 0x100)]
+        );
+        assert_eq!(
+            layout.devices()[1].resources().get_mmio_address_ranges(),
+            vec![(0x2000, 0x100)]
+        );
+
+        let mut restored = IoManager::new();
+        let mut calls = 0;
+        restored
+            .restore(&layout, |_| {
+                calls += 1;
+                Arc::new(DummyDevice::new(CONFIG_DATA))
+            })
+            .unwrap();
+        assert_eq!(calls, 2);
+
+        let mut data = [0; 2];
+        for base in [0x1000, 0x2000, 0x3000] {
+            restored.mmio_read(MmioAddress(base), &mut data).unwrap();
+            assert_eq!(data, [0x34, 0x12]);
+        }
+        assert!(restored.mmio_read(MmioAddress(0x4000), &mut data).is_err());
+
+        // Restoring the same layout again does not fit anymore and leaves the manager as is.
+        let err = restored
+            .restore(&layout, |_| panic!("factory must not be invoked"))
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            super::Error::Bus(bus::Error::DeviceOverlap { base: 0x1000, .. })
+        ));
+        assert_eq!(restored.layout().devices().len(), 2);
+
+        // Layouts saved in any serde format restore the same way.
+        #[cfg(feature = "serde")]
+        {
+            let json = serde_json::to_string(&layout).unwrap();
+            let layout: Layout = serde_json::from_str(&json).unwrap();
+            let mut restored = IoManager::new();
+            restored
+                .restore(&layout, |_| Arc::new(DummyDevice::new(CONFIG_DATA)))
+                .unwrap();
+            assert_eq!(restored.layout().devices().len(), 2);
+        }
+
+        // Ranges which overlap inside the layout are rejected as well.
+        let mut resources = DeviceResources::new();
+        resources.append(other.clone());
+        resources.append(other);
+        let mut layout = Layout::new();
+        layout.append(DeviceDescriptor::new(resources));
+        let err = IoManager::new()
+            .restore(&layout, |_| panic!("factory must not be invoked"))
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            super::Error::Bus(bus::Error::DeviceOverlap { .. })
+        ));
+    }
+
+    #[test]
+    fn test_shared_io_manager() {
+        use std::thread;
+
+        let mut io_mgr = IoManager::new();
+        let range = MmioRange::new(MmioAddress(MMIO_ADDRESS_BASE), 0x100).unwrap();
+        io_mgr
+            .register_mmio(range, Arc::new(DummyDevice::new(CONFIG_DATA)))
+            .unwrap();
+        let shared = Arc::new(SharedIoManager::new(io_mgr));
+        let previous = shared.load();
+
+        let hotplug = MmioRange::new(MmioAddress(MMIO_ADDRESS_BASE + 0x1000), 0x100).unwrap();
+        let dispatchers: Vec<_> = (0..4)
+            .map(|_| {
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        let mut data = [0; 4];
+                        shared
+                            .mmio_read(MmioAddress(MMIO_ADDRESS_BASE), &mut data)
+                            .unwrap();
+                        assert_eq!(u32::from_le_bytes(data), CONFIG_DATA);
+                        let _ = shared.mmio_read(hotplug.base(), &mut data);
+                    }
+                })
+            })
+            .collect();
+        for idx in 0..100 {
+            if idx % 2 == 0 {
+                shared
+                    .update(|io_mgr| io_mgr.register_mmio(hotplug, Arc::new(DummyDevice::new(0))))
+                    .unwrap();
+            } else {
+                assert!(shared
+                    .update(|io_mgr| io_mgr.deregister_mmio(hotplug.base()))
+                    .is_some());
+            }
+        }
+        for dispatcher in dispatchers {
+            dispatcher.join().unwrap();
+        }
+
+        // The last update deregistered the hotplugged device.
+        assert!(shared.mmio_read(hotplug.base(), &mut [0; 4]).is_err());
+        shared
+            .update(|io_mgr| io_mgr.register_mmio(hotplug, Arc::new(DummyDevice::new(0))))
+            .unwrap();
+        assert!(shared.mmio_write(hotplug.base(), &[1]).is_ok());
+        assert!(shared.load().mmio_device(hotplug.base()).is_some());
+        // Earlier versions don't see later updates.
+        assert!(previous.mmio_device(hotplug.base()).is_none());
+        assert!(previous
+            .mmio_read(MmioAddress(MMIO_ADDRESS_BASE), &mut [0; 4])
+            .is_ok());
+
+        // A failed update is still made current.
+        let result = shared.update(|io_mgr| {
+            io_mgr.deregister_mmio(hotplug.base());
+            io_mgr.register_mmio(range, Arc::new(DummyDevice::new(0)))
+        });
+        assert_eq!(result, Err(bus::Error::overlap(&range)));
+        assert!(shared.load().mmio_device(hotplug.base()).is_none());
+
+        let pio_range = PioRange::new(PioAddress(PIO_ADDRESS_BASE), PIO_ADDRESS_SIZE).unwrap();
+        shared
+            .update(|io_mgr| io_mgr.register_pio(pio_range, Arc::new(DummyDevice::new(0))))
+            .unwrap();
+        shared
+            .pio_write(PioAddress(PIO_ADDRESS_BASE), &[0x12])
+            .unwrap();
+        let mut data = [0; 1];
+        shared
+            .pio_read(PioAddress(PIO_ADDRESS_BASE), &mut data)
+            .unwrap();
+        assert_eq!(data, [0x12]);
+    }
+
+    #[test]
+    fn test_deregister_sync() {
+        use std::sync::atomic::AtomicBool;
+        use std::thread;
+        use std::time::Duration;
+
+        // Records the accesses starting once it's been torn down.
+        #[derive(Default)]
+        struct Unplugged {
+            removed: AtomicBool,
+            late: AtomicBool,
+        }
+
+        impl DeviceMmio for Unplugged {
+            fn mmio_read(&self, _: MmioAddress, _: MmioAddressOffset, _: &mut [u8]) {
+                if self.removed.load(Ordering::SeqCst) {
+                    self.late.store(true, Ordering::SeqCst);
+                }
+                thread::sleep(Duration::from_micros(50));
+            }
+
+            fn mmio_write(&self, _: MmioAddress, _: MmioAddressOffset, _: &[u8]) {}
+        }
+
+        let resources = [Resource::MmioAddressRange {
+            base: MMIO_ADDRESS_BASE,
+            size: 0x100,
+        }];
+        let device = Arc::new(Unplugged::default());
+        let mut io_mgr = IoManager::new();
+        io_mgr
+            .register_mmio_resources(device.clone(), &resources)
+            .unwrap();
+        let shared = Arc::new(SharedIoManager::new(io_mgr));
+        let stop = Arc::new(AtomicBool::new(false));
+        let dispatchers: Vec<_> = (0..4)
+            .map(|idx| {
+                let (shared, stop) = (shared.clone(), stop.clone());
+                thread::spawn(move || {
+                    let mut handle = shared.handle();
+                    while !stop.load(Ordering::SeqCst) {
+                        let addr = MmioAddress(MMIO_ADDRESS_BASE);
+                        let _ = match idx % 2 {
+                            0 => handle.mmio_read(addr, &mut [0; 4]),
+                            _ => shared.mmio_read(addr, &mut [0; 4]),
+                        };
+                    }
+                })
+            })
+            .collect();
+
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(shared.deregister_sync(&resources), 1);
+        device.removed.store(true, Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(10));
+        stop.store(true, Ordering::SeqCst);
+        for dispatcher in dispatchers {
+            dispatcher.join().unwrap();
+        }
+        assert!(!device.late.load(Ordering::SeqCst));
+        assert!(shared
+            .mmio_read(MmioAddress(MMIO_ADDRESS_BASE), &mut [0; 4])
+            .is_err());
+        // The handles refreshed, so the device was released.
+        assert_eq!(Arc::strong_count(&device), 1);
+
+        // Only the accesses to the retired versions are waited for, so devices can be
+        // deregistered while the buses are quiesced.
+        shared
+            .update(|io_mgr| io_mgr.register_mmio_resources(device.clone(), &resources))
+            .unwrap();
+        let current = shared.load();
+        let quiesced = current.quiesce();
+        assert_eq!(shared.deregister_sync(&resources), 1);
+        drop(quiesced);
+    }
+
+    #[test]
+    fn test_dispatch_handle() {
+        let shared = SharedIoManager::new(IoManager::new());
+        let mut handle = shared.handle();
+        let range = MmioRange::new(MmioAddress(MMIO_ADDRESS_BASE), 0x100).unwrap();
+        let mut data = [0; 4];
+        assert_eq!(
+            handle.mmio_read(range.base(), &mut data),
+            Err(bus::Error::DeviceNotFound {
+                addr: MMIO_ADDRESS_BASE,
+                len: 4
+            })
+        );
+
+        shared
+            .update(|io_mgr| io_mgr.register_mmio(range, Arc::new(DummyDevice::new(CONFIG_DATA))))
+            .unwrap();
+        handle.mmio_read(range.base(), &mut data).unwrap();
+        assert_eq!(u32::from_le_bytes(data), CONFIG_DATA);
+        assert_eq!(handle.hits(), 0);
+        handle.mmio_write(range.base(), &[0x12]).unwrap();
+        handle
+            .mmio_read(MmioAddress(MMIO_ADDRESS_BASE + 0xfc), &mut data)
+            .unwrap();
+        assert_eq!(data, [0x12, 0, 0, 0]);
+        assert_eq!(handle.hits(), 2);
+
+        // Accesses which don't fit in the remembered range are looked up again.
+        assert_eq!(
+            handle.mmio_read(MmioAddress(MMIO_ADDRESS_BASE + 0xfd), &mut data),
+            Err(bus::Error::DeviceNotFound {
+                addr: MMIO_ADDRESS_BASE + 0xfd,
+                len: 4
+            })
+        );
+        assert_eq!(
+            handle.mmio_read(range.base(), &mut []),
+            Err(bus::Error::InvalidRange {
+                base: MMIO_ADDRESS_BASE,
+                size: 0
+            })
+        );
+        handle.mmio_read(range.base(), &mut data).unwrap();
+        assert_eq!(handle.hits(), 2);
+
+        // The remembered device is forgotten after an update.
+        shared
+            .update(|io_mgr| {
+                io_mgr.deregister_mmio(range.base());
+                io_mgr.register_mmio(range, Arc::new(DummyDevice::new(0)))
+            })
+            .unwrap();
+        handle.mmio_read(range.base(), &mut data).unwrap();
+        assert_eq!(data, [0; 4]);
+        assert_eq!(handle.hits(), 2);
+
+        let pio_range = PioRange::new(PioAddress(PIO_ADDRESS_BASE), PIO_ADDRESS_SIZE).unwrap();
+        shared
+            .update(|io_mgr| io_mgr.register_pio(pio_range, Arc::new(DummyDevice::new(0))))
+            .unwrap();
+        handle.pio_write(pio_range.base(), &[0x34]).unwrap();
+        let mut data = [0; 1];
+        handle.pio_read(pio_range.base(), &mut data).unwrap();
+        assert_eq!(data, [0x34]);
+        assert_eq!(handle.hits(), 3);
+    }
+
+    #[test]
+    fn test_mmio_write_batch() {
+        // Identifier of the device, offset and data of a write.
+        type Write = (u8, u64, Vec<u8>);
+
+        // Records the writes it gets, tagged with an identifier.
+        struct Recorder(u8, Arc<Mutex<Vec<Write>>>);
+
+        impl DeviceMmio for Recorder {
+            fn mmio_read(&self, _base: MmioAddress, _offset: MmioAddressOffset, _data: &mut [u8]) {}
+
+            fn mmio_write(&self, _base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+                self.1.lock().unwrap().push((self.0, offset, data.to_vec()));
+            }
+        }
+
+        let writes = Arc::new(Mutex::new(Vec::new()));
+        let mut io_mgr = IoManager::new();
+        for id in 0..2 {
+            let range = MmioRange::new(MmioAddress(0x1000 * (u64::from(id) + 1)), 0x100).unwrap();
+            io_mgr
+                .register_mmio(range, Arc::new(Recorder(id, writes.clone())))
+                .unwrap();
+        }
+
+        let batch: [(u64, &[u8]); 6] = [
+            (0x1000, &[1]),
+            (0x1010, &[2, 3]),
+            (0x2000, &[4]),
+            (0x10fe, &[5, 6, 7]),
+            (0x3000, &[8]),
+            (0x10fc, &[9, 10, 11, 12]),
+        ];
+        let outcome =
+            io_mgr.mmio_write_batch(batch.iter().map(|&(addr, data)| (MmioAddress(addr), data)));
+        assert_eq!(
+            outcome,
+            BatchOutcome {
+                dispatched: 4,
+                failed: 2,
+                first_error: Some((
+                    3,
+                    bus::Error::DeviceNotFound {
+                        addr: 0x10fe,
+                        len: 3
+                    }
+                )),
+            }
+        );
+        assert_eq!(
+            *writes.lock().unwrap(),
+            vec![
+                (0, 0, vec![1]),
+                (0, 0x10, vec![2, 3]),
+                (1, 0, vec![4]),
+                (0, 0xfc, vec![9, 10, 11, 12]),
+            ]
+        );
+
+        assert_eq!(
+            SharedIoManager::new(io_mgr).mmio_write_batch(vec![(MmioAddress(0x2004), &[0u8][..])]),
+            BatchOutcome {
+                dispatched: 1,
+                ..Default::default()
+            }
+        );
+    }
+    #[test]
+    fn test_quiesce() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::mpsc::{channel, Receiver, Sender};
+        use std::thread;
+        use std::time::Duration;
+
+        // A device which signals when a write starts, and then waits to be released.
+        struct BlockingDevice {
+            started: Mutex<Sender<()>>,
+            release: Mutex<Receiver<()>>,
+        }
+
+        impl DeviceMmio for BlockingDevice {
+            fn mmio_read(&self, _base: MmioAddress, _offset: MmioAddressOffset, _data: &mut [u8]) {}
+
+            fn mmio_write(&self, _base: MmioAddress, _offset: MmioAddressOffset, _data: &[u8]) {
+                self.started.lock().unwrap().send(()).unwrap();
+                self.release.lock().unwrap().recv().unwrap();
+            }
+        }
+
+        let (started_tx, started_rx) = channel();
+        let (release_tx, release_rx) = channel();
+        let device = BlockingDevice {
+            started: Mutex::new(started_tx),
+            release: Mutex::new(release_rx),
+        };
+
+        let mut io_mgr = IoManager::new();
+        let range = MmioRange::new(MmioAddress(MMIO_ADDRESS_BASE), 0x100).unwrap();
+        io_mgr.register_mmio(range, Arc::new(device)).unwrap();
+        let io_mgr = Arc::new(io_mgr);
+        let quiesced = Arc::new(AtomicBool::new(false));
+
+        let writer = {
+            let io_mgr = io_mgr.clone();
+            thread::spawn(move || {
+                io_mgr
+                    .mmio_write(MmioAddress(MMIO_ADDRESS_BASE), &[0; 4])
+                    .unwrap()
+            })
+        };
+        started_rx.recv().unwrap();
+
+        let snapshotter = {
+            let io_mgr = io_mgr.clone();
+            let quiesced = quiesced.clone();
+            thread::spawn(move || {
+                let _guard = io_mgr.quiesce();
+                quiesced.store(true, Ordering::SeqCst);
+            })
+        };
+
+        // The write is still in flight, so the bus cannot be quiesced yet.
+        thread::sleep(Duration::from_millis(50));
+        assert!(!quiesced.load(Ordering::SeqCst));
+
+        release_tx.send(()).unwrap();
+        writer.join().unwrap();
+        snapshotter.join().unwrap();
+        assert!(quiesced.load(Ordering::SeqCst));
+
+        // Accesses go through again once the guard is dropped.
+        assert!(io_mgr
+            .mmio_read(MmioAddress(MMIO_ADDRESS_BASE), &mut [0; 4])
+            .is_ok());
+    }
+
+    #[test]
+    fn test_restore_rebased() {
+        let mut layout = Layout::new();
+        for ranges in [
+            vec![(0x1000, 0x100), (0x3000, 0x100)],
+            vec![(0x2000, 0x100)],
+        ] {
+            let mut resources = DeviceResources::new();
+            for (base, size) in ranges {
+                resources.append(Resource::MmioAddressRange { base, size });
+            }
+            resources.append(Resource::LegacyIrq(LEGACY_IRQ));
+            layout.append(DeviceDescriptor::new(resources));
+        }
+
+        let mut rebase = BTreeMap::new();
+        rebase.insert(0x3000, 0x13000);
+        rebase.insert(0x2000, 0x12000);
+        rebase.insert(0x5000, 0x15000);
+        let rebased = layout.rebase(&rebase);
+        assert_eq!(
+            rebased.devices()[0].resources().get_mmio_address_ranges(),
+            vec![(0x1000, 0x100), (0x13000, 0x100)]
+        );
+        assert_eq!(
+            rebased.devices()[1].resources().get_legacy_irq(),
+            Some(LEGACY_IRQ)
+        );
+
+        let mut io_mgr = IoManager::new();
+        let mut relocations = Vec::new();
+        io_mgr
+            .restore_rebased(
+                &layout,
+                &rebase,
+                |_| Arc::new(DummyDevice::new(CONFIG_DATA)),
+                |desc, old, new| {
+                    relocations.push((desc.resources().get_mmio_address_ranges()[0].0, old, new))
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            relocations,
+            vec![
+                (0x1000, MmioAddress(0x3000), MmioAddress(0x13000)),
+                (0x12000, MmioAddress(0x2000), MmioAddress(0x12000)),
+            ]
+        );
+
+        let mut data = [0; 1];
+        for base in [0x1000, 0x12000, 0x13000] {
+            assert!(io_mgr.mmio_read(MmioAddress(base), &mut data).is_ok());
+        }
+        for base in [0x2000, 0x3000] {
+            assert!(io_mgr.mmio_read(MmioAddress(base), &mut data).is_err());
+        }
 
+        // Rebasing onto an invalid range fails without invoking the relocation callback.
+        let mut rebase = BTreeMap::new();
+        rebase.insert(0x2000, u64::MAX);
+        let err = IoManager::new()
+            .restore_rebased(
+                &layout,
+                &rebase,
+                |_| Arc::new(DummyDevice::new(CONFIG_DATA)),
+                |_, _, _| panic!("no relocation expected"),
+            )
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            super::Error::Bus(bus::Error::InvalidRange { base: u64::MAX, .. })
+        ));
+    }
+
+    #[test]
+    fn test_restore_resources() {
+        use crate::snapshot::{load_resources, save_resources};
+        use std::collections::HashSet;
+
+        #[derive(Default)]
+        struct IrqAllocator {
+            used: HashSet<u32>,
+        }
+
+        impl ResourceReservation for IrqAllocator {
+            fn reserve(&mut self, resource: &Resource) -> bool {
+                match resource {
+                    Resource::LegacyIrq(irq) => self.used.insert(*irq),
+                    _ => true,
+                }
+            }
+
+            fn release(&mut self, resource: &Resource) {
+                if let Resource::LegacyIrq(irq) = resource {
+                    self.used.remove(irq);
+                }
+            }
+        }
+
+        let mut resources = DeviceResources::new();
+        resources.append(Resource::MmioAddressRange {
+            base: MMIO_ADDRESS_BASE,
+            size: MMIO_ADDRESS_SIZE,
+        });
+        resources.append(Resource::LegacyIrq(LEGACY_IRQ));
+        resources.append(Resource::PioAddressRange {
+            base: PIO_ADDRESS_BASE,
+            size: PIO_ADDRESS_SIZE,
+        });
+        let saved = save_resources(&resources);
+
+        let mut io_mgr = IoManager::new();
+        let mut allocator = IrqAllocator::default();
+        let dum = Arc::new(DummyDevice::new(CONFIG_DATA));
+        io_mgr
+            .restore_resources(dum, &load_resources(&saved).unwrap(), &mut allocator)
+            .unwrap();
+        assert!(allocator.used.contains(&LEGACY_IRQ));
+        let mut data = [0; 1];
+        io_mgr
+            .mmio_read(MmioAddress(MMIO_ADDRESS_BASE), &mut data)
+            .unwrap();
+        io_mgr
+            .pio_read(PioAddress(PIO_ADDRESS_BASE), &mut data)
+            .unwrap();
+
+        // The IRQ is already in use, so nothing gets registered.
+        let mut other = IoManager::new();
+        let err = other
+            .restore_resources(Arc::new(DummyDevice::new(0)), &resources, &mut allocator)
+            .unwrap_err();
+        assert_eq!(
+            err,
+            super::Error::ResourceUnavailable {
+                index: 1,
+                resource: Resource::LegacyIrq(LEGACY_IRQ)
+            }
+        );
+        assert_eq!(other.layout().devices().len(), 0);
+
+        // The PIO range overlaps, so the MMIO registration and the IRQ reservation
+        // get rolled back.
+        let mut resources = DeviceResources::new();
+        resources.append(Resource::LegacyIrq(LEGACY_IRQ + 1));
+        resources.append(Resource::MmioAddressRange {
+            base: 0x1000,
+            size: 0x1000,
+        });
+        resources.append(Resource::PioAddressRange {
+            base: PIO_ADDRESS_BASE,
+            size: 1,
+        });
+        let err = io_mgr
+            .restore_resources(Arc::new(DummyDevice::new(0)), &resources, &mut allocator)
+            .unwrap_err();
+        assert_eq!(
+            err,
+            super::Error::Resource {
+                index: 2,
+                error: bus::Error::DeviceOverlap {
+                    base: u64::from(PIO_ADDRESS_BASE),
+                    size: 1
+                }
+            }
+        );
+        assert_eq!(allocator.used.len(), 1);
+        assert!(io_mgr.mmio_read(MmioAddress(0x1000), &mut data).is_err());
+        assert!(io_mgr
+            .mmio_read(MmioAddress(MMIO_ADDRESS_BASE), &mut data)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_register_evented() {
+        // Event loop keeping the configuration of the devices it was given.
+        #[derive(Default)]
+        struct Events {
+            devices: Vec<(usize, Arc<DummyDevice>)>,
+            next: usize,
+            full: bool,
+        }
+
+        impl EventLoop for Events {
+            type Id = usize;
+
+            fn remove(&mut self, id: usize) {
+                self.devices.retain(|(other, _)| *other != id);
+            }
+        }
+
+        impl Subscribe<DummyDevice> for Events {
+            fn add(&mut self, device: Arc<DummyDevice>) -> Option<usize> {
+                if self.full {
+                    return None;
+                }
+                self.next += 1;
+                self.devices.push((self.next, device));
+                Some(self.next)
+            }
+        }
+
+        let resources = [
+            Resource::MmioAddressRange {
+                base: 0x1000,
+                size: 0x100,
+            },
+            Resource::LegacyIrq(LEGACY_IRQ),
+            Resource::MmioAddressRange {
+                base: 0x2000,
+                size: 0x100,
+            },
+        ];
+        let mut io_mgr = IoManager::new();
+        let mut events = Events::default();
+        let id = io_mgr
+            .register_mmio_evented(Arc::new(DummyDevice::new(1)), &resources, &mut events)
+            .unwrap();
+        assert_eq!(events.devices.len(), 1);
+        let mut data = [0; 4];
+        io_mgr.mmio_read(MmioAddress(0x2000), &mut data).unwrap();
+
+        // The last range overlaps, so the first one is deregistered again, but not the PIO
+        // range, which isn't registered by the MMIO device.
+        let port = PioRange::new(PioAddress(0x60), 4).unwrap();
+        io_mgr
+            .register_pio(port, Arc::new(DummyDevice::new(3)))
+            .unwrap();
+        let overlapping = [
+            Resource::MmioAddressRange {
+                base: 0x3000,
+                size: 0x100,
+            },
+            Resource::PioAddressRange {
+                base: 0x60,
+                size: 4,
+            },
+            Resource::MmioAddressRange {
+                base: 0x20f0,
+                size: 0x100,
+            },
+        ];
+        let err = io_mgr
+            .register_mmio_evented(Arc::new(DummyDevice::new(2)), &overlapping, &mut events)
+            .unwrap_err();
+        assert_eq!(
+            err,
+            super::Error::Resource {
+                index: 2,
+                error: bus::Error::DeviceOverlap {
+                    base: 0x20f0,
+                    size: 0x100
+                }
+            }
+        );
+        assert!(io_mgr.mmio_read(MmioAddress(0x3000), &mut data).is_err());
+        io_mgr.pio_read(PioAddress(0x60), &mut data).unwrap();
+        assert_eq!(events.devices.len(), 1);
+
+        // Likewise when the event loop rejects the device.
+        events.full = true;
+        let err = io_mgr
+            .register_mmio_evented(
+                Arc::new(DummyDevice::new(2)),
+                &overlapping[..1],
+                &mut events,
+            )
+            .unwrap_err();
+        assert!(matches!(err, super::Error::EventLoop));
+        assert!(io_mgr.mmio_read(MmioAddress(0x3000), &mut data).is_err());
+
+        assert_eq!(io_mgr.deregister_evented(&resources, id, &mut events), 2);
+        assert!(events.devices.is_empty());
+        assert!(io_mgr.mmio_read(MmioAddress(0x1000), &mut data).is_err());
+    }
+
+    #[test]
+    fn test_update_resources() {
+        // Device returning the interrupt it's wired to.
+        struct Rewired(u32);
+
+        impl MutDeviceMmio for Rewired {
+            fn mmio_read(&mut self, _: MmioAddress, _: MmioAddressOffset, data: &mut [u8]) {
+                data.fill(self.0 as u8);
+            }
+
+            fn mmio_write(&mut self, _: MmioAddress, _: MmioAddressOffset, _: &[u8]) {}
+
+            fn update_resources(&mut self, resources: &[Resource]) {
+                for res in resources {
+                    if let Resource::LegacyIrq(irq) = *res {
+                        self.0 = irq;
+                    }
+                }
+            }
+        }
+
+        let range = |base| Resource::MmioAddressRange { base, size: 0x100 };
+        let resources = [range(0x1000), Resource::LegacyIrq(5)];
+        let mut io_mgr = IoManager::new();
+        let device = Arc::new(Mutex::new(Rewired(5)));
+        io_mgr.register_mmio_resources(device, &resources).unwrap();
+        io_mgr
+            .register_mmio(
+                MmioRange::new(MmioAddress(0x3000), 0x100).unwrap(),
+                Arc::new(DummyDevice::new(0)),
+            )
+            .unwrap();
+
+        let moved = [range(0x2000), Resource::LegacyIrq(6)];
+        io_mgr.update_resources(&resources, &moved).unwrap();
+        let mut data = [0; 1];
+        assert!(io_mgr.mmio_read(MmioAddress(0x1000), &mut data).is_err());
+        io_mgr.mmio_read(MmioAddress(0x2000), &mut data).unwrap();
+        assert_eq!(data, [6]);
+
+        // The device keeps its ranges and resources upon failure.
+        let overlapping = [range(0x4000), Resource::LegacyIrq(7), range(0x30f0)];
+        assert_eq!(
+            io_mgr.update_resources(&moved, &overlapping),
+            Err(super::Error::Resource {
+                index: 2,
+                error: bus::Error::DeviceOverlap {
+                    base: 0x30f0,
+                    size: 0x100
+                }
+            })
+        );
+        assert!(io_mgr.mmio_read(MmioAddress(0x4000), &mut data).is_err());
+        io_mgr.mmio_read(MmioAddress(0x2000), &mut data).unwrap();
+        assert_eq!(data, [6]);
+
+        assert_eq!(
+            io_mgr.update_resources(&resources, &moved),
+            Err(super::Error::NoDevice)
+        );
+        // The ranges of other devices are left alone.
+        assert_eq!(
+            io_mgr.update_resources(&[range(0x2000), range(0x3000)], &[range(0x5000)]),
+            Err(super::Error::NoDevice)
+        );
+        io_mgr.mmio_read(MmioAddress(0x3000), &mut data).unwrap();
+        assert_eq!(data, [0]);
+        io_mgr.mmio_read(MmioAddress(0x2000), &mut data).unwrap();
+        assert_eq!(data, [6]);
+        let pio = Resource::PioAddressRange {
+            base: PIO_ADDRESS_BASE,
+            size: PIO_ADDRESS_SIZE,
+        };
+        assert!(matches!(
+            io_mgr.update_resources(&moved, &[range(0x1000), pio]),
+            Err(super::Error::UnsupportedResource { index: 1, .. })
+        ));
+
+        // Rewiring the interrupt only keeps the ranges.
+        let rewired = [Resource::LegacyIrq(8)];
+        io_mgr.update_resources(&moved, &rewired).unwrap();
+        io_mgr.mmio_read(MmioAddress(0x2000), &mut data).unwrap();
+        assert_eq!(data, [8]);
+
+        // Ranges listed in both lists stay registered, the others move.
+        let grown = [range(0x2000), range(0x6000), Resource::LegacyIrq(9)];
+        io_mgr.update_resources(&[range(0x2000)], &grown).unwrap();
+        let partial = [range(0x2000), range(0x7000)];
+        io_mgr
+            .update_resources(&[range(0x2000), range(0x6000)], &partial)
+            .unwrap();
+        assert!(io_mgr.mmio_read(MmioAddress(0x6000), &mut data).is_err());
+        for addr in [0x2000, 0x7000] {
+            io_mgr.mmio_read(MmioAddress(addr), &mut data).unwrap();
+            assert_eq!(data, [9]);
+        }
+    }
+
+    #[test]
+    fn test_replace_device() {
+        let mut io_mgr = IoManager::new();
+        let old = Arc::new(DummyDevice::new(1));
+        let resources = [
+            Resource::MmioAddressRange {
+                base: 0x1000,
+                size: 0x10,
+            },
+            Resource::PioAddressRange {
+                base: 0x60,
+                size: 4,
+            },
+            Resource::MmioAddressRange {
+                base: 0x2000,
+                size: 0x10,
+            },
+        ];
+        io_mgr.register_resources(old.clone(), &resources).unwrap();
+        let other = Arc::new(DummyDevice::new(3));
+        io_mgr
+            .register_mmio(MmioRange::new(MmioAddress(0x3000), 0x10).unwrap(), other)
+            .unwrap();
+
+        let new = Arc::new(DummyDevice::new(2));
+        let replaced = io_mgr
+            .replace_mmio(MmioAddress(0x2008), new.clone())
+            .unwrap();
+        assert!(Arc::ptr_eq(
+            &replaced,
+            &(old.clone() as Arc<dyn DeviceMmio + Send + Sync>)
+        ));
+        let mut data = [0; 1];
+        for (addr, expected) in [(0x1000, 2), (0x2000, 2), (0x3000, 3)] {
+            io_mgr.mmio_read(MmioAddress(addr), &mut data).unwrap();
+            assert_eq!(data, [expected]);
+        }
+        // The PIO bus is left alone.
+        io_mgr.pio_read(PioAddress(0x60), &mut data).unwrap();
+        assert_eq!(data, [1]);
+        io_mgr.replace_pio(PioAddress(0x63), new).unwrap();
+        io_mgr.pio_read(PioAddress(0x60), &mut data).unwrap();
+        assert_eq!(data, [2]);
+
+        assert!(io_mgr
+            .replace_mmio(MmioAddress(0x1010), Arc::new(DummyDevice::new(4)))
+            .is_none());
+    }
+
+    #[test]
+    fn test_registration_guard() {
+        let mut io_mgr = IoManager::new();
+        let range = MmioRange::new(MmioAddress(0x1000), 0x10).unwrap();
+        let mut data = [0; 1];
+        {
+            let mut guard = io_mgr
+                .register_mmio_guarded(range, Arc::new(DummyDevice::new(1)))
+                .unwrap();
+            guard.mmio_read(MmioAddress(0x1000), &mut data).unwrap();
+            assert_eq!(data, [1]);
+            // The guard lends the manager.
+            guard
+                .register_mmio(
+                    MmioRange::new(MmioAddress(0x3000), 0x10).unwrap(),
+                    Arc::new(DummyDevice::new(3)),
+                )
+                .unwrap();
+        }
+        assert!(io_mgr.mmio_read(MmioAddress(0x1000), &mut data).is_err());
+        io_mgr.mmio_read(MmioAddress(0x3000), &mut data).unwrap();
+
+        let resources = [
+            Resource::MmioAddressRange {
+                base: 0x1000,
+                size: 0x10,
+            },
+            Resource::MmioAddressRange {
+                base: 0x2000,
+                size: 0x10,
+            },
+        ];
+        let mut released = Vec::new();
+        io_mgr
+            .register_mmio_resources_guarded(Arc::new(DummyDevice::new(2)), &resources)
+            .unwrap()
+            .with_release(|resources| released.extend_from_slice(resources));
+        assert_eq!(released, resources);
+        assert!(io_mgr.mmio_read(MmioAddress(0x2000), &mut data).is_err());
+
+        io_mgr
+            .register_mmio_resources_guarded(Arc::new(DummyDevice::new(2)), &resources)
+            .unwrap()
+            .keep();
+        io_mgr.mmio_read(MmioAddress(0x2000), &mut data).unwrap();
+        assert_eq!(data, [2]);
+
+        // A failed registration doesn't leave the ranges registered so far behind.
+        let overlapping = [
+            Resource::MmioAddressRange {
+                base: 0x4000,
+                size: 0x10,
+            },
+            Resource::MmioAddressRange {
+                base: 0x3008,
+                size: 0x10,
+            },
+        ];
+        assert!(io_mgr
+            .register_mmio_resources_guarded(Arc::new(DummyDevice::new(4)), &overlapping)
+            .is_err());
+        assert!(io_mgr.mmio_read(MmioAddress(0x4000), &mut data).is_err());
+
+        // The PIO ranges among the resources aren't registered by the guard, so they're
+        // left to the device registered with them.
+        let port = PioRange::new(PioAddress(0x60), 4).unwrap();
+        io_mgr
+            .register_pio(port, Arc::new(DummyDevice::new(5)))
+            .unwrap();
+        let resources = [
+            Resource::MmioAddressRange {
+                base: 0x5000,
+                size: 0x10,
+            },
+            Resource::PioAddressRange {
+                base: 0x60,
+                size: 4,
+            },
+        ];
+        let guard = io_mgr
+            .register_mmio_resources_guarded(Arc::new(DummyDevice::new(6)), &resources)
+            .unwrap();
+        assert_eq!(guard.resources(), resources);
+        drop(guard);
+        assert!(io_mgr.mmio_read(MmioAddress(0x5000), &mut data).is_err());
+        io_mgr.pio_read(PioAddress(0x60), &mut data).unwrap();
+        assert_eq!(data, [5]);
+    }
+
+    #[test]
+    fn test_register_mmio_dma() {
+        use crate::dma::tests::Ram;
+
+        // Device writing the accessed offset to guest memory at the address it's given.
+        struct Dma(Arc<DmaMemory>);
+
+        impl DeviceMmio for Dma {
+            fn mmio_read(&self, _base: MmioAddress, _offset: MmioAddressOffset, _data: &mut [u8]) {}
+
+            fn mmio_write(&self, _base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+                let addr = u64::from(data[0]);
+                self.0.write_value(addr, offset as u8).unwrap();
+            }
+        }
+
+        let mut io_mgr = IoManager::new();
+        let range = MmioRange::new(MmioAddress(0x1000), 0x100).unwrap();
+        let err = io_mgr
+            .register_mmio_dma(range, |memory| Arc::new(Dma(memory)))
+            .unwrap_err();
+        assert!(matches!(err, super::Error::NoDmaMemory));
+
+        let memory = Arc::new(DmaMemory::new(Ram(Mutex::new(vec![0; 0x10]))));
+        io_mgr.set_dma_memory(memory.clone());
+        io_mgr
+            .register_mmio_dma(range, |memory| Arc::new(Dma(memory)))
+            .unwrap();
+        io_mgr.mmio_write(MmioAddress(0x1042), &[3]).unwrap();
+        assert_eq!(memory.read_value::<u8>(3), Ok(0x42));
+
+        io_mgr
+            .set_dma_translation(|iova, _, _| Some(iova ^ 0x8))
+            .unwrap();
+        let range = MmioRange::new(MmioAddress(0x2000), 0x100).unwrap();
+        io_mgr
+            .register_mmio_dma(range, |memory| Arc::new(Dma(memory)))
+            .unwrap();
+        io_mgr.mmio_write(MmioAddress(0x2043), &[0x4]).unwrap();
+        io_mgr.mmio_write(MmioAddress(0x1044), &[0x4]).unwrap();
+        assert_eq!(memory.read_value::<u8>(4), Ok(0x44));
+        assert_eq!(memory.read_value::<u8>(0xc), Ok(0x43));
+    }
+
+    #[test]
+    fn test_introspect() {
+        struct Introspectable;
+
+        impl DeviceMmio for Introspectable {
+            fn mmio_read(&self, _base: MmioAddress, _offset: MmioAddressOffset, _data: &mut [u8]) {}
+            fn mmio_write(&self, _base: MmioAddress, _offset: MmioAddressOffset, _data: &[u8]) {}
+
+            fn introspect(&self) -> Vec<(String, String)> {
+                vec![
+                    ("name".to_string(), "serial \"0\"".to_string()),
+                    ("status".to_string(), "0x0f\n".to_string()),
+                ]
+            }
+        }
+
+        let mut io_mgr = IoManager::new();
+        assert_eq!(io_mgr.introspect(), r#"{"pio":[],"mmio":[]}"#);
+
+        let dum = Arc::new(DummyDevice::new(CONFIG_DATA));
+        let resources = [
+            Resource::PioAddressRange {
+                base: PIO_ADDRESS_BASE,
+                size: PIO_ADDRESS_SIZE,
+            },
+            Resource::MmioAddressRange {
+                base: 0x1000,
+                size: 0x10,
+            },
+            Resource::MmioAddressRange {
+                base: 0x3000,
+                size: 0x10,
+            },
+        ];
+        io_mgr.register_resources(dum, &resources).unwrap();
+        let range = MmioRange::new(MmioAddress(0x2000), 0x10).unwrap();
+        io_mgr
+            .register_mmio(range, Arc::new(Introspectable))
+            .unwrap();
+
+        assert_eq!(
+            io_mgr.introspect(),
+            concat!(
+                r#"{"pio":[{"ranges":[{"base":64,"size":4}],"properties":{}}],"#,
+                r#""mmio":[{"ranges":[{"base":4096,"size":16},{"base":12288,"size":16}],"#,
+                r#""properties":{}},{"ranges":[{"base":8192,"size":16}],"#,
+                r#""properties":{"name":"serial \"0\"","status":"0x0f\n"}}]}"#
+            )
+        );
+
+        let mut json = String::new();
+        write_json_string(&mut json, "\u{1}\\\t\r");
+        assert_eq!(json, r#""\u0001\\\t\r""#);
+    }
+
+    #[test]
+    fn test_error_code() {
+        let err = super::Error::Bus(bus::Error::DeviceOverlap {
+            base: 0x1000,
+            size: 0x100,
+        });
         assert!(err.source().is_some());
-        assert_eq!(format!("{}", err), "device_manager: bus error");
+        assert_eq!(
+            format!("{}", err),
+            "device_manager: range at 0x1000 of size 0x100 overlaps with existing device"
+        );
+
+        let err = super::Error::Resource {
+            index: 2,
+            error: bus::Error::InvalidRange {
+                base: 0x60,
+                size: 0,
+            },
+        };
+        assert!(err.source().is_some());
+        assert_eq!(
+            format!("{}", err),
+            "device_manager: resource 2: invalid range provided (base 0x60, size 0x0)"
+        );
+
+        let err = super::Error::ResourceUnavailable {
+            index: 0,
+            resource: Resource::LegacyIrq(5),
+        };
+        assert!(err.source().is_none());
+        assert_eq!(
+            format!("{}", err),
+            "device_manager: resource 0 not available: LegacyIrq(5)"
+        );
+
+        let err = super::Error::NoDmaMemory;
+        assert!(err.source().is_none());
+        assert_eq!(format!("{}", err), "device_manager: no DMA memory");
+
+        let err = super::Error::UnsupportedResource {
+            index: 1,
+            resource: Resource::LegacyIrq(5),
+        };
+        assert!(err.source().is_none());
+        assert_eq!(
+            format!("{}", err),
+            "device_manager: unsupported resource 1: LegacyIrq(5)"
+        );
+
+        let err = super::Error::NoDevice;
+        assert!(err.source().is_none());
+        assert_eq!(
+            format!("{}", err),
+            "device_manager: no device with these resources"
+        );
+
+        let overlap = || super::Error::Resource {
+            index: 0,
+            error: bus::Error::DeviceOverlap {
+                base: 0x1000,
+                size: 0x100,
+            },
+        };
+        let err = super::Error::Rollback {
+            error: Box::new(overlap()),
+            rollback: Box::new(overlap()),
+        };
+        assert_eq!(err.source().unwrap().to_string(), overlap().to_string());
+        assert_eq!(
+            format!("{}", err),
+            "device_manager: device left unregistered by a failed update"
+        );
+
+        let err = super::Error::EventLoop;
+        assert!(err.source().is_none());
+        assert_eq!(
+            format!("{}", err),
+            "device_manager: event loop rejected the device"
+        );
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::*;
+
+    use loom::sync::atomic::{AtomicBool, Ordering};
+    use loom::thread;
+
+    use bus::MmioAddressOffset;
+
+    struct Flag(loom::sync::Arc<AtomicBool>);
+
+    impl DeviceMmio for Flag {
+        fn mmio_read(&self, _base: MmioAddress, _offset: MmioAddressOffset, _data: &mut [u8]) {}
+
+        fn mmio_write(&self, _base: MmioAddress, _offset: MmioAddressOffset, _data: &[u8]) {
+            self.0.store(true, Ordering::Relaxed);
+            self.0.store(false, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn loom_quiesce_waits_for_dispatch() {
+        loom::model(|| {
+            let in_flight = loom::sync::Arc::new(AtomicBool::new(false));
+            let mut manager = IoManager::new();
+            let range = MmioRange::new(MmioAddress(0x1000), 0x10).unwrap();
+            manager
+                .register_mmio(range, Arc::new(Flag(in_flight.clone())))
+                .unwrap();
+            let manager = loom::sync::Arc::new(manager);
+
+            let dispatcher = {
+                let manager = manager.clone();
+                thread::spawn(move || manager.mmio_write(MmioAddress(0x1004), &[1]).unwrap())
+            };
+
+            {
+                let _quiesced = manager.quiesce();
+                assert!(!in_flight.load(Ordering::Relaxed));
+            }
+            dispatcher.join().unwrap();
+        });
     }
 }
@@ -0,0 +1,608 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Validated device accesses to guest memory.
+//!
+//! Devices doing DMA (e.g. walking virtqueues) all need the same checks before touching
+//! guest memory: the access must not wrap around the address space, must stay within the
+//! memory the device may use, and structures read from the guest must be decoded in little
+//! endian. [`DmaMemory`] performs these checks on top of a [`Memory`] backend, and can be set
+//! on an [`IoManager`](crate::device_manager::IoManager) to be handed to the devices it
+//! registers.
+//!
+//! With [`vm-memory`](https://github.com/rust-vmm/vm-memory), the backend maps onto the
+//! `Bytes` implementation of the guest memory:
+//!
+//! ```ignore
+//! struct GuestRam(GuestMemoryMmap);
+//!
+//! impl Memory for GuestRam {
+//!     fn read(&self, addr: u64, data: &mut [u8]) -> bool {
+//!         self.0.read_slice(data, GuestAddress(addr)).is_ok()
+//!     }
+//!
+//!     fn write(&self, addr: u64, data: &[u8]) -> bool {
+//!         self.0.write_slice(data, GuestAddress(addr)).is_ok()
+//!     }
+//! }
+//! ```
+//!
+//! Deployments where the guest memory isn't mapped into the VMM upfront, such as Xen guests
+//! granting access to their pages, implement a [`Mapper`] instead, and create the checked
+//! memory with [`DmaMemory::mapped`]. Every access is then performed through mappings
+//! created for its duration:
+//!
+//! ```ignore
+//! impl Mapper for GrantMapper {
+//!     type Mapping = GrantMapping;
+//!
+//!     fn map(&self, addr: u64, len: usize, direction: Direction) -> Option<GrantMapping> {
+//!         // Map the page granted with the reference encoded in `addr`, read-only for
+//!         // `Direction::Read`, and stop the mapping at the end of that page.
+//!         self.gnttab.map_grant_ref(self.domid, grant_ref(addr), direction).ok()?.at(addr, len)
+//!     }
+//! }
+//! ```
+
+use std::convert::TryInto;
+use std::fmt::{Display, Formatter};
+use std::ops::Range;
+use std::sync::Arc;
+
+/// Guest memory accessed by devices.
+pub trait Memory: Send + Sync {
+    /// Fill `data` with the guest memory starting at `addr`, returning `false` if part of
+    /// it isn't backed.
+    fn read(&self, addr: u64, data: &mut [u8]) -> bool;
+
+    /// Write `data` to the guest memory starting at `addr`, returning `false` if part of it
+    /// isn't backed.
+    fn write(&self, addr: u64, data: &[u8]) -> bool;
+}
+
+/// Maps guest memory into the VMM for the duration of an access, e.g. with Xen grant
+/// mappings.
+pub trait Mapper: Send + Sync {
+    /// Guest memory mapped by [`Mapper::map`], unmapped when dropped.
+    type Mapping: Mapping;
+
+    /// Map the guest memory starting at `addr` for a `direction` access of `len` bytes,
+    /// returning `None` if it can't be mapped.
+    ///
+    /// The mapping may be shorter than `len`, e.g. stop at the end of a granted page, in
+    /// which case the rest of the access is mapped separately, but it must not be empty.
+    fn map(&self, addr: u64, len: usize, direction: Direction) -> Option<Self::Mapping>;
+}
+
+/// Guest memory mapped by a [`Mapper`], starting at the address passed to [`Mapper::map`].
+pub trait Mapping {
+    /// Return the number of mapped bytes.
+    fn size(&self) -> usize;
+
+    /// Fill `data` with the mapped memory, from its start. `data` is never longer than the
+    /// mapping.
+    fn read(&self, data: &mut [u8]);
+
+    /// Write `data` to the mapped memory, from its start. `data` is never longer than the
+    /// mapping.
+    fn write(&mut self, data: &[u8]);
+}
+
+// Memory backend accessing guest memory through the mappings created by a `Mapper`.
+struct Mapped<P>(P);
+
+impl<P: Mapper> Mapped<P> {
+    // Map the `len` bytes at `addr` piece by piece, and pass each mapping to `f` with the
+    // range it covers in the access. Returns `false` if part of the access can't be mapped,
+    // in which case the pieces before it were already accessed.
+    fn each<F>(&self, addr: u64, len: usize, direction: Direction, mut f: F) -> bool
+    where
+        F: FnMut(&mut P::Mapping, Range<usize>),
+    {
+        let mut done = 0;
+        while done < len {
+            let mut mapping = match self.0.map(addr + done as u64, len - done, direction) {
+                Some(mapping) if mapping.size() > 0 => mapping,
+                _ => return false,
+            };
+            let size = mapping.size().min(len - done);
+            f(&mut mapping, done..done + size);
+            done += size;
+        }
+        true
+    }
+}
+
+impl<P: Mapper> Memory for Mapped<P> {
+    fn read(&self, addr: u64, data: &mut [u8]) -> bool {
+        self.each(addr, data.len(), Direction::Read, |mapping, range| {
+            mapping.read(&mut data[range])
+        })
+    }
+
+    fn write(&self, addr: u64, data: &[u8]) -> bool {
+        self.each(addr, data.len(), Direction::Write, |mapping, range| {
+            mapping.write(&data[range])
+        })
+    }
+}
+
+/// Direction of a DMA access, from the point of view of the device.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Direction {
+    /// The device reads guest memory.
+    Read,
+    /// The device writes guest memory.
+    Write,
+}
+
+/// Errors encountered while accessing guest memory.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The access wraps around the end of the address space.
+    Overflow,
+    /// The access is longer than allowed.
+    TooLong(usize),
+    /// The permission hook denied the access.
+    Denied(u64, usize, Direction),
+    /// Part of the accessed memory isn't backed.
+    Unbacked(u64, usize),
+    /// The translation hook couldn't translate the access.
+    Translation(u64, usize, Direction),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Overflow => write!(f, "dma: access overflows the address space"),
+            Error::TooLong(len) => write!(f, "dma: access too long ({})", len),
+            Error::Denied(addr, len, direction) => write!(
+                f,
+                "dma: {:?} access of {} bytes at {:#x} denied",
+                direction, len, addr
+            ),
+            Error::Unbacked(addr, len) => {
+                write!(f, "dma: {} bytes at {:#x} are not backed", len, addr)
+            }
+            Error::Translation(iova, len, direction) => write!(
+                f,
+                "dma: {:?} access of {} bytes at IOVA {:#x} not translated",
+                direction, len, iova
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Values devices read from and write to guest memory, in little endian.
+pub trait DmaValue: Sized {
+    /// Size of the value in guest memory, which is at most 8 bytes.
+    const SIZE: usize;
+
+    /// Decode a value from `SIZE` bytes.
+    fn from_le(bytes: &[u8]) -> Self;
+
+    /// Encode the value into `SIZE` bytes.
+    fn to_le(&self, bytes: &mut [u8]);
+}
+
+macro_rules! dma_value {
+    ($($ty:ty),*) => {
+        $(
+            impl DmaValue for $ty {
+                const SIZE: usize = std::mem::size_of::<$ty>();
+
+                fn from_le(bytes: &[u8]) -> Self {
+                    <$ty>::from_le_bytes(bytes.try_into().unwrap())
+                }
+
+                fn to_le(&self, bytes: &mut [u8]) {
+                    bytes.copy_from_slice(&self.to_le_bytes());
+                }
+            }
+        )*
+    };
+}
+
+dma_value!(u8, u16, u32, u64);
+
+type Permission = Arc<dyn Fn(u64, usize, Direction) -> bool + Send + Sync>;
+type Translation = Arc<dyn Fn(u64, usize, Direction) -> Option<u64> + Send + Sync>;
+type Fault = Arc<dyn Fn(&Error) + Send + Sync>;
+
+/// Checked access to guest memory for devices.
+///
+/// Every access is checked for overflow and against the maximum access length, translated
+/// by the translation hook, if any, then submitted to the permission hook, if any, before
+/// reaching the [`Memory`] backend. Clones share the backend and the hooks.
+#[derive(Clone)]
+pub struct DmaMemory {
+    memory: Arc<dyn Memory>,
+    max_len: usize,
+    permission: Option<Permission>,
+    translation: Option<Translation>,
+    fault: Option<Fault>,
+}
+
+impl DmaMemory {
+    /// Check the accesses to `memory`, allowing any length.
+    pub fn new<M: Memory + 'static>(memory: M) -> Self {
+        DmaMemory {
+            memory: Arc::new(memory),
+            max_len: usize::MAX,
+            permission: None,
+            translation: None,
+            fault: None,
+        }
+    }
+
+    /// Check the accesses to the guest memory mapped by `mapper`, allowing any length.
+    ///
+    /// Each access is performed through mappings created for its duration, and is reported
+    /// as [`Error::Unbacked`] if part of it can't be mapped.
+    pub fn mapped<P: Mapper + 'static>(mapper: P) -> Self {
+        DmaMemory::new(Mapped(mapper))
+    }
+
+    /// Reject accesses longer than `max_len` bytes, e.g. to bound the buffers a guest can
+    /// make a device process at once.
+    pub fn with_max_len(mut self, max_len: usize) -> Self {
+        self.max_len = max_len;
+        self
+    }
+
+    /// Submit every access to `permission`, which receives the guest address, the length
+    /// and the direction of the access, and returns whether the access is allowed.
+    pub fn with_permission<F>(mut self, permission: F) -> Self
+    where
+        F: Fn(u64, usize, Direction) -> bool + Send + Sync + 'static,
+    {
+        self.permission = Some(Arc::new(permission));
+        self
+    }
+
+    /// Translate the addresses devices access with `translation`, e.g. from the I/O virtual
+    /// addresses programmed by the guest into a virtio-iommu to guest physical addresses.
+    ///
+    /// The hook receives the address, the length and the direction of the access, and
+    /// returns the guest physical address the whole access maps to contiguously, or `None`
+    /// if it can't be translated. Accesses crossing translation boundaries must be split by
+    /// the device.
+    pub fn with_translation<F>(mut self, translation: F) -> Self
+    where
+        F: Fn(u64, usize, Direction) -> Option<u64> + Send + Sync + 'static,
+    {
+        self.translation = Some(Arc::new(translation));
+        self
+    }
+
+    /// Report every failed access to `fault`, before the error is returned to the device,
+    /// e.g. to record translation faults in a virtio-iommu event queue.
+    pub fn with_fault_handler<F>(mut self, fault: F) -> Self
+    where
+        F: Fn(&Error) + Send + Sync + 'static,
+    {
+        self.fault = Some(Arc::new(fault));
+        self
+    }
+
+    // Validate an access, and return the guest physical address it targets.
+    fn check(&self, addr: u64, len: usize, direction: Direction) -> Result<u64, Error> {
+        if len > self.max_len {
+            return Err(Error::TooLong(len));
+        }
+        let overflows = |addr: u64| len > 0 && addr.checked_add(len as u64 - 1).is_none();
+        if overflows(addr) {
+            return Err(Error::Overflow);
+        }
+        let gpa = match &self.translation {
+            Some(translation) => {
+                translation(addr, len, direction).ok_or(Error::Translation(addr, len, direction))?
+            }
+            None => addr,
+        };
+        if overflows(gpa) {
+            return Err(Error::Overflow);
+        }
+        match &self.permission {
+            Some(permission) if !permission(gpa, len, direction) => {
+                Err(Error::Denied(gpa, len, direction))
+            }
+            _ => Ok(gpa),
+        }
+    }
+
+    // Report `result` to the fault handler if it's an error.
+    fn report<T>(&self, result: Result<T, Error>) -> Result<T, Error> {
+        if let (Err(e), Some(fault)) = (&result, &self.fault) {
+            fault(e);
+        }
+        result
+    }
+
+    fn access<F>(&self, addr: u64, len: usize, direction: Direction, f: F) -> Result<(), Error>
+    where
+        F: FnOnce(&dyn Memory, u64) -> bool,
+    {
+        let result = self.check(addr, len, direction).and_then(|gpa| {
+            if f(&*self.memory, gpa) {
+                Ok(())
+            } else {
+                Err(Error::Unbacked(gpa, len))
+            }
+        });
+        self.report(result)
+    }
+
+    /// Fill `data` with the guest memory starting at `addr`.
+    pub fn read(&self, addr: u64, data: &mut [u8]) -> Result<(), Error> {
+        self.access(addr, data.len(), Direction::Read, |memory, gpa| {
+            memory.read(gpa, data)
+        })
+    }
+
+    /// Write `data` to the guest memory starting at `addr`.
+    pub fn write(&self, addr: u64, data: &[u8]) -> Result<(), Error> {
+        self.access(addr, data.len(), Direction::Write, |memory, gpa| {
+            memory.write(gpa, data)
+        })
+    }
+
+    /// Read a little endian value at `addr`.
+    pub fn read_value<T: DmaValue>(&self, addr: u64) -> Result<T, Error> {
+        let mut bytes = [0; 8];
+        let bytes = &mut bytes[..T::SIZE];
+        self.read(addr, bytes)?;
+        Ok(T::from_le(bytes))
+    }
+
+    /// Write `value` at `addr`, in little endian.
+    pub fn write_value<T: DmaValue>(&self, addr: u64, value: T) -> Result<(), Error> {
+        let mut bytes = [0; 8];
+        let bytes = &mut bytes[..T::SIZE];
+        value.to_le(bytes);
+        self.write(addr, bytes)
+    }
+
+    /// Copy `len` bytes of guest memory at `addr` into a bounce buffer.
+    ///
+    /// The device works on the copy, which can't change under its feet while it's being
+    /// validated, and writes it back with [`DmaMemory::commit`] if needed.
+    pub fn bounce(&self, addr: u64, len: usize) -> Result<BounceBuffer, Error> {
+        // Check before allocating, so the guest can't make the device allocate arbitrarily
+        // large buffers.
+        let gpa = self.report(self.check(addr, len, Direction::Read))?;
+        let mut data = vec![0; len];
+        if !self.memory.read(gpa, &mut data) {
+            return self.report(Err(Error::Unbacked(gpa, len)));
+        }
+        Ok(BounceBuffer { addr, data })
+    }
+
+    /// Write the content of `buffer` back to the guest memory it was copied from.
+    pub fn commit(&self, buffer: &BounceBuffer) -> Result<(), Error> {
+        self.write(buffer.addr, &buffer.data)
+    }
+}
+
+/// A copy of guest memory, obtained with [`DmaMemory::bounce`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BounceBuffer {
+    addr: u64,
+    data: Vec<u8>,
+}
+
+impl BounceBuffer {
+    /// Return the guest address the buffer was copied from.
+    pub fn addr(&self) -> u64 {
+        self.addr
+    }
+
+    /// Return the content of the buffer.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Return the content of the buffer, for modifying it before committing it.
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    // Guest memory backed by a vector, starting at address 0.
+    pub(crate) struct Ram(pub Mutex<Vec<u8>>);
+
+    impl Memory for Ram {
+        fn read(&self, addr: u64, data: &mut [u8]) -> bool {
+            let ram = self.0.lock().unwrap();
+            let start = addr as usize;
+            match ram.get(start..start + data.len()) {
+                Some(bytes) => {
+                    data.copy_from_slice(bytes);
+                    true
+                }
+                None => false,
+            }
+        }
+
+        fn write(&self, addr: u64, data: &[u8]) -> bool {
+            let mut ram = self.0.lock().unwrap();
+            let start = addr as usize;
+            match ram.get_mut(start..start + data.len()) {
+                Some(bytes) => {
+                    bytes.copy_from_slice(data);
+                    true
+                }
+                None => false,
+            }
+        }
+    }
+
+    #[test]
+    fn test_dma_memory() {
+        let memory = DmaMemory::new(Ram(Mutex::new(vec![0; 0x100])))
+            .with_max_len(0x40)
+            .with_permission(|addr, _, direction| addr < 0x80 || direction == Direction::Read);
+
+        memory.write_value(0x10, 0x1234_5678u32).unwrap();
+        memory.write_value(0x14, 0xabu8).unwrap();
+        assert_eq!(memory.read_value::<u16>(0x12), Ok(0x1234));
+        assert_eq!(memory.read_value::<u64>(0x10), Ok(0xab_1234_5678));
+
+        assert_eq!(
+            memory.write_value(0x80, 1u8),
+            Err(Error::Denied(0x80, 1, Direction::Write))
+        );
+        assert_eq!(
+            memory.read_value::<u32>(0xfe),
+            Err(Error::Unbacked(0xfe, 4))
+        );
+        assert_eq!(memory.read_value::<u16>(u64::MAX), Err(Error::Overflow));
+        assert_eq!(memory.bounce(0, 0x41), Err(Error::TooLong(0x41)));
+
+        let mut buffer = memory.bounce(0x10, 8).unwrap();
+        assert_eq!(buffer.data(), [0x78, 0x56, 0x34, 0x12, 0xab, 0, 0, 0]);
+        buffer.data_mut()[7] = 0xcd;
+        // The guest changing the memory doesn't affect the bounce buffer.
+        memory.write_value(0x10, 0u32).unwrap();
+        assert_eq!(buffer.data()[0], 0x78);
+        memory.commit(&buffer).unwrap();
+        assert_eq!(memory.read_value::<u64>(0x10), Ok(0xcd00_00ab_1234_5678));
+    }
+
+    // Guest memory made of 0x10 byte pages, which can only be accessed through mappings
+    // stopping at the end of the page. The first page is read-only, the last isn't granted.
+    struct Grants {
+        memory: Arc<Mutex<Vec<u8>>>,
+        maps: Arc<Mutex<(usize, usize)>>,
+    }
+
+    struct Grant {
+        memory: Arc<Mutex<Vec<u8>>>,
+        maps: Arc<Mutex<(usize, usize)>>,
+        start: usize,
+        size: usize,
+    }
+
+    impl Mapper for Grants {
+        type Mapping = Grant;
+
+        fn map(&self, addr: u64, len: usize, direction: Direction) -> Option<Grant> {
+            let page = addr as usize / 0x10;
+            if page >= 3 || (page == 0 && direction == Direction::Write) {
+                return None;
+            }
+            let mut maps = self.maps.lock().unwrap();
+            maps.0 += 1;
+            maps.1 += 1;
+            Some(Grant {
+                memory: self.memory.clone(),
+                maps: self.maps.clone(),
+                start: addr as usize,
+                size: len.min((page + 1) * 0x10 - addr as usize),
+            })
+        }
+    }
+
+    impl Mapping for Grant {
+        fn size(&self) -> usize {
+            self.size
+        }
+
+        fn read(&self, data: &mut [u8]) {
+            let memory = self.memory.lock().unwrap();
+            data.copy_from_slice(&memory[self.start..self.start + data.len()]);
+        }
+
+        fn write(&mut self, data: &[u8]) {
+            let mut memory = self.memory.lock().unwrap();
+            memory[self.start..self.start + data.len()].copy_from_slice(data);
+        }
+    }
+
+    impl Drop for Grant {
+        fn drop(&mut self) {
+            self.maps.lock().unwrap().0 -= 1;
+        }
+    }
+
+    #[test]
+    fn test_dma_mapped() {
+        let memory = Arc::new(Mutex::new((0..0x40).collect::<Vec<u8>>()));
+        // Mappings alive, mappings created.
+        let maps = Arc::new(Mutex::new((0, 0)));
+        let dma = DmaMemory::mapped(Grants {
+            memory: memory.clone(),
+            maps: maps.clone(),
+        });
+
+        // Accesses crossing pages go through one mapping per page, released once done.
+        assert_eq!(dma.read_value::<u32>(0x0e), Ok(0x1110_0f0e));
+        assert_eq!(*maps.lock().unwrap(), (0, 2));
+        let mut buffer = dma.bounce(0x08, 0x20).unwrap();
+        assert_eq!(buffer.data()[..2], [0x08, 0x09]);
+        assert_eq!(*maps.lock().unwrap(), (0, 5));
+        buffer.data_mut().fill(0);
+        assert_eq!(dma.commit(&buffer), Err(Error::Unbacked(0x08, 0x20)));
+
+        dma.write(0x1e, &[0xaa; 4]).unwrap();
+        assert_eq!(
+            memory.lock().unwrap()[0x1d..0x23],
+            [0x1d, 0xaa, 0xaa, 0xaa, 0xaa, 0x22]
+        );
+        assert_eq!(dma.read_value::<u16>(0x2f), Err(Error::Unbacked(0x2f, 2)));
+        assert_eq!(dma.read(0x10, &mut []), Ok(()));
+        assert_eq!(maps.lock().unwrap().0, 0);
+    }
+
+    #[test]
+    fn test_dma_translation() {
+        let ram = DmaMemory::new(Ram(Mutex::new(vec![0; 0x100])))
+            .with_permission(|gpa, _, _| gpa >= 0x40);
+        let faults = Arc::new(Mutex::new(Vec::new()));
+        let recorded = faults.clone();
+        // A single mapping of IOVA 0x1000-0x103f to 0x80-0xbf.
+        let iommu = ram
+            .clone()
+            .with_translation(|iova, len, _| {
+                (iova >= 0x1000 && iova + len as u64 <= 0x1040).then(|| iova - 0x1000 + 0x80)
+            })
+            .with_fault_handler(move |e| recorded.lock().unwrap().push(format!("{}", e)));
+
+        iommu.write_value(0x1008, 0xdead_beefu32).unwrap();
+        assert_eq!(ram.read_value::<u32>(0x88), Ok(0xdead_beef));
+        assert_eq!(iommu.bounce(0x1008, 2).unwrap().data(), [0xef, 0xbe]);
+
+        assert_eq!(
+            iommu.read_value::<u64>(0x103c),
+            Err(Error::Translation(0x103c, 8, Direction::Read))
+        );
+        assert_eq!(
+            iommu.bounce(0x2000, 4),
+            Err(Error::Translation(0x2000, 4, Direction::Read))
+        );
+        // The permission hook sees guest physical addresses.
+        let denied = iommu
+            .clone()
+            .with_translation(|iova, _, _| Some(iova - 0x1000));
+        assert_eq!(
+            denied.write_value(0x1000, 0u8),
+            Err(Error::Denied(0, 1, Direction::Write))
+        );
+        assert_eq!(
+            *faults.lock().unwrap(),
+            vec![
+                "dma: Read access of 8 bytes at IOVA 0x103c not translated",
+                "dma: Read access of 4 bytes at IOVA 0x2000 not translated",
+                "dma: Write access of 1 bytes at 0x0 denied",
+            ]
+        );
+    }
+}
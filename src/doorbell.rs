@@ -0,0 +1,402 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Page-aligned doorbell ranges.
+//!
+//! Queue notifications are the hottest accesses of a paravirtualized device, so VMMs route
+//! them to an ioeventfd or let a user-space poller watch them, rather than emulating them
+//! like the other registers. Both work best when the doorbells sit on pages of their own,
+//! away from the other registers of the device: the page can then be protected or mapped
+//! independently. A [`DoorbellWindow`] carves such pages out of a window of the guest
+//! physical address space, either one page per device, where the guest writes the index
+//! of the notified queue, or one page per queue. [`IoManager::register_doorbells`]
+//! registers the pages of a device on the bus at once, and the returned [`Doorbells`]
+//! report the address of each doorbell to set up the ioeventfds or the poller.
+//!
+//! ```
+//! # use std::sync::Arc;
+//! # use vm_device::bus::{MmioAddress, MmioAddressOffset};
+//! use vm_device::device_manager::IoManager;
+//! use vm_device::doorbell::{DoorbellWindow, Granularity};
+//! # use vm_device::DeviceMmio;
+//! # struct Net;
+//! # impl DeviceMmio for Net {
+//! #     fn mmio_read(&self, _: MmioAddress, _: MmioAddressOffset, _: &mut [u8]) {}
+//! #     fn mmio_write(&self, _: MmioAddress, _: MmioAddressOffset, _: &[u8]) {}
+//! # }
+//!
+//! let mut window = DoorbellWindow::new(0xd000_0000, 0x10_0000, 0x1000).unwrap();
+//! let mut manager = IoManager::new();
+//! let doorbells = manager
+//!     .register_doorbells(&mut window, Arc::new(Net), 2, Granularity::PerQueue)
+//!     .unwrap();
+//! let addresses: Vec<u64> = doorbells.doorbells().iter().map(|d| d.addr).collect();
+//! assert_eq!(addresses, [0xd000_0000, 0xd000_1000]);
+//! assert_eq!(doorbells.queue(0x1000), Some(1));
+//! ```
+
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
+use crate::bus::{self, MmioAddress, MmioRange};
+use crate::device_manager::{IoManager, MmioManager};
+use crate::DeviceMmio;
+
+/// Errors encountered while allocating doorbells.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The page size isn't a power of two.
+    InvalidPageSize(u64),
+    /// The window at the base address with the size isn't made of whole pages, or
+    /// overflows.
+    InvalidWindow {
+        /// Base address of the window.
+        base: u64,
+        /// Size of the window.
+        size: u64,
+    },
+    /// Doorbells were requested for no queues.
+    NoQueues,
+    /// The window doesn't have the number of contiguous free pages left.
+    Exhausted(u64),
+    /// The doorbells at the address weren't allocated from the window.
+    Foreign(u64),
+    /// The doorbells couldn't be registered on the bus.
+    Bus(bus::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::InvalidPageSize(size) => write!(f, "doorbell: invalid page size {:#x}", size),
+            Error::InvalidWindow { base, size } => {
+                write!(f, "doorbell: invalid window {:#x}+{:#x}", base, size)
+            }
+            Error::NoQueues => write!(f, "doorbell: no queues"),
+            Error::Exhausted(pages) => write!(f, "doorbell: no {} free pages left", pages),
+            Error::Foreign(addr) => {
+                write!(f, "doorbell: doorbells at {:#x} not from this window", addr)
+            }
+            Error::Bus(e) => write!(f, "doorbell: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Bus(e) => Some(e),
+            Error::InvalidPageSize(_)
+            | Error::InvalidWindow { .. }
+            | Error::NoQueues
+            | Error::Exhausted(_)
+            | Error::Foreign(_) => None,
+        }
+    }
+}
+
+/// How the doorbells of a device are laid out.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Granularity {
+    /// A single page, at the start of which the guest writes the index of the notified
+    /// queue, like the `QueueNotify` register of virtio-mmio.
+    PerDevice,
+    /// A page per queue, at the start of which the guest writes to notify the queue.
+    PerQueue,
+}
+
+/// The doorbell of a queue.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Doorbell {
+    /// Index of the queue.
+    pub queue: u16,
+    /// Guest physical address the guest writes to.
+    pub addr: u64,
+    /// Value written by the guest to notify the queue, if the doorbell is shared with other
+    /// queues, e.g. to match on when registering an ioeventfd.
+    pub datamatch: Option<u32>,
+}
+
+/// The doorbell pages of a device, allocated from a [`DoorbellWindow`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Doorbells {
+    range: MmioRange,
+    page_size: u64,
+    queues: u16,
+    granularity: Granularity,
+}
+
+impl Doorbells {
+    /// Return the range spanning the pages, registered on the bus.
+    pub fn range(&self) -> MmioRange {
+        self.range
+    }
+
+    /// Return the number of queues.
+    pub fn queues(&self) -> u16 {
+        self.queues
+    }
+
+    /// Return how the doorbells are laid out.
+    pub fn granularity(&self) -> Granularity {
+        self.granularity
+    }
+
+    /// Return the doorbell of each queue, by queue index.
+    pub fn doorbells(&self) -> Vec<Doorbell> {
+        let base = self.range.base().0;
+        (0..self.queues)
+            .map(|queue| match self.granularity {
+                Granularity::PerDevice => Doorbell {
+                    queue,
+                    addr: base,
+                    datamatch: Some(u32::from(queue)),
+                },
+                Granularity::PerQueue => Doorbell {
+                    queue,
+                    addr: base + u64::from(queue) * self.page_size,
+                    datamatch: None,
+                },
+            })
+            .collect()
+    }
+
+    /// Return the queue whose page holds `offset`, an offset in the range of the doorbells
+    /// as handed to the device. Always returns `None` for per-device doorbells, where the
+    /// queue is the value written.
+    pub fn queue(&self, offset: u64) -> Option<u16> {
+        match self.granularity {
+            Granularity::PerDevice => None,
+            Granularity::PerQueue if offset < self.range.size() => {
+                Some((offset / self.page_size) as u16)
+            }
+            Granularity::PerQueue => None,
+        }
+    }
+}
+
+impl Display for Doorbells {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for doorbell in self.doorbells() {
+            write!(f, "queue {}: {:#x}", doorbell.queue, doorbell.addr)?;
+            if let Some(datamatch) = doorbell.datamatch {
+                write!(f, " datamatch {}", datamatch)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// A window of the guest physical address space, carved into page-aligned doorbells.
+///
+/// The pages of a device are contiguous, and taken from the lowest free ones.
+#[derive(Clone, Debug)]
+pub struct DoorbellWindow {
+    base: u64,
+    page_size: u64,
+    // Whether each page of the window is allocated.
+    used: Vec<bool>,
+}
+
+impl DoorbellWindow {
+    /// Create a window of `size` bytes at `base`, made of pages of `page_size` bytes.
+    pub fn new(base: u64, size: u64, page_size: u64) -> Result<Self, Error> {
+        if !page_size.is_power_of_two() {
+            return Err(Error::InvalidPageSize(page_size));
+        }
+        let invalid = Error::InvalidWindow { base, size };
+        if size == 0 || !base.is_multiple_of(page_size) || !size.is_multiple_of(page_size) {
+            return Err(invalid);
+        }
+        base.checked_add(size - 1).ok_or(invalid)?;
+        Ok(DoorbellWindow {
+            base,
+            page_size,
+            used: vec![false; (size / page_size) as usize],
+        })
+    }
+
+    /// Return the page size.
+    pub fn page_size(&self) -> u64 {
+        self.page_size
+    }
+
+    /// Return the number of free pages.
+    pub fn free_pages(&self) -> usize {
+        self.used.iter().filter(|used| !**used).count()
+    }
+
+    /// Allocate the doorbells of a device with `queues` queues.
+    pub fn allocate(&mut self, queues: u16, granularity: Granularity) -> Result<Doorbells, Error> {
+        if queues == 0 {
+            return Err(Error::NoQueues);
+        }
+        let pages = match granularity {
+            Granularity::PerDevice => 1,
+            Granularity::PerQueue => usize::from(queues),
+        };
+        let first = self
+            .used
+            .windows(pages)
+            .position(|run| run.iter().all(|used| !used))
+            .ok_or(Error::Exhausted(pages as u64))?;
+        let base = self.base + first as u64 * self.page_size;
+        let range =
+            MmioRange::new(MmioAddress(base), pages as u64 * self.page_size).map_err(Error::Bus)?;
+        self.used[first..first + pages].fill(true);
+        Ok(Doorbells {
+            range,
+            page_size: self.page_size,
+            queues,
+            granularity,
+        })
+    }
+
+    /// Free the pages of `doorbells`, allocated from this window.
+    ///
+    /// Fails with [`Error::Foreign`] if the doorbells don't cover whole pages of the window.
+    pub fn release(&mut self, doorbells: &Doorbells) -> Result<(), Error> {
+        let base = doorbells.range.base().0;
+        let size = doorbells.range.size();
+        let foreign = Error::Foreign(base);
+        let offset = base.checked_sub(self.base).ok_or(foreign.clone())?;
+        if doorbells.page_size != self.page_size
+            || !offset.is_multiple_of(self.page_size)
+            || !size.is_multiple_of(self.page_size)
+        {
+            return Err(foreign);
+        }
+        let first = offset / self.page_size;
+        let last = first + size / self.page_size;
+        if last > self.used.len() as u64 {
+            return Err(foreign);
+        }
+        self.used[first as usize..last as usize].fill(false);
+        Ok(())
+    }
+}
+
+impl IoManager {
+    /// Allocate the doorbells of `device` from `window` (see [`DoorbellWindow::allocate`]),
+    /// and register their pages on the MMIO bus. The pages are released if the registration
+    /// fails.
+    ///
+    /// # Arguments
+    ///
+    /// * `window`: window the pages are taken from
+    /// * `device`: device handling the notifications
+    /// * `queues`: number of queues of the device
+    /// * `granularity`: whether the device gets a page, or each queue
+    pub fn register_doorbells(
+        &mut self,
+        window: &mut DoorbellWindow,
+        device: Arc<dyn DeviceMmio + Send + Sync>,
+        queues: u16,
+        granularity: Granularity,
+    ) -> Result<Doorbells, Error> {
+        let doorbells = window.allocate(queues, granularity)?;
+        if let Err(e) = self.register_mmio(doorbells.range(), device) {
+            window.release(&doorbells)?;
+            return Err(Error::Bus(e));
+        }
+        Ok(doorbells)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::Scratchpad;
+
+    #[test]
+    fn test_doorbell_window() {
+        assert_eq!(
+            DoorbellWindow::new(0, 0x3000, 0x1800).err(),
+            Some(Error::InvalidPageSize(0x1800))
+        );
+        assert_eq!(
+            DoorbellWindow::new(0x800, 0x1000, 0x1000).err(),
+            Some(Error::InvalidWindow {
+                base: 0x800,
+                size: 0x1000
+            })
+        );
+        assert!(DoorbellWindow::new(0xffff_ffff_ffff_f000, 0x2000, 0x1000).is_err());
+
+        let mut window = DoorbellWindow::new(0x10_0000, 0x4000, 0x1000).unwrap();
+        let device = window.allocate(4, Granularity::PerDevice).unwrap();
+        assert_eq!(device.range().size(), 0x1000);
+        assert_eq!(device.queue(0), None);
+        assert_eq!(
+            device.doorbells()[3],
+            Doorbell {
+                queue: 3,
+                addr: 0x10_0000,
+                datamatch: Some(3)
+            }
+        );
+        let queues = window.allocate(2, Granularity::PerQueue).unwrap();
+        assert_eq!(queues.to_string(), "queue 0: 0x101000\nqueue 1: 0x102000\n");
+        assert_eq!(queues.queue(0x1ffc), Some(1));
+        assert_eq!(queues.queue(0x2000), None);
+        assert_eq!(
+            window.allocate(0, Granularity::PerQueue).err(),
+            Some(Error::NoQueues)
+        );
+        assert_eq!(
+            window.allocate(2, Granularity::PerQueue).err(),
+            Some(Error::Exhausted(2))
+        );
+
+        // Released pages are allocated again, lowest first.
+        window.release(&device).unwrap();
+        assert_eq!(window.free_pages(), 2);
+        assert!(window.allocate(2, Granularity::PerQueue).is_err());
+        let queue = window.allocate(1, Granularity::PerQueue).unwrap();
+        assert_eq!(queue.range().base(), MmioAddress(0x10_0000));
+        let device = window.allocate(1, Granularity::PerDevice).unwrap();
+        assert_eq!(device.range().base(), MmioAddress(0x10_3000));
+
+        // Doorbells from other windows are rejected, whether they lie outside the window or
+        // use other pages.
+        let mut other = DoorbellWindow::new(0x10_3000, 0x2000, 0x1000).unwrap();
+        other.allocate(1, Granularity::PerDevice).unwrap();
+        let outside = other.allocate(1, Granularity::PerDevice).unwrap();
+        assert_eq!(window.release(&outside), Err(Error::Foreign(0x10_4000)));
+        let below = DoorbellWindow::new(0, 0x1000, 0x1000)
+            .unwrap()
+            .allocate(1, Granularity::PerDevice)
+            .unwrap();
+        assert_eq!(window.release(&below), Err(Error::Foreign(0)));
+        let small = DoorbellWindow::new(0x10_0800, 0x800, 0x800)
+            .unwrap()
+            .allocate(1, Granularity::PerDevice)
+            .unwrap();
+        assert_eq!(window.release(&small), Err(Error::Foreign(0x10_0800)));
+        assert_eq!(window.free_pages(), 0);
+    }
+
+    #[test]
+    fn test_register_doorbells() {
+        let mut window = DoorbellWindow::new(0x10_0000, 0x2000, 0x1000).unwrap();
+        let mut manager = IoManager::new();
+        let device = Arc::new(Scratchpad::new(0x2000));
+        let doorbells = manager
+            .register_doorbells(&mut window, device.clone(), 2, Granularity::PerQueue)
+            .unwrap();
+        manager
+            .mmio_write(MmioAddress(0x10_1000), &[1, 0, 0, 0])
+            .unwrap();
+        assert_eq!(doorbells.queue(0x1000), Some(1));
+
+        // A window overlapping registered ranges gets its pages back.
+        let mut overlapping = DoorbellWindow::new(0x10_1000, 0x1000, 0x1000).unwrap();
+        assert!(matches!(
+            manager.register_doorbells(&mut overlapping, device, 1, Granularity::PerDevice),
+            Err(Error::Bus(bus::Error::DeviceOverlap { .. }))
+        ));
+        assert_eq!(overlapping.free_pages(), 1);
+    }
+}
@@ -0,0 +1,58 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Registration of device backends with the VMM's event loop.
+//!
+//! Device backends usually wait on file descriptors (queue notification eventfds, tap
+//! devices, sockets, etc.) from an event loop such as the one provided by the
+//! [`event-manager`](https://github.com/rust-vmm/event-manager) crate. Adding a device to
+//! the event loop separately from registering it on the bus is a recurring source of leaked
+//! registrations, so [`IoManager::register_mmio_evented`] and
+//! [`IoManager::deregister_evented`] do both at once, through the [`EventLoop`] and
+//! [`Subscribe`] traits.
+//!
+//! The crate doesn't depend on `event-manager` and doesn't implement the traits for it, so
+//! that VMMs using another event loop don't pull it in. With `event-manager`, the traits map
+//! onto `SubscriberOps`, implemented for a type of the VMM wrapping the `EventManager`, as
+//! the orphan rules require:
+//!
+//! ```ignore
+//! type Subscriber = Arc<Mutex<dyn MutEventSubscriber + Send>>;
+//!
+//! struct Events(EventManager<Subscriber>);
+//!
+//! impl EventLoop for Events {
+//!     type Id = SubscriberId;
+//!
+//!     fn remove(&mut self, id: SubscriberId) {
+//!         let _ = self.0.remove_subscriber(id);
+//!     }
+//! }
+//!
+//! impl<S: MutEventSubscriber + Send + 'static> Subscribe<Mutex<S>> for Events {
+//!     fn add(&mut self, subscriber: Arc<Mutex<S>>) -> Option<SubscriberId> {
+//!         Some(self.0.add_subscriber(subscriber))
+//!     }
+//! }
+//! ```
+//!
+//! [`IoManager::register_mmio_evented`]: crate::device_manager::IoManager::register_mmio_evented
+//! [`IoManager::deregister_evented`]: crate::device_manager::IoManager::deregister_evented
+
+use std::sync::Arc;
+
+/// An event loop devices can be removed from.
+pub trait EventLoop {
+    /// Identifies a device added to the event loop.
+    type Id;
+
+    /// Remove the device identified by `id`, unregistering its file descriptors.
+    fn remove(&mut self, id: Self::Id);
+}
+
+/// An event loop devices of type `T` can be added to.
+pub trait Subscribe<T: ?Sized>: EventLoop {
+    /// Add `device`, registering its file descriptors, or return `None` if the event loop
+    /// rejected it.
+    fn add(&mut self, device: Arc<T>) -> Option<Self::Id>;
+}
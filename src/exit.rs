@@ -0,0 +1,180 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Hypervisor agnostic handling of the VM exits caused by device accesses.
+//!
+//! Each hypervisor describes the accesses which trap to the VMM with its own types (e.g.
+//! `VcpuExit` in `kvm-ioctls`, or the `hv_message` payloads of `mshv-ioctls`). An adapter
+//! implementing [`HypervisorExitSource`] turns them into an [`IoExit`], which
+//! [`handle_exit`] dispatches to the devices registered with a manager, so the exit loop of
+//! the VMM doesn't depend on the hypervisor:
+//!
+//! ```ignore
+//! loop {
+//!     if let Some(exit) = handle_exit(&manager, vcpu.run()?)? {
+//!         // Not a device access, e.g. `VcpuExit::Hlt`.
+//!     }
+//! }
+//! ```
+//!
+//! The adapter for KVM comes with the `kvm` feature. Until adapters for other hypervisors
+//! (e.g. MSHV or WHP) are provided, their exits can be wrapped in a type implementing
+//! [`HypervisorExitSource`], or converted to an [`IoExit`] directly.
+
+use crate::bus::{self, MmioAddress, PioAddress};
+use crate::device_manager::{MmioManager, PioManager};
+
+/// Direction and data of an access which caused a VM exit.
+#[derive(Debug, Eq, PartialEq)]
+pub enum ExitAccess<'a> {
+    /// The guest reads into the buffer, which must be filled in before it resumes.
+    Read(&'a mut [u8]),
+    /// The guest writes the data.
+    Write(&'a [u8]),
+}
+
+/// An MMIO access which caused a VM exit.
+#[derive(Debug, Eq, PartialEq)]
+pub struct MmioExit<'a> {
+    /// Address of the access.
+    pub addr: MmioAddress,
+    /// Direction and data of the access.
+    pub access: ExitAccess<'a>,
+}
+
+/// A PIO access which caused a VM exit.
+#[derive(Debug, Eq, PartialEq)]
+pub struct PioExit<'a> {
+    /// Port of the access.
+    pub addr: PioAddress,
+    /// Direction and data of the access.
+    pub access: ExitAccess<'a>,
+}
+
+/// A VM exit caused by a device access.
+#[derive(Debug, Eq, PartialEq)]
+pub enum IoExit<'a> {
+    /// Access to the MMIO bus.
+    Mmio(MmioExit<'a>),
+    /// Access to the PIO bus.
+    Pio(PioExit<'a>),
+}
+
+impl IoExit<'_> {
+    /// Dispatch the access to the device registered at its address with `manager`.
+    pub fn dispatch<M>(self, manager: &M) -> Result<(), bus::Error>
+    where
+        M: MmioManager + PioManager,
+    {
+        match self {
+            IoExit::Mmio(MmioExit { addr, access }) => match access {
+                ExitAccess::Read(data) => manager.mmio_read(addr, data),
+                ExitAccess::Write(data) => manager.mmio_write(addr, data),
+            },
+            IoExit::Pio(PioExit { addr, access }) => match access {
+                ExitAccess::Read(data) => manager.pio_read(addr, data),
+                ExitAccess::Write(data) => manager.pio_write(addr, data),
+            },
+        }
+    }
+}
+
+/// A VM exit reported by a hypervisor, which may be caused by a device access.
+pub trait HypervisorExitSource<'a>: Sized {
+    /// Return the device access described by the exit, or the exit itself if it isn't
+    /// caused by a device access.
+    fn into_io_exit(self) -> Result<IoExit<'a>, Self>;
+}
+
+impl<'a> HypervisorExitSource<'a> for IoExit<'a> {
+    fn into_io_exit(self) -> Result<IoExit<'a>, Self> {
+        Ok(self)
+    }
+}
+
+/// Dispatch the device access which caused `exit` with `manager`.
+///
+/// Returns the exit when it isn't caused by a device access, so the caller can handle it,
+/// and `None` once the access is dispatched.
+pub fn handle_exit<'a, M, E>(manager: &M, exit: E) -> Result<Option<E>, bus::Error>
+where
+    M: MmioManager + PioManager,
+    E: HypervisorExitSource<'a>,
+{
+    match exit.into_io_exit() {
+        Ok(exit) => exit.dispatch(manager).map(|_| None),
+        Err(exit) => Ok(Some(exit)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::bus::{MmioRange, PioRange};
+    use crate::device_manager::IoManager;
+    use crate::testing::Scratchpad;
+
+    // Exits of a made up hypervisor.
+    #[derive(Debug)]
+    enum Exit<'a> {
+        MmioWrite(u64, &'a [u8]),
+        PortIn(u16, &'a mut [u8]),
+        Halt,
+    }
+
+    impl<'a> HypervisorExitSource<'a> for Exit<'a> {
+        fn into_io_exit(self) -> Result<IoExit<'a>, Self> {
+            match self {
+                Exit::MmioWrite(addr, data) => Ok(IoExit::Mmio(MmioExit {
+                    addr: MmioAddress(addr),
+                    access: ExitAccess::Write(data),
+                })),
+                Exit::PortIn(port, data) => Ok(IoExit::Pio(PioExit {
+                    addr: PioAddress(port),
+                    access: ExitAccess::Read(data),
+                })),
+                exit => Err(exit),
+            }
+        }
+    }
+
+    #[test]
+    fn test_handle_exit() {
+        let mut manager = IoManager::new();
+        let scratchpad = Arc::new(Scratchpad::new(0x10));
+        let range = MmioRange::new(MmioAddress(0x1000), 0x10).unwrap();
+        manager.register_mmio(range, scratchpad.clone()).unwrap();
+        let range = PioRange::new(PioAddress(0x60), 0x10).unwrap();
+        manager.register_pio(range, scratchpad).unwrap();
+
+        assert!(handle_exit(&manager, Exit::MmioWrite(0x1004, &[1, 2]))
+            .unwrap()
+            .is_none());
+        let mut data = [0; 2];
+        assert!(handle_exit(&manager, Exit::PortIn(0x64, &mut data))
+            .unwrap()
+            .is_none());
+        assert_eq!(data, [1, 2]);
+        assert!(matches!(
+            handle_exit(&manager, Exit::Halt),
+            Ok(Some(Exit::Halt))
+        ));
+        assert_eq!(
+            handle_exit(&manager, Exit::MmioWrite(0x2000, &[0])).unwrap_err(),
+            bus::Error::DeviceNotFound {
+                addr: 0x2000,
+                len: 1
+            }
+        );
+
+        let exit = IoExit::Mmio(MmioExit {
+            addr: MmioAddress(0x1005),
+            access: ExitAccess::Read(&mut data),
+        });
+        assert!(handle_exit(&manager, exit).unwrap().is_none());
+        assert_eq!(data, [2, 0]);
+    }
+}
@@ -0,0 +1,387 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Devices whose accesses may fail.
+//!
+//! The device traits can't report errors, so a device whose backend fails (e.g. with `EIO`
+//! from a disk image) has to swallow them. Devices implementing [`TryDeviceMmio`] or
+//! [`TryDevicePio`] return a [`DeviceError`] instead, and are registered wrapped in a
+//! [`Fallible`], which applies a [`FailurePolicy`] to the failed accesses:
+//!
+//! ```ignore
+//! let disk = Arc::new(Fallible::new(Disk::open(path)?, FailurePolicy::MarkFailed));
+//! manager.register_mmio(range, disk.clone())?;
+//! // Later, e.g. when polled by the VMM.
+//! if disk.is_failed() {
+//!     hotplug.remove(slot)?;
+//! }
+//! ```
+//!
+//! Failed reads return zeros. The failures are counted, and reported as warnings with the
+//! `log` feature.
+
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::bus::{MmioAddress, MmioAddressOffset, PioAddress, PioAddressOffset};
+use crate::completion::{self, Completion};
+use crate::exit::ExitAccess;
+use crate::trace;
+use crate::{DeviceMmio, DevicePio};
+
+/// Error returned by a device which failed to handle an access.
+#[derive(Debug)]
+pub struct DeviceError(Box<dyn std::error::Error + Send + Sync>);
+
+impl DeviceError {
+    /// Create an error caused by `error`.
+    pub fn new<E>(error: E) -> Self
+    where
+        E: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        DeviceError(error.into())
+    }
+}
+
+impl From<io::Error> for DeviceError {
+    fn from(e: io::Error) -> Self {
+        DeviceError::new(e)
+    }
+}
+
+impl Display for DeviceError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "fallible: device access failed: {}", self.0)
+    }
+}
+
+impl std::error::Error for DeviceError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.0.as_ref())
+    }
+}
+
+/// Allows a device whose accesses may fail to be attached to a MMIO bus, through a
+/// [`Fallible`].
+pub trait TryDeviceMmio {
+    /// Handle a read operation on the device, like [`DeviceMmio::mmio_read`].
+    fn try_mmio_read(
+        &self,
+        base: MmioAddress,
+        offset: MmioAddressOffset,
+        data: &mut [u8],
+    ) -> Result<(), DeviceError>;
+
+    /// Handle a write operation to the device, like [`DeviceMmio::mmio_write`].
+    fn try_mmio_write(
+        &self,
+        base: MmioAddress,
+        offset: MmioAddressOffset,
+        data: &[u8],
+    ) -> Result<(), DeviceError>;
+}
+
+/// Allows a device whose accesses may fail to be attached to a PIO bus, through a
+/// [`Fallible`].
+pub trait TryDevicePio {
+    /// Handle a read operation on the device, like [`DevicePio::pio_read`].
+    fn try_pio_read(
+        &self,
+        base: PioAddress,
+        offset: PioAddressOffset,
+        data: &mut [u8],
+    ) -> Result<(), DeviceError>;
+
+    /// Handle a write operation to the device, like [`DevicePio::pio_write`].
+    fn try_pio_write(
+        &self,
+        base: PioAddress,
+        offset: PioAddressOffset,
+        data: &[u8],
+    ) -> Result<(), DeviceError>;
+}
+
+/// What a [`Fallible`] does when an access to its device fails.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FailurePolicy {
+    /// Only count and log the failure.
+    Log,
+    /// Return the failure to the caller of
+    /// [`IoManager::dispatch_deferred`](crate::device_manager::IoManager::dispatch_deferred)
+    /// or [`IoManager::dispatch_stalling`](crate::device_manager::IoManager::dispatch_stalling),
+    /// as [`completion::Error::Backend`]. The other dispatch paths can't report errors, so
+    /// they only log it.
+    Report,
+    /// Mark the device as failed: the later accesses don't reach it until
+    /// [`Fallible::clear_failure`] is called, reads returning zeros.
+    MarkFailed,
+}
+
+/// Exposes a device implementing [`TryDeviceMmio`] or [`TryDevicePio`] on the buses,
+/// applying a [`FailurePolicy`] to its failures.
+pub struct Fallible<D> {
+    device: D,
+    policy: FailurePolicy,
+    failures: AtomicU64,
+    failed: AtomicBool,
+}
+
+impl<D> Fallible<D> {
+    /// Expose `device`, handling its failures with `policy`.
+    pub fn new(device: D, policy: FailurePolicy) -> Self {
+        Fallible {
+            device,
+            policy,
+            failures: AtomicU64::new(0),
+            failed: AtomicBool::new(false),
+        }
+    }
+
+    /// Return the device.
+    pub fn inner(&self) -> &D {
+        &self.device
+    }
+
+    /// Return the number of failed accesses.
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    /// Return whether the device was marked as failed, with [`FailurePolicy::MarkFailed`].
+    pub fn is_failed(&self) -> bool {
+        self.failed.load(Ordering::Acquire)
+    }
+
+    /// Let the accesses reach the device again, e.g. once it was reset.
+    pub fn clear_failure(&self) {
+        self.failed.store(false, Ordering::Release);
+    }
+
+    // Handle `access` with `handler`, unless the device failed, and apply the policy to
+    // the failure.
+    fn access<F>(&self, mut access: ExitAccess<'_>, handler: F) -> Completion
+    where
+        F: FnOnce(&mut ExitAccess<'_>) -> Result<(), DeviceError>,
+    {
+        let skipped = self.is_failed();
+        let error = if skipped {
+            None
+        } else {
+            handler(&mut access).err()
+        };
+        if let (ExitAccess::Read(data), true) = (&mut access, skipped || error.is_some()) {
+            data.fill(0);
+        }
+
+        let error = match error {
+            Some(error) => error,
+            None => return Completion::Done,
+        };
+        self.failures.fetch_add(1, Ordering::Relaxed);
+        trace::device_failed(&error);
+        match self.policy {
+            FailurePolicy::Log => Completion::Done,
+            FailurePolicy::Report => {
+                let (completer, pending) = completion::channel();
+                completer.fail(error);
+                Completion::Pending(pending)
+            }
+            FailurePolicy::MarkFailed => {
+                self.failed.store(true, Ordering::Release);
+                Completion::Done
+            }
+        }
+    }
+
+    fn properties(&self) -> Vec<(String, String)> {
+        vec![
+            ("failures".to_string(), self.failures().to_string()),
+            ("failed".to_string(), self.is_failed().to_string()),
+        ]
+    }
+}
+
+impl<D: TryDeviceMmio> DeviceMmio for Fallible<D> {
+    fn mmio_read(&self, base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+        // Nothing can be reported on this path.
+        let _ = self.mmio_deferred(base, offset, ExitAccess::Read(data));
+    }
+
+    fn mmio_write(&self, base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+        let _ = self.mmio_deferred(base, offset, ExitAccess::Write(data));
+    }
+
+    fn introspect(&self) -> Vec<(String, String)> {
+        self.properties()
+    }
+
+    fn mmio_deferred(
+        &self,
+        base: MmioAddress,
+        offset: MmioAddressOffset,
+        access: ExitAccess<'_>,
+    ) -> Completion {
+        self.access(access, |access| match access {
+            ExitAccess::Read(data) => self.device.try_mmio_read(base, offset, data),
+            ExitAccess::Write(data) => self.device.try_mmio_write(base, offset, data),
+        })
+    }
+}
+
+impl<D: TryDevicePio> DevicePio for Fallible<D> {
+    fn pio_read(&self, base: PioAddress, offset: PioAddressOffset, data: &mut [u8]) {
+        let _ = self.pio_deferred(base, offset, ExitAccess::Read(data));
+    }
+
+    fn pio_write(&self, base: PioAddress, offset: PioAddressOffset, data: &[u8]) {
+        let _ = self.pio_deferred(base, offset, ExitAccess::Write(data));
+    }
+
+    fn introspect(&self) -> Vec<(String, String)> {
+        self.properties()
+    }
+
+    fn pio_deferred(
+        &self,
+        base: PioAddress,
+        offset: PioAddressOffset,
+        access: ExitAccess<'_>,
+    ) -> Completion {
+        self.access(access, |access| match access {
+            ExitAccess::Read(data) => self.device.try_pio_read(base, offset, data),
+            ExitAccess::Write(data) => self.device.try_pio_write(base, offset, data),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::bus::{MmioRange, PioRange};
+    use crate::device_manager::{IoManager, MmioManager, PioManager};
+    use crate::exit::{IoExit, MmioExit, PioExit};
+
+    // Disk whose backend fails while `broken` is set.
+    #[derive(Default)]
+    struct Disk {
+        broken: AtomicBool,
+    }
+
+    impl Disk {
+        fn backend(&self) -> Result<(), DeviceError> {
+            if self.broken.load(Ordering::Relaxed) {
+                return Err(io::Error::from_raw_os_error(5).into());
+            }
+            Ok(())
+        }
+    }
+
+    impl TryDeviceMmio for Disk {
+        fn try_mmio_read(
+            &self,
+            _base: MmioAddress,
+            _offset: MmioAddressOffset,
+            data: &mut [u8],
+        ) -> Result<(), DeviceError> {
+            data.fill(0xff);
+            self.backend()
+        }
+
+        fn try_mmio_write(
+            &self,
+            _base: MmioAddress,
+            _offset: MmioAddressOffset,
+            _data: &[u8],
+        ) -> Result<(), DeviceError> {
+            self.backend()
+        }
+    }
+
+    impl TryDevicePio for Disk {
+        fn try_pio_read(
+            &self,
+            _base: PioAddress,
+            _offset: PioAddressOffset,
+            data: &mut [u8],
+        ) -> Result<(), DeviceError> {
+            data.fill(0xff);
+            self.backend()
+        }
+
+        fn try_pio_write(
+            &self,
+            _base: PioAddress,
+            _offset: PioAddressOffset,
+            _data: &[u8],
+        ) -> Result<(), DeviceError> {
+            self.backend()
+        }
+    }
+
+    fn setup(policy: FailurePolicy) -> (IoManager, Arc<Fallible<Disk>>) {
+        let mut manager = IoManager::new();
+        let disk = Arc::new(Fallible::new(Disk::default(), policy));
+        let range = MmioRange::new(MmioAddress(0x1000), 0x100).unwrap();
+        manager.register_mmio(range, disk.clone()).unwrap();
+        let range = PioRange::new(PioAddress(0x60), 4).unwrap();
+        manager.register_pio(range, disk.clone()).unwrap();
+        (manager, disk)
+    }
+
+    #[test]
+    fn test_failure_policies() {
+        let (manager, disk) = setup(FailurePolicy::Log);
+        let mut data = [0; 4];
+        manager.mmio_read(MmioAddress(0x1000), &mut data).unwrap();
+        assert_eq!(data, [0xff; 4]);
+        disk.inner().broken.store(true, Ordering::Relaxed);
+        manager.mmio_read(MmioAddress(0x1000), &mut data).unwrap();
+        assert_eq!(data, [0; 4]);
+        manager.pio_write(PioAddress(0x60), &[1]).unwrap();
+        assert_eq!(disk.failures(), 2);
+        assert!(!disk.is_failed());
+        assert!(disk
+            .inner()
+            .try_mmio_write(MmioAddress(0x1000), 0, &[0])
+            .unwrap_err()
+            .to_string()
+            .starts_with("fallible: device access failed: "));
+
+        // The failures are returned by the deferred dispatch paths.
+        let (manager, disk) = setup(FailurePolicy::Report);
+        disk.inner().broken.store(true, Ordering::Relaxed);
+        let mut data = [0xaa; 2];
+        let exit = IoExit::Pio(PioExit {
+            addr: PioAddress(0x62),
+            access: ExitAccess::Read(&mut data),
+        });
+        let err = manager.dispatch_stalling(exit, |_| {}).unwrap_err();
+        assert!(matches!(err, completion::Error::Backend(_)));
+        assert_eq!(data, [0; 2]);
+        manager.mmio_write(MmioAddress(0x1000), &[1]).unwrap();
+        assert_eq!(disk.failures(), 2);
+
+        // Failed devices aren't accessed anymore, until the failure is cleared.
+        let (manager, disk) = setup(FailurePolicy::MarkFailed);
+        disk.inner().broken.store(true, Ordering::Relaxed);
+        let exit = IoExit::Mmio(MmioExit {
+            addr: MmioAddress(0x1004),
+            access: ExitAccess::Write(&[1]),
+        });
+        assert!(manager.dispatch_deferred(exit).unwrap().is_none());
+        assert!(disk.is_failed());
+        disk.inner().broken.store(false, Ordering::Relaxed);
+        let mut data = [0xaa; 4];
+        manager.mmio_read(MmioAddress(0x1000), &mut data).unwrap();
+        assert_eq!(data, [0; 4]);
+        assert_eq!(disk.failures(), 1);
+        disk.clear_failure();
+        manager.mmio_read(MmioAddress(0x1000), &mut data).unwrap();
+        assert_eq!(data, [0xff; 4]);
+        assert!(DeviceMmio::introspect(&*disk).contains(&("failures".to_string(), "1".to_string())));
+    }
+}
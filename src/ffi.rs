@@ -0,0 +1,481 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! C interface to the [`IoManager`] (`ffi` feature).
+//!
+//! The functions below let a C or C++ VMM component create a manager, register devices
+//! implemented as callbacks, and dispatch accesses through the same bus logic as Rust
+//! devices. The manager is an opaque pointer on the C side:
+//!
+//! ```c
+//! struct IoManager;
+//!
+//! struct DeviceOps {
+//!     void (*read)(void *ctx, uint64_t base, uint64_t offset, uint8_t *data, size_t len);
+//!     void (*write)(void *ctx, uint64_t base, uint64_t offset, const uint8_t *data, size_t len);
+//!     void (*release)(void *ctx);
+//! };
+//!
+//! struct IoManager *vm_device_manager_new(void);
+//! void vm_device_manager_free(struct IoManager *manager);
+//! int32_t vm_device_register_mmio(struct IoManager *manager, uint64_t base, uint64_t size,
+//!                                 const struct DeviceOps *ops, void *ctx);
+//! int32_t vm_device_mmio_write(const struct IoManager *manager, uint64_t addr,
+//!                              const uint8_t *data, size_t len);
+//! ```
+//!
+//! Every function returns a [`Status`], `0` on success. Registering a device passes the
+//! ownership of its `ctx` to the manager, which calls `release` once the device is
+//! deregistered or the manager freed, or right away if the registration fails.
+//!
+//! Registration needs exclusive access to the manager, whereas accesses may be dispatched
+//! from several threads at once, in which case the callbacks must be thread safe.
+
+use std::os::raw::c_void;
+use std::slice;
+use std::sync::Arc;
+
+use crate::bus::{
+    self, MmioAddress, MmioAddressOffset, MmioRange, PioAddress, PioAddressOffset, PioRange,
+};
+use crate::device_manager::{IoManager, MmioManager, PioManager};
+use crate::{DeviceMmio, DevicePio};
+
+/// Outcome of the functions of the C interface.
+#[repr(i32)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Status {
+    /// The operation succeeded.
+    Ok = 0,
+    /// A pointer argument is null.
+    NullPointer = 1,
+    /// No device is registered at the address, or the access doesn't fit in its range.
+    DeviceNotFound = 2,
+    /// The range overlaps a registered range.
+    DeviceOverlap = 3,
+    /// The access length is invalid.
+    InvalidAccessLength = 4,
+    /// The range is empty, overflows the address space, or is misaligned.
+    InvalidRange = 5,
+}
+
+impl From<bus::Error> for Status {
+    fn from(e: bus::Error) -> Self {
+        match e {
+            bus::Error::DeviceNotFound { .. } => Status::DeviceNotFound,
+            bus::Error::DeviceOverlap { .. } => Status::DeviceOverlap,
+            bus::Error::InvalidAccessLength { .. } => Status::InvalidAccessLength,
+            bus::Error::InvalidRange { .. } | bus::Error::MisalignedRange { .. } => {
+                Status::InvalidRange
+            }
+        }
+    }
+}
+
+impl<T> From<Result<T, bus::Error>> for Status {
+    fn from(result: Result<T, bus::Error>) -> Self {
+        result.map_or_else(Status::from, |_| Status::Ok)
+    }
+}
+
+/// Callbacks of a device implemented in C.
+///
+/// `base` is the base address of the range the access falls in, and `offset` the offset
+/// of the access within the range. Missing callbacks are skipped, reads then return zeros.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct DeviceOps {
+    /// Fill the `len` bytes at `data` with the value read.
+    pub read: Option<
+        unsafe extern "C" fn(ctx: *mut c_void, base: u64, offset: u64, data: *mut u8, len: usize),
+    >,
+    /// Handle the write of the `len` bytes at `data`.
+    pub write: Option<
+        unsafe extern "C" fn(ctx: *mut c_void, base: u64, offset: u64, data: *const u8, len: usize),
+    >,
+    /// Release `ctx`, once the device is no longer used.
+    pub release: Option<unsafe extern "C" fn(ctx: *mut c_void)>,
+}
+
+// A device forwarding its accesses to C callbacks.
+struct CallbackDevice {
+    ops: DeviceOps,
+    ctx: *mut c_void,
+}
+
+// The callbacks are required to be thread safe, as documented in the module.
+unsafe impl Send for CallbackDevice {}
+unsafe impl Sync for CallbackDevice {}
+
+impl CallbackDevice {
+    fn read(&self, base: u64, offset: u64, data: &mut [u8]) {
+        data.fill(0);
+        if let Some(read) = self.ops.read {
+            // SAFETY: `data` is valid for `data.len()` bytes, and the caller of the
+            // registration function vouched for the callback and `ctx`.
+            unsafe { read(self.ctx, base, offset, data.as_mut_ptr(), data.len()) };
+        }
+    }
+
+    fn write(&self, base: u64, offset: u64, data: &[u8]) {
+        if let Some(write) = self.ops.write {
+            // SAFETY: as in `read`.
+            unsafe { write(self.ctx, base, offset, data.as_ptr(), data.len()) };
+        }
+    }
+}
+
+impl Drop for CallbackDevice {
+    fn drop(&mut self) {
+        if let Some(release) = self.ops.release {
+            // SAFETY: the device is dropped once, after its last access.
+            unsafe { release(self.ctx) };
+        }
+    }
+}
+
+impl DevicePio for CallbackDevice {
+    fn pio_read(&self, base: PioAddress, offset: PioAddressOffset, data: &mut [u8]) {
+        self.read(u64::from(base.0), u64::from(offset), data);
+    }
+
+    fn pio_write(&self, base: PioAddress, offset: PioAddressOffset, data: &[u8]) {
+        self.write(u64::from(base.0), u64::from(offset), data);
+    }
+}
+
+impl DeviceMmio for CallbackDevice {
+    fn mmio_read(&self, base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+        self.read(base.0, offset, data);
+    }
+
+    fn mmio_write(&self, base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+        self.write(base.0, offset, data);
+    }
+}
+
+// Build the device registered with `ops` and `ctx`, if `ops` isn't null.
+unsafe fn device(ops: *const DeviceOps, ctx: *mut c_void) -> Option<Arc<CallbackDevice>> {
+    ops.as_ref()
+        .map(|ops| Arc::new(CallbackDevice { ops: *ops, ctx }))
+}
+
+// Return the `len` bytes at `data`, if `data` isn't null or `len` is 0.
+unsafe fn buffer<'a>(data: *const u8, len: usize) -> Option<&'a [u8]> {
+    match (data.is_null(), len) {
+        (_, 0) => Some(&[]),
+        (true, _) => None,
+        (false, len) => Some(slice::from_raw_parts(data, len)),
+    }
+}
+
+// Mutable counterpart of `buffer`.
+unsafe fn buffer_mut<'a>(data: *mut u8, len: usize) -> Option<&'a mut [u8]> {
+    match (data.is_null(), len) {
+        (_, 0) => Some(&mut []),
+        (true, _) => None,
+        (false, len) => Some(slice::from_raw_parts_mut(data, len)),
+    }
+}
+
+/// Create a manager without any device, to be freed with [`vm_device_manager_free`].
+#[no_mangle]
+pub extern "C" fn vm_device_manager_new() -> *mut IoManager {
+    Box::into_raw(Box::new(IoManager::new()))
+}
+
+/// Free `manager`, releasing its devices. Null pointers are ignored.
+///
+/// # Safety
+///
+/// `manager` must be null or returned by [`vm_device_manager_new`], and not be used
+/// afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn vm_device_manager_free(manager: *mut IoManager) {
+    if !manager.is_null() {
+        drop(Box::from_raw(manager));
+    }
+}
+
+/// Register the device implemented by `ops` and `ctx` on the MMIO range of `size` bytes
+/// starting at `base`.
+///
+/// # Safety
+///
+/// `manager` must be a valid manager which isn't used concurrently, and `ops` must point
+/// to valid callbacks, which may be called with `ctx` until `release` is.
+#[no_mangle]
+pub unsafe extern "C" fn vm_device_register_mmio(
+    manager: *mut IoManager,
+    base: u64,
+    size: u64,
+    ops: *const DeviceOps,
+    ctx: *mut c_void,
+) -> Status {
+    let device = match device(ops, ctx) {
+        Some(device) => device,
+        None => return Status::NullPointer,
+    };
+    let manager = match manager.as_mut() {
+        Some(manager) => manager,
+        None => return Status::NullPointer,
+    };
+    MmioRange::new(MmioAddress(base), size)
+        .and_then(|range| manager.register_mmio(range, device))
+        .into()
+}
+
+/// Register the device implemented by `ops` and `ctx` on the PIO range of `size` ports
+/// starting at `base`.
+///
+/// # Safety
+///
+/// As for [`vm_device_register_mmio`].
+#[no_mangle]
+pub unsafe extern "C" fn vm_device_register_pio(
+    manager: *mut IoManager,
+    base: u16,
+    size: u16,
+    ops: *const DeviceOps,
+    ctx: *mut c_void,
+) -> Status {
+    let device = match device(ops, ctx) {
+        Some(device) => device,
+        None => return Status::NullPointer,
+    };
+    let manager = match manager.as_mut() {
+        Some(manager) => manager,
+        None => return Status::NullPointer,
+    };
+    PioRange::new(PioAddress(base), size)
+        .and_then(|range| manager.register_pio(range, device))
+        .into()
+}
+
+/// Deregister the MMIO device whose range includes `addr`.
+///
+/// # Safety
+///
+/// `manager` must be a valid manager which isn't used concurrently.
+#[no_mangle]
+pub unsafe extern "C" fn vm_device_deregister_mmio(manager: *mut IoManager, addr: u64) -> Status {
+    match manager.as_mut() {
+        Some(manager) => manager
+            .deregister_mmio(MmioAddress(addr))
+            .map_or(Status::DeviceNotFound, |_| Status::Ok),
+        None => Status::NullPointer,
+    }
+}
+
+/// Deregister the PIO device whose range includes `addr`.
+///
+/// # Safety
+///
+/// As for [`vm_device_deregister_mmio`].
+#[no_mangle]
+pub unsafe extern "C" fn vm_device_deregister_pio(manager: *mut IoManager, addr: u16) -> Status {
+    match manager.as_mut() {
+        Some(manager) => manager
+            .deregister_pio(PioAddress(addr))
+            .map_or(Status::DeviceNotFound, |_| Status::Ok),
+        None => Status::NullPointer,
+    }
+}
+
+/// Read `len` bytes at the MMIO address `addr` into `data`.
+///
+/// # Safety
+///
+/// `manager` must be a valid manager, and `data` must be valid for writing `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn vm_device_mmio_read(
+    manager: *const IoManager,
+    addr: u64,
+    data: *mut u8,
+    len: usize,
+) -> Status {
+    match (manager.as_ref(), buffer_mut(data, len)) {
+        (Some(manager), Some(data)) => manager.mmio_read(MmioAddress(addr), data).into(),
+        _ => Status::NullPointer,
+    }
+}
+
+/// Write the `len` bytes at `data` to the MMIO address `addr`.
+///
+/// # Safety
+///
+/// `manager` must be a valid manager, and `data` must be valid for reading `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn vm_device_mmio_write(
+    manager: *const IoManager,
+    addr: u64,
+    data: *const u8,
+    len: usize,
+) -> Status {
+    match (manager.as_ref(), buffer(data, len)) {
+        (Some(manager), Some(data)) => manager.mmio_write(MmioAddress(addr), data).into(),
+        _ => Status::NullPointer,
+    }
+}
+
+/// Read `len` bytes at the port `addr` into `data`.
+///
+/// # Safety
+///
+/// As for [`vm_device_mmio_read`].
+#[no_mangle]
+pub unsafe extern "C" fn vm_device_pio_read(
+    manager: *const IoManager,
+    addr: u16,
+    data: *mut u8,
+    len: usize,
+) -> Status {
+    match (manager.as_ref(), buffer_mut(data, len)) {
+        (Some(manager), Some(data)) => manager.pio_read(PioAddress(addr), data).into(),
+        _ => Status::NullPointer,
+    }
+}
+
+/// Write the `len` bytes at `data` to the port `addr`.
+///
+/// # Safety
+///
+/// As for [`vm_device_mmio_write`].
+#[no_mangle]
+pub unsafe extern "C" fn vm_device_pio_write(
+    manager: *const IoManager,
+    addr: u16,
+    data: *const u8,
+    len: usize,
+) -> Status {
+    match (manager.as_ref(), buffer(data, len)) {
+        (Some(manager), Some(data)) => manager.pio_write(PioAddress(addr), data).into(),
+        _ => Status::NullPointer,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::ptr;
+    use std::sync::Mutex;
+
+    // Device state, as a C component would keep it behind `ctx`.
+    #[derive(Default)]
+    struct Registers {
+        data: Mutex<[u8; 16]>,
+        accesses: Mutex<Vec<(u64, u64, usize)>>,
+        released: Mutex<usize>,
+    }
+
+    unsafe extern "C" fn read(ctx: *mut c_void, base: u64, offset: u64, data: *mut u8, len: usize) {
+        let regs = &*(ctx as *const Registers);
+        regs.accesses.lock().unwrap().push((base, offset, len));
+        let src = regs.data.lock().unwrap();
+        ptr::copy_nonoverlapping(src[offset as usize..].as_ptr(), data, len);
+    }
+
+    unsafe extern "C" fn write(
+        ctx: *mut c_void,
+        base: u64,
+        offset: u64,
+        data: *const u8,
+        len: usize,
+    ) {
+        let regs = &*(ctx as *const Registers);
+        regs.accesses.lock().unwrap().push((base, offset, len));
+        let mut dst = regs.data.lock().unwrap();
+        ptr::copy_nonoverlapping(data, dst[offset as usize..].as_mut_ptr(), len);
+    }
+
+    unsafe extern "C" fn release(ctx: *mut c_void) {
+        *(*(ctx as *const Registers)).released.lock().unwrap() += 1;
+    }
+
+    const OPS: DeviceOps = DeviceOps {
+        read: Some(read),
+        write: Some(write),
+        release: Some(release),
+    };
+
+    #[test]
+    fn test_ffi() {
+        let regs = Registers::default();
+        let ctx = &regs as *const Registers as *mut c_void;
+        let manager = vm_device_manager_new();
+        let mut data = [0u8; 4];
+
+        unsafe {
+            assert_eq!(
+                vm_device_register_mmio(manager, 0x1000, 0x10, &OPS, ctx),
+                Status::Ok
+            );
+            assert_eq!(
+                vm_device_register_pio(manager, 0x60, 0x10, &OPS, ctx),
+                Status::Ok
+            );
+            // The context of a rejected device is released right away.
+            assert_eq!(
+                vm_device_register_mmio(manager, 0x1008, 0x10, &OPS, ctx),
+                Status::DeviceOverlap
+            );
+            assert_eq!(
+                vm_device_register_mmio(manager, 0x2000, 0, &OPS, ctx),
+                Status::InvalidRange
+            );
+            assert_eq!(*regs.released.lock().unwrap(), 2);
+            assert_eq!(
+                vm_device_register_mmio(manager, 0x2000, 0x10, ptr::null(), ctx),
+                Status::NullPointer
+            );
+
+            let value = 0x1234_5678u32.to_le_bytes();
+            assert_eq!(
+                vm_device_mmio_write(manager, 0x1004, value.as_ptr(), 4),
+                Status::Ok
+            );
+            assert_eq!(
+                vm_device_pio_read(manager, 0x64, data.as_mut_ptr(), 4),
+                Status::Ok
+            );
+            assert_eq!(data, value);
+            assert_eq!(
+                vm_device_pio_write(manager, 0x6e, [7, 7].as_ptr(), 2),
+                Status::Ok
+            );
+            assert_eq!(
+                vm_device_mmio_read(manager, 0x100e, data.as_mut_ptr(), 2),
+                Status::Ok
+            );
+            assert_eq!(data[..2], [7, 7]);
+            assert_eq!(
+                *regs.accesses.lock().unwrap(),
+                [(0x1000, 4, 4), (0x60, 4, 4), (0x60, 14, 2), (0x1000, 14, 2)]
+            );
+
+            assert_eq!(
+                vm_device_mmio_read(manager, 0x100e, data.as_mut_ptr(), 4),
+                Status::DeviceNotFound
+            );
+            assert_eq!(
+                vm_device_mmio_write(manager, 0x1000, ptr::null(), 4),
+                Status::NullPointer
+            );
+            assert_eq!(
+                vm_device_mmio_write(ptr::null(), 0x1000, data.as_ptr(), 4),
+                Status::NullPointer
+            );
+
+            assert_eq!(vm_device_deregister_mmio(manager, 0x100f), Status::Ok);
+            assert_eq!(*regs.released.lock().unwrap(), 3);
+            assert_eq!(
+                vm_device_deregister_mmio(manager, 0x1000),
+                Status::DeviceNotFound
+            );
+            vm_device_manager_free(manager);
+            vm_device_manager_free(ptr::null_mut());
+        }
+        assert_eq!(*regs.released.lock().unwrap(), 4);
+    }
+}
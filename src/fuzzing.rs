@@ -0,0 +1,522 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Helpers for fuzzing the bus and manager logic.
+//!
+//! This module is available with the `arbitrary` feature. A byte stream is decoded into a
+//! sequence of [`Operation`]s, which a [`Harness`] applies to an [`IoManager`] while
+//! checking the results against a simple reference model. Any divergence (an unexpected
+//! overlap, an inconsistent lookup, an access dispatched to the wrong device or offset)
+//! causes a panic, which fuzzers report as a crash.
+//!
+//! Addresses and sizes are biased towards a few narrow windows, including both ends of the
+//! address spaces, so that overlapping and adjacent ranges are generated frequently.
+//!
+//! # Example
+//!
+//! A `cargo-fuzz` target only needs to forward its input:
+//!
+//! ```ignore
+//! libfuzzer_sys::fuzz_target!(|data: &[u8]| vm_device::fuzzing::run(data));
+//! ```
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+
+use arbitrary::{Arbitrary, Unstructured};
+
+use crate::bus::{
+    self, BusManager, MmioAddress, MmioAddressOffset, MmioRange, PioAddress, PioAddressOffset,
+    PioRange,
+};
+use crate::device_manager::{IoManager, MmioManager, PioManager};
+use crate::{DeviceMmio, DevicePio};
+
+/// The largest access generated when decoding operations.
+pub const MAX_ACCESS_LEN: usize = 16;
+
+/// An operation against an [`IoManager`].
+#[derive(Clone, Debug)]
+pub enum Operation {
+    /// Register a new device on the MMIO bus.
+    RegisterMmio(MmioRange),
+    /// Deregister the device found at an address of the MMIO bus.
+    DeregisterMmio(MmioAddress),
+    /// Read `len` bytes from the MMIO bus.
+    MmioRead {
+        /// Address of the access.
+        addr: MmioAddress,
+        /// Length of the access.
+        len: usize,
+    },
+    /// Write to the MMIO bus.
+    MmioWrite {
+        /// Address of the access.
+        addr: MmioAddress,
+        /// Data to write.
+        data: Vec<u8>,
+    },
+    /// Register a new device on the PIO bus.
+    RegisterPio(PioRange),
+    /// Deregister the device found at an address of the PIO bus.
+    DeregisterPio(PioAddress),
+    /// Read `len` bytes from the PIO bus.
+    PioRead {
+        /// Address of the access.
+        addr: PioAddress,
+        /// Length of the access.
+        len: usize,
+    },
+    /// Write to the PIO bus.
+    PioWrite {
+        /// Address of the access.
+        addr: PioAddress,
+        /// Data to write.
+        data: Vec<u8>,
+    },
+}
+
+// Return an address within `0..=max`, usually close to one of the ends of the address space.
+fn address(u: &mut Unstructured<'_>, max: u64) -> arbitrary::Result<u64> {
+    Ok(match u.int_in_range(0..=3)? {
+        0 => u.int_in_range(0..=0x100)?,
+        1 => max - u.int_in_range(0..=0x100)?,
+        2 => (max / 2).saturating_sub(0x80) + u.int_in_range(0..=0x100)?,
+        _ => u.int_in_range(0..=max)?,
+    })
+}
+
+// Return a valid `(base, size)` range within `0..=max`.
+fn range(u: &mut Unstructured<'_>, max: u64) -> arbitrary::Result<(u64, u64)> {
+    let size = if u.ratio(7, 8)? {
+        u.int_in_range(1..=0x40)?
+    } else {
+        u.int_in_range(1..=max)?
+    };
+    let base = std::cmp::min(address(u, max)?, max - (size - 1));
+    Ok((base, size))
+}
+
+fn access_len(u: &mut Unstructured<'_>) -> arbitrary::Result<usize> {
+    u.int_in_range(0..=MAX_ACCESS_LEN)
+}
+
+fn access_data(u: &mut Unstructured<'_>) -> arbitrary::Result<Vec<u8>> {
+    let len = access_len(u)?;
+    (0..len).map(|_| u.arbitrary()).collect()
+}
+
+impl<'a> Arbitrary<'a> for Operation {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        let mmio_max = MmioAddressOffset::MAX;
+        let pio_max = u64::from(PioAddressOffset::MAX);
+        Ok(match u.int_in_range(0..=7)? {
+            0 => {
+                let (base, size) = range(u, mmio_max)?;
+                Operation::RegisterMmio(MmioRange::new(MmioAddress(base), size).unwrap())
+            }
+            1 => Operation::DeregisterMmio(MmioAddress(address(u, mmio_max)?)),
+            2 => Operation::MmioRead {
+                addr: MmioAddress(address(u, mmio_max)?),
+                len: access_len(u)?,
+            },
+            3 => Operation::MmioWrite {
+                addr: MmioAddress(address(u, mmio_max)?),
+                data: access_data(u)?,
+            },
+            // The values below fit in the PIO address space, so the casts never truncate.
+            4 => {
+                let (base, size) = range(u, pio_max)?;
+                Operation::RegisterPio(PioRange::new(PioAddress(base as u16), size as u16).unwrap())
+            }
+            5 => Operation::DeregisterPio(PioAddress(address(u, pio_max)? as u16)),
+            6 => Operation::PioRead {
+                addr: PioAddress(address(u, pio_max)? as u16),
+                len: access_len(u)?,
+            },
+            _ => Operation::PioWrite {
+                addr: PioAddress(address(u, pio_max)? as u16),
+                data: access_data(u)?,
+            },
+        })
+    }
+}
+
+/// Decode `data` into a sequence of operations.
+///
+/// Decoding stops when the input is exhausted, so every input yields a (possibly empty)
+/// sequence.
+pub fn decode(data: &[u8]) -> Vec<Operation> {
+    let mut u = Unstructured::new(data);
+    let mut operations = Vec::new();
+    while !u.is_empty() {
+        match Operation::arbitrary(&mut u) {
+            Ok(operation) => operations.push(operation),
+            Err(_) => break,
+        }
+    }
+    operations
+}
+
+/// Decode `data` and apply the resulting operations to a fresh [`Harness`].
+///
+/// Panics if any of the invariants checked by the harness is violated.
+pub fn run(data: &[u8]) {
+    let mut harness = Harness::new();
+    for operation in decode(data) {
+        harness.apply(&operation);
+    }
+}
+
+// A device remembering the last access it handled as `(base, offset, len)`, and answering
+// reads with a pattern derived from its identifier.
+struct Probe {
+    id: u8,
+    last: Mutex<Option<(u64, u64, usize)>>,
+}
+
+impl Probe {
+    fn record(&self, base: u64, offset: u64, len: usize) {
+        *self.last.lock().unwrap() = Some((base, offset, len));
+    }
+
+    fn take(&self) -> Option<(u64, u64, usize)> {
+        self.last.lock().unwrap().take()
+    }
+}
+
+impl DeviceMmio for Probe {
+    fn mmio_read(&self, base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+        data.iter_mut().for_each(|byte| *byte = self.id);
+        self.record(base.0, offset, data.len());
+    }
+
+    fn mmio_write(&self, base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+        self.record(base.0, offset, data.len());
+    }
+}
+
+impl DevicePio for Probe {
+    fn pio_read(&self, base: PioAddress, offset: PioAddressOffset, data: &mut [u8]) {
+        data.iter_mut().for_each(|byte| *byte = self.id);
+        self.record(u64::from(base.0), u64::from(offset), data.len());
+    }
+
+    fn pio_write(&self, base: PioAddress, offset: PioAddressOffset, data: &[u8]) {
+        self.record(u64::from(base.0), u64::from(offset), data.len());
+    }
+}
+
+// The reference model of a bus, mapping the base of each registered range to its last
+// address and device. Both address spaces are represented with `u64` values.
+struct Model {
+    max: u64,
+    ranges: BTreeMap<u64, (u64, Arc<Probe>)>,
+}
+
+impl Model {
+    fn new(max: u64) -> Self {
+        Model {
+            max,
+            ranges: BTreeMap::new(),
+        }
+    }
+
+    fn overlaps(&self, base: u64, last: u64) -> bool {
+        self.ranges
+            .iter()
+            .any(|(&other_base, &(other_last, _))| base <= other_last && other_base <= last)
+    }
+
+    fn find(&self, addr: u64) -> Option<(u64, u64, &Arc<Probe>)> {
+        self.ranges
+            .range(..=addr)
+            .next_back()
+            .filter(|(_, (last, _))| *last >= addr)
+            .map(|(&base, (last, probe))| (base, *last, probe))
+    }
+
+    // Return the range and device expected to handle an access, if any.
+    fn target(&self, addr: u64, len: usize) -> Option<(u64, &Arc<Probe>)> {
+        let len = u64::try_from(len).ok().filter(|&len| len > 0)?;
+        let end = addr.checked_add(len - 1).filter(|&end| end <= self.max)?;
+        self.find(addr)
+            .filter(|(_, last, _)| *last >= end)
+            .map(|(base, _, probe)| (base, probe))
+    }
+
+    // Check that the outcome of an access matches the model, and that only the expected
+    // device (if any) handled it.
+    fn check_access(&self, addr: u64, data: Option<&[u8]>, len: usize, ok: bool) {
+        let target = self.target(addr, len);
+        assert_eq!(
+            ok,
+            target.is_some(),
+            "unexpected outcome for an access of {} bytes at {:#x}",
+            len,
+            addr
+        );
+        if let Some((base, probe)) = target {
+            assert_eq!(probe.take(), Some((base, addr - base, len)));
+            if let Some(data) = data {
+                assert!(data.iter().all(|&byte| byte == probe.id));
+            }
+        }
+        for (_, probe) in self.ranges.values() {
+            assert_eq!(probe.take(), None, "access dispatched to the wrong device");
+        }
+    }
+
+    fn check_register(&mut self, base: u64, size: u64, probe: Arc<Probe>, ok: bool) {
+        let last = base + (size - 1);
+        assert_eq!(
+            ok,
+            !self.overlaps(base, last),
+            "unexpected outcome when registering [{:#x}, {:#x}]",
+            base,
+            last
+        );
+        if ok {
+            self.ranges.insert(base, (last, probe));
+        }
+    }
+
+    fn check_deregister(&mut self, addr: u64, removed: Option<u64>) {
+        let expected = self.find(addr).map(|(base, _, _)| base);
+        assert_eq!(
+            removed, expected,
+            "unexpected range deregistered at {:#x}",
+            addr
+        );
+        if let Some(base) = expected {
+            self.ranges.remove(&base);
+        }
+    }
+
+    // Check that the ranges registered on `bus` match the model, and that looking up
+    // either end of each range finds it.
+    fn check_bus<F>(&self, ranges: Vec<(u64, u64)>, lookup: F)
+    where
+        F: Fn(u64) -> Option<(u64, u64)>,
+    {
+        let expected = self
+            .ranges
+            .iter()
+            .map(|(&base, &(last, _))| (base, last))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ranges, expected,
+            "registered ranges diverged from the model"
+        );
+        for pair in ranges.windows(2) {
+            assert!(pair[0].1 < pair[1].0, "overlapping ranges registered");
+        }
+        for &(base, last) in ranges.iter() {
+            assert_eq!(lookup(base), Some((base, last)));
+            assert_eq!(lookup(last), Some((base, last)));
+        }
+    }
+}
+
+/// Applies [`Operation`]s to an [`IoManager`] and checks the manager's behavior against a
+/// reference model.
+pub struct Harness {
+    manager: IoManager,
+    mmio: Model,
+    pio: Model,
+    next_id: u8,
+}
+
+impl Default for Harness {
+    fn default() -> Self {
+        Harness {
+            manager: IoManager::new(),
+            mmio: Model::new(MmioAddressOffset::MAX),
+            pio: Model::new(u64::from(PioAddressOffset::MAX)),
+            next_id: 0,
+        }
+    }
+}
+
+impl Harness {
+    /// Create a harness around an empty [`IoManager`].
+    pub fn new() -> Self {
+        Harness::default()
+    }
+
+    /// Return the manager operations are applied to.
+    pub fn manager(&self) -> &IoManager {
+        &self.manager
+    }
+
+    /// Apply `operation` to the manager, then check the invariants.
+    ///
+    /// Panics if the outcome of the operation, or the state of the manager afterwards,
+    /// diverges from the reference model.
+    pub fn apply(&mut self, operation: &Operation) {
+        match operation {
+            Operation::RegisterMmio(range) => {
+                let probe = self.probe();
+                let ok = self.manager.register_mmio(*range, probe.clone()).is_ok();
+                self.mmio
+                    .check_register(range.base().0, range.size(), probe, ok);
+            }
+            Operation::DeregisterMmio(addr) => {
+                let removed = self.manager.deregister_mmio(*addr);
+                self.mmio
+                    .check_deregister(addr.0, removed.map(|(range, _)| range.base().0));
+            }
+            Operation::MmioRead { addr, len } => {
+                let mut data = vec![0; *len];
+                let ok = self.manager.mmio_read(*addr, &mut data).is_ok();
+                self.mmio.check_access(addr.0, Some(&data), *len, ok);
+            }
+            Operation::MmioWrite { addr, data } => {
+                let ok = self.manager.mmio_write(*addr, data).is_ok();
+                self.mmio.check_access(addr.0, None, data.len(), ok);
+            }
+            Operation::RegisterPio(range) => {
+                let probe = self.probe();
+                let ok = self.manager.register_pio(*range, probe.clone()).is_ok();
+                self.pio.check_register(
+                    u64::from(range.base().0),
+                    u64::from(range.size()),
+                    probe,
+                    ok,
+                );
+            }
+            Operation::DeregisterPio(addr) => {
+                let removed = self.manager.deregister_pio(*addr);
+                self.pio.check_deregister(
+                    u64::from(addr.0),
+                    removed.map(|(range, _)| u64::from(range.base().0)),
+                );
+            }
+            Operation::PioRead { addr, len } => {
+                let mut data = vec![0; *len];
+                let ok = self.manager.pio_read(*addr, &mut data).is_ok();
+                self.pio
+                    .check_access(u64::from(addr.0), Some(&data), *len, ok);
+            }
+            Operation::PioWrite { addr, data } => {
+                let ok = self.manager.pio_write(*addr, data).is_ok();
+                self.pio
+                    .check_access(u64::from(addr.0), None, data.len(), ok);
+            }
+        }
+        self.check_invariants();
+    }
+
+    /// Check that the ranges registered with the manager match the reference model, never
+    /// overlap, and can be looked up by any of their ends.
+    pub fn check_invariants(&self) {
+        let mmio_bus: &bus::MmioBus<_> = self.manager.bus();
+        self.mmio.check_bus(
+            mmio_bus
+                .iter()
+                .map(|(range, _)| (range.base().0, range.last().0))
+                .collect(),
+            |addr| {
+                self.manager
+                    .mmio_device(MmioAddress(addr))
+                    .map(|(range, _)| (range.base().0, range.last().0))
+            },
+        );
+
+        let pio_bus: &bus::PioBus<_> = self.manager.bus();
+        self.pio.check_bus(
+            pio_bus
+                .iter()
+                .map(|(range, _)| (u64::from(range.base().0), u64::from(range.last().0)))
+                .collect(),
+            |addr| {
+                self.manager
+                    .pio_device(PioAddress(addr as u16))
+                    .map(|(range, _)| (u64::from(range.base().0), u64::from(range.last().0)))
+            },
+        );
+    }
+
+    fn probe(&mut self) -> Arc<Probe> {
+        self.next_id = self.next_id.wrapping_add(1);
+        Arc::new(Probe {
+            id: self.next_id,
+            last: Mutex::new(None),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_harness() {
+        let mut harness = Harness::new();
+        let range = |base, size| MmioRange::new(MmioAddress(base), size).unwrap();
+
+        harness.apply(&Operation::RegisterMmio(range(0x1000, 0x10)));
+        harness.apply(&Operation::RegisterMmio(range(0x1008, 0x10)));
+        harness.apply(&Operation::RegisterMmio(range(0x1010, 0x10)));
+        harness.apply(&Operation::RegisterMmio(range(u64::MAX, 1)));
+        harness.apply(&Operation::MmioRead {
+            addr: MmioAddress(0x100c),
+            len: 8,
+        });
+        harness.apply(&Operation::MmioWrite {
+            addr: MmioAddress(0x100c),
+            data: vec![1, 2, 3, 4],
+        });
+        harness.apply(&Operation::MmioRead {
+            addr: MmioAddress(u64::MAX),
+            len: 2,
+        });
+        harness.apply(&Operation::MmioRead {
+            addr: MmioAddress(0x1000),
+            len: 0,
+        });
+        harness.apply(&Operation::DeregisterMmio(MmioAddress(0x101f)));
+        harness.apply(&Operation::DeregisterMmio(MmioAddress(0x101f)));
+        assert_eq!(
+            harness
+                .manager()
+                .mmio_device(MmioAddress(0x1000))
+                .unwrap()
+                .0
+                .size(),
+            0x10
+        );
+
+        harness.apply(&Operation::RegisterPio(
+            PioRange::new(PioAddress(u16::MAX - 3), 4).unwrap(),
+        ));
+        harness.apply(&Operation::PioRead {
+            addr: PioAddress(u16::MAX - 1),
+            len: 2,
+        });
+        harness.apply(&Operation::PioWrite {
+            addr: PioAddress(u16::MAX - 1),
+            data: vec![0; 3],
+        });
+    }
+
+    #[test]
+    fn test_run() {
+        run(&[]);
+        run(&[0xff; 256]);
+
+        // A simple xorshift generator, so the input is reproducible.
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let data = (0..0x4000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect::<Vec<_>>();
+        assert!(decode(&data).len() > 100);
+        run(&data);
+    }
+}
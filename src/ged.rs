@@ -0,0 +1,303 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! ACPI Generic Event Device notifying the guest about hotplugged devices.
+//!
+//! virtio-mmio has no hotplug mechanism of its own, so guests relying on ACPI (e.g. x86
+//! guests) learn about devices added or removed at runtime through a Generic Event Device:
+//! the VMM latches the event, raises the GED interrupt, and the `_EVT` method of the GED in
+//! the DSDT reads the [`GedDevice`] registers to find out which slots changed, then notifies
+//! the matching device objects.
+//!
+//! All registers are 32 bits wide, and cleared when read:
+//!
+//! | Offset | Register |
+//! |--------|----------|
+//! | `0x0`  | pending events, [`EVENT_MMIO_HOTPLUG`] and the ones passed to [`GedDevice::notify`] |
+//! | `0x4`  | slots with devices added since the last read |
+//! | `0x8`  | slots with devices removed since the last read |
+//!
+//! [`IoManager::hotplug_mmio`] and [`IoManager::hot_unplug_mmio`] update the bus and notify
+//! the guest at once.
+
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+use crate::bus::{MmioAddress, MmioAddressOffset};
+use crate::device_manager::{self, IoManager};
+use crate::interrupt::{self, Interrupt};
+use crate::resources::Resource;
+use crate::trace;
+use crate::DeviceMmio;
+
+/// Event raised when virtio-mmio devices are added or removed.
+pub const EVENT_MMIO_HOTPLUG: u32 = 1 << 4;
+
+/// Offset of the pending events register.
+pub const EVENT_OFFSET: u64 = 0x0;
+
+/// Offset of the added slots register.
+pub const ADDED_OFFSET: u64 = 0x4;
+
+/// Offset of the removed slots register.
+pub const REMOVED_OFFSET: u64 = 0x8;
+
+/// Size of the GED register block.
+pub const GED_SIZE: u64 = 0xc;
+
+/// Number of hotplug slots a GED tracks.
+pub const SLOTS: u32 = 32;
+
+/// Errors encountered while hotplugging devices.
+#[derive(Debug)]
+pub enum Error {
+    /// The slot is out of range.
+    InvalidSlot(u32),
+    /// Updating the bus failed.
+    Manager(device_manager::Error),
+    /// Raising the GED interrupt failed.
+    Interrupt(interrupt::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::InvalidSlot(slot) => write!(f, "ged: invalid slot {}", slot),
+            Error::Manager(_) => write!(f, "ged: device registration failed"),
+            Error::Interrupt(_) => write!(f, "ged: cannot raise the interrupt"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Manager(e) => Some(e),
+            Error::Interrupt(e) => Some(e),
+            Error::InvalidSlot(_) => None,
+        }
+    }
+}
+
+/// An ACPI Generic Event Device, raising `interrupt` whenever events are latched.
+pub struct GedDevice<I> {
+    interrupt: I,
+    events: AtomicU32,
+    added: AtomicU32,
+    removed: AtomicU32,
+}
+
+impl<I: Interrupt> GedDevice<I> {
+    /// Create a device without pending events.
+    pub fn new(interrupt: I) -> Self {
+        GedDevice {
+            interrupt,
+            events: AtomicU32::new(0),
+            added: AtomicU32::new(0),
+            removed: AtomicU32::new(0),
+        }
+    }
+
+    /// Latch `events` and raise the interrupt.
+    pub fn notify(&self, events: u32) -> Result<(), interrupt::Error> {
+        self.events.fetch_or(events, Ordering::SeqCst);
+        trace::interrupt("ged", self.interrupt.trigger())
+    }
+
+    /// Record that a device was added to `slot`, and notify the guest.
+    pub fn device_added(&self, slot: u32) -> Result<(), Error> {
+        trace::hotplug(slot, true);
+        self.slot_changed(&self.added, &self.removed, slot)
+    }
+
+    /// Record that the device in `slot` was removed, and notify the guest.
+    pub fn device_removed(&self, slot: u32) -> Result<(), Error> {
+        trace::hotplug(slot, false);
+        self.slot_changed(&self.removed, &self.added, slot)
+    }
+
+    // A slot changing twice before the guest looks only reports the last change.
+    fn slot_changed(&self, set: &AtomicU32, clear: &AtomicU32, slot: u32) -> Result<(), Error> {
+        let bit = 1u32.checked_shl(slot).ok_or(Error::InvalidSlot(slot))?;
+        clear.fetch_and(!bit, Ordering::SeqCst);
+        set.fetch_or(bit, Ordering::SeqCst);
+        self.notify(EVENT_MMIO_HOTPLUG).map_err(Error::Interrupt)
+    }
+
+    fn register(&self, offset: u64) -> Option<&AtomicU32> {
+        match offset {
+            EVENT_OFFSET => Some(&self.events),
+            ADDED_OFFSET => Some(&self.added),
+            REMOVED_OFFSET => Some(&self.removed),
+            _ => None,
+        }
+    }
+}
+
+impl<I: Interrupt> DeviceMmio for GedDevice<I> {
+    fn mmio_read(&self, _base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+        match self.register(offset) {
+            Some(register) if data.len() == 4 => {
+                data.copy_from_slice(&register.swap(0, Ordering::SeqCst).to_le_bytes())
+            }
+            _ => data.fill(0),
+        }
+    }
+
+    fn mmio_write(&self, _base: MmioAddress, _offset: MmioAddressOffset, _data: &[u8]) {}
+
+    fn debug_read(&self, _base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) -> bool {
+        match self.register(offset) {
+            Some(register) if data.len() == 4 => {
+                data.copy_from_slice(&register.load(Ordering::SeqCst).to_le_bytes())
+            }
+            _ => data.fill(0),
+        }
+        true
+    }
+
+    fn introspect(&self) -> Vec<(String, String)> {
+        vec![
+            (
+                "events".to_string(),
+                format!("{:#x}", self.events.load(Ordering::SeqCst)),
+            ),
+            (
+                "added".to_string(),
+                format!("{:#x}", self.added.load(Ordering::SeqCst)),
+            ),
+            (
+                "removed".to_string(),
+                format!("{:#x}", self.removed.load(Ordering::SeqCst)),
+            ),
+        ]
+    }
+}
+
+impl IoManager {
+    /// Register a virtio-mmio device hot-added to `slot`, and notify the guest through `ged`.
+    ///
+    /// If the guest can't be notified, the device is deregistered again.
+    ///
+    /// # Arguments
+    ///
+    /// * `ged`: Generic Event Device of the guest
+    /// * `slot`: hotplug slot of the device, matching its object in the DSDT
+    /// * `device`: device instance object to be registered
+    /// * `resources`: resources that this device owns
+    pub fn hotplug_mmio<I, T>(
+        &mut self,
+        ged: &GedDevice<I>,
+        slot: u32,
+        device: Arc<T>,
+        resources: &[Resource],
+    ) -> Result<(), Error>
+    where
+        I: Interrupt,
+        T: DeviceMmio + Send + Sync + 'static,
+    {
+        if slot >= SLOTS {
+            return Err(Error::InvalidSlot(slot));
+        }
+        let registered = self
+            .register_mmio_ranges(device, resources)
+            .map_err(Error::Manager)?;
+        ged.device_added(slot).inspect_err(|_| {
+            self.deregister_resources(&registered);
+        })
+    }
+
+    /// Deregister the virtio-mmio device in `slot`, and notify the guest through `ged`.
+    /// Returns the number of deregistered ranges.
+    ///
+    /// The device is deregistered even if the guest can't be notified.
+    ///
+    /// # Arguments
+    ///
+    /// * `ged`: Generic Event Device of the guest
+    /// * `slot`: hotplug slot of the device
+    /// * `resources`: resources that this device owns
+    pub fn hot_unplug_mmio<I: Interrupt>(
+        &mut self,
+        ged: &GedDevice<I>,
+        slot: u32,
+        resources: &[Resource],
+    ) -> Result<usize, Error> {
+        if slot >= SLOTS {
+            return Err(Error::InvalidSlot(slot));
+        }
+        let count = self.deregister_resources(resources);
+        ged.device_removed(slot).map(|_| count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::bus::MmioRange;
+    use crate::device_manager::MmioManager;
+    use crate::testing::{MockDevice, MockInterrupt};
+
+    fn read(manager: &IoManager, offset: u64) -> u32 {
+        let mut data = [0; 4];
+        manager
+            .mmio_read(MmioAddress(0x1000 + offset), &mut data)
+            .unwrap();
+        u32::from_le_bytes(data)
+    }
+
+    #[test]
+    fn test_ged_hotplug() {
+        let ged = Arc::new(GedDevice::new(MockInterrupt::new()));
+        let mut manager = IoManager::new();
+        let range = MmioRange::new(MmioAddress(0x1000), GED_SIZE).unwrap();
+        manager.register_mmio(range, ged.clone()).unwrap();
+
+        let resources = [Resource::MmioAddressRange {
+            base: 0xd000_0000,
+            size: 0x200,
+        }];
+        let device = Arc::new(MockDevice::new());
+        manager
+            .hotplug_mmio(&ged, 3, device.clone(), &resources)
+            .unwrap();
+        ged.interrupt.expect_triggered(1);
+        // Debug reads don't clear the registers.
+        let mut data = [0; 4];
+        assert!(manager
+            .mmio_debug_read(MmioAddress(0x1000), &mut data)
+            .unwrap());
+        assert_eq!(u32::from_le_bytes(data), EVENT_MMIO_HOTPLUG);
+        assert_eq!(read(&manager, EVENT_OFFSET), EVENT_MMIO_HOTPLUG);
+        assert_eq!(read(&manager, EVENT_OFFSET), 0);
+        assert_eq!(read(&manager, ADDED_OFFSET), 1 << 3);
+        assert_eq!(read(&manager, REMOVED_OFFSET), 0);
+
+        // The range is taken, and nothing is reported for the second device.
+        assert!(matches!(
+            manager.hotplug_mmio(&ged, 4, device, &resources),
+            Err(Error::Manager(_))
+        ));
+        assert!(matches!(
+            manager.hotplug_mmio(&ged, SLOTS, Arc::new(MockDevice::new()), &[]),
+            Err(Error::InvalidSlot(SLOTS))
+        ));
+        ged.interrupt.expect_triggered(1);
+
+        assert_eq!(manager.hot_unplug_mmio(&ged, 3, &resources).unwrap(), 1);
+        ged.interrupt.expect_triggered(2);
+        ged.notify(1).unwrap();
+        assert_eq!(read(&manager, EVENT_OFFSET), EVENT_MMIO_HOTPLUG | 1);
+        assert_eq!(read(&manager, ADDED_OFFSET), 0);
+        assert_eq!(read(&manager, REMOVED_OFFSET), 1 << 3);
+
+        // Narrow reads don't clear the registers.
+        ged.device_added(0).unwrap();
+        let mut data = [0xff; 2];
+        manager.mmio_read(MmioAddress(0x1004), &mut data).unwrap();
+        assert_eq!(data, [0; 2]);
+        assert_eq!(read(&manager, ADDED_OFFSET), 1);
+    }
+}
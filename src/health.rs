@@ -0,0 +1,298 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Health checks of the devices hosted by a manager.
+//!
+//! Devices relying on a backend, e.g. a socket or a worker thread, report whether it still
+//! works through the `health` method of the device traits. The VMM polls all the registered
+//! devices with `IoManager::check_health`, which returns the unhealthy ones and publishes
+//! a [failure event](crate::topology::TopologyEvent::Failed) for each of them to the
+//! subscribers of the manager. Wrapping a device in a
+//! [`Quarantined`](crate::quarantine::Quarantined) makes it quarantined as well once it
+//! reports itself unhealthy.
+//!
+//! ```
+//! # use std::sync::atomic::{AtomicBool, Ordering};
+//! # use std::sync::Arc;
+//! use vm_device::bus::{MmioAddress, MmioAddressOffset, MmioRange};
+//! use vm_device::device_manager::{IoManager, MmioManager};
+//! use vm_device::health::Health;
+//! use vm_device::DeviceMmio;
+//!
+//! struct Net {
+//!     connected: AtomicBool,
+//! }
+//!
+//! impl DeviceMmio for Net {
+//!     fn mmio_read(&self, _: MmioAddress, _: MmioAddressOffset, _: &mut [u8]) {}
+//!     fn mmio_write(&self, _: MmioAddress, _: MmioAddressOffset, _: &[u8]) {}
+//!
+//!     fn health(&self) -> Health {
+//!         if self.connected.load(Ordering::Acquire) {
+//!             Health::Healthy
+//!         } else {
+//!             Health::Unhealthy("tap closed".to_string())
+//!         }
+//!     }
+//! }
+//!
+//! let net = Arc::new(Net {
+//!     connected: AtomicBool::new(true),
+//! });
+//! let mut manager = IoManager::new();
+//! let range = MmioRange::new(MmioAddress(0x1000), 0x100).unwrap();
+//! manager.register_mmio(range, net.clone()).unwrap();
+//! assert!(manager.check_health().is_empty());
+//!
+//! net.connected.store(false, Ordering::Release);
+//! let unhealthy = manager.check_health();
+//! assert_eq!(
+//!     unhealthy[0].to_string(),
+//!     "health: mmio device at 0x1000 unhealthy: tap closed"
+//! );
+//! ```
+
+use alloc::string::String;
+use core::fmt::{Display, Formatter};
+
+#[cfg(feature = "std")]
+use std::sync::Arc;
+
+#[cfg(feature = "std")]
+use crate::bus::{Bus, BusAddress, BusManager, MmioAddress, PioAddress};
+#[cfg(feature = "std")]
+use crate::device_manager::{group_ranges, IoManager};
+#[cfg(feature = "std")]
+use crate::topology::TopologyEvent;
+
+/// Whether a device works.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub enum Health {
+    /// The device works.
+    #[default]
+    Healthy,
+    /// The device doesn't work anymore, e.g. its backend is stuck or its file descriptor
+    /// was closed, for the given reason.
+    Unhealthy(String),
+}
+
+impl Health {
+    /// Return whether the device works.
+    pub fn is_healthy(&self) -> bool {
+        matches!(self, Health::Healthy)
+    }
+}
+
+impl Display for Health {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Health::Healthy => write!(f, "healthy"),
+            Health::Unhealthy(reason) => write!(f, "unhealthy: {}", reason),
+        }
+    }
+}
+
+/// A registered device reporting itself unhealthy, returned by `IoManager::check_health`.
+#[cfg(feature = "std")]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct UnhealthyDevice {
+    /// Bus of the device, `"mmio"` or `"pio"`.
+    pub bus: &'static str,
+    /// Base address of the first range of the device.
+    pub base: u64,
+    /// Reason reported by the device.
+    pub reason: String,
+}
+
+#[cfg(feature = "std")]
+impl Display for UnhealthyDevice {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "health: {} device at {:#x} unhealthy: {}",
+            self.bus, self.base, self.reason
+        )
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<&UnhealthyDevice> for TopologyEvent {
+    fn from(device: &UnhealthyDevice) -> Self {
+        TopologyEvent::Failed {
+            bus: device.bus,
+            base: device.base,
+            reason: device.reason.clone(),
+        }
+    }
+}
+
+// Return the devices of `bus` that `health` reports unhealthy.
+#[cfg(feature = "std")]
+fn check<A, D, F>(bus: &Bus<A, Arc<D>>, name: &'static str, health: F) -> Vec<UnhealthyDevice>
+where
+    A: BusAddress,
+    A::V: Into<u64>,
+    D: ?Sized,
+    F: Fn(&D) -> Health,
+{
+    group_ranges(bus)
+        .into_iter()
+        .filter_map(|(device, ranges)| match health(device) {
+            Health::Healthy => None,
+            Health::Unhealthy(reason) => Some(UnhealthyDevice {
+                bus: name,
+                base: ranges[0].base().value().into(),
+                reason,
+            }),
+        })
+        .collect()
+}
+
+#[cfg(feature = "std")]
+impl IoManager {
+    /// Ask all the registered devices for their health (see
+    /// [`DeviceMmio::health`](crate::DeviceMmio::health) and
+    /// [`DevicePio::health`](crate::DevicePio::health)), and return the unhealthy ones, PIO devices first. Devices
+    /// registered with several ranges are asked once.
+    ///
+    /// A [`TopologyEvent::Failed`] is published for each unhealthy device (see
+    /// [`IoManager::subscribe`]), at every check until the device recovers.
+    pub fn check_health(&self) -> Vec<UnhealthyDevice> {
+        let mut unhealthy = check(BusManager::<PioAddress>::bus(self), "pio", |device| {
+            device.health()
+        });
+        unhealthy.extend(check(
+            BusManager::<MmioAddress>::bus(self),
+            "mmio",
+            |device| device.health(),
+        ));
+        for device in unhealthy.iter() {
+            self.topology().publish(device.into());
+        }
+        unhealthy
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use crate::bus::{MmioAddressOffset, PioAddressOffset};
+    use crate::device_manager::MmioManager;
+    use crate::quarantine::Quarantined;
+    use crate::resources::Resource;
+    use crate::{DeviceMmio, DevicePio};
+
+    // Stands for a device whose backend may go away.
+    #[derive(Default)]
+    struct Backend {
+        stuck: AtomicBool,
+    }
+
+    impl DeviceMmio for Backend {
+        fn mmio_read(&self, _base: MmioAddress, _offset: MmioAddressOffset, data: &mut [u8]) {
+            data.fill(1);
+        }
+
+        fn mmio_write(&self, _base: MmioAddress, _offset: MmioAddressOffset, _data: &[u8]) {}
+
+        fn health(&self) -> Health {
+            if self.stuck.load(Ordering::Acquire) {
+                Health::Unhealthy("backend stuck".to_string())
+            } else {
+                Health::Healthy
+            }
+        }
+    }
+
+    impl DevicePio for Backend {
+        fn pio_read(&self, _base: PioAddress, _offset: PioAddressOffset, _data: &mut [u8]) {}
+
+        fn pio_write(&self, _base: PioAddress, _offset: PioAddressOffset, _data: &[u8]) {}
+
+        fn health(&self) -> Health {
+            DeviceMmio::health(self)
+        }
+    }
+
+    #[test]
+    fn test_check_health() {
+        let mut manager = IoManager::new();
+        let events = manager.subscribe();
+        let mmio = Arc::new(Quarantined::new(Backend::default()));
+        manager
+            .register_mmio_resources(
+                mmio.clone(),
+                &[
+                    Resource::MmioAddressRange {
+                        base: 0x1000,
+                        size: 0x10,
+                    },
+                    Resource::MmioAddressRange {
+                        base: 0x2000,
+                        size: 0x10,
+                    },
+                ],
+            )
+            .unwrap();
+        let pio = Arc::new(Backend::default());
+        manager
+            .register_pio_resources(
+                pio.clone(),
+                &[Resource::PioAddressRange {
+                    base: 0x60,
+                    size: 4,
+                }],
+            )
+            .unwrap();
+        let mut data = [0; 4];
+        assert!(manager.check_health().is_empty());
+
+        mmio.inner().stuck.store(true, Ordering::Release);
+        pio.stuck.store(true, Ordering::Release);
+        let unhealthy = manager.check_health();
+        let reason = "backend stuck".to_string();
+        assert_eq!(
+            unhealthy,
+            [
+                UnhealthyDevice {
+                    bus: "pio",
+                    base: 0x60,
+                    reason: reason.clone(),
+                },
+                UnhealthyDevice {
+                    bus: "mmio",
+                    base: 0x1000,
+                    reason: reason.clone(),
+                },
+            ]
+        );
+        let received: Vec<_> = std::iter::from_fn(|| events.try_recv())
+            .filter(|event| matches!(event, TopologyEvent::Failed { .. }))
+            .collect();
+        assert_eq!(
+            received,
+            unhealthy
+                .iter()
+                .map(TopologyEvent::from)
+                .collect::<Vec<_>>()
+        );
+
+        // The unhealthy device is quarantined until released.
+        assert!(mmio.is_quarantined());
+        manager.mmio_read(MmioAddress(0x2000), &mut data).unwrap();
+        assert_eq!(data, [0xff; 4]);
+        mmio.inner().stuck.store(false, Ordering::Release);
+        assert_eq!(
+            DeviceMmio::health(&mmio),
+            Health::Unhealthy("quarantined".to_string())
+        );
+        mmio.release();
+        assert!(DeviceMmio::health(&mmio).is_healthy());
+        manager.mmio_read(MmioAddress(0x2000), &mut data).unwrap();
+        assert_eq!(data, [1; 4]);
+        assert_eq!(manager.check_health().len(), 1);
+    }
+}
@@ -0,0 +1,118 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Identification of the devices hosted by a manager.
+//!
+//! Devices describe themselves by implementing [`DeviceInfo`], and hand a [`DeviceDetails`]
+//! snapshot of it to the manager through the `info` method of the device traits. The VMM
+//! then queries the devices with [`MmioManager::mmio_info`] and [`PioManager::pio_info`] to
+//! enumerate them, generate the FDT or ACPI tables, or log about them, and the details end
+//! up in the manifests exported with `IoManager::export_manifest` as well.
+//!
+//! ```
+//! # use std::sync::{Arc, Mutex};
+//! use vm_device::bus::{MmioAddress, MmioAddressOffset, MmioRange};
+//! use vm_device::device_manager::{IoManager, MmioManager};
+//! use vm_device::info::{DeviceDetails, DeviceInfo};
+//! use vm_device::MutDeviceMmio;
+//!
+//! struct Uart;
+//!
+//! impl DeviceInfo for Uart {
+//!     fn name(&self) -> String {
+//!         "uart0".to_string()
+//!     }
+//!
+//!     fn device_type(&self) -> String {
+//!         "pl011".to_string()
+//!     }
+//! }
+//!
+//! impl MutDeviceMmio for Uart {
+//!     # fn mmio_read(&mut self, _: MmioAddress, _: MmioAddressOffset, _: &mut [u8]) {}
+//!     # fn mmio_write(&mut self, _: MmioAddress, _: MmioAddressOffset, _: &[u8]) {}
+//!     // ...
+//!
+//!     fn info(&self) -> Option<DeviceDetails> {
+//!         Some(DeviceDetails::of(self))
+//!     }
+//! }
+//!
+//! let mut manager = IoManager::new();
+//! let range = MmioRange::new(MmioAddress(0x900_0000), 0x1000).unwrap();
+//! manager.register_mmio(range, Arc::new(Mutex::new(Uart))).unwrap();
+//! let info = manager.mmio_info(MmioAddress(0x900_0000)).unwrap();
+//! assert_eq!(info.device_type, "pl011");
+//! ```
+//!
+//! [`MmioManager::mmio_info`]: crate::device_manager::MmioManager::mmio_info
+//! [`PioManager::pio_info`]: crate::device_manager::PioManager::pio_info
+
+use alloc::string::String;
+
+/// Describes what a device is.
+pub trait DeviceInfo {
+    /// Name of the device instance, e.g. `uart0`.
+    fn name(&self) -> String;
+
+    /// Type of the device, e.g. `pl011` or `virtio-mmio`.
+    fn device_type(&self) -> String;
+
+    /// Device ID of the virtio device, for virtio transports.
+    ///
+    /// The default implementation returns `None`.
+    fn virtio_device_id(&self) -> Option<u32> {
+        None
+    }
+
+    /// Version of the device model, e.g. the version of a register interface.
+    ///
+    /// The default implementation returns `None`.
+    fn version(&self) -> Option<u32> {
+        None
+    }
+}
+
+/// A snapshot of the [`DeviceInfo`] of a device.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DeviceDetails {
+    /// Name of the device instance.
+    pub name: String,
+    /// Type of the device.
+    pub device_type: String,
+    /// Device ID of the virtio device, for virtio transports.
+    pub virtio_device_id: Option<u32>,
+    /// Version of the device model.
+    pub version: Option<u32>,
+}
+
+impl DeviceDetails {
+    /// Take a snapshot of `info`.
+    pub fn of<I: DeviceInfo + ?Sized>(info: &I) -> Self {
+        DeviceDetails {
+            name: info.name(),
+            device_type: info.device_type(),
+            virtio_device_id: info.virtio_device_id(),
+            version: info.version(),
+        }
+    }
+}
+
+impl DeviceInfo for DeviceDetails {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn device_type(&self) -> String {
+        self.device_type.clone()
+    }
+
+    fn virtio_device_id(&self) -> Option<u32> {
+        self.virtio_device_id
+    }
+
+    fn version(&self) -> Option<u32> {
+        self.version
+    }
+}
@@ -0,0 +1,51 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Abstractions for the interrupts devices raise towards the guest.
+//!
+//! Devices only need to know how to trigger their interrupts, while the VMM decides how
+//! interrupts are delivered (e.g. through an `irqfd`, or by injecting an MSI message), and
+//! provides the matching [`Interrupt`] implementations.
+
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::sync::Arc;
+
+/// Errors encountered while operating interrupts.
+#[derive(Debug)]
+pub enum Error {
+    /// The interrupt does not support the requested operation.
+    OperationNotSupported,
+    /// The backend delivering the interrupt failed.
+    Backend(io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::OperationNotSupported => write!(f, "interrupt: operation not supported"),
+            Error::Backend(_) => write!(f, "interrupt: backend error"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Backend(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// An interrupt a device can raise towards the guest.
+pub trait Interrupt {
+    /// Inject the interrupt into the guest.
+    fn trigger(&self) -> Result<(), Error>;
+}
+
+impl<T: Interrupt + ?Sized> Interrupt for Arc<T> {
+    fn trigger(&self) -> Result<(), Error> {
+        (**self).trigger()
+    }
+}
@@ -0,0 +1,502 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Wiring of virtio-mmio devices into KVM (`kvm` feature).
+//!
+//! Besides registering its ranges on the MMIO bus, attaching a virtio-mmio device to a KVM
+//! guest takes an ioeventfd for every queue doorbell, so that queue notifications don't exit
+//! to the VMM, and an irqfd for every interrupt the device raises.
+//! [`IoManager::register_virtio_mmio_kvm`] does all of it at once, and undoes what it did
+//! if any step fails.
+//!
+//! Writes to registers without side effects, such as a framebuffer or the data registers
+//! read back on the next doorbell, don't need to exit to the VMM one by one: once declared
+//! with [`IoManager::register_coalesced_mmio`], KVM appends them to a ring shared with each
+//! vCPU, which [`IoManager::drain_coalesced_mmio`] dispatches through the batched write
+//! path. The ring must be drained before handling each exit, so that the coalesced writes
+//! reach the devices before the accesses which follow them:
+//!
+//! ```ignore
+//! manager.register_coalesced_mmio(&vm_fd, MmioRange::new(MmioAddress(0xd000_0000), 0x1000)?)?;
+//! let mut buffer = Vec::new();
+//! loop {
+//!     let exit = vcpu_fd.run()?;
+//!     manager.drain_coalesced_mmio(&mut vcpu_fd, &mut buffer)?;
+//!     handle_exit(&manager, exit)?;
+//! }
+//! ```
+//!
+//! The module also implements [`HypervisorExitSource`] for `VcpuExit`, so the exits of KVM
+//! vCPUs can be passed to [`handle_exit`](crate::exit::handle_exit).
+
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
+use std::convert::TryFrom;
+
+use kvm_ioctls::{IoEventAddress, VcpuExit, VcpuFd, VmFd};
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::bus::{BusManager, MmioAddress, MmioRange, PioAddress};
+use crate::device_manager::{self, BatchOutcome, IoManager};
+use crate::exit::{ExitAccess, HypervisorExitSource, IoExit, MmioExit, PioExit};
+use crate::resources::Resource;
+use crate::trace;
+use crate::DeviceMmio;
+
+/// Offset of the `QueueNotify` register in a virtio-mmio transport.
+pub const QUEUE_NOTIFY_OFFSET: u64 = 0x50;
+
+/// Event file descriptors of a virtio-mmio device.
+pub trait VirtioMmioEvents {
+    /// Return the eventfds signalled when the guest notifies each queue, by queue index.
+    fn queue_events(&self) -> Vec<&EventFd>;
+
+    /// Return the eventfds the device signals to raise its interrupts, one for each
+    /// `LegacyIrq` resource of the device, in the same order.
+    fn irq_events(&self) -> Vec<&EventFd>;
+}
+
+/// Errors encountered while wiring a device into KVM.
+#[derive(Debug)]
+pub enum Error {
+    /// The device has no MMIO range to put the queue doorbells in.
+    NoMmioRange,
+    /// The device doesn't have an eventfd for each of its interrupts.
+    IrqEvents {
+        /// Number of `LegacyIrq` resources.
+        irqs: usize,
+        /// Number of interrupt eventfds.
+        events: usize,
+    },
+    /// Registering the device on the bus failed.
+    Manager(device_manager::Error),
+    /// The coalesced MMIO zone at the address isn't within a single registered range, or
+    /// spans 4 GiB or more.
+    InvalidZone(u64),
+    /// A KVM ioctl, such as registering an ioeventfd or irqfd, failed.
+    Kvm(kvm_ioctls::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::NoMmioRange => write!(f, "kvm: no MMIO range"),
+            Error::IrqEvents { irqs, events } => write!(
+                f,
+                "kvm: {} interrupt eventfds for {} interrupts",
+                events, irqs
+            ),
+            Error::Manager(_) => write!(f, "kvm: device registration failed"),
+            Error::InvalidZone(base) => {
+                write!(f, "kvm: invalid coalesced MMIO zone at {:#x}", base)
+            }
+            Error::Kvm(_) => write!(f, "kvm: ioctl failed"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Manager(e) => Some(e),
+            Error::Kvm(e) => Some(e),
+            Error::NoMmioRange | Error::IrqEvents { .. } | Error::InvalidZone(_) => None,
+        }
+    }
+}
+
+// Address of the queue doorbell and GSIs of a device with `resources`.
+fn wiring(resources: &[Resource]) -> Result<(IoEventAddress, Vec<u32>), Error> {
+    let base = resources
+        .iter()
+        .find_map(|res| match *res {
+            Resource::MmioAddressRange { base, .. } => Some(base),
+            _ => None,
+        })
+        .ok_or(Error::NoMmioRange)?;
+    let gsis = resources
+        .iter()
+        .filter_map(|res| match *res {
+            Resource::LegacyIrq(gsi) => Some(gsi),
+            _ => None,
+        })
+        .collect();
+    Ok((IoEventAddress::Mmio(base + QUEUE_NOTIFY_OFFSET), gsis))
+}
+
+impl<'a> HypervisorExitSource<'a> for VcpuExit<'a> {
+    fn into_io_exit(self) -> Result<IoExit<'a>, Self> {
+        let mmio = |addr, access| {
+            IoExit::Mmio(MmioExit {
+                addr: MmioAddress(addr),
+                access,
+            })
+        };
+        let pio = |port, access| {
+            IoExit::Pio(PioExit {
+                addr: PioAddress(port),
+                access,
+            })
+        };
+        match self {
+            VcpuExit::MmioRead(addr, data) => Ok(mmio(addr, ExitAccess::Read(data))),
+            VcpuExit::MmioWrite(addr, data) => Ok(mmio(addr, ExitAccess::Write(data))),
+            VcpuExit::IoIn(port, data) => Ok(pio(port, ExitAccess::Read(data))),
+            VcpuExit::IoOut(port, data) => Ok(pio(port, ExitAccess::Write(data))),
+            exit => Err(exit),
+        }
+    }
+}
+
+impl IoManager {
+    /// Register a virtio-mmio device with its allocated resources, and wire its eventfds
+    /// into KVM.
+    ///
+    /// The MMIO ranges of the device are registered on the bus, an ioeventfd matching the
+    /// queue index is installed on the `QueueNotify` register of the first range for every
+    /// queue, and an irqfd is created for every `LegacyIrq` resource. Upon failure,
+    /// everything done so far is undone.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm_fd`: VM the device is attached to
+    /// * `device`: device instance object to be registered
+    /// * `resources`: resources that this device owns
+    pub fn register_virtio_mmio_kvm<T>(
+        &mut self,
+        vm_fd: &VmFd,
+        device: Arc<T>,
+        resources: &[Resource],
+    ) -> Result<(), Error>
+    where
+        T: DeviceMmio + VirtioMmioEvents + Send + Sync + 'static,
+    {
+        let (doorbell, gsis) = wiring(resources)?;
+        let queue_events = device.queue_events();
+        let irq_events = device.irq_events();
+        if irq_events.len() != gsis.len() {
+            return Err(Error::IrqEvents {
+                irqs: gsis.len(),
+                events: irq_events.len(),
+            });
+        }
+
+        let registered = self
+            .register_mmio_ranges(device.clone(), resources)
+            .map_err(Error::Manager)?;
+
+        let mut queues = 0;
+        let mut irqs = 0;
+        let result = queue_events
+            .iter()
+            .try_for_each(|event| {
+                vm_fd.register_ioevent(event, &doorbell, queues as u32)?;
+                queues += 1;
+                Ok(())
+            })
+            .and_then(|_| {
+                irq_events.iter().zip(&gsis).try_for_each(|(event, &gsi)| {
+                    vm_fd.register_irqfd(event, gsi)?;
+                    irqs += 1;
+                    Ok(())
+                })
+            });
+
+        result.map_err(|e| {
+            for (event, &gsi) in irq_events.iter().zip(&gsis).take(irqs) {
+                if let Err(e) = vm_fd.unregister_irqfd(event, gsi) {
+                    trace::cleanup_failed("register_virtio_mmio_kvm", &e);
+                }
+            }
+            for (idx, event) in queue_events.iter().enumerate().take(queues) {
+                if let Err(e) = vm_fd.unregister_ioevent(event, &doorbell, idx as u32) {
+                    trace::cleanup_failed("register_virtio_mmio_kvm", &e);
+                }
+            }
+            self.deregister_resources(&registered);
+            Error::Kvm(e)
+        })
+    }
+
+    /// Undo the wiring performed by [`IoManager::register_virtio_mmio_kvm`], and deregister
+    /// the device. Returns the number of deregistered ranges.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm_fd`: VM the device is attached to
+    /// * `device`: device instance object to be deregistered
+    /// * `resources`: resources that this device owns
+    pub fn deregister_virtio_mmio_kvm<T>(
+        &mut self,
+        vm_fd: &VmFd,
+        device: &T,
+        resources: &[Resource],
+    ) -> usize
+    where
+        T: VirtioMmioEvents + ?Sized,
+    {
+        if let Ok((doorbell, gsis)) = wiring(resources) {
+            for (idx, event) in device.queue_events().iter().enumerate() {
+                if let Err(e) = vm_fd.unregister_ioevent(event, &doorbell, idx as u32) {
+                    trace::cleanup_failed("deregister_virtio_mmio_kvm", &e);
+                }
+            }
+            for (event, gsi) in device.irq_events().iter().zip(gsis) {
+                if let Err(e) = vm_fd.unregister_irqfd(event, gsi) {
+                    trace::cleanup_failed("deregister_virtio_mmio_kvm", &e);
+                }
+            }
+        }
+        self.deregister_resources(resources)
+    }
+
+    /// Declare the writes to `zone` as safe to coalesce, and register it with KVM.
+    ///
+    /// The writes to the zone no longer exit to the VMM, and are only seen by the device
+    /// when [`IoManager::drain_coalesced_mmio`] is called, so the zone must only cover
+    /// registers whose writes have no side effects, and which the guest doesn't expect to
+    /// read back before an exit. Reads still exit as usual. The zone must be within a
+    /// single registered range.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm_fd`: VM the device is attached to
+    /// * `zone`: range of addresses whose writes are coalesced
+    pub fn register_coalesced_mmio(&self, vm_fd: &VmFd, zone: MmioRange) -> Result<(), Error> {
+        let (addr, size) = coalesced_zone(self, zone)?;
+        vm_fd
+            .register_coalesced_mmio(addr, size)
+            .map_err(Error::Kvm)
+    }
+
+    /// Stop coalescing the writes to `zone`, registered with
+    /// [`IoManager::register_coalesced_mmio`].
+    ///
+    /// The writes already in the rings of the vCPUs still have to be drained.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm_fd`: VM the device is attached to
+    /// * `zone`: range of addresses whose writes were coalesced
+    pub fn deregister_coalesced_mmio(&self, vm_fd: &VmFd, zone: MmioRange) -> Result<(), Error> {
+        let size = u32::try_from(zone.size()).map_err(|_| Error::InvalidZone(zone.base().0))?;
+        vm_fd
+            .unregister_coalesced_mmio(IoEventAddress::Mmio(zone.base().0), size)
+            .map_err(Error::Kvm)
+    }
+
+    /// Dispatch the writes coalesced in the ring of `vcpu_fd`, in the order the guest made
+    /// them, with [`IoManager::mmio_write_batch`].
+    ///
+    /// The ring is mapped on first use. Writes whose range was deregistered in the meantime
+    /// are reported as failed in the returned [`BatchOutcome`].
+    ///
+    /// The entries are read from the ring into `buffer`, as (address, length, data)
+    /// records, before being dispatched. The buffer is cleared first, so the same one can be
+    /// passed to every drain of a vCPU, which then doesn't allocate once the buffer has grown
+    /// to the size of the ring.
+    ///
+    /// # Arguments
+    ///
+    /// * `vcpu_fd`: vCPU whose ring is drained
+    /// * `buffer`: scratch space for the entries of the ring
+    pub fn drain_coalesced_mmio(
+        &self,
+        vcpu_fd: &mut VcpuFd,
+        buffer: &mut Vec<(MmioAddress, usize, [u8; 8])>,
+    ) -> Result<BatchOutcome, Error> {
+        vcpu_fd.map_coalesced_mmio_ring().map_err(Error::Kvm)?;
+        buffer.clear();
+        while let Some(entry) = vcpu_fd.coalesced_mmio_read().map_err(Error::Kvm)? {
+            let len = (entry.len as usize).min(entry.data.len());
+            buffer.push((MmioAddress(entry.phys_addr), len, entry.data));
+        }
+        Ok(self.mmio_write_batch(
+            buffer
+                .iter()
+                .map(|(addr, len, data)| (*addr, &data[..*len])),
+        ))
+    }
+}
+
+// Check that `zone` is within a single range registered with `manager`, and return its
+// address and size as KVM expects them.
+fn coalesced_zone(manager: &IoManager, zone: MmioRange) -> Result<(IoEventAddress, u32), Error> {
+    let base = zone.base().0;
+    let size = u32::try_from(zone.size()).map_err(|_| Error::InvalidZone(base))?;
+    BusManager::<MmioAddress>::bus(manager)
+        .check_access(zone.base(), size as usize)
+        .map_err(|_| Error::InvalidZone(base))?;
+    Ok((IoEventAddress::Mmio(base), size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use kvm_ioctls::{Cap, Kvm};
+
+    use crate::bus::{MmioAddressOffset, MmioRange};
+    use crate::device_manager::MmioManager;
+    use crate::exit::handle_exit;
+    use crate::testing::Scratchpad;
+
+    struct Transport {
+        queues: Vec<EventFd>,
+        irqs: Vec<EventFd>,
+    }
+
+    impl Transport {
+        fn new(queues: usize, irqs: usize) -> Self {
+            let event = || EventFd::new(0).unwrap();
+            Transport {
+                queues: (0..queues).map(|_| event()).collect(),
+                irqs: (0..irqs).map(|_| event()).collect(),
+            }
+        }
+    }
+
+    impl DeviceMmio for Transport {
+        fn mmio_read(&self, _base: MmioAddress, _offset: MmioAddressOffset, _data: &mut [u8]) {}
+        fn mmio_write(&self, _base: MmioAddress, _offset: MmioAddressOffset, _data: &[u8]) {}
+    }
+
+    impl VirtioMmioEvents for Transport {
+        fn queue_events(&self) -> Vec<&EventFd> {
+            self.queues.iter().collect()
+        }
+
+        fn irq_events(&self) -> Vec<&EventFd> {
+            self.irqs.iter().collect()
+        }
+    }
+
+    fn resources(base: u64, gsi: u32) -> Vec<Resource> {
+        vec![
+            Resource::MmioAddressRange { base, size: 0x200 },
+            Resource::LegacyIrq(gsi),
+        ]
+    }
+
+    // Open KVM, or return `None` on hosts without `/dev/kvm`, where the tests using it are
+    // skipped.
+    fn kvm() -> Option<Kvm> {
+        Kvm::new().ok()
+    }
+
+    #[test]
+    fn test_register_virtio_mmio_kvm() {
+        let vm = match kvm() {
+            Some(kvm) => kvm.create_vm().unwrap(),
+            None => return,
+        };
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        vm.create_irq_chip().unwrap();
+        let mut manager = IoManager::new();
+        let mut data = [0; 4];
+
+        let device = Arc::new(Transport::new(2, 1));
+        let first = resources(0xd000_0000, 5);
+        manager
+            .register_virtio_mmio_kvm(&vm, device.clone(), &first)
+            .unwrap();
+        manager
+            .mmio_read(MmioAddress(0xd000_0000), &mut data)
+            .unwrap();
+        // The doorbells are already taken.
+        let doorbell = IoEventAddress::Mmio(0xd000_0000 + QUEUE_NOTIFY_OFFSET);
+        assert!(vm
+            .register_ioevent(&device.queues[1], &doorbell, 1u32)
+            .is_err());
+
+        // Reusing the interrupt eventfd for another GSI fails, so the doorbells and the range
+        // of the second device are released again.
+        let mut other = Transport::new(1, 1);
+        other.irqs[0] = device.irqs[0].try_clone().unwrap();
+        let other = Arc::new(other);
+        let second = resources(0xd000_1000, 6);
+        let err = manager
+            .register_virtio_mmio_kvm(&vm, other.clone(), &second)
+            .unwrap_err();
+        assert!(matches!(err, Error::Kvm(_)));
+        assert!(manager
+            .mmio_read(MmioAddress(0xd000_1000), &mut data)
+            .is_err());
+        let doorbell = IoEventAddress::Mmio(0xd000_1000 + QUEUE_NOTIFY_OFFSET);
+        vm.register_ioevent(&other.queues[0], &doorbell, 0u32)
+            .unwrap();
+        vm.unregister_ioevent(&other.queues[0], &doorbell, 0u32)
+            .unwrap();
+
+        let err = manager
+            .register_virtio_mmio_kvm(&vm, Arc::new(Transport::new(1, 0)), &second)
+            .unwrap_err();
+        assert!(matches!(err, Error::IrqEvents { irqs: 1, events: 0 }));
+
+        assert_eq!(manager.deregister_virtio_mmio_kvm(&vm, &*device, &first), 1);
+        assert!(manager
+            .mmio_read(MmioAddress(0xd000_0000), &mut data)
+            .is_err());
+        manager
+            .register_virtio_mmio_kvm(&vm, device, &first)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_coalesced_mmio() {
+        let kvm = match kvm() {
+            Some(kvm) if kvm.check_extension(Cap::CoalescedMmio) => kvm,
+            _ => return,
+        };
+        let vm = kvm.create_vm().unwrap();
+        let mut vcpu = vm.create_vcpu(0).unwrap();
+        let mut manager = IoManager::new();
+        let range = MmioRange::new(MmioAddress(0x1000), 0x100).unwrap();
+        manager
+            .register_mmio(range, Arc::new(Scratchpad::new(0x100)))
+            .unwrap();
+
+        let zone = MmioRange::new(MmioAddress(0x1000), 0x80).unwrap();
+        manager.register_coalesced_mmio(&vm, zone).unwrap();
+        // Zones must be within a registered range.
+        for (base, size) in [(0x1080, 0x100), (0x2000, 0x10)] {
+            let other = MmioRange::new(MmioAddress(base), size).unwrap();
+            let err = manager.register_coalesced_mmio(&vm, other).unwrap_err();
+            assert!(matches!(err, Error::InvalidZone(b) if b == base));
+        }
+
+        // Nothing was written by the guest.
+        let mut buffer = vec![(MmioAddress(0x1000), 4, [0; 8])];
+        let outcome = manager
+            .drain_coalesced_mmio(&mut vcpu, &mut buffer)
+            .unwrap();
+        assert_eq!(outcome, BatchOutcome::default());
+        // Entries left over from a previous drain aren't dispatched again.
+        assert!(buffer.is_empty());
+        manager.deregister_coalesced_mmio(&vm, zone).unwrap();
+    }
+
+    #[test]
+    fn test_vcpu_exit() {
+        let mut manager = IoManager::new();
+        let range = MmioRange::new(MmioAddress(0x1000), 0x10).unwrap();
+        manager
+            .register_mmio(range, Arc::new(Scratchpad::new(0x10)))
+            .unwrap();
+
+        assert!(handle_exit(&manager, VcpuExit::MmioWrite(0x1000, &[7]))
+            .unwrap()
+            .is_none());
+        let mut data = [0; 1];
+        assert!(handle_exit(&manager, VcpuExit::MmioRead(0x1000, &mut data))
+            .unwrap()
+            .is_none());
+        assert_eq!(data, [7]);
+        assert!(handle_exit(&manager, VcpuExit::IoOut(0x60, &[0])).is_err());
+        assert!(matches!(
+            handle_exit(&manager, VcpuExit::Hlt),
+            Ok(Some(VcpuExit::Hlt))
+        ));
+    }
+}
@@ -0,0 +1,307 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Simulation of slow device backends.
+//!
+//! [`Delayed`] wraps a device and stalls the calling thread for a duration drawn from a
+//! [`Latency`] distribution before forwarding each access. This makes it possible to study
+//! how guests and schedulers behave with slow MMIO or PIO without modifying the devices.
+//!
+//! # Example
+//!
+//! ```
+//! # use std::time::Duration;
+//! # use vm_device::bus::{MmioAddress, MmioAddressOffset};
+//! # use vm_device::DeviceMmio;
+//! use vm_device::latency::{Delayed, Latency};
+//!
+//! struct Dummy;
+//!
+//! impl DeviceMmio for Dummy {
+//!     fn mmio_read(&self, _base: MmioAddress, _offset: MmioAddressOffset, _data: &mut [u8]) {}
+//!     fn mmio_write(&self, _base: MmioAddress, _offset: MmioAddressOffset, _data: &[u8]) {}
+//! }
+//!
+//! let device = Delayed::new(
+//!     Dummy,
+//!     Latency::Uniform {
+//!         min: Duration::from_micros(1),
+//!         max: Duration::from_micros(5),
+//!     },
+//! );
+//! device.mmio_write(MmioAddress(0), 0, &[0]);
+//! ```
+
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use crate::bus::{MmioAddress, MmioAddressOffset, PioAddress, PioAddressOffset};
+use crate::completion::Completion;
+use crate::exit::ExitAccess;
+use crate::health::Health;
+use crate::info::DeviceDetails;
+use crate::resources::Resource;
+use crate::stats::Statistics;
+use crate::{DeviceMmio, DevicePio};
+
+/// Distribution of the latency added to each access.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Latency {
+    /// The same latency for every access.
+    Fixed(Duration),
+    /// A latency uniformly distributed between `min` and `max`.
+    Uniform {
+        /// Smallest latency.
+        min: Duration,
+        /// Largest latency.
+        max: Duration,
+    },
+    /// A heavy-tailed latency following a Pareto distribution, capped at `max`.
+    ///
+    /// Most accesses take close to `scale`, while a few take much longer. Smaller `shape`
+    /// values make long latencies more frequent.
+    Pareto {
+        /// Smallest latency.
+        scale: Duration,
+        /// Shape (tail index) of the distribution, which must be positive.
+        shape: f64,
+        /// Largest latency.
+        max: Duration,
+    },
+}
+
+impl Latency {
+    // Map `u`, uniformly distributed in `[0, 1)`, to a latency following the distribution.
+    fn sample(&self, u: f64) -> Duration {
+        match *self {
+            Latency::Fixed(latency) => latency,
+            Latency::Uniform { min, max } => min + max.saturating_sub(min).mul_f64(u),
+            Latency::Pareto { scale, shape, max } => {
+                let factor = (1.0 - u).powf(-1.0 / shape);
+                if factor.is_finite() && scale.as_secs_f64() * factor < max.as_secs_f64() {
+                    scale.mul_f64(factor)
+                } else {
+                    max
+                }
+            }
+        }
+    }
+}
+
+/// A device wrapper delaying every access by a duration drawn from a [`Latency`]
+/// distribution.
+///
+/// The delay is applied by sleeping on the thread dispatching the access, before the access
+/// is forwarded to the wrapped device.
+pub struct Delayed<D> {
+    device: D,
+    latency: Latency,
+    // State of the xorshift generator used to draw latencies.
+    state: Mutex<u64>,
+}
+
+impl<D> Delayed<D> {
+    /// Wrap `device`, delaying its accesses according to `latency`.
+    pub fn new(device: D, latency: Latency) -> Self {
+        Delayed::with_seed(device, latency, 0x9e37_79b9_7f4a_7c15)
+    }
+
+    /// Wrap `device`, drawing latencies from a generator initialized with `seed`.
+    ///
+    /// Wrappers created with the same seed and distribution produce the same sequence of
+    /// latencies.
+    pub fn with_seed(device: D, latency: Latency, seed: u64) -> Self {
+        Delayed {
+            device,
+            latency,
+            // The generator gets stuck on zero.
+            state: Mutex::new(if seed == 0 { 1 } else { seed }),
+        }
+    }
+
+    /// Return the wrapped device.
+    pub fn inner(&self) -> &D {
+        &self.device
+    }
+
+    fn next_latency(&self) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        // Use the top 53 bits, which is all a `f64` mantissa can represent.
+        let u = (*state >> 11) as f64 / (1u64 << 53) as f64;
+        self.latency.sample(u)
+    }
+
+    fn delay(&self) {
+        let latency = self.next_latency();
+        if latency > Duration::from_secs(0) {
+            thread::sleep(latency);
+        }
+    }
+}
+
+impl<D: DeviceMmio> DeviceMmio for Delayed<D> {
+    fn mmio_read(&self, base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+        self.delay();
+        self.device.mmio_read(base, offset, data);
+    }
+
+    fn mmio_write(&self, base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+        self.delay();
+        self.device.mmio_write(base, offset, data);
+    }
+
+    fn introspect(&self) -> Vec<(String, String)> {
+        self.device.introspect()
+    }
+
+    fn info(&self) -> Option<DeviceDetails> {
+        self.device.info()
+    }
+
+    fn health(&self) -> Health {
+        self.device.health()
+    }
+
+    fn stats(&self) -> Option<Statistics> {
+        self.device.stats()
+    }
+
+    fn update_resources(&self, resources: &[Resource]) {
+        self.device.update_resources(resources)
+    }
+
+    fn debug_read(&self, base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) -> bool {
+        self.device.debug_read(base, offset, data)
+    }
+
+    fn mmio_update(
+        &self,
+        base: MmioAddress,
+        offset: MmioAddressOffset,
+        data: &mut [u8],
+        update: &mut dyn FnMut(&mut [u8]),
+    ) {
+        self.delay();
+        self.device.mmio_update(base, offset, data, update);
+    }
+
+    fn mmio_deferred(
+        &self,
+        base: MmioAddress,
+        offset: MmioAddressOffset,
+        access: ExitAccess<'_>,
+    ) -> Completion {
+        self.delay();
+        self.device.mmio_deferred(base, offset, access)
+    }
+}
+
+impl<D: DevicePio> DevicePio for Delayed<D> {
+    fn pio_read(&self, base: PioAddress, offset: PioAddressOffset, data: &mut [u8]) {
+        self.delay();
+        self.device.pio_read(base, offset, data);
+    }
+
+    fn pio_write(&self, base: PioAddress, offset: PioAddressOffset, data: &[u8]) {
+        self.delay();
+        self.device.pio_write(base, offset, data);
+    }
+
+    fn introspect(&self) -> Vec<(String, String)> {
+        self.device.introspect()
+    }
+
+    fn info(&self) -> Option<DeviceDetails> {
+        self.device.info()
+    }
+
+    fn health(&self) -> Health {
+        self.device.health()
+    }
+
+    fn stats(&self) -> Option<Statistics> {
+        self.device.stats()
+    }
+
+    fn debug_read(&self, base: PioAddress, offset: PioAddressOffset, data: &mut [u8]) -> bool {
+        self.device.debug_read(base, offset, data)
+    }
+
+    fn pio_deferred(
+        &self,
+        base: PioAddress,
+        offset: PioAddressOffset,
+        access: ExitAccess<'_>,
+    ) -> Completion {
+        self.delay();
+        self.device.pio_deferred(base, offset, access)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    struct Dummy;
+
+    impl DeviceMmio for Dummy {
+        fn mmio_read(&self, _base: MmioAddress, _offset: MmioAddressOffset, data: &mut [u8]) {
+            data.fill(0xaa);
+        }
+
+        fn mmio_write(&self, _base: MmioAddress, _offset: MmioAddressOffset, _data: &[u8]) {}
+    }
+
+    #[test]
+    fn test_latency_sample() {
+        let ms = Duration::from_millis;
+
+        assert_eq!(Latency::Fixed(ms(3)).sample(0.5), ms(3));
+
+        let uniform = Latency::Uniform {
+            min: ms(10),
+            max: ms(20),
+        };
+        assert_eq!(uniform.sample(0.0), ms(10));
+        assert_eq!(uniform.sample(0.5), ms(15));
+
+        let pareto = Latency::Pareto {
+            scale: ms(10),
+            shape: 1.0,
+            max: ms(100),
+        };
+        assert_eq!(pareto.sample(0.0), ms(10));
+        assert_eq!(pareto.sample(0.5), ms(20));
+        assert_eq!(pareto.sample(0.95), ms(100));
+        assert_eq!(pareto.sample(1.0), ms(100));
+    }
+
+    #[test]
+    fn test_delayed() {
+        let latency = Latency::Uniform {
+            min: Duration::from_micros(100),
+            max: Duration::from_micros(200),
+        };
+        let device = Delayed::new(Dummy, latency);
+        let other = Delayed::new(Dummy, latency);
+        for _ in 0..16 {
+            let latency = device.next_latency();
+            assert!(latency >= Duration::from_micros(100));
+            assert!(latency <= Duration::from_micros(200));
+            assert_eq!(other.next_latency(), latency);
+        }
+
+        let device = Delayed::new(Dummy, Latency::Fixed(Duration::from_millis(2)));
+        let start = Instant::now();
+        let mut data = [0; 2];
+        device.mmio_read(MmioAddress(0), 0, &mut data);
+        device.mmio_write(MmioAddress(0), 0, &data);
+        assert!(start.elapsed() >= Duration::from_millis(4));
+        assert_eq!(data, [0xaa; 2]);
+    }
+}
@@ -2,13 +2,16 @@
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
 #![deny(missing_docs)]
+#![cfg_attr(not(feature = "std"), no_std)]
 
 //! This crate provides:
 //! * device traits defining read and write operations on specialized buses
 //! * device manager (bus-specific traits and a concrete implementation) for
-//! operating devices and dispatching I/O
+//!   operating devices and dispatching I/O
 //! * abstractions for defining resources and their constraints (e.g. a specific bus
-//! address range, IRQ number, etc)
+//!   address range, IRQ number, etc)
+//! * helpers for saving and restoring device state
+//! * an abstraction for the interrupts devices raise towards the guest
 //!
 //! [`MutDevicePio`] and [`MutDeviceMmio`] traits help with composite inner mutability
 //! (i.e. if we have a `Mutex` that holds a `T` which implements [`MutDevicePio`],
@@ -49,15 +52,134 @@
 //!     .unwrap();
 //! manager.pio_write(PioAddress(0), &vec![b'o', b'k']).unwrap();
 //! ```
+//!
+//! # `no_std` support
+//!
+//! The `std` feature is enabled by default. Without it, the crate builds with `no_std` and
+//! `alloc`, and only provides the device traits, the [`bus`] module (except for the types
+//! relying on `std`, such as [`ShardedBus`](bus/struct.ShardedBus.html)), the
+//! [`resources`] module and the [`PioManager`](device_manager/trait.PioManager.html) and
+//! [`MmioManager`](device_manager/trait.MmioManager.html) traits dispatching accesses over
+//! a bus. Buses then lock through [`spin`](https://docs.rs/spin), so the `spin` feature has
+//! to be enabled as well:
+//!
+//! ```toml
+//! vm-device = { version = "0.1", default-features = false, features = ["spin"] }
+//! ```
 
+extern crate alloc;
+
+pub mod access;
 pub mod bus;
+#[cfg(feature = "std")]
+pub mod cache;
+#[cfg(feature = "std")]
+pub mod cmdline;
+#[cfg(feature = "std")]
+pub mod combine;
+#[cfg(feature = "std")]
+pub mod completion;
+#[cfg(feature = "std")]
+pub mod composite;
+#[cfg(feature = "config")]
+pub mod config;
+#[cfg(feature = "device-console")]
+pub mod console;
 pub mod device_manager;
+#[cfg(feature = "std")]
+pub mod dma;
+#[cfg(feature = "std")]
+pub mod doorbell;
+#[cfg(feature = "std")]
+pub mod events;
+pub mod exit;
+#[cfg(feature = "std")]
+pub mod fallible;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "arbitrary")]
+pub mod fuzzing;
+#[cfg(feature = "std")]
+pub mod ged;
+pub mod health;
+pub mod info;
+#[cfg(feature = "std")]
+pub mod interrupt;
+#[cfg(feature = "kvm")]
+pub mod kvm;
+#[cfg(feature = "std")]
+pub mod latency;
+#[cfg(feature = "std")]
+pub mod manifest;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(feature = "gdbstub")]
+pub mod monitor;
+#[cfg(feature = "std")]
+pub mod namespace;
+#[cfg(feature = "pci")]
+pub mod pci;
+#[cfg(feature = "std")]
+pub mod proxy;
+#[cfg(feature = "std")]
+pub mod quarantine;
+#[cfg(feature = "std")]
+pub mod ram;
+#[cfg(feature = "std")]
+pub mod registry;
+#[cfg(all(feature = "std", unix))]
+pub mod remote;
+#[cfg(feature = "std")]
+pub mod replay;
 pub mod resources;
+#[cfg(feature = "device-rng")]
+pub mod rng;
+#[cfg(feature = "std")]
+pub mod snapshot;
+#[cfg(feature = "std")]
+pub mod spec;
+pub mod stats;
+#[cfg(feature = "proptest")]
+pub mod strategies;
+#[cfg(feature = "std")]
+pub mod superio;
+mod sync;
+#[cfg(all(feature = "std", any(test, feature = "test-utils")))]
+pub mod testing;
+#[cfg(feature = "std")]
+pub mod throttle;
+#[cfg(feature = "std")]
+pub mod topology;
+mod trace;
+#[cfg(feature = "std")]
+pub mod trusted;
+#[cfg(all(feature = "std", unix))]
+pub mod vfio;
+#[cfg(feature = "virtio")]
+pub mod virtio;
+#[cfg(all(feature = "std", debug_assertions))]
+pub mod watch;
 
-use std::ops::Deref;
-use std::sync::{Arc, Mutex};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Deref;
+#[cfg(feature = "std")]
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "std")]
+use std::sync::{LockResult, Mutex, RwLock};
 
 use bus::{MmioAddress, MmioAddressOffset, PioAddress, PioAddressOffset};
+#[cfg(feature = "std")]
+use completion::Completion;
+#[cfg(feature = "std")]
+use exit::ExitAccess;
+use health::Health;
+use info::DeviceDetails;
+use resources::Resource;
+use stats::Statistics;
+#[cfg(feature = "derive")]
+pub use vm_device_derive::MmioRegisters;
 
 /// Allows a device to be attached to a
 /// [PIO](https://en.wikipedia.org/wiki/Programmed_input%E2%80%93output) bus.
@@ -105,11 +227,79 @@ pub trait DevicePio {
     /// * `offset`: base address' offset
     /// * `data`:   a buffer provided by the caller holding the data to write
     fn pio_write(&self, base: PioAddress, offset: PioAddressOffset, data: &[u8]);
+
+    /// Describe the current state of the device as key-value pairs, for debugging and
+    /// monitoring purposes (see
+    /// [`IoManager::introspect`](device_manager/struct.IoManager.html#method.introspect)).
+    ///
+    /// The default implementation returns no properties.
+    fn introspect(&self) -> Vec<(String, String)> {
+        Vec::new()
+    }
+
+    /// Describe what the device is, for enumeration, manifests, FDT or ACPI generation and
+    /// logging (see [`info`]).
+    ///
+    /// The default implementation returns `None`.
+    fn info(&self) -> Option<DeviceDetails> {
+        None
+    }
+
+    /// Report whether the device works, e.g. whether its backend is still connected, for
+    /// the VMM polling the devices (see [`health`]).
+    ///
+    /// The default implementation returns [`Health::Healthy`].
+    fn health(&self) -> Health {
+        Health::Healthy
+    }
+
+    /// Report the statistics of the device, for dashboards and monitoring (see [`stats`]).
+    ///
+    /// The default implementation returns `None`.
+    fn stats(&self) -> Option<Statistics> {
+        None
+    }
+
+    /// Read from the device like [`DevicePio::pio_read`], but without side effects (e.g.
+    /// clearing a status register), so debuggers and monitors can inspect the device.
+    ///
+    /// The default implementation returns `false`, meaning the device doesn't support
+    /// debug reads; `data` is left untouched in that case.
+    fn debug_read(&self, _base: PioAddress, _offset: PioAddressOffset, _data: &mut [u8]) -> bool {
+        false
+    }
+
+    /// Start an access which the device may complete asynchronously (see [`completion`]),
+    /// e.g. a write to a status register which connects a backend.
+    ///
+    /// The device either handles the access and returns [`Completion::Done`], or returns
+    /// [`Completion::Pending`] and completes the access later, in which case the value of
+    /// a read is the one passed to the [`Completer`](completion::Completer).
+    ///
+    /// The default implementation handles the access with [`DevicePio::pio_read`] or
+    /// [`DevicePio::pio_write`].
+    #[cfg(feature = "std")]
+    fn pio_deferred(
+        &self,
+        base: PioAddress,
+        offset: PioAddressOffset,
+        access: ExitAccess<'_>,
+    ) -> Completion {
+        match access {
+            ExitAccess::Read(data) => self.pio_read(base, offset, data),
+            ExitAccess::Write(data) => self.pio_write(base, offset, data),
+        }
+        Completion::Done
+    }
 }
 
 /// Allows a device to be attached to a
 /// [MMIO](https://en.wikipedia.org/wiki/Memory-mapped_I/O) bus.
 ///
+/// Accesses carry the raw offset in the range of the device, whatever the device is: a
+/// virtio-mmio transport, a GED, a PL011 UART or a fw_cfg device all implement this trait,
+/// and the buses and managers don't assume any register layout.
+///
 /// # Example
 /// ```
 /// # use std::sync::Mutex;
@@ -153,6 +343,94 @@ pub trait DeviceMmio {
     /// * `offset`: base address' offset
     /// * `data`:   a buffer provided by the caller holding the data to write
     fn mmio_write(&self, base: MmioAddress, offset: MmioAddressOffset, data: &[u8]);
+
+    /// Describe the current state of the device as key-value pairs, for debugging and
+    /// monitoring purposes (see
+    /// [`IoManager::introspect`](device_manager/struct.IoManager.html#method.introspect)).
+    ///
+    /// The default implementation returns no properties.
+    fn introspect(&self) -> Vec<(String, String)> {
+        Vec::new()
+    }
+
+    /// Describe what the device is, for enumeration, manifests, FDT or ACPI generation and
+    /// logging (see [`info`]).
+    ///
+    /// The default implementation returns `None`.
+    fn info(&self) -> Option<DeviceDetails> {
+        None
+    }
+
+    /// Report whether the device works, e.g. whether its backend is still connected, for
+    /// the VMM polling the devices (see [`health`]).
+    ///
+    /// The default implementation returns [`Health::Healthy`].
+    fn health(&self) -> Health {
+        Health::Healthy
+    }
+
+    /// Report the statistics of the device, for dashboards and monitoring (see [`stats`]).
+    ///
+    /// The default implementation returns `None`.
+    fn stats(&self) -> Option<Statistics> {
+        None
+    }
+
+    /// Apply the resources of the device updated with
+    /// [`IoManager::update_resources`](device_manager/struct.IoManager.html#method.update_resources),
+    /// e.g. switch to another interrupt, once its MMIO ranges were moved.
+    ///
+    /// The default implementation ignores them.
+    fn update_resources(&self, _resources: &[Resource]) {}
+
+    /// Read from the device like [`DeviceMmio::mmio_read`], but without side effects (e.g.
+    /// clearing a status register), so debuggers and monitors can inspect the device.
+    ///
+    /// The default implementation returns `false`, meaning the device doesn't support
+    /// debug reads; `data` is left untouched in that case.
+    fn debug_read(&self, _base: MmioAddress, _offset: MmioAddressOffset, _data: &mut [u8]) -> bool {
+        false
+    }
+
+    /// Read from the device into `data`, let `update` modify the value and write it back, as
+    /// a single access with respect to the other accesses to the device.
+    ///
+    /// The default implementation performs the read and the write separately; the blanket
+    /// implementations for the locks wrapping mutable devices hold the lock across both.
+    fn mmio_update(
+        &self,
+        base: MmioAddress,
+        offset: MmioAddressOffset,
+        data: &mut [u8],
+        update: &mut dyn FnMut(&mut [u8]),
+    ) {
+        self.mmio_read(base, offset, data);
+        update(data);
+        self.mmio_write(base, offset, data);
+    }
+
+    /// Start an access which the device may complete asynchronously (see [`completion`]),
+    /// e.g. a write to a status register which connects a backend.
+    ///
+    /// The device either handles the access and returns [`Completion::Done`], or returns
+    /// [`Completion::Pending`] and completes the access later, in which case the value of
+    /// a read is the one passed to the [`Completer`](completion::Completer).
+    ///
+    /// The default implementation handles the access with [`DeviceMmio::mmio_read`] or
+    /// [`DeviceMmio::mmio_write`].
+    #[cfg(feature = "std")]
+    fn mmio_deferred(
+        &self,
+        base: MmioAddress,
+        offset: MmioAddressOffset,
+        access: ExitAccess<'_>,
+    ) -> Completion {
+        match access {
+            ExitAccess::Read(data) => self.mmio_read(base, offset, data),
+            ExitAccess::Write(data) => self.mmio_write(base, offset, data),
+        }
+        Completion::Done
+    }
 }
 
 /// Same as [DevicePio] but the methods are invoked with a mutable self borrow.
@@ -197,6 +475,70 @@ pub trait MutDevicePio {
     /// * `offset`: base address' offset
     /// * `data`:   a buffer provided by the caller holding the data to write
     fn pio_write(&mut self, base: PioAddress, offset: PioAddressOffset, data: &[u8]);
+
+    /// Describe the current state of the device as key-value pairs, for debugging and
+    /// monitoring purposes (see
+    /// [`IoManager::introspect`](device_manager/struct.IoManager.html#method.introspect)).
+    ///
+    /// The default implementation returns no properties.
+    fn introspect(&self) -> Vec<(String, String)> {
+        Vec::new()
+    }
+
+    /// Describe what the device is, for enumeration, manifests, FDT or ACPI generation and
+    /// logging (see [`info`]).
+    ///
+    /// The default implementation returns `None`.
+    fn info(&self) -> Option<DeviceDetails> {
+        None
+    }
+
+    /// Report whether the device works, e.g. whether its backend is still connected, for
+    /// the VMM polling the devices (see [`health`]).
+    ///
+    /// The default implementation returns [`Health::Healthy`].
+    fn health(&self) -> Health {
+        Health::Healthy
+    }
+
+    /// Report the statistics of the device, for dashboards and monitoring (see [`stats`]).
+    ///
+    /// The default implementation returns `None`.
+    fn stats(&self) -> Option<Statistics> {
+        None
+    }
+
+    /// Read from the device like [`MutDevicePio::pio_read`], but without side effects (e.g.
+    /// clearing a status register), so debuggers and monitors can inspect the device.
+    ///
+    /// The default implementation returns `false`, meaning the device doesn't support
+    /// debug reads; `data` is left untouched in that case.
+    fn debug_read(&self, _base: PioAddress, _offset: PioAddressOffset, _data: &mut [u8]) -> bool {
+        false
+    }
+
+    /// Start an access which the device may complete asynchronously (see [`completion`]),
+    /// e.g. a write to a status register which connects a backend.
+    ///
+    /// The device either handles the access and returns [`Completion::Done`], or returns
+    /// [`Completion::Pending`] and completes the access later, in which case the value of
+    /// a read is the one passed to the [`Completer`](completion::Completer).
+    ///
+    /// The default implementation handles the access with [`MutDevicePio::pio_read`] or
+    /// [`MutDevicePio::pio_write`].
+    #[cfg(feature = "std")]
+    fn pio_deferred(
+        &mut self,
+        base: PioAddress,
+        offset: PioAddressOffset,
+        access: ExitAccess<'_>,
+    ) -> Completion {
+        match access {
+            ExitAccess::Read(data) => self.pio_read(base, offset, data),
+            ExitAccess::Write(data) => self.pio_write(base, offset, data),
+        }
+        Completion::Done
+    }
 }
 
 /// Same as [DeviceMmio] but the methods are invoked with a mutable self borrow.
@@ -240,8 +582,88 @@ pub trait MutDeviceMmio {
     /// * `offset`: base address' offset
     /// * `data`:   a buffer provided by the caller holding the data to write
     fn mmio_write(&mut self, base: MmioAddress, offset: MmioAddressOffset, data: &[u8]);
+
+    /// Describe the current state of the device as key-value pairs, for debugging and
+    /// monitoring purposes (see
+    /// [`IoManager::introspect`](device_manager/struct.IoManager.html#method.introspect)).
+    ///
+    /// The default implementation returns no properties.
+    fn introspect(&self) -> Vec<(String, String)> {
+        Vec::new()
+    }
+
+    /// Describe what the device is, for enumeration, manifests, FDT or ACPI generation and
+    /// logging (see [`info`]).
+    ///
+    /// The default implementation returns `None`.
+    fn info(&self) -> Option<DeviceDetails> {
+        None
+    }
+
+    /// Report whether the device works, e.g. whether its backend is still connected, for
+    /// the VMM polling the devices (see [`health`]).
+    ///
+    /// The default implementation returns [`Health::Healthy`].
+    fn health(&self) -> Health {
+        Health::Healthy
+    }
+
+    /// Report the statistics of the device, for dashboards and monitoring (see [`stats`]).
+    ///
+    /// The default implementation returns `None`.
+    fn stats(&self) -> Option<Statistics> {
+        None
+    }
+
+    /// Apply the resources of the device updated with
+    /// [`IoManager::update_resources`](device_manager/struct.IoManager.html#method.update_resources),
+    /// e.g. switch to another interrupt, once its MMIO ranges were moved.
+    ///
+    /// The default implementation ignores them.
+    fn update_resources(&mut self, _resources: &[Resource]) {}
+
+    /// Read from the device like [`MutDeviceMmio::mmio_read`], but without side effects (e.g.
+    /// clearing a status register), so debuggers and monitors can inspect the device.
+    ///
+    /// The default implementation returns `false`, meaning the device doesn't support
+    /// debug reads; `data` is left untouched in that case.
+    fn debug_read(&self, _base: MmioAddress, _offset: MmioAddressOffset, _data: &mut [u8]) -> bool {
+        false
+    }
+
+    /// Start an access which the device may complete asynchronously (see [`completion`]),
+    /// e.g. a write to a status register which connects a backend.
+    ///
+    /// The device either handles the access and returns [`Completion::Done`], or returns
+    /// [`Completion::Pending`] and completes the access later, in which case the value of
+    /// a read is the one passed to the [`Completer`](completion::Completer).
+    ///
+    /// The default implementation handles the access with [`MutDeviceMmio::mmio_read`] or
+    /// [`MutDeviceMmio::mmio_write`].
+    #[cfg(feature = "std")]
+    fn mmio_deferred(
+        &mut self,
+        base: MmioAddress,
+        offset: MmioAddressOffset,
+        access: ExitAccess<'_>,
+    ) -> Completion {
+        match access {
+            ExitAccess::Read(data) => self.mmio_read(base, offset, data),
+            ExitAccess::Write(data) => self.mmio_write(base, offset, data),
+        }
+        Completion::Done
+    }
 }
 
+/// Marks mutable devices whose reads may run concurrently, when wrapped in a `RwLock`.
+///
+/// The `RwLock` wrappers of [`MutDeviceMmio`] and [`MutDevicePio`] devices serve reads with
+/// the read lock held, through `debug_read`, and only take the write lock for the reads
+/// `debug_read` declines and for writes. Implementing this trait asserts that `debug_read`
+/// returns the same data as the regular read whenever it returns `true`, i.e. that it
+/// declines the reads with side effects, such as clearing a status register.
+pub trait SharedReads {}
+
 // Blanket implementations for Arc<T>.
 
 impl<T: DeviceMmio + ?Sized> DeviceMmio for Arc<T> {
@@ -252,6 +674,50 @@ impl<T: DeviceMmio + ?Sized> DeviceMmio for Arc<T> {
     fn mmio_write(&self, base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
         self.deref().mmio_write(base, offset, data);
     }
+
+    fn introspect(&self) -> Vec<(String, String)> {
+        self.deref().introspect()
+    }
+
+    fn info(&self) -> Option<DeviceDetails> {
+        self.deref().info()
+    }
+
+    fn health(&self) -> Health {
+        self.deref().health()
+    }
+
+    fn stats(&self) -> Option<Statistics> {
+        self.deref().stats()
+    }
+
+    fn update_resources(&self, resources: &[Resource]) {
+        self.deref().update_resources(resources)
+    }
+
+    fn debug_read(&self, base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) -> bool {
+        self.deref().debug_read(base, offset, data)
+    }
+
+    fn mmio_update(
+        &self,
+        base: MmioAddress,
+        offset: MmioAddressOffset,
+        data: &mut [u8],
+        update: &mut dyn FnMut(&mut [u8]),
+    ) {
+        self.deref().mmio_update(base, offset, data, update)
+    }
+
+    #[cfg(feature = "std")]
+    fn mmio_deferred(
+        &self,
+        base: MmioAddress,
+        offset: MmioAddressOffset,
+        access: ExitAccess<'_>,
+    ) -> Completion {
+        self.deref().mmio_deferred(base, offset, access)
+    }
 }
 
 impl<T: DevicePio + ?Sized> DevicePio for Arc<T> {
@@ -262,26 +728,518 @@ impl<T: DevicePio + ?Sized> DevicePio for Arc<T> {
     fn pio_write(&self, base: PioAddress, offset: PioAddressOffset, data: &[u8]) {
         self.deref().pio_write(base, offset, data);
     }
-}
 
-// Blanket implementations for Mutex<T>.
+    fn introspect(&self) -> Vec<(String, String)> {
+        self.deref().introspect()
+    }
 
-impl<T: MutDeviceMmio + ?Sized> DeviceMmio for Mutex<T> {
-    fn mmio_read(&self, base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
-        self.lock().unwrap().mmio_read(base, offset, data)
+    fn info(&self) -> Option<DeviceDetails> {
+        self.deref().info()
     }
 
-    fn mmio_write(&self, base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
-        self.lock().unwrap().mmio_write(base, offset, data)
+    fn health(&self) -> Health {
+        self.deref().health()
+    }
+
+    fn stats(&self) -> Option<Statistics> {
+        self.deref().stats()
+    }
+
+    fn debug_read(&self, base: PioAddress, offset: PioAddressOffset, data: &mut [u8]) -> bool {
+        self.deref().debug_read(base, offset, data)
+    }
+
+    #[cfg(feature = "std")]
+    fn pio_deferred(
+        &self,
+        base: PioAddress,
+        offset: PioAddressOffset,
+        access: ExitAccess<'_>,
+    ) -> Completion {
+        self.deref().pio_deferred(base, offset, access)
     }
 }
 
-impl<T: MutDevicePio + ?Sized> DevicePio for Mutex<T> {
-    fn pio_read(&self, base: PioAddress, offset: PioAddressOffset, data: &mut [u8]) {
-        self.lock().unwrap().pio_read(base, offset, data)
+// Blanket implementations for the mutexes wrapping mutable devices. `$lock` takes the
+// mutex `$m` and returns a guard dereferencing to the device.
+#[cfg(any(feature = "std", feature = "parking_lot", feature = "spin"))]
+macro_rules! mutex_device {
+    ($($mutex:ident)::+, |$m:ident| $lock:expr) => {
+        impl<T: MutDeviceMmio + ?Sized> DeviceMmio for $($mutex)::+<T> {
+            fn mmio_read(&self, base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+                let $m = self;
+                $lock.mmio_read(base, offset, data)
+            }
+
+            fn mmio_write(&self, base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+                let $m = self;
+                $lock.mmio_write(base, offset, data)
+            }
+
+            fn introspect(&self) -> Vec<(String, String)> {
+                let $m = self;
+                $lock.introspect()
+            }
+
+            fn info(&self) -> Option<DeviceDetails> {
+                let $m = self;
+                $lock.info()
+            }
+
+            fn health(&self) -> Health {
+                let $m = self;
+                $lock.health()
+            }
+
+            fn stats(&self) -> Option<Statistics> {
+                let $m = self;
+                $lock.stats()
+            }
+
+            fn update_resources(&self, resources: &[Resource]) {
+                let $m = self;
+                $lock.update_resources(resources)
+            }
+
+            fn debug_read(
+                &self,
+                base: MmioAddress,
+                offset: MmioAddressOffset,
+                data: &mut [u8],
+            ) -> bool {
+                let $m = self;
+                $lock.debug_read(base, offset, data)
+            }
+
+            fn mmio_update(
+                &self,
+                base: MmioAddress,
+                offset: MmioAddressOffset,
+                data: &mut [u8],
+                update: &mut dyn FnMut(&mut [u8]),
+            ) {
+                let $m = self;
+                let mut device = $lock;
+                device.mmio_read(base, offset, data);
+                update(data);
+                device.mmio_write(base, offset, data)
+            }
+
+            #[cfg(feature = "std")]
+            fn mmio_deferred(
+                &self,
+                base: MmioAddress,
+                offset: MmioAddressOffset,
+                access: ExitAccess<'_>,
+            ) -> Completion {
+                let $m = self;
+                $lock.mmio_deferred(base, offset, access)
+            }
+        }
+
+        impl<T: MutDevicePio + ?Sized> DevicePio for $($mutex)::+<T> {
+            fn pio_read(&self, base: PioAddress, offset: PioAddressOffset, data: &mut [u8]) {
+                let $m = self;
+                $lock.pio_read(base, offset, data)
+            }
+
+            fn pio_write(&self, base: PioAddress, offset: PioAddressOffset, data: &[u8]) {
+                let $m = self;
+                $lock.pio_write(base, offset, data)
+            }
+
+            fn introspect(&self) -> Vec<(String, String)> {
+                let $m = self;
+                $lock.introspect()
+            }
+
+            fn info(&self) -> Option<DeviceDetails> {
+                let $m = self;
+                $lock.info()
+            }
+
+            fn health(&self) -> Health {
+                let $m = self;
+                $lock.health()
+            }
+
+            fn stats(&self) -> Option<Statistics> {
+                let $m = self;
+                $lock.stats()
+            }
+
+            fn debug_read(
+                &self,
+                base: PioAddress,
+                offset: PioAddressOffset,
+                data: &mut [u8],
+            ) -> bool {
+                let $m = self;
+                $lock.debug_read(base, offset, data)
+            }
+
+            #[cfg(feature = "std")]
+            fn pio_deferred(
+                &self,
+                base: PioAddress,
+                offset: PioAddressOffset,
+                access: ExitAccess<'_>,
+            ) -> Completion {
+                let $m = self;
+                $lock.pio_deferred(base, offset, access)
+            }
+        }
+    };
+}
+
+// Blanket implementations for the reader-writer locks wrapping mutable devices whose reads
+// don't mutate them. `$read` and `$write` take the lock `$l` for reading and writing.
+#[cfg(any(feature = "std", feature = "parking_lot", feature = "spin"))]
+macro_rules! rwlock_device {
+    ($($rwlock:ident)::+, |$l:ident| $read:expr, $write:expr) => {
+        impl<T: MutDeviceMmio + SharedReads + ?Sized> DeviceMmio for $($rwlock)::+<T> {
+            fn mmio_read(&self, base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+                let $l = self;
+                if !$read.debug_read(base, offset, data) {
+                    $write.mmio_read(base, offset, data)
+                }
+            }
+
+            fn mmio_write(&self, base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+                let $l = self;
+                $write.mmio_write(base, offset, data)
+            }
+
+            fn introspect(&self) -> Vec<(String, String)> {
+                let $l = self;
+                $read.introspect()
+            }
+
+            fn info(&self) -> Option<DeviceDetails> {
+                let $l = self;
+                $read.info()
+            }
+
+            fn health(&self) -> Health {
+                let $l = self;
+                $read.health()
+            }
+
+            fn stats(&self) -> Option<Statistics> {
+                let $l = self;
+                $read.stats()
+            }
+
+            fn update_resources(&self, resources: &[Resource]) {
+                let $l = self;
+                $write.update_resources(resources)
+            }
+
+            fn debug_read(
+                &self,
+                base: MmioAddress,
+                offset: MmioAddressOffset,
+                data: &mut [u8],
+            ) -> bool {
+                let $l = self;
+                $read.debug_read(base, offset, data)
+            }
+
+            fn mmio_update(
+                &self,
+                base: MmioAddress,
+                offset: MmioAddressOffset,
+                data: &mut [u8],
+                update: &mut dyn FnMut(&mut [u8]),
+            ) {
+                let $l = self;
+                let mut device = $write;
+                device.mmio_read(base, offset, data);
+                update(data);
+                device.mmio_write(base, offset, data)
+            }
+
+            #[cfg(feature = "std")]
+            fn mmio_deferred(
+                &self,
+                base: MmioAddress,
+                offset: MmioAddressOffset,
+                access: ExitAccess<'_>,
+            ) -> Completion {
+                let $l = self;
+                $write.mmio_deferred(base, offset, access)
+            }
+        }
+
+        impl<T: MutDevicePio + SharedReads + ?Sized> DevicePio for $($rwlock)::+<T> {
+            fn pio_read(&self, base: PioAddress, offset: PioAddressOffset, data: &mut [u8]) {
+                let $l = self;
+                if !$read.debug_read(base, offset, data) {
+                    $write.pio_read(base, offset, data)
+                }
+            }
+
+            fn pio_write(&self, base: PioAddress, offset: PioAddressOffset, data: &[u8]) {
+                let $l = self;
+                $write.pio_write(base, offset, data)
+            }
+
+            fn introspect(&self) -> Vec<(String, String)> {
+                let $l = self;
+                $read.introspect()
+            }
+
+            fn info(&self) -> Option<DeviceDetails> {
+                let $l = self;
+                $read.info()
+            }
+
+            fn health(&self) -> Health {
+                let $l = self;
+                $read.health()
+            }
+
+            fn stats(&self) -> Option<Statistics> {
+                let $l = self;
+                $read.stats()
+            }
+
+            fn debug_read(
+                &self,
+                base: PioAddress,
+                offset: PioAddressOffset,
+                data: &mut [u8],
+            ) -> bool {
+                let $l = self;
+                $read.debug_read(base, offset, data)
+            }
+
+            #[cfg(feature = "std")]
+            fn pio_deferred(
+                &self,
+                base: PioAddress,
+                offset: PioAddressOffset,
+                access: ExitAccess<'_>,
+            ) -> Completion {
+                let $l = self;
+                $write.pio_deferred(base, offset, access)
+            }
+        }
+    };
+}
+
+#[cfg(feature = "std")]
+static POISONED_LOCKS: AtomicU64 = AtomicU64::new(0);
+
+/// Return how many times the standard `Mutex` and `RwLock` wrappers of mutable devices
+/// recovered from a device panicking in the middle of an access.
+///
+/// Such a panic poisons the lock of the device. Instead of failing all the later accesses,
+/// e.g. from other vCPUs, the wrappers clear the poison, count it here, warn about it with
+/// the `log` feature, and keep dispatching accesses to the device, whose state may be
+/// inconsistent. VMMs can poll this counter to find out and, for instance, reset or remove
+/// the device.
+#[cfg(feature = "std")]
+pub fn poisoned_locks() -> u64 {
+    POISONED_LOCKS.load(Ordering::Relaxed)
+}
+
+// Return the guard of `result`, recovering from poisoning with `clear`.
+#[cfg(feature = "std")]
+fn recover<G>(result: LockResult<G>, clear: impl FnOnce()) -> G {
+    result.unwrap_or_else(|e| {
+        POISONED_LOCKS.fetch_add(1, Ordering::Relaxed);
+        trace::poisoned();
+        clear();
+        e.into_inner()
+    })
+}
+
+#[cfg(feature = "std")]
+mutex_device!(Mutex, |m| recover(m.lock(), || m.clear_poison()));
+// Device critical sections are usually tiny, so these avoid the overhead of the standard
+// mutex on contended accesses.
+#[cfg(feature = "parking_lot")]
+mutex_device!(parking_lot::Mutex, |m| m.lock());
+#[cfg(feature = "spin")]
+mutex_device!(spin::Mutex, |m| m.lock());
+
+#[cfg(feature = "std")]
+rwlock_device!(
+    RwLock,
+    |l| recover(l.read(), || l.clear_poison()),
+    recover(l.write(), || l.clear_poison())
+);
+#[cfg(feature = "parking_lot")]
+rwlock_device!(parking_lot::RwLock, |l| l.read(), l.write());
+#[cfg(feature = "spin")]
+rwlock_device!(spin::RwLock, |l| l.read(), l.write());
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Counter(u8);
+
+    impl MutDeviceMmio for Counter {
+        fn mmio_read(&mut self, _base: MmioAddress, _offset: MmioAddressOffset, data: &mut [u8]) {
+            data[0] = self.0;
+        }
+
+        fn mmio_write(&mut self, _base: MmioAddress, _offset: MmioAddressOffset, data: &[u8]) {
+            self.0 += data[0];
+        }
     }
 
-    fn pio_write(&self, base: PioAddress, offset: PioAddressOffset, data: &[u8]) {
-        self.lock().unwrap().pio_write(base, offset, data)
+    impl MutDevicePio for Counter {
+        fn pio_read(&mut self, _base: PioAddress, _offset: PioAddressOffset, data: &mut [u8]) {
+            data[0] = self.0;
+        }
+
+        fn pio_write(&mut self, _base: PioAddress, _offset: PioAddressOffset, data: &[u8]) {
+            self.0 += data[0];
+        }
+    }
+
+    impl SharedReads for Counter {}
+
+    fn check_mutex_device<M: DeviceMmio + DevicePio>(device: M) {
+        let mut data = [0];
+        device.mmio_write(MmioAddress(0), 0, &[2]);
+        device.pio_write(PioAddress(0), 0, &[3]);
+        device.mmio_read(MmioAddress(0), 0, &mut data);
+        assert_eq!(data, [5]);
+        device.pio_read(PioAddress(0), 0, &mut data);
+        assert_eq!(data, [5]);
+    }
+
+    // Device whose register 0 is read concurrently, whereas reading register 1 counts the
+    // reads.
+    #[derive(Default)]
+    struct Registers {
+        value: u8,
+        reads: u8,
+    }
+
+    impl Registers {
+        fn read(&self, offset: u64, data: &mut [u8]) -> bool {
+            match offset {
+                0 => data[0] = self.value,
+                _ => return false,
+            }
+            true
+        }
+    }
+
+    impl MutDeviceMmio for Registers {
+        fn mmio_read(&mut self, _base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+            if !self.read(offset, data) {
+                self.reads += 1;
+                data[0] = self.reads;
+            }
+        }
+
+        fn mmio_write(&mut self, _base: MmioAddress, _offset: MmioAddressOffset, data: &[u8]) {
+            self.value = data[0];
+        }
+
+        fn debug_read(
+            &self,
+            _base: MmioAddress,
+            offset: MmioAddressOffset,
+            data: &mut [u8],
+        ) -> bool {
+            self.read(offset, data)
+        }
+    }
+
+    impl MutDevicePio for Registers {
+        fn pio_read(&mut self, base: PioAddress, offset: PioAddressOffset, data: &mut [u8]) {
+            self.mmio_read(MmioAddress(base.0.into()), offset.into(), data);
+        }
+
+        fn pio_write(&mut self, base: PioAddress, offset: PioAddressOffset, data: &[u8]) {
+            self.mmio_write(MmioAddress(base.0.into()), offset.into(), data);
+        }
+
+        fn debug_read(&self, _base: PioAddress, offset: PioAddressOffset, data: &mut [u8]) -> bool {
+            self.read(offset.into(), data)
+        }
+    }
+
+    impl SharedReads for Registers {}
+
+    // Device panicking on writes.
+    struct Panicking;
+
+    impl MutDeviceMmio for Panicking {
+        fn mmio_read(&mut self, _base: MmioAddress, _offset: MmioAddressOffset, data: &mut [u8]) {
+            data.fill(1);
+        }
+
+        fn mmio_write(&mut self, _base: MmioAddress, _offset: MmioAddressOffset, _data: &[u8]) {
+            panic!("device failure");
+        }
+    }
+
+    impl SharedReads for Panicking {}
+
+    fn check_poisoned_device<D: DeviceMmio + std::panic::RefUnwindSafe>(device: &D) {
+        let poisoned = poisoned_locks();
+        let write = std::panic::catch_unwind(|| device.mmio_write(MmioAddress(0), 0, &[0]));
+        assert!(write.is_err());
+        // The next access recovers the lock, once.
+        let mut data = [0];
+        device.mmio_read(MmioAddress(0), 0, &mut data);
+        device.mmio_read(MmioAddress(0), 0, &mut data);
+        assert_eq!(data, [1]);
+        // Other tests may poison locks concurrently.
+        assert!(poisoned_locks() > poisoned);
+    }
+
+    #[test]
+    fn test_poisoned_device() {
+        let device = Mutex::new(Panicking);
+        check_poisoned_device(&device);
+        assert!(!device.is_poisoned());
+        let device = RwLock::new(Panicking);
+        check_poisoned_device(&device);
+        assert!(!device.is_poisoned());
+    }
+
+    #[test]
+    fn test_rwlock_device() {
+        let device = RwLock::new(Registers::default());
+        let mut data = [0];
+        device.mmio_write(MmioAddress(0), 0, &[7]);
+        // Register 0 is read with the read lock, so other readers may hold it meanwhile.
+        {
+            let _reader = device.read().unwrap();
+            device.mmio_read(MmioAddress(0), 0, &mut data);
+            assert_eq!(data, [7]);
+            device.pio_read(PioAddress(0), 0, &mut data);
+            assert_eq!(data, [7]);
+        }
+        device.mmio_read(MmioAddress(0), 1, &mut data);
+        assert_eq!(data, [1]);
+        device.pio_read(PioAddress(0), 1, &mut data);
+        assert_eq!(data, [2]);
+
+        // Devices without debug reads take the write lock for every access.
+        check_mutex_device(RwLock::new(Counter::default()));
+        #[cfg(feature = "parking_lot")]
+        check_mutex_device(parking_lot::RwLock::new(Counter::default()));
+        #[cfg(feature = "spin")]
+        check_mutex_device(spin::RwLock::new(Counter::default()));
+    }
+
+    #[test]
+    fn test_mutex_device() {
+        check_mutex_device(Mutex::new(Counter::default()));
+        #[cfg(feature = "parking_lot")]
+        check_mutex_device(parking_lot::Mutex::new(Counter::default()));
+        #[cfg(feature = "spin")]
+        check_mutex_device(spin::Mutex::new(Counter::default()));
     }
 }
@@ -0,0 +1,305 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Machine-readable description of the devices registered with an [`IoManager`].
+//!
+//! [`IoManager::export_manifest`] builds a [`Manifest`] out of the bus registrations and the
+//! properties reported by the `introspect` method of the devices. Devices fill in the
+//! structured fields of their entry by reporting the following properties, or through the
+//! details returned by their `info` method (see [`info`](crate::info)), which take
+//! precedence:
+//!
+//! | Property   | Field                        | Format                          |
+//! |------------|------------------------------|---------------------------------|
+//! | `name`     | [`ManifestDevice::name`]     | any string                      |
+//! | `type`     | [`ManifestDevice::kind`]     | any string                      |
+//! | `irqs`     | [`ManifestDevice::irqs`]     | comma separated decimal numbers |
+//! | `features` | [`ManifestDevice::features`] | comma separated strings         |
+//!
+//! Any other property is kept in [`ManifestDevice::properties`].
+//!
+//! With the `serde` feature, the manifest implements `serde::Serialize` (and
+//! `serde::Deserialize`), so it can be exported as JSON (or any other format) for external
+//! tooling:
+//!
+//! ```ignore
+//! let json = serde_json::to_string(&manager.export_manifest())?;
+//! ```
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use crate::bus::{Bus, BusAddress, BusManager, MmioAddress, PioAddress};
+use crate::device_manager::{group_ranges, IoManager};
+use crate::info::DeviceDetails;
+
+/// An address range taken by a device.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ManifestRange {
+    /// First address of the range.
+    pub base: u64,
+    /// Size of the range.
+    pub size: u64,
+}
+
+/// Description of a device object registered on a bus.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ManifestDevice {
+    /// Name of the device, from its `name` property.
+    pub name: Option<String>,
+    /// Type of the device, from its `type` property.
+    #[cfg_attr(feature = "serde", serde(rename = "type"))]
+    pub kind: Option<String>,
+    /// Ranges the device is registered with, in bus order.
+    pub ranges: Vec<ManifestRange>,
+    /// Interrupts of the device, from its `irqs` property.
+    pub irqs: Vec<u32>,
+    /// Features of the device, from its `features` property.
+    pub features: Vec<String>,
+    /// Remaining properties reported by the device.
+    pub properties: BTreeMap<String, String>,
+}
+
+impl ManifestDevice {
+    fn new(ranges: Vec<ManifestRange>, introspected: Vec<(String, String)>) -> Self {
+        let list = |value: &str| -> Vec<String> {
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(String::from)
+                .collect()
+        };
+
+        let mut device = ManifestDevice {
+            ranges,
+            ..Default::default()
+        };
+        for (key, value) in introspected {
+            match key.as_str() {
+                "name" => device.name = Some(value),
+                "type" => device.kind = Some(value),
+                "irqs" => match list(&value).iter().map(|irq| irq.parse()).collect() {
+                    Ok(irqs) => device.irqs = irqs,
+                    Err(_) => {
+                        device.properties.insert(key, value);
+                    }
+                },
+                "features" => device.features = list(&value),
+                _ => {
+                    device.properties.insert(key, value);
+                }
+            }
+        }
+        device
+    }
+}
+
+/// Description of all the devices registered with an [`IoManager`].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Manifest {
+    /// Devices registered on the PIO bus, in the order in which they appear on the bus.
+    pub pio: Vec<ManifestDevice>,
+    /// Devices registered on the MMIO bus, in the order in which they appear on the bus.
+    pub mmio: Vec<ManifestDevice>,
+}
+
+fn describe<A, D, F>(bus: &Bus<A, Arc<D>>, introspect: F) -> Vec<ManifestDevice>
+where
+    A: BusAddress,
+    D: ?Sized,
+    F: Fn(&D) -> Vec<(String, String)>,
+{
+    group_ranges(bus)
+        .into_iter()
+        .map(|(device, ranges)| {
+            let ranges = ranges
+                .iter()
+                .map(|range| ManifestRange {
+                    base: range.base().value().into(),
+                    size: range.size().into(),
+                })
+                .collect();
+            ManifestDevice::new(ranges, introspect(device))
+        })
+        .collect()
+}
+
+// Append the details of a device to its introspected properties, taking precedence over them.
+fn properties(
+    mut introspected: Vec<(String, String)>,
+    info: Option<DeviceDetails>,
+) -> Vec<(String, String)> {
+    if let Some(info) = info {
+        introspected.push(("name".to_string(), info.name));
+        introspected.push(("type".to_string(), info.device_type));
+        if let Some(id) = info.virtio_device_id {
+            introspected.push(("virtio_device_id".to_string(), id.to_string()));
+        }
+        if let Some(version) = info.version {
+            introspected.push(("version".to_string(), version.to_string()));
+        }
+    }
+    introspected
+}
+
+impl IoManager {
+    /// Describe every registered device object, with its ranges, its details (see
+    /// [`info`](crate::info)) and the properties it reports through `introspect`.
+    pub fn export_manifest(&self) -> Manifest {
+        Manifest {
+            pio: describe(BusManager::<PioAddress>::bus(self), |device| {
+                properties(device.introspect(), device.info())
+            }),
+            mmio: describe(BusManager::<MmioAddress>::bus(self), |device| {
+                properties(device.introspect(), device.info())
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::bus::{MmioAddressOffset, MmioRange, PioRange};
+    use crate::device_manager::{MmioManager, PioManager};
+    use std::sync::Mutex;
+
+    use crate::testing::MockDevice;
+    use crate::{DeviceMmio, MutDeviceMmio};
+
+    struct Described;
+
+    impl DeviceMmio for Described {
+        fn mmio_read(&self, _base: MmioAddress, _offset: MmioAddressOffset, _data: &mut [u8]) {}
+        fn mmio_write(&self, _base: MmioAddress, _offset: MmioAddressOffset, _data: &[u8]) {}
+
+        fn introspect(&self) -> Vec<(String, String)> {
+            [
+                ("name", "net0"),
+                ("type", "virtio-net"),
+                ("irqs", "5, 6"),
+                ("features", "csum,mrg_rxbuf"),
+                ("queues", "2"),
+            ]
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+        }
+    }
+
+    #[test]
+    fn test_export_manifest() {
+        let mut manager = IoManager::new();
+        let device = Arc::new(Described);
+        for base in [0x2000, 0x1000] {
+            let range = MmioRange::new(MmioAddress(base), 0x100).unwrap();
+            manager.register_mmio(range, device.clone()).unwrap();
+        }
+        let range = PioRange::new(PioAddress(0x60), 4).unwrap();
+        manager
+            .register_pio(range, Arc::new(MockDevice::new()))
+            .unwrap();
+
+        let manifest = manager.export_manifest();
+        assert_eq!(manifest.pio.len(), 1);
+        assert_eq!(manifest.pio[0].name, None);
+        assert_eq!(
+            manifest.pio[0].ranges,
+            [ManifestRange {
+                base: 0x60,
+                size: 4
+            }]
+        );
+
+        let net = &manifest.mmio[0];
+        assert_eq!(manifest.mmio.len(), 1);
+        assert_eq!(net.name.as_deref(), Some("net0"));
+        assert_eq!(net.kind.as_deref(), Some("virtio-net"));
+        assert_eq!(net.ranges[0].base, 0x1000);
+        assert_eq!(net.ranges[1].base, 0x2000);
+        assert_eq!(net.irqs, [5, 6]);
+        assert_eq!(net.features, ["csum", "mrg_rxbuf"]);
+        assert_eq!(net.properties.len(), 1);
+        assert_eq!(net.properties["queues"], "2");
+
+        // Malformed interrupts are kept as a plain property.
+        let device = ManifestDevice::new(Vec::new(), vec![("irqs".into(), "x".into())]);
+        assert!(device.irqs.is_empty());
+        assert_eq!(device.properties["irqs"], "x");
+    }
+
+    #[test]
+    fn test_device_info() {
+        struct Uart;
+
+        impl MutDeviceMmio for Uart {
+            fn mmio_read(&mut self, _: MmioAddress, _: MmioAddressOffset, _: &mut [u8]) {}
+            fn mmio_write(&mut self, _: MmioAddress, _: MmioAddressOffset, _: &[u8]) {}
+
+            fn introspect(&self) -> Vec<(String, String)> {
+                vec![("type".to_string(), "serial".to_string())]
+            }
+
+            fn info(&self) -> Option<DeviceDetails> {
+                Some(DeviceDetails {
+                    name: "uart0".to_string(),
+                    device_type: "pl011".to_string(),
+                    virtio_device_id: None,
+                    version: Some(3),
+                })
+            }
+        }
+
+        let mut manager = IoManager::new();
+        let range = MmioRange::new(MmioAddress(0x1000), 0x100).unwrap();
+        manager
+            .register_mmio(range, Arc::new(Mutex::new(Uart)))
+            .unwrap();
+        let range = PioRange::new(PioAddress(0x60), 4).unwrap();
+        manager
+            .register_pio(range, Arc::new(MockDevice::new()))
+            .unwrap();
+
+        let info = manager.mmio_info(MmioAddress(0x1010)).unwrap();
+        assert_eq!(info.name, "uart0");
+        assert_eq!(info.version, Some(3));
+        assert_eq!(manager.pio_info(PioAddress(0x60)), None);
+        assert_eq!(manager.mmio_info(MmioAddress(0x2000)), None);
+
+        // The details take precedence over the introspected properties.
+        let uart = &manager.export_manifest().mmio[0];
+        assert_eq!(uart.name.as_deref(), Some("uart0"));
+        assert_eq!(uart.kind.as_deref(), Some("pl011"));
+        assert_eq!(uart.properties.len(), 1);
+        assert_eq!(uart.properties["version"], "3");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_manifest_json() {
+        let mut manager = IoManager::new();
+        let range = MmioRange::new(MmioAddress(0x1000), 0x100).unwrap();
+        manager.register_mmio(range, Arc::new(Described)).unwrap();
+
+        let json = serde_json::to_value(manager.export_manifest()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "pio": [],
+                "mmio": [{
+                    "name": "net0",
+                    "type": "virtio-net",
+                    "ranges": [{"base": 0x1000, "size": 0x100}],
+                    "irqs": [5, 6],
+                    "features": ["csum", "mrg_rxbuf"],
+                    "properties": {"queues": "2"},
+                }],
+            })
+        );
+    }
+}
@@ -0,0 +1,502 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Device I/O and interrupt counters, rendered in the Prometheus text format (`metrics`
+//! feature).
+//!
+//! Devices wrapped in [`Counted`] count the accesses dispatched to them, and interrupts
+//! wrapped in [`CountedInterrupt`] count their triggers. On every scrape, the VMM collects
+//! the counters it cares about in a [`Metrics`] snapshot, along with the
+//! [`ShardStats`] of its sharded buses, and renders it:
+//!
+//! ```
+//! # use std::sync::Arc;
+//! # use vm_device::bus::{MmioAddress, MmioAddressOffset, MmioRange};
+//! use vm_device::device_manager::{IoManager, MmioManager};
+//! use vm_device::metrics::{Counted, Metrics};
+//! # use vm_device::DeviceMmio;
+//! # struct Rtc;
+//! # impl DeviceMmio for Rtc {
+//! #     fn mmio_read(&self, _: MmioAddress, _: MmioAddressOffset, _: &mut [u8]) {}
+//! #     fn mmio_write(&self, _: MmioAddress, _: MmioAddressOffset, _: &[u8]) {}
+//! # }
+//!
+//! let rtc = Arc::new(Counted::new(Rtc));
+//! let mut manager = IoManager::new();
+//! let range = MmioRange::new(MmioAddress(0x1000), 0x10).unwrap();
+//! manager.register_mmio(range, rtc.clone()).unwrap();
+//! manager.mmio_read(MmioAddress(0x1000), &mut [0; 4]).unwrap();
+//!
+//! let mut metrics = Metrics::new();
+//! metrics.device("rtc", rtc.counters());
+//! assert!(metrics
+//!     .render()
+//!     .contains("vm_device_io_read_bytes_total{device=\"rtc\"} 4\n"));
+//! ```
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::bus::{MmioAddress, MmioAddressOffset, PioAddress, PioAddressOffset, ShardStats};
+use crate::completion::Completion;
+use crate::exit::ExitAccess;
+use crate::health::Health;
+use crate::info::DeviceDetails;
+use crate::interrupt::{self, Interrupt};
+use crate::resources::Resource;
+use crate::stats::{DeviceStats, Statistics};
+use crate::{DeviceMmio, DevicePio};
+
+/// Counters of the accesses dispatched to a device.
+#[derive(Debug, Default)]
+pub struct IoCounters {
+    reads: AtomicU64,
+    writes: AtomicU64,
+    read_bytes: AtomicU64,
+    write_bytes: AtomicU64,
+}
+
+impl IoCounters {
+    /// Return the number of reads.
+    pub fn reads(&self) -> u64 {
+        self.reads.load(Ordering::Relaxed)
+    }
+
+    /// Return the number of writes.
+    pub fn writes(&self) -> u64 {
+        self.writes.load(Ordering::Relaxed)
+    }
+
+    /// Return the number of bytes read.
+    pub fn read_bytes(&self) -> u64 {
+        self.read_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Return the number of bytes written.
+    pub fn write_bytes(&self) -> u64 {
+        self.write_bytes.load(Ordering::Relaxed)
+    }
+
+    fn read(&self, len: usize) {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        self.read_bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    fn write(&self, len: usize) {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        self.write_bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+}
+
+// The operations are the accesses, reads and writes together.
+impl DeviceStats for IoCounters {
+    fn ops(&self) -> u64 {
+        self.reads() + self.writes()
+    }
+
+    fn bytes(&self) -> u64 {
+        self.read_bytes() + self.write_bytes()
+    }
+}
+
+/// A device wrapper counting the accesses dispatched to the device.
+///
+/// Debug reads aren't counted, and read-modify-writes count as a read and a write. The
+/// statistics of the device (see [`stats`](crate::stats)) default to the counters, if the
+/// device doesn't report its own.
+pub struct Counted<D> {
+    device: D,
+    counters: IoCounters,
+}
+
+impl<D> Counted<D> {
+    /// Wrap `device`, with all counters at zero.
+    pub fn new(device: D) -> Self {
+        Counted {
+            device,
+            counters: IoCounters::default(),
+        }
+    }
+
+    /// Return the counters of the device.
+    pub fn counters(&self) -> &IoCounters {
+        &self.counters
+    }
+
+    /// Return the wrapped device.
+    pub fn inner(&self) -> &D {
+        &self.device
+    }
+}
+
+impl<D: DeviceMmio> DeviceMmio for Counted<D> {
+    fn mmio_read(&self, base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+        self.counters.read(data.len());
+        self.device.mmio_read(base, offset, data);
+    }
+
+    fn mmio_write(&self, base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+        self.counters.write(data.len());
+        self.device.mmio_write(base, offset, data);
+    }
+
+    fn introspect(&self) -> Vec<(String, String)> {
+        self.device.introspect()
+    }
+
+    fn info(&self) -> Option<DeviceDetails> {
+        self.device.info()
+    }
+
+    fn health(&self) -> Health {
+        self.device.health()
+    }
+
+    fn stats(&self) -> Option<Statistics> {
+        self.device
+            .stats()
+            .or_else(|| Some(Statistics::of(&self.counters)))
+    }
+
+    fn update_resources(&self, resources: &[Resource]) {
+        self.device.update_resources(resources)
+    }
+
+    fn debug_read(&self, base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) -> bool {
+        self.device.debug_read(base, offset, data)
+    }
+
+    fn mmio_update(
+        &self,
+        base: MmioAddress,
+        offset: MmioAddressOffset,
+        data: &mut [u8],
+        update: &mut dyn FnMut(&mut [u8]),
+    ) {
+        self.counters.read(data.len());
+        self.counters.write(data.len());
+        self.device.mmio_update(base, offset, data, update);
+    }
+
+    fn mmio_deferred(
+        &self,
+        base: MmioAddress,
+        offset: MmioAddressOffset,
+        access: ExitAccess<'_>,
+    ) -> Completion {
+        match &access {
+            ExitAccess::Read(data) => self.counters.read(data.len()),
+            ExitAccess::Write(data) => self.counters.write(data.len()),
+        }
+        self.device.mmio_deferred(base, offset, access)
+    }
+}
+
+impl<D: DevicePio> DevicePio for Counted<D> {
+    fn pio_read(&self, base: PioAddress, offset: PioAddressOffset, data: &mut [u8]) {
+        self.counters.read(data.len());
+        self.device.pio_read(base, offset, data);
+    }
+
+    fn pio_write(&self, base: PioAddress, offset: PioAddressOffset, data: &[u8]) {
+        self.counters.write(data.len());
+        self.device.pio_write(base, offset, data);
+    }
+
+    fn introspect(&self) -> Vec<(String, String)> {
+        self.device.introspect()
+    }
+
+    fn info(&self) -> Option<DeviceDetails> {
+        self.device.info()
+    }
+
+    fn health(&self) -> Health {
+        self.device.health()
+    }
+
+    fn stats(&self) -> Option<Statistics> {
+        self.device
+            .stats()
+            .or_else(|| Some(Statistics::of(&self.counters)))
+    }
+
+    fn debug_read(&self, base: PioAddress, offset: PioAddressOffset, data: &mut [u8]) -> bool {
+        self.device.debug_read(base, offset, data)
+    }
+
+    fn pio_deferred(
+        &self,
+        base: PioAddress,
+        offset: PioAddressOffset,
+        access: ExitAccess<'_>,
+    ) -> Completion {
+        match &access {
+            ExitAccess::Read(data) => self.counters.read(data.len()),
+            ExitAccess::Write(data) => self.counters.write(data.len()),
+        }
+        self.device.pio_deferred(base, offset, access)
+    }
+}
+
+/// Counters of the triggers of an interrupt.
+#[derive(Debug, Default)]
+pub struct InterruptCounters {
+    triggers: AtomicU64,
+    failures: AtomicU64,
+}
+
+impl InterruptCounters {
+    /// Return the number of triggers, including the failed ones.
+    pub fn triggers(&self) -> u64 {
+        self.triggers.load(Ordering::Relaxed)
+    }
+
+    /// Return the number of triggers which failed.
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+}
+
+/// An interrupt wrapper counting the triggers of the interrupt.
+pub struct CountedInterrupt<I> {
+    interrupt: I,
+    counters: InterruptCounters,
+}
+
+impl<I> CountedInterrupt<I> {
+    /// Wrap `interrupt`, with all counters at zero.
+    pub fn new(interrupt: I) -> Self {
+        CountedInterrupt {
+            interrupt,
+            counters: InterruptCounters::default(),
+        }
+    }
+
+    /// Return the counters of the interrupt.
+    pub fn counters(&self) -> &InterruptCounters {
+        &self.counters
+    }
+
+    /// Return the wrapped interrupt.
+    pub fn inner(&self) -> &I {
+        &self.interrupt
+    }
+}
+
+impl<I: Interrupt> Interrupt for CountedInterrupt<I> {
+    fn trigger(&self) -> Result<(), interrupt::Error> {
+        self.counters.triggers.fetch_add(1, Ordering::Relaxed);
+        self.interrupt.trigger().inspect_err(|_| {
+            self.counters.failures.fetch_add(1, Ordering::Relaxed);
+        })
+    }
+}
+
+// Name, type and help of the metric families, in rendering order.
+const FAMILIES: [(&str, &str, &str); 10] = [
+    (
+        "vm_device_io_reads_total",
+        "counter",
+        "Reads dispatched to the device.",
+    ),
+    (
+        "vm_device_io_writes_total",
+        "counter",
+        "Writes dispatched to the device.",
+    ),
+    (
+        "vm_device_io_read_bytes_total",
+        "counter",
+        "Bytes read from the device.",
+    ),
+    (
+        "vm_device_io_write_bytes_total",
+        "counter",
+        "Bytes written to the device.",
+    ),
+    (
+        "vm_device_interrupt_triggers_total",
+        "counter",
+        "Triggers of the interrupt.",
+    ),
+    (
+        "vm_device_interrupt_failures_total",
+        "counter",
+        "Failed triggers of the interrupt.",
+    ),
+    (
+        "vm_device_bus_shard_devices",
+        "gauge",
+        "Ranges registered in the bus shard.",
+    ),
+    (
+        "vm_device_bus_shard_lookups_total",
+        "counter",
+        "Lookups served by the bus shard.",
+    ),
+    (
+        "vm_device_bus_shard_updates_total",
+        "counter",
+        "Registrations and deregistrations in the bus shard.",
+    ),
+    (
+        "vm_device_bus_shard_contended_total",
+        "counter",
+        "Operations which waited for the bus shard lock.",
+    ),
+];
+
+/// A snapshot of counters, rendered in the Prometheus text exposition format.
+#[derive(Clone, Debug, Default)]
+pub struct Metrics {
+    // Family index, labels and value of every sample.
+    samples: Vec<(usize, String, u64)>,
+}
+
+impl Metrics {
+    /// Create an empty snapshot.
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    /// Add the counters of the device called `name`.
+    pub fn device(&mut self, name: &str, counters: &IoCounters) {
+        let labels = label("device", name);
+        self.add(0, &labels, counters.reads());
+        self.add(1, &labels, counters.writes());
+        self.add(2, &labels, counters.read_bytes());
+        self.add(3, &labels, counters.write_bytes());
+    }
+
+    /// Add the counters of the interrupt called `name`.
+    pub fn interrupt(&mut self, name: &str, counters: &InterruptCounters) {
+        let labels = label("interrupt", name);
+        self.add(4, &labels, counters.triggers());
+        self.add(5, &labels, counters.failures());
+    }
+
+    /// Add the shard counters of the sharded bus called `name`, as returned by
+    /// [`ShardedBus::stats`](crate::bus::ShardedBus::stats).
+    pub fn sharded_bus(&mut self, name: &str, stats: &[ShardStats]) {
+        for (idx, shard) in stats.iter().enumerate() {
+            let labels = format!("{},shard=\"{}\"", label("bus", name), idx);
+            self.add(6, &labels, shard.devices as u64);
+            self.add(7, &labels, shard.lookups);
+            self.add(8, &labels, shard.updates);
+            self.add(9, &labels, shard.contended);
+        }
+    }
+
+    /// Render the snapshot in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+        for (family, (name, kind, help)) in FAMILIES.iter().enumerate() {
+            let mut samples = self.samples.iter().filter(|(f, _, _)| *f == family);
+            let mut sample = samples.next();
+            if sample.is_none() {
+                continue;
+            }
+            // Writing to a `String` never fails.
+            let _ = writeln!(text, "# HELP {} {}", name, help);
+            let _ = writeln!(text, "# TYPE {} {}", name, kind);
+            while let Some((_, labels, value)) = sample {
+                let _ = writeln!(text, "{}{{{}}} {}", name, labels, value);
+                sample = samples.next();
+            }
+        }
+        text
+    }
+
+    fn add(&mut self, family: usize, labels: &str, value: u64) {
+        self.samples.push((family, labels.to_string(), value));
+    }
+}
+
+// Render the `key="value"` label, escaping `value`.
+fn label(key: &str, value: &str) -> String {
+    let mut label = format!("{}=\"", key);
+    for c in value.chars() {
+        match c {
+            '\\' => label.push_str("\\\\"),
+            '"' => label.push_str("\\\""),
+            '\n' => label.push_str("\\n"),
+            c => label.push(c),
+        }
+    }
+    label.push('"');
+    label
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::bus::{MmioRange, PioRange, ShardedBus};
+    use crate::device_manager::{IoManager, MmioManager, PioManager};
+    use crate::testing::{MockInterrupt, Scratchpad};
+
+    #[test]
+    fn test_counted() {
+        let device = Arc::new(Counted::new(Scratchpad::new(0x10)));
+        let mut manager = IoManager::new();
+        let range = MmioRange::new(MmioAddress(0x1000), 0x10).unwrap();
+        manager.register_mmio(range, device.clone()).unwrap();
+        let range = PioRange::new(PioAddress(0x60), 0x10).unwrap();
+        manager.register_pio(range, device.clone()).unwrap();
+
+        manager
+            .mmio_write(MmioAddress(0x1000), &[1, 2, 3, 4])
+            .unwrap();
+        manager.pio_read(PioAddress(0x60), &mut [0; 2]).unwrap();
+        manager.mmio_read(MmioAddress(0x1000), &mut [0; 1]).unwrap();
+        assert!(manager
+            .mmio_debug_read(MmioAddress(0x1000), &mut [0; 4])
+            .unwrap());
+        let counters = device.counters();
+        assert_eq!(counters.reads(), 2);
+        assert_eq!(counters.read_bytes(), 3);
+        assert_eq!(counters.writes(), 1);
+        assert_eq!(counters.write_bytes(), 4);
+        let stats = DeviceMmio::stats(&*device).unwrap();
+        assert_eq!((stats.ops, stats.bytes), (3, 7));
+
+        let irq = CountedInterrupt::new(MockInterrupt::new());
+        irq.trigger().unwrap();
+        irq.inner().expect_triggered(1);
+        assert_eq!(irq.counters().triggers(), 1);
+        assert_eq!(irq.counters().failures(), 0);
+    }
+
+    #[test]
+    fn test_metrics_render() {
+        let mut metrics = Metrics::new();
+        assert_eq!(metrics.render(), "");
+
+        let device = Counted::new(Scratchpad::new(0x10));
+        device.mmio_write(MmioAddress(0), 0, &[0; 8]);
+        metrics.device("net\"0\"", device.counters());
+        metrics.device("rtc", &IoCounters::default());
+        let bus = ShardedBus::<MmioAddress, u32>::new(2, 12);
+        bus.register(MmioRange::new(MmioAddress(0x1000), 0x10).unwrap(), 0)
+            .unwrap();
+        metrics.sharded_bus("mmio", &bus.stats());
+
+        let text = metrics.render();
+        assert!(text.starts_with(
+            "# HELP vm_device_io_reads_total Reads dispatched to the device.\n\
+             # TYPE vm_device_io_reads_total counter\n\
+             vm_device_io_reads_total{device=\"net\\\"0\\\"\"} 0\n\
+             vm_device_io_reads_total{device=\"rtc\"} 0\n"
+        ));
+        assert!(text.contains("vm_device_io_write_bytes_total{device=\"net\\\"0\\\"\"} 8\n"));
+        assert!(text.contains(
+            "# TYPE vm_device_bus_shard_devices gauge\n\
+             vm_device_bus_shard_devices{bus=\"mmio\",shard=\"0\"} 0\n\
+             vm_device_bus_shard_devices{bus=\"mmio\",shard=\"1\"} 1\n"
+        ));
+        assert!(!text.contains("interrupt"));
+    }
+}
@@ -0,0 +1,309 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Device register inspection from a `gdbstub` monitor command (`gdbstub` feature).
+//!
+//! [`DeviceMonitor`] handles `monitor dev ...` commands issued from GDB, reading device
+//! registers through the bus so guest/device interactions can be inspected while the guest
+//! is stopped:
+//!
+//! ```text
+//! (gdb) monitor dev read mmio 0xd0000070 4
+//! 0xd0000070: 0x0000000f
+//! (gdb) monitor dev dump pio 0x60 8
+//! 0x00000060: 00 11 22 33 44 55 66 77
+//! ```
+//!
+//! Registers are read with `debug_read`, so devices which implement it are inspected without
+//! side effects. Reads from the other devices fail, unless the monitor is built with
+//! [`DeviceMonitor::allow_side_effects`], in which case they fall back to regular reads.
+//!
+//! The monitor plugs into the `MonitorCmd` extension of the `gdbstub` target:
+//!
+//! ```ignore
+//! impl MonitorCmd for VmTarget {
+//!     fn handle_monitor_cmd(&mut self, cmd: &[u8], out: ConsoleOutput<'_>) -> Result<(), Error> {
+//!         let manager = self.io_manager.load();
+//!         if !self.device_monitor.handle_monitor_cmd(&*manager, cmd, out) {
+//!             // Not a device command.
+//!         }
+//!         Ok(())
+//!     }
+//! }
+//! ```
+
+use std::convert::TryFrom;
+use std::fmt::{self, Write};
+
+use gdbstub::target::ext::monitor_cmd::ConsoleOutput;
+
+use crate::bus::{self, MmioAddress, PioAddress};
+use crate::device_manager::{MmioManager, PioManager};
+
+const USAGE: &str =
+    "usage: dev read <mmio|pio> <addr> [1|2|4|8]\n       dev dump <mmio|pio> <addr> <len>\n";
+
+// Width of the accesses performed when dumping registers.
+const DUMP_WIDTH: usize = 4;
+
+/// Handles the `dev` monitor commands, reading device registers through the bus.
+#[derive(Clone, Debug, Default)]
+pub struct DeviceMonitor {
+    side_effects: bool,
+}
+
+impl DeviceMonitor {
+    /// Create a monitor which only reads devices supporting debug reads.
+    pub fn new() -> Self {
+        DeviceMonitor::default()
+    }
+
+    /// Fall back to regular reads, with their side effects, for devices which don't support
+    /// debug reads.
+    pub fn allow_side_effects(mut self, allow: bool) -> Self {
+        self.side_effects = allow;
+        self
+    }
+
+    /// Handle the monitor command `cmd`, writing the result to `out`. Returns `false` if
+    /// `cmd` isn't a `dev` command.
+    pub fn handle_monitor_cmd<M>(&self, manager: &M, cmd: &[u8], mut out: ConsoleOutput<'_>) -> bool
+    where
+        M: MmioManager + PioManager,
+    {
+        // Writing to the console never fails.
+        self.handle(manager, cmd, &mut out).unwrap_or(true)
+    }
+
+    /// Same as [`DeviceMonitor::handle_monitor_cmd`], writing the result to any `out`.
+    pub fn handle<M, W>(&self, manager: &M, cmd: &[u8], out: &mut W) -> Result<bool, fmt::Error>
+    where
+        M: MmioManager + PioManager,
+        W: Write,
+    {
+        let cmd = String::from_utf8_lossy(cmd);
+        let mut args = cmd.split_whitespace();
+        if args.next() != Some("dev") {
+            return Ok(false);
+        }
+
+        let action = args.next();
+        let bus = args.next();
+        let addr = args.next().and_then(parse_number);
+        let len = args.next().map(parse_number);
+        if args.next().is_some() {
+            out.write_str(USAGE)?;
+            return Ok(true);
+        }
+        match (action, bus, addr, len) {
+            (Some("read"), Some(bus), Some(addr), len) => {
+                match len
+                    .unwrap_or(Some(4))
+                    .filter(|len| [1, 2, 4, 8].contains(len))
+                {
+                    Some(len) => self.read(manager, bus, addr, len as usize, out)?,
+                    None => out.write_str(USAGE)?,
+                }
+            }
+            (Some("dump"), Some(bus), Some(addr), Some(Some(len))) => {
+                self.dump(manager, bus, addr, len, out)?
+            }
+            _ => out.write_str(USAGE)?,
+        }
+        Ok(true)
+    }
+
+    fn read<M, W>(&self, manager: &M, bus: &str, addr: u64, len: usize, out: &mut W) -> fmt::Result
+    where
+        M: MmioManager + PioManager,
+        W: Write,
+    {
+        let mut data = [0; 8];
+        match self.access(manager, bus, addr, &mut data[..len]) {
+            Ok(()) => writeln!(
+                out,
+                "{:#010x}: {:#0width$x}",
+                addr,
+                u64::from_le_bytes(data),
+                width = 2 + 2 * len
+            ),
+            Err(e) => writeln!(out, "{:#010x}: {}", addr, e),
+        }
+    }
+
+    fn dump<M, W>(&self, manager: &M, bus: &str, addr: u64, len: u64, out: &mut W) -> fmt::Result
+    where
+        M: MmioManager + PioManager,
+        W: Write,
+    {
+        let mut failed = None;
+        let mut offset = 0;
+        while offset < len {
+            let line = addr.wrapping_add(offset);
+            write!(out, "{:#010x}:", line)?;
+            for word in (0..16).step_by(DUMP_WIDTH) {
+                if offset + word >= len {
+                    break;
+                }
+                let mut data = [0; DUMP_WIDTH];
+                match self.access(manager, bus, line.wrapping_add(word), &mut data) {
+                    Ok(()) => data
+                        .iter()
+                        .try_for_each(|byte| write!(out, " {:02x}", byte))?,
+                    Err(e) => {
+                        failed.get_or_insert(e);
+                        out.write_str(" ?? ?? ?? ??")?;
+                    }
+                }
+            }
+            out.write_char('\n')?;
+            offset += 16;
+        }
+        match failed {
+            Some(e) => writeln!(out, "??: {}", e),
+            None => Ok(()),
+        }
+    }
+
+    fn access<M>(&self, manager: &M, bus: &str, addr: u64, data: &mut [u8]) -> Result<(), Failure>
+    where
+        M: MmioManager + PioManager,
+    {
+        let debug = match bus {
+            "mmio" => manager.mmio_debug_read(MmioAddress(addr), data)?,
+            "pio" => manager.pio_debug_read(pio_address(addr, data.len())?, data)?,
+            _ => return Err(Failure::UnknownBus),
+        };
+        match (debug, self.side_effects) {
+            (true, _) => Ok(()),
+            (false, true) => match bus {
+                "mmio" => Ok(manager.mmio_read(MmioAddress(addr), data)?),
+                _ => Ok(manager.pio_read(pio_address(addr, data.len())?, data)?),
+            },
+            (false, false) => Err(Failure::NoDebugRead),
+        }
+    }
+}
+
+// Reasons for which registers couldn't be read.
+enum Failure {
+    UnknownBus,
+    NoDebugRead,
+    Bus(bus::Error),
+}
+
+impl From<bus::Error> for Failure {
+    fn from(e: bus::Error) -> Self {
+        Failure::Bus(e)
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::UnknownBus => write!(f, "unknown bus, expected mmio or pio"),
+            Failure::NoDebugRead => write!(f, "device doesn't support side effect free reads"),
+            Failure::Bus(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+fn pio_address(addr: u64, len: usize) -> Result<PioAddress, Failure> {
+    u16::try_from(addr)
+        .map(PioAddress)
+        .map_err(|_| Failure::Bus(bus::Error::DeviceNotFound { addr, len }))
+}
+
+fn parse_number(arg: &str) -> Option<u64> {
+    match arg.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => arg.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::bus::{MmioAddressOffset, MmioRange, PioRange};
+    use crate::device_manager::IoManager;
+    use crate::testing::Scratchpad;
+    use crate::DeviceMmio;
+
+    // Register cleared when read.
+    #[derive(Default)]
+    struct Status(std::sync::atomic::AtomicU8);
+
+    impl DeviceMmio for Status {
+        fn mmio_read(&self, _base: MmioAddress, _offset: MmioAddressOffset, data: &mut [u8]) {
+            data.fill(self.0.swap(0, std::sync::atomic::Ordering::SeqCst));
+        }
+        fn mmio_write(&self, _base: MmioAddress, _offset: MmioAddressOffset, data: &[u8]) {
+            self.0.store(data[0], std::sync::atomic::Ordering::SeqCst);
+        }
+    }
+
+    fn run(monitor: &DeviceMonitor, manager: &IoManager, cmd: &str) -> String {
+        let mut out = String::new();
+        assert!(monitor.handle(manager, cmd.as_bytes(), &mut out).unwrap());
+        out
+    }
+
+    #[test]
+    fn test_device_monitor() {
+        let mut manager = IoManager::new();
+        let scratchpad = Arc::new(Scratchpad::new(0x20));
+        let range = PioRange::new(PioAddress(0x60), 0x20).unwrap();
+        manager.register_pio(range, scratchpad.clone()).unwrap();
+        manager
+            .pio_write(PioAddress(0x60), &(0..0x14).collect::<Vec<u8>>())
+            .unwrap();
+        let status = Arc::new(Status::default());
+        let range = MmioRange::new(MmioAddress(0x1000), 0x10).unwrap();
+        manager.register_mmio(range, status.clone()).unwrap();
+        manager.mmio_write(MmioAddress(0x1000), &[0xf]).unwrap();
+
+        let monitor = DeviceMonitor::new();
+        let mut out = String::new();
+        assert!(!monitor.handle(&manager, b"info", &mut out).unwrap());
+        assert!(out.is_empty());
+
+        assert_eq!(
+            run(&monitor, &manager, "dev read pio 0x62 2"),
+            "0x00000062: 0x0302\n"
+        );
+        assert_eq!(
+            run(&monitor, &manager, "dev read pio 0x64"),
+            "0x00000064: 0x07060504\n"
+        );
+        assert_eq!(
+            run(&monitor, &manager, "dev dump pio 0x60 20"),
+            "0x00000060: 00 01 02 03 04 05 06 07 08 09 0a 0b 0c 0d 0e 0f\n\
+             0x00000070: 10 11 12 13\n"
+        );
+        assert_eq!(
+            run(&monitor, &manager, "dev read pio 0x7e 4"),
+            "0x0000007e: device not found at 0x7e (access length 4)\n"
+        );
+        assert_eq!(run(&monitor, &manager, "dev read pio 0x60 3"), USAGE);
+        assert_eq!(run(&monitor, &manager, "dev dump pio 0x60"), USAGE);
+
+        // The status register is only read with its side effects when allowed to.
+        assert_eq!(
+            run(&monitor, &manager, "dev dump mmio 0x1000 4"),
+            "0x00001000: ?? ?? ?? ??\n\
+             ??: device doesn't support side effect free reads\n"
+        );
+        let monitor = monitor.allow_side_effects(true);
+        assert_eq!(
+            run(&monitor, &manager, "dev read mmio 0x1000 1"),
+            "0x00001000: 0x0f\n"
+        );
+        assert_eq!(
+            run(&monitor, &manager, "dev read mmio 0x1000 1"),
+            "0x00001000: 0x00\n"
+        );
+    }
+}
@@ -0,0 +1,493 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Hosting the devices of several VMs in one process.
+//!
+//! Runtimes running many microVMs per process keep one [`Namespace`] per VM in a
+//! [`Namespaces`] set, keyed by [`VmId`]. Each namespace has its own [`IoManager`], so the
+//! buses of the VMs are isolated from each other, and its own [`VmStats`], while the set
+//! gives a view over all the VMs. The namespaces can share resource allocators, e.g. for the
+//! host interrupts or the MMIO windows of passed through devices: the resources of the
+//! devices registered through a namespace are then reserved with the allocator, and released
+//! when the devices are deregistered or the namespace is removed.
+//!
+//! ```
+//! # use std::sync::Arc;
+//! # use vm_device::bus::{MmioAddress, MmioAddressOffset};
+//! use vm_device::namespace::{Namespaces, VmId};
+//! use vm_device::resources::Resource;
+//! # use vm_device::DeviceMmio;
+//! # struct Rtc;
+//! # impl DeviceMmio for Rtc {
+//! #     fn mmio_read(&self, _: MmioAddress, _: MmioAddressOffset, _: &mut [u8]) {}
+//! #     fn mmio_write(&self, _: MmioAddress, _: MmioAddressOffset, _: &[u8]) {}
+//! # }
+//!
+//! let mut vms = Namespaces::new();
+//! let resources = [Resource::MmioAddressRange {
+//!     base: 0x1000,
+//!     size: 0x10,
+//! }];
+//! for id in 0..2 {
+//!     vms.create(VmId(id))
+//!         .unwrap()
+//!         .register_mmio_resources(Arc::new(Rtc), &resources)
+//!         .unwrap();
+//! }
+//! vms.mmio_read(VmId(1), MmioAddress(0x1000), &mut [0; 4]).unwrap();
+//! assert_eq!(vms.get(VmId(0)).unwrap().stats().reads(), 0);
+//! assert_eq!(vms.get(VmId(1)).unwrap().stats().reads(), 1);
+//! ```
+//!
+//! [`IoManager`]: crate::device_manager::IoManager
+
+use std::collections::btree_map::{self, BTreeMap};
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::bus::{self, MmioAddress, PioAddress};
+use crate::device_manager::{self, IoManager, MmioManager, PioManager};
+use crate::resources::{Resource, ResourceReservation};
+use crate::{DeviceMmio, DevicePio};
+
+/// Identifies a VM hosted by a [`Namespaces`] set, e.g. a partition or sandbox ID.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct VmId(pub u32);
+
+impl Display for VmId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "vm{}", self.0)
+    }
+}
+
+/// Error type for [`Namespaces`] operations.
+#[derive(Debug)]
+pub enum Error {
+    /// No namespace exists for the VM.
+    UnknownVm(VmId),
+    /// A namespace already exists for the VM.
+    DuplicateVm(VmId),
+    /// Error during bus operation.
+    Bus(bus::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::UnknownVm(vm) => write!(f, "namespace: unknown VM {}", vm),
+            Error::DuplicateVm(vm) => write!(f, "namespace: VM {} already exists", vm),
+            Error::Bus(e) => write!(f, "namespace: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Bus(e) => Some(e),
+            Error::UnknownVm(_) | Error::DuplicateVm(_) => None,
+        }
+    }
+}
+
+/// Resource allocators shared by several namespaces.
+pub type SharedAllocator = Arc<Mutex<dyn ResourceReservation + Send>>;
+
+/// Counters of the accesses dispatched through a [`Namespace`].
+#[derive(Debug, Default)]
+pub struct VmStats {
+    reads: AtomicU64,
+    writes: AtomicU64,
+    unhandled: AtomicU64,
+}
+
+impl VmStats {
+    /// Return the number of reads.
+    pub fn reads(&self) -> u64 {
+        self.reads.load(Ordering::Relaxed)
+    }
+
+    /// Return the number of writes.
+    pub fn writes(&self) -> u64 {
+        self.writes.load(Ordering::Relaxed)
+    }
+
+    /// Return the number of accesses which couldn't be dispatched to a device.
+    pub fn unhandled(&self) -> u64 {
+        self.unhandled.load(Ordering::Relaxed)
+    }
+
+    // Count an access, and whether it failed.
+    fn count(&self, write: bool, result: Result<(), bus::Error>) -> Result<(), bus::Error> {
+        let counter = if write { &self.writes } else { &self.reads };
+        counter.fetch_add(1, Ordering::Relaxed);
+        if result.is_err() {
+            self.unhandled.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+/// The devices of a VM: an [`IoManager`], the counters of the accesses dispatched through
+/// it, and the resource allocators of the VM, if any.
+///
+/// The devices registered directly with [`Namespace::manager_mut`] bypass the allocators.
+#[derive(Default)]
+pub struct Namespace {
+    manager: IoManager,
+    stats: VmStats,
+    allocator: Option<SharedAllocator>,
+    // Resources reserved with `allocator` for the registered devices.
+    reserved: Vec<Resource>,
+}
+
+impl Namespace {
+    /// Create an empty namespace, without resource allocators.
+    pub fn new() -> Self {
+        Namespace::default()
+    }
+
+    /// Reserve the resources of the devices registered from now on with `allocator`.
+    pub fn with_allocator(mut self, allocator: SharedAllocator) -> Self {
+        self.allocator = Some(allocator);
+        self
+    }
+
+    /// Return the manager of the VM.
+    pub fn manager(&self) -> &IoManager {
+        &self.manager
+    }
+
+    /// Return the manager of the VM, e.g. to register devices without reserving their
+    /// resources.
+    pub fn manager_mut(&mut self) -> &mut IoManager {
+        &mut self.manager
+    }
+
+    /// Return the counters of the VM.
+    pub fn stats(&self) -> &VmStats {
+        &self.stats
+    }
+
+    /// Register a new MMIO device with its resources (see
+    /// [`IoManager::register_mmio_resources`]), reserving them with the allocators of the
+    /// namespace first.
+    pub fn register_mmio_resources(
+        &mut self,
+        device: Arc<dyn DeviceMmio + Send + Sync>,
+        resources: &[Resource],
+    ) -> Result<(), device_manager::Error> {
+        self.reserve(resources, |manager| {
+            manager.register_mmio_resources(device, resources)
+        })
+    }
+
+    /// Register a new PIO device with its resources (see
+    /// [`IoManager::register_pio_resources`]), reserving them with the allocators of the
+    /// namespace first.
+    pub fn register_pio_resources(
+        &mut self,
+        device: Arc<dyn DevicePio + Send + Sync>,
+        resources: &[Resource],
+    ) -> Result<(), device_manager::Error> {
+        self.reserve(resources, |manager| {
+            manager.register_pio_resources(device, resources)
+        })
+    }
+
+    /// Register a new MMIO + PIO device with its resources (see
+    /// [`IoManager::register_resources`]), reserving them with the allocators of the
+    /// namespace first.
+    pub fn register_resources<T: DeviceMmio + DevicePio + 'static + Send + Sync>(
+        &mut self,
+        device: Arc<T>,
+        resources: &[Resource],
+    ) -> Result<(), device_manager::Error> {
+        self.reserve(resources, |manager| {
+            manager.register_resources(device, resources)
+        })
+    }
+
+    /// Deregister the device owning `resources` (see [`IoManager::deregister_resources`]),
+    /// and release the resources reserved for it.
+    pub fn deregister_resources(&mut self, resources: &[Resource]) -> usize {
+        let count = self.manager.deregister_resources(resources);
+        for res in resources {
+            if let Some(idx) = self.reserved.iter().position(|r| r == res) {
+                let res = self.reserved.swap_remove(idx);
+                self.release(&[res]);
+            }
+        }
+        count
+    }
+
+    /// Dispatch a read operation to the MMIO device registered at `addr`.
+    pub fn mmio_read(&self, addr: MmioAddress, data: &mut [u8]) -> Result<(), bus::Error> {
+        self.stats.count(false, self.manager.mmio_read(addr, data))
+    }
+
+    /// Dispatch a write operation to the MMIO device registered at `addr`.
+    pub fn mmio_write(&self, addr: MmioAddress, data: &[u8]) -> Result<(), bus::Error> {
+        self.stats.count(true, self.manager.mmio_write(addr, data))
+    }
+
+    /// Dispatch a read operation to the PIO device registered at `addr`.
+    pub fn pio_read(&self, addr: PioAddress, data: &mut [u8]) -> Result<(), bus::Error> {
+        self.stats.count(false, self.manager.pio_read(addr, data))
+    }
+
+    /// Dispatch a write operation to the PIO device registered at `addr`.
+    pub fn pio_write(&self, addr: PioAddress, data: &[u8]) -> Result<(), bus::Error> {
+        self.stats.count(true, self.manager.pio_write(addr, data))
+    }
+
+    // Reserve `resources` with the allocators, if any, and call `register`. The reservations
+    // are undone upon failure.
+    fn reserve<F>(
+        &mut self,
+        resources: &[Resource],
+        register: F,
+    ) -> Result<(), device_manager::Error>
+    where
+        F: FnOnce(&mut IoManager) -> Result<(), device_manager::Error>,
+    {
+        let allocator = match &self.allocator {
+            Some(allocator) => allocator,
+            None => return register(&mut self.manager),
+        };
+        {
+            let mut allocator = allocator.lock().unwrap();
+            for (index, res) in resources.iter().enumerate() {
+                if !allocator.reserve(res) {
+                    resources[..index]
+                        .iter()
+                        .for_each(|res| allocator.release(res));
+                    return Err(device_manager::Error::ResourceUnavailable {
+                        index,
+                        resource: res.clone(),
+                    });
+                }
+            }
+        }
+        match register(&mut self.manager) {
+            Ok(()) => {
+                self.reserved.extend_from_slice(resources);
+                Ok(())
+            }
+            Err(e) => {
+                self.release(resources);
+                Err(e)
+            }
+        }
+    }
+
+    fn release(&self, resources: &[Resource]) {
+        if let Some(allocator) = &self.allocator {
+            let mut allocator = allocator.lock().unwrap();
+            resources.iter().for_each(|res| allocator.release(res));
+        }
+    }
+}
+
+impl Drop for Namespace {
+    fn drop(&mut self) {
+        self.release(&self.reserved);
+    }
+}
+
+/// A set of [`Namespace`]s, one per VM.
+#[derive(Default)]
+pub struct Namespaces {
+    vms: BTreeMap<VmId, Namespace>,
+    allocator: Option<SharedAllocator>,
+}
+
+impl Namespaces {
+    /// Create an empty set, whose namespaces don't share resource allocators.
+    pub fn new() -> Self {
+        Namespaces::default()
+    }
+
+    /// Share `allocator` between the namespaces created from now on with
+    /// [`Namespaces::create`].
+    pub fn with_shared_allocator(mut self, allocator: SharedAllocator) -> Self {
+        self.allocator = Some(allocator);
+        self
+    }
+
+    /// Create the namespace of `vm`, with the shared allocators, if any.
+    pub fn create(&mut self, vm: VmId) -> Result<&mut Namespace, Error> {
+        let mut namespace = Namespace::new();
+        namespace.allocator = self.allocator.clone();
+        self.insert(vm, namespace)
+    }
+
+    /// Add `namespace` as the namespace of `vm`, e.g. one with allocators of its own.
+    pub fn insert(&mut self, vm: VmId, namespace: Namespace) -> Result<&mut Namespace, Error> {
+        match self.vms.entry(vm) {
+            btree_map::Entry::Vacant(entry) => Ok(entry.insert(namespace)),
+            btree_map::Entry::Occupied(_) => Err(Error::DuplicateVm(vm)),
+        }
+    }
+
+    /// Remove the namespace of `vm`. Its reserved resources are released once it's dropped.
+    pub fn remove(&mut self, vm: VmId) -> Option<Namespace> {
+        self.vms.remove(&vm)
+    }
+
+    /// Return the namespace of `vm`, if any.
+    pub fn get(&self, vm: VmId) -> Option<&Namespace> {
+        self.vms.get(&vm)
+    }
+
+    /// Return the namespace of `vm`, if any.
+    pub fn get_mut(&mut self, vm: VmId) -> Option<&mut Namespace> {
+        self.vms.get_mut(&vm)
+    }
+
+    /// Return an iterator over the namespaces, sorted by VM.
+    pub fn iter(&self) -> impl Iterator<Item = (VmId, &Namespace)> {
+        self.vms.iter().map(|(vm, namespace)| (*vm, namespace))
+    }
+
+    /// Return the number of VMs.
+    pub fn len(&self) -> usize {
+        self.vms.len()
+    }
+
+    /// Return whether there are no VMs.
+    pub fn is_empty(&self) -> bool {
+        self.vms.is_empty()
+    }
+
+    /// Dispatch a read operation to the MMIO device registered at `addr` in `vm`.
+    pub fn mmio_read(&self, vm: VmId, addr: MmioAddress, data: &mut [u8]) -> Result<(), Error> {
+        self.namespace(vm)?
+            .mmio_read(addr, data)
+            .map_err(Error::Bus)
+    }
+
+    /// Dispatch a write operation to the MMIO device registered at `addr` in `vm`.
+    pub fn mmio_write(&self, vm: VmId, addr: MmioAddress, data: &[u8]) -> Result<(), Error> {
+        self.namespace(vm)?
+            .mmio_write(addr, data)
+            .map_err(Error::Bus)
+    }
+
+    /// Dispatch a read operation to the PIO device registered at `addr` in `vm`.
+    pub fn pio_read(&self, vm: VmId, addr: PioAddress, data: &mut [u8]) -> Result<(), Error> {
+        self.namespace(vm)?.pio_read(addr, data).map_err(Error::Bus)
+    }
+
+    /// Dispatch a write operation to the PIO device registered at `addr` in `vm`.
+    pub fn pio_write(&self, vm: VmId, addr: PioAddress, data: &[u8]) -> Result<(), Error> {
+        self.namespace(vm)?
+            .pio_write(addr, data)
+            .map_err(Error::Bus)
+    }
+
+    fn namespace(&self, vm: VmId) -> Result<&Namespace, Error> {
+        self.get(vm).ok_or(Error::UnknownVm(vm))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::BTreeSet;
+
+    use crate::testing::Scratchpad;
+
+    // Hands out each MMIO base once.
+    #[derive(Default)]
+    struct Bases(BTreeSet<u64>);
+
+    impl ResourceReservation for Bases {
+        fn reserve(&mut self, resource: &Resource) -> bool {
+            match *resource {
+                Resource::MmioAddressRange { base, .. } => self.0.insert(base),
+                _ => true,
+            }
+        }
+
+        fn release(&mut self, resource: &Resource) {
+            if let Resource::MmioAddressRange { base, .. } = *resource {
+                self.0.remove(&base);
+            }
+        }
+    }
+
+    fn range(base: u64) -> Resource {
+        Resource::MmioAddressRange { base, size: 0x10 }
+    }
+
+    #[test]
+    fn test_namespaces() {
+        let bases = Arc::new(Mutex::new(Bases::default()));
+        let mut vms = Namespaces::new().with_shared_allocator(bases.clone());
+        let first = Arc::new(Scratchpad::new(0x10));
+        vms.create(VmId(1))
+            .unwrap()
+            .register_mmio_resources(first.clone(), &[range(0x1000)])
+            .unwrap();
+        assert!(matches!(
+            vms.create(VmId(1)),
+            Err(Error::DuplicateVm(VmId(1)))
+        ));
+        // The bases are shared, but the buses aren't.
+        let second = vms.create(VmId(2)).unwrap();
+        assert!(matches!(
+            second.register_mmio_resources(
+                Arc::new(Scratchpad::new(0x10)),
+                &[range(0x2000), range(0x1000)]
+            ),
+            Err(device_manager::Error::ResourceUnavailable { index: 1, .. })
+        ));
+        assert_eq!(bases.lock().unwrap().0.len(), 1);
+        second
+            .register_mmio_resources(Arc::new(Scratchpad::new(0x10)), &[range(0x2000)])
+            .unwrap();
+        let isolated = Namespace::new();
+        vms.insert(VmId(3), isolated)
+            .unwrap()
+            .register_mmio_resources(Arc::new(Scratchpad::new(0x10)), &[range(0x1000)])
+            .unwrap();
+
+        vms.mmio_write(VmId(1), MmioAddress(0x1000), &[1, 2])
+            .unwrap();
+        let mut data = [0; 2];
+        vms.mmio_read(VmId(3), MmioAddress(0x1000), &mut data)
+            .unwrap();
+        assert_eq!(data, [0; 2]);
+        assert!(matches!(
+            vms.mmio_read(VmId(2), MmioAddress(0x1000), &mut data),
+            Err(Error::Bus(_))
+        ));
+        assert!(matches!(
+            vms.mmio_read(VmId(4), MmioAddress(0x1000), &mut data),
+            Err(Error::UnknownVm(VmId(4)))
+        ));
+        assert_eq!(first.contents()[..2], [1, 2]);
+        let stats: Vec<_> = vms
+            .iter()
+            .map(|(vm, namespace)| {
+                let stats = namespace.stats();
+                (vm, stats.reads(), stats.writes(), stats.unhandled())
+            })
+            .collect();
+        assert_eq!(
+            stats,
+            [(VmId(1), 0, 1, 0), (VmId(2), 1, 0, 1), (VmId(3), 1, 0, 0)]
+        );
+
+        // The resources go back to the allocator once deregistered or with their VM.
+        let second = vms.get_mut(VmId(2)).unwrap();
+        assert_eq!(second.deregister_resources(&[range(0x2000)]), 1);
+        assert_eq!(*bases.lock().unwrap().0.iter().next().unwrap(), 0x1000);
+        vms.remove(VmId(1));
+        assert!(bases.lock().unwrap().0.is_empty());
+        assert_eq!(vms.len(), 2);
+    }
+}
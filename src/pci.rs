@@ -0,0 +1,260 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! PCI configuration space access through an ECAM region (`pci` feature).
+//!
+//! The PCI Express Enhanced Configuration Access Mechanism maps the 4 KiB configuration space
+//! of every function into MMIO, at an offset made of the bus, device and function numbers.
+//! [`EcamRegion`] is registered on the MMIO bus like any other device, decodes the accessed
+//! offsets into an [`EcamAddress`], and forwards the accesses to the [`PciFunction`]s added
+//! to it. Reads from absent functions return all ones, which is how guests enumerate them.
+//!
+//! # Example
+//!
+//! ```
+//! # use std::sync::Arc;
+//! use vm_device::bus::{MmioAddress, MmioRange};
+//! use vm_device::device_manager::{IoManager, MmioManager};
+//! use vm_device::pci::{EcamAddress, EcamRegion, PciFunction};
+//!
+//! struct HostBridge;
+//!
+//! impl PciFunction for HostBridge {
+//!     fn read_config(&self, register: u16, data: &mut [u8]) {
+//!         // Vendor and device IDs.
+//!         let ids = [0x86, 0x80, 0x37, 0x12];
+//!         for (idx, byte) in data.iter_mut().enumerate() {
+//!             *byte = ids.get(register as usize + idx).copied().unwrap_or(0);
+//!         }
+//!     }
+//!     fn write_config(&self, _register: u16, _data: &[u8]) {}
+//! }
+//!
+//! let mut ecam = EcamRegion::new(0, 0);
+//! assert!(ecam.add(EcamAddress::new(0, 0, 0, 0), Arc::new(HostBridge)).is_ok());
+//! let mut manager = IoManager::new();
+//! manager
+//!     .register_mmio(ecam.range(MmioAddress(0xe000_0000)), Arc::new(ecam))
+//!     .unwrap();
+//!
+//! let mut data = [0; 4];
+//! manager.mmio_read(MmioAddress(0xe000_0000), &mut data).unwrap();
+//! assert_eq!(data, [0x86, 0x80, 0x37, 0x12]);
+//! // Device 1 isn't there.
+//! manager.mmio_read(MmioAddress(0xe000_8000), &mut data).unwrap();
+//! assert_eq!(data, [0xff; 4]);
+//! ```
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use crate::bus::{MmioAddress, MmioAddressOffset, MmioRange};
+use crate::DeviceMmio;
+
+/// Size of the configuration space of a function.
+pub const CONFIG_SPACE_SIZE: u64 = 0x1000;
+
+/// Location of a configuration space register, as decoded from an ECAM offset.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct EcamAddress {
+    /// Bus number.
+    pub bus: u8,
+    /// Device number, below 32.
+    pub device: u8,
+    /// Function number, below 8.
+    pub function: u8,
+    /// Offset of the register in the configuration space, below 4096.
+    pub register: u16,
+}
+
+impl EcamAddress {
+    /// Create an address, truncating the fields which are too large.
+    pub fn new(bus: u8, device: u8, function: u8, register: u16) -> Self {
+        EcamAddress {
+            bus,
+            device: device & 0x1f,
+            function: function & 0x7,
+            register: register & 0xfff,
+        }
+    }
+
+    /// Decode the address of an ECAM `offset`, relative to the start of bus 0.
+    pub fn from_offset(offset: u64) -> Self {
+        EcamAddress::new(
+            (offset >> 20) as u8,
+            (offset >> 15) as u8,
+            (offset >> 12) as u8,
+            offset as u16,
+        )
+    }
+
+    /// Return the ECAM offset of the address, relative to the start of bus 0.
+    pub fn offset(&self) -> u64 {
+        (u64::from(self.bus) << 20)
+            | (u64::from(self.device) << 15)
+            | (u64::from(self.function) << 12)
+            | u64::from(self.register)
+    }
+
+    // Identify the function, ignoring the register.
+    fn function_key(&self) -> (u8, u8, u8) {
+        (self.bus, self.device, self.function)
+    }
+}
+
+/// A PCI function, as seen through its configuration space.
+pub trait PciFunction: Send + Sync {
+    /// Read `data.len()` bytes of the configuration space at `register`.
+    fn read_config(&self, register: u16, data: &mut [u8]);
+
+    /// Write `data` to the configuration space at `register`.
+    fn write_config(&self, register: u16, data: &[u8]);
+}
+
+/// An ECAM region covering buses `first_bus` to `last_bus`, dispatching configuration space
+/// accesses to the functions added to it.
+pub struct EcamRegion {
+    first_bus: u8,
+    last_bus: u8,
+    functions: BTreeMap<(u8, u8, u8), Arc<dyn PciFunction>>,
+}
+
+impl EcamRegion {
+    /// Create an empty region covering buses `first_bus` to `last_bus`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `last_bus` is below `first_bus`.
+    pub fn new(first_bus: u8, last_bus: u8) -> Self {
+        assert!(first_bus <= last_bus, "invalid bus range");
+        EcamRegion {
+            first_bus,
+            last_bus,
+            functions: BTreeMap::new(),
+        }
+    }
+
+    /// Return the MMIO range the region takes when mapped at `base`.
+    pub fn range(&self, base: MmioAddress) -> MmioRange {
+        let buses = u64::from(self.last_bus - self.first_bus) + 1;
+        MmioRange::new(base, buses << 20).unwrap()
+    }
+
+    /// Add `function` at `address`, whose register is ignored, returning it back if the
+    /// address is outside of the region or already taken.
+    pub fn add(
+        &mut self,
+        address: EcamAddress,
+        function: Arc<dyn PciFunction>,
+    ) -> Result<(), Arc<dyn PciFunction>> {
+        let key = address.function_key();
+        if address.bus < self.first_bus
+            || address.bus > self.last_bus
+            || self.functions.contains_key(&key)
+        {
+            return Err(function);
+        }
+        self.functions.insert(key, function);
+        Ok(())
+    }
+
+    /// Remove the function at `address`, whose register is ignored.
+    pub fn remove(&mut self, address: EcamAddress) -> Option<Arc<dyn PciFunction>> {
+        self.functions.remove(&address.function_key())
+    }
+
+    // Return the address of an access at `offset` of the region with `len` bytes, if it
+    // doesn't cross into the next configuration space, and the function it targets.
+    fn decode(&self, offset: u64, len: usize) -> (EcamAddress, Option<&Arc<dyn PciFunction>>) {
+        let address = EcamAddress::from_offset(offset + (u64::from(self.first_bus) << 20));
+        let function = Some(address)
+            .filter(|address| u64::from(address.register) + len as u64 <= CONFIG_SPACE_SIZE)
+            .and_then(|address| self.functions.get(&address.function_key()));
+        (address, function)
+    }
+}
+
+impl DeviceMmio for EcamRegion {
+    fn mmio_read(&self, _base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+        match self.decode(offset, data.len()) {
+            (address, Some(function)) => function.read_config(address.register, data),
+            (_, None) => data.fill(0xff),
+        }
+    }
+
+    fn mmio_write(&self, _base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+        if let (address, Some(function)) = self.decode(offset, data.len()) {
+            function.write_config(address.register, data);
+        }
+    }
+
+    fn introspect(&self) -> Vec<(String, String)> {
+        vec![
+            (
+                "buses".to_string(),
+                format!("{}-{}", self.first_bus, self.last_bus),
+            ),
+            ("functions".to_string(), self.functions.len().to_string()),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    // Function with a plain RAM configuration space.
+    struct Config(Mutex<Vec<u8>>);
+
+    impl PciFunction for Config {
+        fn read_config(&self, register: u16, data: &mut [u8]) {
+            let start = register as usize;
+            data.copy_from_slice(&self.0.lock().unwrap()[start..start + data.len()]);
+        }
+
+        fn write_config(&self, register: u16, data: &[u8]) {
+            let start = register as usize;
+            self.0.lock().unwrap()[start..start + data.len()].copy_from_slice(data);
+        }
+    }
+
+    #[test]
+    fn test_ecam_address() {
+        let address = EcamAddress::from_offset(0x00ab_8ffc);
+        assert_eq!(address, EcamAddress::new(0xa, 0x17, 0, 0xffc));
+        assert_eq!(address.offset(), 0x00ab_8ffc);
+        assert_eq!(EcamAddress::new(1, 0xff, 0xff, 0xffff).offset(), 0x1f_ffff);
+    }
+
+    #[test]
+    fn test_ecam_region() {
+        let function = Arc::new(Config(Mutex::new(vec![0; CONFIG_SPACE_SIZE as usize])));
+        let mut ecam = EcamRegion::new(2, 3);
+        assert_eq!(ecam.range(MmioAddress(0)).size(), 0x20_0000);
+        let address = EcamAddress::new(3, 1, 2, 0);
+        assert!(ecam.add(address, function.clone()).is_ok());
+        assert!(ecam.add(address, function.clone()).is_err());
+        assert!(ecam
+            .add(EcamAddress::new(4, 0, 0, 0), function.clone())
+            .is_err());
+
+        // Offsets are relative to the first bus of the region.
+        let offset = address.offset() - (2 << 20);
+        ecam.mmio_write(MmioAddress(0), offset + 0x10, &[1, 2, 3, 4]);
+        let mut data = [0; 4];
+        ecam.mmio_read(MmioAddress(0), offset + 0x10, &mut data);
+        assert_eq!(data, [1, 2, 3, 4]);
+        assert_eq!(function.0.lock().unwrap()[0x10..0x14], [1, 2, 3, 4]);
+
+        // Accesses crossing the end of the configuration space are dropped.
+        ecam.mmio_read(MmioAddress(0), offset + 0xffe, &mut data);
+        assert_eq!(data, [0xff; 4]);
+        ecam.mmio_write(MmioAddress(0), offset + 0xffe, &[1, 2, 3, 4]);
+
+        assert!(ecam.remove(address).is_some());
+        ecam.mmio_read(MmioAddress(0), offset + 0x10, &mut data);
+        assert_eq!(data, [0xff; 4]);
+    }
+}
@@ -0,0 +1,256 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Devices running on a dedicated thread.
+//!
+//! Devices built on backend libraries which aren't `Send` can't be shared with the vCPU
+//! threads. [`ProxyDevice::spawn`] creates such a device on a worker thread which owns it,
+//! and the returned proxy is registered on the bus instead: each access is sent to the
+//! worker, and the vCPU thread waits for the reply, up to a timeout.
+//!
+//! ```
+//! # use std::cell::Cell;
+//! # use std::rc::Rc;
+//! # use std::sync::Arc;
+//! # use std::time::Duration;
+//! use vm_device::bus::{MmioAddress, MmioAddressOffset, MmioRange};
+//! use vm_device::device_manager::{IoManager, MmioManager};
+//! use vm_device::proxy::ProxyDevice;
+//! use vm_device::MutDeviceMmio;
+//!
+//! // A device holding a handle which can't leave its thread.
+//! struct Backend(Rc<Cell<u8>>);
+//!
+//! impl MutDeviceMmio for Backend {
+//!     fn mmio_read(&mut self, _: MmioAddress, _: MmioAddressOffset, data: &mut [u8]) {
+//!         data.fill(self.0.get());
+//!     }
+//!
+//!     fn mmio_write(&mut self, _: MmioAddress, _: MmioAddressOffset, data: &[u8]) {
+//!         self.0.set(data[0]);
+//!     }
+//! }
+//!
+//! let proxy = ProxyDevice::spawn("backend", Duration::from_secs(1), || {
+//!     Backend(Rc::new(Cell::new(0)))
+//! })
+//! .unwrap();
+//! let mut manager = IoManager::new();
+//! let range = MmioRange::new(MmioAddress(0x1000), 0x10).unwrap();
+//! manager.register_mmio(range, Arc::new(proxy)).unwrap();
+//! manager.mmio_write(MmioAddress(0x1000), &[7]).unwrap();
+//! let mut data = [0; 2];
+//! manager.mmio_read(MmioAddress(0x1000), &mut data).unwrap();
+//! assert_eq!(data, [7; 2]);
+//! ```
+
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender};
+use std::thread;
+use std::time::Duration;
+
+use crate::bus::{MmioAddress, MmioAddressOffset};
+use crate::trace;
+use crate::{DeviceMmio, MutDeviceMmio};
+
+/// Errors of the accesses forwarded by a [`ProxyDevice`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The worker didn't reply within the timeout.
+    Timeout,
+    /// The worker thread is gone, e.g. because the device panicked.
+    Disconnected,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Timeout => write!(f, "proxy: the device didn't reply in time"),
+            Error::Disconnected => write!(f, "proxy: the device thread is gone"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+// Direction and data of a forwarded access.
+enum Access {
+    Read(usize),
+    Write(Vec<u8>),
+}
+
+// An access forwarded to the worker, which sends the read data, or nothing for writes, back
+// through `reply`.
+struct Request {
+    base: MmioAddress,
+    offset: MmioAddressOffset,
+    access: Access,
+    reply: SyncSender<Vec<u8>>,
+}
+
+/// Forwards the accesses to a device owned by a worker thread.
+///
+/// Reads which fail, because the worker didn't reply within the timeout or is gone, return
+/// zeros. The failures are logged with the `log` feature, and counted by
+/// [`ProxyDevice::failures`]. The worker exits once the proxy is dropped and the pending
+/// accesses are handled.
+pub struct ProxyDevice {
+    sender: Sender<Request>,
+    timeout: Duration,
+    failures: AtomicU64,
+}
+
+impl ProxyDevice {
+    /// Spawn a worker thread named `name`, owning the device created by `factory`, and
+    /// return the proxy forwarding the accesses to it.
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: name of the worker thread
+    /// * `timeout`: how long each access waits for the worker
+    /// * `factory`: closure creating the device on the worker thread
+    pub fn spawn<D, F>(name: &str, timeout: Duration, factory: F) -> io::Result<Self>
+    where
+        D: MutDeviceMmio,
+        F: FnOnce() -> D + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || serve(factory(), receiver))?;
+        Ok(ProxyDevice {
+            sender,
+            timeout,
+            failures: AtomicU64::new(0),
+        })
+    }
+
+    /// Return how many accesses failed.
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    // Forward an access to the worker and wait for its reply.
+    fn forward(
+        &self,
+        base: MmioAddress,
+        offset: MmioAddressOffset,
+        access: Access,
+    ) -> Result<Vec<u8>, Error> {
+        let (reply, receiver) = mpsc::sync_channel(1);
+        let request = Request {
+            base,
+            offset,
+            access,
+            reply,
+        };
+        self.sender.send(request).map_err(|_| Error::Disconnected)?;
+        receiver.recv_timeout(self.timeout).map_err(|e| match e {
+            RecvTimeoutError::Timeout => Error::Timeout,
+            RecvTimeoutError::Disconnected => Error::Disconnected,
+        })
+    }
+
+    fn failed(&self, error: Error) {
+        self.failures.fetch_add(1, Ordering::Relaxed);
+        trace::device_failed(&error);
+    }
+}
+
+// Handle the requests sent to the worker until the proxy is dropped.
+fn serve<D: MutDeviceMmio>(mut device: D, receiver: Receiver<Request>) {
+    for request in receiver {
+        let data = match request.access {
+            Access::Read(len) => {
+                let mut data = vec![0; len];
+                device.mmio_read(request.base, request.offset, &mut data);
+                data
+            }
+            Access::Write(data) => {
+                device.mmio_write(request.base, request.offset, &data);
+                Vec::new()
+            }
+        };
+        // The access may have timed out already, in which case nobody waits for the reply.
+        let _ = request.reply.send(data);
+    }
+}
+
+impl DeviceMmio for ProxyDevice {
+    fn mmio_read(&self, base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+        match self.forward(base, offset, Access::Read(data.len())) {
+            Ok(reply) => data.copy_from_slice(&reply),
+            Err(error) => {
+                data.fill(0);
+                self.failed(error);
+            }
+        }
+    }
+
+    fn mmio_write(&self, base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+        if let Err(error) = self.forward(base, offset, Access::Write(data.to_vec())) {
+            self.failed(error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    // Sleeps on the reads at offset 0x10, and panics on the writes at offset 0x20.
+    struct Backend(Rc<Cell<u8>>);
+
+    impl MutDeviceMmio for Backend {
+        fn mmio_read(&mut self, _: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+            if offset == 0x10 {
+                thread::sleep(Duration::from_millis(200));
+            }
+            data.fill(self.0.get());
+        }
+
+        fn mmio_write(&mut self, _: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+            assert_ne!(offset, 0x20, "device failure");
+            self.0.set(data[0]);
+        }
+    }
+
+    #[test]
+    fn test_proxy_device() {
+        let proxy = ProxyDevice::spawn("proxy-test", Duration::from_millis(50), || {
+            Backend(Rc::new(Cell::new(0)))
+        })
+        .unwrap();
+        let base = MmioAddress(0x1000);
+
+        proxy.mmio_write(base, 0, &[3]);
+        let mut data = [0xff; 4];
+        proxy.mmio_read(base, 0, &mut data);
+        assert_eq!(data, [3; 4]);
+        assert_eq!(proxy.failures(), 0);
+
+        // Slow accesses time out, and the later ones are handled once the worker catches up.
+        proxy.mmio_read(base, 0x10, &mut data);
+        assert_eq!(data, [0; 4]);
+        assert_eq!(proxy.failures(), 1);
+        thread::sleep(Duration::from_millis(200));
+        proxy.mmio_write(base, 0, &[4]);
+        proxy.mmio_read(base, 0, &mut data);
+        assert_eq!(data, [4; 4]);
+
+        // A panicking device takes the worker down.
+        proxy.mmio_write(base, 0x20, &[5]);
+        proxy.mmio_read(base, 0, &mut data);
+        assert_eq!(data, [0; 4]);
+        assert_eq!(proxy.failures(), 3);
+        assert_eq!(
+            proxy.forward(base, 0, Access::Read(1)),
+            Err(Error::Disconnected)
+        );
+    }
+}
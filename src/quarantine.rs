@@ -0,0 +1,523 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Isolation of panicking devices.
+//!
+//! A device panicking in the middle of an access unwinds through the bus into the vCPU
+//! thread, which usually takes the whole VM down. [`Quarantined`] wraps a device and catches
+//! the panics of its accesses: the device is quarantined, the later accesses don't reach it
+//! anymore, reads returning a fixed value, and the panic is reported to a handler, e.g. to
+//! let the VMM reset or hot-unplug the device.
+//!
+//! ```
+//! # use std::sync::Arc;
+//! use vm_device::bus::{MmioAddress, MmioAddressOffset, MmioRange};
+//! use vm_device::device_manager::{IoManager, MmioManager};
+//! use vm_device::quarantine::Quarantined;
+//! use vm_device::DeviceMmio;
+//!
+//! struct Buggy;
+//!
+//! impl DeviceMmio for Buggy {
+//!     fn mmio_read(&self, _: MmioAddress, _: MmioAddressOffset, _: &mut [u8]) {
+//!         unimplemented!()
+//!     }
+//!
+//!     fn mmio_write(&self, _: MmioAddress, _: MmioAddressOffset, _: &[u8]) {}
+//! }
+//!
+//! let device = Arc::new(Quarantined::new(Buggy).with_handler(|panic| eprintln!("{}", panic)));
+//! let mut manager = IoManager::new();
+//! let range = MmioRange::new(MmioAddress(0x1000), 0x10).unwrap();
+//! manager.register_mmio(range, device.clone()).unwrap();
+//! # let hook = std::panic::take_hook();
+//! # std::panic::set_hook(Box::new(|_| {}));
+//! let mut data = [0; 4];
+//! manager.mmio_read(MmioAddress(0x1000), &mut data).unwrap();
+//! # std::panic::set_hook(hook);
+//! assert_eq!(data, [0xff; 4]);
+//! assert!(device.is_quarantined());
+//! ```
+//!
+//! Panics can only be caught when the VMM is built with `panic = "unwind"`, the default.
+//!
+//! Devices reporting themselves unhealthy (see [`health`](crate::health)) are quarantined
+//! too, when their health is checked, e.g. with `IoManager::check_health`.
+
+use std::any::Any;
+use std::fmt::{Display, Formatter};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::bus::{MmioAddress, MmioAddressOffset, PioAddress, PioAddressOffset};
+use crate::completion::Completion;
+use crate::exit::ExitAccess;
+use crate::health::Health;
+use crate::info::DeviceDetails;
+use crate::resources::Resource;
+use crate::stats::Statistics;
+use crate::trace;
+use crate::{DeviceMmio, DevicePio};
+
+/// A panic caught by a [`Quarantined`] device.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Panic {
+    /// Bus of the access, `"mmio"` or `"pio"`.
+    pub bus: &'static str,
+    /// Base address of the range of the device.
+    pub base: u64,
+    /// Offset of the access in the range.
+    pub offset: u64,
+    /// Whether the access was a write.
+    pub write: bool,
+    /// Message of the panic, if it's a string.
+    pub message: Option<String>,
+}
+
+impl Display for Panic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let op = if self.write { "write" } else { "read" };
+        write!(
+            f,
+            "quarantine: device panicked on {} {} at {:#x}+{:#x}",
+            self.bus, op, self.base, self.offset
+        )?;
+        if let Some(message) = &self.message {
+            write!(f, ": {}", message)?;
+        }
+        Ok(())
+    }
+}
+
+type Handler = Arc<dyn Fn(&Panic) + Send + Sync>;
+
+/// Catches the panics of a device, quarantining it.
+///
+/// Once quarantined, the accesses don't reach the device until [`Quarantined::release`] is
+/// called: reads return the bytes set with [`Quarantined::with_read_value`], `0xff` by
+/// default like a missing device, and writes are ignored. Debug reads are forwarded until
+/// the device is quarantined, and aren't supported afterwards. The panic is reported to
+/// the handler, if any, and with the `log` feature.
+pub struct Quarantined<D> {
+    device: D,
+    read_value: u8,
+    handler: Option<Handler>,
+    quarantined: AtomicBool,
+}
+
+impl<D> Quarantined<D> {
+    /// Wrap `device`, catching its panics.
+    pub fn new(device: D) -> Self {
+        Quarantined {
+            device,
+            read_value: 0xff,
+            handler: None,
+            quarantined: AtomicBool::new(false),
+        }
+    }
+
+    /// Fill the reads from the device with `value` once quarantined.
+    pub fn with_read_value(mut self, value: u8) -> Self {
+        self.read_value = value;
+        self
+    }
+
+    /// Report the panic quarantining the device to `handler`.
+    pub fn with_handler<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(&Panic) + Send + Sync + 'static,
+    {
+        self.handler = Some(Arc::new(handler));
+        self
+    }
+
+    /// Return the device.
+    pub fn inner(&self) -> &D {
+        &self.device
+    }
+
+    /// Return whether the device is quarantined.
+    pub fn is_quarantined(&self) -> bool {
+        self.quarantined.load(Ordering::Acquire)
+    }
+
+    /// Let the accesses reach the device again, e.g. once it was reset.
+    pub fn release(&self) {
+        self.quarantined.store(false, Ordering::Release);
+    }
+
+    // Run `access` on the device, unless it's quarantined, and quarantine it if `access`
+    // panics. Return whether the access completed.
+    fn guard<F: FnOnce()>(&self, access: F, panic: impl FnOnce(Option<String>) -> Panic) -> bool {
+        if self.is_quarantined() {
+            return false;
+        }
+        let payload = match panic::catch_unwind(AssertUnwindSafe(access)) {
+            Ok(()) => return true,
+            Err(payload) => payload,
+        };
+        self.quarantined.store(true, Ordering::Release);
+        let panic = panic(message(payload.as_ref()));
+        trace::device_failed(&panic);
+        if let Some(handler) = &self.handler {
+            handler(&panic);
+        }
+        false
+    }
+
+    // Quarantine the device if `health` is unhealthy. A quarantined device is reported
+    // unhealthy until released.
+    fn check_health(&self, health: impl FnOnce() -> Health) -> Health {
+        if self.is_quarantined() {
+            return Health::Unhealthy("quarantined".to_string());
+        }
+        let health = health();
+        if !health.is_healthy() {
+            self.quarantined.store(true, Ordering::Release);
+        }
+        health
+    }
+
+    fn properties(&self, mut properties: Vec<(String, String)>) -> Vec<(String, String)> {
+        properties.push(("quarantined".to_string(), self.is_quarantined().to_string()));
+        properties
+    }
+}
+
+// Return the message of a panic raised with `panic!`.
+fn message(payload: &(dyn Any + Send)) -> Option<String> {
+    payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+}
+
+impl<D: DeviceMmio> DeviceMmio for Quarantined<D> {
+    fn mmio_read(&self, base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+        let panic = |message| Panic {
+            bus: "mmio",
+            base: base.0,
+            offset,
+            write: false,
+            message,
+        };
+        if !self.guard(|| self.device.mmio_read(base, offset, data), panic) {
+            data.fill(self.read_value);
+        }
+    }
+
+    fn mmio_write(&self, base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+        let panic = |message| Panic {
+            bus: "mmio",
+            base: base.0,
+            offset,
+            write: true,
+            message,
+        };
+        self.guard(|| self.device.mmio_write(base, offset, data), panic);
+    }
+
+    fn introspect(&self) -> Vec<(String, String)> {
+        self.properties(self.device.introspect())
+    }
+
+    fn info(&self) -> Option<DeviceDetails> {
+        self.device.info()
+    }
+
+    fn health(&self) -> Health {
+        self.check_health(|| self.device.health())
+    }
+
+    fn stats(&self) -> Option<Statistics> {
+        self.device.stats()
+    }
+
+    fn update_resources(&self, resources: &[Resource]) {
+        self.device.update_resources(resources)
+    }
+
+    fn debug_read(&self, base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) -> bool {
+        let panic = |message| Panic {
+            bus: "mmio",
+            base: base.0,
+            offset,
+            write: false,
+            message,
+        };
+        let mut supported = false;
+        let access = || supported = self.device.debug_read(base, offset, data);
+        self.guard(access, panic) && supported
+    }
+
+    fn mmio_update(
+        &self,
+        base: MmioAddress,
+        offset: MmioAddressOffset,
+        data: &mut [u8],
+        update: &mut dyn FnMut(&mut [u8]),
+    ) {
+        let panic = |message| Panic {
+            bus: "mmio",
+            base: base.0,
+            offset,
+            write: true,
+            message,
+        };
+        let access = || self.device.mmio_update(base, offset, &mut *data, update);
+        if !self.guard(access, panic) {
+            data.fill(self.read_value);
+        }
+    }
+
+    fn mmio_deferred(
+        &self,
+        base: MmioAddress,
+        offset: MmioAddressOffset,
+        access: ExitAccess<'_>,
+    ) -> Completion {
+        let mut completion = Completion::Done;
+        let panic = |write| {
+            move |message| Panic {
+                bus: "mmio",
+                base: base.0,
+                offset,
+                write,
+                message,
+            }
+        };
+        match access {
+            ExitAccess::Read(data) => {
+                let access = || {
+                    completion =
+                        self.device
+                            .mmio_deferred(base, offset, ExitAccess::Read(&mut *data))
+                };
+                if !self.guard(access, panic(false)) {
+                    data.fill(self.read_value);
+                }
+            }
+            ExitAccess::Write(data) => {
+                let access = || {
+                    completion = self
+                        .device
+                        .mmio_deferred(base, offset, ExitAccess::Write(data))
+                };
+                self.guard(access, panic(true));
+            }
+        }
+        completion
+    }
+}
+
+impl<D: DevicePio> DevicePio for Quarantined<D> {
+    fn pio_read(&self, base: PioAddress, offset: PioAddressOffset, data: &mut [u8]) {
+        let panic = |message| Panic {
+            bus: "pio",
+            base: base.0.into(),
+            offset: offset.into(),
+            write: false,
+            message,
+        };
+        if !self.guard(|| self.device.pio_read(base, offset, data), panic) {
+            data.fill(self.read_value);
+        }
+    }
+
+    fn pio_write(&self, base: PioAddress, offset: PioAddressOffset, data: &[u8]) {
+        let panic = |message| Panic {
+            bus: "pio",
+            base: base.0.into(),
+            offset: offset.into(),
+            write: true,
+            message,
+        };
+        self.guard(|| self.device.pio_write(base, offset, data), panic);
+    }
+
+    fn introspect(&self) -> Vec<(String, String)> {
+        self.properties(self.device.introspect())
+    }
+
+    fn info(&self) -> Option<DeviceDetails> {
+        self.device.info()
+    }
+
+    fn health(&self) -> Health {
+        self.check_health(|| self.device.health())
+    }
+
+    fn stats(&self) -> Option<Statistics> {
+        self.device.stats()
+    }
+
+    fn debug_read(&self, base: PioAddress, offset: PioAddressOffset, data: &mut [u8]) -> bool {
+        let panic = |message| Panic {
+            bus: "pio",
+            base: base.0.into(),
+            offset: offset.into(),
+            write: false,
+            message,
+        };
+        let mut supported = false;
+        let access = || supported = self.device.debug_read(base, offset, data);
+        self.guard(access, panic) && supported
+    }
+
+    fn pio_deferred(
+        &self,
+        base: PioAddress,
+        offset: PioAddressOffset,
+        access: ExitAccess<'_>,
+    ) -> Completion {
+        let mut completion = Completion::Done;
+        let panic = |write| {
+            move |message| Panic {
+                bus: "pio",
+                base: base.0.into(),
+                offset: offset.into(),
+                write,
+                message,
+            }
+        };
+        match access {
+            ExitAccess::Read(data) => {
+                let access = || {
+                    completion =
+                        self.device
+                            .pio_deferred(base, offset, ExitAccess::Read(&mut *data))
+                };
+                if !self.guard(access, panic(false)) {
+                    data.fill(self.read_value);
+                }
+            }
+            ExitAccess::Write(data) => {
+                let access = || {
+                    completion = self
+                        .device
+                        .pio_deferred(base, offset, ExitAccess::Write(data))
+                };
+                self.guard(access, panic(true));
+            }
+        }
+        completion
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use crate::bus::{MmioRange, PioRange};
+    use crate::device_manager::{IoManager, MmioManager, PioManager};
+    use crate::testing::Scratchpad;
+
+    // Panics on the accesses at offset 4.
+    struct Buggy(Scratchpad);
+
+    impl DeviceMmio for Buggy {
+        fn mmio_read(&self, base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+            assert_ne!(offset, 4, "bad offset");
+            self.0.mmio_read(base, offset, data)
+        }
+
+        fn mmio_write(&self, base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+            assert_ne!(offset, 4, "bad offset");
+            self.0.mmio_write(base, offset, data)
+        }
+
+        fn debug_read(
+            &self,
+            base: MmioAddress,
+            offset: MmioAddressOffset,
+            data: &mut [u8],
+        ) -> bool {
+            assert_ne!(offset, 4, "bad offset");
+            DeviceMmio::debug_read(&self.0, base, offset, data)
+        }
+    }
+
+    impl DevicePio for Buggy {
+        fn pio_read(&self, base: PioAddress, offset: PioAddressOffset, data: &mut [u8]) {
+            assert_ne!(offset, 4, "bad offset");
+            self.0.pio_read(base, offset, data)
+        }
+
+        fn pio_write(&self, base: PioAddress, offset: PioAddressOffset, data: &[u8]) {
+            assert_ne!(offset, 4, "bad offset");
+            self.0.pio_write(base, offset, data)
+        }
+
+        fn debug_read(&self, base: PioAddress, offset: PioAddressOffset, data: &mut [u8]) -> bool {
+            DevicePio::debug_read(&self.0, base, offset, data)
+        }
+    }
+
+    #[test]
+    fn test_quarantine() {
+        let panics = Arc::new(Mutex::new(Vec::new()));
+        let recorded = panics.clone();
+        let device = Arc::new(
+            Quarantined::new(Buggy(Scratchpad::new(8)))
+                .with_read_value(0xee)
+                .with_handler(move |panic| recorded.lock().unwrap().push(panic.clone())),
+        );
+        let mut manager = IoManager::new();
+        let range = MmioRange::new(MmioAddress(0x1000), 8).unwrap();
+        manager.register_mmio(range, device.clone()).unwrap();
+        let range = PioRange::new(PioAddress(0x60), 8).unwrap();
+        manager.register_pio(range, device.clone()).unwrap();
+
+        manager.mmio_write(MmioAddress(0x1000), &[1, 2]).unwrap();
+        let mut data = [0; 2];
+        manager.pio_read(PioAddress(0x60), &mut data).unwrap();
+        assert_eq!(data, [1, 2]);
+        assert!(!device.is_quarantined());
+        data = [0; 2];
+        assert!(manager
+            .mmio_debug_read(MmioAddress(0x1000), &mut data)
+            .unwrap());
+        assert_eq!(data, [1, 2]);
+
+        manager.mmio_write(MmioAddress(0x1004), &[3]).unwrap();
+        assert!(device.is_quarantined());
+        manager.mmio_read(MmioAddress(0x1000), &mut data).unwrap();
+        assert_eq!(data, [0xee; 2]);
+        assert!(!manager.pio_debug_read(PioAddress(0x60), &mut data).unwrap());
+        manager.pio_write(PioAddress(0x60), &[4]).unwrap();
+        let recorded = panics.lock().unwrap()[0].clone();
+        assert_eq!(
+            (recorded.bus, recorded.base, recorded.offset, recorded.write),
+            ("mmio", 0x1000, 4, true)
+        );
+        assert!(recorded.message.unwrap().contains("bad offset"));
+        assert_eq!(
+            DeviceMmio::introspect(&*device),
+            [("quarantined".to_string(), "true".to_string())]
+        );
+
+        // The device is reachable again once released.
+        device.release();
+        manager.pio_read(PioAddress(0x60), &mut data).unwrap();
+        assert_eq!(data, [1, 2]);
+        assert!(manager.pio_debug_read(PioAddress(0x60), &mut data).unwrap());
+        manager.pio_read(PioAddress(0x64), &mut data).unwrap();
+        assert_eq!(data, [0xee; 2]);
+        assert_eq!(panics.lock().unwrap()[1].bus, "pio");
+
+        // Debug reads panicking quarantine the device too.
+        device.release();
+        assert!(!manager
+            .mmio_debug_read(MmioAddress(0x1004), &mut data)
+            .unwrap());
+        assert!(device.is_quarantined());
+        let recorded = panics.lock().unwrap()[2].clone();
+        assert_eq!(
+            (recorded.bus, recorded.offset, recorded.write),
+            ("mmio", 4, false)
+        );
+    }
+}
@@ -0,0 +1,261 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Memory-backed regions served by the bus.
+//!
+//! Many devices expose ranges that behave like plain memory, e.g. the MSI-X table the guest
+//! programs and the device model reads back, or scratch registers. A [`RamRegion`] serves
+//! such a range on its own: reads return the bytes last written at the same offsets, and
+//! an optional callback is notified of the guest writes, e.g. to apply an updated MSI-X
+//! entry. The device model accesses the same memory through [`RamRegion::read`] and
+//! [`RamRegion::write`].
+//!
+//! ```
+//! # use std::sync::{Arc, Mutex};
+//! use vm_device::bus::{MmioAddress, MmioRange};
+//! use vm_device::device_manager::{IoManager, MmioManager};
+//! use vm_device::ram::RamRegion;
+//!
+//! let writes = Arc::new(Mutex::new(Vec::new()));
+//! let log = writes.clone();
+//! let table = Arc::new(
+//!     RamRegion::new(0x40).with_write_callback(move |offset, data| {
+//!         log.lock().unwrap().push((offset, data.len()));
+//!     }),
+//! );
+//! let mut manager = IoManager::new();
+//! let range = MmioRange::new(MmioAddress(0x1000), 0x40).unwrap();
+//! manager.register_mmio(range, table.clone()).unwrap();
+//!
+//! manager.mmio_write(MmioAddress(0x1010), &[1, 2, 3, 4]).unwrap();
+//! let mut entry = [0; 4];
+//! table.read(0x10, &mut entry);
+//! assert_eq!(entry, [1, 2, 3, 4]);
+//! assert_eq!(*writes.lock().unwrap(), [(0x10, 4)]);
+//! ```
+
+use std::convert::TryFrom;
+use std::sync::{Arc, RwLock};
+
+use crate::bus::{self, MmioAddress, MmioAddressOffset, MmioRange, PioAddress, PioAddressOffset};
+use crate::device_manager::{IoManager, MmioManager};
+use crate::{DeviceMmio, DevicePio};
+
+type WriteCallback = Arc<dyn Fn(u64, &[u8]) + Send + Sync>;
+
+/// A region of memory, served as a device.
+///
+/// Accesses beyond the size of the region read as zeroes, and writes there are ignored.
+/// Reads have no side effects, so they are also served as debug reads.
+pub struct RamRegion {
+    memory: RwLock<Vec<u8>>,
+    on_write: Option<WriteCallback>,
+}
+
+impl RamRegion {
+    /// Create a zeroed region of `size` bytes.
+    pub fn new(size: usize) -> Self {
+        RamRegion::from_contents(vec![0; size])
+    }
+
+    /// Create a region holding `contents`.
+    pub fn from_contents(contents: Vec<u8>) -> Self {
+        RamRegion {
+            memory: RwLock::new(contents),
+            on_write: None,
+        }
+    }
+
+    /// Notify `callback` of the writes of the guest, with their offset in the region and
+    /// the bytes written, once the region is updated.
+    pub fn with_write_callback<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(u64, &[u8]) + Send + Sync + 'static,
+    {
+        self.on_write = Some(Arc::new(callback));
+        self
+    }
+
+    /// Return the size of the region.
+    pub fn len(&self) -> usize {
+        self.memory.read().unwrap().len()
+    }
+
+    /// Return whether the region is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Return a copy of the region contents.
+    pub fn contents(&self) -> Vec<u8> {
+        self.memory.read().unwrap().clone()
+    }
+
+    /// Read the bytes at `offset` into `data`, as the guest would.
+    pub fn read(&self, offset: u64, data: &mut [u8]) {
+        read(&self.memory.read().unwrap(), offset, data);
+    }
+
+    /// Write `data` at `offset`, without notifying the write callback.
+    pub fn write(&self, offset: u64, data: &[u8]) {
+        write(&mut self.memory.write().unwrap(), offset, data);
+    }
+
+    // Write `data` at `offset` for the guest, and notify the callback.
+    fn guest_write(&self, offset: u64, data: &[u8]) {
+        self.write(offset, data);
+        if let Some(callback) = &self.on_write {
+            callback(offset, data);
+        }
+    }
+}
+
+// Return the part of `len` bytes at `offset` within `size` bytes, as a range of positions.
+fn span(size: usize, offset: u64, len: usize) -> std::ops::Range<usize> {
+    let start = usize::try_from(offset).unwrap_or(usize::MAX).min(size);
+    start..start.saturating_add(len).min(size)
+}
+
+fn read(memory: &[u8], offset: u64, data: &mut [u8]) {
+    let span = span(memory.len(), offset, data.len());
+    let (within, beyond) = data.split_at_mut(span.len());
+    within.copy_from_slice(&memory[span]);
+    beyond.fill(0);
+}
+
+fn write(memory: &mut [u8], offset: u64, data: &[u8]) {
+    let span = span(memory.len(), offset, data.len());
+    let len = span.len();
+    memory[span].copy_from_slice(&data[..len]);
+}
+
+impl DeviceMmio for RamRegion {
+    fn mmio_read(&self, _base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+        self.read(offset, data);
+    }
+
+    fn mmio_write(&self, _base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+        self.guest_write(offset, data);
+    }
+
+    fn introspect(&self) -> Vec<(String, String)> {
+        vec![("size".to_string(), self.len().to_string())]
+    }
+
+    fn debug_read(&self, _base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) -> bool {
+        self.read(offset, data);
+        true
+    }
+
+    fn mmio_update(
+        &self,
+        _base: MmioAddress,
+        offset: MmioAddressOffset,
+        data: &mut [u8],
+        update: &mut dyn FnMut(&mut [u8]),
+    ) {
+        {
+            let mut memory = self.memory.write().unwrap();
+            read(&memory, offset, data);
+            update(data);
+            write(&mut memory, offset, data);
+        }
+        if let Some(callback) = &self.on_write {
+            callback(offset, data);
+        }
+    }
+}
+
+impl DevicePio for RamRegion {
+    fn pio_read(&self, _base: PioAddress, offset: PioAddressOffset, data: &mut [u8]) {
+        self.read(u64::from(offset), data);
+    }
+
+    fn pio_write(&self, _base: PioAddress, offset: PioAddressOffset, data: &[u8]) {
+        self.guest_write(u64::from(offset), data);
+    }
+
+    fn introspect(&self) -> Vec<(String, String)> {
+        vec![("size".to_string(), self.len().to_string())]
+    }
+
+    fn debug_read(&self, _base: PioAddress, offset: PioAddressOffset, data: &mut [u8]) -> bool {
+        self.read(u64::from(offset), data);
+        true
+    }
+}
+
+impl IoManager {
+    /// Register a zeroed [`RamRegion`] as large as `range` on the MMIO bus, and return it.
+    pub fn register_mmio_ram(&mut self, range: MmioRange) -> Result<Arc<RamRegion>, bus::Error> {
+        let size = usize::try_from(range.size())
+            .map_err(|_| bus::Error::invalid_range(range.base(), range.size()))?;
+        let region = Arc::new(RamRegion::new(size));
+        self.register_mmio(range, region.clone())?;
+        Ok(region)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use crate::bus::PioRange;
+    use crate::device_manager::PioManager;
+
+    #[test]
+    fn test_ram_region() {
+        let mut manager = IoManager::new();
+        let range = MmioRange::new(MmioAddress(0x1000), 0x10).unwrap();
+        let region = manager.register_mmio_ram(range).unwrap();
+        assert_eq!(region.len(), 0x10);
+        manager.mmio_write(MmioAddress(0x100e), &[1, 2]).unwrap();
+        region.write(0, &[3; 4]);
+        let mut data = [0; 4];
+        manager.mmio_read(MmioAddress(0x100c), &mut data).unwrap();
+        assert_eq!(data, [0, 0, 1, 2]);
+        assert!(manager
+            .mmio_debug_read(MmioAddress(0x1000), &mut data)
+            .unwrap());
+        assert_eq!(data, [3; 4]);
+        let old = manager
+            .mmio_rmw(MmioAddress(0x1000), |value| value + 1)
+            .unwrap();
+        assert_eq!(old, 0x0303_0303);
+        assert_eq!(region.contents()[..4], [4, 3, 3, 3]);
+
+        // Accesses past the region read as zeroes and aren't written.
+        let small = RamRegion::from_contents(vec![1, 2]);
+        small.write(1, &[5, 6, 7]);
+        small.read(1, &mut data);
+        assert_eq!(data, [5, 0, 0, 0]);
+        small.read(u64::MAX, &mut data);
+        assert_eq!(data, [0; 4]);
+        assert_eq!(small.contents(), [1, 5]);
+    }
+
+    #[test]
+    fn test_write_callback() {
+        let writes = Arc::new(Mutex::new(Vec::new()));
+        let log = writes.clone();
+        let region = Arc::new(RamRegion::new(8).with_write_callback(move |offset, data| {
+            log.lock().unwrap().push((offset, data.to_vec()))
+        }));
+        let mut manager = IoManager::new();
+        let range = PioRange::new(PioAddress(0x60), 8).unwrap();
+        manager.register_pio(range, region.clone()).unwrap();
+        let range = MmioRange::new(MmioAddress(0x1000), 8).unwrap();
+        manager.register_mmio(range, region.clone()).unwrap();
+
+        manager.pio_write(PioAddress(0x64), &[1, 2]).unwrap();
+        manager.mmio_rmw(MmioAddress(0x1000), |_| 7).unwrap();
+        region.write(0, &[9]);
+        assert_eq!(
+            *writes.lock().unwrap(),
+            [(4, vec![1, 2]), (0, vec![7, 0, 0, 0])]
+        );
+        assert_eq!(region.contents(), [9, 0, 0, 0, 1, 2, 0, 0]);
+    }
+}
@@ -0,0 +1,310 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Creation of devices from their type name.
+//!
+//! A [`DeviceRegistry`] maps device type names, e.g. `virtio-blk`, to factories creating
+//! MMIO devices from their resources and a configuration blob, whose format is up to each
+//! device type. Code restoring a [`Layout`] or setting up devices from a configuration file
+//! then creates the devices through the registry, without compile-time knowledge of every
+//! device type:
+//!
+//! ```
+//! # use std::convert::TryInto;
+//! # use std::sync::Arc;
+//! # use vm_device::bus::{MmioAddress, MmioAddressOffset};
+//! use vm_device::device_manager::{IoManager, MmioManager};
+//! use vm_device::registry::DeviceRegistry;
+//! use vm_device::resources::{DeviceResources, Resource};
+//! # use vm_device::DeviceMmio;
+//! # struct Rtc(u32);
+//! # impl DeviceMmio for Rtc {
+//! #     fn mmio_read(&self, _: MmioAddress, _: MmioAddressOffset, data: &mut [u8]) {
+//! #         data.copy_from_slice(&self.0.to_le_bytes());
+//! #     }
+//! #     fn mmio_write(&self, _: MmioAddress, _: MmioAddressOffset, _: &[u8]) {}
+//! # }
+//!
+//! let mut registry = DeviceRegistry::new();
+//! registry
+//!     .register("rtc", |_resources, config| {
+//!         let time = u32::from_le_bytes(config.try_into()?);
+//!         Ok(Arc::new(Rtc(time)))
+//!     })
+//!     .unwrap();
+//!
+//! let mut manager = IoManager::new();
+//! let mut resources = DeviceResources::new();
+//! resources.append(Resource::MmioAddressRange {
+//!     base: 0x1000,
+//!     size: 4,
+//! });
+//! let config = 42u32.to_le_bytes();
+//! registry.add(&mut manager, "rtc", &resources, &config).unwrap();
+//! let mut data = [0; 4];
+//! manager.mmio_read(MmioAddress(0x1000), &mut data).unwrap();
+//! assert_eq!(u32::from_le_bytes(data), 42);
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
+use crate::device_manager::{self, IoManager, Layout};
+use crate::resources::DeviceResources;
+use crate::DeviceMmio;
+
+/// Error returned by a device factory.
+pub type FactoryError = Box<dyn std::error::Error + Send + Sync>;
+
+type Factory = Box<
+    dyn Fn(&DeviceResources, &[u8]) -> Result<Arc<dyn DeviceMmio + Send + Sync>, FactoryError>
+        + Send
+        + Sync,
+>;
+
+/// Errors encountered while creating devices from a [`DeviceRegistry`].
+#[derive(Debug)]
+pub enum Error {
+    /// A factory is already registered for the device type.
+    DuplicateType(String),
+    /// No factory is registered for the device type.
+    UnknownType(String),
+    /// The descriptor at the index of the layout doesn't have a device type.
+    MissingType(usize),
+    /// The factory failed to create the device.
+    Factory {
+        /// Type of the device.
+        device_type: String,
+        /// Cause of the failure.
+        error: FactoryError,
+    },
+    /// The device couldn't be registered.
+    Manager(device_manager::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::DuplicateType(device_type) => {
+                write!(
+                    f,
+                    "registry: device type {} already registered",
+                    device_type
+                )
+            }
+            Error::UnknownType(device_type) => {
+                write!(f, "registry: unknown device type {}", device_type)
+            }
+            Error::MissingType(index) => write!(f, "registry: device {} has no type", index),
+            Error::Factory { device_type, error } => {
+                write!(
+                    f,
+                    "registry: cannot create {} device: {}",
+                    device_type, error
+                )
+            }
+            Error::Manager(e) => write!(f, "registry: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Factory { error, .. } => Some(error.as_ref()),
+            Error::Manager(e) => Some(e),
+            Error::DuplicateType(_) | Error::UnknownType(_) | Error::MissingType(_) => None,
+        }
+    }
+}
+
+/// Factories creating MMIO devices, keyed by device type name.
+#[derive(Default)]
+pub struct DeviceRegistry {
+    factories: BTreeMap<String, Factory>,
+}
+
+impl DeviceRegistry {
+    /// Create an empty registry.
+    pub fn new() -> Self {
+        DeviceRegistry::default()
+    }
+
+    /// Create the devices of type `device_type` with `factory`, which is handed the
+    /// resources and the configuration blob of each device.
+    pub fn register<F>(&mut self, device_type: &str, factory: F) -> Result<(), Error>
+    where
+        F: Fn(&DeviceResources, &[u8]) -> Result<Arc<dyn DeviceMmio + Send + Sync>, FactoryError>
+            + Send
+            + Sync
+            + 'static,
+    {
+        if self.factories.contains_key(device_type) {
+            return Err(Error::DuplicateType(device_type.to_string()));
+        }
+        self.factories
+            .insert(device_type.to_string(), Box::new(factory));
+        Ok(())
+    }
+
+    /// Return whether a factory is registered for `device_type`.
+    pub fn contains(&self, device_type: &str) -> bool {
+        self.factories.contains_key(device_type)
+    }
+
+    /// Return the device types with a registered factory, sorted by name.
+    pub fn device_types(&self) -> impl Iterator<Item = &str> {
+        self.factories.keys().map(String::as_str)
+    }
+
+    /// Create a device of type `device_type`, owning `resources` and configured with
+    /// `config`.
+    pub fn create(
+        &self,
+        device_type: &str,
+        resources: &DeviceResources,
+        config: &[u8],
+    ) -> Result<Arc<dyn DeviceMmio + Send + Sync>, Error> {
+        let factory = self
+            .factories
+            .get(device_type)
+            .ok_or_else(|| Error::UnknownType(device_type.to_string()))?;
+        factory(resources, config).map_err(|error| Error::Factory {
+            device_type: device_type.to_string(),
+            error,
+        })
+    }
+
+    /// Create a device of type `device_type` (see [`DeviceRegistry::create`]), and register
+    /// it with `manager` (see [`IoManager::register_mmio_resources`]). Returns the device.
+    pub fn add(
+        &self,
+        manager: &mut IoManager,
+        device_type: &str,
+        resources: &DeviceResources,
+        config: &[u8],
+    ) -> Result<Arc<dyn DeviceMmio + Send + Sync>, Error> {
+        let device = self.create(device_type, resources, config)?;
+        manager
+            .register_mmio_resources(device.clone(), resources.get_all_resources())
+            .map_err(Error::Manager)?;
+        Ok(device)
+    }
+
+    /// Re-create the devices described by `layout` from their type and configuration, and
+    /// register them with `manager` (see [`IoManager::restore`]).
+    ///
+    /// All the devices are created before any of them is registered, so the manager is
+    /// left untouched when an error is returned.
+    pub fn restore(&self, manager: &mut IoManager, layout: &Layout) -> Result<(), Error> {
+        let mut devices = Vec::with_capacity(layout.devices().len());
+        for (index, descriptor) in layout.devices().iter().enumerate() {
+            let device_type = descriptor.device_type().ok_or(Error::MissingType(index))?;
+            devices.push(self.create(device_type, descriptor.resources(), descriptor.config())?);
+        }
+        let mut devices = devices.into_iter();
+        // The factory is called once per descriptor, in order.
+        manager
+            .restore(layout, |_| devices.next().unwrap())
+            .map_err(Error::Manager)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::bus::{MmioAddress, MmioAddressOffset};
+    use crate::device_manager::{DeviceDescriptor, MmioManager};
+    use crate::info::DeviceDetails;
+    use crate::resources::Resource;
+
+    // Reads back its configuration.
+    struct Config(Vec<u8>);
+
+    impl DeviceMmio for Config {
+        fn mmio_read(&self, _base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+            let offset = offset as usize;
+            data.copy_from_slice(&self.0[offset..offset + data.len()]);
+        }
+
+        fn mmio_write(&self, _base: MmioAddress, _offset: MmioAddressOffset, _data: &[u8]) {}
+
+        fn info(&self) -> Option<DeviceDetails> {
+            Some(DeviceDetails {
+                device_type: "config".to_string(),
+                ..Default::default()
+            })
+        }
+    }
+
+    fn resources(base: u64) -> DeviceResources {
+        let mut resources = DeviceResources::new();
+        resources.append(Resource::MmioAddressRange { base, size: 4 });
+        resources
+    }
+
+    fn registry() -> DeviceRegistry {
+        let mut registry = DeviceRegistry::new();
+        registry
+            .register("config", |_, config| {
+                if config.len() != 4 {
+                    return Err("bad config".into());
+                }
+                Ok(Arc::new(Config(config.to_vec())))
+            })
+            .unwrap();
+        registry
+    }
+
+    #[test]
+    fn test_registry() {
+        let mut registry = registry();
+        assert!(matches!(
+            registry.register("config", |_, _| Err("unused".into())),
+            Err(Error::DuplicateType(_))
+        ));
+        assert!(registry.contains("config"));
+        assert_eq!(registry.device_types().collect::<Vec<_>>(), ["config"]);
+
+        let mut manager = IoManager::new();
+        registry
+            .add(&mut manager, "config", &resources(0x1000), &[1, 2, 3, 4])
+            .unwrap();
+        let err = registry
+            .add(&mut manager, "config", &resources(0x2000), &[1])
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "registry: cannot create config device: bad config"
+        );
+        assert!(matches!(
+            registry.add(&mut manager, "config", &resources(0x1000), &[0; 4]),
+            Err(Error::Manager(_))
+        ));
+        assert!(matches!(
+            registry.create("virtio-blk", &resources(0x2000), &[]),
+            Err(Error::UnknownType(_))
+        ));
+
+        // The layout holds the types of the devices, and the VMM adds their configuration.
+        let mut layout = manager.layout();
+        assert_eq!(layout.devices()[0].device_type(), Some("config"));
+        layout.devices_mut()[0].set_config(vec![5, 6, 7, 8]);
+        let mut restored = IoManager::new();
+        registry.restore(&mut restored, &layout).unwrap();
+        let mut data = [0; 4];
+        restored.mmio_read(MmioAddress(0x1000), &mut data).unwrap();
+        assert_eq!(data, [5, 6, 7, 8]);
+
+        let mut untyped = Layout::new();
+        untyped.append(DeviceDescriptor::new(resources(0x3000)));
+        assert!(matches!(
+            registry.restore(&mut restored, &untyped),
+            Err(Error::MissingType(0))
+        ));
+        assert!(restored.mmio_read(MmioAddress(0x3000), &mut data).is_err());
+    }
+}
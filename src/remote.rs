@@ -0,0 +1,514 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Devices emulated by another process.
+//!
+//! Running a device in a separate process isolates the VMM from bugs in its emulation. The
+//! VMM registers a [`RemoteDevice`] on the bus, which forwards the MMIO accesses over a Unix
+//! socket, and the device process serves them with a [`RemoteServer`]. The device process
+//! raises its interrupts through [`RemoteInterrupt`]s, which the VMM side delivers with the
+//! [`Interrupt`]s it passed to [`RemoteDevice::new`].
+//!
+//! The protocol is made of the [`Message`]s below, each of them a one byte tag followed by
+//! its little endian fields:
+//!
+//! | Tag | Message     | Fields                                             | Direction |
+//! |-----|-------------|----------------------------------------------------|-----------|
+//! | 1   | `Read`      | base (u64), offset (u64), length (u32)             | to device |
+//! | 2   | `Write`     | base (u64), offset (u64), length (u32), data       | to device |
+//! | 3   | `Data`      | length (u32), data                                 | to VMM    |
+//! | 4   | `Ack`       |                                                    | to VMM    |
+//! | 5   | `Interrupt` | index (u32)                                        | to VMM    |
+//!
+//! Each `Read` is answered by a `Data` message and each `Write` by an `Ack`, in order, while
+//! `Interrupt` messages may come at any time.
+//!
+//! ```ignore
+//! // In the device process.
+//! let mut server = RemoteServer::new(stream)?;
+//! let mut device = Rng::new(server.interrupt(0));
+//! server.run(&mut device)?;
+//!
+//! // In the VMM.
+//! let device = RemoteDevice::new(stream, vec![irqfd], Duration::from_millis(100))?;
+//! manager.register_mmio(range, Arc::new(device))?;
+//! ```
+
+use std::fmt::{Display, Formatter};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+use crate::bus::{MmioAddress, MmioAddressOffset};
+use crate::interrupt::{self, Interrupt};
+use crate::trace;
+use crate::{DeviceMmio, MutDeviceMmio};
+
+/// Largest amount of data a message carries.
+pub const MAX_DATA_LEN: usize = 4096;
+
+const TAG_READ: u8 = 1;
+const TAG_WRITE: u8 = 2;
+const TAG_DATA: u8 = 3;
+const TAG_ACK: u8 = 4;
+const TAG_INTERRUPT: u8 = 5;
+
+/// Errors of the accesses forwarded by a [`RemoteDevice`].
+#[derive(Debug)]
+pub enum Error {
+    /// Sending the access failed.
+    Io(io::Error),
+    /// The connection to the device process is closed.
+    Disconnected,
+    /// The device process didn't reply within the timeout.
+    Timeout,
+    /// The device process replied with an unexpected message.
+    UnexpectedReply(Message),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io(_) => write!(f, "remote: failed to send the access"),
+            Error::Disconnected => write!(f, "remote: the device process is disconnected"),
+            Error::Timeout => write!(f, "remote: the device process didn't reply in time"),
+            Error::UnexpectedReply(message) => {
+                write!(f, "remote: unexpected reply {:?}", message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// A message exchanged between the VMM and a device process.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Message {
+    /// Read `len` bytes at `offset` in the range at `base`.
+    Read {
+        /// Base address of the range of the device.
+        base: u64,
+        /// Offset of the access in the range.
+        offset: u64,
+        /// Length of the access.
+        len: u32,
+    },
+    /// Write `data` at `offset` in the range at `base`.
+    Write {
+        /// Base address of the range of the device.
+        base: u64,
+        /// Offset of the access in the range.
+        offset: u64,
+        /// Data written by the guest.
+        data: Vec<u8>,
+    },
+    /// Data returned for a read.
+    Data(Vec<u8>),
+    /// Acknowledges a write.
+    Ack,
+    /// Trigger the interrupt with the given index.
+    Interrupt(u32),
+}
+
+impl Message {
+    /// Write the message to `writer`, with a single call to `write_all`.
+    pub fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(21);
+        match self {
+            Message::Read { base, offset, len } => {
+                buf.push(TAG_READ);
+                buf.extend_from_slice(&base.to_le_bytes());
+                buf.extend_from_slice(&offset.to_le_bytes());
+                buf.extend_from_slice(&len.to_le_bytes());
+            }
+            Message::Write { base, offset, data } => {
+                buf.push(TAG_WRITE);
+                buf.extend_from_slice(&base.to_le_bytes());
+                buf.extend_from_slice(&offset.to_le_bytes());
+                encode_data(&mut buf, data)?;
+            }
+            Message::Data(data) => {
+                buf.push(TAG_DATA);
+                encode_data(&mut buf, data)?;
+            }
+            Message::Ack => buf.push(TAG_ACK),
+            Message::Interrupt(index) => {
+                buf.push(TAG_INTERRUPT);
+                buf.extend_from_slice(&index.to_le_bytes());
+            }
+        }
+        writer.write_all(&buf)
+    }
+
+    /// Read a message from `reader`.
+    ///
+    /// Unknown tags and data longer than [`MAX_DATA_LEN`] are rejected with
+    /// `ErrorKind::InvalidData`.
+    pub fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let mut tag = [0];
+        reader.read_exact(&mut tag)?;
+        match tag[0] {
+            TAG_READ => Ok(Message::Read {
+                base: read_u64(reader)?,
+                offset: read_u64(reader)?,
+                len: read_len(reader)? as u32,
+            }),
+            TAG_WRITE => Ok(Message::Write {
+                base: read_u64(reader)?,
+                offset: read_u64(reader)?,
+                data: read_data(reader)?,
+            }),
+            TAG_DATA => Ok(Message::Data(read_data(reader)?)),
+            TAG_ACK => Ok(Message::Ack),
+            TAG_INTERRUPT => Ok(Message::Interrupt(read_u32(reader)?)),
+            tag => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("unknown message tag {}", tag),
+            )),
+        }
+    }
+}
+
+fn encode_data(buf: &mut Vec<u8>, data: &[u8]) -> io::Result<()> {
+    if data.len() > MAX_DATA_LEN {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "message data too long",
+        ));
+    }
+    buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    buf.extend_from_slice(data);
+    Ok(())
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_len<R: Read>(reader: &mut R) -> io::Result<usize> {
+    match read_u32(reader)? as usize {
+        len if len <= MAX_DATA_LEN => Ok(len),
+        _ => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "message data too long",
+        )),
+    }
+}
+
+fn read_data<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut data = vec![0; read_len(reader)?];
+    reader.read_exact(&mut data)?;
+    Ok(data)
+}
+
+// The socket to the device process, and the replies to the accesses sent over it.
+struct Channel {
+    stream: UnixStream,
+    replies: Receiver<Message>,
+}
+
+/// Forwards the accesses to a device emulated by another process.
+///
+/// Reads which fail return zeros. The failures are logged with the `log` feature, and
+/// counted by [`RemoteDevice::failures`]. An access timing out closes the connection, since
+/// the replies would no longer match the accesses.
+pub struct RemoteDevice {
+    channel: Mutex<Channel>,
+    timeout: Duration,
+    failures: AtomicU64,
+}
+
+impl RemoteDevice {
+    /// Forward the accesses over `stream`, connected to a [`RemoteServer`].
+    ///
+    /// A thread receives the messages of the device process, triggering the interrupts it
+    /// signals by their index in `interrupts`.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream`: socket connected to the device process
+    /// * `interrupts`: interrupts the device process may trigger
+    /// * `timeout`: how long each access waits for the device process
+    pub fn new<I>(stream: UnixStream, interrupts: Vec<I>, timeout: Duration) -> io::Result<Self>
+    where
+        I: Interrupt + Send + 'static,
+    {
+        let mut reader = stream.try_clone()?;
+        let (sender, replies) = mpsc::channel();
+        thread::Builder::new()
+            .name("remote-device".to_string())
+            .spawn(move || {
+                while let Ok(message) = Message::decode(&mut reader) {
+                    if let Message::Interrupt(index) = message {
+                        let result = interrupts
+                            .get(index as usize)
+                            .ok_or(interrupt::Error::OperationNotSupported)
+                            .and_then(Interrupt::trigger);
+                        if let Err(error) = result {
+                            trace::device_failed(&error);
+                        }
+                    } else if sender.send(message).is_err() {
+                        break;
+                    }
+                }
+            })?;
+        Ok(RemoteDevice {
+            channel: Mutex::new(Channel { stream, replies }),
+            timeout,
+            failures: AtomicU64::new(0),
+        })
+    }
+
+    /// Return how many accesses failed.
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    // Send `request` to the device process and wait for its reply.
+    fn forward(&self, request: Message) -> Result<Message, Error> {
+        let mut channel = self.channel.lock().unwrap_or_else(PoisonError::into_inner);
+        request.encode(&mut channel.stream).map_err(Error::Io)?;
+        match channel.replies.recv_timeout(self.timeout) {
+            Ok(reply) => Ok(reply),
+            Err(RecvTimeoutError::Timeout) => {
+                let _ = channel.stream.shutdown(Shutdown::Both);
+                Err(Error::Timeout)
+            }
+            Err(RecvTimeoutError::Disconnected) => Err(Error::Disconnected),
+        }
+    }
+
+    fn failed(&self, error: Error) {
+        self.failures.fetch_add(1, Ordering::Relaxed);
+        trace::device_failed(&error);
+    }
+}
+
+impl DeviceMmio for RemoteDevice {
+    fn mmio_read(&self, base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+        let request = Message::Read {
+            base: base.0,
+            offset,
+            len: data.len() as u32,
+        };
+        match self.forward(request) {
+            Ok(Message::Data(reply)) if reply.len() == data.len() => data.copy_from_slice(&reply),
+            result => {
+                data.fill(0);
+                self.failed(result.map_or_else(|e| e, Error::UnexpectedReply));
+            }
+        }
+    }
+
+    fn mmio_write(&self, base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+        let request = Message::Write {
+            base: base.0,
+            offset,
+            data: data.to_vec(),
+        };
+        match self.forward(request) {
+            Ok(Message::Ack) => {}
+            result => self.failed(result.map_or_else(|e| e, Error::UnexpectedReply)),
+        }
+    }
+}
+
+/// Serves the accesses of a [`RemoteDevice`] in the device process.
+pub struct RemoteServer {
+    reader: UnixStream,
+    writer: Arc<Mutex<UnixStream>>,
+}
+
+impl RemoteServer {
+    /// Serve the accesses received over `stream`, connected to a [`RemoteDevice`].
+    pub fn new(stream: UnixStream) -> io::Result<Self> {
+        Ok(RemoteServer {
+            reader: stream.try_clone()?,
+            writer: Arc::new(Mutex::new(stream)),
+        })
+    }
+
+    /// Return the interrupt with the given index in the interrupts of the `RemoteDevice`.
+    pub fn interrupt(&self, index: u32) -> RemoteInterrupt {
+        RemoteInterrupt {
+            writer: self.writer.clone(),
+            index,
+        }
+    }
+
+    /// Handle the accesses with `device` until the VMM closes the connection.
+    pub fn run<D: MutDeviceMmio + ?Sized>(&mut self, device: &mut D) -> io::Result<()> {
+        loop {
+            let message = match Message::decode(&mut self.reader) {
+                Ok(message) => message,
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            let reply = match message {
+                Message::Read { base, offset, len } => {
+                    let mut data = vec![0; len as usize];
+                    device.mmio_read(MmioAddress(base), offset, &mut data);
+                    Message::Data(data)
+                }
+                Message::Write { base, offset, data } => {
+                    device.mmio_write(MmioAddress(base), offset, &data);
+                    Message::Ack
+                }
+                message => {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("unexpected message {:?}", message),
+                    ))
+                }
+            };
+            reply.encode(&mut *self.writer.lock().unwrap_or_else(PoisonError::into_inner))?;
+        }
+    }
+}
+
+/// An interrupt of a device process, delivered by the [`RemoteDevice`] of the VMM.
+#[derive(Clone)]
+pub struct RemoteInterrupt {
+    writer: Arc<Mutex<UnixStream>>,
+    index: u32,
+}
+
+impl Interrupt for RemoteInterrupt {
+    fn trigger(&self) -> Result<(), interrupt::Error> {
+        let mut writer = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+        Message::Interrupt(self.index)
+            .encode(&mut *writer)
+            .map_err(interrupt::Error::Backend)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::MockInterrupt;
+
+    // Stores a byte, and triggers its interrupt on the writes at offset 0x10.
+    struct Backend {
+        value: u8,
+        irq: RemoteInterrupt,
+    }
+
+    impl MutDeviceMmio for Backend {
+        fn mmio_read(&mut self, _: MmioAddress, _: MmioAddressOffset, data: &mut [u8]) {
+            data.fill(self.value);
+        }
+
+        fn mmio_write(&mut self, _: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+            if offset == 0x10 {
+                self.irq.trigger().unwrap();
+            } else {
+                self.value = data[0];
+            }
+        }
+    }
+
+    #[test]
+    fn test_message_encoding() {
+        let messages = [
+            Message::Read {
+                base: 0x1000,
+                offset: 4,
+                len: 2,
+            },
+            Message::Write {
+                base: 0x1000,
+                offset: 8,
+                data: vec![1, 2, 3, 4],
+            },
+            Message::Data(vec![5, 6]),
+            Message::Ack,
+            Message::Interrupt(3),
+        ];
+        let mut buf = Vec::new();
+        for message in messages.iter() {
+            message.encode(&mut buf).unwrap();
+        }
+        assert_eq!(
+            &buf[..21],
+            &[1, 0, 0x10, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0]
+        );
+        let mut reader = &buf[..];
+        for message in messages.iter() {
+            assert_eq!(&Message::decode(&mut reader).unwrap(), message);
+        }
+        assert!(reader.is_empty());
+
+        let e = Message::decode(&mut &[6u8][..]).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        let e = Message::decode(&mut &[3u8, 1, 0x10, 0, 0][..]).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        let e = Message::Data(vec![0; MAX_DATA_LEN + 1])
+            .encode(&mut Vec::new())
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_remote_device() {
+        let (vmm, process) = UnixStream::pair().unwrap();
+        let server = thread::spawn(move || {
+            let mut server = RemoteServer::new(process).unwrap();
+            let mut device = Backend {
+                value: 0,
+                irq: server.interrupt(1),
+            };
+            server.run(&mut device)
+        });
+        let irqs = vec![
+            Arc::new(MockInterrupt::new()),
+            Arc::new(MockInterrupt::new()),
+        ];
+        let device = RemoteDevice::new(vmm, irqs.clone(), Duration::from_secs(5)).unwrap();
+        let base = MmioAddress(0x1000);
+
+        device.mmio_write(base, 0, &[7]);
+        let mut data = [0; 4];
+        device.mmio_read(base, 0, &mut data);
+        assert_eq!(data, [7; 4]);
+
+        // Interrupts are signalled before the write is acknowledged.
+        device.mmio_write(base, 0x10, &[1]);
+        assert_eq!(irqs[0].count(), 0);
+        assert_eq!(irqs[1].count(), 1);
+        assert_eq!(device.failures(), 0);
+
+        // The server returns once the VMM side is gone.
+        device
+            .channel
+            .lock()
+            .unwrap()
+            .stream
+            .shutdown(Shutdown::Both)
+            .unwrap();
+        server.join().unwrap().unwrap();
+        device.mmio_read(base, 0, &mut data);
+        assert_eq!(data, [0; 4]);
+        assert_eq!(device.failures(), 1);
+    }
+}
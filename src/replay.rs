@@ -0,0 +1,796 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Record and replay of the accesses handled by devices.
+//!
+//! A [`Recorder`] wraps a device and appends every access it forwards to a [`Log`]. Logs
+//! can be serialized with [`Log::to_bytes`], and later fed back into an [`IoManager`]
+//! with [`replay`], which checks that reads return the same data as during recording.
+//! This makes it possible to reproduce bugs depending on the behavior of a guest driver
+//! without booting the guest. Recorded logs can also be used as golden traces, with
+//! [`compare`] reporting where two implementations of the same devices behave differently.
+//!
+//! # Example
+//!
+//! ```
+//! # use std::sync::{Arc, Mutex};
+//! # use vm_device::bus::{MmioAddress, MmioAddressOffset, MmioRange};
+//! # use vm_device::device_manager::{IoManager, MmioManager};
+//! # use vm_device::DeviceMmio;
+//! use vm_device::replay::{replay, Log, Recorder};
+//!
+//! struct Counter(Mutex<u8>);
+//!
+//! impl DeviceMmio for Counter {
+//!     fn mmio_read(&self, _base: MmioAddress, _offset: MmioAddressOffset, data: &mut [u8]) {
+//!         data[0] = *self.0.lock().unwrap();
+//!     }
+//!     fn mmio_write(&self, _base: MmioAddress, _offset: MmioAddressOffset, data: &[u8]) {
+//!         *self.0.lock().unwrap() += data[0];
+//!     }
+//! }
+//!
+//! let range = MmioRange::new(MmioAddress(0x1000), 0x10).unwrap();
+//! let recorder = Arc::new(Recorder::new(Counter(Mutex::new(0))));
+//! let mut manager = IoManager::new();
+//! manager.register_mmio(range, recorder.clone()).unwrap();
+//! manager.mmio_write(MmioAddress(0x1000), &[2]).unwrap();
+//! manager.mmio_read(MmioAddress(0x1000), &mut [0]).unwrap();
+//!
+//! let bytes = recorder.log().lock().unwrap().to_bytes();
+//!
+//! // Later, against a fresh device.
+//! let mut manager = IoManager::new();
+//! manager
+//!     .register_mmio(range, Arc::new(Counter(Mutex::new(0))))
+//!     .unwrap();
+//! replay(&Log::from_bytes(&bytes).unwrap(), &manager).unwrap();
+//! ```
+
+use std::convert::TryFrom;
+use std::fmt::{Display, Formatter};
+use std::result::Result;
+use std::sync::{Arc, Mutex};
+
+use crate::bus::{self, MmioAddress, MmioAddressOffset, PioAddress, PioAddressOffset};
+use crate::completion::Completion;
+use crate::device_manager::{IoManager, MmioManager, PioManager};
+use crate::exit::ExitAccess;
+use crate::health::Health;
+use crate::info::DeviceDetails;
+use crate::resources::Resource;
+use crate::stats::Statistics;
+use crate::{DeviceMmio, DevicePio};
+
+// Bits of the tag byte preceding each encoded entry.
+const TAG_WRITE: u8 = 1 << 0;
+const TAG_PIO: u8 = 1 << 1;
+
+/// Errors encountered while decoding or replaying a [`Log`].
+#[derive(Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The encoded log ends in the middle of an entry.
+    Truncated,
+    /// The encoded log contains an unknown tag or an out of range value.
+    InvalidEntry,
+    /// A recorded access could not be dispatched.
+    Bus(usize, bus::Error),
+    /// A read returned different data than during recording.
+    Divergence {
+        /// Index of the diverging entry in the log.
+        index: usize,
+        /// Data returned by the read during recording.
+        expected: Vec<u8>,
+        /// Data returned by the read during replay.
+        found: Vec<u8>,
+    },
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Truncated => write!(f, "replay: truncated log"),
+            Error::InvalidEntry => write!(f, "replay: invalid log entry"),
+            Error::Bus(index, e) => write!(f, "replay: cannot dispatch entry {}: {}", index, e),
+            Error::Divergence {
+                index,
+                expected,
+                found,
+            } => write!(
+                f,
+                "replay: entry {} read {:x?}, expected {:x?}",
+                index, found, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Bus(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Direction of an access.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Direction {
+    /// The guest reads from the device.
+    Read,
+    /// The guest writes to the device.
+    Write,
+}
+
+/// Bus an access was performed on.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BusKind {
+    /// The PIO bus.
+    Pio,
+    /// The MMIO bus.
+    Mmio,
+}
+
+/// An access recorded in a [`Log`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Entry {
+    /// Bus the access was performed on.
+    pub bus: BusKind,
+    /// Direction of the access.
+    pub direction: Direction,
+    /// Base address of the range the access targeted.
+    pub base: u64,
+    /// Offset of the access within the range.
+    pub offset: u64,
+    /// Data written by the guest, or returned by the device for reads.
+    pub data: Vec<u8>,
+}
+
+/// A sequence of recorded accesses.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Log {
+    entries: Vec<Entry>,
+}
+
+impl Log {
+    /// Create an empty log.
+    pub fn new() -> Self {
+        Log::default()
+    }
+
+    /// Append `entry` to the log.
+    pub fn push(&mut self, entry: Entry) {
+        self.entries.push(entry);
+    }
+
+    /// Return the recorded entries, in the order in which they were handled.
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// Remove all the entries from the log.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Encode the log.
+    ///
+    /// Each entry is stored as a tag byte followed by the base, offset and length encoded
+    /// as LEB128 values, and the data. Accesses are typically a few bytes wide at small
+    /// offsets, so most entries take less than a dozen bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        for entry in self.entries.iter() {
+            let mut tag = 0;
+            if entry.direction == Direction::Write {
+                tag |= TAG_WRITE;
+            }
+            if entry.bus == BusKind::Pio {
+                tag |= TAG_PIO;
+            }
+            data.push(tag);
+            put_leb128(&mut data, entry.base);
+            put_leb128(&mut data, entry.offset);
+            put_leb128(&mut data, entry.data.len() as u64);
+            data.extend_from_slice(&entry.data);
+        }
+        data
+    }
+
+    /// Decode a log previously encoded with [`Log::to_bytes`].
+    pub fn from_bytes(mut data: &[u8]) -> Result<Self, Error> {
+        let mut log = Log::new();
+        while let Some((&tag, rest)) = data.split_first() {
+            data = rest;
+            if tag & !(TAG_WRITE | TAG_PIO) != 0 {
+                return Err(Error::InvalidEntry);
+            }
+            let base = get_leb128(&mut data)?;
+            let offset = get_leb128(&mut data)?;
+            let len = usize::try_from(get_leb128(&mut data)?).map_err(|_| Error::Truncated)?;
+            if len > data.len() {
+                return Err(Error::Truncated);
+            }
+            let (bytes, rest) = data.split_at(len);
+            data = rest;
+            log.push(Entry {
+                bus: if tag & TAG_PIO != 0 {
+                    BusKind::Pio
+                } else {
+                    BusKind::Mmio
+                },
+                direction: if tag & TAG_WRITE != 0 {
+                    Direction::Write
+                } else {
+                    Direction::Read
+                },
+                base,
+                offset,
+                data: bytes.to_vec(),
+            });
+        }
+        Ok(log)
+    }
+}
+
+fn put_leb128(data: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            data.push(byte);
+            return;
+        }
+        data.push(byte | 0x80);
+    }
+}
+
+fn get_leb128(data: &mut &[u8]) -> Result<u64, Error> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = data.split_first().ok_or(Error::Truncated)?;
+        *data = rest;
+        let bits = u64::from(byte & 0x7f);
+        if bits << shift >> shift != bits {
+            return Err(Error::InvalidEntry);
+        }
+        value |= bits << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(Error::InvalidEntry)
+}
+
+/// A device wrapper appending every access it forwards to a [`Log`].
+///
+/// Several recorders can share the same log (see [`Recorder::with_log`]) to capture the
+/// interleaving of the accesses to all the devices of a VM.
+///
+/// Reads the device completes asynchronously (see [`DeviceMmio::mmio_deferred`]) aren't
+/// recorded, since their value doesn't go through the recorder.
+pub struct Recorder<D> {
+    device: D,
+    log: Arc<Mutex<Log>>,
+}
+
+impl<D> Recorder<D> {
+    /// Wrap `device`, recording its accesses to a new log.
+    pub fn new(device: D) -> Self {
+        Recorder::with_log(device, Arc::new(Mutex::new(Log::new())))
+    }
+
+    /// Wrap `device`, recording its accesses to `log`.
+    pub fn with_log(device: D, log: Arc<Mutex<Log>>) -> Self {
+        Recorder { device, log }
+    }
+
+    /// Return the log accesses are recorded to.
+    pub fn log(&self) -> &Arc<Mutex<Log>> {
+        &self.log
+    }
+
+    /// Return the wrapped device.
+    pub fn inner(&self) -> &D {
+        &self.device
+    }
+
+    fn record(&self, bus: BusKind, direction: Direction, base: u64, offset: u64, data: &[u8]) {
+        self.log.lock().unwrap().push(Entry {
+            bus,
+            direction,
+            base,
+            offset,
+            data: data.to_vec(),
+        });
+    }
+}
+
+impl<D: DeviceMmio> DeviceMmio for Recorder<D> {
+    fn mmio_read(&self, base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+        self.device.mmio_read(base, offset, data);
+        self.record(BusKind::Mmio, Direction::Read, base.0, offset, data);
+    }
+
+    fn mmio_write(&self, base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+        self.record(BusKind::Mmio, Direction::Write, base.0, offset, data);
+        self.device.mmio_write(base, offset, data);
+    }
+
+    fn introspect(&self) -> Vec<(String, String)> {
+        self.device.introspect()
+    }
+
+    fn info(&self) -> Option<DeviceDetails> {
+        self.device.info()
+    }
+
+    fn health(&self) -> Health {
+        self.device.health()
+    }
+
+    fn stats(&self) -> Option<Statistics> {
+        self.device.stats()
+    }
+
+    fn update_resources(&self, resources: &[Resource]) {
+        self.device.update_resources(resources)
+    }
+
+    fn debug_read(&self, base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) -> bool {
+        self.device.debug_read(base, offset, data)
+    }
+
+    fn mmio_update(
+        &self,
+        base: MmioAddress,
+        offset: MmioAddressOffset,
+        data: &mut [u8],
+        update: &mut dyn FnMut(&mut [u8]),
+    ) {
+        self.device.mmio_update(base, offset, data, &mut |data| {
+            self.record(BusKind::Mmio, Direction::Read, base.0, offset, data);
+            update(data);
+            self.record(BusKind::Mmio, Direction::Write, base.0, offset, data);
+        });
+    }
+
+    fn mmio_deferred(
+        &self,
+        base: MmioAddress,
+        offset: MmioAddressOffset,
+        access: ExitAccess<'_>,
+    ) -> Completion {
+        match access {
+            ExitAccess::Read(data) => {
+                let completion =
+                    self.device
+                        .mmio_deferred(base, offset, ExitAccess::Read(&mut *data));
+                if let Completion::Done = completion {
+                    self.record(BusKind::Mmio, Direction::Read, base.0, offset, data);
+                }
+                completion
+            }
+            ExitAccess::Write(data) => {
+                self.record(BusKind::Mmio, Direction::Write, base.0, offset, data);
+                self.device
+                    .mmio_deferred(base, offset, ExitAccess::Write(data))
+            }
+        }
+    }
+}
+
+impl<D: DevicePio> DevicePio for Recorder<D> {
+    fn pio_read(&self, base: PioAddress, offset: PioAddressOffset, data: &mut [u8]) {
+        self.device.pio_read(base, offset, data);
+        self.record(
+            BusKind::Pio,
+            Direction::Read,
+            u64::from(base.0),
+            u64::from(offset),
+            data,
+        );
+    }
+
+    fn pio_write(&self, base: PioAddress, offset: PioAddressOffset, data: &[u8]) {
+        self.record(
+            BusKind::Pio,
+            Direction::Write,
+            u64::from(base.0),
+            u64::from(offset),
+            data,
+        );
+        self.device.pio_write(base, offset, data);
+    }
+
+    fn introspect(&self) -> Vec<(String, String)> {
+        self.device.introspect()
+    }
+
+    fn info(&self) -> Option<DeviceDetails> {
+        self.device.info()
+    }
+
+    fn health(&self) -> Health {
+        self.device.health()
+    }
+
+    fn stats(&self) -> Option<Statistics> {
+        self.device.stats()
+    }
+
+    fn debug_read(&self, base: PioAddress, offset: PioAddressOffset, data: &mut [u8]) -> bool {
+        self.device.debug_read(base, offset, data)
+    }
+
+    fn pio_deferred(
+        &self,
+        base: PioAddress,
+        offset: PioAddressOffset,
+        access: ExitAccess<'_>,
+    ) -> Completion {
+        let (address, position) = (u64::from(base.0), u64::from(offset));
+        match access {
+            ExitAccess::Read(data) => {
+                let completion =
+                    self.device
+                        .pio_deferred(base, offset, ExitAccess::Read(&mut *data));
+                if let Completion::Done = completion {
+                    self.record(BusKind::Pio, Direction::Read, address, position, data);
+                }
+                completion
+            }
+            ExitAccess::Write(data) => {
+                self.record(BusKind::Pio, Direction::Write, address, position, data);
+                self.device
+                    .pio_deferred(base, offset, ExitAccess::Write(data))
+            }
+        }
+    }
+}
+
+/// Feed the accesses recorded in `log` into the devices registered with `manager`.
+///
+/// Accesses are dispatched to the address they were recorded at, so the devices must be
+/// registered with the same ranges as during recording. Replay stops at the first access
+/// that cannot be dispatched, or at the first read returning different data than during
+/// recording.
+pub fn replay(log: &Log, manager: &IoManager) -> Result<(), Error> {
+    for (index, entry) in log.entries().iter().enumerate() {
+        let data = dispatch(entry, manager)?.map_err(|e| Error::Bus(index, e))?;
+        if entry.direction == Direction::Read && data != entry.data {
+            return Err(Error::Divergence {
+                index,
+                expected: entry.data.clone(),
+                found: data,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// A log entry for which two managers behaved differently, as reported by [`compare`].
+///
+/// Each outcome holds the data returned by a read (empty for writes), or the error
+/// encountered while dispatching the access.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Difference {
+    /// Index of the entry in the log.
+    pub index: usize,
+    /// Outcome of the access on the first manager.
+    pub left: Result<Vec<u8>, bus::Error>,
+    /// Outcome of the access on the second manager.
+    pub right: Result<Vec<u8>, bus::Error>,
+}
+
+/// Feed the accesses recorded in `log` into both `left` and `right`, and return the entries
+/// for which their outcomes differ.
+///
+/// This is meant for checking that two implementations of the same devices (e.g. before and
+/// after a rewrite) behave identically for a recorded trace. Unlike [`replay`], the data
+/// read during recording is ignored, and all the entries are fed regardless of the
+/// differences found.
+pub fn compare(log: &Log, left: &IoManager, right: &IoManager) -> Result<Vec<Difference>, Error> {
+    let mut differences = Vec::new();
+    for (index, entry) in log.entries().iter().enumerate() {
+        let left = dispatch(entry, left)?;
+        let right = dispatch(entry, right)?;
+        if left != right {
+            differences.push(Difference { index, left, right });
+        }
+    }
+    Ok(differences)
+}
+
+// Dispatch the access described by `entry` to `manager`, and return the data read, or an
+// empty buffer for writes.
+fn dispatch(entry: &Entry, manager: &IoManager) -> Result<Result<Vec<u8>, bus::Error>, Error> {
+    let addr = entry
+        .base
+        .checked_add(entry.offset)
+        .ok_or(Error::InvalidEntry)?;
+    let mut data = vec![0; entry.data.len()];
+    let result = match entry.bus {
+        BusKind::Mmio => match entry.direction {
+            Direction::Read => manager.mmio_read(MmioAddress(addr), &mut data),
+            Direction::Write => manager.mmio_write(MmioAddress(addr), &entry.data),
+        },
+        BusKind::Pio => {
+            let addr = u16::try_from(addr)
+                .map(PioAddress)
+                .map_err(|_| Error::InvalidEntry)?;
+            match entry.direction {
+                Direction::Read => manager.pio_read(addr, &mut data),
+                Direction::Write => manager.pio_write(addr, &entry.data),
+            }
+        }
+    };
+    if entry.direction == Direction::Write {
+        data.clear();
+    }
+    Ok(result.map(|_| data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::{MmioRange, PioRange};
+    use crate::cache::Cached;
+
+    struct Counter(Mutex<u8>);
+
+    impl DeviceMmio for Counter {
+        fn mmio_read(&self, _base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+            data.fill(*self.0.lock().unwrap() + offset as u8);
+        }
+
+        fn mmio_write(&self, _base: MmioAddress, _offset: MmioAddressOffset, data: &[u8]) {
+            *self.0.lock().unwrap() += data.iter().sum::<u8>();
+        }
+    }
+
+    impl DevicePio for Counter {
+        fn pio_read(&self, base: PioAddress, offset: PioAddressOffset, data: &mut [u8]) {
+            self.mmio_read(MmioAddress(u64::from(base.0)), u64::from(offset), data);
+        }
+
+        fn pio_write(&self, base: PioAddress, offset: PioAddressOffset, data: &[u8]) {
+            self.mmio_write(MmioAddress(u64::from(base.0)), u64::from(offset), data);
+        }
+    }
+
+    // Register only updated as a whole, so that wrappers splitting a read-modify-write into
+    // a read and a write stand out.
+    struct Register(Mutex<u32>);
+
+    impl DeviceMmio for Register {
+        fn mmio_read(&self, _base: MmioAddress, _offset: MmioAddressOffset, _data: &mut [u8]) {
+            unreachable!("split read-modify-write");
+        }
+
+        fn mmio_write(&self, _base: MmioAddress, _offset: MmioAddressOffset, _data: &[u8]) {
+            unreachable!("split read-modify-write");
+        }
+
+        fn mmio_update(
+            &self,
+            _base: MmioAddress,
+            _offset: MmioAddressOffset,
+            data: &mut [u8],
+            update: &mut dyn FnMut(&mut [u8]),
+        ) {
+            let mut value = self.0.lock().unwrap();
+            data.copy_from_slice(&value.to_le_bytes()[..data.len()]);
+            update(data);
+            *value = data
+                .iter()
+                .rev()
+                .fold(0, |value, &byte| value << 8 | u32::from(byte));
+        }
+    }
+
+    fn new_manager(device: Arc<Recorder<Counter>>) -> IoManager {
+        let mut manager = IoManager::new();
+        let range = MmioRange::new(MmioAddress(0x1_0000_0000), 0x1000).unwrap();
+        manager.register_mmio(range, device.clone()).unwrap();
+        let range = PioRange::new(PioAddress(0x3f8), 8).unwrap();
+        manager.register_pio(range, device).unwrap();
+        manager
+    }
+
+    #[test]
+    fn test_record_replay() {
+        let recorder = Arc::new(Recorder::new(Counter(Mutex::new(0))));
+        let manager = new_manager(recorder.clone());
+        let mut data = [0; 4];
+        manager
+            .mmio_write(MmioAddress(0x1_0000_0010), &[1, 2])
+            .unwrap();
+        manager.pio_read(PioAddress(0x3f9), &mut data[..1]).unwrap();
+        manager.pio_write(PioAddress(0x3f8), &[4]).unwrap();
+        manager
+            .mmio_read(MmioAddress(0x1_0000_0ff0), &mut data)
+            .unwrap();
+
+        let log = recorder.log().lock().unwrap().clone();
+        assert_eq!(log.entries().len(), 4);
+        assert_eq!(
+            log.entries()[1],
+            Entry {
+                bus: BusKind::Pio,
+                direction: Direction::Read,
+                base: 0x3f8,
+                offset: 1,
+                data: vec![4],
+            }
+        );
+        assert_eq!(
+            log.entries()[3],
+            Entry {
+                bus: BusKind::Mmio,
+                direction: Direction::Read,
+                base: 0x1_0000_0000,
+                offset: 0xff0,
+                data: vec![247; 4],
+            }
+        );
+
+        let bytes = log.to_bytes();
+        assert_eq!(bytes.len(), 35);
+        let decoded = Log::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded, log);
+
+        // Replaying against a fresh device reproduces the same reads, and records the
+        // same log again.
+        let recorder = Arc::new(Recorder::new(Counter(Mutex::new(0))));
+        replay(&decoded, &new_manager(recorder.clone())).unwrap();
+        assert_eq!(*recorder.log().lock().unwrap(), log);
+
+        // A device in a different state diverges on the first read.
+        let manager = new_manager(Arc::new(Recorder::new(Counter(Mutex::new(1)))));
+        assert_eq!(
+            replay(&decoded, &manager),
+            Err(Error::Divergence {
+                index: 1,
+                expected: vec![4],
+                found: vec![5],
+            })
+        );
+
+        let mut log = Log::new();
+        log.push(Entry {
+            bus: BusKind::Pio,
+            direction: Direction::Write,
+            base: 0x3f0,
+            offset: 0,
+            data: vec![0],
+        });
+        assert_eq!(
+            replay(&log, &manager),
+            Err(Error::Bus(
+                0,
+                bus::Error::DeviceNotFound {
+                    addr: 0x3f0,
+                    len: 1
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_record_update() {
+        let recorder = Arc::new(Recorder::new(Cached::new(Register(Mutex::new(1)))));
+        let mut manager = IoManager::new();
+        let range = MmioRange::new(MmioAddress(0x1000), 4).unwrap();
+        manager.register_mmio(range, recorder.clone()).unwrap();
+
+        // The update reaches the device through both wrappers as a single access.
+        let old = manager
+            .mmio_rmw(MmioAddress(0x1000), |value| value + 1)
+            .unwrap();
+        assert_eq!(old, 1);
+        assert_eq!(*recorder.inner().inner().0.lock().unwrap(), 2);
+        let entry = |direction, data| Entry {
+            bus: BusKind::Mmio,
+            direction,
+            base: 0x1000,
+            offset: 0,
+            data,
+        };
+        assert_eq!(
+            recorder.log().lock().unwrap().entries(),
+            [
+                entry(Direction::Read, vec![1, 0, 0, 0]),
+                entry(Direction::Write, vec![2, 0, 0, 0]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalid_log() {
+        let mut log = Log::new();
+        log.push(Entry {
+            bus: BusKind::Mmio,
+            direction: Direction::Write,
+            base: u64::MAX,
+            offset: 0,
+            data: vec![1, 2, 3],
+        });
+        let bytes = log.to_bytes();
+        assert_eq!(Log::from_bytes(&bytes).unwrap(), log);
+
+        for len in 1..bytes.len() {
+            assert_eq!(Log::from_bytes(&bytes[..len]), Err(Error::Truncated));
+        }
+        assert_eq!(Log::from_bytes(&[0x80]), Err(Error::InvalidEntry));
+        // A LEB128 value exceeding 64 bits.
+        let mut bytes = vec![0];
+        bytes.extend_from_slice(&[0xff; 9]);
+        bytes.push(0x7f);
+        assert_eq!(Log::from_bytes(&bytes), Err(Error::InvalidEntry));
+    }
+
+    #[test]
+    fn test_compare() {
+        let recorder = Arc::new(Recorder::new(Counter(Mutex::new(0))));
+        let manager = new_manager(recorder.clone());
+        manager
+            .mmio_write(MmioAddress(0x1_0000_0000), &[1])
+            .unwrap();
+        manager.pio_read(PioAddress(0x3f8), &mut [0; 2]).unwrap();
+        manager
+            .mmio_write(MmioAddress(0x1_0000_0000), &[1])
+            .unwrap();
+        manager
+            .mmio_read(MmioAddress(0x1_0000_0004), &mut [0])
+            .unwrap();
+        let mut log = recorder.log().lock().unwrap().clone();
+        log.push(Entry {
+            bus: BusKind::Pio,
+            direction: Direction::Read,
+            base: 0x3f0,
+            offset: 0,
+            data: vec![0],
+        });
+
+        let left = new_manager(Arc::new(Recorder::new(Counter(Mutex::new(0)))));
+        let right = new_manager(Arc::new(Recorder::new(Counter(Mutex::new(0)))));
+        assert_eq!(compare(&log, &left, &right), Ok(Vec::new()));
+
+        let left = new_manager(Arc::new(Recorder::new(Counter(Mutex::new(0)))));
+        // The second entry reads the same data, as the failed write is compensated by the
+        // different initial state.
+        let mut right = new_manager(Arc::new(Recorder::new(Counter(Mutex::new(1)))));
+        right.deregister_mmio(MmioAddress(0x1_0000_0000)).unwrap();
+        assert_eq!(
+            compare(&log, &left, &right),
+            Ok(vec![
+                Difference {
+                    index: 0,
+                    left: Ok(Vec::new()),
+                    right: Err(bus::Error::DeviceNotFound {
+                        addr: 0x1_0000_0000,
+                        len: 1
+                    }),
+                },
+                Difference {
+                    index: 2,
+                    left: Ok(Vec::new()),
+                    right: Err(bus::Error::DeviceNotFound {
+                        addr: 0x1_0000_0000,
+                        len: 1
+                    }),
+                },
+                Difference {
+                    index: 3,
+                    left: Ok(vec![6]),
+                    right: Err(bus::Error::DeviceNotFound {
+                        addr: 0x1_0000_0004,
+                        len: 1
+                    }),
+                },
+            ])
+        );
+    }
+}
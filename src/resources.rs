@@ -12,9 +12,12 @@
 //! 5) the VMM registers the new device onto corresponding device managers according the allocated
 //!    resources.
 
-use std::{u16, u32, u64};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter};
 
 /// Enumeration describing a device's resource constraints.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum ResourceConstraint {
     /// Constraint for an IO Port address range.
     PioAddress {
@@ -107,8 +110,46 @@ impl ResourceConstraint {
     }
 }
 
+impl Display for ResourceConstraint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ResourceConstraint::PioAddress { range, align, size } => {
+                write!(f, "PIO range of size {:#x} aligned to {:#x}", size, align)?;
+                if let Some((min, max)) = range {
+                    write!(f, " within [{:#x}, {:#x}]", min, max)?;
+                }
+                Ok(())
+            }
+            ResourceConstraint::MmioAddress { range, align, size } => {
+                write!(f, "MMIO range of size {:#x} aligned to {:#x}", size, align)?;
+                if let Some((min, max)) = range {
+                    write!(f, " within [{:#x}, {:#x}]", min, max)?;
+                }
+                Ok(())
+            }
+            ResourceConstraint::LegacyIrq { irq: Some(irq) } => write!(f, "legacy IRQ {}", irq),
+            ResourceConstraint::LegacyIrq { irq: None } => write!(f, "legacy IRQ"),
+            ResourceConstraint::PciMsiIrq { size } => {
+                write!(f, "{} {} IRQs", size, MsiIrqType::PciMsi)
+            }
+            ResourceConstraint::PciMsixIrq { size } => {
+                write!(f, "{} {} IRQs", size, MsiIrqType::PciMsix)
+            }
+            ResourceConstraint::GenericIrq { size } => write!(f, "{} generic IRQs", size),
+            ResourceConstraint::KvmMemSlot { slot, size } => {
+                write!(f, "{} KVM memory slots", size)?;
+                if let Some(slot) = slot {
+                    write!(f, " from {}", slot)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
 /// Type of Message Signaled Interrupt
-#[derive(Clone, Copy, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum MsiIrqType {
     /// PCI MSI IRQ numbers.
     PciMsi,
@@ -120,7 +161,8 @@ pub enum MsiIrqType {
 
 /// Enumeration for device resources.
 #[allow(missing_docs)]
-#[derive(Clone)]
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Resource {
     /// IO Port address range.
     PioAddressRange { base: u16, size: u16 },
@@ -140,10 +182,121 @@ pub enum Resource {
     KvmMemSlot(u32),
 }
 
+impl Display for MsiIrqType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            MsiIrqType::PciMsi => write!(f, "PCI MSI"),
+            MsiIrqType::PciMsix => write!(f, "PCI MSI-X"),
+            MsiIrqType::GenericMsi => write!(f, "generic MSI"),
+        }
+    }
+}
+
+impl Display for Resource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Resource::PioAddressRange { base, size } => {
+                write!(f, "PIO range at {:#x} of size {:#x}", base, size)
+            }
+            Resource::MmioAddressRange { base, size } => {
+                write!(f, "MMIO range at {:#x} of size {:#x}", base, size)
+            }
+            Resource::LegacyIrq(irq) => write!(f, "legacy IRQ {}", irq),
+            Resource::MsiIrq { ty, base, size } => {
+                write!(f, "{} {} IRQs from {}", size, ty, base)
+            }
+            Resource::MacAddresss(mac) => write!(f, "MAC address {}", mac),
+            Resource::KvmMemSlot(slot) => write!(f, "KVM memory slot {}", slot),
+        }
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for MsiIrqType {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(*u.choose(&[
+            MsiIrqType::PciMsi,
+            MsiIrqType::PciMsix,
+            MsiIrqType::GenericMsi,
+        ])?)
+    }
+}
+
+// Address ranges and MSI vectors are generated non-empty and without overflowing their
+// address space, and MAC addresses are well formed, so they can be fed straight into the
+// managers and allocators.
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for Resource {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(match u.int_in_range(0..=5)? {
+            0 => {
+                let size = u.int_in_range(1..=u16::MAX)?;
+                let base = u.int_in_range(0..=u16::MAX - (size - 1))?;
+                Resource::PioAddressRange { base, size }
+            }
+            1 => {
+                let size = u.int_in_range(1..=u64::MAX)?;
+                let base = u.int_in_range(0..=u64::MAX - (size - 1))?;
+                Resource::MmioAddressRange { base, size }
+            }
+            2 => Resource::LegacyIrq(u.arbitrary()?),
+            3 => {
+                let size = u.int_in_range(1..=u32::MAX)?;
+                let base = u.int_in_range(0..=u32::MAX - (size - 1))?;
+                Resource::MsiIrq {
+                    ty: u.arbitrary()?,
+                    base,
+                    size,
+                }
+            }
+            4 => {
+                let mac: [u8; 6] = u.arbitrary()?;
+                Resource::MacAddresss(
+                    mac.iter()
+                        .map(|byte| format!("{:02x}", byte))
+                        .collect::<Vec<_>>()
+                        .join(":"),
+                )
+            }
+            _ => Resource::KvmMemSlot(u.arbitrary()?),
+        })
+    }
+}
+
 /// Newtype to store a set of device resources.
-#[derive(Default, Clone)]
+#[derive(Default, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(transparent)
+)]
 pub struct DeviceResources(Vec<Resource>);
 
+/// Lists the resources separated by commas, in the order they were appended.
+impl Display for DeviceResources {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "no resources");
+        }
+        for (idx, resource) in self.0.iter().enumerate() {
+            if idx > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", resource)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for DeviceResources {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        u.arbitrary_iter()?
+            .collect::<Result<_, _>>()
+            .map(DeviceResources)
+    }
+}
+
 impl DeviceResources {
     /// Create a container object to store device resources.
     pub fn new() -> Self {
@@ -245,6 +398,25 @@ impl DeviceResources {
     }
 }
 
+/// Allows resources which are already assigned to a device to be marked as used by the
+/// resource allocators, e.g. when the device is restored from a snapshot.
+pub trait ResourceReservation {
+    /// Reserve `resource`, returning `false` if it's not available.
+    fn reserve(&mut self, resource: &Resource) -> bool;
+
+    /// Release a resource previously reserved with [`ResourceReservation::reserve`].
+    fn release(&mut self, resource: &Resource);
+}
+
+/// Allows resources to be allocated from constraints, e.g. for the devices of a
+/// configuration file which leave some of their resources unspecified.
+pub trait ResourceAllocator: ResourceReservation {
+    /// Allocate a resource satisfying `constraint`, returning `None` if none is available.
+    ///
+    /// The resource is released with [`ResourceReservation::release`].
+    fn allocate(&mut self, constraint: &ResourceConstraint) -> Option<Resource>;
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -431,4 +603,39 @@ mod tests {
             panic!("KVM slot resource constraint is invalid.");
         }
     }
+
+    #[test]
+    fn test_display() {
+        let resources = get_device_resource();
+        assert_eq!(
+            resources.to_string(),
+            "PIO range at 0x0 of size 0x5, MMIO range at 0x12345678 of size 0x87654321, \
+             legacy IRQ 360, 34952 PCI MSI IRQs from 26214, \
+             91750 PCI MSI-X IRQs from 34952, 92296 generic MSI IRQs from 91784, \
+             MAC address 00:08:63:66:86:88, KVM memory slot 256"
+        );
+        assert_eq!(DeviceResources::new().to_string(), "no resources");
+
+        let constraint = ResourceConstraint::mmio_with_constraints(0x2000, Some((0, 0xffff)), 8);
+        assert_eq!(
+            constraint.to_string(),
+            "MMIO range of size 0x2000 aligned to 0x8 within [0x0, 0xffff]"
+        );
+        assert_eq!(
+            ResourceConstraint::new_pio(8).to_string(),
+            "PIO range of size 0x8 aligned to 0x1"
+        );
+        assert_eq!(
+            ResourceConstraint::new_legacy_irq(None).to_string(),
+            "legacy IRQ"
+        );
+        assert_eq!(
+            ResourceConstraint::PciMsixIrq { size: 4 }.to_string(),
+            "4 PCI MSI-X IRQs"
+        );
+        assert_eq!(
+            ResourceConstraint::new_kvm_mem_slot(2, Some(1)).to_string(),
+            "2 KVM memory slots from 1"
+        );
+    }
 }
@@ -0,0 +1,189 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Reference virtio-rng (entropy) device (`device-rng` feature).
+//!
+//! [`RngDevice`] is a complete, if minimal, virtio device built on the crate abstractions:
+//! the [`MmioTransport`](crate::virtio::MmioTransport) serves the virtio-mmio registers and
+//! raises the interrupt, and the device fills the buffers of its single request queue with
+//! bytes from an [`Entropy`] source, through [`DmaMemory`]:
+//!
+//! ```ignore
+//! let entropy = File::open("/dev/urandom")?;
+//! manager.register_mmio_dma(range, |memory| {
+//!     Arc::new(Mutex::new(MmioTransport::new(memory, irq, RngDevice::new(entropy))))
+//! })?;
+//! ```
+
+use std::fs::File;
+use std::io::{self, Read};
+
+use crate::dma::DmaMemory;
+use crate::virtio::{Error, Queue, VirtioDevice};
+
+/// Maximum size of the request queue.
+pub const QUEUE_MAX_SIZE: u16 = 256;
+
+// Virtio device ID of entropy sources.
+const VIRTIO_ID_RNG: u32 = 4;
+
+// Bytes of entropy produced at once.
+const CHUNK: usize = 256;
+
+/// A source of the random bytes handed to the guest.
+pub trait Entropy: Send {
+    /// Fill `data` with random bytes.
+    fn fill(&mut self, data: &mut [u8]) -> io::Result<()>;
+}
+
+impl<F: FnMut(&mut [u8]) -> io::Result<()> + Send> Entropy for F {
+    fn fill(&mut self, data: &mut [u8]) -> io::Result<()> {
+        self(data)
+    }
+}
+
+impl Entropy for File {
+    fn fill(&mut self, data: &mut [u8]) -> io::Result<()> {
+        self.read_exact(data)
+    }
+}
+
+/// A virtio-rng device, filling the buffers the guest makes available with random bytes.
+pub struct RngDevice<E> {
+    entropy: E,
+}
+
+impl<E: Entropy> RngDevice<E> {
+    /// Create a device reading random bytes from `entropy`.
+    pub fn new(entropy: E) -> Self {
+        RngDevice { entropy }
+    }
+
+    // Fill the `len` bytes at `addr`, and return the number of bytes written, and whether
+    // all of them were.
+    fn fill(&mut self, memory: &DmaMemory, addr: u64, len: u32) -> Result<(u32, bool), Error> {
+        let mut data = [0; CHUNK];
+        let mut done = 0;
+        while done < len {
+            let chunk = &mut data[..CHUNK.min((len - done) as usize)];
+            if self.entropy.fill(chunk).is_err() {
+                return Ok((done, false));
+            }
+            memory.write(addr + u64::from(done), chunk)?;
+            done += chunk.len() as u32;
+        }
+        Ok((done, true))
+    }
+}
+
+impl<E: Entropy> VirtioDevice for RngDevice<E> {
+    fn device_type(&self) -> u32 {
+        VIRTIO_ID_RNG
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &[QUEUE_MAX_SIZE]
+    }
+
+    fn process_queue(
+        &mut self,
+        _index: usize,
+        queues: &mut [Queue],
+        memory: &DmaMemory,
+    ) -> Result<bool, Error> {
+        let queue = &mut queues[0];
+        let mut used = false;
+        while let Some(chain) = queue.pop(memory)? {
+            // Fill the device writable buffers, up to the first entropy failure.
+            let mut written = 0u32;
+            for desc in chain.descriptors.iter().filter(|desc| desc.writable) {
+                let (filled, complete) = self.fill(memory, desc.addr, desc.len)?;
+                written = written.saturating_add(filled);
+                if !complete {
+                    break;
+                }
+            }
+            queue.add_used(memory, chain.head, written)?;
+            used = true;
+        }
+        Ok(used)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use crate::virtio::tests::Driver;
+    use crate::virtio::*;
+
+    #[test]
+    fn test_rng_requests() {
+        let (driver, _) = Driver::new(|memory, irq| {
+            let mut counter = 0u8;
+            let entropy = move |data: &mut [u8]| {
+                for byte in data {
+                    counter = counter.wrapping_add(1);
+                    *byte = counter;
+                }
+                Ok(())
+            };
+            Mutex::new(MmioTransport::new(memory, irq, RngDevice::new(entropy)))
+        });
+        assert_eq!(driver.read(DEVICE_ID), VIRTIO_ID_RNG);
+        driver.init(VIRTIO_F_VERSION_1, 1);
+
+        // A single buffer, then a chain of a read-only and two writable buffers.
+        driver.descriptor(0, 0, (0x40000, 4), DESC_F_WRITE);
+        driver.descriptor(0, 1, (0x50000, 8), DESC_F_NEXT);
+        driver.descriptor(0, 2, (0x50000, 2), DESC_F_WRITE | DESC_F_NEXT);
+        driver.descriptor(0, 3, (0x60000, 300), DESC_F_WRITE);
+        driver.make_available(0, &[0, 1]);
+        driver.write(QUEUE_NOTIFY, 0);
+
+        assert_eq!(driver.used(0), (2, vec![(0, 4), (1, 302)]));
+        let mut data = [0; 4];
+        driver.memory.read(0x40000, &mut data).unwrap();
+        assert_eq!(data, [1, 2, 3, 4]);
+        driver.memory.read(0x50000, &mut data).unwrap();
+        assert_eq!(data, [5, 6, 0, 0]);
+        driver.memory.read(0x60000 + 298, &mut data).unwrap();
+        assert_eq!(data, [49, 50, 0, 0]);
+
+        driver.irq.expect_triggered(1);
+        assert_eq!(driver.read(INTERRUPT_STATUS), INT_USED_RING);
+        driver.write(INTERRUPT_ACK, INT_USED_RING);
+        assert_eq!(driver.read(INTERRUPT_STATUS), 0);
+
+        // Nothing new is available.
+        driver.write(QUEUE_NOTIFY, 0);
+        driver.irq.expect_triggered(1);
+    }
+
+    #[test]
+    fn test_rng_entropy_failure() {
+        let (driver, _) = Driver::new(|memory, irq| {
+            let mut available = 6;
+            let entropy = move |data: &mut [u8]| match data.len() <= available {
+                true => {
+                    available -= data.len();
+                    data.fill(0xaa);
+                    Ok(())
+                }
+                false => Err(io::Error::from(io::ErrorKind::WouldBlock)),
+            };
+            Mutex::new(MmioTransport::new(memory, irq, RngDevice::new(entropy)))
+        });
+        driver.init(VIRTIO_F_VERSION_1, 1);
+
+        // The chain is handed back with the bytes written before the failure.
+        driver.descriptor(0, 0, (0x40000, 4), DESC_F_WRITE | DESC_F_NEXT);
+        driver.descriptor(0, 1, (0x40004, 4), DESC_F_WRITE);
+        driver.make_available(0, &[0]);
+        driver.write(QUEUE_NOTIFY, 0);
+        assert_eq!(driver.used(0), (1, vec![(0, 4)]));
+        assert_eq!(driver.read(STATUS) & STATUS_NEEDS_RESET, 0);
+    }
+}
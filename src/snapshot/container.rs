@@ -0,0 +1,600 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Single-file container for snapshots.
+
+use std::convert::TryFrom;
+use std::ffi::OsStr;
+use std::fmt::{Display, Formatter};
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::result::Result;
+
+use crate::device_manager::{DeviceDescriptor, Layout};
+use crate::resources::DeviceResources;
+use crate::snapshot::{
+    self, load_resources, save_resources, DeviceState, ExternalResource, Reader, Snapshot,
+};
+
+// Identifies snapshot containers.
+const MAGIC: [u8; 8] = *b"VMDEVSNP";
+// Version of the container format.
+const VERSION: u32 = 1;
+// Size of the fixed header: magic, version, number of sections and index checksum.
+const HEADER_SIZE: usize = 20;
+
+/// Errors encountered while reading a snapshot container.
+#[derive(Debug)]
+pub enum Error {
+    /// I/O error while reading or writing the container.
+    Io(io::Error),
+    /// The data does not start with the container magic.
+    InvalidMagic,
+    /// The container uses an unsupported format version.
+    UnsupportedVersion(u32),
+    /// The container is shorter than what its header and index describe.
+    Truncated,
+    /// The index checksum does not match its contents.
+    IndexChecksum,
+    /// The checksum of the specified section does not match its contents.
+    SectionChecksum(usize),
+    /// The contents of a section could not be decoded.
+    InvalidSection(snapshot::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io(_) => write!(f, "snapshot container: I/O error"),
+            Error::InvalidMagic => write!(f, "snapshot container: invalid magic"),
+            Error::UnsupportedVersion(version) => {
+                write!(f, "snapshot container: unsupported version {}", version)
+            }
+            Error::Truncated => write!(f, "snapshot container: truncated data"),
+            Error::IndexChecksum => write!(f, "snapshot container: index checksum mismatch"),
+            Error::SectionChecksum(idx) => {
+                write!(
+                    f,
+                    "snapshot container: checksum mismatch in section {}",
+                    idx
+                )
+            }
+            Error::InvalidSection(_) => write!(f, "snapshot container: invalid section"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::InvalidSection(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Kind of the data held by a container section.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SectionKind {
+    /// State of a device, as a [`DeviceState`].
+    DeviceState,
+    /// Layout of the MMIO bus, as a [`Layout`].
+    Layout,
+    /// Resources of a device, as a [`DeviceResources`].
+    Resources,
+}
+
+impl SectionKind {
+    fn tag(self) -> u8 {
+        match self {
+            SectionKind::DeviceState => 0,
+            SectionKind::Layout => 1,
+            SectionKind::Resources => 2,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            0 => Some(SectionKind::DeviceState),
+            1 => Some(SectionKind::Layout),
+            2 => Some(SectionKind::Resources),
+            _ => None,
+        }
+    }
+}
+
+/// A tagged section of a [`Container`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Section {
+    kind: SectionKind,
+    name: String,
+    data: Vec<u8>,
+}
+
+impl Section {
+    /// Get the kind of the section.
+    pub fn kind(&self) -> SectionKind {
+        self.kind
+    }
+
+    /// Get the name of the section (i.e. the device identifier for per-device sections).
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Get the encoded contents of the section.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+/// Single-file container for the device states, the bus layout and the device resources
+/// making up a snapshot.
+///
+/// The container starts with a magic header, followed by an index describing the kind,
+/// name, location and CRC32 checksum of every section, and then by the section contents.
+/// The index itself is checksummed as well, and the integrity of the whole container is
+/// verified when it's read back.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Container {
+    sections: Vec<Section>,
+}
+
+impl Container {
+    /// Create an empty container.
+    pub fn new() -> Self {
+        Container::default()
+    }
+
+    /// Append a section to the container.
+    pub fn add(&mut self, kind: SectionKind, name: &str, data: Vec<u8>) {
+        self.sections.push(Section {
+            kind,
+            name: name.to_string(),
+            data,
+        });
+    }
+
+    /// Append a section holding the state of a device, named after the device.
+    pub fn add_state(&mut self, state: &DeviceState) {
+        self.add(SectionKind::DeviceState, state.id(), encode_state(state));
+    }
+
+    /// Append one section per device state of `snapshot`.
+    pub fn add_snapshot(&mut self, snapshot: &Snapshot) {
+        snapshot.iter().for_each(|state| self.add_state(state));
+    }
+
+    /// Append a section holding the layout of the MMIO bus.
+    pub fn add_layout(&mut self, layout: &Layout) {
+        self.add(SectionKind::Layout, "", encode_layout(layout));
+    }
+
+    /// Append a section holding the resources of the device identified by `id`.
+    pub fn add_resources(&mut self, id: &str, resources: &DeviceResources) {
+        self.add(SectionKind::Resources, id, save_resources(resources));
+    }
+
+    /// Get all the sections of the container.
+    pub fn sections(&self) -> &[Section] {
+        &self.sections
+    }
+
+    /// Decode the device states held by the container.
+    pub fn snapshot(&self) -> Result<Snapshot, Error> {
+        let mut snapshot = Snapshot::new();
+        for section in self.of_kind(SectionKind::DeviceState) {
+            snapshot.insert(decode_state(&section.data).map_err(Error::InvalidSection)?);
+        }
+        Ok(snapshot)
+    }
+
+    /// Decode the first bus layout held by the container, if any.
+    pub fn layout(&self) -> Result<Option<Layout>, Error> {
+        self.of_kind(SectionKind::Layout)
+            .next()
+            .map(|section| decode_layout(&section.data).map_err(Error::InvalidSection))
+            .transpose()
+    }
+
+    /// Decode the resources of the device identified by `id`, if present.
+    pub fn resources(&self, id: &str) -> Result<Option<DeviceResources>, Error> {
+        self.of_kind(SectionKind::Resources)
+            .find(|section| section.name == id)
+            .map(|section| load_resources(&section.data).map_err(Error::InvalidSection))
+            .transpose()
+    }
+
+    fn of_kind(&self, kind: SectionKind) -> impl Iterator<Item = &Section> {
+        self.sections.iter().filter(move |s| s.kind == kind)
+    }
+
+    /// Encode the container.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let index_size: usize = self
+            .sections
+            .iter()
+            .map(|s| 1 + 4 + s.name.len() + 8 + 8 + 4)
+            .sum();
+
+        let mut index = Vec::with_capacity(index_size);
+        let mut offset = 0u64;
+        for section in self.sections.iter() {
+            index.push(section.kind.tag());
+            put_bytes(&mut index, section.name.as_bytes());
+            index.extend_from_slice(&offset.to_le_bytes());
+            index.extend_from_slice(&(section.data.len() as u64).to_le_bytes());
+            index.extend_from_slice(&crc32(&section.data).to_le_bytes());
+            offset += section.data.len() as u64;
+        }
+
+        let mut counts = Vec::with_capacity(8);
+        counts.extend_from_slice(&VERSION.to_le_bytes());
+        counts.extend_from_slice(&(self.sections.len() as u32).to_le_bytes());
+
+        let mut data = Vec::with_capacity(HEADER_SIZE + index.len() + offset as usize);
+        data.extend_from_slice(&MAGIC);
+        data.extend_from_slice(&counts);
+        data.extend_from_slice(&crc32_update(crc32(&counts), &index).to_le_bytes());
+        data.extend_from_slice(&index);
+        for section in self.sections.iter() {
+            data.extend_from_slice(&section.data);
+        }
+        data
+    }
+
+    /// Decode a container, verifying its integrity.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, Error> {
+        if data.len() < HEADER_SIZE {
+            return Err(Error::Truncated);
+        }
+        if data[..MAGIC.len()] != MAGIC {
+            return Err(Error::InvalidMagic);
+        }
+
+        let mut reader = Reader {
+            data: &data[MAGIC.len()..],
+        };
+        let version = reader.u32().map_err(|_| Error::Truncated)?;
+        if version != VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        let count = reader.u32().map_err(|_| Error::Truncated)?;
+        let checksum = reader.u32().map_err(|_| Error::Truncated)?;
+
+        let index_start = reader.data;
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            let entry = read_index_entry(&mut reader).map_err(|_| Error::Truncated)?;
+            entries.push(entry);
+        }
+        let index = &index_start[..index_start.len() - reader.data.len()];
+        let counts = &data[MAGIC.len()..MAGIC.len() + 8];
+        if crc32_update(crc32(counts), index) != checksum {
+            return Err(Error::IndexChecksum);
+        }
+
+        let payload = reader.data;
+        let mut sections = Vec::with_capacity(entries.len());
+        for (idx, (tag, name, offset, len, crc)) in entries.into_iter().enumerate() {
+            let kind = SectionKind::from_tag(tag)
+                .ok_or(Error::InvalidSection(snapshot::Error::InvalidState))?;
+            let bytes = usize::try_from(offset)
+                .ok()
+                .zip(usize::try_from(len).ok())
+                .and_then(|(offset, len)| payload.get(offset..offset.checked_add(len)?))
+                .ok_or(Error::Truncated)?;
+            if crc32(bytes) != crc {
+                return Err(Error::SectionChecksum(idx));
+            }
+            sections.push(Section {
+                kind,
+                name,
+                data: bytes.to_vec(),
+            });
+        }
+
+        Ok(Container { sections })
+    }
+
+    /// Write the encoded container to `writer`.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
+        writer.write_all(&self.to_bytes()).map_err(Error::Io)
+    }
+
+    /// Read a container from `reader` until end of file, verifying its integrity.
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Self, Error> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).map_err(Error::Io)?;
+        Container::from_bytes(&data)
+    }
+}
+
+type IndexEntry = (u8, String, u64, u64, u32);
+
+fn read_index_entry(reader: &mut Reader) -> Result<IndexEntry, snapshot::Error> {
+    let tag = reader.u8()?;
+    let name = get_string(reader)?;
+    Ok((tag, name, reader.u64()?, reader.u64()?, reader.u32()?))
+}
+
+// Append `bytes` to `data`, prefixed by their length.
+fn put_bytes(data: &mut Vec<u8>, bytes: &[u8]) {
+    data.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    data.extend_from_slice(bytes);
+}
+
+// Read bytes previously written with `put_bytes`.
+fn get_bytes<'a>(reader: &mut Reader<'a>) -> Result<&'a [u8], snapshot::Error> {
+    let len = reader.u32()? as usize;
+    reader.bytes(len)
+}
+
+fn get_string(reader: &mut Reader) -> Result<String, snapshot::Error> {
+    let bytes = get_bytes(reader)?;
+    String::from_utf8(bytes.to_vec()).map_err(|_| snapshot::Error::InvalidState)
+}
+
+fn encode_state(state: &DeviceState) -> Vec<u8> {
+    let mut data = Vec::new();
+    put_bytes(&mut data, state.device_type().as_bytes());
+    put_bytes(&mut data, state.id().as_bytes());
+    data.extend_from_slice(&(state.mmio_ranges().len() as u32).to_le_bytes());
+    for (base, size) in state.mmio_ranges() {
+        data.extend_from_slice(&base.to_le_bytes());
+        data.extend_from_slice(&size.to_le_bytes());
+    }
+    put_bytes(&mut data, state.data());
+    data.extend_from_slice(&(state.external().len() as u32).to_le_bytes());
+    for res in state.external() {
+        match res {
+            ExternalResource::File(path) => {
+                data.push(0);
+                put_bytes(&mut data, path.as_os_str().as_bytes());
+            }
+            ExternalResource::NetworkInterface(name) => {
+                data.push(1);
+                put_bytes(&mut data, name.as_bytes());
+            }
+            ExternalResource::Fd(fd) => {
+                data.push(2);
+                data.extend_from_slice(&fd.to_le_bytes());
+            }
+        }
+    }
+    data
+}
+
+fn decode_state(data: &[u8]) -> Result<DeviceState, snapshot::Error> {
+    let mut reader = Reader { data };
+    let device_type = get_string(&mut reader)?;
+    let id = get_string(&mut reader)?;
+    let mut ranges = Vec::new();
+    for _ in 0..reader.u32()? {
+        ranges.push((reader.u64()?, reader.u64()?));
+    }
+    let state = get_bytes(&mut reader)?.to_vec();
+    let mut external = Vec::new();
+    for _ in 0..reader.u32()? {
+        external.push(match reader.u8()? {
+            0 => ExternalResource::File(OsStr::from_bytes(get_bytes(&mut reader)?).into()),
+            1 => ExternalResource::NetworkInterface(get_string(&mut reader)?),
+            2 => ExternalResource::Fd(reader.u32()? as i32),
+            _ => return Err(snapshot::Error::InvalidState),
+        });
+    }
+    if !reader.data.is_empty() {
+        return Err(snapshot::Error::InvalidState);
+    }
+    Ok(DeviceState::new(&device_type, &id, ranges, state).with_external(external))
+}
+
+// The types and configurations of the devices follow their resources, if any device has one,
+// so that layouts without them are encoded as before.
+fn encode_layout(layout: &Layout) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.extend_from_slice(&(layout.devices().len() as u32).to_le_bytes());
+    for device in layout.devices() {
+        put_bytes(&mut data, &save_resources(device.resources()));
+    }
+    let typed = layout
+        .devices()
+        .iter()
+        .any(|device| device.device_type().is_some() || !device.config().is_empty());
+    if typed {
+        for device in layout.devices() {
+            put_bytes(&mut data, device.device_type().unwrap_or("").as_bytes());
+            put_bytes(&mut data, device.config());
+        }
+    }
+    data
+}
+
+fn decode_layout(data: &[u8]) -> Result<Layout, snapshot::Error> {
+    let mut reader = Reader { data };
+    let mut layout = Layout::new();
+    for _ in 0..reader.u32()? {
+        let resources = load_resources(get_bytes(&mut reader)?)?;
+        layout.append(DeviceDescriptor::new(resources));
+    }
+    if !reader.data.is_empty() {
+        for device in layout.devices_mut() {
+            let device_type = get_string(&mut reader)?;
+            let mut descriptor = DeviceDescriptor::new(device.resources().clone())
+                .with_config(get_bytes(&mut reader)?.to_vec());
+            if !device_type.is_empty() {
+                descriptor = descriptor.with_device_type(&device_type);
+            }
+            *device = descriptor;
+        }
+    }
+    if !reader.data.is_empty() {
+        return Err(snapshot::Error::InvalidState);
+    }
+    Ok(layout)
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0, data)
+}
+
+// Bitwise CRC-32 (IEEE 802.3), continuing from a previously computed `crc`.
+fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::resources::Resource;
+
+    fn container() -> Container {
+        let mut snapshot = Snapshot::new();
+        snapshot.insert(DeviceState::new(
+            "virtio-blk",
+            "blk0",
+            vec![(0xd000_0000, 0x200)],
+            vec![1, 2, 3],
+        ));
+        snapshot.insert(
+            DeviceState::new("virtio-net", "net0", vec![], vec![]).with_external(vec![
+                ExternalResource::File("/var/lib/disk.img".into()),
+                ExternalResource::NetworkInterface("tap0".to_string()),
+                ExternalResource::Fd(7),
+            ]),
+        );
+
+        let mut resources = DeviceResources::new();
+        resources.append(Resource::MmioAddressRange {
+            base: 0xd000_0000,
+            size: 0x200,
+        });
+        resources.append(Resource::LegacyIrq(5));
+
+        let mut layout = Layout::new();
+        layout.append(
+            DeviceDescriptor::new(resources.clone())
+                .with_device_type("virtio-blk")
+                .with_config(vec![4, 5]),
+        );
+        layout.append(DeviceDescriptor::new(DeviceResources::new()));
+
+        let mut container = Container::new();
+        container.add_snapshot(&snapshot);
+        container.add_layout(&layout);
+        container.add_resources("blk0", &resources);
+        container
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32_update(crc32(b"1234"), b"56789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let container = container();
+        assert_eq!(container.sections().len(), 4);
+
+        let mut file = Vec::new();
+        container.write_to(&mut file).unwrap();
+        let read = Container::read_from(&mut file.as_slice()).unwrap();
+        assert_eq!(read, container);
+
+        let snapshot = read.snapshot().unwrap();
+        assert_eq!(snapshot.iter().count(), 2);
+        assert_eq!(snapshot.get("blk0").unwrap().data(), &[1, 2, 3]);
+        assert_eq!(snapshot.get("net0").unwrap().external().len(), 3);
+
+        let layout = read.layout().unwrap().unwrap();
+        assert_eq!(layout.devices().len(), 2);
+        assert_eq!(
+            layout.devices()[0].resources().get_mmio_address_ranges(),
+            vec![(0xd000_0000, 0x200)]
+        );
+        assert_eq!(layout.devices()[0].device_type(), Some("virtio-blk"));
+        assert_eq!(layout.devices()[0].config(), [4, 5]);
+        assert_eq!(layout.devices()[1].device_type(), None);
+
+        let resources = read.resources("blk0").unwrap().unwrap();
+        assert_eq!(resources.get_legacy_irq(), Some(5));
+        assert!(read.resources("net0").unwrap().is_none());
+
+        // Layouts without types are encoded as before.
+        let mut untyped = Layout::new();
+        untyped.append(DeviceDescriptor::new(resources.clone()));
+        let saved = save_resources(&resources);
+        assert_eq!(encode_layout(&untyped).len(), 4 + 4 + saved.len());
+
+        let empty = Container::from_bytes(&Container::new().to_bytes()).unwrap();
+        assert!(empty.sections().is_empty());
+        assert!(empty.layout().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_integrity() {
+        let data = container().to_bytes();
+
+        assert!(matches!(
+            Container::from_bytes(&data[..HEADER_SIZE - 1]),
+            Err(Error::Truncated)
+        ));
+        assert!(matches!(
+            Container::from_bytes(&data[..data.len() - 1]),
+            Err(Error::Truncated)
+        ));
+
+        let mut bad = data.clone();
+        bad[0] ^= 1;
+        assert!(matches!(
+            Container::from_bytes(&bad),
+            Err(Error::InvalidMagic)
+        ));
+
+        let mut bad = data.clone();
+        bad[8] = 2;
+        assert!(matches!(
+            Container::from_bytes(&bad),
+            Err(Error::UnsupportedVersion(2))
+        ));
+
+        // Corrupt the name of the first section in the index.
+        let mut bad = data.clone();
+        bad[HEADER_SIZE + 5] ^= 1;
+        assert!(matches!(
+            Container::from_bytes(&bad),
+            Err(Error::IndexChecksum)
+        ));
+
+        // Corrupt the last byte of the last section.
+        let mut bad = data.clone();
+        *bad.last_mut().unwrap() ^= 1;
+        let err = Container::from_bytes(&bad).unwrap_err();
+        assert!(matches!(err, Error::SectionChecksum(3)));
+        assert_eq!(
+            format!("{}", err),
+            "snapshot container: checksum mismatch in section 3"
+        );
+
+        // A well formed container holding an undecodable section.
+        let mut container = Container::new();
+        container.add(SectionKind::DeviceState, "blk0", vec![0xff]);
+        let read = Container::from_bytes(&container.to_bytes()).unwrap();
+        assert!(matches!(
+            read.snapshot(),
+            Err(Error::InvalidSection(snapshot::Error::InvalidState))
+        ));
+    }
+}
@@ -0,0 +1,1002 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Helpers for saving and restoring device state.
+//!
+//! A device which implements [`Persist`] can have its state saved into a [`DeviceState`].
+//! Besides the opaque state of the device, a [`DeviceState`] records the type and the
+//! identifier of the device, as well as the MMIO ranges it was registered with, so that
+//! restoring the state onto a device which doesn't match is rejected with a descriptive
+//! error instead of silently feeding the state to the wrong device.
+//!
+//! Resources backing a device outside of its register state (disk images, tap interfaces,
+//! file descriptors passed by the VMM) are described by [`ExternalResource`]s, which the
+//! device reports when saved and gets back, before its state is restored, to reattach to.
+//!
+//! The states of multiple devices are grouped in a [`Snapshot`]. For periodic checkpoints,
+//! a [`Delta`] holding only what changed since a base snapshot can be computed with
+//! [`Snapshot::diff`] and later applied on top of that base with [`Snapshot::apply`].
+//!
+//! The resources owned by a device are saved with [`save_resources`] and loaded back with
+//! [`load_resources`], so they can be registered again with
+//! [`IoManager::restore_resources`](../device_manager/struct.IoManager.html#method.restore_resources).
+//!
+//! Before migrating, [`check_migration`] tells for each device whether the features used
+//! on the source are supported on the destination, and which ones have to be masked.
+//!
+//! Everything making up a snapshot can be stored in a single, checksummed
+//! [`Container`](container/struct.Container.html).
+
+pub mod container;
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fmt::{Display, Formatter};
+use std::os::unix::io::RawFd;
+use std::path::PathBuf;
+use std::result::Result;
+
+use crate::resources::{DeviceResources, MsiIrqType, Resource};
+
+/// Errors encountered while restoring device state.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The state was saved from a device of another type.
+    DeviceTypeMismatch {
+        /// Type of the device being restored.
+        expected: String,
+        /// Type recorded in the saved state.
+        found: String,
+    },
+    /// The state was saved from another device instance.
+    DeviceIdMismatch {
+        /// Identifier of the device being restored.
+        expected: String,
+        /// Identifier recorded in the saved state.
+        found: String,
+    },
+    /// The state was saved from a device registered with other MMIO ranges.
+    RangeMismatch {
+        /// MMIO ranges of the device being restored, as `(base, size)` pairs.
+        expected: Vec<(u64, u64)>,
+        /// MMIO ranges recorded in the saved state, as `(base, size)` pairs.
+        found: Vec<(u64, u64)>,
+    },
+    /// The device rejected the saved state.
+    InvalidState,
+    /// The snapshot holds no state for the device with the specified identifier.
+    MissingDevice(String),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::DeviceTypeMismatch { expected, found } => write!(
+                f,
+                "device type mismatch (expected {}, found {})",
+                expected, found
+            ),
+            Error::DeviceIdMismatch { expected, found } => write!(
+                f,
+                "device id mismatch (expected {}, found {})",
+                expected, found
+            ),
+            Error::RangeMismatch { expected, found } => write!(
+                f,
+                "device ranges mismatch (expected {:x?}, found {:x?})",
+                expected, found
+            ),
+            Error::InvalidState => write!(f, "invalid device state"),
+            Error::MissingDevice(id) => write!(f, "no state for device {}", id),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Allows the state of a device to be saved and restored.
+pub trait Persist {
+    /// Return the type of the device (e.g. `"virtio-blk"`).
+    fn device_type(&self) -> &str;
+
+    /// Save the current state of the device.
+    fn save_state(&self) -> Vec<u8>;
+
+    /// Restore the device to a previously saved state.
+    ///
+    /// # Arguments
+    ///
+    /// * `data`: state previously returned by [`Persist::save_state`]
+    fn restore_state(&self, data: &[u8]) -> Result<(), Error>;
+
+    /// Describe the external resources backing the device, which have to be handed back to
+    /// the device on restore.
+    ///
+    /// The default implementation reports no external resources.
+    fn prepare_external_state(&self) -> Vec<ExternalResource> {
+        Vec::new()
+    }
+
+    /// Reattach the device to its external resources. Invoked on restore before
+    /// [`Persist::restore_state`].
+    ///
+    /// # Arguments
+    ///
+    /// * `resources`: resources previously returned by [`Persist::prepare_external_state`]
+    ///
+    /// The default implementation does nothing.
+    fn reattach_external_state(&self, _resources: &[ExternalResource]) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Resource backing a device outside of its register state.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ExternalResource {
+    /// File backing the device, e.g. a disk image.
+    File(PathBuf),
+    /// Host network interface, e.g. a tap device.
+    NetworkInterface(String),
+    /// File descriptor provided by the VMM, which has to be passed again on restore.
+    Fd(RawFd),
+}
+
+/// Saved state of a device, together with the identity of the device.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DeviceState {
+    device_type: String,
+    id: String,
+    mmio_ranges: Vec<(u64, u64)>,
+    data: Vec<u8>,
+    external: Vec<ExternalResource>,
+}
+
+impl DeviceState {
+    /// Create a device state object from its parts.
+    ///
+    /// # Arguments
+    ///
+    /// * `device_type`: type of the device the state belongs to
+    /// * `id`: identifier of the device instance
+    /// * `mmio_ranges`: MMIO ranges of the device, as `(base, size)` pairs
+    /// * `data`: opaque device state
+    pub fn new(device_type: &str, id: &str, mmio_ranges: Vec<(u64, u64)>, data: Vec<u8>) -> Self {
+        DeviceState {
+            device_type: device_type.to_string(),
+            id: id.to_string(),
+            mmio_ranges,
+            data,
+            external: Vec::new(),
+        }
+    }
+
+    /// Set the external resources backing the device.
+    pub fn with_external(mut self, external: Vec<ExternalResource>) -> Self {
+        self.external = external;
+        self
+    }
+
+    /// Save the state of `device`.
+    ///
+    /// # Arguments
+    ///
+    /// * `device`: device whose state is saved
+    /// * `id`: identifier of the device instance
+    /// * `mmio_ranges`: MMIO ranges of the device, as `(base, size)` pairs
+    pub fn save<T: Persist + ?Sized>(device: &T, id: &str, mmio_ranges: &[(u64, u64)]) -> Self {
+        DeviceState::new(
+            device.device_type(),
+            id,
+            mmio_ranges.to_vec(),
+            device.save_state(),
+        )
+        .with_external(device.prepare_external_state())
+    }
+
+    /// Get the type of the device the state was saved from.
+    pub fn device_type(&self) -> &str {
+        &self.device_type
+    }
+
+    /// Get the identifier of the device the state was saved from.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Get the MMIO ranges of the device the state was saved from.
+    pub fn mmio_ranges(&self) -> &[(u64, u64)] {
+        &self.mmio_ranges
+    }
+
+    /// Get the opaque device state.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Get the external resources backing the device.
+    pub fn external(&self) -> &[ExternalResource] {
+        &self.external
+    }
+
+    /// Move the recorded MMIO ranges based at one of the keys of `rebase` to the
+    /// associated base address, so the state can be validated against a device restored
+    /// at a different location.
+    pub fn rebase(&mut self, rebase: &BTreeMap<u64, u64>) {
+        for (base, _) in self.mmio_ranges.iter_mut() {
+            if let Some(new_base) = rebase.get(base) {
+                *base = *new_base;
+            }
+        }
+    }
+
+    /// Check whether the state was saved from a device matching `device`, `id`
+    /// and `mmio_ranges`.
+    pub fn validate<T: Persist + ?Sized>(
+        &self,
+        device: &T,
+        id: &str,
+        mmio_ranges: &[(u64, u64)],
+    ) -> Result<(), Error> {
+        if device.device_type() != self.device_type {
+            return Err(Error::DeviceTypeMismatch {
+                expected: device.device_type().to_string(),
+                found: self.device_type.clone(),
+            });
+        }
+        if id != self.id {
+            return Err(Error::DeviceIdMismatch {
+                expected: id.to_string(),
+                found: self.id.clone(),
+            });
+        }
+        if mmio_ranges != self.mmio_ranges.as_slice() {
+            return Err(Error::RangeMismatch {
+                expected: mmio_ranges.to_vec(),
+                found: self.mmio_ranges.clone(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Restore the state onto `device`, after checking that it was saved from a matching
+    /// device with [`DeviceState::validate`] and reattaching the device to its external
+    /// resources.
+    pub fn restore<T: Persist + ?Sized>(
+        &self,
+        device: &T,
+        id: &str,
+        mmio_ranges: &[(u64, u64)],
+    ) -> Result<(), Error> {
+        self.validate(device, id, mmio_ranges)?;
+        device.reattach_external_state(&self.external)?;
+        device.restore_state(&self.data)
+    }
+}
+
+/// Saved state of a set of devices, indexed by device identifier.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Snapshot {
+    devices: BTreeMap<String, DeviceState>,
+}
+
+impl Snapshot {
+    /// Create an empty snapshot.
+    pub fn new() -> Self {
+        Snapshot::default()
+    }
+
+    /// Add the state of a device to the snapshot, returning the state previously
+    /// recorded for a device with the same identifier, if any.
+    pub fn insert(&mut self, state: DeviceState) -> Option<DeviceState> {
+        self.devices.insert(state.id.clone(), state)
+    }
+
+    /// Get the state of the device identified by `id`.
+    pub fn get(&self, id: &str) -> Option<&DeviceState> {
+        self.devices.get(id)
+    }
+
+    /// Return an iterator over the device states, in ascending identifier order.
+    pub fn iter(&self) -> impl Iterator<Item = &DeviceState> {
+        self.devices.values()
+    }
+
+    /// Compute the changes required to turn `self` into `other`.
+    pub fn diff(&self, other: &Snapshot) -> Delta {
+        let mut delta = Delta::default();
+
+        for (id, state) in other.devices.iter() {
+            match self.devices.get(id) {
+                Some(base) if base == state => {}
+                Some(base)
+                    if base.device_type == state.device_type
+                        && base.mmio_ranges == state.mmio_ranges
+                        && base.external == state.external =>
+                {
+                    delta
+                        .patched
+                        .push(StatePatch::new(id, &base.data, &state.data));
+                }
+                _ => delta.added.push(state.clone()),
+            }
+        }
+
+        for id in self.devices.keys() {
+            if !other.devices.contains_key(id) {
+                delta.removed.push(id.clone());
+            }
+        }
+
+        delta
+    }
+
+    /// Apply `delta` on top of `self`, which must be the snapshot the delta was computed
+    /// against, and return the resulting snapshot.
+    pub fn apply(&self, delta: &Delta) -> Result<Snapshot, Error> {
+        let mut snapshot = self.clone();
+
+        for id in delta.removed.iter() {
+            snapshot
+                .devices
+                .remove(id)
+                .ok_or_else(|| Error::MissingDevice(id.clone()))?;
+        }
+        for patch in delta.patched.iter() {
+            let state = snapshot
+                .devices
+                .get_mut(&patch.id)
+                .ok_or_else(|| Error::MissingDevice(patch.id.clone()))?;
+            patch.apply(&mut state.data)?;
+        }
+        for state in delta.added.iter() {
+            snapshot.insert(state.clone());
+        }
+
+        Ok(snapshot)
+    }
+}
+
+// Changes to the opaque state of a single device, as a list of `(offset, bytes)` chunks
+// together with the length of the resulting state.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct StatePatch {
+    id: String,
+    len: usize,
+    chunks: Vec<(usize, Vec<u8>)>,
+}
+
+impl StatePatch {
+    fn new(id: &str, base: &[u8], data: &[u8]) -> Self {
+        let mut chunks: Vec<(usize, Vec<u8>)> = Vec::new();
+        for (offset, byte) in data.iter().enumerate() {
+            if base.get(offset) == Some(byte) {
+                continue;
+            }
+            match chunks.last_mut() {
+                Some((start, bytes)) if *start + bytes.len() == offset => bytes.push(*byte),
+                _ => chunks.push((offset, vec![*byte])),
+            }
+        }
+
+        StatePatch {
+            id: id.to_string(),
+            len: data.len(),
+            chunks,
+        }
+    }
+
+    fn apply(&self, data: &mut Vec<u8>) -> Result<(), Error> {
+        data.resize(self.len, 0);
+        for (offset, bytes) in self.chunks.iter() {
+            data.get_mut(*offset..*offset + bytes.len())
+                .ok_or(Error::InvalidState)?
+                .copy_from_slice(bytes);
+        }
+        Ok(())
+    }
+}
+
+/// Changes between two snapshots, as computed by [`Snapshot::diff`].
+///
+/// Devices whose state didn't change are left out, while devices whose opaque state
+/// changed are only described by the modified bytes.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Delta {
+    // Devices which are new, or whose type, ranges or external resources changed.
+    added: Vec<DeviceState>,
+    patched: Vec<StatePatch>,
+    removed: Vec<String>,
+}
+
+impl Delta {
+    /// Check whether the delta holds no changes.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.patched.is_empty() && self.removed.is_empty()
+    }
+
+    /// Return the number of state bytes carried by the delta.
+    pub fn data_len(&self) -> usize {
+        let added: usize = self.added.iter().map(|state| state.data.len()).sum();
+        let patched: usize = self
+            .patched
+            .iter()
+            .flat_map(|patch| patch.chunks.iter())
+            .map(|(_, bytes)| bytes.len())
+            .sum();
+        added + patched
+    }
+}
+
+/// Feature bits of a device on the migration source.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Features {
+    /// Features offered by the device.
+    pub offered: u64,
+    /// Features acknowledged by the driver.
+    pub acked: u64,
+}
+
+/// Migration compatibility of a device, as computed by [`check_migration`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Compatibility {
+    /// Features offered on the source which the destination does not support, and which
+    /// must be masked from the device on the source.
+    pub masked: u64,
+    /// Features acknowledged by the driver which the destination does not support.
+    pub unsupported: u64,
+}
+
+impl Compatibility {
+    /// Compute the compatibility of a device with the `source` features with a device
+    /// supporting the `destination` features.
+    pub fn new(source: Features, destination: u64) -> Self {
+        Compatibility {
+            masked: source.offered & !destination,
+            unsupported: source.acked & !destination,
+        }
+    }
+
+    /// Check whether the device can be migrated, i.e. no acknowledged feature is
+    /// missing on the destination.
+    pub fn is_compatible(&self) -> bool {
+        self.unsupported == 0
+    }
+}
+
+/// Compute the migration compatibility of every device on the source.
+///
+/// # Arguments
+///
+/// * `source`: features of the source devices, indexed by device identifier
+/// * `destination`: features supported by the destination devices, indexed by device
+///   identifier
+pub fn check_migration(
+    source: &BTreeMap<String, Features>,
+    destination: &BTreeMap<String, u64>,
+) -> Result<BTreeMap<String, Compatibility>, Error> {
+    source
+        .iter()
+        .map(|(id, features)| {
+            let supported = destination
+                .get(id)
+                .ok_or_else(|| Error::MissingDevice(id.clone()))?;
+            Ok((id.clone(), Compatibility::new(*features, *supported)))
+        })
+        .collect()
+}
+
+// Tags identifying the resource variants in the encoding used by `save_resources`.
+const TAG_PIO_ADDRESS_RANGE: u8 = 0;
+const TAG_MMIO_ADDRESS_RANGE: u8 = 1;
+const TAG_LEGACY_IRQ: u8 = 2;
+const TAG_MSI_IRQ: u8 = 3;
+const TAG_MAC_ADDRESS: u8 = 4;
+const TAG_KVM_MEM_SLOT: u8 = 5;
+
+/// Encode `resources` into a byte vector, which can be decoded with [`load_resources`].
+pub fn save_resources(resources: &DeviceResources) -> Vec<u8> {
+    let mut data = Vec::new();
+    for res in resources.get_all_resources() {
+        match res {
+            Resource::PioAddressRange { base, size } => {
+                data.push(TAG_PIO_ADDRESS_RANGE);
+                data.extend_from_slice(&base.to_le_bytes());
+                data.extend_from_slice(&size.to_le_bytes());
+            }
+            Resource::MmioAddressRange { base, size } => {
+                data.push(TAG_MMIO_ADDRESS_RANGE);
+                data.extend_from_slice(&base.to_le_bytes());
+                data.extend_from_slice(&size.to_le_bytes());
+            }
+            Resource::LegacyIrq(irq) => {
+                data.push(TAG_LEGACY_IRQ);
+                data.extend_from_slice(&irq.to_le_bytes());
+            }
+            Resource::MsiIrq { ty, base, size } => {
+                data.push(TAG_MSI_IRQ);
+                data.push(match ty {
+                    MsiIrqType::PciMsi => 0,
+                    MsiIrqType::PciMsix => 1,
+                    MsiIrqType::GenericMsi => 2,
+                });
+                data.extend_from_slice(&base.to_le_bytes());
+                data.extend_from_slice(&size.to_le_bytes());
+            }
+            Resource::MacAddresss(addr) => {
+                data.push(TAG_MAC_ADDRESS);
+                data.extend_from_slice(&(addr.len() as u32).to_le_bytes());
+                data.extend_from_slice(addr.as_bytes());
+            }
+            Resource::KvmMemSlot(slot) => {
+                data.push(TAG_KVM_MEM_SLOT);
+                data.extend_from_slice(&slot.to_le_bytes());
+            }
+        }
+    }
+    data
+}
+
+// Helper for decoding the fields of an encoded resource.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if len > self.data.len() {
+            return Err(Error::InvalidState);
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+}
+
+/// Decode resources previously encoded with [`save_resources`].
+pub fn load_resources(data: &[u8]) -> Result<DeviceResources, Error> {
+    let mut reader = Reader { data };
+    let mut resources = DeviceResources::new();
+    while !reader.data.is_empty() {
+        let res = match reader.u8()? {
+            TAG_PIO_ADDRESS_RANGE => Resource::PioAddressRange {
+                base: reader.u16()?,
+                size: reader.u16()?,
+            },
+            TAG_MMIO_ADDRESS_RANGE => Resource::MmioAddressRange {
+                base: reader.u64()?,
+                size: reader.u64()?,
+            },
+            TAG_LEGACY_IRQ => Resource::LegacyIrq(reader.u32()?),
+            TAG_MSI_IRQ => Resource::MsiIrq {
+                ty: match reader.u8()? {
+                    0 => MsiIrqType::PciMsi,
+                    1 => MsiIrqType::PciMsix,
+                    2 => MsiIrqType::GenericMsi,
+                    _ => return Err(Error::InvalidState),
+                },
+                base: reader.u32()?,
+                size: reader.u32()?,
+            },
+            TAG_MAC_ADDRESS => {
+                let len = reader.u32()? as usize;
+                let addr =
+                    std::str::from_utf8(reader.bytes(len)?).map_err(|_| Error::InvalidState)?;
+                Resource::MacAddresss(addr.to_string())
+            }
+            TAG_KVM_MEM_SLOT => Resource::KvmMemSlot(reader.u32()?),
+            _ => return Err(Error::InvalidState),
+        };
+        resources.append(res);
+    }
+    Ok(resources)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    const RANGES: [(u64, u64); 1] = [(0xd000_0000, 0x200)];
+
+    struct DummyDevice {
+        device_type: &'static str,
+        config: Mutex<u8>,
+    }
+
+    impl DummyDevice {
+        fn new(device_type: &'static str, config: u8) -> Self {
+            DummyDevice {
+                device_type,
+                config: Mutex::new(config),
+            }
+        }
+    }
+
+    impl Persist for DummyDevice {
+        fn device_type(&self) -> &str {
+            self.device_type
+        }
+
+        fn save_state(&self) -> Vec<u8> {
+            vec![*self.config.lock().unwrap()]
+        }
+
+        fn restore_state(&self, data: &[u8]) -> Result<(), Error> {
+            match data {
+                [config] => {
+                    *self.config.lock().unwrap() = *config;
+                    Ok(())
+                }
+                _ => Err(Error::InvalidState),
+            }
+        }
+    }
+
+    #[test]
+    fn test_save_restore() {
+        let state = DeviceState::save(&DummyDevice::new("virtio-blk", 5), "blk0", &RANGES);
+        assert_eq!(state.device_type(), "virtio-blk");
+        assert_eq!(state.id(), "blk0");
+        assert_eq!(state.mmio_ranges(), &RANGES);
+        assert_eq!(state.data(), &[5]);
+
+        let device = DummyDevice::new("virtio-blk", 0);
+        state.restore(&device, "blk0", &RANGES).unwrap();
+        assert_eq!(*device.config.lock().unwrap(), 5);
+
+        let state = DeviceState::new("virtio-blk", "blk0", RANGES.to_vec(), vec![1, 2]);
+        assert_eq!(
+            state.restore(&device, "blk0", &RANGES),
+            Err(Error::InvalidState)
+        );
+        assert_eq!(*device.config.lock().unwrap(), 5);
+    }
+
+    #[test]
+    fn test_external_state() {
+        struct TapDevice {
+            tap: Mutex<Option<String>>,
+        }
+
+        impl Persist for TapDevice {
+            fn device_type(&self) -> &str {
+                "virtio-net"
+            }
+
+            fn save_state(&self) -> Vec<u8> {
+                Vec::new()
+            }
+
+            fn restore_state(&self, _data: &[u8]) -> Result<(), Error> {
+                // The backend has to be attached before the state is restored.
+                self.tap
+                    .lock()
+                    .unwrap()
+                    .as_ref()
+                    .ok_or(Error::InvalidState)?;
+                Ok(())
+            }
+
+            fn prepare_external_state(&self) -> Vec<ExternalResource> {
+                let tap = self.tap.lock().unwrap().clone().unwrap();
+                vec![ExternalResource::NetworkInterface(tap)]
+            }
+
+            fn reattach_external_state(&self, resources: &[ExternalResource]) -> Result<(), Error> {
+                match resources {
+                    [ExternalResource::NetworkInterface(tap)] => {
+                        *self.tap.lock().unwrap() = Some(tap.clone());
+                        Ok(())
+                    }
+                    _ => Err(Error::InvalidState),
+                }
+            }
+        }
+
+        let device = TapDevice {
+            tap: Mutex::new(Some("tap0".to_string())),
+        };
+        let state = DeviceState::save(&device, "net0", &RANGES);
+        assert_eq!(
+            state.external(),
+            &[ExternalResource::NetworkInterface("tap0".to_string())]
+        );
+
+        let restored = TapDevice {
+            tap: Mutex::new(None),
+        };
+        state.restore(&restored, "net0", &RANGES).unwrap();
+        assert_eq!(restored.tap.lock().unwrap().as_deref(), Some("tap0"));
+
+        // Devices without external resources use the default implementations.
+        let state = DeviceState::save(&DummyDevice::new("virtio-blk", 1), "blk0", &RANGES)
+            .with_external(vec![ExternalResource::Fd(3)]);
+        assert!(state
+            .restore(&DummyDevice::new("virtio-blk", 0), "blk0", &RANGES)
+            .is_ok());
+
+        // A change in external resources carries the whole state in deltas.
+        let mut base = Snapshot::new();
+        base.insert(DeviceState::new("virtio-blk", "blk0", vec![], vec![0; 8]));
+        let mut next = Snapshot::new();
+        next.insert(
+            DeviceState::new("virtio-blk", "blk0", vec![], vec![0; 8])
+                .with_external(vec![ExternalResource::File(PathBuf::from("/disk.img"))]),
+        );
+        let delta = base.diff(&next);
+        assert_eq!(delta.added.len(), 1);
+        assert_eq!(base.apply(&delta).unwrap(), next);
+    }
+
+    #[test]
+    fn test_rebase() {
+        let device = DummyDevice::new("virtio-blk", 5);
+        let ranges = [(0xd000_0000, 0x200), (0xd000_1000, 0x200)];
+        let mut state = DeviceState::save(&device, "blk0", &ranges);
+
+        let mut rebase = BTreeMap::new();
+        rebase.insert(0xd000_1000, 0xe000_0000);
+        state.rebase(&rebase);
+        assert_eq!(
+            state.mmio_ranges(),
+            &[(0xd000_0000, 0x200), (0xe000_0000, 0x200)]
+        );
+        assert!(state.validate(&device, "blk0", &ranges).is_err());
+        assert!(state.validate(&device, "blk0", state.mmio_ranges()).is_ok());
+    }
+
+    #[test]
+    fn test_identity_mismatch() {
+        let state = DeviceState::save(&DummyDevice::new("virtio-blk", 5), "blk0", &RANGES);
+        let device = DummyDevice::new("virtio-net", 0);
+
+        let err = state.restore(&device, "blk0", &RANGES).unwrap_err();
+        assert_eq!(
+            err,
+            Error::DeviceTypeMismatch {
+                expected: "virtio-net".to_string(),
+                found: "virtio-blk".to_string(),
+            }
+        );
+        assert_eq!(
+            format!("{}", err),
+            "device type mismatch (expected virtio-net, found virtio-blk)"
+        );
+
+        let device = DummyDevice::new("virtio-blk", 0);
+        assert_eq!(
+            state.restore(&device, "blk1", &RANGES),
+            Err(Error::DeviceIdMismatch {
+                expected: "blk1".to_string(),
+                found: "blk0".to_string(),
+            })
+        );
+
+        let ranges = [(0xd000_1000, 0x200)];
+        let err = state.restore(&device, "blk0", &ranges).unwrap_err();
+        assert_eq!(
+            err,
+            Error::RangeMismatch {
+                expected: ranges.to_vec(),
+                found: RANGES.to_vec(),
+            }
+        );
+        assert_eq!(
+            format!("{}", err),
+            "device ranges mismatch (expected [(d0001000, 200)], found [(d0000000, 200)])"
+        );
+
+        // Nothing got restored along the way.
+        assert_eq!(*device.config.lock().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_snapshot_delta() {
+        let mut base = Snapshot::new();
+        base.insert(DeviceState::new(
+            "virtio-blk",
+            "blk0",
+            RANGES.to_vec(),
+            vec![0; 64],
+        ));
+        base.insert(DeviceState::new(
+            "virtio-net",
+            "net0",
+            vec![],
+            vec![1, 2, 3],
+        ));
+        base.insert(DeviceState::new("virtio-rng", "rng0", vec![], vec![4]));
+        assert!(base.diff(&base).is_empty());
+        assert_eq!(base.apply(&Delta::default()).unwrap(), base);
+
+        let mut next = base.clone();
+        let mut data = vec![0; 64];
+        data[10] = 1;
+        data[11] = 2;
+        data[40] = 3;
+        next.insert(DeviceState::new(
+            "virtio-blk",
+            "blk0",
+            RANGES.to_vec(),
+            data,
+        ));
+        // Shrinking and growing the state are both covered by patches.
+        next.insert(DeviceState::new("virtio-net", "net0", vec![], vec![1, 5]));
+        next.insert(DeviceState::new(
+            "virtio-rng",
+            "rng0",
+            vec![],
+            vec![4, 4, 4],
+        ));
+        next.insert(DeviceState::new(
+            "virtio-vsock",
+            "vsock0",
+            vec![],
+            vec![6; 8],
+        ));
+
+        let delta = base.diff(&next);
+        assert!(!delta.is_empty());
+        assert_eq!(delta.patched.len(), 3);
+        assert_eq!(
+            delta.patched[0].chunks,
+            vec![(10, vec![1, 2]), (40, vec![3])]
+        );
+        assert_eq!(delta.added.len(), 1);
+        assert_eq!(delta.data_len(), 3 + 1 + 2 + 8);
+        assert_eq!(base.apply(&delta).unwrap(), next);
+
+        // Devices changing type or being removed.
+        let mut last = next.clone();
+        last.devices.remove("vsock0");
+        last.insert(DeviceState::new("virtio-blk", "net0", vec![], vec![1, 5]));
+        let delta = next.diff(&last);
+        assert_eq!(delta.removed, vec!["vsock0".to_string()]);
+        assert_eq!(delta.added.len(), 1);
+        assert_eq!(next.apply(&delta).unwrap(), last);
+
+        // The delta does not apply on top of an unrelated snapshot.
+        assert_eq!(
+            Snapshot::new().apply(&delta),
+            Err(Error::MissingDevice("vsock0".to_string()))
+        );
+        assert_eq!(
+            Snapshot::new().apply(&base.diff(&next)),
+            Err(Error::MissingDevice("blk0".to_string()))
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_snapshot() {
+        let mut state = DeviceState::new("virtio-blk", "blk0", RANGES.to_vec(), vec![0; 8]);
+        state.external = vec![
+            ExternalResource::File(PathBuf::from("/var/lib/disk.img")),
+            ExternalResource::Fd(3),
+        ];
+        let mut base = Snapshot::new();
+        base.insert(state);
+        let mut next = base.clone();
+        next.insert(DeviceState::new(
+            "virtio-blk",
+            "blk0",
+            RANGES.to_vec(),
+            vec![1; 8],
+        ));
+
+        let json = serde_json::to_string(&base).unwrap();
+        assert_eq!(serde_json::from_str::<Snapshot>(&json).unwrap(), base);
+        let delta = base.diff(&next);
+        let json = serde_json::to_string(&delta).unwrap();
+        assert_eq!(serde_json::from_str::<Delta>(&json).unwrap(), delta);
+    }
+
+    #[test]
+    fn test_save_load_resources() {
+        let mut resources = DeviceResources::new();
+        resources.append(Resource::PioAddressRange {
+            base: 0x3f8,
+            size: 8,
+        });
+        resources.append(Resource::MmioAddressRange {
+            base: 0xd000_0000,
+            size: 0x200,
+        });
+        resources.append(Resource::LegacyIrq(5));
+        resources.append(Resource::MsiIrq {
+            ty: MsiIrqType::PciMsix,
+            base: 24,
+            size: 4,
+        });
+        resources.append(Resource::MacAddresss("00:08:63:66:86:88".to_string()));
+        resources.append(Resource::KvmMemSlot(3));
+
+        let data = save_resources(&resources);
+        let loaded = load_resources(&data).unwrap();
+        assert_eq!(loaded.get_all_resources().len(), 6);
+        assert_eq!(loaded.get_pio_address_ranges(), vec![(0x3f8, 8)]);
+        assert_eq!(loaded.get_mmio_address_ranges(), vec![(0xd000_0000, 0x200)]);
+        assert_eq!(loaded.get_legacy_irq(), Some(5));
+        assert_eq!(loaded.get_pci_msix_irqs(), Some((24, 4)));
+        assert_eq!(loaded.get_mac_address().unwrap(), "00:08:63:66:86:88");
+        assert_eq!(loaded.get_kvm_mem_slots(), vec![3]);
+
+        assert_eq!(load_resources(&[]).unwrap().get_all_resources().len(), 0);
+        // Truncated input and unknown tags are rejected.
+        assert_eq!(
+            load_resources(&data[..data.len() - 1]).err(),
+            Some(Error::InvalidState)
+        );
+        assert_eq!(load_resources(&[0xff]).err(), Some(Error::InvalidState));
+        assert_eq!(
+            load_resources(&[TAG_MSI_IRQ, 3, 0, 0, 0, 0, 0, 0, 0, 0]).err(),
+            Some(Error::InvalidState)
+        );
+    }
+
+    #[test]
+    fn test_check_migration() {
+        let mut source = BTreeMap::new();
+        source.insert(
+            "blk0".to_string(),
+            Features {
+                offered: 0b1111,
+                acked: 0b0011,
+            },
+        );
+        source.insert(
+            "net0".to_string(),
+            Features {
+                offered: 0b0111,
+                acked: 0b0101,
+            },
+        );
+
+        let mut destination = BTreeMap::new();
+        destination.insert("blk0".to_string(), 0b0111);
+        destination.insert("net0".to_string(), 0b0011);
+        destination.insert("rng0".to_string(), 0);
+
+        let report = check_migration(&source, &destination).unwrap();
+        assert_eq!(report.len(), 2);
+
+        let blk = report["blk0"];
+        assert!(blk.is_compatible());
+        assert_eq!(blk.masked, 0b1000);
+
+        let net = report["net0"];
+        assert!(!net.is_compatible());
+        assert_eq!(net.masked, 0b0100);
+        assert_eq!(net.unsupported, 0b0100);
+
+        destination.remove("net0");
+        assert_eq!(
+            check_migration(&source, &destination),
+            Err(Error::MissingDevice("net0".to_string()))
+        );
+    }
+}
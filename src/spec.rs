@@ -0,0 +1,314 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Devices described on the command line.
+//!
+//! A [`DeviceSpec`] is parsed from a QEMU-style device string: the device type, followed by
+//! comma separated `key=value` properties. The `id`, `addr`, `size` and `irq` properties
+//! describe the device and its resources, and the other ones are type specific options.
+//! Commas are written `,,` within values.
+//!
+//! ```
+//! use vm_device::spec::DeviceSpec;
+//!
+//! let spec: DeviceSpec = "virtio-mmio,id=blk0,addr=0xd0000000,size=0x200,irq=5,path=a,,b"
+//!     .parse()
+//!     .unwrap();
+//! assert_eq!(spec.device_type, "virtio-mmio");
+//! assert_eq!(spec.id.as_deref(), Some("blk0"));
+//! assert_eq!(
+//!     spec.resources.get_mmio_address_ranges(),
+//!     vec![(0xd000_0000, 0x200)]
+//! );
+//! assert_eq!(spec.resources.get_legacy_irq(), Some(5));
+//! assert_eq!(spec.options, [("path".to_string(), "a,b".to_string())]);
+//!
+//! let err = "virtio-mmio,addr=0xd000000g,size=0x200"
+//!     .parse::<DeviceSpec>()
+//!     .unwrap_err();
+//! assert_eq!(
+//!     err.to_string(),
+//!     "spec: invalid value for addr in \"addr=0xd000000g\""
+//! );
+//! ```
+//!
+//! The [`descriptor`](DeviceSpec::descriptor) of a spec hands the options to the factory of
+//! a [`DeviceRegistry`](crate::registry::DeviceRegistry) as its configuration blob.
+
+use std::convert::TryFrom;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use crate::device_manager::DeviceDescriptor;
+use crate::resources::{DeviceResources, Resource};
+
+/// Errors encountered while parsing a [`DeviceSpec`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The string doesn't start with a device type.
+    MissingType,
+    /// The token isn't a `key=value` property.
+    InvalidToken(String),
+    /// The property is given more than once.
+    DuplicateKey(String),
+    /// The value of the property, given by the key and the token, is invalid.
+    InvalidValue {
+        /// Key of the property.
+        key: String,
+        /// The whole `key=value` token.
+        token: String,
+    },
+    /// The property is required by another one, e.g. `size` by `addr`.
+    MissingKey(&'static str),
+    /// The MMIO range at the address with the size is empty or overflows.
+    InvalidRange {
+        /// Base address of the range.
+        addr: u64,
+        /// Size of the range.
+        size: u64,
+    },
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::MissingType => write!(f, "spec: missing device type"),
+            Error::InvalidToken(token) => {
+                write!(f, "spec: expected key=value, found {:?}", token)
+            }
+            Error::DuplicateKey(key) => write!(f, "spec: duplicate property {}", key),
+            Error::InvalidValue { key, token } => {
+                write!(f, "spec: invalid value for {} in {:?}", key, token)
+            }
+            Error::MissingKey(key) => write!(f, "spec: missing property {}", key),
+            Error::InvalidRange { addr, size } => {
+                write!(f, "spec: invalid range {:#x}+{:#x}", addr, size)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// A device described by a QEMU-style device string.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DeviceSpec {
+    /// Type of the device.
+    pub device_type: String,
+    /// Name of the device, from the `id` property.
+    pub id: Option<String>,
+    /// Resources of the device: the MMIO range given by the `addr` and `size` properties,
+    /// and the interrupt given by the `irq` property.
+    pub resources: DeviceResources,
+    /// The other properties, in order.
+    pub options: Vec<(String, String)>,
+}
+
+impl DeviceSpec {
+    /// Describe the device, with its options as the configuration blob, in the same
+    /// `key=value` syntax.
+    pub fn descriptor(&self) -> DeviceDescriptor {
+        DeviceDescriptor::new(self.resources.clone())
+            .with_device_type(&self.device_type)
+            .with_config(join(&self.options).into_bytes())
+    }
+
+    /// Parse `key=value` options, as found in the configuration blob of a
+    /// [`descriptor`](DeviceSpec::descriptor).
+    pub fn parse_options(options: &str) -> Result<Vec<(String, String)>, Error> {
+        if options.is_empty() {
+            return Ok(Vec::new());
+        }
+        properties(options)
+    }
+}
+
+impl FromStr for DeviceSpec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let (device_type, properties) = match s.find(',') {
+            Some(idx) => (&s[..idx], properties(&s[idx + 1..])?),
+            None => (s, Vec::new()),
+        };
+        if device_type.is_empty() || device_type.contains('=') {
+            return Err(Error::MissingType);
+        }
+        let mut spec = DeviceSpec {
+            device_type: device_type.to_string(),
+            ..Default::default()
+        };
+        let (mut addr, mut size, mut irq) = (None, None, None);
+        for (key, value) in properties {
+            let token = || format!("{}={}", key, value);
+            let invalid = || Error::InvalidValue {
+                key: key.clone(),
+                token: token(),
+            };
+            match key.as_str() {
+                "id" => spec.id = Some(value.clone()),
+                "addr" => addr = Some(number(&value).ok_or_else(invalid)?),
+                "size" => size = Some(number(&value).ok_or_else(invalid)?),
+                "irq" => {
+                    let value = number(&value).ok_or_else(invalid)?;
+                    irq = Some(u32::try_from(value).map_err(|_| invalid())?);
+                }
+                _ => spec.options.push((key, value)),
+            }
+        }
+        match (addr, size) {
+            (Some(addr), Some(size)) => {
+                if size == 0 || addr.checked_add(size - 1).is_none() {
+                    return Err(Error::InvalidRange { addr, size });
+                }
+                spec.resources
+                    .append(Resource::MmioAddressRange { base: addr, size });
+            }
+            (Some(_), None) => return Err(Error::MissingKey("size")),
+            (None, Some(_)) => return Err(Error::MissingKey("addr")),
+            (None, None) => {}
+        }
+        if let Some(irq) = irq {
+            spec.resources.append(Resource::LegacyIrq(irq));
+        }
+        Ok(spec)
+    }
+}
+
+// Parse the comma separated `key=value` properties of `s`.
+fn properties(s: &str) -> Result<Vec<(String, String)>, Error> {
+    let mut parsed: Vec<(String, String)> = Vec::new();
+    for token in split(s) {
+        let (key, value) = property(&token)?;
+        if parsed.iter().any(|(k, _)| *k == key) {
+            return Err(Error::DuplicateKey(key.to_string()));
+        }
+        parsed.push((key.to_string(), value.to_string()));
+    }
+    Ok(parsed)
+}
+
+// Split `s` at the commas, except the doubled ones which are unescaped.
+fn split(s: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            ',' if chars.peek() == Some(&',') => {
+                chars.next();
+                token.push(',');
+            }
+            ',' => tokens.push(std::mem::take(&mut token)),
+            c => token.push(c),
+        }
+    }
+    tokens.push(token);
+    tokens
+}
+
+// Join `options` into `key=value` tokens, escaping the commas of the values.
+fn join(options: &[(String, String)]) -> String {
+    let tokens: Vec<String> = options
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value.replace(',', ",,")))
+        .collect();
+    tokens.join(",")
+}
+
+// Split a `key=value` token.
+fn property(token: &str) -> Result<(&str, &str), Error> {
+    match token.find('=') {
+        Some(idx) if idx > 0 => Ok((&token[..idx], &token[idx + 1..])),
+        _ => Err(Error::InvalidToken(token.to_string())),
+    }
+}
+
+// Parse a decimal or `0x` prefixed hexadecimal number, optionally followed by a `K`, `M`
+// or `G` binary multiplier.
+fn number(value: &str) -> Option<u64> {
+    let (value, shift) = match value.char_indices().last()? {
+        (idx, 'k') | (idx, 'K') => (&value[..idx], 10),
+        (idx, 'M') => (&value[..idx], 20),
+        (idx, 'G') => (&value[..idx], 30),
+        _ => (value, 0),
+    };
+    let number = match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+        None => value.parse().ok()?,
+    };
+    number.checked_mul(1 << shift)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device_spec() {
+        let spec: DeviceSpec = "virtio-blk,size=4K,path=/img,,1,addr=4096,ro=on"
+            .parse()
+            .unwrap();
+        assert_eq!(spec.device_type, "virtio-blk");
+        assert_eq!(spec.id, None);
+        assert_eq!(
+            spec.resources.get_mmio_address_ranges(),
+            vec![(0x1000, 0x1000)]
+        );
+        assert_eq!(spec.resources.get_legacy_irq(), None);
+        let descriptor = spec.descriptor();
+        assert_eq!(descriptor.device_type(), Some("virtio-blk"));
+        assert_eq!(descriptor.config(), b"path=/img,,1,ro=on");
+        assert_eq!(
+            DeviceSpec::parse_options("path=/img,,1,ro=on").unwrap(),
+            spec.options
+        );
+
+        let spec: DeviceSpec = "pl011".parse().unwrap();
+        assert!(spec.resources.get_all_resources().is_empty());
+
+        for (s, err) in [
+            ("", Error::MissingType),
+            ("id=x", Error::MissingType),
+            ("pl011,", Error::InvalidToken(String::new())),
+            ("pl011,ro", Error::InvalidToken("ro".to_string())),
+            ("pl011,=1", Error::InvalidToken("=1".to_string())),
+            ("pl011,id=a,id=b", Error::DuplicateKey("id".to_string())),
+            (
+                "pl011,irq=0x1_0000_0000",
+                Error::InvalidValue {
+                    key: "irq".to_string(),
+                    token: "irq=0x1_0000_0000".to_string(),
+                },
+            ),
+            (
+                "pl011,irq=4294967296",
+                Error::InvalidValue {
+                    key: "irq".to_string(),
+                    token: "irq=4294967296".to_string(),
+                },
+            ),
+            (
+                "pl011,size=16G,addr=0xffffffff00000000",
+                Error::InvalidRange {
+                    addr: 0xffff_ffff_0000_0000,
+                    size: 0x4_0000_0000,
+                },
+            ),
+            (
+                "pl011,addr=0x1000,size=0",
+                Error::InvalidRange {
+                    addr: 0x1000,
+                    size: 0,
+                },
+            ),
+            ("pl011,addr=0x1000", Error::MissingKey("size")),
+            ("pl011,size=0x1000", Error::MissingKey("addr")),
+        ] {
+            assert_eq!(s.parse::<DeviceSpec>(), Err(err), "{}", s);
+        }
+    }
+}
@@ -0,0 +1,334 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Statistics of the devices hosted by a manager.
+//!
+//! Devices keep their statistics behind [`DeviceStats`], and hand a [`Statistics`] snapshot
+//! of them to the manager through the `stats` method of the device traits. The VMM then
+//! collects the statistics of all the registered devices at once with
+//! `IoManager::collect_stats`, in a [`StatsReport`] keyed by device name, e.g. to feed a
+//! dashboard the same way for every device model.
+//!
+//! ```
+//! # use std::sync::atomic::{AtomicU64, Ordering};
+//! # use std::sync::Arc;
+//! use vm_device::bus::{MmioAddress, MmioAddressOffset, MmioRange};
+//! use vm_device::device_manager::{IoManager, MmioManager};
+//! use vm_device::info::{DeviceDetails, DeviceInfo};
+//! use vm_device::stats::{DeviceStats, Statistics};
+//! use vm_device::DeviceMmio;
+//!
+//! #[derive(Default)]
+//! struct Blk {
+//!     requests: AtomicU64,
+//! }
+//!
+//! impl DeviceInfo for Blk {
+//!     fn name(&self) -> String {
+//!         "blk0".to_string()
+//!     }
+//!
+//!     fn device_type(&self) -> String {
+//!         "virtio-blk".to_string()
+//!     }
+//! }
+//!
+//! impl DeviceStats for Blk {
+//!     fn ops(&self) -> u64 {
+//!         self.requests.load(Ordering::Relaxed)
+//!     }
+//!
+//!     fn bytes(&self) -> u64 {
+//!         self.ops() * 512
+//!     }
+//! }
+//!
+//! impl DeviceMmio for Blk {
+//!     fn mmio_read(&self, _: MmioAddress, _: MmioAddressOffset, _: &mut [u8]) {}
+//!
+//!     fn mmio_write(&self, _: MmioAddress, _: MmioAddressOffset, _: &[u8]) {
+//!         self.requests.fetch_add(1, Ordering::Relaxed);
+//!     }
+//!
+//!     fn info(&self) -> Option<DeviceDetails> {
+//!         Some(DeviceDetails::of(self))
+//!     }
+//!
+//!     fn stats(&self) -> Option<Statistics> {
+//!         Some(Statistics::of(self))
+//!     }
+//! }
+//!
+//! let mut manager = IoManager::new();
+//! let range = MmioRange::new(MmioAddress(0x1000), 0x200).unwrap();
+//! manager.register_mmio(range, Arc::new(Blk::default())).unwrap();
+//! manager.mmio_write(MmioAddress(0x1050), &[0; 4]).unwrap();
+//!
+//! let report = manager.collect_stats();
+//! assert_eq!(report.devices["blk0"].bytes, 512);
+//! ```
+
+use alloc::vec::Vec;
+
+#[cfg(feature = "std")]
+use std::collections::BTreeMap;
+#[cfg(feature = "std")]
+use std::sync::Arc;
+
+#[cfg(feature = "std")]
+use crate::bus::{Bus, BusAddress, BusManager, MmioAddress, PioAddress};
+#[cfg(feature = "std")]
+use crate::device_manager::{group_ranges, IoManager};
+#[cfg(feature = "std")]
+use crate::info::DeviceDetails;
+
+/// Provides the statistics of a device.
+pub trait DeviceStats {
+    /// Number of operations handled by the device, e.g. accesses or requests.
+    fn ops(&self) -> u64;
+
+    /// Number of bytes transferred by the device.
+    fn bytes(&self) -> u64;
+
+    /// Current depth of each queue of the device.
+    ///
+    /// The default implementation returns no queues.
+    fn queue_depths(&self) -> Vec<u64> {
+        Vec::new()
+    }
+
+    /// Number of errors encountered by the device.
+    ///
+    /// The default implementation returns 0.
+    fn errors(&self) -> u64 {
+        0
+    }
+}
+
+/// A snapshot of the [`DeviceStats`] of a device.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Statistics {
+    /// Number of operations handled by the device.
+    pub ops: u64,
+    /// Number of bytes transferred by the device.
+    pub bytes: u64,
+    /// Depth of each queue of the device.
+    pub queue_depths: Vec<u64>,
+    /// Number of errors encountered by the device.
+    pub errors: u64,
+}
+
+impl Statistics {
+    /// Take a snapshot of `stats`.
+    pub fn of<S: DeviceStats + ?Sized>(stats: &S) -> Self {
+        Statistics {
+            ops: stats.ops(),
+            bytes: stats.bytes(),
+            queue_depths: stats.queue_depths(),
+            errors: stats.errors(),
+        }
+    }
+}
+
+impl DeviceStats for Statistics {
+    fn ops(&self) -> u64 {
+        self.ops
+    }
+
+    fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    fn queue_depths(&self) -> Vec<u64> {
+        self.queue_depths.clone()
+    }
+
+    fn errors(&self) -> u64 {
+        self.errors
+    }
+}
+
+/// Statistics of all the devices registered with an [`IoManager`] reporting some.
+#[cfg(feature = "std")]
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StatsReport {
+    /// Statistics of the devices, keyed by the name from their details (see
+    /// [`info`](crate::info)). Devices without details are keyed by their bus and the
+    /// base address of their first range, e.g. `mmio:0x1000`, and so are the devices whose
+    /// name is taken, after it, e.g. `uart@mmio:0x1000`.
+    pub devices: BTreeMap<String, Statistics>,
+}
+
+#[cfg(feature = "std")]
+impl StatsReport {
+    /// Return the sum of the operations, bytes and errors of all the devices. Queue depths
+    /// aren't summed.
+    pub fn totals(&self) -> Statistics {
+        let mut totals = Statistics::default();
+        for stats in self.devices.values() {
+            totals.ops += stats.ops;
+            totals.bytes += stats.bytes;
+            totals.errors += stats.errors;
+        }
+        totals
+    }
+
+    fn insert(&mut self, details: Option<DeviceDetails>, address: String, stats: Statistics) {
+        let key = match details {
+            Some(details) if self.devices.contains_key(&details.name) => {
+                format!("{}@{}", details.name, address)
+            }
+            Some(details) => details.name,
+            None => address,
+        };
+        self.devices.insert(key, stats);
+    }
+}
+
+// Add the statistics the devices of `bus` report through `stats` to `report`.
+#[cfg(feature = "std")]
+fn collect<A, D, F>(report: &mut StatsReport, bus: &Bus<A, Arc<D>>, name: &str, stats: F)
+where
+    A: BusAddress,
+    A::V: Into<u64>,
+    D: ?Sized,
+    F: Fn(&D) -> Option<(Option<DeviceDetails>, Statistics)>,
+{
+    for (device, ranges) in group_ranges(bus) {
+        if let Some((details, stats)) = stats(device) {
+            let base: u64 = ranges[0].base().value().into();
+            report.insert(details, format!("{}:{:#x}", name, base), stats);
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl IoManager {
+    /// Collect the statistics of all the registered devices reporting some (see the
+    /// `stats` method of the device traits), PIO devices first. Devices registered with
+    /// several ranges are asked once.
+    pub fn collect_stats(&self) -> StatsReport {
+        let mut report = StatsReport::default();
+        collect(
+            &mut report,
+            BusManager::<PioAddress>::bus(self),
+            "pio",
+            |device| Some((device.info(), device.stats()?)),
+        );
+        collect(
+            &mut report,
+            BusManager::<MmioAddress>::bus(self),
+            "mmio",
+            |device| Some((device.info(), device.stats()?)),
+        );
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::bus::{MmioAddressOffset, PioAddressOffset};
+    use crate::resources::Resource;
+    use crate::testing::Scratchpad;
+    use crate::{DeviceMmio, DevicePio};
+
+    // Reports fixed statistics, and optionally its name.
+    struct Fixed(Option<&'static str>);
+
+    impl DeviceStats for Fixed {
+        fn ops(&self) -> u64 {
+            2
+        }
+
+        fn bytes(&self) -> u64 {
+            8
+        }
+
+        fn queue_depths(&self) -> Vec<u64> {
+            vec![1, 0]
+        }
+    }
+
+    impl DeviceMmio for Fixed {
+        fn mmio_read(&self, _base: MmioAddress, _offset: MmioAddressOffset, _data: &mut [u8]) {}
+
+        fn mmio_write(&self, _base: MmioAddress, _offset: MmioAddressOffset, _data: &[u8]) {}
+
+        fn info(&self) -> Option<DeviceDetails> {
+            self.0.map(|name| DeviceDetails {
+                name: name.to_string(),
+                ..Default::default()
+            })
+        }
+
+        fn stats(&self) -> Option<Statistics> {
+            Some(Statistics::of(self))
+        }
+    }
+
+    impl DevicePio for Fixed {
+        fn pio_read(&self, _base: PioAddress, _offset: PioAddressOffset, _data: &mut [u8]) {}
+
+        fn pio_write(&self, _base: PioAddress, _offset: PioAddressOffset, _data: &[u8]) {}
+
+        fn stats(&self) -> Option<Statistics> {
+            DeviceMmio::stats(self)
+        }
+    }
+
+    fn mmio(base: u64) -> Resource {
+        Resource::MmioAddressRange { base, size: 0x10 }
+    }
+
+    #[test]
+    fn test_collect_stats() {
+        let mut manager = IoManager::new();
+        let uart = Arc::new(Fixed(Some("uart")));
+        manager
+            .register_mmio_resources(uart, &[mmio(0x1000), mmio(0x2000)])
+            .unwrap();
+        let other = Arc::new(Fixed(Some("uart")));
+        manager
+            .register_mmio_resources(other, &[mmio(0x3000)])
+            .unwrap();
+        manager
+            .register_mmio_resources(Arc::new(Scratchpad::new(0x10)), &[mmio(0x4000)])
+            .unwrap();
+        let port = Arc::new(Fixed(None));
+        manager
+            .register_pio_resources(
+                port,
+                &[Resource::PioAddressRange {
+                    base: 0x60,
+                    size: 4,
+                }],
+            )
+            .unwrap();
+
+        let report = manager.collect_stats();
+        let keys: Vec<_> = report.devices.keys().map(String::as_str).collect();
+        assert_eq!(keys, ["pio:0x60", "uart", "uart@mmio:0x3000"]);
+        assert_eq!(
+            report.devices["uart"],
+            Statistics {
+                ops: 2,
+                bytes: 8,
+                queue_depths: vec![1, 0],
+                errors: 0,
+            }
+        );
+        assert_eq!(
+            report.totals(),
+            Statistics {
+                ops: 6,
+                bytes: 24,
+                queue_depths: Vec::new(),
+                errors: 0,
+            }
+        );
+    }
+}
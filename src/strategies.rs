@@ -0,0 +1,148 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! [`proptest`] strategies for the core types of the crate.
+//!
+//! This module is available with the `proptest` feature. The generated values uphold the
+//! invariants enforced by the crate (e.g. ranges are never empty and never overflow), while
+//! still reaching the edges of the address spaces.
+//!
+//! # Example
+//!
+//! ```
+//! use proptest::prelude::*;
+//! use vm_device::strategies;
+//!
+//! proptest!(|((range, (addr, len)) in strategies::mmio_range()
+//!     .prop_flat_map(|range| (Just(range), strategies::mmio_access(range))))| {
+//!     prop_assert!(range.base() <= addr);
+//!     prop_assert!(addr.0 + (len as u64 - 1) <= range.last().0);
+//! });
+//! ```
+
+use proptest::prelude::*;
+
+use crate::bus::{MmioAddress, MmioRange, PioAddress, PioRange};
+use crate::resources::{DeviceResources, MsiIrqType, Resource};
+
+/// The largest access generated by [`mmio_access`] and [`pio_access`].
+pub const MAX_ACCESS_LEN: usize = 8;
+
+/// Generate MMIO addresses.
+pub fn mmio_address() -> impl Strategy<Value = MmioAddress> {
+    any::<u64>().prop_map(MmioAddress)
+}
+
+/// Generate PIO addresses.
+pub fn pio_address() -> impl Strategy<Value = PioAddress> {
+    any::<u16>().prop_map(PioAddress)
+}
+
+/// Generate valid MMIO ranges.
+pub fn mmio_range() -> impl Strategy<Value = MmioRange> {
+    (1..=u64::MAX)
+        .prop_flat_map(|size| (0..=u64::MAX - (size - 1), Just(size)))
+        .prop_map(|(base, size)| MmioRange::new(MmioAddress(base), size).unwrap())
+}
+
+/// Generate valid PIO ranges.
+pub fn pio_range() -> impl Strategy<Value = PioRange> {
+    (1..=u16::MAX)
+        .prop_flat_map(|size| (0..=u16::MAX - (size - 1), Just(size)))
+        .prop_map(|(base, size)| PioRange::new(PioAddress(base), size).unwrap())
+}
+
+/// Generate the address and length of accesses that fall entirely within `range`.
+pub fn mmio_access(range: MmioRange) -> impl Strategy<Value = (MmioAddress, usize)> {
+    let max_len = std::cmp::min(range.size(), MAX_ACCESS_LEN as u64) as usize;
+    (1..=max_len).prop_flat_map(move |len| {
+        (range.base().0..=range.last().0 - (len as u64 - 1))
+            .prop_map(move |addr| (MmioAddress(addr), len))
+    })
+}
+
+/// Generate the address and length of accesses that fall entirely within `range`.
+pub fn pio_access(range: PioRange) -> impl Strategy<Value = (PioAddress, usize)> {
+    let max_len = std::cmp::min(range.size(), MAX_ACCESS_LEN as u16) as usize;
+    (1..=max_len).prop_flat_map(move |len| {
+        (range.base().0..=range.last().0 - (len as u16 - 1))
+            .prop_map(move |addr| (PioAddress(addr), len))
+    })
+}
+
+/// Generate device resources.
+pub fn resource() -> impl Strategy<Value = Resource> {
+    prop_oneof![
+        pio_range().prop_map(|range| Resource::PioAddressRange {
+            base: range.base().0,
+            size: range.size(),
+        }),
+        mmio_range().prop_map(|range| Resource::MmioAddressRange {
+            base: range.base().0,
+            size: range.size(),
+        }),
+        any::<u32>().prop_map(Resource::LegacyIrq),
+        (
+            prop_oneof![
+                Just(MsiIrqType::PciMsi),
+                Just(MsiIrqType::PciMsix),
+                Just(MsiIrqType::GenericMsi),
+            ],
+            (1..=u32::MAX).prop_flat_map(|size| (0..=u32::MAX - (size - 1), Just(size))),
+        )
+            .prop_map(|(ty, (base, size))| Resource::MsiIrq { ty, base, size }),
+        any::<[u8; 6]>().prop_map(|mac| {
+            Resource::MacAddresss(
+                mac.iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect::<Vec<_>>()
+                    .join(":"),
+            )
+        }),
+        any::<u32>().prop_map(Resource::KvmMemSlot),
+    ]
+}
+
+/// Generate sets of up to `max_len` device resources.
+pub fn device_resources(max_len: usize) -> impl Strategy<Value = DeviceResources> {
+    proptest::collection::vec(resource(), 0..=max_len).prop_map(|resources| {
+        let mut res = DeviceResources::new();
+        for resource in resources {
+            res.append(resource);
+        }
+        res
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    proptest! {
+        #[test]
+        fn test_pio_access_in_range(
+            (range, (addr, len)) in pio_range()
+                .prop_flat_map(|range| (Just(range), pio_access(range)))
+        ) {
+            prop_assert!(range.base() <= addr);
+            prop_assert!(addr.0.checked_add(len as u16 - 1).unwrap() <= range.last().0);
+        }
+
+        #[test]
+        fn test_resource_is_valid(resource in resource()) {
+            match resource {
+                Resource::PioAddressRange { base, size } => {
+                    prop_assert!(PioRange::new(PioAddress(base), size).is_ok());
+                }
+                Resource::MmioAddressRange { base, size } => {
+                    prop_assert!(MmioRange::new(MmioAddress(base), size).is_ok());
+                }
+                Resource::MsiIrq { base, size, .. } => {
+                    prop_assert!(size > 0 && base.checked_add(size - 1).is_some());
+                }
+                Resource::MacAddresss(mac) => prop_assert_eq!(mac.len(), 17),
+                _ => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,322 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Adapters exposing legacy platform devices, such as the `vm-superio` serial console and
+//! RTC, on the buses of an [`IoManager`](crate::device_manager::IoManager).
+//!
+//! The devices of `vm-superio` only know about their register offsets. [`SerialAdapter`]
+//! maps the 8 bit registers of a UART on the PIO bus (e.g. at `0x3f8` on x86) or on the MMIO
+//! bus, where registers may be spread out, as described by the `reg-shift` property of the
+//! device tree. [`RtcAdapter`] maps the 32 bit registers of a PL031 RTC on the MMIO bus. The
+//! devices plug in through the [`SerialRegisters`] and [`RtcRegisters`] traits, implemented
+//! by a thin wrapper:
+//!
+//! ```ignore
+//! struct Uart(vm_superio::Serial<Trigger, NoEvents, Stdout>);
+//!
+//! impl SerialRegisters for Uart {
+//!     fn read(&mut self, offset: u8) -> u8 {
+//!         self.0.read(offset)
+//!     }
+//!
+//!     fn write(&mut self, offset: u8, value: u8) {
+//!         // Output errors are dropped, as with a disconnected serial line.
+//!         let _ = self.0.write(offset, value);
+//!     }
+//! }
+//!
+//! let serial = Arc::new(Mutex::new(SerialAdapter::new(Uart(serial))));
+//! manager.register_pio(PioRange::new(PioAddress(0x3f8), 8)?, serial)?;
+//! ```
+//!
+//! Since the adapters implement [`MutDevicePio`] and [`MutDeviceMmio`], the legacy devices
+//! share the manager with the virtio transports.
+
+use std::convert::TryFrom;
+
+use crate::bus::{MmioAddress, MmioAddressOffset, PioAddress, PioAddressOffset};
+use crate::info::DeviceDetails;
+use crate::{MutDeviceMmio, MutDevicePio};
+
+/// A device with 8 bit registers, such as `vm_superio::Serial`.
+pub trait SerialRegisters: Send {
+    /// Read the register at `offset`.
+    fn read(&mut self, offset: u8) -> u8;
+
+    /// Write `value` to the register at `offset`.
+    fn write(&mut self, offset: u8, value: u8);
+}
+
+/// A device with 32 bit registers, such as `vm_superio::Rtc`.
+pub trait RtcRegisters: Send {
+    /// Read the register at `offset`, which is 4 bytes aligned.
+    fn read(&mut self, offset: u16, data: &mut [u8; 4]);
+
+    /// Write the register at `offset`, which is 4 bytes aligned.
+    fn write(&mut self, offset: u16, data: &[u8; 4]);
+}
+
+/// Exposes a device with 8 bit registers on the PIO and MMIO buses.
+///
+/// Each access reaches a single register, through the first byte of the data: the other
+/// bytes of a wider access are ignored, or read as zeros.
+pub struct SerialAdapter<T> {
+    device: T,
+    shift: u8,
+}
+
+impl<T: SerialRegisters> SerialAdapter<T> {
+    /// Expose `device` with contiguous registers.
+    pub fn new(device: T) -> Self {
+        SerialAdapter { device, shift: 0 }
+    }
+
+    /// Space the registers `1 << shift` bytes apart on the MMIO bus, e.g. 2 for UARTs with
+    /// 32 bit registers. The registers stay contiguous on the PIO bus.
+    pub fn with_reg_shift(mut self, shift: u8) -> Self {
+        self.shift = shift;
+        self
+    }
+
+    /// Return the device.
+    pub fn device(&self) -> &T {
+        &self.device
+    }
+
+    /// Return the device, e.g. to feed it input.
+    pub fn device_mut(&mut self) -> &mut T {
+        &mut self.device
+    }
+
+    fn read(&mut self, register: Option<u8>, data: &mut [u8]) {
+        data.fill(0);
+        if let (Some(register), Some(byte)) = (register, data.first_mut()) {
+            *byte = self.device.read(register);
+        }
+    }
+
+    fn write(&mut self, register: Option<u8>, data: &[u8]) {
+        if let (Some(register), Some(byte)) = (register, data.first()) {
+            self.device.write(register, *byte);
+        }
+    }
+
+    // Return the register at `offset` on the MMIO bus, if any.
+    fn mmio_register(&self, offset: u64) -> Option<u8> {
+        let shift = u32::from(self.shift.min(63));
+        match offset & ((1 << shift) - 1) {
+            0 => u8::try_from(offset >> shift).ok(),
+            _ => None,
+        }
+    }
+}
+
+impl<T: SerialRegisters> MutDevicePio for SerialAdapter<T> {
+    fn pio_read(&mut self, _base: PioAddress, offset: PioAddressOffset, data: &mut [u8]) {
+        self.read(u8::try_from(offset).ok(), data);
+    }
+
+    fn pio_write(&mut self, _base: PioAddress, offset: PioAddressOffset, data: &[u8]) {
+        self.write(u8::try_from(offset).ok(), data);
+    }
+
+    fn introspect(&self) -> Vec<(String, String)> {
+        vec![("type".to_string(), "serial".to_string())]
+    }
+
+    fn info(&self) -> Option<DeviceDetails> {
+        Some(DeviceDetails {
+            name: "serial".to_string(),
+            device_type: "serial".to_string(),
+            ..Default::default()
+        })
+    }
+}
+
+impl<T: SerialRegisters> MutDeviceMmio for SerialAdapter<T> {
+    fn mmio_read(&mut self, _base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+        self.read(self.mmio_register(offset), data);
+    }
+
+    fn mmio_write(&mut self, _base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+        self.write(self.mmio_register(offset), data);
+    }
+
+    fn introspect(&self) -> Vec<(String, String)> {
+        vec![("type".to_string(), "serial".to_string())]
+    }
+
+    fn info(&self) -> Option<DeviceDetails> {
+        Some(DeviceDetails {
+            name: "serial".to_string(),
+            device_type: "serial".to_string(),
+            ..Default::default()
+        })
+    }
+}
+
+/// Exposes a device with 32 bit registers on the MMIO bus.
+///
+/// Only aligned 32 bit accesses reach the device: other reads return zeros, and other writes
+/// are ignored.
+pub struct RtcAdapter<T> {
+    device: T,
+}
+
+impl<T: RtcRegisters> RtcAdapter<T> {
+    /// Expose `device`.
+    pub fn new(device: T) -> Self {
+        RtcAdapter { device }
+    }
+
+    /// Return the device.
+    pub fn device(&self) -> &T {
+        &self.device
+    }
+
+    /// Return the device.
+    pub fn device_mut(&mut self) -> &mut T {
+        &mut self.device
+    }
+}
+
+// Return the register at `offset` for a 32 bit access of `len` bytes, if any.
+fn word_register(offset: u64, len: usize) -> Option<u16> {
+    match (offset % 4, len) {
+        (0, 4) => u16::try_from(offset).ok(),
+        _ => None,
+    }
+}
+
+impl<T: RtcRegisters> MutDeviceMmio for RtcAdapter<T> {
+    fn mmio_read(&mut self, _base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+        data.fill(0);
+        if let (Some(register), Ok(word)) = (
+            word_register(offset, data.len()),
+            <&mut [u8; 4]>::try_from(&mut *data),
+        ) {
+            self.device.read(register, word);
+        }
+    }
+
+    fn mmio_write(&mut self, _base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+        if let (Some(register), Ok(word)) = (
+            word_register(offset, data.len()),
+            <&[u8; 4]>::try_from(data),
+        ) {
+            self.device.write(register, word);
+        }
+    }
+
+    fn introspect(&self) -> Vec<(String, String)> {
+        vec![("type".to_string(), "rtc".to_string())]
+    }
+
+    fn info(&self) -> Option<DeviceDetails> {
+        Some(DeviceDetails {
+            name: "rtc".to_string(),
+            device_type: "rtc".to_string(),
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    use crate::bus::{MmioRange, PioRange};
+    use crate::device_manager::{IoManager, MmioManager, PioManager};
+
+    // UART recording the register accesses.
+    #[derive(Default)]
+    struct Uart {
+        registers: [u8; 8],
+    }
+
+    impl SerialRegisters for Uart {
+        fn read(&mut self, offset: u8) -> u8 {
+            self.registers
+                .get(usize::from(offset))
+                .copied()
+                .unwrap_or(0)
+        }
+
+        fn write(&mut self, offset: u8, value: u8) {
+            if let Some(register) = self.registers.get_mut(usize::from(offset)) {
+                *register = value;
+            }
+        }
+    }
+
+    #[derive(Default)]
+    struct Rtc {
+        load: u32,
+    }
+
+    impl RtcRegisters for Rtc {
+        fn read(&mut self, offset: u16, data: &mut [u8; 4]) {
+            if offset == 0x8 {
+                *data = self.load.to_le_bytes();
+            }
+        }
+
+        fn write(&mut self, offset: u16, data: &[u8; 4]) {
+            if offset == 0x8 {
+                self.load = u32::from_le_bytes(*data);
+            }
+        }
+    }
+
+    #[test]
+    fn test_superio_adapters() {
+        let mut manager = IoManager::new();
+        let pio = Arc::new(Mutex::new(SerialAdapter::new(Uart::default())));
+        let range = PioRange::new(PioAddress(0x3f8), 8).unwrap();
+        manager.register_pio(range, pio.clone()).unwrap();
+        let mmio = Arc::new(Mutex::new(
+            SerialAdapter::new(Uart::default()).with_reg_shift(2),
+        ));
+        let range = MmioRange::new(MmioAddress(0x1000), 0x20).unwrap();
+        manager.register_mmio(range, mmio.clone()).unwrap();
+        let rtc = Arc::new(Mutex::new(RtcAdapter::new(Rtc::default())));
+        let range = MmioRange::new(MmioAddress(0x2000), 0x1000).unwrap();
+        manager.register_mmio(range, rtc.clone()).unwrap();
+
+        manager.pio_write(PioAddress(0x3fb), &[0x80]).unwrap();
+        assert_eq!(pio.lock().unwrap().device().registers[3], 0x80);
+        let mut data = [0xff; 2];
+        manager.pio_read(PioAddress(0x3fb), &mut data).unwrap();
+        assert_eq!(data, [0x80, 0]);
+
+        // Registers are 4 bytes apart, and the bytes in between don't reach the device.
+        manager
+            .mmio_write(MmioAddress(0x100c), &[3, 0, 0, 0])
+            .unwrap();
+        manager.mmio_write(MmioAddress(0x100d), &[7]).unwrap();
+        assert_eq!(mmio.lock().unwrap().device().registers[3], 3);
+        let mut data = [0xff; 4];
+        manager.mmio_read(MmioAddress(0x100c), &mut data).unwrap();
+        assert_eq!(data, [3, 0, 0, 0]);
+        manager.mmio_read(MmioAddress(0x100e), &mut data).unwrap();
+        assert_eq!(data, [0; 4]);
+
+        // Only aligned 32 bit accesses reach the RTC.
+        manager
+            .mmio_write(MmioAddress(0x2008), &0x1234_5678u32.to_le_bytes())
+            .unwrap();
+        manager.mmio_write(MmioAddress(0x2008), &[0; 2]).unwrap();
+        assert_eq!(rtc.lock().unwrap().device().load, 0x1234_5678);
+        manager.mmio_read(MmioAddress(0x2008), &mut data).unwrap();
+        assert_eq!(u32::from_le_bytes(data), 0x1234_5678);
+        let mut data = [0xff; 2];
+        manager.mmio_read(MmioAddress(0x2008), &mut data).unwrap();
+        assert_eq!(data, [0; 2]);
+
+        let manifest = manager.export_manifest();
+        assert_eq!(manifest.pio[0].kind.as_deref(), Some("serial"));
+        assert_eq!(manifest.mmio[1].kind.as_deref(), Some("rtc"));
+    }
+}
@@ -0,0 +1,77 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Synchronization primitives used internally by the crate.
+//!
+//! The bus and manager paths shared between threads lock through this module instead of
+//! `std::sync`. Building with `RUSTFLAGS="--cfg loom"` swaps the primitives for their
+//! [`loom`](https://docs.rs/loom) counterparts, so the tests under `cfg(loom)` explore every
+//! interleaving of those paths:
+//!
+//! ```text
+//! RUSTFLAGS="--cfg loom" cargo test --release --lib loom
+//! ```
+//!
+//! Without the `std` feature, the locks come from [`spin`](https://docs.rs/spin) instead.
+//!
+//! Types that are part of the public API (e.g. the `Arc` holding devices registered with an
+//! `IoManager`) keep using `std::sync`.
+
+#[cfg(not(any(feature = "std", feature = "spin")))]
+compile_error!("building without the `std` feature requires the `spin` feature");
+
+#[cfg(loom)]
+pub(crate) use loom::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+#[cfg(all(not(loom), feature = "std"))]
+pub(crate) use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+#[cfg(all(not(loom), not(feature = "std")))]
+pub(crate) use alloc::sync::Arc;
+#[cfg(all(not(loom), not(feature = "std"), feature = "spin"))]
+pub(crate) use spin::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+// Stand-ins keeping the crate type checking without a lock implementation, so that the
+// `compile_error!` above is the only error reported.
+#[cfg(all(not(loom), not(feature = "std"), not(feature = "spin")))]
+pub(crate) use core::cell::{
+    Ref as RwLockReadGuard, RefCell as RwLock, RefMut as RwLockWriteGuard,
+};
+
+// Lock `lock` for reading. Poisoning is ignored, the callers only use locks protecting no
+// data. Loom reports poisoning through the `std` types.
+#[cfg(any(loom, feature = "std"))]
+#[inline]
+pub(crate) fn read<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+// Lock `lock` for writing, ignoring poisoning.
+#[cfg(any(loom, feature = "std"))]
+pub(crate) fn write<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+// Lock `lock` for reading.
+#[cfg(all(not(any(loom, feature = "std")), feature = "spin"))]
+#[inline]
+pub(crate) fn read<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read()
+}
+
+// Lock `lock` for writing.
+#[cfg(all(not(any(loom, feature = "std")), feature = "spin"))]
+pub(crate) fn write<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write()
+}
+
+// Stand-ins for the functions above, without a lock implementation.
+#[cfg(not(any(loom, feature = "std", feature = "spin")))]
+pub(crate) fn read<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.borrow()
+}
+
+#[cfg(not(any(loom, feature = "std", feature = "spin")))]
+pub(crate) fn write<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.borrow_mut()
+}
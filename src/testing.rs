@@ -0,0 +1,1384 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Utilities for testing code built on top of the device traits and managers.
+//!
+//! This module is available with the `test-utils` feature.
+//!
+//! # Example
+//!
+//! ```
+//! # use std::sync::Arc;
+//! # use vm_device::bus::{MmioAddress, MmioRange};
+//! # use vm_device::device_manager::{IoManager, MmioManager};
+//! use vm_device::testing::{Direction, MockDevice};
+//!
+//! let device = Arc::new(MockDevice::new());
+//! device.push_read_response(0x10, &[0xaa, 0xbb]);
+//!
+//! let mut manager = IoManager::new();
+//! let range = MmioRange::new(MmioAddress(0x1000), 0x100).unwrap();
+//! manager.register_mmio(range, device.clone()).unwrap();
+//!
+//! let mut data = [0; 2];
+//! manager.mmio_read(MmioAddress(0x1010), &mut data).unwrap();
+//! assert_eq!(data, [0xaa, 0xbb]);
+//! manager.mmio_write(MmioAddress(0x1020), &[1, 2, 3, 4]).unwrap();
+//!
+//! device.expect_read(0x10, 2);
+//! device.expect_write(0x20, &[1, 2, 3, 4]);
+//! assert_eq!(device.accesses()[1].direction, Direction::Write);
+//! ```
+//!
+//! The [`Scratchpad`] device is a minimal, RAM-backed reference device, and
+//! [`TestVmBuilder`] sets up a manager with a number of device slots laid out like in a
+//! typical microVM. [`MockInterruptController`] records the interrupts devices trigger, and
+//! [`stress`] hammers a shared manager from multiple threads.
+
+use std::any::Any;
+use std::collections::{BTreeMap, VecDeque};
+use std::convert::TryFrom;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::bus::{MmioAddress, MmioAddressOffset, MmioRange, PioAddress, PioAddressOffset};
+use crate::device_manager::{write_json_string, IoManager, MmioManager};
+use crate::interrupt::{self, Interrupt};
+pub use crate::replay::Direction;
+use crate::resources::{DeviceResources, Resource};
+use crate::{DeviceMmio, DevicePio};
+
+/// An access recorded by a [`MockDevice`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Access {
+    /// Direction of the access.
+    pub direction: Direction,
+    /// Base address of the range the access targeted.
+    pub base: u64,
+    /// Offset of the access within the range.
+    pub offset: u64,
+    /// Data written by the guest, or returned by the device for reads.
+    pub data: Vec<u8>,
+}
+
+/// A device recording all the accesses it handles, on both the PIO and MMIO buses.
+///
+/// Reads are served from responses scripted with [`MockDevice::push_read_response`], and
+/// return zeroes when no response is pending for the accessed offset.
+#[derive(Default)]
+pub struct MockDevice {
+    accesses: Mutex<Vec<Access>>,
+    responses: Mutex<BTreeMap<u64, VecDeque<Vec<u8>>>>,
+}
+
+impl MockDevice {
+    /// Create a mock device with no recorded accesses and no scripted responses.
+    pub fn new() -> Self {
+        MockDevice::default()
+    }
+
+    /// Queue `data` as the response to the next read at `offset`.
+    ///
+    /// If the read is shorter than `data`, the response is truncated, and if it's longer
+    /// the remaining bytes are zeroed.
+    pub fn push_read_response(&self, offset: u64, data: &[u8]) {
+        self.responses
+            .lock()
+            .unwrap()
+            .entry(offset)
+            .or_default()
+            .push_back(data.to_vec());
+    }
+
+    /// Return the accesses recorded so far, in the order in which they were handled.
+    pub fn accesses(&self) -> Vec<Access> {
+        self.accesses.lock().unwrap().clone()
+    }
+
+    /// Forget the accesses recorded so far.
+    pub fn clear(&self) {
+        self.accesses.lock().unwrap().clear();
+    }
+
+    /// Panic unless a read of `len` bytes at `offset` was recorded.
+    pub fn expect_read(&self, offset: u64, len: usize) {
+        let found =
+            self.accesses.lock().unwrap().iter().any(|a| {
+                a.direction == Direction::Read && a.offset == offset && a.data.len() == len
+            });
+        if !found {
+            panic!(
+                "expected a read of {} bytes at offset {:#x}, recorded accesses: {:#x?}",
+                len,
+                offset,
+                self.accesses()
+            );
+        }
+    }
+
+    /// Panic unless a write of `data` at `offset` was recorded.
+    pub fn expect_write(&self, offset: u64, data: &[u8]) {
+        let found = self
+            .accesses
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|a| a.direction == Direction::Write && a.offset == offset && a.data == data);
+        if !found {
+            panic!(
+                "expected a write of {:x?} at offset {:#x}, recorded accesses: {:#x?}",
+                data,
+                offset,
+                self.accesses()
+            );
+        }
+    }
+
+    /// Panic if any access was recorded.
+    pub fn expect_no_accesses(&self) {
+        let accesses = self.accesses();
+        if !accesses.is_empty() {
+            panic!("expected no accesses, recorded accesses: {:#x?}", accesses);
+        }
+    }
+
+    fn read(&self, base: u64, offset: u64, data: &mut [u8]) {
+        let response = self
+            .responses
+            .lock()
+            .unwrap()
+            .get_mut(&offset)
+            .and_then(|queue| queue.pop_front())
+            .unwrap_or_default();
+        for (idx, byte) in data.iter_mut().enumerate() {
+            *byte = response.get(idx).copied().unwrap_or(0);
+        }
+        self.record(Direction::Read, base, offset, data);
+    }
+
+    fn record(&self, direction: Direction, base: u64, offset: u64, data: &[u8]) {
+        self.accesses.lock().unwrap().push(Access {
+            direction,
+            base,
+            offset,
+            data: data.to_vec(),
+        });
+    }
+}
+
+impl DeviceMmio for MockDevice {
+    fn mmio_read(&self, base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+        self.read(base.0, offset, data);
+    }
+
+    fn mmio_write(&self, base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+        self.record(Direction::Write, base.0, offset, data);
+    }
+}
+
+impl DevicePio for MockDevice {
+    fn pio_read(&self, base: PioAddress, offset: PioAddressOffset, data: &mut [u8]) {
+        self.read(u64::from(base.0), u64::from(offset), data);
+    }
+
+    fn pio_write(&self, base: PioAddress, offset: PioAddressOffset, data: &[u8]) {
+        self.record(Direction::Write, u64::from(base.0), u64::from(offset), data);
+    }
+}
+
+/// A RAM-backed device, where reads return the bytes previously written at the same
+/// offsets.
+///
+/// The scratchpad is the simplest device doing something observable, which makes it a
+/// convenient target for integration tests, examples and benchmarks. Accesses beyond its
+/// size read as zeroes and writes there are ignored.
+pub struct Scratchpad {
+    memory: Mutex<Vec<u8>>,
+}
+
+impl Scratchpad {
+    /// Create a zeroed scratchpad of `size` bytes.
+    pub fn new(size: usize) -> Self {
+        Scratchpad {
+            memory: Mutex::new(vec![0; size]),
+        }
+    }
+
+    /// Return a copy of the scratchpad contents.
+    pub fn contents(&self) -> Vec<u8> {
+        self.memory.lock().unwrap().clone()
+    }
+
+    fn read(&self, offset: u64, data: &mut [u8]) {
+        let memory = self.memory.lock().unwrap();
+        for (idx, byte) in data.iter_mut().enumerate() {
+            *byte = usize::try_from(offset)
+                .ok()
+                .and_then(|offset| offset.checked_add(idx))
+                .and_then(|pos| memory.get(pos))
+                .copied()
+                .unwrap_or(0);
+        }
+    }
+
+    fn write(&self, offset: u64, data: &[u8]) {
+        let mut memory = self.memory.lock().unwrap();
+        for (idx, byte) in data.iter().enumerate() {
+            if let Some(slot) = usize::try_from(offset)
+                .ok()
+                .and_then(|offset| offset.checked_add(idx))
+                .and_then(|pos| memory.get_mut(pos))
+            {
+                *slot = *byte;
+            }
+        }
+    }
+}
+
+impl DeviceMmio for Scratchpad {
+    fn mmio_read(&self, _base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+        self.read(offset, data);
+    }
+
+    fn mmio_write(&self, _base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+        self.write(offset, data);
+    }
+
+    fn debug_read(&self, _base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) -> bool {
+        self.read(offset, data);
+        true
+    }
+}
+
+impl DevicePio for Scratchpad {
+    fn pio_read(&self, _base: PioAddress, offset: PioAddressOffset, data: &mut [u8]) {
+        self.read(u64::from(offset), data);
+    }
+
+    fn pio_write(&self, _base: PioAddress, offset: PioAddressOffset, data: &[u8]) {
+        self.write(u64::from(offset), data);
+    }
+
+    fn debug_read(&self, _base: PioAddress, offset: PioAddressOffset, data: &mut [u8]) -> bool {
+        self.read(u64::from(offset), data);
+        true
+    }
+}
+
+/// An interrupt counting how many times it was triggered.
+///
+/// # Example
+///
+/// ```
+/// use vm_device::interrupt::Interrupt;
+/// use vm_device::testing::MockInterrupt;
+///
+/// let irq = MockInterrupt::new();
+/// irq.expect_not_triggered();
+/// irq.trigger().unwrap();
+/// irq.expect_triggered(1);
+/// ```
+#[derive(Default)]
+pub struct MockInterrupt {
+    count: Mutex<usize>,
+}
+
+impl MockInterrupt {
+    /// Create an interrupt that was never triggered.
+    pub fn new() -> Self {
+        MockInterrupt::default()
+    }
+
+    /// Return how many times the interrupt was triggered.
+    pub fn count(&self) -> usize {
+        *self.count.lock().unwrap()
+    }
+
+    /// Reset the trigger count.
+    pub fn clear(&self) {
+        *self.count.lock().unwrap() = 0;
+    }
+
+    /// Panic unless the interrupt was triggered exactly `count` times.
+    pub fn expect_triggered(&self, count: usize) {
+        let found = self.count();
+        if found != count {
+            panic!(
+                "expected the interrupt to be triggered {} time(s), but it was triggered {} \
+                 time(s)",
+                count, found
+            );
+        }
+    }
+
+    /// Panic if the interrupt was triggered.
+    pub fn expect_not_triggered(&self) {
+        let found = self.count();
+        if found != 0 {
+            panic!(
+                "expected the interrupt not to be triggered, but it was triggered {} time(s)",
+                found
+            );
+        }
+    }
+}
+
+impl Interrupt for MockInterrupt {
+    fn trigger(&self) -> Result<(), interrupt::Error> {
+        *self.count.lock().unwrap() += 1;
+        Ok(())
+    }
+}
+
+/// An interrupt message recorded by a [`MockInterruptController`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum InterruptMessage {
+    /// A legacy interrupt asserted on a GSI.
+    Gsi(u32),
+    /// A message signaled interrupt.
+    Msi {
+        /// Address of the message.
+        address: u64,
+        /// Payload of the message.
+        data: u32,
+    },
+}
+
+/// An interrupt delivered to a [`MockInterruptController`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct InterruptRecord {
+    /// The delivered message.
+    pub message: InterruptMessage,
+    /// When the message was delivered.
+    pub timestamp: Instant,
+}
+
+/// A fake interrupt backend, recording the GSIs and MSI messages triggered by devices.
+///
+/// The controller hands out [`Interrupt`] implementations for GSIs and MSI messages, which
+/// devices trigger as usual. Records are kept in delivery order, so tests can check the
+/// path from a device access to the interrupt reaching the guest without a real VM.
+///
+/// # Example
+///
+/// ```
+/// use vm_device::interrupt::Interrupt;
+/// use vm_device::testing::{InterruptMessage, MockInterruptController};
+///
+/// let controller = MockInterruptController::new();
+/// let config = controller.gsi(5);
+/// let queue = controller.msi(0xfee0_0000, 0x41);
+///
+/// queue.trigger().unwrap();
+/// config.trigger().unwrap();
+///
+/// let msi = InterruptMessage::Msi { address: 0xfee0_0000, data: 0x41 };
+/// assert!(controller.triggered_before(msi, InterruptMessage::Gsi(5)));
+/// controller.expect_order(&[msi, InterruptMessage::Gsi(5)]);
+/// ```
+#[derive(Clone, Default)]
+pub struct MockInterruptController {
+    records: Arc<Mutex<Vec<InterruptRecord>>>,
+}
+
+impl MockInterruptController {
+    /// Create a controller without any recorded interrupt.
+    pub fn new() -> Self {
+        MockInterruptController::default()
+    }
+
+    /// Return an interrupt asserting `gsi` when triggered.
+    pub fn gsi(&self, gsi: u32) -> ControllerInterrupt {
+        self.interrupt(InterruptMessage::Gsi(gsi))
+    }
+
+    /// Return an interrupt sending the MSI message described by `address` and `data` when
+    /// triggered.
+    pub fn msi(&self, address: u64, data: u32) -> ControllerInterrupt {
+        self.interrupt(InterruptMessage::Msi { address, data })
+    }
+
+    fn interrupt(&self, message: InterruptMessage) -> ControllerInterrupt {
+        ControllerInterrupt {
+            message,
+            records: self.records.clone(),
+        }
+    }
+
+    /// Return the recorded interrupts, in delivery order.
+    pub fn records(&self) -> Vec<InterruptRecord> {
+        self.records.lock().unwrap().clone()
+    }
+
+    /// Return the recorded messages, in delivery order.
+    pub fn messages(&self) -> Vec<InterruptMessage> {
+        self.records
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|record| record.message)
+            .collect()
+    }
+
+    /// Return how many times `message` was delivered.
+    pub fn count(&self, message: InterruptMessage) -> usize {
+        self.records
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|record| record.message == message)
+            .count()
+    }
+
+    /// Forget the recorded interrupts.
+    pub fn clear(&self) {
+        self.records.lock().unwrap().clear();
+    }
+
+    /// Return whether the first delivery of `first` happened before the first delivery of
+    /// `second`.
+    ///
+    /// Returns `false` when `first` was never delivered, and `true` when only `first` was.
+    pub fn triggered_before(&self, first: InterruptMessage, second: InterruptMessage) -> bool {
+        let messages = self.messages();
+        let position = |message| messages.iter().position(|&m| m == message);
+        match (position(first), position(second)) {
+            (Some(first), Some(second)) => first < second,
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+
+    /// Panic unless `expected` were delivered in this order.
+    ///
+    /// Other interrupts may be delivered in between, i.e. `expected` has to be a subsequence
+    /// of the recorded messages.
+    pub fn expect_order(&self, expected: &[InterruptMessage]) {
+        let messages = self.messages();
+        let mut remaining = messages.iter();
+        for message in expected {
+            if !remaining.any(|m| m == message) {
+                panic!(
+                    "expected the interrupts {:?} in this order, but got {:?}",
+                    expected, messages
+                );
+            }
+        }
+    }
+}
+
+/// An interrupt handed out by a [`MockInterruptController`].
+pub struct ControllerInterrupt {
+    message: InterruptMessage,
+    records: Arc<Mutex<Vec<InterruptRecord>>>,
+}
+
+impl ControllerInterrupt {
+    /// Return the message delivered when the interrupt is triggered.
+    pub fn message(&self) -> InterruptMessage {
+        self.message
+    }
+}
+
+impl Interrupt for ControllerInterrupt {
+    fn trigger(&self) -> Result<(), interrupt::Error> {
+        // Take the timestamp under the lock, so records are ordered by timestamp too.
+        let mut records = self.records.lock().unwrap();
+        records.push(InterruptRecord {
+            message: self.message,
+            timestamp: Instant::now(),
+        });
+        Ok(())
+    }
+}
+
+/// A device slot of a [`TestVm`].
+pub struct Slot {
+    range: MmioRange,
+    irq: u32,
+    device: Arc<MockDevice>,
+}
+
+impl Slot {
+    /// Return the MMIO range of the slot.
+    pub fn range(&self) -> MmioRange {
+        self.range
+    }
+
+    /// Return the legacy IRQ assigned to the slot.
+    pub fn irq(&self) -> u32 {
+        self.irq
+    }
+
+    /// Return the device registered in the slot.
+    pub fn device(&self) -> &Arc<MockDevice> {
+        &self.device
+    }
+
+    /// Return the resources assigned to the slot.
+    pub fn resources(&self) -> DeviceResources {
+        let mut resources = DeviceResources::new();
+        resources.append(Resource::MmioAddressRange {
+            base: self.range.base().0,
+            size: self.range.size(),
+        });
+        resources.append(Resource::LegacyIrq(self.irq));
+        resources
+    }
+}
+
+/// An [`IoManager`] populated with device slots by a [`TestVmBuilder`].
+pub struct TestVm {
+    manager: IoManager,
+    slots: Vec<Slot>,
+}
+
+impl TestVm {
+    /// Return the manager.
+    pub fn manager(&self) -> &IoManager {
+        &self.manager
+    }
+
+    /// Return a mutable reference to the manager, e.g. to register more devices.
+    pub fn manager_mut(&mut self) -> &mut IoManager {
+        &mut self.manager
+    }
+
+    /// Return the device slots, in ascending address order.
+    pub fn slots(&self) -> &[Slot] {
+        &self.slots
+    }
+
+    /// Read `len` bytes at `offset` within slot `idx`, going through the manager.
+    ///
+    /// Panics if the slot doesn't exist or the access fails.
+    pub fn read(&self, idx: usize, offset: u64, len: usize) -> Vec<u8> {
+        let mut data = vec![0; len];
+        self.manager
+            .mmio_read(self.slot_address(idx, offset), &mut data)
+            .unwrap();
+        data
+    }
+
+    /// Write `data` at `offset` within slot `idx`, going through the manager.
+    ///
+    /// Panics if the slot doesn't exist or the access fails.
+    pub fn write(&self, idx: usize, offset: u64, data: &[u8]) {
+        self.manager
+            .mmio_write(self.slot_address(idx, offset), data)
+            .unwrap();
+    }
+
+    fn slot_address(&self, idx: usize, offset: u64) -> MmioAddress {
+        MmioAddress(self.slots[idx].range.base().0 + offset)
+    }
+}
+
+/// Builds a [`TestVm`] with device slots at conventional addresses and IRQs.
+///
+/// By default, slots are `0x1000` bytes apart starting at `0xd000_0000`, and use the
+/// legacy IRQs following `5`, which matches the layout of MMIO devices in common microVMs.
+/// Each slot holds a [`MockDevice`].
+///
+/// # Example
+///
+/// ```
+/// use vm_device::testing::TestVmBuilder;
+///
+/// let vm = TestVmBuilder::new().slots(2).build();
+/// assert_eq!(vm.slots()[1].range().base().0, 0xd000_1000);
+/// assert_eq!(vm.slots()[1].irq(), 6);
+///
+/// vm.write(1, 0x70, &[1, 2]);
+/// vm.slots()[1].device().expect_write(0x70, &[1, 2]);
+/// ```
+pub struct TestVmBuilder {
+    slots: usize,
+    mmio_base: u64,
+    slot_size: u64,
+    first_irq: u32,
+}
+
+impl Default for TestVmBuilder {
+    fn default() -> Self {
+        TestVmBuilder {
+            slots: 0,
+            mmio_base: 0xd000_0000,
+            slot_size: 0x1000,
+            first_irq: 5,
+        }
+    }
+}
+
+impl TestVmBuilder {
+    /// Create a builder for a VM without any slot.
+    pub fn new() -> Self {
+        TestVmBuilder::default()
+    }
+
+    /// Set the number of slots.
+    pub fn slots(mut self, slots: usize) -> Self {
+        self.slots = slots;
+        self
+    }
+
+    /// Set the base address of the first slot.
+    pub fn mmio_base(mut self, mmio_base: u64) -> Self {
+        self.mmio_base = mmio_base;
+        self
+    }
+
+    /// Set the size of each slot, which is also the distance between consecutive slots.
+    pub fn slot_size(mut self, slot_size: u64) -> Self {
+        self.slot_size = slot_size;
+        self
+    }
+
+    /// Set the IRQ of the first slot.
+    pub fn first_irq(mut self, first_irq: u32) -> Self {
+        self.first_irq = first_irq;
+        self
+    }
+
+    /// Build the VM.
+    ///
+    /// Panics if the slots don't fit in the MMIO address space.
+    pub fn build(self) -> TestVm {
+        let mut vm = TestVm {
+            manager: IoManager::new(),
+            slots: Vec::with_capacity(self.slots),
+        };
+        for idx in 0..self.slots {
+            let base = (idx as u64)
+                .checked_mul(self.slot_size)
+                .and_then(|offset| offset.checked_add(self.mmio_base))
+                .expect("slots exceed the MMIO address space");
+            let range = MmioRange::new(MmioAddress(base), self.slot_size)
+                .expect("slots exceed the MMIO address space");
+            let device = Arc::new(MockDevice::new());
+            vm.manager.register_mmio(range, device.clone()).unwrap();
+            vm.slots.push(Slot {
+                range,
+                irq: self.first_irq + idx as u32,
+                device,
+            });
+        }
+        vm
+    }
+}
+
+/// Configuration of a [`stress`] run.
+#[derive(Clone, Debug)]
+pub struct StressConfig {
+    /// Number of threads dispatching accesses.
+    pub dispatchers: usize,
+    /// Number of accesses performed by each dispatching thread.
+    pub dispatches: usize,
+    /// Number of hotplug or unplug operations performed while accesses are dispatched.
+    pub hotplugs: usize,
+    /// Number of devices that stay registered for the whole run.
+    pub stable_devices: usize,
+    /// Number of slots devices are hotplugged to and unplugged from.
+    pub hotplug_slots: usize,
+    /// Seed of the generators picking the accesses and hotplug operations.
+    pub seed: u64,
+    /// Time after which the run is considered deadlocked.
+    pub timeout: Duration,
+}
+
+impl Default for StressConfig {
+    fn default() -> Self {
+        StressConfig {
+            dispatchers: 4,
+            dispatches: 10_000,
+            hotplugs: 1_000,
+            stable_devices: 4,
+            hotplug_slots: 8,
+            seed: 1,
+            timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Counters collected during a [`stress`] run.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct StressReport {
+    /// Accesses that reached a device.
+    pub dispatched: usize,
+    /// Accesses that found no device, because it was unplugged.
+    pub missed: usize,
+    /// Devices hotplugged.
+    pub plugged: usize,
+    /// Devices unplugged.
+    pub unplugged: usize,
+}
+
+// Size of the MMIO range of each device used by `stress`.
+const STRESS_SLOT_SIZE: u64 = 0x1000;
+// Base address of the first hotplug slot used by `stress`; stable devices come before it.
+const STRESS_HOTPLUG_BASE: u64 = 0x1_0000_0000;
+
+struct XorShift(u64);
+
+impl XorShift {
+    fn new(seed: u64) -> Self {
+        // The generator gets stuck on zero.
+        XorShift(seed | 1)
+    }
+
+    fn below(&mut self, bound: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % bound as u64) as usize
+    }
+}
+
+fn stress_range(base: u64, idx: usize) -> MmioRange {
+    MmioRange::new(
+        MmioAddress(base + idx as u64 * STRESS_SLOT_SIZE),
+        STRESS_SLOT_SIZE,
+    )
+    .unwrap()
+}
+
+/// Dispatch accesses from multiple threads to a shared [`IoManager`], while another thread
+/// keeps hotplugging and unplugging devices.
+///
+/// The manager is shared behind a `RwLock`, the way VMMs typically share it between vCPU
+/// threads (dispatching under the read lock) and the thread handling hotplug (registering
+/// and deregistering under the write lock). The run panics if:
+/// - any thread panics, with the original panic payload;
+/// - the run doesn't complete within `config.timeout`, which hints at a deadlock;
+/// - an access to a device that was never unplugged fails, or reads back different data
+///   than the dispatching thread wrote, hinting at a lost registration or a misrouted
+///   access;
+/// - a hotplug or unplug operation doesn't behave as expected, or the registered devices
+///   don't match the expected ones at the end of the run.
+///
+/// # Example
+///
+/// ```
+/// use vm_device::testing::{stress, StressConfig};
+///
+/// let report = stress(&StressConfig {
+///     dispatches: 1000,
+///     hotplugs: 100,
+///     ..Default::default()
+/// });
+/// assert_eq!(report.dispatched + report.missed, 4 * 1000);
+/// ```
+pub fn stress(config: &StressConfig) -> StressReport {
+    assert!(config.stable_devices > 0 && config.hotplug_slots > 0);
+
+    // Each dispatching thread uses its own 8 bytes of every device.
+    let device_size = config.dispatchers * 8;
+    let mut manager = IoManager::new();
+    for idx in 0..config.stable_devices {
+        manager
+            .register_mmio(stress_range(0, idx), Arc::new(Scratchpad::new(device_size)))
+            .unwrap();
+    }
+    let manager = Arc::new(RwLock::new(manager));
+    let (sender, receiver) = mpsc::channel();
+    let mut handles = Vec::new();
+
+    for thread_idx in 0..config.dispatchers {
+        let (manager, sender, config) = (manager.clone(), sender.clone(), config.clone());
+        handles.push(thread::spawn(move || {
+            let mut rng = XorShift::new(config.seed.wrapping_add(thread_idx as u64 + 1));
+            let mut report = StressReport::default();
+            let offset = thread_idx as u64 * 8;
+            for iteration in 0..config.dispatches {
+                let slot = rng.below(config.stable_devices + config.hotplug_slots);
+                let stable = slot < config.stable_devices;
+                let range = if stable {
+                    stress_range(0, slot)
+                } else {
+                    stress_range(STRESS_HOTPLUG_BASE, slot - config.stable_devices)
+                };
+                let addr = MmioAddress(range.base().0 + offset);
+                let written = (iteration as u64).to_le_bytes();
+                let mut read = [0; 8];
+
+                let manager = manager.read().unwrap();
+                let result = manager
+                    .mmio_write(addr, &written)
+                    .and_then(|_| manager.mmio_read(addr, &mut read));
+                match result {
+                    Ok(()) => {
+                        assert_eq!(read, written, "access misrouted at {:#x}", addr.0);
+                        report.dispatched += 1;
+                    }
+                    Err(e) if stable => {
+                        panic!("access to stable device at {:#x} failed: {}", addr.0, e)
+                    }
+                    Err(_) => report.missed += 1,
+                }
+            }
+            sender.send(()).unwrap();
+            report
+        }));
+    }
+
+    {
+        let (manager, sender, config) = (manager.clone(), sender.clone(), config.clone());
+        handles.push(thread::spawn(move || {
+            let mut rng = XorShift::new(config.seed);
+            let mut report = StressReport::default();
+            let mut plugged = vec![false; config.hotplug_slots];
+            for _ in 0..config.hotplugs {
+                let slot = rng.below(config.hotplug_slots);
+                let range = stress_range(STRESS_HOTPLUG_BASE, slot);
+                let mut manager = manager.write().unwrap();
+                if plugged[slot] {
+                    let (removed, _) = manager
+                        .deregister_mmio(range.base())
+                        .expect("hotplugged device lost");
+                    assert_eq!(removed.base(), range.base());
+                    report.unplugged += 1;
+                } else {
+                    manager
+                        .register_mmio(range, Arc::new(Scratchpad::new(device_size)))
+                        .expect("cannot hotplug device");
+                    report.plugged += 1;
+                }
+                plugged[slot] = !plugged[slot];
+                drop(manager);
+                thread::yield_now();
+            }
+
+            let manager = manager.read().unwrap();
+            for (slot, plugged) in plugged.into_iter().enumerate() {
+                let base = stress_range(STRESS_HOTPLUG_BASE, slot).base();
+                assert_eq!(
+                    manager.mmio_device(base).is_some(),
+                    plugged,
+                    "unexpected registration state for the device at {:#x}",
+                    base.0
+                );
+            }
+            sender.send(()).unwrap();
+            report
+        }));
+    }
+
+    drop(sender);
+    for _ in 0..handles.len() {
+        match receiver.recv_timeout(config.timeout) {
+            Ok(()) => {}
+            Err(RecvTimeoutError::Timeout) => {
+                panic!("stress run timed out after {:?}", config.timeout)
+            }
+            // Some thread panicked before reporting; joining below surfaces the panic.
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+
+    let mut report = StressReport::default();
+    for handle in handles {
+        match handle.join() {
+            Ok(partial) => {
+                report.dispatched += partial.dispatched;
+                report.missed += partial.missed;
+                report.plugged += partial.plugged;
+                report.unplugged += partial.unplugged;
+            }
+            Err(payload) => std::panic::resume_unwind(payload),
+        }
+    }
+
+    let manager = manager.read().unwrap();
+    for idx in 0..config.stable_devices {
+        assert!(manager.mmio_device(stress_range(0, idx).base()).is_some());
+    }
+    report
+}
+
+/// Outcome of an access performed by [`sweep_mmio`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SweepOutcome {
+    /// The device panicked, with the panic message if it was a string.
+    Panicked(String),
+    /// The device left the read buffer untouched.
+    Ignored,
+    /// The device returned data for a read.
+    Returned(Vec<u8>),
+    /// The device handled a write without panicking.
+    Completed,
+}
+
+/// An access performed by [`sweep_mmio`], with its outcome.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SweepEntry {
+    /// Direction of the access.
+    pub direction: Direction,
+    /// Offset of the access within the range.
+    pub offset: u64,
+    /// Size of the access.
+    pub width: usize,
+    /// What the device did.
+    pub outcome: SweepOutcome,
+}
+
+/// Report of a [`sweep_mmio`] run.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SweepReport {
+    /// The performed accesses, in order.
+    pub entries: Vec<SweepEntry>,
+}
+
+impl SweepReport {
+    /// Return the accesses which made the device panic.
+    pub fn panicked(&self) -> impl Iterator<Item = &SweepEntry> {
+        self.entries
+            .iter()
+            .filter(|entry| matches!(entry.outcome, SweepOutcome::Panicked(_)))
+    }
+
+    /// Return the reads the device ignored.
+    pub fn ignored(&self) -> impl Iterator<Item = &SweepEntry> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.outcome == SweepOutcome::Ignored)
+    }
+
+    /// Return the reads for which the device returned data.
+    pub fn returned(&self) -> impl Iterator<Item = &SweepEntry> {
+        self.entries
+            .iter()
+            .filter(|entry| matches!(entry.outcome, SweepOutcome::Returned(_)))
+    }
+
+    /// Describe the report as a JSON array, with an object for each access.
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("[");
+        for (idx, entry) in self.entries.iter().enumerate() {
+            if idx > 0 {
+                json.push(',');
+            }
+            let direction = match entry.direction {
+                Direction::Read => "read",
+                Direction::Write => "write",
+            };
+            json.push_str(&format!(
+                "{{\"direction\":\"{}\",\"offset\":{},\"width\":{},",
+                direction, entry.offset, entry.width
+            ));
+            match &entry.outcome {
+                SweepOutcome::Panicked(message) => {
+                    json.push_str("\"outcome\":\"panicked\",\"message\":");
+                    write_json_string(&mut json, message);
+                }
+                SweepOutcome::Ignored => json.push_str("\"outcome\":\"ignored\""),
+                SweepOutcome::Returned(data) => {
+                    json.push_str("\"outcome\":\"returned\",\"data\":[");
+                    let bytes: Vec<String> = data.iter().map(u8::to_string).collect();
+                    json.push_str(&bytes.join(","));
+                    json.push(']');
+                }
+                SweepOutcome::Completed => json.push_str("\"outcome\":\"completed\""),
+            }
+            json.push('}');
+        }
+        json.push(']');
+        json
+    }
+}
+
+// Widths of the accesses performed by `sweep_mmio`.
+const SWEEP_WIDTHS: [usize; 4] = [1, 2, 4, 8];
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => payload
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .unwrap_or_default(),
+    }
+}
+
+/// Read from and write to every naturally aligned offset of `range`, with all access widths,
+/// and report how `device` handled each access.
+///
+/// Each offset is read first, and then written with the bytes the read returned (or zeros),
+/// so registers are mostly written back with their current value. A read leaving both a
+/// zeroed and a `0xff`-filled buffer untouched is reported as ignored. Panics are caught
+/// and reported, which makes the sweep an easy way of catching missing match arms in
+/// device implementations; the default panic hook still prints them.
+///
+/// # Example
+///
+/// ```
+/// # use vm_device::bus::{MmioAddress, MmioRange};
+/// use vm_device::testing::{sweep_mmio, Scratchpad};
+///
+/// let range = MmioRange::new(MmioAddress(0x1000), 0x10).unwrap();
+/// let report = sweep_mmio(&Scratchpad::new(0x10), range);
+/// assert_eq!(report.panicked().count(), 0);
+/// assert_eq!(report.returned().count(), 16 + 8 + 4 + 2);
+/// ```
+pub fn sweep_mmio<D: DeviceMmio + ?Sized>(device: &D, range: MmioRange) -> SweepReport {
+    let base = range.base();
+    let mut report = SweepReport::default();
+    for &width in SWEEP_WIDTHS.iter() {
+        let mut offset = 0;
+        while offset + width as u64 <= range.size() {
+            let read = |fill: u8| {
+                panic::catch_unwind(AssertUnwindSafe(|| {
+                    let mut data = vec![fill; width];
+                    device.mmio_read(base, offset, &mut data);
+                    data
+                }))
+            };
+            let outcome = match read(0).and_then(|zeroed| Ok((zeroed, read(0xff)?))) {
+                Ok((zeroed, filled))
+                    if zeroed == [0; 8][..width] && filled == [0xff; 8][..width] =>
+                {
+                    SweepOutcome::Ignored
+                }
+                Ok((zeroed, _)) => SweepOutcome::Returned(zeroed),
+                Err(payload) => SweepOutcome::Panicked(panic_message(payload)),
+            };
+            let written = match &outcome {
+                SweepOutcome::Returned(data) => data.clone(),
+                _ => vec![0; width],
+            };
+            report.entries.push(SweepEntry {
+                direction: Direction::Read,
+                offset,
+                width,
+                outcome,
+            });
+
+            let outcome = match panic::catch_unwind(AssertUnwindSafe(|| {
+                device.mmio_write(base, offset, &written)
+            })) {
+                Ok(()) => SweepOutcome::Completed,
+                Err(payload) => SweepOutcome::Panicked(panic_message(payload)),
+            };
+            report.entries.push(SweepEntry {
+                direction: Direction::Write,
+                offset,
+                width,
+                outcome,
+            });
+            offset += width as u64;
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mock_device() {
+        let device = MockDevice::new();
+        device.expect_no_accesses();
+
+        device.push_read_response(4, &[1, 2, 3]);
+        device.push_read_response(4, &[4]);
+
+        let mut data = [0xff; 2];
+        device.mmio_read(MmioAddress(0x1000), 4, &mut data);
+        assert_eq!(data, [1, 2]);
+        device.pio_read(PioAddress(0x40), 4, &mut data);
+        assert_eq!(data, [4, 0]);
+        device.mmio_read(MmioAddress(0x1000), 4, &mut data);
+        assert_eq!(data, [0, 0]);
+        device.pio_write(PioAddress(0x40), 1, &[5]);
+
+        assert_eq!(
+            device.accesses(),
+            vec![
+                Access {
+                    direction: Direction::Read,
+                    base: 0x1000,
+                    offset: 4,
+                    data: vec![1, 2],
+                },
+                Access {
+                    direction: Direction::Read,
+                    base: 0x40,
+                    offset: 4,
+                    data: vec![4, 0],
+                },
+                Access {
+                    direction: Direction::Read,
+                    base: 0x1000,
+                    offset: 4,
+                    data: vec![0, 0],
+                },
+                Access {
+                    direction: Direction::Write,
+                    base: 0x40,
+                    offset: 1,
+                    data: vec![5],
+                },
+            ]
+        );
+        device.expect_read(4, 2);
+        device.expect_write(1, &[5]);
+
+        device.clear();
+        device.expect_no_accesses();
+    }
+
+    #[test]
+    #[should_panic(expected = "expected a write of [6] at offset 0x1")]
+    fn test_expect_write_failure() {
+        let device = MockDevice::new();
+        device.mmio_write(MmioAddress(0), 1, &[5]);
+        device.expect_write(1, &[6]);
+    }
+
+    #[test]
+    #[should_panic(expected = "expected no accesses")]
+    fn test_expect_no_accesses_failure() {
+        let device = MockDevice::new();
+        device.mmio_read(MmioAddress(0), 0, &mut [0]);
+        device.expect_no_accesses();
+    }
+
+    #[test]
+    fn test_mock_interrupt() {
+        let irq = Arc::new(MockInterrupt::new());
+        irq.expect_not_triggered();
+        irq.trigger().unwrap();
+        irq.clone().trigger().unwrap();
+        irq.expect_triggered(2);
+        assert_eq!(irq.count(), 2);
+        irq.clear();
+        irq.expect_not_triggered();
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "expected the interrupt to be triggered 1 time(s), but it was triggered 2"
+    )]
+    fn test_mock_interrupt_failure() {
+        let irq = MockInterrupt::new();
+        irq.trigger().unwrap();
+        irq.trigger().unwrap();
+        irq.expect_triggered(1);
+    }
+
+    #[test]
+    fn test_mock_interrupt_controller() {
+        let controller = MockInterruptController::new();
+        let gsi = controller.gsi(4);
+        let msi = controller.msi(0xfee0_0000, 0x30);
+        let other = InterruptMessage::Gsi(5);
+        assert_eq!(gsi.message(), InterruptMessage::Gsi(4));
+
+        gsi.trigger().unwrap();
+        msi.trigger().unwrap();
+        gsi.trigger().unwrap();
+
+        assert_eq!(
+            controller.messages(),
+            vec![gsi.message(), msi.message(), gsi.message()]
+        );
+        let records = controller.records();
+        assert!(records[0].timestamp <= records[1].timestamp);
+        assert!(records[1].timestamp <= records[2].timestamp);
+        assert_eq!(controller.count(gsi.message()), 2);
+        assert_eq!(controller.count(other), 0);
+
+        assert!(controller.triggered_before(gsi.message(), msi.message()));
+        assert!(!controller.triggered_before(msi.message(), gsi.message()));
+        assert!(controller.triggered_before(msi.message(), other));
+        assert!(!controller.triggered_before(other, msi.message()));
+        controller.expect_order(&[gsi.message(), gsi.message()]);
+        controller.expect_order(&[msi.message(), gsi.message()]);
+
+        controller.clear();
+        assert!(controller.records().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "expected the interrupts [Gsi(1), Gsi(1)] in this order")]
+    fn test_mock_interrupt_controller_order_failure() {
+        let controller = MockInterruptController::new();
+        controller.gsi(1).trigger().unwrap();
+        controller.gsi(2).trigger().unwrap();
+        controller.expect_order(&[InterruptMessage::Gsi(1), InterruptMessage::Gsi(1)]);
+    }
+
+    #[test]
+    fn test_scratchpad() {
+        use crate::bus::{MmioRange, PioRange};
+        use crate::device_manager::{IoManager, MmioManager, PioManager};
+        use std::sync::Arc;
+
+        let scratchpad = Arc::new(Scratchpad::new(8));
+        let mut manager = IoManager::new();
+        let range = MmioRange::new(MmioAddress(0x1000), 0x10).unwrap();
+        manager.register_mmio(range, scratchpad.clone()).unwrap();
+        let range = PioRange::new(PioAddress(0x40), 0x10).unwrap();
+        manager.register_pio(range, scratchpad.clone()).unwrap();
+
+        manager
+            .mmio_write(MmioAddress(0x1002), &[1, 2, 3, 4])
+            .unwrap();
+        // The write is truncated at the end of the scratchpad.
+        manager.pio_write(PioAddress(0x46), &[5, 6, 7]).unwrap();
+        assert_eq!(scratchpad.contents(), vec![0, 0, 1, 2, 3, 4, 5, 6]);
+
+        let mut data = [0xff; 4];
+        manager.pio_read(PioAddress(0x43), &mut data).unwrap();
+        assert_eq!(data, [2, 3, 4, 5]);
+        manager.mmio_read(MmioAddress(0x1006), &mut data).unwrap();
+        assert_eq!(data, [5, 6, 0, 0]);
+
+        scratchpad.mmio_read(MmioAddress(0x1000), u64::MAX, &mut data);
+        assert_eq!(data, [0; 4]);
+        scratchpad.mmio_write(MmioAddress(0x1000), u64::MAX, &data);
+    }
+
+    #[test]
+    fn test_vm_builder() {
+        let vm = TestVmBuilder::new()
+            .slots(3)
+            .mmio_base(0x1_0000)
+            .slot_size(0x200)
+            .first_irq(32)
+            .build();
+        let bases = vm
+            .slots()
+            .iter()
+            .map(|slot| (slot.range().base().0, slot.irq()))
+            .collect::<Vec<_>>();
+        assert_eq!(bases, vec![(0x1_0000, 32), (0x1_0200, 33), (0x1_0400, 34)]);
+        assert_eq!(vm.slots()[2].resources().get_legacy_irq(), Some(34));
+
+        vm.slots()[2].device().push_read_response(0x1ff, &[7]);
+        assert_eq!(vm.read(2, 0x1ff, 1), vec![7]);
+        vm.slots()[0].device().expect_no_accesses();
+        assert!(vm
+            .manager()
+            .mmio_read(MmioAddress(0x1_0600), &mut [0])
+            .is_err());
+
+        assert!(TestVmBuilder::new().build().slots().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "slots exceed the MMIO address space")]
+    fn test_vm_builder_overflow() {
+        TestVmBuilder::new()
+            .mmio_base(u64::MAX - 0x1000)
+            .slots(2)
+            .build();
+    }
+
+    #[test]
+    fn test_stress() {
+        let report = stress(&StressConfig {
+            dispatches: 2000,
+            hotplugs: 200,
+            ..Default::default()
+        });
+        assert_eq!(report.dispatched + report.missed, 4 * 2000);
+        assert_eq!(report.plugged + report.unplugged, 200);
+    }
+
+    struct PartialDevice;
+
+    impl DeviceMmio for PartialDevice {
+        fn mmio_read(&self, _base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+            match (offset, data.len()) {
+                (0, 4) => data.copy_from_slice(&[1, 2, 3, 4]),
+                (4, 4) => unimplemented!("register {:#x}", offset),
+                _ => {}
+            }
+        }
+
+        fn mmio_write(&self, _base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+            if data.len() == 1 {
+                panic!("unexpected write at {:#x}", offset);
+            }
+        }
+    }
+
+    #[test]
+    fn test_sweep_mmio() {
+        let range = MmioRange::new(MmioAddress(0x1000), 8).unwrap();
+        let report = sweep_mmio(&PartialDevice, range);
+        // 8 + 4 + 2 + 1 offsets, each read and written.
+        assert_eq!(report.entries.len(), 2 * 15);
+
+        let returned: Vec<_> = report.returned().collect();
+        assert_eq!(returned.len(), 1);
+        assert_eq!(returned[0].direction, Direction::Read);
+        assert_eq!((returned[0].offset, returned[0].width), (0, 4));
+        assert_eq!(
+            returned[0].outcome,
+            SweepOutcome::Returned(vec![1, 2, 3, 4])
+        );
+
+        let panicked: Vec<_> = report
+            .panicked()
+            .map(|entry| (entry.direction, entry.offset, entry.width))
+            .collect();
+        assert_eq!(panicked.len(), 9);
+        assert_eq!(panicked[0], (Direction::Write, 0, 1));
+        assert!(panicked.contains(&(Direction::Read, 4, 4)));
+        assert_eq!(
+            report
+                .panicked()
+                .find(|entry| entry.width == 4)
+                .unwrap()
+                .outcome,
+            SweepOutcome::Panicked("not implemented: register 0x4".to_string())
+        );
+        assert_eq!(report.ignored().count(), 15 - 2);
+    }
+
+    #[test]
+    fn test_sweep_report_json() {
+        let report = SweepReport {
+            entries: vec![
+                SweepEntry {
+                    direction: Direction::Read,
+                    offset: 0,
+                    width: 2,
+                    outcome: SweepOutcome::Returned(vec![1, 2]),
+                },
+                SweepEntry {
+                    direction: Direction::Read,
+                    offset: 2,
+                    width: 2,
+                    outcome: SweepOutcome::Ignored,
+                },
+                SweepEntry {
+                    direction: Direction::Write,
+                    offset: 4,
+                    width: 1,
+                    outcome: SweepOutcome::Panicked("bad \"offset\"".to_string()),
+                },
+                SweepEntry {
+                    direction: Direction::Write,
+                    offset: 5,
+                    width: 1,
+                    outcome: SweepOutcome::Completed,
+                },
+            ],
+        };
+        assert_eq!(
+            report.to_json(),
+            "[{\"direction\":\"read\",\"offset\":0,\"width\":2,\"outcome\":\"returned\",\
+             \"data\":[1,2]},\
+             {\"direction\":\"read\",\"offset\":2,\"width\":2,\"outcome\":\"ignored\"},\
+             {\"direction\":\"write\",\"offset\":4,\"width\":1,\"outcome\":\"panicked\",\
+             \"message\":\"bad \\\"offset\\\"\"},\
+             {\"direction\":\"write\",\"offset\":5,\"width\":1,\"outcome\":\"completed\"}]"
+        );
+    }
+}
@@ -0,0 +1,357 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Rate limiting of the accesses to a device.
+//!
+//! On hosts running many VMs, a guest hammering a device can keep its vCPUs busy emulating
+//! accesses, at the expense of the other tenants. [`Throttled`] wraps a device and bounds
+//! the rate of its accesses, or of the bytes they transfer, with [`TokenBucket`]s: an
+//! access exceeding the budget stalls the vCPU until the buckets are refilled.
+//!
+//! [`IoManager::throttle_mmio`] wraps a device which is already registered:
+//!
+//! ```
+//! # use std::sync::Arc;
+//! # use vm_device::bus::{MmioAddress, MmioAddressOffset};
+//! use vm_device::device_manager::{IoManager, MmioManager};
+//! use vm_device::resources::Resource;
+//! use vm_device::throttle::{Throttle, TokenBucket};
+//! # use vm_device::DeviceMmio;
+//! # struct Rtc;
+//! # impl DeviceMmio for Rtc {
+//! #     fn mmio_read(&self, _: MmioAddress, _: MmioAddressOffset, _: &mut [u8]) {}
+//! #     fn mmio_write(&self, _: MmioAddress, _: MmioAddressOffset, _: &[u8]) {}
+//! # }
+//!
+//! let mut manager = IoManager::new();
+//! let resources = [Resource::MmioAddressRange {
+//!     base: 0x1000,
+//!     size: 0x10,
+//! }];
+//! manager.register_mmio_resources(Arc::new(Rtc), &resources).unwrap();
+//! let throttle = Throttle {
+//!     accesses: Some(TokenBucket::new(100, 10_000)),
+//!     bytes: None,
+//! };
+//! let rtc = manager.throttle_mmio(&resources, throttle).unwrap();
+//! manager.mmio_read(MmioAddress(0x1000), &mut [0; 4]).unwrap();
+//! assert_eq!(rtc.throttled(), 0);
+//! ```
+//!
+//! [`IoManager::throttle_mmio`]: crate::device_manager::IoManager::throttle_mmio
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::bus::{MmioAddress, MmioAddressOffset, PioAddress, PioAddressOffset};
+use crate::completion::Completion;
+use crate::exit::ExitAccess;
+use crate::health::Health;
+use crate::info::DeviceDetails;
+use crate::resources::Resource;
+use crate::stats::Statistics;
+use crate::{DeviceMmio, DevicePio};
+
+/// A budget of tokens, refilled at a constant rate up to its capacity.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TokenBucket {
+    capacity: u64,
+    rate: u64,
+}
+
+impl TokenBucket {
+    /// Create a bucket holding up to `capacity` tokens, the largest burst it allows, and
+    /// refilled with `rate` tokens per second.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `rate` is zero.
+    pub fn new(capacity: u64, rate: u64) -> Self {
+        assert!(rate > 0, "throttle: the refill rate must be positive");
+        TokenBucket { capacity, rate }
+    }
+
+    /// Return the capacity of the bucket.
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    /// Return the number of tokens added to the bucket each second.
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+}
+
+/// Limits applied by a [`Throttled`] device.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Throttle {
+    /// Bucket consumed by one token per access, if any.
+    pub accesses: Option<TokenBucket>,
+    /// Bucket consumed by one token per byte read or written, if any.
+    pub bytes: Option<TokenBucket>,
+}
+
+// Tokens left in a bucket. The balance goes negative when an access takes more tokens than
+// available, and the access then waits for the debt to be refilled.
+struct Balance {
+    bucket: TokenBucket,
+    tokens: f64,
+}
+
+impl Balance {
+    fn new(bucket: TokenBucket) -> Self {
+        Balance {
+            bucket,
+            tokens: bucket.capacity as f64,
+        }
+    }
+
+    // Refill the bucket for `elapsed`, take `cost` tokens, and return how long the access
+    // has to wait for the balance to be positive again.
+    fn take(&mut self, elapsed: Duration, cost: u64) -> Duration {
+        let rate = self.bucket.rate as f64;
+        let refilled = self.tokens + elapsed.as_secs_f64() * rate;
+        self.tokens = refilled.min(self.bucket.capacity as f64) - cost as f64;
+        Duration::from_secs_f64((-self.tokens).max(0.0) / rate)
+    }
+}
+
+struct State {
+    accesses: Option<Balance>,
+    bytes: Option<Balance>,
+    last: Instant,
+}
+
+/// A device wrapper bounding the rate of the accesses to the device with a [`Throttle`].
+///
+/// The accesses going over budget sleep on the thread dispatching them before they are
+/// forwarded to the wrapped device. They are counted by [`Throttled::throttled`]. Debug
+/// reads aren't throttled.
+pub struct Throttled<D> {
+    device: D,
+    state: Mutex<State>,
+    throttled: AtomicU64,
+}
+
+impl<D> Throttled<D> {
+    /// Wrap `device`, limiting its accesses with `throttle`. The buckets start full.
+    pub fn new(device: D, throttle: Throttle) -> Self {
+        Throttled {
+            device,
+            state: Mutex::new(State {
+                accesses: throttle.accesses.map(Balance::new),
+                bytes: throttle.bytes.map(Balance::new),
+                last: Instant::now(),
+            }),
+            throttled: AtomicU64::new(0),
+        }
+    }
+
+    /// Return the wrapped device.
+    pub fn inner(&self) -> &D {
+        &self.device
+    }
+
+    /// Return how many accesses were stalled.
+    pub fn throttled(&self) -> u64 {
+        self.throttled.load(Ordering::Relaxed)
+    }
+
+    // Take the tokens of an access of `len` bytes, and wait until the budget allows it.
+    fn throttle(&self, len: usize) {
+        let delay = {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = now.duration_since(state.last);
+            state.last = now;
+            let accesses = state
+                .accesses
+                .as_mut()
+                .map_or(Duration::ZERO, |balance| balance.take(elapsed, 1));
+            let bytes = state
+                .bytes
+                .as_mut()
+                .map_or(Duration::ZERO, |balance| balance.take(elapsed, len as u64));
+            accesses.max(bytes)
+        };
+        if delay > Duration::ZERO {
+            self.throttled.fetch_add(1, Ordering::Relaxed);
+            thread::sleep(delay);
+        }
+    }
+
+    fn properties(&self, mut properties: Vec<(String, String)>) -> Vec<(String, String)> {
+        properties.push(("throttled".to_string(), self.throttled().to_string()));
+        properties
+    }
+}
+
+impl<D: DeviceMmio> DeviceMmio for Throttled<D> {
+    fn mmio_read(&self, base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+        self.throttle(data.len());
+        self.device.mmio_read(base, offset, data);
+    }
+
+    fn mmio_write(&self, base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+        self.throttle(data.len());
+        self.device.mmio_write(base, offset, data);
+    }
+
+    fn introspect(&self) -> Vec<(String, String)> {
+        self.properties(self.device.introspect())
+    }
+
+    fn info(&self) -> Option<DeviceDetails> {
+        self.device.info()
+    }
+
+    fn health(&self) -> Health {
+        self.device.health()
+    }
+
+    fn stats(&self) -> Option<Statistics> {
+        self.device.stats()
+    }
+
+    fn update_resources(&self, resources: &[Resource]) {
+        self.device.update_resources(resources)
+    }
+
+    fn debug_read(&self, base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) -> bool {
+        self.device.debug_read(base, offset, data)
+    }
+
+    fn mmio_update(
+        &self,
+        base: MmioAddress,
+        offset: MmioAddressOffset,
+        data: &mut [u8],
+        update: &mut dyn FnMut(&mut [u8]),
+    ) {
+        self.throttle(data.len());
+        self.device.mmio_update(base, offset, data, update);
+    }
+
+    fn mmio_deferred(
+        &self,
+        base: MmioAddress,
+        offset: MmioAddressOffset,
+        access: ExitAccess<'_>,
+    ) -> Completion {
+        let len = match &access {
+            ExitAccess::Read(data) => data.len(),
+            ExitAccess::Write(data) => data.len(),
+        };
+        self.throttle(len);
+        self.device.mmio_deferred(base, offset, access)
+    }
+}
+
+impl<D: DevicePio> DevicePio for Throttled<D> {
+    fn pio_read(&self, base: PioAddress, offset: PioAddressOffset, data: &mut [u8]) {
+        self.throttle(data.len());
+        self.device.pio_read(base, offset, data);
+    }
+
+    fn pio_write(&self, base: PioAddress, offset: PioAddressOffset, data: &[u8]) {
+        self.throttle(data.len());
+        self.device.pio_write(base, offset, data);
+    }
+
+    fn introspect(&self) -> Vec<(String, String)> {
+        self.properties(self.device.introspect())
+    }
+
+    fn info(&self) -> Option<DeviceDetails> {
+        self.device.info()
+    }
+
+    fn health(&self) -> Health {
+        self.device.health()
+    }
+
+    fn stats(&self) -> Option<Statistics> {
+        self.device.stats()
+    }
+
+    fn debug_read(&self, base: PioAddress, offset: PioAddressOffset, data: &mut [u8]) -> bool {
+        self.device.debug_read(base, offset, data)
+    }
+
+    fn pio_deferred(
+        &self,
+        base: PioAddress,
+        offset: PioAddressOffset,
+        access: ExitAccess<'_>,
+    ) -> Completion {
+        let len = match &access {
+            ExitAccess::Read(data) => data.len(),
+            ExitAccess::Write(data) => data.len(),
+        };
+        self.throttle(len);
+        self.device.pio_deferred(base, offset, access)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::device_manager::{IoManager, MmioManager};
+    use crate::testing::Scratchpad;
+
+    #[test]
+    fn test_token_bucket() {
+        let ms = Duration::from_millis;
+        let mut balance = Balance::new(TokenBucket::new(4, 1000));
+        assert_eq!(balance.take(ms(0), 4), ms(0));
+        assert_eq!(balance.take(ms(0), 2), ms(2));
+        // The debt is paid back first, and the bucket doesn't overflow.
+        assert_eq!(balance.take(ms(3), 1), ms(0));
+        assert_eq!(balance.take(ms(100), 6), ms(2));
+    }
+
+    #[test]
+    fn test_throttled() {
+        let mut manager = IoManager::new();
+        let resources = [
+            Resource::MmioAddressRange {
+                base: 0x1000,
+                size: 0x10,
+            },
+            Resource::MmioAddressRange {
+                base: 0x2000,
+                size: 0x10,
+            },
+        ];
+        let scratchpad = Arc::new(Scratchpad::new(0x10));
+        manager
+            .register_mmio_resources(scratchpad.clone(), &resources)
+            .unwrap();
+        let throttle = Throttle {
+            accesses: None,
+            bytes: Some(TokenBucket::new(8, 200)),
+        };
+        let device = manager.throttle_mmio(&resources, throttle).unwrap();
+
+        // Both ranges share the budget, and the third access waits for 4 bytes worth of
+        // tokens.
+        let start = Instant::now();
+        manager.mmio_write(MmioAddress(0x1000), &[1; 4]).unwrap();
+        manager.mmio_write(MmioAddress(0x2004), &[2; 4]).unwrap();
+        assert_eq!(device.throttled(), 0);
+        let mut data = [0; 4];
+        manager.mmio_read(MmioAddress(0x2000), &mut data).unwrap();
+        assert_eq!(data, [1; 4]);
+        assert_eq!(device.throttled(), 1);
+        assert!(start.elapsed() >= Duration::from_millis(15));
+        assert_eq!(scratchpad.contents()[4..8], [2; 4]);
+        assert!(
+            DeviceMmio::introspect(&*device).contains(&("throttled".to_string(), "1".to_string()))
+        );
+    }
+}
@@ -0,0 +1,396 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Subscriptions to the changes of the devices registered with a manager.
+//!
+//! Control planes keeping track of the devices of a VM subscribe to its [`IoManager`] with
+//! [`IoManager::subscribe`], and receive a [`TopologyEvent`] for every range registered or
+//! deregistered, device relocated or replaced, and failure reported through
+//! [`IoManager::topology`]. Events are queued per [`Subscription`], which is received from
+//! like a channel, either blocking or as a future usable with any executor, so the
+//! subscribers never hold locks into the manager.
+//!
+//! ```
+//! # use std::sync::Arc;
+//! # use vm_device::bus::{MmioAddress, MmioAddressOffset, MmioRange};
+//! use vm_device::device_manager::{IoManager, MmioManager};
+//! use vm_device::topology::TopologyEvent;
+//! # use vm_device::DeviceMmio;
+//! # struct Rtc;
+//! # impl DeviceMmio for Rtc {
+//! #     fn mmio_read(&self, _: MmioAddress, _: MmioAddressOffset, _: &mut [u8]) {}
+//! #     fn mmio_write(&self, _: MmioAddress, _: MmioAddressOffset, _: &[u8]) {}
+//! # }
+//!
+//! let mut manager = IoManager::new();
+//! let events = manager.subscribe();
+//! let range = MmioRange::new(MmioAddress(0x1000), 0x10).unwrap();
+//! manager.register_mmio(range, Arc::new(Rtc)).unwrap();
+//! assert_eq!(
+//!     events.try_recv(),
+//!     Some(TopologyEvent::Registered {
+//!         bus: "mmio",
+//!         base: 0x1000,
+//!         size: 0x10
+//!     })
+//! );
+//! ```
+//!
+//! [`IoManager`]: crate::device_manager::IoManager
+//! [`IoManager::subscribe`]: crate::device_manager::IoManager::subscribe
+//! [`IoManager::topology`]: crate::device_manager::IoManager::topology
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::task::{Context, Poll, Waker};
+
+use crate::quarantine::Panic;
+
+/// A change of the devices registered with a manager.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum TopologyEvent {
+    /// A range was registered.
+    Registered {
+        /// Bus of the range, `"mmio"` or `"pio"`.
+        bus: &'static str,
+        /// Base address of the range.
+        base: u64,
+        /// Size of the range.
+        size: u64,
+    },
+    /// A range was deregistered.
+    Deregistered {
+        /// Bus of the range, `"mmio"` or `"pio"`.
+        bus: &'static str,
+        /// Base address of the range.
+        base: u64,
+        /// Size of the range.
+        size: u64,
+    },
+    /// The MMIO ranges of a device were moved by `IoManager::update_resources`, after the
+    /// events deregistering the old ranges and registering the new ones.
+    Relocated {
+        /// Base addresses and sizes of the old ranges.
+        old: Vec<(u64, u64)>,
+        /// Base addresses and sizes of the new ranges.
+        new: Vec<(u64, u64)>,
+    },
+    /// The device behind some ranges was replaced, the ranges staying registered.
+    Replaced {
+        /// Bus of the ranges, `"mmio"` or `"pio"`.
+        bus: &'static str,
+        /// Base addresses and sizes of the ranges.
+        ranges: Vec<(u64, u64)>,
+    },
+    /// A device failed, as reported with [`Topology::publish`].
+    Failed {
+        /// Bus of the device, `"mmio"` or `"pio"`.
+        bus: &'static str,
+        /// Base address of the range of the device.
+        base: u64,
+        /// Description of the failure.
+        reason: String,
+    },
+}
+
+impl From<&Panic> for TopologyEvent {
+    fn from(panic: &Panic) -> Self {
+        TopologyEvent::Failed {
+            bus: panic.bus,
+            base: panic.base,
+            reason: panic.to_string(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    events: VecDeque<TopologyEvent>,
+    waker: Option<Waker>,
+    closed: bool,
+}
+
+#[derive(Default)]
+struct Channel {
+    state: Mutex<State>,
+    available: Condvar,
+}
+
+impl Channel {
+    fn update<F: FnOnce(&mut State)>(&self, update: F) {
+        let mut state = self.state.lock().unwrap();
+        update(&mut state);
+        let waker = state.waker.take();
+        drop(state);
+        self.available.notify_all();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+#[derive(Default)]
+struct Hub {
+    subscribers: Mutex<Vec<Weak<Channel>>>,
+}
+
+impl Drop for Hub {
+    fn drop(&mut self) {
+        let subscribers = self.subscribers.get_mut().unwrap();
+        for channel in subscribers.iter().filter_map(Weak::upgrade) {
+            channel.update(|state| state.closed = true);
+        }
+    }
+}
+
+/// Publishes [`TopologyEvent`]s to the subscriptions.
+///
+/// Clones publish to the same subscriptions, which are closed once all the clones are
+/// dropped. The clones of an [`IoManager`](crate::device_manager::IoManager) share their
+/// `Topology`.
+#[derive(Clone, Default)]
+pub struct Topology {
+    hub: Arc<Hub>,
+}
+
+impl Topology {
+    /// Create a topology without subscriptions.
+    pub fn new() -> Self {
+        Topology::default()
+    }
+
+    /// Subscribe to the events published from now on.
+    pub fn subscribe(&self) -> Subscription {
+        let channel = Arc::new(Channel::default());
+        let mut subscribers = self.hub.subscribers.lock().unwrap();
+        subscribers.push(Arc::downgrade(&channel));
+        Subscription { channel }
+    }
+
+    /// Queue `event` on all the subscriptions, e.g. to report the failure of a device.
+    pub fn publish(&self, event: TopologyEvent) {
+        let mut subscribers = self.hub.subscribers.lock().unwrap();
+        // Forget the dropped subscriptions.
+        subscribers.retain(|channel| channel.strong_count() > 0);
+        for channel in subscribers.iter().filter_map(Weak::upgrade) {
+            channel.update(|state| state.events.push_back(event.clone()));
+        }
+    }
+}
+
+/// The events published to a [`Topology`] since the subscription was created, in order.
+pub struct Subscription {
+    channel: Arc<Channel>,
+}
+
+impl Subscription {
+    /// Return the next event, if one is queued.
+    pub fn try_recv(&self) -> Option<TopologyEvent> {
+        self.channel.state.lock().unwrap().events.pop_front()
+    }
+
+    /// Block until an event is queued, and return it. Returns `None` once all the events are
+    /// received and the topology is dropped.
+    pub fn recv(&self) -> Option<TopologyEvent> {
+        let mut state = self.channel.state.lock().unwrap();
+        loop {
+            if let Some(event) = state.events.pop_front() {
+                return Some(event);
+            }
+            if state.closed {
+                return None;
+            }
+            state = self.channel.available.wait(state).unwrap();
+        }
+    }
+
+    /// Return a future resolving to the next event, or to `None` once all the events are
+    /// received and the topology is dropped.
+    pub fn changed(&mut self) -> Changed<'_> {
+        Changed { subscription: self }
+    }
+}
+
+/// Future returned by [`Subscription::changed`].
+pub struct Changed<'a> {
+    subscription: &'a mut Subscription,
+}
+
+impl Future for Changed<'_> {
+    type Output = Option<TopologyEvent>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.subscription.channel.state.lock().unwrap();
+        match state.events.pop_front() {
+            Some(event) => Poll::Ready(Some(event)),
+            None if state.closed => Poll::Ready(None),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::Wake;
+    use std::thread;
+
+    use crate::bus::{MmioAddress, MmioRange, PioAddress, PioRange};
+    use crate::device_manager::{IoManager, MmioManager, PioManager};
+    use crate::quarantine::Quarantined;
+    use crate::resources::Resource;
+    use crate::testing::Scratchpad;
+
+    struct Counter(AtomicUsize);
+
+    impl Wake for Counter {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn range(base: u64) -> Resource {
+        Resource::MmioAddressRange { base, size: 0x10 }
+    }
+
+    #[test]
+    fn test_topology_events() {
+        let mut manager = IoManager::new();
+        let events = manager.subscribe();
+        let device = Arc::new(Scratchpad::new(0x10));
+        manager
+            .register_resources(
+                device,
+                &[
+                    range(0x1000),
+                    Resource::PioAddressRange {
+                        base: 0x60,
+                        size: 4,
+                    },
+                ],
+            )
+            .unwrap();
+        manager
+            .update_resources(&[range(0x1000)], &[range(0x2000)])
+            .unwrap();
+        manager
+            .replace_mmio(MmioAddress(0x2000), Arc::new(Scratchpad::new(0x10)))
+            .unwrap();
+        manager.deregister_pio(PioAddress(0x60)).unwrap();
+        assert!(manager
+            .register_mmio(
+                MmioRange::new(MmioAddress(0x2008), 0x10).unwrap(),
+                Arc::new(Scratchpad::new(0x10))
+            )
+            .is_err());
+
+        let mmio = |base| (base, 0x10);
+        let received: Vec<_> = std::iter::from_fn(|| events.try_recv()).collect();
+        assert_eq!(
+            received,
+            [
+                TopologyEvent::Registered {
+                    bus: "mmio",
+                    base: 0x1000,
+                    size: 0x10
+                },
+                TopologyEvent::Registered {
+                    bus: "pio",
+                    base: 0x60,
+                    size: 4
+                },
+                TopologyEvent::Deregistered {
+                    bus: "mmio",
+                    base: 0x1000,
+                    size: 0x10
+                },
+                TopologyEvent::Registered {
+                    bus: "mmio",
+                    base: 0x2000,
+                    size: 0x10
+                },
+                TopologyEvent::Relocated {
+                    old: vec![mmio(0x1000)],
+                    new: vec![mmio(0x2000)]
+                },
+                TopologyEvent::Replaced {
+                    bus: "mmio",
+                    ranges: vec![mmio(0x2000)]
+                },
+                TopologyEvent::Deregistered {
+                    bus: "pio",
+                    base: 0x60,
+                    size: 4
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_subscription() {
+        let topology = Topology::new();
+        let mut first = topology.subscribe();
+        let dropped = topology.subscribe();
+        drop(dropped);
+
+        // Failures are published by the VMM, e.g. from the handler of a quarantined device.
+        let publisher = topology.clone();
+        let device = Arc::new(
+            Quarantined::new(Scratchpad::new(4))
+                .with_handler(move |panic| publisher.publish(panic.into())),
+        );
+        let mut manager = IoManager::new();
+        let range = PioRange::new(PioAddress(0x60), 4).unwrap();
+        manager.register_pio(range, device).unwrap();
+        let second = topology.subscribe();
+        let event = TopologyEvent::Failed {
+            bus: "pio",
+            base: 0x60,
+            reason: "device reset".to_string(),
+        };
+        topology.publish(event.clone());
+        assert_eq!(topology.hub.subscribers.lock().unwrap().len(), 2);
+
+        // The future is woken up by a publisher on another thread.
+        let counter = Arc::new(Counter(AtomicUsize::new(0)));
+        let waker = Waker::from(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+        let mut changed = first.changed();
+        assert_eq!(
+            Pin::new(&mut changed).poll(&mut cx),
+            Poll::Ready(Some(event.clone()))
+        );
+        let mut changed = first.changed();
+        assert_eq!(Pin::new(&mut changed).poll(&mut cx), Poll::Pending);
+        let publisher = topology.clone();
+        thread::spawn(move || {
+            publisher.publish(TopologyEvent::Registered {
+                bus: "mmio",
+                base: 0,
+                size: 1,
+            })
+        })
+        .join()
+        .unwrap();
+        assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+        assert!(matches!(
+            Pin::new(&mut changed).poll(&mut cx),
+            Poll::Ready(Some(TopologyEvent::Registered { .. }))
+        ));
+
+        assert_eq!(second.recv(), Some(event));
+        // The subscriptions are closed once the handler holding the last clone is dropped.
+        drop(topology);
+        drop(manager);
+        assert!(second.recv().is_some());
+        assert_eq!(second.recv(), None);
+        assert_eq!(first.try_recv(), None);
+    }
+}
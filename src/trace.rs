@@ -0,0 +1,276 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Instrumentation used internally by the crate.
+//!
+//! With the `tracing` feature, registrations, dispatched accesses, interrupts and hotplug
+//! events are reported through [`tracing`](https://docs.rs/tracing) with the `vm_device`
+//! target: dispatched accesses at the `TRACE` level, failed ones and everything else at the
+//! `DEBUG` level. Without the feature, the helpers below compile to nothing.
+//!
+//! With the `log` feature, the situations in which the crate skips something or drops an
+//! error, e.g. resources the [`IoManager`](crate::device_manager::IoManager) doesn't handle
+//! or cleanup failures, are reported as warnings through [`log`](https://docs.rs/log) with
+//! the `vm_device` target.
+
+use crate::bus;
+#[cfg(feature = "std")]
+use crate::resources::Resource;
+
+/// A span entered until dropped, or nothing without the `tracing` feature.
+#[cfg(feature = "std")]
+pub(crate) struct Span {
+    #[cfg(feature = "tracing")]
+    pub(crate) _span: tracing::span::EnteredSpan,
+}
+
+// Enter a `DEBUG` span called `$name`.
+#[cfg(feature = "std")]
+macro_rules! span {
+    ($name:literal) => {
+        $crate::trace::Span {
+            #[cfg(feature = "tracing")]
+            _span: tracing::debug_span!(target: "vm_device", $name).entered(),
+        }
+    };
+}
+#[cfg(feature = "std")]
+pub(crate) use span;
+
+/// Report the registration of `size` bytes at `base` on `bus`.
+#[inline]
+pub(crate) fn register(bus: &'static str, base: u64, size: u64, result: &Result<(), bus::Error>) {
+    #[cfg(feature = "tracing")]
+    match result {
+        Ok(()) => tracing::debug!(target: "vm_device", bus, base, size, "registered"),
+        Err(e) => {
+            tracing::debug!(target: "vm_device", bus, base, size, error = %e, "registration failed")
+        }
+    }
+    #[cfg(not(feature = "tracing"))]
+    let _ = (bus, base, size, result);
+}
+
+/// Report the deregistration of the range at `addr` on `bus`.
+#[inline]
+pub(crate) fn deregister(bus: &'static str, addr: u64, found: bool) {
+    #[cfg(feature = "tracing")]
+    tracing::debug!(target: "vm_device", bus, addr, found, "deregistered");
+    #[cfg(not(feature = "tracing"))]
+    let _ = (bus, addr, found);
+}
+
+/// Report an access of `len` bytes at `addr` on `bus`, returning `result`.
+#[inline]
+pub(crate) fn dispatch<T>(
+    bus: &'static str,
+    op: &'static str,
+    addr: u64,
+    len: usize,
+    result: Result<T, bus::Error>,
+) -> Result<T, bus::Error> {
+    #[cfg(feature = "tracing")]
+    match &result {
+        Ok(_) => tracing::trace!(target: "vm_device", bus, op, addr, len, "dispatched"),
+        Err(e) => tracing::debug!(target: "vm_device", bus, op, addr, len, error = %e, "unhandled"),
+    }
+    #[cfg(not(feature = "tracing"))]
+    let _ = (bus, op, addr, len);
+    result
+}
+
+/// Report that `source` raised its interrupt, returning `result`.
+#[cfg(feature = "std")]
+#[inline]
+pub(crate) fn interrupt<T, E: core::fmt::Display>(
+    source: &'static str,
+    result: Result<T, E>,
+) -> Result<T, E> {
+    #[cfg(feature = "tracing")]
+    match &result {
+        Ok(_) => tracing::debug!(target: "vm_device", source, "interrupt raised"),
+        Err(e) => tracing::debug!(target: "vm_device", source, error = %e, "interrupt failed"),
+    }
+    #[cfg(not(feature = "tracing"))]
+    let _ = source;
+    result
+}
+
+/// Report a device being added to or removed from hotplug `slot`.
+#[cfg(feature = "std")]
+#[inline]
+pub(crate) fn hotplug(slot: u32, added: bool) {
+    #[cfg(feature = "tracing")]
+    tracing::debug!(target: "vm_device", slot, added, "hotplug");
+    #[cfg(not(feature = "tracing"))]
+    let _ = (slot, added);
+}
+
+/// Warn that `op` skips `resource`, which it doesn't handle.
+#[cfg(feature = "std")]
+#[inline]
+pub(crate) fn ignored(op: &'static str, resource: &Resource) {
+    #[cfg(feature = "log")]
+    log::warn!(target: "vm_device", "{}: ignoring unsupported resource {:?}", op, resource);
+    #[cfg(not(feature = "log"))]
+    let _ = (op, resource);
+}
+
+/// Warn that an access to a device failed with `error`.
+#[cfg(feature = "std")]
+#[inline]
+pub(crate) fn device_failed(error: &dyn core::fmt::Display) {
+    #[cfg(feature = "log")]
+    log::warn!(target: "vm_device", "{}", error);
+    #[cfg(not(feature = "log"))]
+    let _ = error;
+}
+
+/// Warn that a device panicked during an access, poisoning its lock.
+#[cfg(feature = "std")]
+#[inline]
+pub(crate) fn poisoned() {
+    #[cfg(feature = "log")]
+    log::warn!(target: "vm_device", "device panicked during an access, recovering its lock");
+}
+
+/// Warn that cleaning up after a failure of `op` failed with `error`.
+#[inline]
+#[cfg(feature = "kvm")]
+pub(crate) fn cleanup_failed(op: &'static str, error: &dyn core::fmt::Display) {
+    #[cfg(feature = "log")]
+    log::warn!(target: "vm_device", "{}: cleanup failed: {}", op, error);
+    #[cfg(not(feature = "log"))]
+    let _ = (op, error);
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    use crate::bus::{MmioAddress, MmioRange};
+    use crate::device_manager::{IoManager, MmioManager};
+    use crate::testing::MockDevice;
+
+    // Subscriber recording the messages of the events, and the names of the spans.
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    struct Message<'a>(&'a mut String);
+
+    impl tracing::field::Visit for Message<'_> {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            if field.name() == "message" {
+                *self.0 = format!("{:?}", value);
+            }
+        }
+    }
+
+    impl Subscriber for Recorder {
+        fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+            metadata.target() == "vm_device"
+        }
+
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            self.0
+                .lock()
+                .unwrap()
+                .push(span.metadata().name().to_string());
+            Id::from_u64(1)
+        }
+
+        fn record(&self, _span: &Id, _values: &Record<'_>) {}
+
+        fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            let mut message = String::new();
+            event.record(&mut Message(&mut message));
+            self.0.lock().unwrap().push(message);
+        }
+
+        fn enter(&self, _span: &Id) {}
+
+        fn exit(&self, _span: &Id) {}
+    }
+
+    #[test]
+    fn test_tracing() {
+        let recorder = Recorder::default();
+        tracing::subscriber::with_default(recorder.clone(), || {
+            let mut manager = IoManager::new();
+            let range = MmioRange::new(MmioAddress(0x1000), 0x10).unwrap();
+            manager
+                .register_mmio(range, Arc::new(MockDevice::new()))
+                .unwrap();
+            manager.mmio_write(MmioAddress(0x1000), &[0]).unwrap();
+            assert!(manager.mmio_read(MmioAddress(0x2000), &mut [0]).is_err());
+            manager.deregister_resources(&[crate::resources::Resource::MmioAddressRange {
+                base: 0x1000,
+                size: 0x10,
+            }]);
+        });
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            [
+                "registered",
+                "dispatched",
+                "unhandled",
+                "deregister_resources",
+                "deregistered"
+            ]
+        );
+    }
+}
+
+#[cfg(all(test, feature = "log"))]
+mod log_tests {
+    use std::sync::{Arc, Mutex};
+
+    use log::{Level, Log, Metadata, Record};
+
+    use crate::device_manager::IoManager;
+    use crate::resources::Resource;
+    use crate::testing::MockDevice;
+
+    // Logger recording the warnings of the crate.
+    static WARNINGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    struct Logger;
+
+    impl Log for Logger {
+        fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+            metadata.target() == "vm_device" && metadata.level() <= Level::Warn
+        }
+
+        fn log(&self, record: &Record<'_>) {
+            if self.enabled(record.metadata()) {
+                WARNINGS.lock().unwrap().push(record.args().to_string());
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn test_log_ignored() {
+        log::set_logger(&Logger).unwrap();
+        log::set_max_level(log::LevelFilter::Warn);
+
+        let mut manager = IoManager::new();
+        let resources = [Resource::LegacyIrq(5)];
+        manager
+            .register_mmio_resources(Arc::new(MockDevice::new()), &resources)
+            .unwrap();
+        manager.deregister_resources(&resources);
+        let warnings = WARNINGS.lock().unwrap();
+        assert!(warnings.contains(
+            &"register_mmio_resources: ignoring unsupported resource LegacyIrq(5)".into()
+        ));
+        assert!(warnings
+            .contains(&"deregister_resources: ignoring unsupported resource LegacyIrq(5)".into()));
+    }
+}
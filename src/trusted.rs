@@ -0,0 +1,217 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Dispatch through a fixed MMIO layout.
+//!
+//! VMMs with a device topology known at build time don't need the registration machinery of
+//! [`IoManager`](crate::device_manager::IoManager). A [`TrustedLayout`] is a table of `N`
+//! ranges and devices, checked once when it's built, which can be evaluated at compile time
+//! and stored in a `static`. Dispatch only looks the address up: accesses are trusted not to
+//! run past the end of the range they start in, and there's no quiescing or hotplug.
+//!
+//! # Example
+//!
+//! ```
+//! # use vm_device::bus::{MmioAddress, MmioAddressOffset};
+//! # use vm_device::DeviceMmio;
+//! use vm_device::trusted::TrustedLayout;
+//!
+//! struct Transport;
+//!
+//! impl DeviceMmio for Transport {
+//!     fn mmio_read(&self, _base: MmioAddress, _offset: MmioAddressOffset, _data: &mut [u8]) {}
+//!     fn mmio_write(&self, _base: MmioAddress, _offset: MmioAddressOffset, _data: &[u8]) {}
+//! }
+//!
+//! static NET: Transport = Transport;
+//! static BLOCK: Transport = Transport;
+//! // An invalid layout fails to compile.
+//! static LAYOUT: TrustedLayout<2> = TrustedLayout::new([
+//!     (MmioAddress(0xd000_0000), 0x200, &NET),
+//!     (MmioAddress(0xd000_1000), 0x200, &BLOCK),
+//! ]);
+//!
+//! LAYOUT.mmio_write(MmioAddress(0xd000_1050), &[1]).unwrap();
+//! assert!(LAYOUT.mmio_write(MmioAddress(0xd000_0200), &[1]).is_err());
+//! ```
+
+use crate::bus::{self, MmioAddress, MmioAddressOffset};
+use crate::DeviceMmio;
+
+/// A device of a [`TrustedLayout`], with the base and size of its range.
+pub type TrustedEntry<'a> = (MmioAddress, MmioAddressOffset, &'a (dyn DeviceMmio + Sync));
+
+/// A fixed table of `N` MMIO ranges and the devices handling them.
+#[derive(Clone, Copy)]
+pub struct TrustedLayout<'a, const N: usize> {
+    entries: [TrustedEntry<'a>; N],
+}
+
+impl<'a, const N: usize> TrustedLayout<'a, N> {
+    /// Create a layout from ranges given by their base and size, and their devices.
+    ///
+    /// # Panics
+    ///
+    /// Panics, or fails to compile when evaluated in a constant context, if a range is empty,
+    /// ends past the end of the address space, or doesn't start after the end of the previous
+    /// range.
+    pub const fn new(entries: [TrustedEntry<'a>; N]) -> Self {
+        let mut idx = 0;
+        while idx < N {
+            let (base, size, _) = entries[idx];
+            assert!(size > 0, "empty range");
+            assert!(base.0.checked_add(size - 1).is_some(), "range overflows");
+            if idx > 0 {
+                let (prev, prev_size, _) = entries[idx - 1];
+                assert!(
+                    base.0 > prev.0 + (prev_size - 1),
+                    "ranges are unsorted or overlap"
+                );
+            }
+            idx += 1;
+        }
+        TrustedLayout { entries }
+    }
+
+    // Return the index of the range starting at or before `addr`, which is one past the
+    // index of that range.
+    #[inline]
+    fn position(&self, addr: MmioAddress) -> usize {
+        self.entries
+            .partition_point(|(base, _, _)| base.0 <= addr.0)
+    }
+
+    #[inline]
+    fn find(
+        &self,
+        addr: MmioAddress,
+    ) -> Option<(MmioAddress, MmioAddressOffset, &'a dyn DeviceMmio)> {
+        let (base, size, device) = *self.entries.get(self.position(addr).checked_sub(1)?)?;
+        let offset = addr.0 - base.0;
+        if offset < size {
+            Some((base, offset, device))
+        } else {
+            None
+        }
+    }
+
+    /// Dispatch a read of `data.len()` bytes at `addr` to the device whose range contains
+    /// `addr`.
+    #[inline]
+    pub fn mmio_read(&self, addr: MmioAddress, data: &mut [u8]) -> Result<(), bus::Error> {
+        let (base, offset, device) = self
+            .find(addr)
+            .ok_or_else(|| bus::Error::not_found(addr, data.len()))?;
+        device.mmio_read(base, offset, data);
+        Ok(())
+    }
+
+    /// Dispatch a write of `data` at `addr` to the device whose range contains `addr`.
+    #[inline]
+    pub fn mmio_write(&self, addr: MmioAddress, data: &[u8]) -> Result<(), bus::Error> {
+        let (base, offset, device) = self
+            .find(addr)
+            .ok_or_else(|| bus::Error::not_found(addr, data.len()))?;
+        device.mmio_write(base, offset, data);
+        Ok(())
+    }
+
+    /// Dispatch a read without checking that `addr` is handled by a device.
+    ///
+    /// # Safety
+    ///
+    /// `addr` must lie within one of the ranges of the layout.
+    #[inline]
+    pub unsafe fn mmio_read_unchecked(&self, addr: MmioAddress, data: &mut [u8]) {
+        let (base, _, device) = *self.entries.get_unchecked(self.position(addr) - 1);
+        device.mmio_read(base, addr.0 - base.0, data);
+    }
+
+    /// Dispatch a write without checking that `addr` is handled by a device.
+    ///
+    /// # Safety
+    ///
+    /// `addr` must lie within one of the ranges of the layout.
+    #[inline]
+    pub unsafe fn mmio_write_unchecked(&self, addr: MmioAddress, data: &[u8]) {
+        let (base, _, device) = *self.entries.get_unchecked(self.position(addr) - 1);
+        device.mmio_write(base, addr.0 - base.0, data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    type Access = (MmioAddress, MmioAddressOffset, Vec<u8>);
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<Access>>);
+
+    impl DeviceMmio for Recorder {
+        fn mmio_read(&self, _base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+            data.fill(offset as u8);
+        }
+
+        fn mmio_write(&self, base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+            self.0.lock().unwrap().push((base, offset, data.to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_trusted_layout() {
+        let first = Recorder::default();
+        let second = Recorder::default();
+        let layout = TrustedLayout::new([
+            (MmioAddress(0x1000), 0x100, &first),
+            (MmioAddress(0x1100), 0x10, &second),
+        ]);
+
+        layout.mmio_write(MmioAddress(0x10ff), &[1]).unwrap();
+        layout.mmio_write(MmioAddress(0x1100), &[2]).unwrap();
+        unsafe { layout.mmio_write_unchecked(MmioAddress(0x1108), &[3, 4]) };
+        for addr in [0xfff, 0x1110, u64::MAX] {
+            assert_eq!(
+                layout.mmio_write(MmioAddress(addr), &[0]).unwrap_err(),
+                bus::Error::DeviceNotFound { addr, len: 1 }
+            );
+        }
+        assert_eq!(
+            *first.0.lock().unwrap(),
+            vec![(MmioAddress(0x1000), 0xff, vec![1])]
+        );
+        assert_eq!(
+            *second.0.lock().unwrap(),
+            vec![
+                (MmioAddress(0x1100), 0, vec![2]),
+                (MmioAddress(0x1100), 8, vec![3, 4])
+            ]
+        );
+
+        let mut data = [0; 2];
+        layout.mmio_read(MmioAddress(0x1004), &mut data).unwrap();
+        assert_eq!(data, [4; 2]);
+        unsafe { layout.mmio_read_unchecked(MmioAddress(0x110f), &mut data) };
+        assert_eq!(data, [0xf; 2]);
+        assert!(layout.mmio_read(MmioAddress(0), &mut data).is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "ranges are unsorted or overlap")]
+    fn test_trusted_layout_overlap() {
+        let device = Recorder::default();
+        TrustedLayout::new([
+            (MmioAddress(0x1000), 0x100, &device),
+            (MmioAddress(0x10ff), 0x10, &device),
+        ]);
+    }
+
+    #[test]
+    #[should_panic(expected = "range overflows")]
+    fn test_trusted_layout_overflow() {
+        let device = Recorder::default();
+        TrustedLayout::new([(MmioAddress(u64::MAX), 2, &device)]);
+    }
+}
@@ -0,0 +1,157 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Forwarding of accesses to VFIO device regions.
+//!
+//! VFIO exposes the regions of a passthrough device (e.g. the MMIO registers of a platform
+//! device) at fixed offsets of the device file descriptor, as reported by
+//! `VFIO_DEVICE_GET_REGION_INFO`. [`VfioRegionDevice`] forwards the accesses to its range with
+//! `pread`/`pwrite` at the matching offsets, so passthrough devices can sit on the same bus as
+//! emulated ones.
+//!
+//! Regions which support `mmap` are usually mapped into the guest directly instead, leaving
+//! only the regions (or parts of them) which must be trapped to this device.
+
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::bus::{MmioAddress, MmioAddressOffset, PioAddress, PioAddressOffset};
+use crate::{DeviceMmio, DevicePio};
+
+/// A device forwarding accesses to a region of a VFIO device.
+///
+/// Offset 0 of the bus range maps to the start of the region. Accesses which fail, or fall
+/// outside of the region, are counted as errors; failed reads return all ones, like reads of
+/// unbacked addresses on real hardware.
+pub struct VfioRegionDevice {
+    file: File,
+    offset: u64,
+    size: u64,
+    errors: AtomicU64,
+}
+
+impl VfioRegionDevice {
+    /// Forward accesses to the region of `size` bytes at `offset` in the VFIO device file
+    /// `file`.
+    pub fn new(file: File, offset: u64, size: u64) -> Self {
+        VfioRegionDevice {
+            file,
+            offset,
+            size,
+            errors: AtomicU64::new(0),
+        }
+    }
+
+    /// Return the number of accesses which failed or fell outside of the region.
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    // Return the file offset of an access at `offset` of `len` bytes, if it fits within the
+    // region.
+    fn file_offset(&self, offset: u64, len: usize) -> Option<u64> {
+        offset
+            .checked_add(len as u64)
+            .filter(|&end| end <= self.size)
+            .map(|_| self.offset + offset)
+    }
+
+    fn read(&self, offset: u64, data: &mut [u8]) {
+        let result = self
+            .file_offset(offset, data.len())
+            .map(|offset| self.file.read_exact_at(data, offset));
+        if !matches!(result, Some(Ok(()))) {
+            data.fill(0xff);
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn write(&self, offset: u64, data: &[u8]) {
+        let result = self
+            .file_offset(offset, data.len())
+            .map(|offset| self.file.write_all_at(data, offset));
+        if !matches!(result, Some(Ok(()))) {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl DeviceMmio for VfioRegionDevice {
+    fn mmio_read(&self, _base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+        self.read(offset, data);
+    }
+
+    fn mmio_write(&self, _base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+        self.write(offset, data);
+    }
+
+    fn introspect(&self) -> Vec<(String, String)> {
+        vec![
+            ("region_offset".to_string(), format!("{:#x}", self.offset)),
+            ("region_size".to_string(), format!("{:#x}", self.size)),
+        ]
+    }
+}
+
+impl DevicePio for VfioRegionDevice {
+    fn pio_read(&self, _base: PioAddress, offset: PioAddressOffset, data: &mut [u8]) {
+        self.read(u64::from(offset), data);
+    }
+
+    fn pio_write(&self, _base: PioAddress, offset: PioAddressOffset, data: &[u8]) {
+        self.write(u64::from(offset), data);
+    }
+
+    fn introspect(&self) -> Vec<(String, String)> {
+        DeviceMmio::introspect(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::{self, OpenOptions};
+    use std::sync::Arc;
+
+    use crate::bus::MmioRange;
+    use crate::device_manager::{IoManager, MmioManager};
+
+    #[test]
+    fn test_vfio_region_device() {
+        // A regular file stands in for the VFIO device, with the region at offset 0x10.
+        let path = std::env::temp_dir().join(format!("vm-device-vfio-{}", std::process::id()));
+        fs::write(&path, (0..0x30).collect::<Vec<u8>>()).unwrap();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let device = Arc::new(VfioRegionDevice::new(file, 0x10, 0x10));
+        let mut manager = IoManager::new();
+        let range = MmioRange::new(MmioAddress(0x1000), 0x20).unwrap();
+        manager.register_mmio(range, device.clone()).unwrap();
+
+        let mut data = [0; 4];
+        manager.mmio_read(MmioAddress(0x1004), &mut data).unwrap();
+        assert_eq!(data, [0x14, 0x15, 0x16, 0x17]);
+        manager
+            .mmio_write(MmioAddress(0x100e), &[0xaa, 0xbb])
+            .unwrap();
+        manager.mmio_read(MmioAddress(0x100c), &mut data).unwrap();
+        assert_eq!(data, [0x1c, 0x1d, 0xaa, 0xbb]);
+        assert_eq!(device.errors(), 0);
+
+        // The bus range is larger than the region.
+        manager.mmio_read(MmioAddress(0x100e), &mut data).unwrap();
+        assert_eq!(data, [0xff; 4]);
+        manager.mmio_write(MmioAddress(0x1010), &[0]).unwrap();
+        assert_eq!(device.errors(), 2);
+
+        device.pio_read(PioAddress(0x60), 0, &mut data);
+        assert_eq!(data, [0x10, 0x11, 0x12, 0x13]);
+    }
+}
@@ -0,0 +1,1575 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! virtio-mmio transport for the reference virtio devices (`virtio` feature).
+//!
+//! [`MmioTransport`] serves the registers of the virtio-mmio transport (version 2) on
+//! behalf of a [`VirtioDevice`]: feature negotiation, device status, the configuration of
+//! the split virtqueues, notifications and interrupts. The device only describes itself,
+//! serves its configuration space, and processes its [`Queue`]s when the guest notifies
+//! them:
+//!
+//! ```ignore
+//! manager.register_mmio_dma(range, |memory| {
+//!     Arc::new(Mutex::new(MmioTransport::new(memory, irq, RngDevice::new(entropy))))
+//! })?;
+//! ```
+//!
+//! Queues are processed synchronously, when the guest writes to `QueueNotify` or when the
+//! VMM calls [`MmioTransport::process_queue`], e.g. once a backend has data for the guest.
+//! If the guest hands over an invalid queue, the transport stops processing queues and sets
+//! `DEVICE_NEEDS_RESET` ([`STATUS_NEEDS_RESET`]) in the device status.
+//!
+//! The transport tolerates the accesses breaking the virtio-mmio rules, like most
+//! hypervisors. [`MmioTransport::with_strict_mode`] rejects them instead, and reports each
+//! [`Violation`] to a handler, e.g. while bringing up a new driver.
+
+use std::convert::TryFrom;
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{fence, Ordering};
+use std::sync::Arc;
+
+use crate::bus::{MmioAddress, MmioAddressOffset};
+use crate::dma::{self, DmaMemory};
+use crate::info::{DeviceDetails, DeviceInfo};
+use crate::interrupt::Interrupt;
+use crate::trace;
+use crate::{DeviceMmio, MutDeviceMmio};
+
+/// Size of the register block of a virtio-mmio device, including its configuration space.
+pub const MMIO_SIZE: u64 = 0x200;
+
+/// Feature bit of devices compliant with virtio 1.0 and later, always offered.
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// Device status bit set when the device stopped processing its queues.
+pub const STATUS_NEEDS_RESET: u32 = 0x40;
+
+// Registers of the virtio-mmio transport.
+pub(crate) const MAGIC_VALUE: u64 = 0x00;
+pub(crate) const VERSION: u64 = 0x04;
+pub(crate) const DEVICE_ID: u64 = 0x08;
+pub(crate) const VENDOR_ID: u64 = 0x0c;
+pub(crate) const DEVICE_FEATURES: u64 = 0x10;
+pub(crate) const DEVICE_FEATURES_SEL: u64 = 0x14;
+pub(crate) const DRIVER_FEATURES: u64 = 0x20;
+pub(crate) const DRIVER_FEATURES_SEL: u64 = 0x24;
+pub(crate) const QUEUE_SEL: u64 = 0x30;
+pub(crate) const QUEUE_NUM_MAX: u64 = 0x34;
+pub(crate) const QUEUE_NUM: u64 = 0x38;
+pub(crate) const QUEUE_READY: u64 = 0x44;
+pub(crate) const QUEUE_NOTIFY: u64 = 0x50;
+pub(crate) const INTERRUPT_STATUS: u64 = 0x60;
+pub(crate) const INTERRUPT_ACK: u64 = 0x64;
+pub(crate) const STATUS: u64 = 0x70;
+pub(crate) const QUEUE_DESC_LOW: u64 = 0x80;
+pub(crate) const QUEUE_DESC_HIGH: u64 = 0x84;
+pub(crate) const QUEUE_DRIVER_LOW: u64 = 0x90;
+pub(crate) const QUEUE_DRIVER_HIGH: u64 = 0x94;
+pub(crate) const QUEUE_DEVICE_LOW: u64 = 0xa0;
+pub(crate) const QUEUE_DEVICE_HIGH: u64 = 0xa4;
+pub(crate) const CONFIG_GENERATION: u64 = 0xfc;
+pub(crate) const CONFIG: u64 = 0x100;
+
+// "virt" in little endian.
+pub(crate) const MAGIC: u32 = 0x7472_6976;
+
+pub(crate) const STATUS_DRIVER_OK: u32 = 0x4;
+pub(crate) const STATUS_FEATURES_OK: u32 = 0x8;
+
+pub(crate) const INT_USED_RING: u32 = 0x1;
+pub(crate) const INT_CONFIG: u32 = 0x2;
+
+pub(crate) const DESC_F_NEXT: u16 = 0x1;
+pub(crate) const DESC_F_WRITE: u16 = 0x2;
+pub(crate) const AVAIL_F_NO_INTERRUPT: u16 = 0x1;
+
+/// Errors encountered while processing a queue, all caused by the guest, or while restoring
+/// the state of a transport.
+#[derive(Debug)]
+pub enum Error {
+    /// The queue size isn't a power of two up to the maximum size of the queue.
+    InvalidQueueSize(u16),
+    /// A descriptor chain loops, or references a descriptor out of the table.
+    InvalidChain(u16),
+    /// Accessing the queue or a buffer failed.
+    Dma(dma::Error),
+    /// The restored state doesn't have as many queues as the device.
+    QueueCount {
+        /// Number of queues of the device.
+        expected: usize,
+        /// Number of queues in the restored state.
+        found: usize,
+    },
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::InvalidQueueSize(size) => write!(f, "virtio: invalid queue size {}", size),
+            Error::InvalidChain(head) => write!(f, "virtio: invalid descriptor chain {}", head),
+            Error::Dma(_) => write!(f, "virtio: guest memory access failed"),
+            Error::QueueCount { expected, found } => write!(
+                f,
+                "virtio: state of {} queues restored on a device with {}",
+                found, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Dma(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<dma::Error> for Error {
+    fn from(e: dma::Error) -> Self {
+        Error::Dma(e)
+    }
+}
+
+/// An access to the transport registers breaking the virtio-mmio rules, reported in strict
+/// mode.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Violation {
+    /// An access to a register at an offset which isn't 32 bit aligned.
+    Misaligned {
+        /// Offset of the access.
+        offset: u64,
+        /// Length of the access.
+        len: usize,
+    },
+    /// An access to a register which isn't 32 bit wide.
+    InvalidWidth {
+        /// Offset of the access.
+        offset: u64,
+        /// Length of the access.
+        len: usize,
+    },
+    /// An access to the configuration space which isn't allowed by its [`ConfigAccess`].
+    ConfigAccess {
+        /// Offset of the access in the configuration space.
+        offset: u64,
+        /// Length of the access.
+        len: usize,
+    },
+    /// A write to a read-only register.
+    ReadOnly(u64),
+    /// A read of a write-only register.
+    WriteOnly(u64),
+    /// An access to an offset which isn't a register.
+    Reserved(u64),
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Violation::Misaligned { offset, len } => write!(
+                f,
+                "virtio: misaligned {} byte access to register {:#x}",
+                len, offset
+            ),
+            Violation::InvalidWidth { offset, len } => write!(
+                f,
+                "virtio: invalid {} byte access to register {:#x}",
+                len, offset
+            ),
+            Violation::ConfigAccess { offset, len } => write!(
+                f,
+                "virtio: invalid {} byte access to configuration offset {:#x}",
+                len, offset
+            ),
+            Violation::ReadOnly(offset) => {
+                write!(f, "virtio: write to read-only register {:#x}", offset)
+            }
+            Violation::WriteOnly(offset) => {
+                write!(f, "virtio: read of write-only register {:#x}", offset)
+            }
+            Violation::Reserved(offset) => {
+                write!(f, "virtio: access to reserved offset {:#x}", offset)
+            }
+        }
+    }
+}
+
+/// Accesses to the configuration space of the device accepted in strict mode.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ConfigAccess {
+    /// Accesses of any width and alignment.
+    #[default]
+    Any,
+    /// 1, 2 and 4 byte accesses aligned to their width, as required by the virtio spec.
+    Natural,
+    /// 32 bit aligned accesses only, like the transport registers.
+    Aligned32,
+}
+
+impl ConfigAccess {
+    fn allows(self, offset: u64, len: usize) -> bool {
+        match self {
+            ConfigAccess::Any => true,
+            ConfigAccess::Natural => matches!(len, 1 | 2 | 4) && offset.is_multiple_of(len as u64),
+            ConfigAccess::Aligned32 => len == 4 && offset.is_multiple_of(4),
+        }
+    }
+}
+
+/// Byte order of the guest, used for the transport registers and the configuration space.
+///
+/// Virtio 1.0 devices are little endian whatever the guest, but big endian guests running
+/// legacy drivers, e.g. on ppc64, expect their native byte order. The virtqueues are always
+/// little endian.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Endian {
+    /// Little endian, as required by virtio 1.0.
+    #[default]
+    Little,
+    /// Big endian.
+    Big,
+}
+
+impl Endian {
+    /// Encode `value` in this byte order.
+    pub fn encode_u16(self, value: u16) -> [u8; 2] {
+        match self {
+            Endian::Little => value.to_le_bytes(),
+            Endian::Big => value.to_be_bytes(),
+        }
+    }
+
+    /// Encode `value` in this byte order.
+    pub fn encode_u32(self, value: u32) -> [u8; 4] {
+        match self {
+            Endian::Little => value.to_le_bytes(),
+            Endian::Big => value.to_be_bytes(),
+        }
+    }
+
+    /// Encode `value` in this byte order.
+    pub fn encode_u64(self, value: u64) -> [u8; 8] {
+        match self {
+            Endian::Little => value.to_le_bytes(),
+            Endian::Big => value.to_be_bytes(),
+        }
+    }
+
+    /// Decode `bytes` in this byte order.
+    pub fn decode_u16(self, bytes: [u8; 2]) -> u16 {
+        match self {
+            Endian::Little => u16::from_le_bytes(bytes),
+            Endian::Big => u16::from_be_bytes(bytes),
+        }
+    }
+
+    /// Decode `bytes` in this byte order.
+    pub fn decode_u32(self, bytes: [u8; 4]) -> u32 {
+        match self {
+            Endian::Little => u32::from_le_bytes(bytes),
+            Endian::Big => u32::from_be_bytes(bytes),
+        }
+    }
+
+    /// Decode `bytes` in this byte order.
+    pub fn decode_u64(self, bytes: [u8; 8]) -> u64 {
+        match self {
+            Endian::Little => u64::from_le_bytes(bytes),
+            Endian::Big => u64::from_be_bytes(bytes),
+        }
+    }
+}
+
+type ViolationHandler = Box<dyn Fn(&Violation) + Send>;
+
+/// A buffer of a descriptor chain.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Descriptor {
+    /// Guest address of the buffer.
+    pub addr: u64,
+    /// Length of the buffer.
+    pub len: u32,
+    /// Whether the buffer is written by the device, rather than read.
+    pub writable: bool,
+}
+
+/// A descriptor chain made available by the driver.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Chain {
+    /// Index of the first descriptor, identifying the chain once used.
+    pub head: u16,
+    /// Buffers of the chain, in order.
+    pub descriptors: Vec<Descriptor>,
+}
+
+/// The registers of a [`Queue`], and the position of the device in its rings.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct QueueState {
+    /// Size of the queue set by the driver.
+    pub size: u16,
+    /// Whether the driver enabled the queue.
+    pub ready: bool,
+    /// Guest address of the descriptor table.
+    pub desc: u64,
+    /// Guest address of the driver area (available ring).
+    pub avail: u64,
+    /// Guest address of the device area (used ring).
+    pub used: u64,
+    /// Index of the next available chain the device pops.
+    pub next_avail: u16,
+    /// Index of the next used element the device adds.
+    pub next_used: u16,
+}
+
+/// The state of an [`MmioTransport`], saved with [`MmioTransport::state`] and restored with
+/// [`MmioTransport::set_state`], e.g. when snapshotting the VM.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct VirtioMmioTransportState {
+    /// Device status written by the driver, including [`STATUS_NEEDS_RESET`].
+    pub status: u32,
+    /// Bank of device features selected by the driver.
+    pub device_features_sel: u32,
+    /// Features negotiated with the driver.
+    pub driver_features: u64,
+    /// Bank of driver features selected by the driver.
+    pub driver_features_sel: u32,
+    /// Queue selected by the driver.
+    pub queue_sel: u32,
+    /// Pending interrupt causes.
+    pub interrupt_status: u32,
+    /// Generation of the configuration space.
+    pub config_generation: u32,
+    /// State of each queue of the device.
+    pub queues: Vec<QueueState>,
+}
+
+/// A split virtqueue, as configured by the driver.
+#[derive(Debug)]
+pub struct Queue {
+    max_size: u16,
+    size: u16,
+    ready: bool,
+    desc: u64,
+    avail: u64,
+    used: u64,
+    next_avail: u16,
+    next_used: u16,
+}
+
+impl Queue {
+    fn new(max_size: u16) -> Self {
+        Queue {
+            max_size,
+            size: 0,
+            ready: false,
+            desc: 0,
+            avail: 0,
+            used: 0,
+            next_avail: 0,
+            next_used: 0,
+        }
+    }
+
+    /// Return the registers of the queue and the position of the device in its rings.
+    pub fn state(&self) -> QueueState {
+        QueueState {
+            size: self.size,
+            ready: self.ready,
+            desc: self.desc,
+            avail: self.avail,
+            used: self.used,
+            next_avail: self.next_avail,
+            next_used: self.next_used,
+        }
+    }
+
+    /// Return the size of the queue set by the driver.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Return whether the driver enabled the queue.
+    pub fn ready(&self) -> bool {
+        self.ready
+    }
+
+    /// Return the next chain made available by the driver, if any.
+    pub fn pop(&mut self, memory: &DmaMemory) -> Result<Option<Chain>, Error> {
+        let size = self.checked_size()?;
+        let avail_idx: u16 = memory.read_value(address(self.avail, 2)?)?;
+        if self.next_avail == avail_idx {
+            return Ok(None);
+        }
+        let slot = u64::from(self.next_avail % size);
+        let head: u16 = memory.read_value(address(self.avail, 4 + 2 * slot)?)?;
+
+        let mut descriptors = Vec::new();
+        let mut index = head;
+        // A chain can't be longer than the queue, which also stops loops.
+        loop {
+            if index >= size || descriptors.len() == usize::from(size) {
+                return Err(Error::InvalidChain(head));
+            }
+            let desc = address(self.desc, 16 * u64::from(index))?;
+            let flags: u16 = memory.read_value(address(desc, 12)?)?;
+            descriptors.push(Descriptor {
+                addr: memory.read_value(desc)?,
+                len: memory.read_value(address(desc, 8)?)?,
+                writable: flags & DESC_F_WRITE != 0,
+            });
+            if flags & DESC_F_NEXT == 0 {
+                break;
+            }
+            index = memory.read_value(address(desc, 14)?)?;
+        }
+        self.next_avail = self.next_avail.wrapping_add(1);
+        Ok(Some(Chain { head, descriptors }))
+    }
+
+    /// Hand the chain starting at `head` back to the driver, with the number of bytes the
+    /// device wrote to it.
+    pub fn add_used(&mut self, memory: &DmaMemory, head: u16, len: u32) -> Result<(), Error> {
+        let slot = u64::from(self.next_used % self.checked_size()?);
+        memory.write_value(address(self.used, 4 + 8 * slot)?, u32::from(head))?;
+        memory.write_value(address(self.used, 8 + 8 * slot)?, len)?;
+        self.next_used = self.next_used.wrapping_add(1);
+        // The element must be visible before the index is.
+        fence(Ordering::Release);
+        memory.write_value(address(self.used, 2)?, self.next_used)?;
+        Ok(())
+    }
+
+    // Return the size of the queue, if it's valid.
+    fn checked_size(&self) -> Result<u16, Error> {
+        let size = self.size;
+        if size == 0 || size > self.max_size || !size.is_power_of_two() {
+            return Err(Error::InvalidQueueSize(size));
+        }
+        Ok(size)
+    }
+
+    fn interrupt_wanted(&self, memory: &DmaMemory) -> Result<bool, Error> {
+        let flags: u16 = memory.read_value(self.avail)?;
+        Ok(flags & AVAIL_F_NO_INTERRUPT == 0)
+    }
+}
+
+// Return the address `offset` bytes into the guest structure at `base`.
+fn address(base: u64, offset: u64) -> Result<u64, Error> {
+    base.checked_add(offset)
+        .ok_or(Error::Dma(dma::Error::Overflow))
+}
+
+/// A virtio device behind an [`MmioTransport`].
+pub trait VirtioDevice: Send {
+    /// Return the virtio device ID.
+    fn device_type(&self) -> u32;
+
+    /// Return the device specific features offered to the driver. [`VIRTIO_F_VERSION_1`] is
+    /// always offered by the transport.
+    fn features(&self) -> u64 {
+        0
+    }
+
+    /// Return the maximum size of each queue of the device.
+    fn queue_max_sizes(&self) -> &[u16];
+
+    /// Read the configuration space at `offset`.
+    ///
+    /// The default implementation returns zeros.
+    fn read_config(&self, _offset: u64, data: &mut [u8]) {
+        data.fill(0);
+    }
+
+    /// Write the configuration space at `offset`.
+    ///
+    /// The default implementation ignores the writes.
+    fn write_config(&mut self, _offset: u64, _data: &[u8]) {}
+
+    /// Lay out the configuration space in the byte order of the guest, set with
+    /// [`MmioTransport::with_endian`].
+    ///
+    /// The default implementation does nothing, for devices whose configuration space is
+    /// little endian or made of bytes only.
+    fn set_endian(&mut self, _endian: Endian) {}
+
+    /// Process the queue `index` of `queues`, accessing the buffers through `memory`.
+    /// Returns whether chains were handed back to the driver.
+    fn process_queue(
+        &mut self,
+        index: usize,
+        queues: &mut [Queue],
+        memory: &DmaMemory,
+    ) -> Result<bool, Error>;
+
+    /// Start the device when the driver sets `DRIVER_OK`, with the negotiated `features` and
+    /// the `queues` as configured by the driver, e.g. to hand them over to a vhost backend.
+    /// Not called when the state of the transport is restored.
+    ///
+    /// The default implementation does nothing.
+    fn activate(&mut self, _features: u64, _queues: &[Queue]) {}
+
+    /// Reset the device when the driver resets the transport.
+    ///
+    /// The default implementation does nothing.
+    fn reset(&mut self) {}
+}
+
+/// The virtio-mmio transport of a [`VirtioDevice`], accessing the guest `memory` and
+/// raising `interrupt`.
+pub struct MmioTransport<D, I> {
+    memory: Arc<DmaMemory>,
+    interrupt: I,
+    device: D,
+    queues: Vec<Queue>,
+    device_features_sel: u32,
+    driver_features: u64,
+    driver_features_sel: u32,
+    queue_sel: u32,
+    interrupt_status: u32,
+    status: u32,
+    config_generation: u32,
+    irq: Option<u32>,
+    split_accesses: bool,
+    strict: Option<ViolationHandler>,
+    config_access: ConfigAccess,
+    endian: Endian,
+}
+
+impl<D: VirtioDevice, I: Interrupt> MmioTransport<D, I> {
+    /// Create the transport of `device`, in its reset state.
+    pub fn new(memory: Arc<DmaMemory>, interrupt: I, device: D) -> Self {
+        let queues = device
+            .queue_max_sizes()
+            .iter()
+            .map(|max| Queue::new(*max))
+            .collect();
+        MmioTransport {
+            memory,
+            interrupt,
+            device,
+            queues,
+            device_features_sel: 0,
+            driver_features: 0,
+            driver_features_sel: 0,
+            queue_sel: 0,
+            interrupt_status: 0,
+            status: 0,
+            config_generation: 0,
+            irq: None,
+            split_accesses: false,
+            strict: None,
+            config_access: ConfigAccess::Any,
+            endian: Endian::Little,
+        }
+    }
+
+    /// Report `irq` as the interrupt line `interrupt` is delivered through, e.g. in the
+    /// [`Manifest`](crate::manifest::Manifest) and the kernel command line.
+    pub fn with_irq(mut self, irq: u32) -> Self {
+        self.irq = Some(irq);
+        self
+    }
+
+    /// Split the 8 byte accesses to the `QueueDescLow`/`QueueDescHigh`,
+    /// `QueueDriverLow`/`QueueDriverHigh` and `QueueDeviceLow`/`QueueDeviceHigh` register pairs
+    /// into two 32 bit accesses, low register first, for firmware setting up the queues with
+    /// 64 bit accesses. The 8 byte accesses to the other registers are still ignored.
+    pub fn with_split_accesses(mut self) -> Self {
+        self.split_accesses = true;
+        self
+    }
+
+    /// Reject the accesses to the transport registers breaking the virtio-mmio rules, and
+    /// report them to `violation`, e.g. to catch driver bugs during bring-up.
+    ///
+    /// The rejected writes are ignored, and the rejected reads return zeros. The accesses
+    /// split by [`with_split_accesses`](MmioTransport::with_split_accesses) are accepted, and
+    /// the accesses to the configuration space of the device are checked against the policy
+    /// set by [`with_config_access`](MmioTransport::with_config_access).
+    pub fn with_strict_mode<F>(mut self, violation: F) -> Self
+    where
+        F: Fn(&Violation) + Send + 'static,
+    {
+        self.strict = Some(Box::new(violation));
+        self
+    }
+
+    /// Accept the accesses to the configuration space of the device allowed by `access` in
+    /// strict mode, [`ConfigAccess::Any`] by default.
+    pub fn with_config_access(mut self, access: ConfigAccess) -> Self {
+        self.config_access = access;
+        self
+    }
+
+    /// Serve the registers in the byte order of the guest, [`Endian::Little`] by default,
+    /// and have the device lay out its configuration space accordingly.
+    pub fn with_endian(mut self, endian: Endian) -> Self {
+        self.endian = endian;
+        self.device.set_endian(endian);
+        self
+    }
+
+    /// Return the device.
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    /// Return the device, e.g. to hand it data for the guest.
+    pub fn device_mut(&mut self) -> &mut D {
+        &mut self.device
+    }
+
+    /// Return the device status written by the driver, plus [`STATUS_NEEDS_RESET`] if the
+    /// transport stopped processing the queues.
+    pub fn status(&self) -> u32 {
+        self.status
+    }
+
+    /// Return the features negotiated with the driver.
+    pub fn driver_features(&self) -> u64 {
+        self.driver_features
+    }
+
+    /// Return the state of the transport.
+    ///
+    /// The state of the device behind the transport, such as its configuration space, isn't
+    /// included, and has to be saved separately.
+    pub fn state(&self) -> VirtioMmioTransportState {
+        VirtioMmioTransportState {
+            status: self.status,
+            device_features_sel: self.device_features_sel,
+            driver_features: self.driver_features,
+            driver_features_sel: self.driver_features_sel,
+            queue_sel: self.queue_sel,
+            interrupt_status: self.interrupt_status,
+            config_generation: self.config_generation,
+            queues: self.queues.iter().map(Queue::state).collect(),
+        }
+    }
+
+    /// Restore the transport to `state`, previously returned by [`MmioTransport::state`].
+    ///
+    /// Fails with [`Error::QueueCount`], leaving the transport untouched, if `state` doesn't
+    /// have as many queues as the device. Neither the device nor the driver are notified.
+    pub fn set_state(&mut self, state: &VirtioMmioTransportState) -> Result<(), Error> {
+        if state.queues.len() != self.queues.len() {
+            return Err(Error::QueueCount {
+                expected: self.queues.len(),
+                found: state.queues.len(),
+            });
+        }
+        for (queue, saved) in self.queues.iter_mut().zip(&state.queues) {
+            queue.size = saved.size;
+            queue.ready = saved.ready;
+            queue.desc = saved.desc;
+            queue.avail = saved.avail;
+            queue.used = saved.used;
+            queue.next_avail = saved.next_avail;
+            queue.next_used = saved.next_used;
+        }
+        self.status = state.status;
+        self.device_features_sel = state.device_features_sel;
+        self.driver_features = state.driver_features;
+        self.driver_features_sel = state.driver_features_sel;
+        self.queue_sel = state.queue_sel;
+        self.interrupt_status = state.interrupt_status;
+        self.config_generation = state.config_generation;
+        Ok(())
+    }
+
+    /// Process the queue `index` if the driver enabled it, e.g. once the backend of the
+    /// device has data for the guest, and raise the interrupt if needed.
+    pub fn process_queue(&mut self, index: usize) {
+        let active = STATUS_DRIVER_OK | STATUS_NEEDS_RESET;
+        if self.status & active != STATUS_DRIVER_OK
+            || !self.queues.get(index).is_some_and(Queue::ready)
+        {
+            return;
+        }
+        let result = self
+            .device
+            .process_queue(index, &mut self.queues, &self.memory)
+            .and_then(|used| match used {
+                true => self.queues[index].interrupt_wanted(&self.memory),
+                false => Ok(false),
+            });
+        match result {
+            Ok(true) => self.raise(INT_USED_RING),
+            Ok(false) => {}
+            Err(_) => {
+                self.status |= STATUS_NEEDS_RESET;
+                self.raise(INT_CONFIG);
+            }
+        }
+    }
+
+    /// Update the configuration space of the device with `update`, and notify the driver.
+    pub fn update_config<F: FnOnce(&mut D)>(&mut self, update: F) {
+        update(&mut self.device);
+        self.config_generation = self.config_generation.wrapping_add(1);
+        if self.status & STATUS_DRIVER_OK != 0 {
+            self.raise(INT_CONFIG);
+        }
+    }
+
+    fn raise(&mut self, cause: u32) {
+        self.interrupt_status |= cause;
+        // Failures are reported by the tracing hook, and the guest polls the status anyway.
+        let _ = trace::interrupt("virtio", self.interrupt.trigger());
+    }
+
+    fn reset(&mut self) {
+        for queue in &mut self.queues {
+            *queue = Queue::new(queue.max_size);
+        }
+        self.device_features_sel = 0;
+        self.driver_features = 0;
+        self.driver_features_sel = 0;
+        self.queue_sel = 0;
+        self.interrupt_status = 0;
+        self.status = 0;
+        self.device.reset();
+    }
+
+    fn features(&self) -> u64 {
+        self.device.features() | VIRTIO_F_VERSION_1
+    }
+
+    fn selected(&self) -> Option<&Queue> {
+        self.queues.get(self.queue_sel as usize)
+    }
+
+    // Return whether an 8 byte access at `offset` is split into accesses to a pair of low and
+    // high registers.
+    fn splits(&self, offset: u64) -> bool {
+        self.split_accesses
+            && matches!(offset, QUEUE_DESC_LOW | QUEUE_DRIVER_LOW | QUEUE_DEVICE_LOW)
+    }
+
+    // Return whether an access of `len` bytes at `offset` is accepted, and report the
+    // violation otherwise.
+    fn check(&self, offset: u64, len: usize, write: bool) -> bool {
+        let handler = match &self.strict {
+            Some(handler) => handler,
+            None => return true,
+        };
+        let violation = if offset >= CONFIG {
+            let offset = offset - CONFIG;
+            if self.config_access.allows(offset, len) {
+                return true;
+            }
+            Violation::ConfigAccess { offset, len }
+        } else if !offset.is_multiple_of(4) {
+            Violation::Misaligned { offset, len }
+        } else if len != 4 && !(len == 8 && self.splits(offset)) {
+            Violation::InvalidWidth { offset, len }
+        } else {
+            let readable = matches!(
+                offset,
+                MAGIC_VALUE
+                    | VERSION
+                    | DEVICE_ID
+                    | VENDOR_ID
+                    | DEVICE_FEATURES
+                    | QUEUE_NUM_MAX
+                    | QUEUE_READY
+                    | INTERRUPT_STATUS
+                    | STATUS
+                    | CONFIG_GENERATION
+            );
+            let writable = matches!(
+                offset,
+                DEVICE_FEATURES_SEL
+                    | DRIVER_FEATURES
+                    | DRIVER_FEATURES_SEL
+                    | QUEUE_SEL
+                    | QUEUE_NUM
+                    | QUEUE_READY
+                    | QUEUE_NOTIFY
+                    | INTERRUPT_ACK
+                    | STATUS
+                    | QUEUE_DESC_LOW
+                    | QUEUE_DESC_HIGH
+                    | QUEUE_DRIVER_LOW
+                    | QUEUE_DRIVER_HIGH
+                    | QUEUE_DEVICE_LOW
+                    | QUEUE_DEVICE_HIGH
+            );
+            match (readable, writable, write) {
+                (false, false, _) => Violation::Reserved(offset),
+                (_, false, true) => Violation::ReadOnly(offset),
+                (false, _, false) => Violation::WriteOnly(offset),
+                _ => return true,
+            }
+        };
+        handler(&violation);
+        false
+    }
+
+    fn register(&self, offset: u64) -> u32 {
+        match offset {
+            MAGIC_VALUE => MAGIC,
+            VERSION => 2,
+            DEVICE_ID => self.device.device_type(),
+            VENDOR_ID => 0,
+            DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.features() as u32,
+                1 => (self.features() >> 32) as u32,
+                _ => 0,
+            },
+            QUEUE_NUM_MAX => self.selected().map_or(0, |queue| queue.max_size.into()),
+            QUEUE_READY => self.selected().map_or(0, |queue| queue.ready.into()),
+            INTERRUPT_STATUS => self.interrupt_status,
+            STATUS => self.status,
+            CONFIG_GENERATION => self.config_generation,
+            _ => 0,
+        }
+    }
+
+    fn set_register(&mut self, offset: u64, value: u32) {
+        let queue = self.queues.get_mut(self.queue_sel as usize);
+        match (offset, queue) {
+            (DEVICE_FEATURES_SEL, _) => self.device_features_sel = value,
+            (DRIVER_FEATURES, _) => match self.driver_features_sel {
+                0 => set_low(&mut self.driver_features, value),
+                1 => set_high(&mut self.driver_features, value),
+                _ => {}
+            },
+            (DRIVER_FEATURES_SEL, _) => self.driver_features_sel = value,
+            (QUEUE_SEL, _) => self.queue_sel = value,
+            // The layout of a queue can't change while the device uses it.
+            (
+                QUEUE_NUM | QUEUE_DESC_LOW | QUEUE_DESC_HIGH | QUEUE_DRIVER_LOW | QUEUE_DRIVER_HIGH
+                | QUEUE_DEVICE_LOW | QUEUE_DEVICE_HIGH,
+                Some(queue),
+            ) if queue.ready => {}
+            (QUEUE_NUM, Some(queue)) => queue.size = value as u16,
+            (QUEUE_READY, Some(queue)) => queue.ready = value & 1 != 0,
+            (QUEUE_DESC_LOW, Some(queue)) => set_low(&mut queue.desc, value),
+            (QUEUE_DESC_HIGH, Some(queue)) => set_high(&mut queue.desc, value),
+            (QUEUE_DRIVER_LOW, Some(queue)) => set_low(&mut queue.avail, value),
+            (QUEUE_DRIVER_HIGH, Some(queue)) => set_high(&mut queue.avail, value),
+            (QUEUE_DEVICE_LOW, Some(queue)) => set_low(&mut queue.used, value),
+            (QUEUE_DEVICE_HIGH, Some(queue)) => set_high(&mut queue.used, value),
+            (QUEUE_NOTIFY, _) => self.process_queue(value as usize),
+            (INTERRUPT_ACK, _) => self.interrupt_status &= !value,
+            (STATUS, _) if value == 0 => self.reset(),
+            (STATUS, _) => {
+                let previous = self.status;
+                self.status = value | (self.status & STATUS_NEEDS_RESET);
+                // Refuse the features the device doesn't offer.
+                if self.driver_features & !self.features() != 0 {
+                    self.status &= !STATUS_FEATURES_OK;
+                }
+                let active = STATUS_FEATURES_OK | STATUS_DRIVER_OK | STATUS_NEEDS_RESET;
+                if previous & STATUS_DRIVER_OK == 0
+                    && self.status & active == STATUS_FEATURES_OK | STATUS_DRIVER_OK
+                {
+                    self.device.activate(self.driver_features, &self.queues);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// Type of the device reported by `introspect`.
+fn device_name(device_type: u32) -> String {
+    match device_type {
+        1 => "virtio-net".to_string(),
+        2 => "virtio-block".to_string(),
+        3 => "virtio-console".to_string(),
+        4 => "virtio-rng".to_string(),
+        _ => format!("virtio-{}", device_type),
+    }
+}
+
+fn set_low(value: &mut u64, low: u32) {
+    *value = (*value & !0xffff_ffff) | u64::from(low);
+}
+
+fn set_high(value: &mut u64, high: u32) {
+    *value = (*value & 0xffff_ffff) | (u64::from(high) << 32);
+}
+
+impl<D: VirtioDevice, I: Interrupt> DeviceInfo for MmioTransport<D, I> {
+    fn name(&self) -> String {
+        device_name(self.device.device_type())
+    }
+
+    fn device_type(&self) -> String {
+        "virtio-mmio".to_string()
+    }
+
+    fn virtio_device_id(&self) -> Option<u32> {
+        Some(self.device.device_type())
+    }
+
+    fn version(&self) -> Option<u32> {
+        Some(2)
+    }
+}
+
+impl<D: VirtioDevice, I: Interrupt> MutDeviceMmio for MmioTransport<D, I> {
+    fn mmio_read(&mut self, base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+        if self.check(offset, data.len(), false) {
+            self.debug_read(base, offset, data);
+        } else {
+            data.fill(0);
+        }
+    }
+
+    fn mmio_write(&mut self, _base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+        if !self.check(offset, data.len(), true) {
+            return;
+        }
+        if offset >= CONFIG {
+            self.device.write_config(offset - CONFIG, data);
+        } else if let Ok(bytes) = <[u8; 4]>::try_from(data) {
+            // The transport registers only support 32 bit accesses.
+            self.set_register(offset, self.endian.decode_u32(bytes));
+        } else if let (true, Ok(bytes)) = (self.splits(offset), <[u8; 8]>::try_from(data)) {
+            let value = self.endian.decode_u64(bytes);
+            self.set_register(offset, value as u32);
+            self.set_register(offset + 4, (value >> 32) as u32);
+        }
+    }
+
+    fn introspect(&self) -> Vec<(String, String)> {
+        let mut properties = vec![
+            ("type".to_string(), device_name(self.device.device_type())),
+            ("status".to_string(), format!("{:#x}", self.status)),
+            (
+                "interrupt_status".to_string(),
+                format!("{:#x}", self.interrupt_status),
+            ),
+        ];
+        if let Some(irq) = self.irq {
+            properties.push(("irqs".to_string(), irq.to_string()));
+        }
+        for (index, queue) in self.queues.iter().enumerate() {
+            properties.push((
+                format!("queue{}", index),
+                format!(
+                    "size={} ready={} next_avail={}",
+                    queue.size, queue.ready, queue.next_avail
+                ),
+            ));
+        }
+        properties
+    }
+
+    fn info(&self) -> Option<DeviceDetails> {
+        Some(DeviceDetails::of(self))
+    }
+
+    fn debug_read(&self, _base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) -> bool {
+        // Reading the registers and the configuration space has no side effects.
+        if offset >= CONFIG {
+            self.device.read_config(offset - CONFIG, data);
+        } else if data.len() == 4 {
+            data.copy_from_slice(&self.endian.encode_u32(self.register(offset)));
+        } else if data.len() == 8 && self.splits(offset) {
+            let value =
+                u64::from(self.register(offset)) | (u64::from(self.register(offset + 4)) << 32);
+            data.copy_from_slice(&self.endian.encode_u64(value));
+        } else {
+            data.fill(0);
+        }
+        true
+    }
+}
+
+/// Typed accessors to the registers of an MMIO device, for host side callers such as tests,
+/// debug tools or devices forwarding accesses to other devices.
+///
+/// The values are little endian, like the registers and configuration space of virtio-mmio
+/// devices, or big endian with the `_be` variants, for transports serving big endian guests.
+/// Each of them goes through a single call to the device, e.g. a single 8 byte access for
+/// [`read_u64`](VirtioMmioDeviceExt::read_u64).
+///
+/// ```ignore
+/// let transport = Arc::new(Mutex::new(MmioTransport::new(memory, irq, device)));
+/// assert_eq!(transport.read_u32(base, 0x0), 0x7472_6976);
+/// ```
+pub trait VirtioMmioDeviceExt: DeviceMmio {
+    /// Read the 32 bit register at `offset`.
+    fn read_u32(&self, base: MmioAddress, offset: MmioAddressOffset) -> u32 {
+        let mut data = [0; 4];
+        self.mmio_read(base, offset, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    /// Write `value` to the 32 bit register at `offset`.
+    fn write_u32(&self, base: MmioAddress, offset: MmioAddressOffset, value: u32) {
+        self.mmio_write(base, offset, &value.to_le_bytes());
+    }
+
+    /// Read the 64 bit value at `offset`.
+    fn read_u64(&self, base: MmioAddress, offset: MmioAddressOffset) -> u64 {
+        let mut data = [0; 8];
+        self.mmio_read(base, offset, &mut data);
+        u64::from_le_bytes(data)
+    }
+
+    /// Write the 64 bit `value` at `offset`.
+    fn write_u64(&self, base: MmioAddress, offset: MmioAddressOffset, value: u64) {
+        self.mmio_write(base, offset, &value.to_le_bytes());
+    }
+
+    /// Read the big endian 32 bit register at `offset`.
+    fn read_u32_be(&self, base: MmioAddress, offset: MmioAddressOffset) -> u32 {
+        let mut data = [0; 4];
+        self.mmio_read(base, offset, &mut data);
+        u32::from_be_bytes(data)
+    }
+
+    /// Write `value` to the big endian 32 bit register at `offset`.
+    fn write_u32_be(&self, base: MmioAddress, offset: MmioAddressOffset, value: u32) {
+        self.mmio_write(base, offset, &value.to_be_bytes());
+    }
+
+    /// Read the big endian 64 bit value at `offset`.
+    fn read_u64_be(&self, base: MmioAddress, offset: MmioAddressOffset) -> u64 {
+        let mut data = [0; 8];
+        self.mmio_read(base, offset, &mut data);
+        u64::from_be_bytes(data)
+    }
+
+    /// Write the big endian 64 bit `value` at `offset`.
+    fn write_u64_be(&self, base: MmioAddress, offset: MmioAddressOffset, value: u64) {
+        self.mmio_write(base, offset, &value.to_be_bytes());
+    }
+}
+
+impl<T: DeviceMmio + ?Sized> VirtioMmioDeviceExt for T {}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use crate::bus::MmioRange;
+    use crate::device_manager::{IoManager, MmioManager};
+    use crate::dma::tests::Ram;
+    use crate::testing::MockInterrupt;
+
+    const BASE: u64 = 0xd000_0000;
+
+    // Size of the queues set up by the driver.
+    const QUEUE_SIZE: u16 = 8;
+
+    // A driver accessing a device registered with a manager, with the tables of queue `i`
+    // at `0x10000 * (i + 1)` in guest memory.
+    pub(crate) struct Driver {
+        pub(crate) manager: IoManager,
+        pub(crate) memory: Arc<DmaMemory>,
+        pub(crate) irq: Arc<MockInterrupt>,
+    }
+
+    impl Driver {
+        // Register the device created by `factory`, and return it.
+        pub(crate) fn new<F, T>(factory: F) -> (Self, Arc<T>)
+        where
+            F: FnOnce(Arc<DmaMemory>, Arc<MockInterrupt>) -> T,
+            T: DeviceMmio + Send + Sync + 'static,
+        {
+            let mut manager = IoManager::new();
+            let ram = Ram(Mutex::new(vec![0; 0x80000]));
+            manager.set_dma_memory(Arc::new(DmaMemory::new(ram)));
+            let memory = manager.dma_memory().unwrap().clone();
+            let irq = Arc::new(MockInterrupt::new());
+            let device = Arc::new(factory(memory.clone(), irq.clone()));
+            let range = MmioRange::new(MmioAddress(BASE), MMIO_SIZE).unwrap();
+            manager.register_mmio(range, device.clone()).unwrap();
+            let driver = Driver {
+                manager,
+                memory,
+                irq,
+            };
+            (driver, device)
+        }
+
+        pub(crate) fn read(&self, offset: u64) -> u32 {
+            let mut data = [0; 4];
+            self.manager
+                .mmio_read(MmioAddress(BASE + offset), &mut data)
+                .unwrap();
+            u32::from_le_bytes(data)
+        }
+
+        pub(crate) fn write(&self, offset: u64, value: u32) {
+            self.manager
+                .mmio_write(MmioAddress(BASE + offset), &value.to_le_bytes())
+                .unwrap();
+        }
+
+        // Initialize the device, accepting `features` and setting up `queues` queues.
+        pub(crate) fn init(&self, features: u64, queues: u16) {
+            assert_eq!(self.read(MAGIC_VALUE), MAGIC);
+            assert_eq!(self.read(VERSION), 2);
+            self.write(STATUS, 0x3);
+            for sel in 0..2 {
+                self.write(DRIVER_FEATURES_SEL, sel);
+                self.write(DRIVER_FEATURES, (features >> (32 * sel)) as u32);
+            }
+            self.write(STATUS, 0x3 | STATUS_FEATURES_OK);
+            assert_eq!(self.read(STATUS), 0x3 | STATUS_FEATURES_OK);
+
+            for queue in 0..queues {
+                let desc = Self::desc(queue, 0);
+                self.write(QUEUE_SEL, queue.into());
+                assert!(self.read(QUEUE_NUM_MAX) >= QUEUE_SIZE.into());
+                self.write(QUEUE_NUM, QUEUE_SIZE.into());
+                self.write(QUEUE_DESC_LOW, desc as u32);
+                self.write(QUEUE_DRIVER_LOW, (desc + 0x1000) as u32);
+                self.write(QUEUE_DEVICE_LOW, (desc + 0x2000) as u32);
+                self.write(QUEUE_READY, 1);
+            }
+            self.write(STATUS, 0x3 | STATUS_FEATURES_OK | STATUS_DRIVER_OK);
+        }
+
+        fn desc(queue: u16, index: u16) -> u64 {
+            0x10000 * (u64::from(queue) + 1) + 16 * u64::from(index)
+        }
+
+        pub(crate) fn descriptor(&self, queue: u16, index: u16, buffer: (u64, u32), flags: u16) {
+            let desc = Self::desc(queue, index);
+            self.memory.write_value(desc, buffer.0).unwrap();
+            self.memory.write_value(desc + 8, buffer.1).unwrap();
+            self.memory.write_value(desc + 12, flags).unwrap();
+            self.memory.write_value(desc + 14, index + 1).unwrap();
+        }
+
+        pub(crate) fn make_available(&self, queue: u16, heads: &[u16]) {
+            let avail = Self::desc(queue, 0) + 0x1000;
+            let idx: u16 = self.memory.read_value(avail + 2).unwrap();
+            for (i, head) in heads.iter().enumerate() {
+                let slot = u64::from(idx.wrapping_add(i as u16) % QUEUE_SIZE);
+                self.memory
+                    .write_value(avail + 4 + 2 * slot, *head)
+                    .unwrap();
+            }
+            self.memory
+                .write_value(avail + 2, idx.wrapping_add(heads.len() as u16))
+                .unwrap();
+        }
+
+        pub(crate) fn suppress_interrupts(&self, queue: u16) {
+            let avail = Self::desc(queue, 0) + 0x1000;
+            self.memory
+                .write_value(avail, AVAIL_F_NO_INTERRUPT)
+                .unwrap();
+        }
+
+        // Return the used index of `queue`, and its used elements.
+        pub(crate) fn used(&self, queue: u16) -> (u16, Vec<(u32, u32)>) {
+            let used = Self::desc(queue, 0) + 0x2000;
+            let idx: u16 = self.memory.read_value(used + 2).unwrap();
+            let elements = (0..idx.min(QUEUE_SIZE))
+                .map(|slot| {
+                    let elem = used + 4 + 8 * u64::from(slot);
+                    (
+                        self.memory.read_value(elem).unwrap(),
+                        self.memory.read_value(elem + 4).unwrap(),
+                    )
+                })
+                .collect();
+            (idx, elements)
+        }
+    }
+
+    // Device with two queues, handing back their chains without touching the buffers.
+    #[derive(Default)]
+    struct Null {
+        config: u32,
+        activations: Vec<(u64, Vec<QueueState>)>,
+        resets: usize,
+    }
+
+    impl VirtioDevice for Null {
+        fn device_type(&self) -> u32 {
+            0x42
+        }
+
+        fn features(&self) -> u64 {
+            1 << 3
+        }
+
+        fn queue_max_sizes(&self) -> &[u16] {
+            &[16, 8]
+        }
+
+        fn read_config(&self, offset: u64, data: &mut [u8]) {
+            let config = self.config.to_le_bytes();
+            for (i, byte) in data.iter_mut().enumerate() {
+                *byte = config.get(offset as usize + i).copied().unwrap_or(0);
+            }
+        }
+
+        fn process_queue(
+            &mut self,
+            index: usize,
+            queues: &mut [Queue],
+            memory: &DmaMemory,
+        ) -> Result<bool, Error> {
+            let mut used = false;
+            while let Some(chain) = queues[index].pop(memory)? {
+                queues[index].add_used(memory, chain.head, chain.descriptors.len() as u32)?;
+                used = true;
+            }
+            Ok(used)
+        }
+
+        fn activate(&mut self, features: u64, queues: &[Queue]) {
+            let queues = queues.iter().map(Queue::state).collect();
+            self.activations.push((features, queues));
+        }
+
+        fn reset(&mut self) {
+            self.resets += 1;
+        }
+    }
+
+    #[test]
+    fn test_mmio_device_ext() {
+        let (driver, transport) =
+            Driver::new(|memory, irq| Mutex::new(MmioTransport::new(memory, irq, Null::default())));
+        let base = MmioAddress(BASE);
+        assert_eq!(transport.read_u32(base, MAGIC_VALUE), MAGIC);
+        transport.write_u32(base, QUEUE_SEL, 1);
+        assert_eq!(driver.read(QUEUE_NUM_MAX), 8);
+
+        // The configuration space is read with a single 8 byte access.
+        transport
+            .lock()
+            .unwrap()
+            .update_config(|device| device.config = 0x1234_5678);
+        assert_eq!(transport.read_u64(base, CONFIG), 0x1234_5678);
+        // Transport registers only support 32 bit accesses.
+        assert_eq!(transport.read_u64(base, DEVICE_ID), 0);
+    }
+
+    #[test]
+    fn test_split_accesses() {
+        for split in [false, true] {
+            let (_, transport) = Driver::new(|memory, irq| {
+                let transport = MmioTransport::new(memory, irq, Null::default());
+                Mutex::new(match split {
+                    true => transport.with_split_accesses(),
+                    false => transport,
+                })
+            });
+            let base = MmioAddress(BASE);
+            transport.write_u32(base, QUEUE_SEL, 1);
+            transport.write_u64(base, QUEUE_DESC_LOW, 0x1_0000_2000);
+            transport.write_u64(base, QUEUE_DRIVER_LOW, 0x1_0000_3000);
+            transport.write_u64(base, QUEUE_DEVICE_LOW, 0x1_0000_4000);
+            // The other registers don't support 64 bit accesses.
+            transport.write_u64(base, QUEUE_NUM, 4);
+            transport.write_u64(base, QUEUE_DESC_HIGH, 2);
+
+            let expected = |address| if split { address } else { 0 };
+            let queue = &transport.lock().unwrap().queues[1];
+            assert_eq!(queue.desc, expected(0x1_0000_2000));
+            assert_eq!(queue.avail, expected(0x1_0000_3000));
+            assert_eq!(queue.used, expected(0x1_0000_4000));
+            assert_eq!(queue.size, 0);
+        }
+    }
+
+    #[test]
+    fn test_big_endian() {
+        let (_, transport) = Driver::new(|memory, irq| {
+            let transport = MmioTransport::new(memory, irq, Null::default())
+                .with_split_accesses()
+                .with_endian(Endian::Big);
+            Mutex::new(transport)
+        });
+        let base = MmioAddress(BASE);
+        assert_eq!(transport.read_u32_be(base, MAGIC_VALUE), MAGIC);
+        assert_eq!(transport.read_u32(base, MAGIC_VALUE), MAGIC.swap_bytes());
+        transport.write_u32_be(base, QUEUE_NUM, 4);
+        transport.write_u64_be(base, QUEUE_DESC_LOW, 0x1_0000_2000);
+        let queue = &transport.lock().unwrap().queues[0];
+        assert_eq!((queue.size, queue.desc), (4, 0x1_0000_2000));
+    }
+
+    #[test]
+    fn test_strict_mode() {
+        let violations = Arc::new(Mutex::new(Vec::new()));
+        let recorded = violations.clone();
+        let (driver, _) = Driver::new(|memory, irq| {
+            let transport = MmioTransport::new(memory, irq, Null::default())
+                .with_split_accesses()
+                .with_strict_mode(move |violation| recorded.lock().unwrap().push(*violation));
+            Mutex::new(transport)
+        });
+        driver.init(VIRTIO_F_VERSION_1, 1);
+        assert!(violations.lock().unwrap().is_empty());
+
+        let mut data = [0xff; 2];
+        let addr = |offset| MmioAddress(BASE + offset);
+        driver
+            .manager
+            .mmio_read(addr(DEVICE_ID), &mut data)
+            .unwrap();
+        assert_eq!(data, [0; 2]);
+        driver
+            .manager
+            .mmio_write(addr(STATUS + 2), &[0; 4])
+            .unwrap();
+        driver.write(DEVICE_ID, 1);
+        assert_eq!(driver.read(QUEUE_SEL), 0);
+        assert_eq!(driver.read(0x0c0), 0);
+        driver
+            .manager
+            .mmio_write(addr(QUEUE_DESC_LOW), &[0; 8])
+            .unwrap();
+        // The configuration space accepts any access by default.
+        driver
+            .manager
+            .mmio_write(addr(CONFIG + 1), &[0; 2])
+            .unwrap();
+        assert_eq!(
+            *violations.lock().unwrap(),
+            [
+                Violation::InvalidWidth {
+                    offset: DEVICE_ID,
+                    len: 2
+                },
+                Violation::Misaligned {
+                    offset: STATUS + 2,
+                    len: 4
+                },
+                Violation::ReadOnly(DEVICE_ID),
+                Violation::WriteOnly(QUEUE_SEL),
+                Violation::Reserved(0x0c0),
+            ]
+        );
+        // The rejected accesses don't reach the registers.
+        assert_eq!(
+            driver.read(STATUS),
+            0x3 | STATUS_FEATURES_OK | STATUS_DRIVER_OK
+        );
+        assert_eq!(driver.read(DEVICE_ID), 0x42);
+    }
+
+    #[test]
+    fn test_config_access() {
+        let cases = [
+            (ConfigAccess::Natural, vec![(1, 2), (4, 8)]),
+            (ConfigAccess::Aligned32, vec![(1, 2), (2, 2), (4, 8)]),
+        ];
+        for (access, rejected) in cases {
+            let violations = Arc::new(Mutex::new(Vec::new()));
+            let recorded = violations.clone();
+            let (driver, _) = Driver::new(|memory, irq| {
+                let transport = MmioTransport::new(memory, irq, Null::default())
+                    .with_config_access(access)
+                    .with_strict_mode(move |violation| recorded.lock().unwrap().push(*violation));
+                Mutex::new(transport)
+            });
+            for (offset, len) in [(1, 2), (2, 2), (0, 4), (4, 8)] {
+                let mut data = vec![0xff; len];
+                let addr = MmioAddress(BASE + CONFIG + offset);
+                driver.manager.mmio_read(addr, &mut data).unwrap();
+            }
+            // The transport registers stay 32 bit only.
+            let addr = MmioAddress(BASE + STATUS);
+            driver.manager.mmio_write(addr, &[1, 0]).unwrap();
+            let mut expected: Vec<_> = rejected
+                .into_iter()
+                .map(|(offset, len)| Violation::ConfigAccess { offset, len })
+                .collect();
+            expected.push(Violation::InvalidWidth {
+                offset: STATUS,
+                len: 2,
+            });
+            assert_eq!(*violations.lock().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_queue_layout() {
+        let (driver, transport) =
+            Driver::new(|memory, irq| Mutex::new(MmioTransport::new(memory, irq, Null::default())));
+        driver.init(VIRTIO_F_VERSION_1, 1);
+        // The layout of a ready queue can't be changed.
+        driver.write(QUEUE_NUM, 4);
+        driver.write(QUEUE_DESC_LOW, 0x30000);
+        driver.write(QUEUE_DRIVER_HIGH, 1);
+        {
+            let queue = &transport.lock().unwrap().queues[0];
+            assert_eq!((queue.size, queue.desc), (QUEUE_SIZE, 0x10000));
+            assert_eq!(queue.avail, 0x11000);
+        }
+
+        // Tables wrapping around the address space are rejected.
+        let mut queue = Queue::new(8);
+        queue.size = 8;
+        queue.avail = u64::MAX - 1;
+        assert!(matches!(
+            queue.pop(&driver.memory),
+            Err(Error::Dma(dma::Error::Overflow))
+        ));
+        queue.used = u64::MAX - 2;
+        assert!(matches!(
+            queue.add_used(&driver.memory, 0, 0),
+            Err(Error::Dma(dma::Error::Overflow))
+        ));
+        queue.avail = 0x11000;
+        queue.desc = u64::MAX - 16 * 4;
+        driver.make_available(0, &[7]);
+        assert!(matches!(
+            queue.pop(&driver.memory),
+            Err(Error::Dma(dma::Error::Overflow))
+        ));
+
+        // So are queues the driver didn't size.
+        let mut queue = Queue::new(8);
+        assert!(matches!(
+            queue.add_used(&driver.memory, 0, 0),
+            Err(Error::InvalidQueueSize(0))
+        ));
+    }
+
+    #[test]
+    fn test_transport_state() {
+        let (driver, transport) =
+            Driver::new(|memory, irq| Mutex::new(MmioTransport::new(memory, irq, Null::default())));
+        driver.init(VIRTIO_F_VERSION_1 | 1 << 3, 2);
+        driver.descriptor(1, 0, (0x40000, 4), 0);
+        driver.make_available(1, &[0]);
+        driver.write(QUEUE_NOTIFY, 1);
+        let state = transport.lock().unwrap().state();
+        assert_eq!(state.driver_features, VIRTIO_F_VERSION_1 | 1 << 3);
+        assert_eq!(state.interrupt_status, INT_USED_RING);
+        assert_eq!(
+            (state.queues[1].next_avail, state.queues[1].next_used),
+            (1, 1)
+        );
+
+        // The restored transport picks up where the saved one left.
+        driver.write(STATUS, 0);
+        assert_eq!(driver.read(QUEUE_READY), 0);
+        transport.lock().unwrap().set_state(&state).unwrap();
+        assert_eq!(transport.lock().unwrap().state(), state);
+        driver.make_available(1, &[0]);
+        driver.write(QUEUE_NOTIFY, 1);
+        assert_eq!(driver.used(1), (2, vec![(0, 1), (0, 1)]));
+
+        let mut other = state.clone();
+        other.queues.pop();
+        other.status = 0;
+        assert!(matches!(
+            transport.lock().unwrap().set_state(&other),
+            Err(Error::QueueCount {
+                expected: 2,
+                found: 1
+            })
+        ));
+        assert_eq!(transport.lock().unwrap().state().queues[1].next_used, 2);
+    }
+
+    #[test]
+    fn test_mmio_transport() {
+        let (driver, transport) = Driver::new(|memory, irq| {
+            Mutex::new(MmioTransport::new(memory, irq, Null::default()).with_irq(5))
+        });
+        assert_eq!(driver.read(DEVICE_ID), 0x42);
+        let properties = transport.lock().unwrap().introspect();
+        assert!(properties.contains(&("type".to_string(), "virtio-66".to_string())));
+        assert!(properties.contains(&("irqs".to_string(), "5".to_string())));
+        let info = DeviceMmio::info(&*transport).unwrap();
+        assert_eq!(info.name, "virtio-66");
+        assert_eq!(info.device_type, "virtio-mmio");
+        assert_eq!(info.virtio_device_id, Some(0x42));
+        assert_eq!(info.version, Some(2));
+        assert_eq!(driver.read(DEVICE_FEATURES), 1 << 3);
+        driver.write(DEVICE_FEATURES_SEL, 1);
+        assert_eq!(driver.read(DEVICE_FEATURES), 1);
+        for (queue, max) in [(0, 16), (1, 8), (2, 0)] {
+            driver.write(QUEUE_SEL, queue);
+            assert_eq!(driver.read(QUEUE_NUM_MAX), max);
+        }
+        driver.init(VIRTIO_F_VERSION_1 | 1 << 3, 2);
+        driver.write(QUEUE_SEL, 1);
+        assert_eq!(driver.read(QUEUE_READY), 1);
+        let (features, queues) = transport.lock().unwrap().device().activations[0].clone();
+        assert_eq!(features, VIRTIO_F_VERSION_1 | 1 << 3);
+        assert_eq!((queues[1].size, queues[1].desc), (QUEUE_SIZE, 0x20000));
+        assert!(queues[1].ready);
+
+        // Each queue is notified separately.
+        driver.descriptor(1, 0, (0x40000, 4), DESC_F_NEXT);
+        driver.descriptor(1, 1, (0x40004, 4), 0);
+        driver.make_available(1, &[0]);
+        driver.write(QUEUE_NOTIFY, 0);
+        assert_eq!(driver.used(1).0, 0);
+        driver.write(QUEUE_NOTIFY, 1);
+        assert_eq!(driver.used(1), (1, vec![(0, 2)]));
+        assert_eq!(driver.read(INTERRUPT_STATUS), INT_USED_RING);
+        driver.irq.expect_triggered(1);
+        driver.write(INTERRUPT_ACK, INT_USED_RING);
+
+        // The driver doesn't want to be interrupted.
+        driver.suppress_interrupts(1);
+        driver.make_available(1, &[0]);
+        driver.write(QUEUE_NOTIFY, 1);
+        assert_eq!(driver.used(1).0, 2);
+        driver.irq.expect_triggered(1);
+        assert_eq!(driver.read(INTERRUPT_STATUS), 0);
+
+        // Configuration changes are notified, and bump the generation.
+        let mut config = [0; 4];
+        transport
+            .lock()
+            .unwrap()
+            .update_config(|device| device.config = 0x1234_5678);
+        driver.irq.expect_triggered(2);
+        assert_eq!(driver.read(INTERRUPT_STATUS), INT_CONFIG);
+        assert_eq!(driver.read(CONFIG_GENERATION), 1);
+        assert_eq!(driver.read(CONFIG), 0x1234_5678);
+        driver
+            .manager
+            .mmio_read(MmioAddress(BASE + CONFIG + 2), &mut config[..2])
+            .unwrap();
+        assert_eq!(config[..2], [0x34, 0x12]);
+        assert!(transport.lock().unwrap().introspect().contains(&(
+            "queue1".to_string(),
+            "size=8 ready=true next_avail=2".to_string()
+        )));
+
+        // The chain loops, and the transport stops until reset.
+        driver.descriptor(0, 0, (0x40000, 4), DESC_F_NEXT);
+        driver.memory.write_value(0x10000 + 14, 0u16).unwrap();
+        driver.make_available(0, &[0]);
+        driver.write(QUEUE_NOTIFY, 0);
+        assert_eq!(driver.used(0).0, 0);
+        assert_ne!(driver.read(STATUS) & STATUS_NEEDS_RESET, 0);
+        driver.irq.expect_triggered(3);
+        driver.make_available(1, &[0]);
+        driver.write(QUEUE_NOTIFY, 1);
+        assert_eq!(driver.used(1).0, 2);
+
+        driver.write(STATUS, 0);
+        assert_eq!(driver.read(STATUS), 0);
+        assert_eq!(driver.read(QUEUE_READY), 0);
+        assert_eq!(transport.lock().unwrap().device().resets, 1);
+
+        // Features the device doesn't offer are refused.
+        driver.write(DRIVER_FEATURES, 1 << 5);
+        driver.write(STATUS, 0x3 | STATUS_FEATURES_OK);
+        assert_eq!(driver.read(STATUS), 0x3);
+        driver.write(STATUS, 0x3 | STATUS_DRIVER_OK);
+        assert_eq!(transport.lock().unwrap().device().activations.len(), 1);
+    }
+}
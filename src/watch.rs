@@ -0,0 +1,468 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Watchpoints on device registers (debug builds only).
+//!
+//! [`Watched`] wraps a device and calls back when the guest accesses the offsets it
+//! watches, like a hardware watchpoint on the device model. The callbacks see the access
+//! and its data, and may modify the data or veto the access, e.g. to log the writes to a
+//! doorbell, or fake the value of a status register while debugging a driver.
+//! [`IoManager::watch_mmio`] wraps a device which is already registered:
+//!
+//! ```
+//! # use std::sync::Arc;
+//! # use vm_device::bus::{MmioAddress, MmioAddressOffset};
+//! use vm_device::device_manager::{IoManager, MmioManager};
+//! use vm_device::resources::Resource;
+//! use vm_device::watch::{Action, WatchKind};
+//! # use vm_device::DeviceMmio;
+//! # struct Uart;
+//! # impl DeviceMmio for Uart {
+//! #     fn mmio_read(&self, _: MmioAddress, _: MmioAddressOffset, _: &mut [u8]) {}
+//! #     fn mmio_write(&self, _: MmioAddress, _: MmioAddressOffset, _: &[u8]) {}
+//! # }
+//!
+//! let mut manager = IoManager::new();
+//! let resources = [Resource::MmioAddressRange {
+//!     base: 0x900_0000,
+//!     size: 0x1000,
+//! }];
+//! manager.register_mmio_resources(Arc::new(Uart), &resources).unwrap();
+//! let uart = manager.watch_mmio(&resources).unwrap();
+//! // Pretend the transmitter is always empty.
+//! uart.watch(0x18..0x1c, WatchKind::Read, |_, data| {
+//!     data[0] = 0x90;
+//!     Action::Veto
+//! });
+//! let mut data = [0; 4];
+//! manager.mmio_read(MmioAddress(0x900_0018), &mut data).unwrap();
+//! assert_eq!(data[0], 0x90);
+//! ```
+//!
+//! [`IoManager::watch_mmio`]: crate::device_manager::IoManager::watch_mmio
+
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+use crate::bus::{MmioAddress, MmioAddressOffset, PioAddress, PioAddressOffset};
+use crate::completion::Completion;
+use crate::exit::ExitAccess;
+use crate::health::Health;
+use crate::info::DeviceDetails;
+use crate::resources::Resource;
+use crate::stats::Statistics;
+use crate::{DeviceMmio, DevicePio};
+
+/// Accesses triggering a watchpoint.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum WatchKind {
+    /// Reads only.
+    Read,
+    /// Writes only.
+    Write,
+    /// Reads and writes.
+    Access,
+}
+
+impl WatchKind {
+    fn matches(self, write: bool) -> bool {
+        match self {
+            WatchKind::Read => !write,
+            WatchKind::Write => write,
+            WatchKind::Access => true,
+        }
+    }
+}
+
+/// An access triggering a watchpoint.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Hit {
+    /// Bus of the access, `"mmio"` or `"pio"`.
+    pub bus: &'static str,
+    /// Base address of the range of the device.
+    pub base: u64,
+    /// Offset of the access in the range.
+    pub offset: u64,
+    /// Whether the access is a write.
+    pub write: bool,
+}
+
+/// What happens to an access once a watchpoint callback returns.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Action {
+    /// Hand the access to the device, with the data written as left by the callback.
+    Continue,
+    /// Don't hand the access to the device: writes are dropped, and reads return the data
+    /// as left by the callback.
+    Veto,
+}
+
+/// Identifies a watchpoint of a [`Watched`] device.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct WatchId(u64);
+
+type Callback = Arc<dyn Fn(&Hit, &mut [u8]) -> Action + Send + Sync>;
+
+struct Watchpoint {
+    id: WatchId,
+    offsets: Range<u64>,
+    kind: WatchKind,
+    callback: Callback,
+}
+
+/// A device wrapper calling back when watched offsets are accessed.
+///
+/// The callbacks of the watchpoints overlapping an access run before the access reaches the
+/// device, in the order the watchpoints were added, with the data written or a zeroed read
+/// buffer. The access is vetoed if any callback vetoes it. A read-modify-write (see
+/// [`DeviceMmio::mmio_update`]) hitting watchpoints is handed to them, and to the device, as a
+/// read followed by a write.
+pub struct Watched<D> {
+    device: D,
+    watchpoints: RwLock<Vec<Watchpoint>>,
+    next_id: AtomicU64,
+}
+
+impl<D> Watched<D> {
+    /// Wrap `device`, without watchpoints.
+    pub fn new(device: D) -> Self {
+        Watched {
+            device,
+            watchpoints: RwLock::new(Vec::new()),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Return the wrapped device.
+    pub fn inner(&self) -> &D {
+        &self.device
+    }
+
+    /// Call `callback` on the accesses of `kind` overlapping `offsets`.
+    pub fn watch<F>(&self, offsets: Range<u64>, kind: WatchKind, callback: F) -> WatchId
+    where
+        F: Fn(&Hit, &mut [u8]) -> Action + Send + Sync + 'static,
+    {
+        let id = WatchId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        self.watchpoints.write().unwrap().push(Watchpoint {
+            id,
+            offsets,
+            kind,
+            callback: Arc::new(callback),
+        });
+        id
+    }
+
+    /// Remove the watchpoint `id`. Returns `false` if there is no such watchpoint.
+    pub fn unwatch(&self, id: WatchId) -> bool {
+        let mut watchpoints = self.watchpoints.write().unwrap();
+        let len = watchpoints.len();
+        watchpoints.retain(|watchpoint| watchpoint.id != id);
+        watchpoints.len() != len
+    }
+
+    // Return the callbacks of the watchpoints hit by `hit`, if any. They are cloned so the
+    // callbacks can add or remove watchpoints.
+    fn hits(&self, hit: &Hit, len: usize) -> Vec<Callback> {
+        let end = hit.offset.saturating_add(len as u64);
+        self.watchpoints
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|w| w.kind.matches(hit.write))
+            .filter(|w| w.offsets.start < end && hit.offset < w.offsets.end)
+            .map(|w| w.callback.clone())
+            .collect()
+    }
+
+    // Run the callbacks of the watchpoints hit by a read into `data`, and return whether
+    // the read reaches the device.
+    fn read(&self, hit: Hit, data: &mut [u8]) -> bool {
+        let callbacks = self.hits(&hit, data.len());
+        if callbacks.is_empty() {
+            return true;
+        }
+        data.fill(0);
+        run(&callbacks, &hit, data)
+    }
+
+    // Run the callbacks of the watchpoints hit by a write of `data`, and hand the data to
+    // `write` unless the write is vetoed.
+    fn write<F: FnOnce(&[u8])>(&self, hit: Hit, data: &[u8], write: F) {
+        let callbacks = self.hits(&hit, data.len());
+        if callbacks.is_empty() {
+            return write(data);
+        }
+        let mut data = data.to_vec();
+        if run(&callbacks, &hit, &mut data) {
+            write(&data);
+        }
+    }
+}
+
+// Run `callbacks`, and return whether none of them vetoed the access.
+fn run(callbacks: &[Callback], hit: &Hit, data: &mut [u8]) -> bool {
+    let mut go = true;
+    for callback in callbacks {
+        if callback(hit, data) == Action::Veto {
+            go = false;
+        }
+    }
+    go
+}
+
+impl<D: DeviceMmio> DeviceMmio for Watched<D> {
+    fn mmio_read(&self, base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+        let hit = Hit {
+            bus: "mmio",
+            base: base.0,
+            offset,
+            write: false,
+        };
+        if self.read(hit, data) {
+            self.device.mmio_read(base, offset, data);
+        }
+    }
+
+    fn mmio_write(&self, base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+        let hit = Hit {
+            bus: "mmio",
+            base: base.0,
+            offset,
+            write: true,
+        };
+        self.write(hit, data, |data| self.device.mmio_write(base, offset, data));
+    }
+
+    fn introspect(&self) -> Vec<(String, String)> {
+        self.device.introspect()
+    }
+
+    fn info(&self) -> Option<DeviceDetails> {
+        self.device.info()
+    }
+
+    fn health(&self) -> Health {
+        self.device.health()
+    }
+
+    fn stats(&self) -> Option<Statistics> {
+        self.device.stats()
+    }
+
+    fn update_resources(&self, resources: &[Resource]) {
+        self.device.update_resources(resources)
+    }
+
+    fn debug_read(&self, base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) -> bool {
+        self.device.debug_read(base, offset, data)
+    }
+
+    fn mmio_update(
+        &self,
+        base: MmioAddress,
+        offset: MmioAddressOffset,
+        data: &mut [u8],
+        update: &mut dyn FnMut(&mut [u8]),
+    ) {
+        let hit = |write| Hit {
+            bus: "mmio",
+            base: base.0,
+            offset,
+            write,
+        };
+        if self.hits(&hit(false), data.len()).is_empty()
+            && self.hits(&hit(true), data.len()).is_empty()
+        {
+            return self.device.mmio_update(base, offset, data, update);
+        }
+        // The callbacks may veto either access, so they are handled separately.
+        self.mmio_read(base, offset, data);
+        update(data);
+        self.mmio_write(base, offset, data);
+    }
+
+    fn mmio_deferred(
+        &self,
+        base: MmioAddress,
+        offset: MmioAddressOffset,
+        access: ExitAccess<'_>,
+    ) -> Completion {
+        let mut completion = Completion::Done;
+        let hit = |write| Hit {
+            bus: "mmio",
+            base: base.0,
+            offset,
+            write,
+        };
+        match access {
+            ExitAccess::Read(data) => {
+                if self.read(hit(false), data) {
+                    completion = self
+                        .device
+                        .mmio_deferred(base, offset, ExitAccess::Read(data));
+                }
+            }
+            ExitAccess::Write(data) => {
+                self.write(hit(true), data, |data| {
+                    completion = self
+                        .device
+                        .mmio_deferred(base, offset, ExitAccess::Write(data))
+                });
+            }
+        }
+        completion
+    }
+}
+
+impl<D: DevicePio> DevicePio for Watched<D> {
+    fn pio_read(&self, base: PioAddress, offset: PioAddressOffset, data: &mut [u8]) {
+        let hit = Hit {
+            bus: "pio",
+            base: base.0.into(),
+            offset: offset.into(),
+            write: false,
+        };
+        if self.read(hit, data) {
+            self.device.pio_read(base, offset, data);
+        }
+    }
+
+    fn pio_write(&self, base: PioAddress, offset: PioAddressOffset, data: &[u8]) {
+        let hit = Hit {
+            bus: "pio",
+            base: base.0.into(),
+            offset: offset.into(),
+            write: true,
+        };
+        self.write(hit, data, |data| self.device.pio_write(base, offset, data));
+    }
+
+    fn introspect(&self) -> Vec<(String, String)> {
+        self.device.introspect()
+    }
+
+    fn info(&self) -> Option<DeviceDetails> {
+        self.device.info()
+    }
+
+    fn health(&self) -> Health {
+        self.device.health()
+    }
+
+    fn stats(&self) -> Option<Statistics> {
+        self.device.stats()
+    }
+
+    fn debug_read(&self, base: PioAddress, offset: PioAddressOffset, data: &mut [u8]) -> bool {
+        self.device.debug_read(base, offset, data)
+    }
+
+    fn pio_deferred(
+        &self,
+        base: PioAddress,
+        offset: PioAddressOffset,
+        access: ExitAccess<'_>,
+    ) -> Completion {
+        let mut completion = Completion::Done;
+        let hit = |write| Hit {
+            bus: "pio",
+            base: base.0.into(),
+            offset: offset.into(),
+            write,
+        };
+        match access {
+            ExitAccess::Read(data) => {
+                if self.read(hit(false), data) {
+                    completion = self
+                        .device
+                        .pio_deferred(base, offset, ExitAccess::Read(data));
+                }
+            }
+            ExitAccess::Write(data) => {
+                self.write(hit(true), data, |data| {
+                    completion = self
+                        .device
+                        .pio_deferred(base, offset, ExitAccess::Write(data))
+                });
+            }
+        }
+        completion
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use crate::device_manager::{IoManager, MmioManager};
+    use crate::testing::Scratchpad;
+
+    #[test]
+    fn test_watchpoints() {
+        let mut manager = IoManager::new();
+        let resources = [Resource::MmioAddressRange {
+            base: 0x1000,
+            size: 0x10,
+        }];
+        let scratchpad = Arc::new(Scratchpad::new(0x10));
+        manager
+            .register_mmio_resources(scratchpad.clone(), &resources)
+            .unwrap();
+        let device = manager.watch_mmio(&resources).unwrap();
+
+        let hits = Arc::new(Mutex::new(Vec::new()));
+        let recorded = hits.clone();
+        let id = device.watch(4..6, WatchKind::Access, move |hit, data| {
+            recorded.lock().unwrap().push((*hit, data.to_vec()));
+            data[0] += 1;
+            Action::Continue
+        });
+        device.watch(8..0x10, WatchKind::Write, |_, _| Action::Veto);
+
+        manager
+            .mmio_write(MmioAddress(0x1002), &[1, 2, 3, 4])
+            .unwrap();
+        manager.mmio_write(MmioAddress(0x1006), &[5, 6]).unwrap();
+        manager.mmio_write(MmioAddress(0x100c), &[7]).unwrap();
+        assert_eq!(
+            scratchpad.contents()[..0x10],
+            [0, 0, 2, 2, 3, 4, 5, 6, 0, 0, 0, 0, 0, 0, 0, 0]
+        );
+        let mut data = [0xff; 2];
+        manager.mmio_read(MmioAddress(0x1005), &mut data).unwrap();
+        assert_eq!(data, [4, 5]);
+        assert_eq!(
+            *hits.lock().unwrap(),
+            [
+                (
+                    Hit {
+                        bus: "mmio",
+                        base: 0x1000,
+                        offset: 2,
+                        write: true
+                    },
+                    vec![1, 2, 3, 4]
+                ),
+                (
+                    Hit {
+                        bus: "mmio",
+                        base: 0x1000,
+                        offset: 5,
+                        write: false
+                    },
+                    vec![0, 0]
+                ),
+            ]
+        );
+
+        assert!(device.unwatch(id));
+        assert!(!device.unwatch(id));
+        manager.mmio_write(MmioAddress(0x1004), &[9]).unwrap();
+        assert_eq!(scratchpad.contents()[4], 9);
+        assert_eq!(hits.lock().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,162 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+// Checks that dispatching accesses, and registering devices in reserved room, doesn't
+// allocate. This lives in its own test binary because it replaces the global allocator.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::sync::Arc;
+
+use vm_device::bus::{
+    Error, MmioAddress, MmioAddressOffset, MmioRange, PioAddress, PioAddressOffset, PioRange,
+};
+use vm_device::device_manager::{IoManager, MmioManager, PioManager, SharedIoManager};
+use vm_device::{DeviceMmio, DevicePio};
+
+// Counts the allocations made by the current thread, so that tests running concurrently
+// don't interfere with each other.
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // The counter may already be gone while the thread shuts down.
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+// Return the number of allocations made by `f`.
+fn allocations<F: FnOnce()>(f: F) -> usize {
+    let before = ALLOCATIONS.with(Cell::get);
+    f();
+    ALLOCATIONS.with(Cell::get) - before
+}
+
+struct NoopDevice;
+
+impl DevicePio for NoopDevice {
+    fn pio_read(&self, _base: PioAddress, _offset: PioAddressOffset, _data: &mut [u8]) {}
+    fn pio_write(&self, _base: PioAddress, _offset: PioAddressOffset, _data: &[u8]) {}
+}
+
+impl DeviceMmio for NoopDevice {
+    fn mmio_read(&self, _base: MmioAddress, _offset: MmioAddressOffset, _data: &mut [u8]) {}
+    fn mmio_write(&self, _base: MmioAddress, _offset: MmioAddressOffset, _data: &[u8]) {}
+}
+
+fn mmio_range(idx: u64) -> MmioRange {
+    MmioRange::new(MmioAddress(0x1000 * (idx + 1)), 0x100).unwrap()
+}
+
+#[test]
+fn test_dispatch_does_not_allocate() {
+    let device = Arc::new(NoopDevice);
+    let mut manager = IoManager::new();
+    let pio_range = PioRange::new(PioAddress(0x40), 0x10).unwrap();
+    manager.register_pio(pio_range, device.clone()).unwrap();
+    manager.register_mmio(mmio_range(0), device).unwrap();
+
+    let mut data = [0; 4];
+    let count = allocations(|| {
+        manager.mmio_read(MmioAddress(0x1000), &mut data).unwrap();
+        manager.mmio_write(MmioAddress(0x1002), &data[..2]).unwrap();
+        manager.pio_read(PioAddress(0x41), &mut data[..1]).unwrap();
+        manager.pio_write(PioAddress(0x44), &data).unwrap();
+
+        // Errors don't allocate either.
+        assert_eq!(
+            manager.mmio_read(MmioAddress(0x10fe), &mut data),
+            Err(Error::DeviceNotFound {
+                addr: 0x10fe,
+                len: 4
+            })
+        );
+        assert_eq!(
+            manager.pio_write(PioAddress(0x40), &[0; 0x1_0000]),
+            Err(Error::InvalidAccessLength {
+                addr: 0x40,
+                len: 0x1_0000
+            })
+        );
+        assert_eq!(
+            manager.mmio_write(MmioAddress(0x1000), &[]),
+            Err(Error::InvalidRange {
+                base: 0x1000,
+                size: 0
+            })
+        );
+
+        let batch = [
+            (MmioAddress(0x1000), &data[..]),
+            (MmioAddress(0), &data[..]),
+        ];
+        assert_eq!(manager.mmio_write_batch(batch.iter().copied()).failed, 1);
+    });
+    assert_eq!(count, 0);
+}
+
+#[test]
+fn test_shared_dispatch_does_not_allocate() {
+    let mut manager = IoManager::new();
+    manager
+        .register_mmio(mmio_range(0), Arc::new(NoopDevice))
+        .unwrap();
+    let shared = SharedIoManager::new(manager);
+    let mut handle = shared.handle();
+
+    let mut data = [0; 4];
+    // The first load from a thread sets up per-thread state.
+    shared.mmio_read(MmioAddress(0x1000), &mut data).unwrap();
+    let count = allocations(|| {
+        shared.mmio_read(MmioAddress(0x1000), &mut data).unwrap();
+        shared.mmio_write(MmioAddress(0x1004), &data).unwrap();
+        handle.mmio_read(MmioAddress(0x1000), &mut data).unwrap();
+        handle.mmio_write(MmioAddress(0x1004), &data).unwrap();
+        assert!(handle.mmio_write(MmioAddress(0), &data).is_err());
+    });
+    assert_eq!(count, 0);
+}
+
+#[test]
+fn test_reserved_registration_does_not_allocate() {
+    let devices: Vec<Arc<NoopDevice>> = (0..16).map(|_| Arc::new(NoopDevice)).collect();
+    let mut manager = IoManager::new();
+    manager.reserve(0, devices.len());
+
+    let count = allocations(|| {
+        // Register in descending order, so that ranges get inserted in front.
+        for (idx, device) in devices.iter().enumerate().rev() {
+            manager
+                .register_mmio(mmio_range(idx as u64), device.clone())
+                .unwrap();
+        }
+        assert_eq!(
+            manager.register_mmio(mmio_range(3), devices[0].clone()),
+            Err(Error::DeviceOverlap {
+                base: 0x4000,
+                size: 0x100
+            })
+        );
+        for idx in 0..devices.len() as u64 {
+            assert!(manager.deregister_mmio(mmio_range(idx).base()).is_some());
+        }
+    });
+    assert_eq!(count, 0);
+}
@@ -0,0 +1,122 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+// Checks the register dispatch generated by `#[derive(MmioRegisters)]`.
+
+#![cfg(feature = "derive")]
+
+use std::sync::{Arc, Mutex};
+
+use vm_device::bus::{MmioAddress, MmioRange};
+use vm_device::device_manager::{IoManager, MmioManager};
+use vm_device::MmioRegisters;
+
+const BASE: u64 = 0x1000;
+
+#[derive(Default, MmioRegisters)]
+#[register(offset = 0x00, width = 4, read = magic)]
+#[register(offset = 0x50, width = 4, write = notify)]
+struct Device {
+    #[register(offset = 0x04, read_only)]
+    version: u32,
+    #[register(offset = 0x08, write_only)]
+    doorbell: u16,
+    #[register(offset = 0x10)]
+    address: u64,
+    #[register(offset = 0x70, write = set_status)]
+    status: u8,
+    notified: Vec<u32>,
+}
+
+impl Device {
+    fn magic(&mut self) -> u32 {
+        0x7472_6976
+    }
+
+    fn notify(&mut self, queue: u32) {
+        self.notified.push(queue);
+    }
+
+    fn set_status(&mut self, status: u8) {
+        // Writing zero resets the device.
+        if status == 0 {
+            self.address = 0;
+        }
+        self.status = status;
+    }
+}
+
+#[derive(MmioRegisters)]
+struct Scratch(#[register(offset = 0x0)] u32);
+
+fn read(manager: &IoManager, offset: u64, data: &mut [u8]) {
+    manager.mmio_read(MmioAddress(BASE + offset), data).unwrap();
+}
+
+fn write(manager: &IoManager, offset: u64, data: &[u8]) {
+    manager
+        .mmio_write(MmioAddress(BASE + offset), data)
+        .unwrap();
+}
+
+#[test]
+fn test_derive_mmio_registers() {
+    let mut manager = IoManager::new();
+    let device = Arc::new(Mutex::new(Device {
+        version: 2,
+        ..Default::default()
+    }));
+    let range = MmioRange::new(MmioAddress(BASE), 0x100).unwrap();
+    manager.register_mmio(range, device.clone()).unwrap();
+
+    let mut data = [0xff; 4];
+    read(&manager, 0x00, &mut data);
+    assert_eq!(u32::from_le_bytes(data), 0x7472_6976);
+    read(&manager, 0x04, &mut data);
+    assert_eq!(u32::from_le_bytes(data), 2);
+
+    // Read-only registers ignore writes, and write-only registers read as zeros.
+    write(&manager, 0x04, &7u32.to_le_bytes());
+    read(&manager, 0x04, &mut data);
+    assert_eq!(u32::from_le_bytes(data), 2);
+    write(&manager, 0x08, &0x1234u16.to_le_bytes());
+    assert_eq!(device.lock().unwrap().doorbell, 0x1234);
+    let mut half = [0xff; 2];
+    read(&manager, 0x08, &mut half);
+    assert_eq!(half, [0; 2]);
+
+    // Fields are stored and loaded in little endian.
+    let mut quad = [0; 8];
+    write(&manager, 0x10, &0x1_0000_2000u64.to_le_bytes());
+    read(&manager, 0x10, &mut quad);
+    assert_eq!(u64::from_le_bytes(quad), 0x1_0000_2000);
+
+    // Handlers see the written values.
+    write(&manager, 0x50, &1u32.to_le_bytes());
+    write(&manager, 0x50, &0u32.to_le_bytes());
+    assert_eq!(device.lock().unwrap().notified, vec![1, 0]);
+    write(&manager, 0x70, &[0xf]);
+    write(&manager, 0x70, &[0]);
+    assert_eq!(device.lock().unwrap().address, 0);
+    let mut byte = [0xff];
+    read(&manager, 0x70, &mut byte);
+    assert_eq!(byte, [0]);
+
+    // Accesses must match the offset and width of a register.
+    read(&manager, 0x10, &mut data);
+    assert_eq!(data, [0; 4]);
+    read(&manager, 0x02, &mut half);
+    assert_eq!(half, [0; 2]);
+    write(&manager, 0x04, &[1]);
+    write(&manager, 0x30, &[1; 4]);
+    assert_eq!(device.lock().unwrap().version, 2);
+
+    // Tuple structs work too.
+    let scratch = Arc::new(Mutex::new(Scratch(0)));
+    let range = MmioRange::new(MmioAddress(0x2000), 4).unwrap();
+    manager.register_mmio(range, scratch.clone()).unwrap();
+    manager
+        .mmio_write(MmioAddress(0x2000), &5u32.to_le_bytes())
+        .unwrap();
+    assert_eq!(scratch.lock().unwrap().0, 5);
+}
@@ -0,0 +1,302 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Derive macros for the `vm-device` crate, re-exported by it with the `derive` feature.
+
+use proc_macro2::{Literal, Span, TokenStream};
+use quote::quote;
+use syn::meta::ParseNestedMeta;
+use syn::{parse_macro_input, Attribute, Data, DeriveInput, Error, Fields, Ident, LitInt, Type};
+
+/// Derive `MutDeviceMmio` for a struct describing its registers.
+///
+/// Each field annotated with `#[register(offset = ...)]` is a register, whose width is given
+/// by the type of the field (`u8`, `u16`, `u32` or `u64`). Reads return the value of the field
+/// and writes store the value in the field, unless they go through handlers:
+///
+/// * `read = method` reads the register with `fn method(&mut self) -> T`;
+/// * `write = method` writes the register with `fn method(&mut self, value: T)`;
+/// * `read_only` and `write_only` ignore the writes, or read the register as zeros.
+///
+/// Registers without a backing field are declared on the struct itself, with their `width`
+/// in bytes and at least one handler. Values are little endian, accesses must match the
+/// offset and width of a register exactly, and the other accesses read zeros or are ignored.
+/// Overlapping registers are rejected at compile time.
+///
+/// ```ignore
+/// use vm_device::MmioRegisters;
+///
+/// #[derive(MmioRegisters)]
+/// #[register(offset = 0x00, width = 4, read = magic)]
+/// struct Device {
+///     #[register(offset = 0x04, read_only)]
+///     version: u32,
+///     #[register(offset = 0x70, write = set_status)]
+///     status: u32,
+///     queues: Vec<Queue>,
+/// }
+///
+/// impl Device {
+///     fn magic(&mut self) -> u32 {
+///         0x7472_6976
+///     }
+///
+///     fn set_status(&mut self, status: u32) {
+///         if status == 0 {
+///             self.reset();
+///         }
+///         self.status = status;
+///     }
+/// }
+/// ```
+#[proc_macro_derive(MmioRegisters, attributes(register))]
+pub fn derive_mmio_registers(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    mmio_registers(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+// A register and the code accessing it, where `value` holds the value being written.
+struct Register {
+    offset: u64,
+    width: usize,
+    attr: Attribute,
+    read: Option<TokenStream>,
+    write: Option<TokenStream>,
+}
+
+// The options of a `#[register(...)]` attribute.
+#[derive(Default)]
+struct Options {
+    offset: Option<u64>,
+    width: Option<usize>,
+    read: Option<Ident>,
+    write: Option<Ident>,
+    read_only: bool,
+    write_only: bool,
+}
+
+impl Options {
+    fn parse(attr: &Attribute) -> syn::Result<Self> {
+        let mut options = Options::default();
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("offset") {
+                options.offset = Some(meta.value()?.parse::<LitInt>()?.base10_parse()?);
+            } else if meta.path.is_ident("width") {
+                let lit = meta.value()?.parse::<LitInt>()?;
+                options.width = match lit.base10_parse()? {
+                    width @ (1 | 2 | 4 | 8) => Some(width),
+                    _ => return Err(Error::new(lit.span(), "width must be 1, 2, 4 or 8")),
+                };
+            } else if meta.path.is_ident("read") {
+                options.read = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("write") {
+                options.write = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("read_only") {
+                options.read_only = true;
+            } else if meta.path.is_ident("write_only") {
+                options.write_only = true;
+            } else {
+                return Err(unknown(&meta));
+            }
+            Ok(())
+        })?;
+
+        if options.offset.is_none() {
+            return Err(Error::new_spanned(attr, "register needs an offset"));
+        }
+        if options.read_only && (options.write_only || options.write.is_some())
+            || options.write_only && options.read.is_some()
+        {
+            return Err(Error::new_spanned(attr, "conflicting register accesses"));
+        }
+        Ok(options)
+    }
+}
+
+fn unknown(meta: &ParseNestedMeta) -> Error {
+    meta.error("expected `offset`, `width`, `read`, `write`, `read_only` or `write_only`")
+}
+
+// Return the width of the integer type `ty`, if it's one.
+fn type_width(ty: &Type) -> Option<usize> {
+    match ty {
+        Type::Path(path) if path.qself.is_none() => {
+            let ident = path.path.get_ident()?;
+            ["u8", "u16", "u32", "u64"]
+                .iter()
+                .position(|name| ident == name)
+                .map(|idx| 1 << idx)
+        }
+        _ => None,
+    }
+}
+
+fn value_type(width: usize) -> Ident {
+    Ident::new(&format!("u{}", width * 8), Span::call_site())
+}
+
+fn is_register(attr: &Attribute) -> bool {
+    attr.path().is_ident("register")
+}
+
+fn struct_register(attr: &Attribute) -> syn::Result<Register> {
+    let options = Options::parse(attr)?;
+    let width = options
+        .width
+        .ok_or_else(|| Error::new_spanned(attr, "register without a field needs a width"))?;
+    if options.read_only || options.write_only {
+        return Err(Error::new_spanned(
+            attr,
+            "register without a field is accessed through its handlers",
+        ));
+    }
+    if options.read.is_none() && options.write.is_none() {
+        return Err(Error::new_spanned(
+            attr,
+            "register without a field needs a `read` or `write` handler",
+        ));
+    }
+    Ok(Register {
+        offset: options.offset.unwrap_or_default(),
+        width,
+        attr: attr.clone(),
+        read: options.read.map(|read| quote!(self.#read())),
+        write: options.write.map(|write| quote!(self.#write(value);)),
+    })
+}
+
+fn field_register(attr: &Attribute, member: TokenStream, ty: &Type) -> syn::Result<Register> {
+    let options = Options::parse(attr)?;
+    let width = type_width(ty)
+        .ok_or_else(|| Error::new_spanned(ty, "register field must be u8, u16, u32 or u64"))?;
+    if options.width.is_some_and(|w| w != width) {
+        return Err(Error::new_spanned(
+            attr,
+            "width doesn't match the field type",
+        ));
+    }
+    let read = match (options.read, options.write_only) {
+        (Some(read), _) => Some(quote!(self.#read())),
+        (None, false) => Some(quote!(self.#member)),
+        (None, true) => None,
+    };
+    let write = match (options.write, options.read_only) {
+        (Some(write), _) => Some(quote!(self.#write(value);)),
+        (None, false) => Some(quote!(self.#member = value;)),
+        (None, true) => None,
+    };
+    Ok(Register {
+        offset: options.offset.unwrap_or_default(),
+        width,
+        attr: attr.clone(),
+        read,
+        write,
+    })
+}
+
+fn registers(input: &DeriveInput) -> syn::Result<Vec<Register>> {
+    let fields = match &input.data {
+        Data::Struct(data) => &data.fields,
+        _ => {
+            return Err(Error::new_spanned(
+                &input.ident,
+                "MmioRegisters can only be derived for structs",
+            ))
+        }
+    };
+
+    let mut registers = Vec::new();
+    for attr in input.attrs.iter().filter(|attr| is_register(attr)) {
+        registers.push(struct_register(attr)?);
+    }
+    for (index, field) in fields.iter().enumerate() {
+        let member = match (&field.ident, fields) {
+            (Some(ident), _) => quote!(#ident),
+            (None, Fields::Unnamed(_)) => {
+                let index = syn::Index::from(index);
+                quote!(#index)
+            }
+            (None, _) => continue,
+        };
+        for attr in field.attrs.iter().filter(|attr| is_register(attr)) {
+            registers.push(field_register(attr, member.clone(), &field.ty)?);
+        }
+    }
+
+    registers.sort_by_key(|register| register.offset);
+    for pair in registers.windows(2) {
+        let (prev, next) = (&pair[0], &pair[1]);
+        if prev.offset.saturating_add(prev.width as u64) > next.offset {
+            return Err(Error::new_spanned(
+                &next.attr,
+                format!(
+                    "register at offset {:#x} overlaps the register at offset {:#x}",
+                    next.offset, prev.offset
+                ),
+            ));
+        }
+    }
+    Ok(registers)
+}
+
+fn mmio_registers(input: DeriveInput) -> syn::Result<TokenStream> {
+    let registers = registers(&input)?;
+
+    let reads = registers.iter().filter_map(|register| {
+        let read = register.read.as_ref()?;
+        let offset = Literal::u64_unsuffixed(register.offset);
+        let width = Literal::usize_unsuffixed(register.width);
+        let ty = value_type(register.width);
+        Some(quote! {
+            (#offset, #width) => data.copy_from_slice(&<#ty>::to_le_bytes(#read)),
+        })
+    });
+    let writes = registers.iter().filter_map(|register| {
+        let write = register.write.as_ref()?;
+        let offset = Literal::u64_unsuffixed(register.offset);
+        let width = Literal::usize_unsuffixed(register.width);
+        let ty = value_type(register.width);
+        Some(quote! {
+            (#offset, #width) => {
+                let mut bytes = [0; #width];
+                bytes.copy_from_slice(data);
+                let value = <#ty>::from_le_bytes(bytes);
+                #write
+            }
+        })
+    });
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::vm_device::MutDeviceMmio for #name #ty_generics #where_clause {
+            fn mmio_read(
+                &mut self,
+                _base: ::vm_device::bus::MmioAddress,
+                offset: ::vm_device::bus::MmioAddressOffset,
+                data: &mut [u8],
+            ) {
+                #[allow(clippy::match_single_binding)]
+                match (offset, data.len()) {
+                    #(#reads)*
+                    _ => data.fill(0),
+                }
+            }
+
+            fn mmio_write(
+                &mut self,
+                _base: ::vm_device::bus::MmioAddress,
+                offset: ::vm_device::bus::MmioAddressOffset,
+                data: &[u8],
+            ) {
+                #[allow(clippy::match_single_binding)]
+                match (offset, data.len()) {
+                    #(#writes)*
+                    _ => {}
+                }
+            }
+        }
+    })
+}